@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::prelude::*;
+use tokio::runtime::Runtime;
+
+use ssssh::BppStream;
+
+const PACKETS: usize = 100_000;
+
+/// Channel data sized payloads, as in a bulk transfer.
+const PAYLOAD_SIZE: usize = 1024;
+
+async fn transfer(payload: &[u8]) {
+    let (ours, theirs) = tokio::io::duplex(1024 * 1024);
+    let mut ours = BppStream::new(ours);
+    let mut theirs = BppStream::new(theirs);
+
+    let send = async {
+        for _ in 0..PACKETS {
+            ours.feed(payload).await.unwrap();
+        }
+        ours.flush().await.unwrap();
+    };
+    let receive = async {
+        for _ in 0..PACKETS {
+            theirs.next().await.unwrap().unwrap();
+        }
+    };
+    tokio::join!(send, receive);
+}
+
+fn bench_transfer(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let payload = vec![94; PAYLOAD_SIZE];
+
+    let mut group = c.benchmark_group("bpp");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes((PACKETS * PAYLOAD_SIZE) as u64));
+    group.bench_function("transfer", |b| b.iter(|| rt.block_on(transfer(&payload))));
+    group.finish();
+}
+
+criterion_group!(benches, bench_transfer);
+criterion_main!(benches);
@@ -83,17 +83,12 @@ async fn main() -> anyhow::Result<()> {
                             let master_fd = master.into_raw_fd();
                             unsafe {
                                 builder.pre_exec(move || {
-                                    close(master_fd)
-                                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                                    setsid()
-                                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                                    close(master_fd).map_err(io::Error::other)?;
+                                    setsid().map_err(io::Error::other)?;
                                     Ok(())
                                 });
                             }
                             let mut child = builder.spawn()?;
-                            drop(ptyin);
-                            drop(ptyout);
-                            drop(ptyerr);
 
                             let mut ptyout = unsafe { PipeRead::from_raw_fd(dup(master_fd)?) };
                             let mut ptyin = unsafe { PipeWrite::from_raw_fd(master_fd) };
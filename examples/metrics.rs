@@ -0,0 +1,116 @@
+/// echo server counting traffic and auth results (`examples/metrics.rs`)
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{ok, FutureExt as _};
+use futures::stream::StreamExt as _;
+use ssssh::{AuthOutcome, ChannelKind, ConnectionObserver, Handlers, ServerBuilder};
+
+#[derive(Debug, Default)]
+struct Metrics {
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    auth_failures: AtomicU64,
+    channels_open: AtomicU64,
+    kex: AtomicU64,
+    protocol_warnings: AtomicU64,
+    handler_error_faults: AtomicU64,
+}
+
+impl ConnectionObserver for Metrics {
+    fn on_packet_received(&self, len: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn on_packet_sent(&self, len: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn on_auth_result(&self, _user: &str, _method: &str, outcome: AuthOutcome) {
+        if outcome == AuthOutcome::Failure {
+            self.auth_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_channel_open(&self, _channel: u32, _kind: ChannelKind) {
+        self.channels_open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_channel_close(&self, _channel: u32) {
+        self.channels_open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn on_kex_complete(&self, _algorithms: &ssssh::NegotiatedAlgorithms) {
+        self.kex.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_protocol_warning(&self, _warning: &ssssh::ProtocolWarning) {
+        self.protocol_warnings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_handler_error_fault(&self, _fault: ssssh::HandlerErrorFault) {
+        self.handler_error_faults.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let metrics = Arc::new(Metrics::default());
+    let server = ServerBuilder::default()
+        .timeout(Duration::from_secs(5))
+        .observer(metrics.clone())
+        .build("[::1]:2222")
+        .await?;
+
+    let report = metrics.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            println!("{:?}", report);
+        }
+    });
+
+    let factory = |_: &ssssh::ConnectionInfo| {
+        let mut handlers = Handlers::<anyhow::Error>::new();
+
+        handlers.on_auth_password(|_, password| {
+            let result = if password == "secret" {
+                ssssh::PasswordResult::Ok
+            } else {
+                ssssh::PasswordResult::Failure
+            };
+            ok(result).boxed()
+        });
+        handlers.on_channel_shell(|mut ctx: ssssh::SessionContext| {
+            let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                tokio::io::copy(&mut stdin, &mut stdout).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        handlers
+    };
+
+    server
+        .for_each_concurrent(64, |conn| async {
+            let result = async {
+                let conn = conn?.accept().await?;
+                conn.run_with(&factory).await?;
+                Ok::<_, anyhow::Error>(())
+            };
+            if let Err(e) = result.await {
+                println!("{}", e);
+            }
+        })
+        .await;
+    Ok(())
+}
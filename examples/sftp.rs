@@ -0,0 +1,262 @@
+//! SFTP server on a local directory (`examples/sftp.rs`)
+//!
+//! `cargo run --example sftp --features sftp -- DIR`, then
+//! `sftp -P 2222 -oStrictHostKeyChecking=no -oUserKnownHostsFile=/dev/null '[::1]'`.
+use std::collections::HashMap;
+use std::env;
+use std::io::SeekFrom;
+use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::{ok, BoxFuture, FutureExt as _};
+use ssssh::sftp::{self, FileAttributes, Name, OpenFlags, SftpHandler, StatusCode};
+use ssssh::{ConnectionInfo, Handlers, ServerBuilder};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _};
+
+enum Open {
+    File(File),
+    /// Listed at once, handed out by the first readdir.
+    Dir(Vec<Name>),
+}
+
+/// Client's `/` is `root`.
+struct LocalFs {
+    root: Arc<PathBuf>,
+    handles: HashMap<Bytes, Open>,
+    next: u32,
+}
+
+impl LocalFs {
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(&sftp::normalize(path)[1..])
+    }
+
+    fn insert(&mut self, open: Open) -> Bytes {
+        let handle = Bytes::copy_from_slice(&self.next.to_be_bytes());
+        self.next += 1;
+        self.handles.insert(handle.clone(), open);
+        handle
+    }
+
+    fn file(&mut self, handle: &Bytes) -> Result<&mut File, StatusCode> {
+        match self.handles.get_mut(handle) {
+            Some(Open::File(file)) => Ok(file),
+            _ => Err(StatusCode::Failure),
+        }
+    }
+}
+
+fn attrs(metadata: &std::fs::Metadata) -> FileAttributes {
+    FileAttributes {
+        size: Some(metadata.len()),
+        uid_gid: Some((metadata.uid(), metadata.gid())),
+        permissions: Some(metadata.mode()),
+        atime_mtime: Some((metadata.atime() as u32, metadata.mtime() as u32)),
+    }
+}
+
+fn longname(filename: &str, metadata: &std::fs::Metadata) -> String {
+    let kind = if metadata.is_dir() { 'd' } else { '-' };
+    let mode = metadata.mode();
+    let perms = (0..9)
+        .map(|i| match mode & (0o400 >> i) {
+            0 => '-',
+            _ => ['r', 'w', 'x'][i % 3],
+        })
+        .collect::<String>();
+    format!(
+        "{}{} 1 {} {} {:>8} {}",
+        kind,
+        perms,
+        metadata.uid(),
+        metadata.gid(),
+        metadata.len(),
+        filename
+    )
+}
+
+impl SftpHandler for LocalFs {
+    fn open(
+        &mut self,
+        filename: String,
+        flags: OpenFlags,
+        _: FileAttributes,
+    ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        let path = self.path(&filename);
+        async move {
+            let file = OpenOptions::new()
+                .read(flags.read())
+                .write(flags.write())
+                .append(flags.append())
+                .create(flags.create())
+                .truncate(flags.truncate())
+                .create_new(flags.exclusive())
+                .open(path)
+                .await?;
+            Ok(self.insert(Open::File(file)))
+        }
+        .boxed()
+    }
+
+    fn close(&mut self, handle: Bytes) -> BoxFuture<'_, Result<(), StatusCode>> {
+        let result = self
+            .handles
+            .remove(&handle)
+            .map(drop)
+            .ok_or(StatusCode::Failure);
+        futures::future::ready(result).boxed()
+    }
+
+    fn read(
+        &mut self,
+        handle: Bytes,
+        offset: u64,
+        len: u32,
+    ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        async move {
+            let file = self.file(&handle)?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            let mut buf = vec![0; len as usize];
+            let n = file.read(&mut buf).await?;
+            buf.truncate(n);
+            Ok(buf.into())
+        }
+        .boxed()
+    }
+
+    fn write(
+        &mut self,
+        handle: Bytes,
+        offset: u64,
+        data: Bytes,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        async move {
+            let file = self.file(&handle)?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(&data).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn stat(&mut self, path: String) -> BoxFuture<'_, Result<FileAttributes, StatusCode>> {
+        let path = self.path(&path);
+        async move { Ok(attrs(&fs::metadata(path).await?)) }.boxed()
+    }
+
+    fn lstat(&mut self, path: String) -> BoxFuture<'_, Result<FileAttributes, StatusCode>> {
+        let path = self.path(&path);
+        async move { Ok(attrs(&fs::symlink_metadata(path).await?)) }.boxed()
+    }
+
+    fn fstat(&mut self, handle: Bytes) -> BoxFuture<'_, Result<FileAttributes, StatusCode>> {
+        async move { Ok(attrs(&self.file(&handle)?.metadata().await?)) }.boxed()
+    }
+
+    fn setstat(
+        &mut self,
+        path: String,
+        attrs: FileAttributes,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        let path = self.path(&path);
+        async move {
+            if let Some(mode) = attrs.permissions {
+                let permissions = std::fs::Permissions::from_mode(mode & 0o7777);
+                fs::set_permissions(path, permissions).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn opendir(&mut self, path: String) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        let path = self.path(&path);
+        async move {
+            let mut names = vec![];
+            let mut dir = fs::read_dir(path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let filename = entry.file_name().to_string_lossy().into_owned();
+                let metadata = entry.metadata().await?;
+                let longname = longname(&filename, &metadata);
+                names.push(Name::new(filename, longname, attrs(&metadata)));
+            }
+            Ok(self.insert(Open::Dir(names)))
+        }
+        .boxed()
+    }
+
+    fn readdir(&mut self, handle: Bytes) -> BoxFuture<'_, Result<Vec<Name>, StatusCode>> {
+        let result = match self.handles.get_mut(&handle) {
+            Some(Open::Dir(names)) => Ok(std::mem::take(names)),
+            _ => Err(StatusCode::Failure),
+        };
+        futures::future::ready(result).boxed()
+    }
+
+    fn remove(&mut self, filename: String) -> BoxFuture<'_, Result<(), StatusCode>> {
+        let path = self.path(&filename);
+        async move { Ok(fs::remove_file(path).await?) }.boxed()
+    }
+
+    fn mkdir(&mut self, path: String, _: FileAttributes) -> BoxFuture<'_, Result<(), StatusCode>> {
+        let path = self.path(&path);
+        async move { Ok(fs::create_dir(path).await?) }.boxed()
+    }
+
+    fn rmdir(&mut self, path: String) -> BoxFuture<'_, Result<(), StatusCode>> {
+        let path = self.path(&path);
+        async move { Ok(fs::remove_dir(path).await?) }.boxed()
+    }
+
+    fn rename(
+        &mut self,
+        oldpath: String,
+        newpath: String,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        let (oldpath, newpath) = (self.path(&oldpath), self.path(&newpath));
+        async move { Ok(fs::rename(oldpath, newpath).await?) }.boxed()
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let root = env::args().nth(1).unwrap_or_else(|| ".".into());
+    let root = Arc::new(std::fs::canonicalize(root)?);
+
+    let server = ServerBuilder::default()
+        .timeout(Duration::from_secs(60))
+        .build("[::1]:2222")
+        .await?;
+
+    let factory = move |_: &ConnectionInfo| {
+        let root = root.clone();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_none(|_| ok(true).boxed());
+        handlers.on_channel_subsystem(move |mut ctx: ssssh::SessionContext, name| {
+            let local = LocalFs {
+                root: root.clone(),
+                handles: HashMap::new(),
+                next: 0,
+            };
+            async move {
+                if name != "sftp" {
+                    return Ok(1);
+                }
+                let (stdin, stdout, _) = ctx.take_stdio().unwrap();
+                sftp::serve(local, stdin, stdout).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        handlers
+    };
+
+    server.serve(factory, |e| println!("{}", e)).await;
+    Ok(())
+}
@@ -1,41 +1,46 @@
 /// simple echo server (`examples/simple.rs`)
 use std::time::Duration;
 
-use futures::future::{ok, FutureExt as _, TryFutureExt as _};
-use futures::stream::TryStreamExt as _;
+use futures::future::{ok, FutureExt as _};
+use futures::stream::StreamExt as _;
 use ssssh::{Handlers, ServerBuilder};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    let mut server = ServerBuilder::default()
+    let server = ServerBuilder::default()
         .timeout(Duration::from_secs(5))
         .build("[::1]:2222")
         .await?;
 
-    while let Some(conn) = server.try_next().await? {
-        tokio::spawn(
-            async move {
-                let conn = conn.accept().await?;
-
-                let mut handlers = Handlers::<anyhow::Error>::new();
+    let factory = |_: &ssssh::ConnectionInfo| {
+        let mut handlers = Handlers::<anyhow::Error>::new();
 
-                handlers.on_auth_none(|_| ok(true).boxed());
-                handlers.on_channel_shell(|mut ctx: ssssh::SessionContext| {
-                    let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
-                    async move {
-                        tokio::io::copy(&mut stdin, &mut stdout).await?;
-                        Ok(0)
-                    }
-                    .boxed()
-                });
+        handlers.on_auth_none(|_| ok(true).boxed());
+        handlers.on_channel_shell(|mut ctx: ssssh::SessionContext| {
+            let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                tokio::io::copy(&mut stdin, &mut stdout).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        handlers
+    };
 
-                conn.run(handlers).await?;
+    // At most 64 connections at a time. Accept errors are transient.
+    server
+        .for_each_concurrent(64, |conn| async {
+            let result = async {
+                let conn = conn?.accept().await?;
+                conn.run_with(&factory).await?;
                 Ok::<_, anyhow::Error>(())
+            };
+            if let Err(e) = result.await {
+                println!("{}", e);
             }
-            .map_err(|e| println!("{}", e)),
-        );
-    }
+        })
+        .await;
     Ok(())
 }
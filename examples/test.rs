@@ -48,7 +48,7 @@ async fn main() -> anyhow::Result<()> {
                 handlers.on_channel_exec(|mut ctx: ssssh::SessionContext, _| {
                     let (_, mut stdout, _) = ctx.take_stdio().unwrap();
                     async move {
-                        stdout.write(b"Hello, World!").await?;
+                        stdout.write_all(b"Hello, World!").await?;
                         stdout.shutdown().await?;
                         Ok(0)
                     }
@@ -57,7 +57,7 @@ async fn main() -> anyhow::Result<()> {
 
                 handlers.on_channel_direct_tcpip(|_, mut stdout: SshOutput| {
                     async move {
-                        stdout.write(b"Hello, World!").await?;
+                        stdout.write_all(b"Hello, World!").await?;
                         stdout.shutdown().await?;
                         Ok(())
                     }
@@ -0,0 +1,7 @@
+//! Packets received before any key exchange (`cargo fuzz run bpp`)
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    ssssh::fuzz::bpp(data).ok();
+});
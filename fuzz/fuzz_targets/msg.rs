@@ -0,0 +1,7 @@
+//! Message payloads (`cargo fuzz run msg`)
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    ssssh::fuzz::msg(data);
+});
@@ -1,8 +1,10 @@
 //! OpenSSH `authorized_keys` parser.
 use std::iter::IntoIterator;
+use std::path::Path;
 use std::str::FromStr;
 
 use authorized_keys::openssh::v2::{KeysFile, KeysFileLine};
+use tokio::fs::File;
 use tokio::io::{self, AsyncRead, AsyncReadExt};
 
 use crate::PublicKey;
@@ -66,7 +68,7 @@ impl AuthorizedKeys {
     {
         let mut content = String::new();
         reader.read_to_string(&mut content).await?;
-        let keysfile = KeysFile::from_str(&content).map_err(|e| ParseError::Any(e))?;
+        let keysfile = KeysFile::from_str(&content).map_err(ParseError::Any)?;
 
         let mut keys = vec![];
         for line in keysfile {
@@ -87,6 +89,22 @@ impl AuthorizedKeys {
         }
         Ok(Self(keys))
     }
+
+    /// Read and parse OpenSSH `authorized_keys` file at `path`.
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self, ParseError> {
+        let file = File::open(path).await?;
+        Self::parse(file).await
+    }
+
+    /// Whether `publickey` is listed.
+    pub fn contains(&self, publickey: &PublicKey) -> bool {
+        self.0.iter().any(|key| &key.publickey == publickey)
+    }
+
+    /// Entry listing `publickey`, for its options and comment.
+    pub fn get(&self, publickey: &PublicKey) -> Option<&AuthorizedKey> {
+        self.0.iter().find(|key| &key.publickey == publickey)
+    }
 }
 
 impl IntoIterator for AuthorizedKeys {
@@ -125,10 +143,29 @@ restrict,pty,command="nethack" ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQCwgKJ9qbRMm
             PublicKey::from_str("AAAAB3NzaC1yc2EAAAADAQABAAABgQCwgKJ9qbRMmhYhjGhRRKHHVF8h7IOzOuB1B/XiiexfIPVK9kRBKKMpKmz7lGD1R2TQ2yIDgTjk0Z0xKKtrlHvkLH/78YIV5n3jT3IGHz9WsQBWMg8woFLQQj3eTcWjsRuOrIBOBRBky1h8/mXVxkY1dJZorajJB6svpNNO5Hfm9Ab8INyWPZY3b/qccA1RVChekzj6DnE6VAwcv1xJ3bT8NoCa5q+E1EAGiS/CZH0HzCceqYveP+Z4YmgOTJYufAr9WVKkXydsxKNpKsK3//+yT6MM907RZQonmN0/3+qAjvqi0dUoHCttbuNeNeXRGlcGfWM8u9Kxr5WSCVu2620ETdf6V4IVKZRwUBMclr8oZEiPWOEoiJ4nGjczuIbnKA/U4eyotoTCQrSXNlPp13jvak8Xfb8YzpFPpSDKZ1aD491XNZ2aeUTKVpTfqDiIJ8xSZWCN7D2nEZILcOfTUHnQ0q3FXO3YKkPPotbnk288Tlo8znZwl6OtV7lXg+Ucjkc=").unwrap(),
         ];
 
+        assert!(authorized_keys.contains(&expects[0]));
+        assert_eq!(
+            authorized_keys.get(&expects[1]).unwrap().comment(),
+            "john@example.net"
+        );
+
         let mut authorized_keys = authorized_keys.into_iter();
         for expect in expects {
             let key = authorized_keys.next().unwrap();
             assert_eq!(key.publickey(), &expect);
         }
     }
+
+    #[tokio::test]
+    async fn test_load() {
+        let authorized_keys = AuthorizedKeys::load("tests/ed25519.pub").await.unwrap();
+        let line = std::fs::read_to_string("tests/ed25519.pub").unwrap();
+        let (publickey, _) = PublicKey::from_openssh(&line).unwrap();
+        assert!(authorized_keys.contains(&publickey));
+
+        let other = crate::key::Key::gen(&crate::Key::SshEd25519).unwrap();
+        assert!(!authorized_keys.contains(&other.publickey()));
+
+        AuthorizedKeys::load("tests/not-found").await.unwrap_err();
+    }
 }
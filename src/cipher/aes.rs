@@ -56,7 +56,7 @@ where
     T: AesCipherTrait,
 {
     fn new(key: &[u8], iv: &[u8], mode: Mode) -> Result<Self, SshError> {
-        let crypter = Crypter::new(T::openssl_cipher(), mode, key, Some(&iv))
+        let crypter = Crypter::new(T::openssl_cipher(), mode, key, Some(iv))
             .map_err(SshError::cipher_error)?;
         Ok(Self {
             crypter,
@@ -95,7 +95,7 @@ where
             let b = &mut buf[..chunk.len()];
             b.clone_from_slice(chunk);
             self.crypter
-                .update(&b, chunk)
+                .update(b, chunk)
                 .map_err(SshError::cipher_error)?;
         }
         Ok(())
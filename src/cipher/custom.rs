@@ -0,0 +1,34 @@
+//! Ciphers registered by applications.
+use std::fmt;
+
+use crate::SshError;
+
+/// Creates [`CustomCipher`] instances for a registered cipher name.
+///
+/// Ciphers operate as stream ciphers over whole packets: [`CustomCipher::update`]
+/// is called on consecutive, not necessarily block aligned, byte ranges of one
+/// direction's stream and must carry its state over between calls.
+pub trait CipherFactory: fmt::Debug + Send + Sync + 'static {
+    /// Block size in bytes. Packets are padded to a multiple of it. (8 to 255)
+    fn block_size(&self) -> usize;
+
+    /// Key length in bytes derived by key exchange. (1 to 64)
+    fn key_length(&self) -> usize;
+
+    /// IV length in bytes derived by key exchange. (at most 64, default: block size)
+    fn iv_length(&self) -> usize {
+        self.block_size()
+    }
+
+    /// Instance encrypting server to client packets.
+    fn new_for_encrypt(&self, key: &[u8], iv: &[u8]) -> Result<Box<dyn CustomCipher>, SshError>;
+
+    /// Instance decrypting client to server packets.
+    fn new_for_decrypt(&self, key: &[u8], iv: &[u8]) -> Result<Box<dyn CustomCipher>, SshError>;
+}
+
+/// Running cipher state of one direction.
+pub trait CustomCipher: fmt::Debug + Send + Sync {
+    /// Encrypt or decrypt `target` in place. Its length never changes.
+    fn update(&mut self, target: &mut [u8]) -> Result<(), SshError>;
+}
@@ -6,10 +6,13 @@ use std::str::FromStr;
 
 use bytes::Bytes;
 
-use crate::negotiate::{AlgorithmName, UnknownNameError};
+use crate::negotiate::{AlgorithmName, Registered, UnknownNameError};
 use crate::SshError;
 
+pub use custom::{CipherFactory, CustomCipher};
+
 mod aes;
+mod custom;
 mod none;
 
 /// SSH cipher algorithms.
@@ -26,6 +29,9 @@ pub enum Algorithm {
 
     /// `aes256-ctr`
     Aes256Ctr,
+
+    /// Registered by application.
+    Custom(Registered<dyn CipherFactory>),
 }
 
 impl AsRef<str> for Algorithm {
@@ -35,6 +41,7 @@ impl AsRef<str> for Algorithm {
             Self::Aes128Ctr => "aes128-ctr",
             Self::Aes192Ctr => "aes192-ctr",
             Self::Aes256Ctr => "aes256-ctr",
+            Self::Custom(item) => item.name(),
         }
     }
 }
@@ -91,6 +98,9 @@ pub(crate) enum Cipher {
 
     /// `aes256-ctr` algorithm
     Aes256Ctr(aes::Aes256Ctr),
+
+    /// Registered algorithm and its block size
+    Custom(Box<dyn CustomCipher>, usize),
 }
 
 impl Cipher {
@@ -110,6 +120,10 @@ impl Cipher {
             Algorithm::Aes128Ctr => Ok(Self::Aes128Ctr(aes::Aes128Ctr::new_for_encrypt(key, iv)?)),
             Algorithm::Aes192Ctr => Ok(Self::Aes192Ctr(aes::Aes192Ctr::new_for_encrypt(key, iv)?)),
             Algorithm::Aes256Ctr => Ok(Self::Aes256Ctr(aes::Aes256Ctr::new_for_encrypt(key, iv)?)),
+            Algorithm::Custom(item) => Ok(Self::Custom(
+                item.factory().new_for_encrypt(key, iv)?,
+                item.factory().block_size(),
+            )),
         }
     }
 
@@ -124,6 +138,10 @@ impl Cipher {
             Algorithm::Aes128Ctr => Ok(Self::Aes128Ctr(aes::Aes128Ctr::new_for_decrypt(key, iv)?)),
             Algorithm::Aes192Ctr => Ok(Self::Aes192Ctr(aes::Aes192Ctr::new_for_decrypt(key, iv)?)),
             Algorithm::Aes256Ctr => Ok(Self::Aes256Ctr(aes::Aes256Ctr::new_for_decrypt(key, iv)?)),
+            Algorithm::Custom(item) => Ok(Self::Custom(
+                item.factory().new_for_decrypt(key, iv)?,
+                item.factory().block_size(),
+            )),
         }
     }
 
@@ -134,6 +152,15 @@ impl Cipher {
             Algorithm::Aes128Ctr => aes::Aes128Ctr::BLOCK_SIZE,
             Algorithm::Aes192Ctr => aes::Aes192Ctr::BLOCK_SIZE,
             Algorithm::Aes256Ctr => aes::Aes256Ctr::BLOCK_SIZE,
+            Algorithm::Custom(item) => item.factory().block_size(),
+        }
+    }
+
+    /// Get IV length by name
+    pub(crate) fn iv_length_by_name(name: &Algorithm) -> usize {
+        match name {
+            Algorithm::Custom(item) => item.factory().iv_length(),
+            x => Self::block_size_by_name(x),
         }
     }
 
@@ -144,6 +171,7 @@ impl Cipher {
             Algorithm::Aes128Ctr => aes::Aes128Ctr::KEY_LENGTH,
             Algorithm::Aes192Ctr => aes::Aes192Ctr::KEY_LENGTH,
             Algorithm::Aes256Ctr => aes::Aes256Ctr::KEY_LENGTH,
+            Algorithm::Custom(item) => item.factory().key_length(),
         }
     }
 
@@ -154,6 +182,7 @@ impl Cipher {
             Self::Aes128Ctr(..) => aes::Aes128Ctr::BLOCK_SIZE,
             Self::Aes192Ctr(..) => aes::Aes192Ctr::BLOCK_SIZE,
             Self::Aes256Ctr(..) => aes::Aes256Ctr::BLOCK_SIZE,
+            Self::Custom(_, block_size) => *block_size,
         }
     }
 
@@ -164,6 +193,7 @@ impl Cipher {
             Self::Aes128Ctr(item) => item.update(target),
             Self::Aes192Ctr(item) => item.update(target),
             Self::Aes256Ctr(item) => item.update(target),
+            Self::Custom(item, _) => item.update(target),
         }
     }
 }
@@ -226,6 +256,63 @@ mod tests {
         assert_eq!(&src, &result);
     }
 
+    #[derive(Debug)]
+    struct Rot(u8);
+
+    impl CustomCipher for Rot {
+        fn update(&mut self, target: &mut [u8]) -> Result<(), SshError> {
+            target.iter_mut().for_each(|b| *b = b.wrapping_add(self.0));
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct RotFactory;
+
+    impl CipherFactory for RotFactory {
+        fn block_size(&self) -> usize {
+            8
+        }
+
+        fn key_length(&self) -> usize {
+            1
+        }
+
+        fn iv_length(&self) -> usize {
+            0
+        }
+
+        fn new_for_encrypt(&self, key: &[u8], _: &[u8]) -> Result<Box<dyn CustomCipher>, SshError> {
+            Ok(Box::new(Rot(key[0])))
+        }
+
+        fn new_for_decrypt(&self, key: &[u8], _: &[u8]) -> Result<Box<dyn CustomCipher>, SshError> {
+            Ok(Box::new(Rot(0u8.wrapping_sub(key[0]))))
+        }
+    }
+
+    #[test]
+    fn test_custom() {
+        let name = &Algorithm::Custom(Registered::new("rot", std::sync::Arc::new(RotFactory)));
+        assert_eq!(name.as_ref(), "rot");
+        assert_eq!(Cipher::iv_length_by_name(name), 0);
+        assert_eq!(Cipher::iv_length_by_name(&Algorithm::Aes128Ctr), 16);
+
+        let k = Bytes::from_static(&[3]);
+        let src = BytesMut::from("Hello, world!");
+        let mut result = src.clone();
+
+        let mut encrypt = Cipher::new_for_encrypt(name, &k, &Bytes::new()).unwrap();
+        assert_eq!(encrypt.block_size(), 8);
+        encrypt.update(&mut result).unwrap();
+        assert_ne!(&src, &result);
+        Cipher::new_for_decrypt(name, &k, &Bytes::new())
+            .unwrap()
+            .update(&mut result)
+            .unwrap();
+        assert_eq!(&src, &result);
+    }
+
     #[test]
     fn test_parse() {
         for name in Algorithm::defaults() {
@@ -10,18 +10,25 @@ use crate::negotiate::{AlgorithmName, UnknownNameError};
 use crate::SshError;
 
 mod none;
+mod zlib;
 
 /// SSH compression algorithms.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Algorithm {
     /// `none`
     None,
+    /// `zlib`
+    Zlib,
+    /// `zlib@openssh.com`, enabled once the client is authenticated.
+    ZlibOpenssh,
 }
 
 impl AsRef<str> for Algorithm {
     fn as_ref(&self) -> &str {
         match self {
             Self::None => "none",
+            Self::Zlib => "zlib",
+            Self::ZlibOpenssh => "zlib@openssh.com",
         }
     }
 }
@@ -32,6 +39,8 @@ impl FromStr for Algorithm {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "none" => Ok(Self::None),
+            "zlib" => Ok(Self::Zlib),
+            "zlib@openssh.com" => Ok(Self::ZlibOpenssh),
             x => Err(UnknownNameError(x.into())),
         }
     }
@@ -39,29 +48,33 @@ impl FromStr for Algorithm {
 
 impl AlgorithmName for Algorithm {
     fn defaults() -> Vec<Self> {
-        vec![Self::None]
+        vec![Self::None, Self::ZlibOpenssh]
     }
 }
 
 /// Compression algorithm trait
 trait CompressionTrait: Sized {
     /// algorithm name
+    #[allow(dead_code)]
     const NAME: Algorithm;
 
     /// Create new instance
     fn new() -> Self;
 
     /// Compress target into bytes
-    fn compress(&self, target: &[u8]) -> Result<Bytes, SshError>;
+    fn compress(&mut self, target: &[u8]) -> Result<Bytes, SshError>;
 
     /// Decompress target into bytes
-    fn decompress(&self, target: &[u8]) -> Result<Bytes, SshError>;
+    fn decompress(&mut self, target: &[u8]) -> Result<Bytes, SshError>;
 }
 
 /// Compression algorithms
 #[derive(Debug)]
 pub(crate) enum Compression {
     None(none::None),
+    Zlib(zlib::Zlib),
+    /// `zlib@openssh.com` before authentication. Passes data through.
+    Delayed(zlib::Zlib),
 }
 
 impl Compression {
@@ -73,20 +86,40 @@ impl Compression {
     pub(crate) fn new(name: &Algorithm) -> Self {
         match name {
             Algorithm::None => Self::None(none::None::new()),
+            Algorithm::Zlib => Self::Zlib(zlib::Zlib::new()),
+            Algorithm::ZlibOpenssh => Self::Delayed(zlib::Zlib::new()),
+        }
+    }
+
+    /// Start delayed compression. Client was authenticated.
+    pub(crate) fn activate(&mut self) {
+        if let Self::Delayed(..) = self {
+            if let Self::Delayed(item) = std::mem::replace(self, Self::new_none()) {
+                *self = Self::Zlib(item);
+            }
         }
     }
 
     /// Compress target into bytes
-    pub(crate) fn compress(&self, target: &[u8]) -> Result<Bytes, SshError> {
+    pub(crate) fn compress(&mut self, target: &[u8]) -> Result<Bytes, SshError> {
         match self {
             Self::None(item) => item.compress(target),
+            Self::Zlib(item) => item.compress(target),
+            Self::Delayed(..) => Ok(Bytes::copy_from_slice(target)),
         }
     }
 
+    /// Data passes unchanged, so callers may skip the copy.
+    pub(crate) fn is_passthrough(&self) -> bool {
+        matches!(self, Self::None(..) | Self::Delayed(..))
+    }
+
     /// Decompress target into bytes
-    pub(crate) fn decompress(&self, target: &[u8]) -> Result<Bytes, SshError> {
+    pub(crate) fn decompress(&mut self, target: &[u8]) -> Result<Bytes, SshError> {
         match self {
             Self::None(item) => item.decompress(target),
+            Self::Zlib(item) => item.decompress(target),
+            Self::Delayed(..) => Ok(Bytes::copy_from_slice(target)),
         }
     }
 }
@@ -110,6 +143,80 @@ mod tests {
             assert_eq!(name, a);
         }
 
+        assert_eq!(Algorithm::from_str("zlib").unwrap(), Algorithm::Zlib);
         Algorithm::from_str("").unwrap_err();
     }
+
+    fn packets() -> Vec<Vec<u8>> {
+        (0..8u8)
+            .map(|n| {
+                let mut packet = b"\x5e channel data repeated channel data ".repeat(20);
+                packet.push(n);
+                packet
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_zlib_round_trip() {
+        let mut tx = Compression::new(&Algorithm::Zlib);
+        let mut rx = Compression::new(&Algorithm::Zlib);
+        for packet in packets() {
+            let compressed = tx.compress(&packet).unwrap();
+            assert!(compressed.len() < packet.len());
+            assert_eq!(&rx.decompress(&compressed).unwrap()[..], &packet[..]);
+        }
+        let empty = tx.compress(b"").unwrap();
+        assert_eq!(&rx.decompress(&empty).unwrap()[..], b"");
+
+        let large = (0..100_000u32)
+            .flat_map(|n| n.to_be_bytes())
+            .collect::<Vec<_>>();
+        let compressed = tx.compress(&large).unwrap();
+        assert_eq!(&rx.decompress(&compressed).unwrap()[..], &large[..]);
+    }
+
+    #[test]
+    fn test_zlib_continuous_stream() {
+        let mut tx = Compression::new(&Algorithm::Zlib);
+        let compressed = packets()
+            .iter()
+            .map(|packet| tx.compress(packet).unwrap())
+            .collect::<Vec<_>>();
+        // Later packets refer back to earlier ones.
+        assert!(compressed[1].len() < compressed[0].len());
+
+        let mut fresh = Compression::new(&Algorithm::Zlib);
+        assert!(fresh.decompress(&compressed[1]).is_err());
+
+        let mut rx = Compression::new(&Algorithm::Zlib);
+        for (packet, compressed) in packets().iter().zip(&compressed) {
+            assert_eq!(&rx.decompress(compressed).unwrap()[..], &packet[..]);
+        }
+    }
+
+    #[test]
+    fn test_zlib_too_large() {
+        let mut tx = Compression::new(&Algorithm::Zlib);
+        let compressed = tx.compress(&vec![0; 1024 * 1024]).unwrap();
+        let mut rx = Compression::new(&Algorithm::Zlib);
+        assert!(matches!(
+            rx.decompress(&compressed),
+            Err(SshError::TooLargePacket(..))
+        ));
+    }
+
+    #[test]
+    fn test_delayed() {
+        let mut tx = Compression::new(&Algorithm::ZlibOpenssh);
+        let mut rx = Compression::new(&Algorithm::ZlibOpenssh);
+        assert_eq!(&tx.compress(b"plain").unwrap()[..], b"plain");
+        assert_eq!(&rx.decompress(b"plain").unwrap()[..], b"plain");
+
+        tx.activate();
+        rx.activate();
+        let compressed = tx.compress(b"compressed").unwrap();
+        assert_ne!(&compressed[..], b"compressed");
+        assert_eq!(&rx.decompress(&compressed).unwrap()[..], b"compressed");
+    }
 }
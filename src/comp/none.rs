@@ -14,11 +14,11 @@ impl CompressionTrait for None {
         Self {}
     }
 
-    fn compress(&self, mut target: &[u8]) -> Result<Bytes, SshError> {
+    fn compress(&mut self, mut target: &[u8]) -> Result<Bytes, SshError> {
         Ok(target.copy_to_bytes(target.remaining()))
     }
 
-    fn decompress(&self, mut target: &[u8]) -> Result<Bytes, SshError> {
+    fn decompress(&mut self, mut target: &[u8]) -> Result<Bytes, SshError> {
         Ok(target.copy_to_bytes(target.remaining()))
     }
 }
@@ -0,0 +1,75 @@
+//! `zlib` compression algorithm
+//!
+//! One zlib stream per direction spans all packets, each ending with a sync flush.
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::*;
+
+/// Decompressed payloads larger than this are rejected.
+const MAXIMUM_PAYLOAD_SIZE: usize = 256 * 1024;
+
+/// `zlib` compression algorithm
+#[derive(Debug)]
+pub(crate) struct Zlib {
+    compress: Compress,
+    decompress: Decompress,
+}
+
+fn zlib_error(kind: &str) -> SshError {
+    SshError::Protocol(format!("zlib {} failed", kind))
+}
+
+impl CompressionTrait for Zlib {
+    const NAME: Algorithm = Algorithm::Zlib;
+
+    fn new() -> Self {
+        Self {
+            compress: Compress::new(flate2::Compression::default(), true),
+            decompress: Decompress::new(true),
+        }
+    }
+
+    fn compress(&mut self, target: &[u8]) -> Result<Bytes, SshError> {
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(target.len() + 64);
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&target[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|_| zlib_error("compress"))?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // Flush is complete once output stops short of capacity.
+            if consumed == target.len() && out.len() < out.capacity() {
+                return Ok(out.into());
+            }
+            out.reserve(out.capacity());
+        }
+    }
+
+    fn decompress(&mut self, target: &[u8]) -> Result<Bytes, SshError> {
+        let start = self.decompress.total_in();
+        let mut out = Vec::with_capacity(target.len() * 4 + 64);
+        loop {
+            let before = (self.decompress.total_in(), self.decompress.total_out());
+            let consumed = (before.0 - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&target[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| zlib_error("decompress"))?;
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let flushed = consumed == target.len() && out.len() < out.capacity();
+            if flushed || status == Status::StreamEnd {
+                return Ok(out.into());
+            }
+            if out.len() > MAXIMUM_PAYLOAD_SIZE {
+                return Err(SshError::TooLargePacket(out.len()));
+            }
+            if before == (self.decompress.total_in(), self.decompress.total_out())
+                && out.len() < out.capacity()
+            {
+                return Err(zlib_error("decompress"));
+            }
+            out.reserve(out.capacity());
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! Handler side access to a channel.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::sink::SinkExt as _;
+
+use crate::msg::channel_eof::ChannelEof;
+use crate::msg::channel_extended_data::{ChannelExtendedData, DataTypeCode};
+use crate::msg::channel_request::{ChannelRequest, ExitSignal, Type};
+use crate::{DisconnectReason, NegotiatedAlgorithms, Signal, SshError, SshInput, SshOutput};
+
+use super::detached::{DetachError, DetachedChannel};
+use super::global_handle::{Control, Identity};
+use super::memory::Memory;
+use super::reader_map::ReaderClosed;
+use super::responder::ChannelResponder;
+use super::run::MsgQueue;
+use super::scheduler::Priority;
+
+/// Channel request sent by handler and its reply slot.
+pub(crate) type Request = (u32, ChannelRequest, Option<oneshot::Sender<bool>>);
+
+/// Event loop access needed by [`DetachedChannel`].
+#[derive(Debug, Clone)]
+pub(crate) struct RawAccess {
+    control: mpsc::UnboundedSender<Control>,
+    queue: MsgQueue,
+    memory: Memory,
+}
+
+impl RawAccess {
+    pub(crate) fn new(
+        control: mpsc::UnboundedSender<Control>,
+        queue: MsgQueue,
+        memory: Memory,
+    ) -> Self {
+        Self {
+            control,
+            queue,
+            memory,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ChannelHandle {
+    channel: u32,
+    priority: Priority,
+    requests: mpsc::UnboundedSender<Request>,
+    exited: Arc<AtomicBool>,
+    raw: RawAccess,
+    detached: Arc<AtomicBool>,
+    identity: Identity,
+    /// Stdout and stderr, awaited before EOF.
+    outputs: Vec<ReaderClosed>,
+    eof: Arc<AtomicBool>,
+}
+
+impl ChannelHandle {
+    pub(crate) fn new(
+        channel: u32,
+        priority: Priority,
+        requests: mpsc::UnboundedSender<Request>,
+        exited: Arc<AtomicBool>,
+        raw: RawAccess,
+        identity: Identity,
+        outputs: Vec<ReaderClosed>,
+    ) -> Self {
+        Self {
+            channel,
+            priority,
+            requests,
+            exited,
+            raw,
+            detached: Default::default(),
+            identity,
+            outputs,
+            eof: Default::default(),
+        }
+    }
+
+    pub(crate) fn channel(&self) -> u32 {
+        self.channel
+    }
+
+    pub(crate) fn responder(&self) -> ChannelResponder {
+        ChannelResponder::new(self.channel, self.raw.control.clone())
+    }
+
+    pub(crate) fn detached(&self) -> bool {
+        self.detached.load(Ordering::SeqCst)
+    }
+
+    /// Detach channel from `stdio`. At most once per channel.
+    pub(crate) fn detach(
+        &self,
+        stdio: (SshInput, SshOutput, SshOutput),
+    ) -> Result<DetachedChannel, DetachError> {
+        if self.detached.swap(true, Ordering::SeqCst) {
+            return Err(DetachError::AlreadyDetached(self.channel));
+        }
+        let RawAccess {
+            control,
+            queue,
+            memory,
+        } = self.raw.clone();
+        DetachedChannel::new(self.channel, stdio, control, queue, memory)
+    }
+
+    /// Disconnect the whole connection, see [`GlobalHandle::disconnect`](crate::GlobalHandle::disconnect).
+    pub(crate) fn disconnect(
+        &self,
+        reason: DisconnectReason,
+        description: &str,
+    ) -> Result<(), SshError> {
+        self.raw
+            .control
+            .unbounded_send(Control::Disconnect(reason, description.into()))
+            .map_err(|_| SshError::ConnectionClosing)
+    }
+
+    /// Send EOF and close, resolving once the client's close arrived.
+    ///
+    /// Output queued afterwards is dropped, no exit status is reported
+    /// unless sent before.
+    pub(crate) async fn close(&self) -> Result<(), SshError> {
+        let (tx, rx) = oneshot::channel();
+        self.raw
+            .control
+            .unbounded_send(Control::Close(self.channel, tx))
+            .map_err(|_| SshError::ConnectionClosing)?;
+        rx.await.map_err(|_| SshError::ConnectionClosing)
+    }
+
+    pub(crate) fn client_version(&self) -> String {
+        self.identity.client_version()
+    }
+
+    pub(crate) fn algorithms(&self) -> Option<NegotiatedAlgorithms> {
+        self.identity.algorithms()
+    }
+
+    /// Authenticated user name. Channels only open after auth succeeded.
+    pub(crate) fn username(&self) -> String {
+        self.identity
+            .user()
+            .map(|(user_name, _)| user_name)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn set_priority(&self, weight: u32) {
+        self.priority.set(weight)
+    }
+
+    /// Queue `data` as stderr extended data, waiting while the queue is full.
+    pub(crate) async fn send_extended_data(&self, data: Bytes) -> Result<(), SshError> {
+        if self.eof.load(Ordering::SeqCst) {
+            return Err(SshError::EofAlreadySent(self.channel));
+        }
+        let charge = self.raw.memory.charge(data.len());
+        let msg = ChannelExtendedData::new(self.channel, DataTypeCode::Stderr, data).into();
+        self.raw
+            .queue
+            .clone()
+            .send((self.channel, msg, charge))
+            .await
+            .map_err(|_| SshError::ConnectionClosing)
+    }
+
+    /// Queue EOF once stdout and stderr were dropped and their output queued.
+    pub(crate) async fn send_eof(&self) -> Result<(), SshError> {
+        if self.eof.swap(true, Ordering::SeqCst) {
+            return Err(SshError::EofAlreadySent(self.channel));
+        }
+        for closed in self.outputs.clone() {
+            closed.await.ok();
+        }
+        let msg = ChannelEof::new(self.channel).into();
+        self.raw
+            .queue
+            .clone()
+            .send((self.channel, msg, self.raw.memory.charge(0)))
+            .await
+            .map_err(|_| SshError::ConnectionClosing)
+    }
+
+    pub(crate) async fn send_request(
+        &self,
+        name: &str,
+        want_reply: bool,
+        payload: Bytes,
+    ) -> Result<Option<bool>, SshError> {
+        self.send(Type::Unknown(name.into(), payload), want_reply)
+            .await
+    }
+
+    /// Queue `exit-status` now instead of on completion.
+    ///
+    /// With `drain`, first waits like [`Self::send_eof`] so output written
+    /// to stdout and stderr precedes it.
+    pub(crate) async fn send_exit_status(&self, status: u32, drain: bool) -> Result<(), SshError> {
+        if self.exited.swap(true, Ordering::SeqCst) {
+            return Err(SshError::ExitAlreadySent(self.channel));
+        }
+        self.send_exit(Type::ExitStatus(status), drain).await
+    }
+
+    /// Queue `exit-signal` instead of `exit-status` on completion.
+    pub(crate) async fn send_exit_signal(
+        &self,
+        signal: Signal,
+        core_dumped: bool,
+        message: &str,
+        drain: bool,
+    ) -> Result<(), SshError> {
+        if self.exited.swap(true, Ordering::SeqCst) {
+            return Err(SshError::ExitAlreadySent(self.channel));
+        }
+        let signal = ExitSignal::new(signal.to_string(), core_dumped, message.into(), "".into());
+        self.send_exit(Type::ExitSignal(signal), drain).await
+    }
+
+    /// Queue exit report behind output queued for this channel, like EOF.
+    async fn send_exit(&self, typ: Type, drain: bool) -> Result<(), SshError> {
+        // Detached output bypasses the pipes, it is queued already.
+        if drain && !self.detached() {
+            for closed in self.outputs.clone() {
+                closed.await.ok();
+            }
+        }
+        let msg = ChannelRequest::new(self.channel, false, typ).into();
+        self.raw
+            .queue
+            .clone()
+            .send((self.channel, msg, self.raw.memory.charge(0)))
+            .await
+            .map_err(|_| SshError::ConnectionClosing)
+    }
+
+    async fn send(&self, typ: Type, want_reply: bool) -> Result<Option<bool>, SshError> {
+        let msg = ChannelRequest::new(self.channel, want_reply, typ);
+
+        if want_reply {
+            let (tx, rx) = oneshot::channel();
+            self.requests
+                .unbounded_send((self.channel, msg, Some(tx)))
+                .map_err(|_| SshError::ConnectionClosing)?;
+            let reply = rx
+                .await
+                .map_err(|_| SshError::ChannelClosed(self.channel))?;
+            Ok(Some(reply))
+        } else {
+            self.requests
+                .unbounded_send((self.channel, msg, None))
+                .map_err(|_| SshError::ConnectionClosing)?;
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+
+    use crate::connection::memory::Charge;
+
+    fn channel_handle(requests: mpsc::UnboundedSender<Request>) -> ChannelHandle {
+        let (control, _) = mpsc::unbounded();
+        let (queue, _) = mpsc::channel(1);
+        let raw = RawAccess::new(control, queue, Memory::default());
+        let exited = Default::default();
+        ChannelHandle::new(
+            3,
+            Priority::default(),
+            requests,
+            exited,
+            raw,
+            Default::default(),
+            vec![],
+        )
+    }
+
+    fn queued_channel_handle() -> (
+        ChannelHandle,
+        mpsc::Receiver<(u32, crate::msg::Msg, Charge)>,
+    ) {
+        let (control, _) = mpsc::unbounded();
+        let (queue, queue_rx) = mpsc::channel(1);
+        let (requests, _) = mpsc::unbounded();
+        let raw = RawAccess::new(control, queue, Memory::default());
+        let handle = ChannelHandle::new(
+            3,
+            Priority::default(),
+            requests,
+            Default::default(),
+            raw,
+            Default::default(),
+            vec![],
+        );
+        (handle, queue_rx)
+    }
+
+    #[tokio::test]
+    async fn test_send_extended_data() {
+        let (control, _) = mpsc::unbounded();
+        let (queue, mut queue_rx) = mpsc::channel(0);
+        let (requests, _) = mpsc::unbounded();
+        let raw = RawAccess::new(control, queue, Memory::default());
+        let exited = Default::default();
+        let handle = ChannelHandle::new(
+            3,
+            Priority::default(),
+            requests,
+            exited,
+            raw,
+            Default::default(),
+            vec![],
+        );
+
+        // No room beyond the message itself, so the send waits until taken.
+        let mut send = handle
+            .send_extended_data(Bytes::from_static(b"oops"))
+            .boxed();
+        assert!(futures::poll!(&mut send).is_pending());
+        match queue_rx.next().await.unwrap() {
+            (3, crate::msg::Msg::ChannelExtendedData(msg), _) => {
+                assert_eq!(msg.data_type_code(), &DataTypeCode::Stderr);
+                assert_eq!(&msg.data()[..], b"oops");
+            }
+            x => panic!("{:?}", x),
+        }
+        send.await.unwrap();
+
+        drop(queue_rx);
+        assert!(handle.send_extended_data(Bytes::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_request_reply() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let handle = channel_handle(tx);
+
+        let peer = async move {
+            let (channel, msg, reply) = rx.next().await.unwrap();
+            assert_eq!(channel, 3);
+            assert!(*msg.want_reply());
+            reply.unwrap().send(false).unwrap();
+        };
+        let (result, _) = tokio::join!(
+            handle.send_request("x@example.com", true, Bytes::new()),
+            peer
+        );
+        assert_eq!(result.unwrap(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_send_request_no_reply() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let handle = channel_handle(tx);
+
+        let result = handle
+            .send_request("eow@openssh.com", false, Bytes::new())
+            .await;
+        assert_eq!(result.unwrap(), None);
+        assert!(rx.next().await.unwrap().2.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_request_closed() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let handle = channel_handle(tx);
+
+        let peer = async move {
+            rx.next().await.unwrap();
+        };
+        let (result, _) = tokio::join!(
+            handle.send_request("x@example.com", true, Bytes::new()),
+            peer
+        );
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_exit_signal_once() {
+        let (handle, mut queue_rx) = queued_channel_handle();
+
+        handle
+            .send_exit_signal(Signal::Term, true, "terminated", true)
+            .await
+            .unwrap();
+        match queue_rx.next().await.unwrap() {
+            (3, crate::msg::Msg::ChannelRequest(msg), _) => match msg.typ() {
+                Type::ExitSignal(signal) => {
+                    assert!(!*msg.want_reply());
+                    assert_eq!(signal.name(), "TERM");
+                    assert!(*signal.core_dump());
+                    assert_eq!(signal.error_message(), "terminated");
+                }
+                x => panic!("{:?}", x),
+            },
+            x => panic!("{:?}", x),
+        }
+
+        let err = handle.send_exit_signal(Signal::Kill, false, "", true).await;
+        assert!(matches!(err, Err(SshError::ExitAlreadySent(3))));
+        let err = handle.send_exit_status(1, true).await;
+        assert!(matches!(err, Err(SshError::ExitAlreadySent(3))));
+    }
+
+    #[tokio::test]
+    async fn test_send_exit_status_once() {
+        let (handle, mut queue_rx) = queued_channel_handle();
+
+        handle.send_exit_status(3, true).await.unwrap();
+        match queue_rx.next().await.unwrap() {
+            (3, crate::msg::Msg::ChannelRequest(msg), _) => {
+                assert!(!*msg.want_reply());
+                assert!(matches!(msg.typ(), Type::ExitStatus(3)));
+            }
+            x => panic!("{:?}", x),
+        }
+
+        let err = handle.send_exit_signal(Signal::Kill, false, "", true).await;
+        assert!(matches!(err, Err(SshError::ExitAlreadySent(3))));
+    }
+
+    #[tokio::test]
+    async fn test_send_exit_status_drain() {
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let (control, _) = mpsc::unbounded();
+        let (queue, mut queue_rx) = mpsc::channel(1);
+        let (requests, _) = mpsc::unbounded();
+        let raw = RawAccess::new(control, queue, Memory::default());
+        let handle = ChannelHandle::new(
+            3,
+            Priority::default(),
+            requests,
+            Default::default(),
+            raw,
+            Default::default(),
+            vec![closed_rx.shared()],
+        );
+
+        // Output still open, so the report waits behind it.
+        let mut send = handle.send_exit_status(0, true).boxed();
+        assert!(futures::poll!(&mut send).is_pending());
+        assert!(futures::poll!(queue_rx.next()).is_pending());
+        closed_tx.send(()).unwrap();
+        send.await.unwrap();
+        assert!(queue_rx.next().await.is_some());
+    }
+}
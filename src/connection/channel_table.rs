@@ -0,0 +1,80 @@
+//! Channel ids of one connection.
+use std::collections::HashMap;
+
+/// Allocates our channel ids and maps each to the client's id.
+///
+/// Every channel is addressed by our id internally. The client's id is only
+/// needed on the wire, as recipient of messages we send.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelTable {
+    next: u32,
+    remote: HashMap<u32, u32>,
+}
+
+impl ChannelTable {
+    /// Next id not `in_use` and not bound.
+    pub(crate) fn allocate<F>(&mut self, in_use: F) -> u32
+    where
+        F: Fn(u32) -> bool,
+    {
+        let mut id = self.next;
+        while in_use(id) || self.remote.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        self.next = id.wrapping_add(1);
+        id
+    }
+
+    /// Client addresses our channel `local` as `remote`.
+    pub(crate) fn bind(&mut self, local: u32, remote: u32) {
+        self.remote.insert(local, remote);
+    }
+
+    pub(crate) fn remote(&self, local: u32) -> Option<u32> {
+        self.remote.get(&local).copied()
+    }
+
+    /// Whether the client's `remote` still addresses one of our channels.
+    pub(crate) fn remote_in_use(&self, remote: u32) -> bool {
+        self.remote.values().any(|r| *r == remote)
+    }
+
+    /// Forget `local` once we sent its close.
+    pub(crate) fn release(&mut self, local: u32) -> Option<u32> {
+        self.remote.remove(&local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_table() {
+        let mut table = ChannelTable::default();
+        assert_eq!(table.allocate(|_| false), 0);
+        table.bind(0, 5);
+        assert_eq!(table.allocate(|id| id == 1), 2);
+        table.bind(2, 0);
+        assert_eq!(table.remote(0), Some(5));
+        assert_eq!(table.remote(2), Some(0));
+        assert!(table.remote_in_use(5));
+        assert!(!table.remote_in_use(2));
+
+        assert_eq!(table.release(0), Some(5));
+        assert_eq!(table.remote(0), None);
+        assert!(!table.remote_in_use(5));
+        assert_eq!(table.allocate(|_| false), 3);
+    }
+
+    #[test]
+    fn test_allocate_wraps() {
+        let mut table = ChannelTable {
+            next: u32::MAX,
+            ..Default::default()
+        };
+        table.bind(0, 0);
+        assert_eq!(table.allocate(|_| false), u32::MAX);
+        assert_eq!(table.allocate(|_| false), 1);
+    }
+}
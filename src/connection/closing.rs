@@ -0,0 +1,197 @@
+//! Close handshake of channels.
+use std::collections::{HashMap, HashSet};
+
+use futures::channel::oneshot;
+use tokio::time::{Duration, Instant};
+
+/// How long ids of closed channels stay reserved, and messages the client
+/// sent before seeing our close are dropped quietly.
+pub(crate) const CLOSE_GRACE: Duration = Duration::from_secs(10);
+
+/// Progress of one channel toward close, by our id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CloseState {
+    Open,
+    EofSent,
+    EofReceived,
+    CloseSent,
+    CloseReceived,
+    /// Both sides sent close.
+    Closed,
+}
+
+/// Close state of every channel, and closed channels until their grace ends.
+#[derive(Debug, Default)]
+pub(crate) struct Closing {
+    states: HashMap<u32, CloseState>,
+    /// Fully closed, with the end of the grace period.
+    closed: HashMap<u32, Instant>,
+    /// Channels whose handler has not completed yet.
+    running: HashSet<u32>,
+    /// Resolved once the client's close arrived.
+    waiters: HashMap<u32, Vec<oneshot::Sender<()>>>,
+}
+
+impl Closing {
+    pub(crate) fn state(&self, channel: u32) -> CloseState {
+        if self.closed.contains_key(&channel) {
+            return CloseState::Closed;
+        }
+        self.states
+            .get(&channel)
+            .copied()
+            .unwrap_or(CloseState::Open)
+    }
+
+    pub(crate) fn eof_sent(&mut self, channel: u32) {
+        if self.state(channel) == CloseState::Open {
+            self.states.insert(channel, CloseState::EofSent);
+        }
+    }
+
+    pub(crate) fn eof_received(&mut self, channel: u32) {
+        if self.state(channel) == CloseState::Open {
+            self.states.insert(channel, CloseState::EofReceived);
+        }
+    }
+
+    /// Our close went out. Nothing may be sent on `channel` afterwards.
+    pub(crate) fn close_sent(&mut self, channel: u32) {
+        match self.state(channel) {
+            CloseState::CloseReceived => self.finish(channel),
+            CloseState::CloseSent | CloseState::Closed => {}
+            _ => {
+                self.states.insert(channel, CloseState::CloseSent);
+            }
+        }
+    }
+
+    /// Client's close arrived. Returns whether ours is still due.
+    pub(crate) fn close_received(&mut self, channel: u32) -> bool {
+        for waiter in self.waiters.remove(&channel).into_iter().flatten() {
+            waiter.send(()).ok();
+        }
+        match self.state(channel) {
+            CloseState::CloseSent => {
+                self.finish(channel);
+                false
+            }
+            CloseState::CloseReceived | CloseState::Closed => false,
+            _ => {
+                self.states.insert(channel, CloseState::CloseReceived);
+                true
+            }
+        }
+    }
+
+    /// We sent close or will not send anything else.
+    pub(crate) fn is_closing(&self, channel: u32) -> bool {
+        matches!(
+            self.state(channel),
+            CloseState::CloseSent | CloseState::Closed
+        )
+    }
+
+    /// Closed within the grace period.
+    pub(crate) fn recently_closed(&self, channel: u32) -> bool {
+        self.closed
+            .get(&channel)
+            .is_some_and(|until| Instant::now() < *until)
+    }
+
+    /// Id not to be allocated again yet, the client may still address it.
+    pub(crate) fn reserved(&self, channel: u32) -> bool {
+        self.state(channel) != CloseState::Open
+    }
+
+    /// Resolve `waiter` once the client's close arrived.
+    pub(crate) fn wait(&mut self, channel: u32, waiter: oneshot::Sender<()>) {
+        match self.state(channel) {
+            CloseState::CloseReceived | CloseState::Closed => {
+                waiter.send(()).ok();
+            }
+            _ => self.waiters.entry(channel).or_default().push(waiter),
+        }
+    }
+
+    pub(crate) fn started(&mut self, channel: u32) {
+        self.running.insert(channel);
+    }
+
+    /// Handler of `channel` completed, its output ended.
+    pub(crate) fn finished(&mut self, channel: u32) {
+        self.running.remove(&channel);
+    }
+
+    /// Forget channels past their grace period whose handler completed.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        let running = &self.running;
+        self.closed
+            .retain(|channel, until| now < *until || running.contains(channel));
+    }
+
+    fn finish(&mut self, channel: u32) {
+        self.states.remove(&channel);
+        self.closed.insert(channel, Instant::now() + CLOSE_GRACE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_handshake() {
+        let mut closing = Closing::default();
+        assert_eq!(closing.state(0), CloseState::Open);
+        closing.eof_sent(0);
+        assert_eq!(closing.state(0), CloseState::EofSent);
+        closing.eof_received(0);
+        assert_eq!(closing.state(0), CloseState::EofSent);
+        closing.close_sent(0);
+        assert!(closing.is_closing(0));
+        assert!(!closing.close_received(0));
+        assert_eq!(closing.state(0), CloseState::Closed);
+        assert!(closing.recently_closed(0));
+        assert!(closing.reserved(0));
+
+        assert!(closing.close_received(1));
+        assert_eq!(closing.state(1), CloseState::CloseReceived);
+        assert!(!closing.is_closing(1));
+        closing.close_sent(1);
+        assert_eq!(closing.state(1), CloseState::Closed);
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut closing = Closing::default();
+        let (tx, mut rx) = oneshot::channel();
+        closing.wait(0, tx);
+        closing.close_sent(0);
+        assert_eq!(rx.try_recv(), Ok(None));
+        closing.close_received(0);
+        assert_eq!(rx.try_recv(), Ok(Some(())));
+
+        let (tx, mut rx) = oneshot::channel();
+        closing.wait(0, tx);
+        assert_eq!(rx.try_recv(), Ok(Some(())));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut closing = Closing::default();
+        for channel in 0..2 {
+            closing.close_sent(channel);
+            closing.close_received(channel);
+        }
+        closing.started(1);
+        closing.expire(Instant::now() + CLOSE_GRACE);
+        assert!(!closing.reserved(0));
+        assert_eq!(closing.state(0), CloseState::Open);
+        assert!(closing.reserved(1));
+
+        closing.finished(1);
+        closing.expire(Instant::now() + CLOSE_GRACE);
+        assert!(!closing.reserved(1));
+    }
+}
@@ -0,0 +1,329 @@
+//! Channel taken over by the handler at ChannelData payload level.
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::sink::{Sink, SinkExt as _};
+use futures::stream::Stream;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::msg::channel_data::ChannelData;
+use crate::{SshError, SshInput, SshOutput};
+
+use super::global_handle::Control;
+use super::memory::{Charge, Memory};
+use super::run::MsgQueue;
+
+/// Largest payload sent per ChannelData. Same as stdio pipe reads.
+const SEGMENT_SIZE: usize = 8 * 1024;
+
+/// Detach failure.
+#[derive(Debug, Error)]
+pub enum DetachError {
+    #[error("channel {0} already detached")]
+    AlreadyDetached(u32),
+
+    #[error("stdio of channel {0} already taken")]
+    StdioTaken(u32),
+
+    #[error("channel {0} closed")]
+    Closed(u32),
+}
+
+/// Channel whose data bypasses stdio pipes.
+///
+/// Stream of received ChannelData payloads and sink of payloads to send.
+/// Payloads are split into several ChannelData as needed. Data received
+/// before detach is yielded first, possibly in different chunks.
+///
+/// Window is replenished as frames are taken from the stream. Sends wait
+/// while the connection's send queue is full. Dropping it closes channel
+/// output; channel closes once the handler returns.
+#[derive(Debug)]
+pub struct DetachedChannel {
+    channel: u32,
+    buffered: Option<SshInput>,
+    /// Received payloads, charged until taken.
+    frames: mpsc::UnboundedReceiver<(Bytes, Charge)>,
+    control: mpsc::UnboundedSender<Control>,
+    queue: MsgQueue,
+    /// Rest of the last frame not yet queued.
+    unsent: Bytes,
+    memory: Memory,
+    /// Held so EOF is only sent once dropped.
+    outputs: Option<(SshOutput, SshOutput)>,
+}
+
+impl DetachedChannel {
+    pub(crate) fn new(
+        channel: u32,
+        (stdin, stdout, stderr): (SshInput, SshOutput, SshOutput),
+        control: mpsc::UnboundedSender<Control>,
+        queue: MsgQueue,
+        memory: Memory,
+    ) -> Result<Self, DetachError> {
+        let (tx, frames) = mpsc::unbounded();
+        control
+            .unbounded_send(Control::Detach(channel, tx))
+            .map_err(|_| DetachError::Closed(channel))?;
+        Ok(Self {
+            channel,
+            buffered: Some(stdin),
+            frames,
+            control,
+            queue,
+            unsent: Bytes::new(),
+            memory,
+            outputs: Some((stdout, stderr)),
+        })
+    }
+
+    pub fn channel(&self) -> u32 {
+        self.channel
+    }
+
+    /// Byte stream adapter, e.g. for [`tokio::io::copy`] to and from a process.
+    pub fn into_stream(self) -> ChannelStream {
+        ChannelStream {
+            inner: self,
+            pending: Bytes::new(),
+        }
+    }
+
+    /// Read whatever was written to stdin before detach, until its EOF.
+    fn poll_buffered(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, SshError>>> {
+        let stdin = match &mut self.buffered {
+            Some(stdin) => stdin,
+            None => return Poll::Ready(None),
+        };
+        let mut buf = [0; SEGMENT_SIZE];
+        let mut buf = ReadBuf::new(&mut buf);
+        match Pin::new(stdin).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                self.buffered = None;
+                Poll::Ready(None)
+            }
+            Poll::Ready(Ok(())) => Poll::Ready(Some(Ok(Bytes::copy_from_slice(buf.filled())))),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for DetachedChannel {
+    type Item = Result<Bytes, SshError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(item) = futures::ready!(this.poll_buffered(cx)) {
+            return Poll::Ready(Some(item));
+        }
+        let frame = futures::ready!(Pin::new(&mut this.frames).poll_next(cx));
+        if let Some((frame, _)) = &frame {
+            let consumed = Control::Consumed(this.channel, frame.len());
+            this.control.unbounded_send(consumed).ok();
+        }
+        Poll::Ready(frame.map(|(frame, _)| Ok(frame)))
+    }
+}
+
+impl Sink<Bytes> for DetachedChannel {
+    type Error = SshError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SshError>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Bytes) -> Result<(), SshError> {
+        debug_assert!(self.unsent.is_empty());
+        self.get_mut().unsent = frame;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SshError>> {
+        let this = self.get_mut();
+        while !this.unsent.is_empty() {
+            futures::ready!(this.queue.poll_ready(cx)).map_err(|_| SshError::ConnectionClosing)?;
+            let segment = this.unsent.split_to(this.unsent.len().min(SEGMENT_SIZE));
+            let charge = this.memory.charge(segment.len());
+            let msg = ChannelData::new(this.channel, segment).into();
+            this.queue
+                .start_send((this.channel, msg, charge))
+                .map_err(|_| SshError::ConnectionClosing)?;
+        }
+        this.queue
+            .poll_flush_unpin(cx)
+            .map_err(|_| SshError::ConnectionClosing)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SshError>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// [`DetachedChannel`] as [`AsyncRead`] and [`AsyncWrite`].
+///
+/// Reads yield received data until the client's EOF. Writes are sent as
+/// ChannelData. Shutdown sends EOF, the channel closes once the handler returns.
+#[derive(Debug)]
+pub struct ChannelStream {
+    inner: DetachedChannel,
+    /// Rest of a frame exceeding the last read buffer.
+    pending: Bytes,
+}
+
+impl ChannelStream {
+    pub fn channel(&self) -> u32 {
+        self.inner.channel
+    }
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pending.is_empty() {
+            match futures::ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(frame)) => this.pending = frame,
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.inner.outputs.is_none() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let mut inner = Pin::new(&mut this.inner);
+        futures::ready!(inner.as_mut().poll_ready(cx))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        inner
+            .as_mut()
+            .start_send(Bytes::copy_from_slice(buf))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        // Queue what fits now; the rest goes with the next write or flush.
+        let _ = inner.poll_flush(cx);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.as_mut().poll_flush(cx))?;
+        self.get_mut().inner.outputs = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::Msg;
+    use futures::prelude::*;
+    use tokio::io::AsyncWriteExt as _;
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let (r, mut w) = tokio_pipe::pipe().unwrap();
+        let (_, out) = tokio_pipe::pipe().unwrap();
+        let (_, err) = tokio_pipe::pipe().unwrap();
+        let stdio = (SshInput::new(r), SshOutput::new(out), SshOutput::new(err));
+        let (control_tx, mut control_rx) = mpsc::unbounded();
+        let (queue_tx, queue_rx) = mpsc::channel(8);
+        w.write_all(b"before").await.unwrap();
+
+        let mut detached =
+            DetachedChannel::new(3, stdio, control_tx, queue_tx, Memory::default()).unwrap();
+        let frames = match control_rx.next().await.unwrap() {
+            Control::Detach(3, frames) => frames,
+            x => panic!("{:?}", x),
+        };
+        let memory = Memory::default();
+        let frame = (Bytes::from_static(b"after"), memory.charge(5));
+        frames.unbounded_send(frame).unwrap();
+        drop(w);
+        drop(frames);
+
+        let received = (&mut detached).try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(received, vec![&b"before"[..], &b"after"[..]]);
+        assert_eq!(memory.used(), 0);
+        match control_rx.next().await.unwrap() {
+            Control::Consumed(3, 5) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let frame = Bytes::from(vec![1; SEGMENT_SIZE * 2 + 1]);
+        detached.send(frame).await.unwrap();
+        drop(detached);
+        let sizes = queue_rx
+            .map(|(_, msg, _)| match msg {
+                Msg::ChannelData(data) => data.data().len(),
+                x => panic!("{:?}", x),
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(sizes, vec![SEGMENT_SIZE, SEGMENT_SIZE, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        use tokio::io::AsyncReadExt as _;
+
+        let (r, w) = tokio_pipe::pipe().unwrap();
+        let (mut out_r, out) = tokio_pipe::pipe().unwrap();
+        let (_, err) = tokio_pipe::pipe().unwrap();
+        let stdio = (SshInput::new(r), SshOutput::new(out), SshOutput::new(err));
+        let (control_tx, mut control_rx) = mpsc::unbounded();
+        let (queue_tx, mut queue_rx) = mpsc::channel(8);
+        drop(w);
+
+        let detached =
+            DetachedChannel::new(3, stdio, control_tx, queue_tx, Memory::default()).unwrap();
+        let mut stream = detached.into_stream();
+        let frames = match control_rx.next().await.unwrap() {
+            Control::Detach(3, frames) => frames,
+            x => panic!("{:?}", x),
+        };
+        let frame = (Bytes::from_static(b"hello"), Memory::default().charge(5));
+        frames.unbounded_send(frame).unwrap();
+        drop(frames);
+
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hel");
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"lo");
+
+        stream.write_all(b"reply").await.unwrap();
+        match queue_rx.next().await.unwrap() {
+            (3, Msg::ChannelData(data), _) => assert_eq!(data.data(), &b"reply"[..]),
+            x => panic!("{:?}", x),
+        }
+        stream.shutdown().await.unwrap();
+        assert!(stream.write_all(b"late").await.is_err());
+        // Output pipe closed, so EOF is sent.
+        assert_eq!(out_r.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,612 @@
+//! Connection wide access for administrative code.
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use getset::Getters;
+
+use crate::msg::channel_open::{ForwardedStreamlocal, ForwardedTcpip, Type, X11};
+use crate::{DisconnectReason, NegotiatedAlgorithms, SshError, SshInput, SshOutput, WarningKind};
+
+use super::memory::{Charge, Memory};
+use super::timings::{PhaseTimings, Phases};
+use super::warning::Warnings;
+
+/// Channel type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    Session,
+    DirectTcpip,
+    /// Opened by us toward the client.
+    ForwardedTcpip,
+    /// Opened by us toward the client.
+    X11,
+    /// Opened by us toward the client.
+    AuthAgent,
+    DirectStreamlocal,
+    /// Opened by us toward the client.
+    ForwardedStreamlocal,
+    /// Other channel opened by us toward the client.
+    Outbound,
+}
+
+/// Request a session channel is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelMode {
+    Shell,
+    /// Command as sent, which need not be UTF-8.
+    Exec(OsString),
+    Subsystem(String),
+}
+
+impl ChannelMode {
+    /// Command of [`Self::Exec`], invalid UTF-8 replaced, e.g. for display.
+    pub fn command_lossy(&self) -> Option<Cow<'_, str>> {
+        match self {
+            Self::Exec(command) => Some(command.to_string_lossy()),
+            _ => None,
+        }
+    }
+}
+
+/// Channel lifecycle state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChannelState {
+    Open,
+    /// Either side sent EOF.
+    Eof,
+    /// Either side sent close.
+    Closing,
+}
+
+impl ChannelState {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => Self::Open,
+            1 => Self::Eof,
+            _ => Self::Closing,
+        }
+    }
+}
+
+/// Point in time view of one channel.
+#[derive(Debug, Clone, Getters)]
+pub struct ChannelInfoSnapshot {
+    #[get = "pub"]
+    id: u32,
+
+    #[get = "pub"]
+    kind: ChannelKind,
+
+    #[get = "pub"]
+    state: ChannelState,
+
+    /// `None` until shell, exec or subsystem was requested.
+    #[get = "pub"]
+    mode: Option<ChannelMode>,
+
+    /// Data bytes received from client.
+    #[get = "pub"]
+    bytes_received: u64,
+
+    /// Data bytes queued to client.
+    #[get = "pub"]
+    bytes_sent: u64,
+
+    #[get = "pub"]
+    opened_at: SystemTime,
+
+    #[get = "pub"]
+    last_activity: SystemTime,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Per-channel statistics updated by the event loop.
+#[derive(Debug)]
+pub(crate) struct ChannelStats {
+    kind: ChannelKind,
+    opened_at: SystemTime,
+    state: AtomicU8,
+    mode: Mutex<Option<ChannelMode>>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    last_activity: AtomicU64,
+}
+
+impl ChannelStats {
+    fn new(kind: ChannelKind) -> Self {
+        Self {
+            kind,
+            opened_at: SystemTime::now(),
+            state: AtomicU8::new(ChannelState::Open as u8),
+            mode: Mutex::new(None),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            last_activity: AtomicU64::new(now_millis()),
+        }
+    }
+
+    pub(crate) fn state(&self) -> ChannelState {
+        ChannelState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Advance state. Never moves backwards.
+    pub(crate) fn set_state(&self, state: ChannelState) {
+        self.state.fetch_max(state as u8, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub(crate) fn set_mode(&self, mode: ChannelMode) {
+        *self.mode.lock().unwrap() = Some(mode);
+        self.touch();
+    }
+
+    pub(crate) fn received(&self, n: usize) {
+        self.bytes_received.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub(crate) fn sent(&self, n: usize) {
+        self.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        self.last_activity.store(now_millis(), Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, id: u32) -> ChannelInfoSnapshot {
+        let last_activity =
+            UNIX_EPOCH + Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        ChannelInfoSnapshot {
+            id,
+            kind: self.kind,
+            state: self.state(),
+            mode: self.mode.lock().unwrap().clone(),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            opened_at: self.opened_at,
+            last_activity,
+        }
+    }
+}
+
+/// Open channels of one connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Registry(Arc<RwLock<HashMap<u32, Arc<ChannelStats>>>>);
+
+impl Registry {
+    pub(crate) fn open(&self, id: u32, kind: ChannelKind) -> Arc<ChannelStats> {
+        let stats = Arc::new(ChannelStats::new(kind));
+        self.0.write().unwrap().insert(id, stats.clone());
+        stats
+    }
+
+    pub(crate) fn get(&self, id: u32) -> Option<Arc<ChannelStats>> {
+        self.0.read().unwrap().get(&id).cloned()
+    }
+
+    pub(crate) fn remove(&self, id: u32) {
+        self.0.write().unwrap().remove(&id);
+    }
+}
+
+/// Session id, negotiated algorithms and authenticated user of one connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Identity(Arc<RwLock<IdentityState>>);
+
+#[derive(Debug, Default)]
+struct IdentityState {
+    client_version: String,
+    session_id: Option<Bytes>,
+    algorithms: Option<NegotiatedAlgorithms>,
+    user: Option<(String, &'static str)>,
+}
+
+impl Identity {
+    pub(crate) fn set_client_version(&self, client_version: &str) {
+        self.0.write().unwrap().client_version = client_version.into();
+    }
+
+    /// Record the outcome of the latest kex.
+    pub(crate) fn set_algorithms(&self, algorithms: &NegotiatedAlgorithms) {
+        self.0.write().unwrap().algorithms = Some(algorithms.clone());
+    }
+
+    /// Record the exchange hash of the first kex. Later calls are ignored.
+    pub(crate) fn set_session_id(&self, session_id: &[u8]) {
+        let mut state = self.0.write().unwrap();
+        state
+            .session_id
+            .get_or_insert_with(|| Bytes::copy_from_slice(session_id));
+    }
+
+    pub(crate) fn authenticate(&self, user_name: &str, method: &'static str) {
+        self.0.write().unwrap().user = Some((user_name.into(), method));
+    }
+
+    pub(crate) fn session_id(&self) -> Option<Bytes> {
+        self.0.read().unwrap().session_id.clone()
+    }
+
+    /// User name and method of the accepted auth attempt.
+    pub(crate) fn user(&self) -> Option<(String, &'static str)> {
+        self.0.read().unwrap().user.clone()
+    }
+
+    pub(crate) fn client_version(&self) -> String {
+        self.0.read().unwrap().client_version.clone()
+    }
+
+    pub(crate) fn algorithms(&self) -> Option<NegotiatedAlgorithms> {
+        self.0.read().unwrap().algorithms.clone()
+    }
+}
+
+/// Operation requested through [`GlobalHandle`].
+#[derive(Debug)]
+pub(crate) enum Control {
+    CloseChannel(u32, String),
+    /// Route channel data to the sender instead of stdin.
+    Detach(u32, mpsc::UnboundedSender<(Bytes, Charge)>),
+    /// Detached channel took bytes, window may be replenished.
+    Consumed(u32, usize),
+    /// Send disconnect and stop the connection.
+    Disconnect(DisconnectReason, String),
+    /// Send queued output, then disconnect.
+    Shutdown(DisconnectReason, String),
+    /// Open channel toward the client, replying once it answered.
+    Open(Type, OpenReply),
+    /// Send SSH_MSG_USERAUTH_BANNER.
+    Banner(String),
+    /// Send SSH_MSG_DEBUG.
+    Debug(bool, String),
+    /// Answer a channel request deferred by the handler.
+    Reply(u32, bool),
+    /// Send EOF and close for the channel's handler, replying once the
+    /// client's close arrived.
+    Close(u32, oneshot::Sender<()>),
+}
+
+/// Reply slot of a channel we opened.
+pub(crate) type OpenReply = oneshot::Sender<Result<(SshInput, SshOutput), SshError>>;
+
+/// Event loop side of [`GlobalHandle`].
+#[derive(Debug)]
+pub(crate) struct Controller {
+    pub(crate) registry: Registry,
+    pub(crate) warnings: Warnings,
+    pub(crate) phases: Phases,
+    pub(crate) identity: Identity,
+    pub(crate) memory: Memory,
+    pub(crate) control: mpsc::UnboundedReceiver<Control>,
+    /// For channel handles.
+    pub(crate) control_tx: mpsc::UnboundedSender<Control>,
+}
+
+#[cfg(test)]
+pub(crate) fn global_handle() -> (GlobalHandle, Controller) {
+    global_handle_with(Phases::new())
+}
+
+/// Handle and controller of a connection started as `phases` was created.
+pub(crate) fn global_handle_with(phases: Phases) -> (GlobalHandle, Controller) {
+    let (tx, rx) = mpsc::unbounded();
+    let controller = Controller {
+        registry: Registry::default(),
+        warnings: Warnings::default(),
+        phases,
+        identity: Identity::default(),
+        memory: Memory::default(),
+        control: rx,
+        control_tx: tx,
+    };
+    (controller.handle(), controller)
+}
+
+impl Controller {
+    /// Another handle to the same connection.
+    pub(crate) fn handle(&self) -> GlobalHandle {
+        GlobalHandle {
+            registry: self.registry.clone(),
+            warnings: self.warnings.clone(),
+            phases: self.phases.clone(),
+            identity: self.identity.clone(),
+            memory: self.memory.clone(),
+            control: self.control_tx.clone(),
+        }
+    }
+}
+
+/// Handle to inspect and manage a running connection.
+///
+/// Cheap to clone. Never blocks the event loop.
+#[derive(Debug, Clone)]
+pub struct GlobalHandle {
+    registry: Registry,
+    warnings: Warnings,
+    phases: Phases,
+    identity: Identity,
+    memory: Memory,
+    control: mpsc::UnboundedSender<Control>,
+}
+
+impl GlobalHandle {
+    /// Snapshot of currently open channels ordered by id.
+    pub fn channels(&self) -> Vec<ChannelInfoSnapshot> {
+        let mut channels = self
+            .registry
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, stats)| stats.snapshot(*id))
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|c| c.id);
+        channels
+    }
+
+    /// Number of tolerated protocol warnings per kind.
+    pub fn warnings(&self) -> HashMap<WarningKind, u64> {
+        self.warnings.counts()
+    }
+
+    /// Approximate bytes the connection currently buffers, as counted
+    /// against [`ServerBuilder::memory_limit`](crate::ServerBuilder::memory_limit).
+    pub fn memory_used(&self) -> usize {
+        self.memory.used()
+    }
+
+    /// Setup latency per protocol phase so far.
+    pub fn phase_timings(&self) -> PhaseTimings {
+        self.phases.timings()
+    }
+
+    /// Exchange hash of the first key exchange. `None` until it completed.
+    pub fn session_id(&self) -> Option<Bytes> {
+        self.identity.session_id()
+    }
+
+    /// Version line the client sent, e.g. `SSH-2.0-OpenSSH_8.9`.
+    pub fn client_version(&self) -> String {
+        self.identity.client_version()
+    }
+
+    /// Algorithms of the latest key exchange. `None` until the first completed.
+    pub fn algorithms(&self) -> Option<NegotiatedAlgorithms> {
+        self.identity.algorithms()
+    }
+
+    /// Authenticated user name. `None` until auth succeeded.
+    pub fn username(&self) -> Option<String> {
+        self.identity.user().map(|(user_name, _)| user_name)
+    }
+
+    /// Method the user authenticated with, e.g. `publickey`.
+    pub fn auth_method(&self) -> Option<&'static str> {
+        self.identity.user().map(|(_, method)| method)
+    }
+
+    /// Send SSH_MSG_DISCONNECT and stop the connection.
+    ///
+    /// Channels whose handler still runs get `exit-signal` KILL and close
+    /// first. [`Connection::run`](crate::Connection::run) returns `Ok` afterwards.
+    pub fn disconnect(&self, reason: DisconnectReason, description: &str) -> Result<(), SshError> {
+        self.control
+            .unbounded_send(Control::Disconnect(reason, description.into()))
+            .map_err(|_| SshError::ConnectionClosing)?;
+        Ok(())
+    }
+
+    /// Stop the connection gracefully, e.g. when an admin kicks the user or
+    /// the server shuts down.
+    ///
+    /// Handler output is no longer taken. Output already taken is sent as
+    /// far as the client's windows allow, channels whose handler still runs
+    /// get `exit-signal` KILL and close, then SSH_MSG_DISCONNECT, and
+    /// [`Connection::run`](crate::Connection::run) returns `Ok`.
+    pub fn shutdown(&self, reason: DisconnectReason, description: &str) -> Result<(), SshError> {
+        self.control
+            .unbounded_send(Control::Shutdown(reason, description.into()))
+            .map_err(|_| SshError::ConnectionClosing)?;
+        Ok(())
+    }
+
+    /// Send SSH_MSG_USERAUTH_BANNER, e.g. a legal notice.
+    ///
+    /// Held back until the client requested the `ssh-userauth` service and
+    /// dropped once authenticated. Banners sent from an auth handler reach
+    /// the client before that attempt's result.
+    pub fn send_banner(&self, message: &str) -> Result<(), SshError> {
+        self.control
+            .unbounded_send(Control::Banner(message.into()))
+            .map_err(|_| SshError::ConnectionClosing)?;
+        Ok(())
+    }
+
+    /// Send SSH_MSG_DEBUG, e.g. to explain a refusal to a user running
+    /// `ssh -v`.
+    ///
+    /// Clients show `message` in verbose mode only, or always if
+    /// `always_display` is set.
+    pub fn send_debug(&self, always_display: bool, message: &str) -> Result<(), SshError> {
+        self.control
+            .unbounded_send(Control::Debug(always_display, message.into()))
+            .map_err(|_| SshError::ConnectionClosing)?;
+        Ok(())
+    }
+
+    /// Open a `forwarded-tcpip` channel for a connection accepted on a
+    /// listener bound by [`Handlers::on_tcpip_forward`](crate::Handlers::on_tcpip_forward).
+    ///
+    /// `address` and `port` are the forwarded ones, the originator is the
+    /// peer of the accepted connection. Resolves once the client confirmed,
+    /// with data received from and sent to the client. Dropping the output
+    /// sends EOF, the channel closes once the client sent EOF as well.
+    pub async fn open_forwarded_tcpip(
+        &self,
+        address: &str,
+        port: u16,
+        originator_address: &str,
+        originator_port: u16,
+    ) -> Result<(SshInput, SshOutput), SshError> {
+        let item = ForwardedTcpip::new(
+            address.into(),
+            port as u32,
+            originator_address.into(),
+            originator_port as u32,
+        );
+        self.open(Type::ForwardedTcpip(item)).await
+    }
+
+    /// Open a `forwarded-streamlocal@openssh.com` channel for a connection
+    /// accepted on a Unix domain socket bound by
+    /// [`Handlers::on_streamlocal_forward`](crate::Handlers::on_streamlocal_forward).
+    ///
+    /// `socket_path` is the forwarded one. Resolves as
+    /// [`open_forwarded_tcpip`](Self::open_forwarded_tcpip) does.
+    pub async fn open_forwarded_streamlocal(
+        &self,
+        socket_path: &str,
+    ) -> Result<(SshInput, SshOutput), SshError> {
+        let item = ForwardedStreamlocal::new(socket_path.into(), "".into());
+        self.open(Type::ForwardedStreamlocal(item)).await
+    }
+
+    /// Open an `x11` channel for a local X client connected from
+    /// `originator_address` and `originator_port`, after the client's
+    /// `x11-req` was accepted by [`Handlers::on_channel_x11_request`](crate::Handlers::on_channel_x11_request).
+    ///
+    /// Resolves as [`open_forwarded_tcpip`](Self::open_forwarded_tcpip) does.
+    pub async fn open_x11(
+        &self,
+        originator_address: &str,
+        originator_port: u16,
+    ) -> Result<(SshInput, SshOutput), SshError> {
+        let item = X11::new(originator_address.into(), originator_port as u32);
+        self.open(Type::X11(item)).await
+    }
+
+    /// Open an `auth-agent@openssh.com` channel to the client's agent,
+    /// after the client's `auth-agent-req@openssh.com` was accepted by
+    /// [`Handlers::on_channel_agent_forward_request`](crate::Handlers::on_channel_agent_forward_request).
+    ///
+    /// The channel carries the raw ssh-agent protocol. Resolves as
+    /// [`open_forwarded_tcpip`](Self::open_forwarded_tcpip) does.
+    pub async fn open_agent(&self) -> Result<(SshInput, SshOutput), SshError> {
+        self.open(Type::AuthAgent(())).await
+    }
+
+    /// Open a channel of `channel_type` toward the client, e.g.
+    /// `tun@openssh.com`. `data` holds the type specific fields of
+    /// the open request.
+    ///
+    /// Resolves as [`open_forwarded_tcpip`](Self::open_forwarded_tcpip) does.
+    /// Fails with [`SshError::ChannelOpenFailed`] if the client refused or
+    /// did not answer within the
+    /// [channel open timeout](crate::ServerBuilder::channel_open_timeout).
+    pub async fn open_channel(
+        &self,
+        channel_type: &str,
+        data: Bytes,
+    ) -> Result<(SshInput, SshOutput), SshError> {
+        self.open(Type::Unknown(channel_type.into(), data)).await
+    }
+
+    async fn open(&self, typ: Type) -> Result<(SshInput, SshOutput), SshError> {
+        let (tx, rx) = oneshot::channel();
+        self.control
+            .unbounded_send(Control::Open(typ, tx))
+            .map_err(|_| SshError::ConnectionClosing)?;
+        rx.await.map_err(|_| SshError::ConnectionClosing)?
+    }
+
+    /// Close channel `id` as if its handler finished. `reason` is logged.
+    ///
+    /// No exit status is reported to client.
+    pub fn close_channel(&self, id: u32, reason: &str) -> Result<(), SshError> {
+        self.control
+            .unbounded_send(Control::CloseChannel(id, reason.into()))
+            .map_err(|_| SshError::ConnectionClosing)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+
+    #[test]
+    fn test_snapshot() {
+        let (handle, controller) = global_handle();
+        let shell = controller.registry.open(0, ChannelKind::Session);
+        shell.set_mode(ChannelMode::Shell);
+        shell.received(10);
+        shell.sent(20);
+        shell.set_state(ChannelState::Closing);
+        shell.set_state(ChannelState::Eof);
+        controller.registry.open(2, ChannelKind::DirectTcpip);
+
+        let channels = handle.channels();
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0].id(), &0);
+        assert_eq!(channels[0].mode(), &Some(ChannelMode::Shell));
+        assert_eq!(channels[0].state(), &ChannelState::Closing);
+        assert_eq!(channels[0].bytes_received(), &10);
+        assert_eq!(channels[0].bytes_sent(), &20);
+        assert!(
+            channels[0].last_activity() >= &(*channels[0].opened_at() - Duration::from_secs(1))
+        );
+        assert_eq!(channels[1].kind(), &ChannelKind::DirectTcpip);
+        assert_eq!(channels[1].mode(), &None);
+
+        controller.registry.remove(0);
+        assert_eq!(handle.channels().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_close_channel() {
+        let (handle, mut controller) = global_handle();
+        handle.close_channel(3, "admin").unwrap();
+        match controller.control.next().await.unwrap() {
+            Control::CloseChannel(id, reason) => {
+                assert_eq!(id, 3);
+                assert_eq!(reason, "admin");
+            }
+            x => panic!("{:?}", x),
+        }
+
+        drop(controller);
+        assert!(handle.close_channel(3, "admin").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let (handle, mut controller) = global_handle();
+        handle
+            .disconnect(DisconnectReason::ByApplication, "bye")
+            .unwrap();
+        match controller.control.next().await.unwrap() {
+            Control::Disconnect(DisconnectReason::ByApplication, description) => {
+                assert_eq!(description, "bye")
+            }
+            x => panic!("{:?}", x),
+        }
+
+        drop(controller);
+        assert!(handle
+            .disconnect(DisconnectReason::ByApplication, "bye")
+            .is_err());
+    }
+}
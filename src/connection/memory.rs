@@ -0,0 +1,176 @@
+//! Per-connection memory accounting.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Memory pressure level relative to the configured limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Pressure {
+    /// Below 75%.
+    Normal,
+    /// 75% or more. Stop growing buffers.
+    High,
+    /// 90% or more. Reject new channels.
+    Critical,
+    /// Over the limit. Disconnect.
+    Exceeded,
+}
+
+/// Approximate memory budget shared by one connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Memory {
+    used: Arc<AtomicUsize>,
+    limit: Option<usize>,
+}
+
+impl Memory {
+    /// Same counter, judged against `limit`.
+    pub(crate) fn with_limit(&self, limit: Option<usize>) -> Self {
+        Self {
+            used: self.used.clone(),
+            limit,
+        }
+    }
+
+    /// Currently charged bytes.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Charge `size` bytes. Credited when returned [`Charge`] is dropped.
+    pub(crate) fn charge(&self, size: usize) -> Charge {
+        self.used.fetch_add(size, Ordering::Relaxed);
+        Charge {
+            used: self.used.clone(),
+            size,
+        }
+    }
+
+    pub(crate) fn pressure(&self) -> Pressure {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Pressure::Normal,
+        };
+        let used = self.used();
+        if used > limit {
+            Pressure::Exceeded
+        } else if used >= limit / 10 * 9 {
+            Pressure::Critical
+        } else if used >= limit / 4 * 3 {
+            Pressure::High
+        } else {
+            Pressure::Normal
+        }
+    }
+}
+
+/// Charged bytes. Credit on drop.
+#[derive(Debug)]
+pub(crate) struct Charge {
+    used: Arc<AtomicUsize>,
+    size: usize,
+}
+
+impl Charge {
+    /// Currently charged bytes.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Charge `size` bytes instead, e.g. after the buffer it stands for changed.
+    pub(crate) fn resize(&mut self, size: usize) {
+        if size > self.size {
+            self.used.fetch_add(size - self.size, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(self.size - size, Ordering::Relaxed);
+        }
+        self.size = size;
+    }
+
+    /// Move `size` of the charged bytes to a charge of their own.
+    pub(crate) fn split_to(&mut self, size: usize) -> Self {
+        let size = size.min(self.size);
+        self.size -= size;
+        Self {
+            used: self.used.clone(),
+            size,
+        }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charge_credit() {
+        let memory = Memory::default().with_limit(Some(100));
+        let a = memory.charge(10);
+        let b = memory.clone().charge(20);
+        assert_eq!(memory.used(), 30);
+        drop(a);
+        assert_eq!(memory.used(), 20);
+        drop(b);
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[test]
+    fn test_charge_split() {
+        let memory = Memory::default();
+        let mut a = memory.charge(10);
+        let b = a.split_to(4);
+        assert_eq!(memory.used(), 10);
+        drop(a);
+        assert_eq!(memory.used(), 4);
+        drop(b);
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[test]
+    fn test_charge_resize() {
+        let memory = Memory::default();
+        let mut a = memory.charge(10);
+        a.resize(25);
+        assert_eq!(memory.used(), 25);
+        a.resize(5);
+        assert_eq!((a.size(), memory.used()), (5, 5));
+        drop(a);
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[test]
+    fn test_pressure() {
+        let memory = Memory::default().with_limit(Some(100));
+        assert_eq!(memory.pressure(), Pressure::Normal);
+        let a = memory.charge(75);
+        assert_eq!(memory.pressure(), Pressure::High);
+        let b = memory.charge(15);
+        assert_eq!(memory.pressure(), Pressure::Critical);
+        let c = memory.charge(11);
+        assert_eq!(memory.pressure(), Pressure::Exceeded);
+        drop((a, b, c));
+        assert_eq!(memory.pressure(), Pressure::Normal);
+    }
+
+    #[test]
+    fn test_with_limit() {
+        let memory = Memory::default();
+        let limited = memory.with_limit(Some(10));
+        let _a = memory.charge(8);
+        assert_eq!(limited.used(), 8);
+        assert_eq!(limited.pressure(), Pressure::High);
+        assert_eq!(memory.pressure(), Pressure::Normal);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let memory = Memory::default();
+        let _a = memory.charge(usize::MAX / 2);
+        assert_eq!(memory.pressure(), Pressure::Normal);
+    }
+}
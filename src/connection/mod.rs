@@ -1,20 +1,43 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+use crate::trace::{self, debug, Instrument as _, Span};
 use tokio::io::{self, AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
+use crate::factory::{ConnectionInfo, HandlerFactory};
 use crate::handlers::{HandlerError, Handlers};
 use crate::preference::Preference;
 use crate::stream::msg::MsgStream;
 use crate::SshError;
+pub(crate) use channel_handle::ChannelHandle;
+pub use detached::{ChannelStream, DetachError, DetachedChannel};
+pub use global_handle::{
+    ChannelInfoSnapshot, ChannelKind, ChannelMode, ChannelState, GlobalHandle,
+};
+pub use responder::ChannelResponder;
+pub(crate) use responder::ReplySlot;
 pub use ssh_stream::{SshInput, SshOutput};
+pub use timings::PhaseTimings;
+use timings::{Phase, Phases};
+pub use warning::{ProtocolWarning, WarningKind};
 
+mod channel_handle;
+mod channel_table;
+mod closing;
 mod completion_stream;
+mod detached;
+mod global_handle;
+pub(crate) mod memory;
 mod reader_map;
+mod responder;
 mod run;
+mod scheduler;
 mod ssh_stream;
+mod timings;
 mod version_ex;
+mod warning;
+pub(crate) mod window;
 
 /// Protocol Version Exchange
 ///
@@ -26,14 +49,22 @@ where
 {
     io: IO,
     preference: Arc<Preference>,
+    phases: Phases,
+    span: Span,
 }
 
 impl<IO> Accept<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    pub(crate) fn new(io: IO, preference: Arc<Preference>) -> Self {
-        Accept { io, preference }
+    pub(crate) fn new(io: IO, preference: Arc<Preference>, remote: Option<&str>) -> Self {
+        let phases = Phases::new();
+        Accept {
+            io,
+            preference,
+            phases,
+            span: trace::connection_span(remote),
+        }
     }
 }
 
@@ -45,19 +76,46 @@ where
     io: MsgStream<IO>,
     c_version: String,
     s_version: String,
+    quirks: Vec<String>,
     preference: Arc<Preference>,
+    handle: GlobalHandle,
+    controller: global_handle::Controller,
+    span: Span,
+    /// User authenticated without auth exchange, for replays.
+    user: Option<String>,
 }
 
 impl<IO> Established<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    fn new(io: IO, c_version: String, s_version: String, preference: Arc<Preference>) -> Self {
+    fn new(
+        io: IO,
+        c_version: String,
+        s_version: String,
+        preference: Arc<Preference>,
+        phases: Phases,
+        span: Span,
+    ) -> Self {
+        phases.mark(Phase::Established);
+        let (handle, controller) = global_handle::global_handle_with(phases);
+        let (preference, quirks) = match preference.for_client(&c_version) {
+            Some((preference, quirks)) => {
+                debug!("client quirks: {:?}", quirks);
+                (Arc::new(preference), quirks)
+            }
+            None => (preference, vec![]),
+        };
         Self {
             io: MsgStream::new(io),
             c_version,
             s_version,
+            quirks,
             preference,
+            handle,
+            controller,
+            span,
+            user: None,
         }
     }
 }
@@ -82,18 +140,32 @@ impl<IO> Connection<Accept<IO>>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    pub(crate) fn new(io: IO, preference: Arc<Preference>) -> Self {
-        let state = Accept::new(io, preference);
+    /// Connection over `io`, from `remote` if the transport has an address.
+    pub(crate) fn new(io: IO, preference: Arc<Preference>, remote: Option<&str>) -> Self {
+        let state = Accept::new(io, preference, remote);
         Self { state }
     }
 
     /// Performe SSH version exchange.
     pub async fn accept(self) -> Result<Connection<Established<IO>>, SshError> {
-        let Accept { mut io, preference } = self.state;
-        let (c_version, s_version) = version_ex::vex(&mut io, preference.name()).await?;
-        Ok(Connection {
-            state: Established::new(io, c_version, s_version, preference),
-        })
+        let Accept {
+            mut io,
+            preference,
+            phases,
+            span,
+        } = self.state;
+        let await_first = preference.stealth().await_client_banner_first;
+        let pre_banner = *preference.pre_banner_limit();
+        let deadline = phases.started() + *preference.handshake_timeout();
+        let vex = version_ex::vex(&mut io, preference.version(), await_first, pre_banner)
+            .instrument(span.clone());
+        let (c_version, s_version) = tokio::time::timeout_at(deadline, vex)
+            .await
+            .map_err(|_| SshError::HandshakeTimeout)??;
+        let state = span.in_scope(|| {
+            Established::new(io, c_version, s_version, preference, phases, span.clone())
+        });
+        Ok(Connection { state })
     }
 }
 
@@ -101,10 +173,62 @@ impl<IO> Connection<Established<IO>>
 where
     IO: AsyncRead + AsyncWrite + Unpin + Send,
 {
+    /// Connection past version exchange without a peer handshake,
+    /// authenticated as `user` if given.
+    #[cfg(feature = "replay")]
+    pub(crate) fn established(
+        io: IO,
+        c_version: String,
+        s_version: String,
+        preference: Arc<Preference>,
+        user: Option<String>,
+    ) -> Self {
+        let mut state = Established::new(
+            io,
+            c_version,
+            s_version,
+            preference,
+            Phases::new(),
+            trace::connection_span(None),
+        );
+        state.user = user;
+        Connection { state }
+    }
+
     pub fn client_version(&self) -> &str {
         &self.state.c_version
     }
 
+    /// Get connection information.
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo::new(
+            self.state.c_version.clone(),
+            self.state.s_version.clone(),
+            self.state.quirks.clone(),
+        )
+    }
+
+    /// Record all messages exchanged from now on.
+    #[cfg(feature = "replay")]
+    pub fn record(&mut self, recorder: crate::ReplayRecorder) {
+        self.state.io.set_recorder(recorder);
+    }
+
+    /// Get handle to inspect and manage this connection while running.
+    pub fn handle(&self) -> GlobalHandle {
+        self.state.handle.clone()
+    }
+
+    /// Run with [`Handlers`] created by [`HandlerFactory`]
+    pub async fn run_with<F, E, Pty>(self, factory: &F) -> Result<(), SshError>
+    where
+        F: HandlerFactory<E, Pty>,
+        E: Into<HandlerError> + Send + 'static,
+    {
+        let handlers = factory.create(&self.info());
+        self.run(handlers).await
+    }
+
     /// Run with [`Handlers`]
     pub async fn run<E, Pty>(self, handler: Handlers<E, Pty>) -> Result<(), SshError>
     where
@@ -115,10 +239,279 @@ where
             c_version,
             s_version,
             preference,
+            controller,
+            span,
+            user,
+            ..
         } = self.state;
 
-        run::Runner::new(io, c_version, s_version, preference, handler)
-            .run()
+        let mut runner =
+            run::Runner::new(io, c_version, s_version, preference, handler, controller);
+        if let Some(user) = user {
+            runner = runner.authenticated_as(&user);
+        }
+        runner.in_span(span.clone()).run().instrument(span).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::FutureExt as _;
+    use tokio::time::Duration;
+
+    use crate::msg::channel_close::ChannelClose;
+    use crate::msg::channel_request::Type;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::{duplex_pair, ScriptedClient};
+    use crate::{kex, PasswordResult, SessionContext};
+
+    #[tokio::test]
+    async fn test_handshake_auth_exec() {
+        use tokio::io::AsyncWriteExt as _;
+
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let mut client_preference = PreferenceBuilder::default();
+        client_preference.add_kex_algorithm(kex::Algorithm::Curve25519Sha256);
+        let c_kexinit = client_preference
+            .build()
+            .await
+            .unwrap()
+            .to_kexinit()
+            .unwrap();
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_password(|_, password| {
+            let result = if password == "secret" {
+                PasswordResult::Ok
+            } else {
+                PasswordResult::Failure
+            };
+            futures::future::ok(result).boxed()
+        });
+        handlers.on_channel_exec(|mut ctx: SessionContext, command: std::ffi::OsString| {
+            let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                stdout
+                    .write_all(command.to_str().unwrap().as_bytes())
+                    .await?;
+                Ok(3)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = duplex_pair();
+        let server = async {
+            let connection = Connection::new(ours, preference.clone(), None);
+            connection.accept().await?.run(handlers).await
+        };
+        let client = async {
+            let mut client = ScriptedClient::connect(theirs, "SSH-2.0-client").await;
+            assert_eq!(client.server_version(), preference.version());
+            client.handshake(c_kexinit, &preference).await;
+
+            client.send_raw(b"\x05\x00\x00\x00\x0cssh-userauth").await;
+            client.expect_raw(b"\x06\x00\x00\x00\x0cssh-userauth").await;
+            client
+                .send_raw(
+                    b"\x32\x00\x00\x00\x05alice\x00\x00\x00\x0essh-connection\
+                      \x00\x00\x00\x08password\x00\x00\x00\x00\x06secret",
+                )
+                .await;
+            client.expect_raw(b"\x34").await;
+
+            client
+                .send_raw(
+                    b"\x5a\x00\x00\x00\x07session\x00\x00\x00\x00\x00\x20\x00\x00\x00\x00\x80\x00",
+                )
+                .await;
+            let channel = client
+                .expect(|msg| match msg {
+                    Msg::ChannelOpenConfirmation(msg) => Some(*msg.sender_channel()),
+                    _ => None,
+                })
+                .await;
+            assert_eq!(channel, 0);
+            client
+                .send_raw(b"\x62\x00\x00\x00\x00\x00\x00\x00\x04exec\x01\x00\x00\x00\x05hello")
+                .await;
+
+            let mut output = vec![];
+            let mut status = None;
+            loop {
+                match client.recv().await.unwrap() {
+                    Msg::ChannelData(msg) => output.extend_from_slice(msg.data()),
+                    Msg::ChannelRequest(msg) => {
+                        if let Type::ExitStatus(code) = msg.typ() {
+                            status = Some(*code);
+                        }
+                    }
+                    Msg::ChannelClose(..) => break,
+                    _ => {}
+                }
+            }
+            client.send(ChannelClose::new(channel).into()).await;
+            client.close().await;
+            (output, status)
+        };
+        let (result, (output, status)) = tokio::join!(server, client);
+        result.unwrap();
+        assert_eq!(output, b"hello");
+        assert_eq!(status, Some(3));
+    }
+
+    #[derive(Debug)]
+    struct X25519Factory;
+
+    #[derive(Debug)]
+    struct X25519(ring::agreement::EphemeralPrivateKey);
+
+    impl crate::KexFactory for X25519Factory {
+        fn hash(&self) -> crate::KexHash {
+            crate::KexHash::Sha256
+        }
+
+        fn create(&self) -> Result<Box<dyn crate::CustomKex>, SshError> {
+            let rand = ring::rand::SystemRandom::new();
+            let private =
+                ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rand)
+                    .map_err(SshError::kex_error)?;
+            Ok(Box::new(X25519(private)))
+        }
+    }
+
+    impl crate::CustomKex for X25519 {
+        fn public_key(&self) -> bytes::Bytes {
+            let public = self.0.compute_public_key().unwrap();
+            bytes::Bytes::copy_from_slice(public.as_ref())
+        }
+
+        fn agree(self: Box<Self>, client_public_key: &[u8]) -> Result<bytes::Bytes, SshError> {
+            use ring::agreement::{agree_ephemeral, UnparsedPublicKey, X25519};
+
+            let peer = UnparsedPublicKey::new(&X25519, client_public_key);
+            agree_ephemeral(self.0, &peer, ring::error::Unspecified, |k| {
+                Ok(bytes::Bytes::copy_from_slice(k))
+            })
+            .map_err(SshError::kex_error)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_kex_handshake() {
+        let mut preference = PreferenceBuilder::default();
+        preference
+            .register_kex("x25519@example.com", Arc::new(X25519Factory))
+            .unwrap();
+        assert!(matches!(
+            preference.register_kex("curve25519-sha256", Arc::new(X25519Factory)),
+            Err(SshError::AlgorithmExists(..))
+        ));
+        preference
+            .kex_algorithms(&["x25519@example.com", "curve25519-sha256"])
+            .unwrap();
+        let preference = Arc::new(preference.build().await.unwrap());
+        let custom = preference.kex_algorithms()[0].clone();
+        let mut client_preference = PreferenceBuilder::default();
+        client_preference.add_kex_algorithm(custom.clone());
+        let c_kexinit = client_preference
+            .build()
+            .await
+            .unwrap()
+            .to_kexinit()
+            .unwrap();
+
+        let (ours, theirs) = duplex_pair();
+        let server = async {
+            let connection = Connection::new(ours, preference.clone(), None);
+            connection
+                .accept()
+                .await?
+                .run(Handlers::<anyhow::Error>::new())
+                .await
+        };
+        let client = async {
+            let mut client = ScriptedClient::connect(theirs, "SSH-2.0-client").await;
+            client.handshake(c_kexinit, &preference).await;
+            client.send_raw(b"\x05\x00\x00\x00\x0cssh-userauth").await;
+            client.expect_raw(b"\x06\x00\x00\x00\x0cssh-userauth").await;
+            client.close().await;
+        };
+        let (result, ()) = tokio::join!(server, client);
+        result.unwrap();
+        assert_eq!(custom.as_ref(), "x25519@example.com");
+    }
+
+    /// Serve `io` with `preference`, returning how long until it ended.
+    async fn timed_serve(
+        io: tokio::io::DuplexStream,
+        preference: Arc<Preference>,
+    ) -> (Result<(), SshError>, Duration) {
+        let started = tokio::time::Instant::now();
+        let connection = Connection::new(io, preference, None);
+        let result = match connection.accept().await {
+            Ok(connection) => connection.run(Handlers::<anyhow::Error>::new()).await,
+            Err(err) => Err(err),
+        };
+        (result, started.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_version_exchange_timeout() {
+        let mut preference = PreferenceBuilder::default();
+        preference.handshake_timeout(Duration::from_secs(20));
+        let preference = Arc::new(preference.build().await.unwrap());
+
+        let (ours, _theirs) = duplex_pair();
+        let (result, elapsed) = timed_serve(ours, preference).await;
+        assert!(matches!(result, Err(SshError::HandshakeTimeout)));
+        assert_eq!(elapsed.as_secs(), 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_kex_timeout() {
+        let mut preference = PreferenceBuilder::default();
+        preference.handshake_timeout(Duration::from_secs(20));
+        let preference = Arc::new(preference.build().await.unwrap());
+
+        let (ours, theirs) = duplex_pair();
+        let client = async {
+            let client = ScriptedClient::connect(theirs, "SSH-2.0-client").await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            client
+        };
+        let ((result, elapsed), _client) = tokio::join!(timed_serve(ours, preference), client);
+        assert!(matches!(result, Err(SshError::HandshakeTimeout)));
+        assert_eq!(elapsed.as_secs(), 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_auth_timeout() {
+        let mut preference = PreferenceBuilder::default();
+        preference.handshake_timeout(Duration::from_secs(20));
+        preference.auth_timeout(Duration::from_secs(60));
+        let preference = Arc::new(preference.build().await.unwrap());
+        let mut client_preference = PreferenceBuilder::default();
+        client_preference.add_kex_algorithm(kex::Algorithm::Curve25519Sha256);
+        let c_kexinit = client_preference
+            .build()
             .await
+            .unwrap()
+            .to_kexinit()
+            .unwrap();
+
+        let (ours, theirs) = duplex_pair();
+        let client = async {
+            let mut client = ScriptedClient::connect(theirs, "SSH-2.0-client").await;
+            client.handshake(c_kexinit, &preference).await;
+            client.send_raw(b"\x05\x00\x00\x00\x0cssh-userauth").await;
+            client.expect_raw(b"\x06\x00\x00\x00\x0cssh-userauth").await;
+            client
+        };
+        let ((result, elapsed), _client) =
+            tokio::join!(timed_serve(ours, preference.clone()), client);
+        assert!(matches!(result, Err(SshError::AuthTimeout)));
+        assert_eq!(elapsed.as_secs(), 60);
     }
 }
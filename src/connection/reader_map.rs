@@ -5,13 +5,19 @@ use std::task::{Context, Poll};
 
 use bytes::{BufMut as _, Bytes, BytesMut};
 use futures::channel::oneshot;
+use futures::future::{FutureExt as _, Shared};
 use futures::stream::Stream;
 use tokio::io::{self, AsyncRead, ReadBuf};
 
+/// Resolves once a reader reached EOF and its output was taken.
+pub(crate) type ReaderClosed = Shared<oneshot::Receiver<()>>;
+
 #[derive(Debug)]
 pub(crate) struct ReaderMap<K, V> {
     entries: Vec<(K, V, oneshot::Sender<()>)>,
     buf: BytesMut,
+    /// Entry polled first, so a reader always ready cannot starve the rest.
+    next: usize,
 }
 
 impl<K, V> ReaderMap<K, V> {
@@ -19,16 +25,17 @@ impl<K, V> ReaderMap<K, V> {
         Self {
             entries: vec![],
             buf: BytesMut::with_capacity(8 * 1024),
+            next: 0,
         }
     }
 
-    pub(crate) fn insert(&mut self, k: K, reader: V) -> oneshot::Receiver<()>
+    pub(crate) fn insert(&mut self, k: K, reader: V) -> ReaderClosed
     where
         K: Hash + Eq,
     {
         let (tx, rx) = oneshot::channel();
         self.entries.push((k, reader, tx));
-        rx
+        rx.shared()
     }
 }
 
@@ -43,9 +50,12 @@ where
         let Self {
             ref mut entries,
             ref mut buf,
+            ref mut next,
         } = self.get_mut();
 
-        for n in 0..entries.len() {
+        let len = entries.len();
+        for i in 0..len {
+            let n = (*next + i) % len;
             let (k, reader, _) = &mut entries[n];
             buf.clear();
 
@@ -54,6 +64,7 @@ where
             let mut buf = ReadBuf::uninit(dst);
             match Pin::new(reader).poll_read(cx, &mut buf)? {
                 Poll::Ready(()) => {
+                    *next = n + 1;
                     if buf.filled().is_empty() {
                         let (k, _, close_notify) = entries.swap_remove(n);
                         close_notify.send(()).ok();
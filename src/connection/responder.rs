@@ -0,0 +1,67 @@
+//! Deferred reply to a session channel's shell, exec or subsystem request.
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+
+use crate::SshError;
+
+use super::global_handle::Control;
+
+/// Reply slot shared by a [`SessionContext`](crate::SessionContext) and the
+/// connection until the handler was called.
+pub(crate) type ReplySlot = Arc<Mutex<Option<ChannelResponder>>>;
+
+/// Answers a channel request once the handler knows the outcome, e.g. after
+/// spawning the process.
+///
+/// Taken with [`SessionContext::defer_reply`](crate::SessionContext::defer_reply).
+/// Replies failure if dropped unresolved.
+#[derive(Debug)]
+pub struct ChannelResponder {
+    channel: u32,
+    control: Option<mpsc::UnboundedSender<Control>>,
+}
+
+impl ChannelResponder {
+    pub(crate) fn new(channel: u32, control: mpsc::UnboundedSender<Control>) -> Self {
+        Self {
+            channel,
+            control: Some(control),
+        }
+    }
+
+    /// Channel the request was sent on.
+    pub fn channel(&self) -> u32 {
+        self.channel
+    }
+
+    /// Send SSH_MSG_CHANNEL_SUCCESS.
+    pub fn success(mut self) -> Result<(), SshError> {
+        self.reply(true)
+    }
+
+    /// Send SSH_MSG_CHANNEL_FAILURE.
+    pub fn failure(mut self) -> Result<(), SshError> {
+        self.reply(false)
+    }
+
+    /// Drop without replying, the connection replied itself.
+    pub(crate) fn disarm(mut self) {
+        self.control = None;
+    }
+
+    fn reply(&mut self, success: bool) -> Result<(), SshError> {
+        if let Some(control) = self.control.take() {
+            control
+                .unbounded_send(Control::Reply(self.channel, success))
+                .map_err(|_| SshError::ConnectionClosing)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ChannelResponder {
+    fn drop(&mut self) {
+        self.reply(false).ok();
+    }
+}
@@ -1,54 +1,93 @@
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
 use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use crate::trace::{self, debug, error, warn, Instrument as _, Span};
+use bytes::Bytes;
 use futures::channel::{mpsc, oneshot};
-use futures::future::{Either, TryFutureExt as _};
+use futures::future::{self, Either, TryFutureExt as _};
 use futures::lock::Mutex;
 use futures::sink::SinkExt as _;
 use futures::stream::Stream;
 use futures::stream::StreamExt as _;
-use log::{debug, error, warn};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::time;
 use tokio_pipe::{PipeRead, PipeWrite};
 
-use crate::handlers::{HandlerError, Handlers};
+use crate::handlers::{sanitize, HandlerError, Handlers};
+use crate::key::Verifier;
 use crate::msg::channel_extended_data::DataTypeCode;
-use crate::msg::{self, Msg};
+use crate::msg::{self, Msg, MsgName as _};
 use crate::preference::Preference;
-use crate::stream::msg::MsgStream;
-use crate::SshError;
+use crate::stream::bpp::MAXIMUM_PACKET_SIZE;
+use crate::stream::msg::{Duplex, MsgStream};
+use crate::{Languages, ProtocolWarning, Signal, SshError, WindowSize};
 
+use super::channel_handle::{ChannelHandle, RawAccess, Request};
+use super::channel_table::ChannelTable;
+use super::closing::Closing;
 use super::completion_stream::CompletionStream;
-use super::reader_map::ReaderMap;
+use super::global_handle::{
+    ChannelKind, ChannelState, Control, Controller, GlobalHandle, Identity, OpenReply, Registry,
+};
+use super::memory::{Charge, Memory, Pressure};
+use super::reader_map::{ReaderClosed, ReaderMap};
+use super::scheduler::{Scheduler, Split};
 use super::ssh_stream::{SshInput, SshOutput};
+use super::timings::{Phase, Phases};
+use super::warning::Warnings;
+use super::window::{LocalWindow, RemoteWindow, MAXIMUM_DATA_SIZE};
 
 mod on_channel_close;
 mod on_channel_data;
 mod on_channel_eof;
+mod on_channel_extended_data;
 mod on_channel_open;
+mod on_channel_open_confirmation;
+mod on_channel_open_failure;
 mod on_channel_request;
 mod on_channel_window_adjust;
+mod on_disconnect;
 mod on_global_request;
 mod on_kexinit;
 mod on_service_request;
+mod on_unimplemented;
 mod on_userauth_request;
+#[cfg(test)]
+mod test_support;
 
-type TaskStream = Arc<
-    Mutex<
-        CompletionStream<
-            (u32, bool, Vec<oneshot::Receiver<()>>),
-            Result<Option<u32>, HandlerError>,
-        >,
-    >,
->;
+/// Channel, its exit flag unless completion is silent, and its outputs.
+type Task = (u32, Option<Arc<AtomicBool>>, Vec<ReaderClosed>);
+
+type TaskStream = Arc<Mutex<CompletionStream<Task, Result<Option<u32>, HandlerError>>>>;
+
+/// Handler output, bounded so senders wait while the client does not read.
+pub(super) type MsgQueue = mpsc::Sender<(u32, Msg, Charge)>;
+
+/// Estimated bookkeeping cost of one open channel.
+const CHANNEL_COST: usize = 1024;
+
+/// How often held back windows are retried under memory pressure.
+const RESUME_INTERVAL: time::Duration = time::Duration::from_millis(100);
 
 type OutputReaderMap = Arc<Mutex<ReaderMap<(u32, Option<DataTypeCode>), PipeRead>>>;
 
+impl Split for (Msg, Charge) {
+    fn split_to(&mut self, at: usize) -> Self {
+        let msg = match &mut self.0 {
+            Msg::ChannelData(data) => data.split_to(at).into(),
+            Msg::ChannelExtendedData(data) => data.split_to(at).into(),
+            x => unreachable!("split {:?}", x),
+        };
+        (msg, self.1.split_to(at))
+    }
+}
+
 struct LockNext<'a, S> {
     inner: &'a mut S,
 }
@@ -73,32 +112,170 @@ where
 }
 
 trait MutexStream: Sized {
-    fn lock_next(&mut self) -> LockNext<Self>;
+    fn lock_next(&mut self) -> LockNext<'_, Self>;
 }
 
 impl<S> MutexStream for Arc<Mutex<S>> {
-    fn lock_next(&mut self) -> LockNext<Self> {
+    fn lock_next(&mut self) -> LockNext<'_, Self> {
         LockNext { inner: self }
     }
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 enum Channel<Pty> {
     Session(
         u32,
-        Option<PipeWrite>,
+        Option<Stdin>,
         Option<SshInput>,
-        HashMap<String, String>,
+        HashMap<String, OsString>,
         Option<Pty>,
+        Option<mpsc::UnboundedReceiver<WindowSize>>,
     ),
-    DirectTcpip(u32, Option<PipeWrite>),
+    /// Also `direct-streamlocal@openssh.com`.
+    DirectTcpip(u32, Option<Stdin>),
+    /// Opened by us toward the client.
+    Outbound(u32, Option<Stdin>),
 }
 
-fn maybe_timeout(preference: &Preference) -> impl Future<Output = ()> {
+/// Channel we opened, awaiting the client's answer.
+#[derive(Debug)]
+struct PendingOpen {
+    kind: ChannelKind,
+    /// Opener fails once passed.
+    deadline: time::Instant,
+    /// Taken once the opener failed, the id stays reserved until answered.
+    reply: Option<OpenReply>,
+}
+
+/// Destination of channel data received from client.
+#[derive(Debug)]
+enum Stdin {
+    /// Charged for bytes written, up to what the pipe can hold.
+    Pipe(PipeWrite, Charge),
+    Detached(mpsc::UnboundedSender<(Bytes, Charge)>),
+}
+
+/// Wake up once nothing was received for the inactivity timeout.
+fn maybe_timeout(
+    preference: &Preference,
+    last_received: time::Instant,
+) -> impl Future<Output = ()> {
     if let Some(timeout) = preference.timeout() {
-        Either::Left(time::sleep(*timeout))
+        Either::Left(time::sleep_until(last_received + *timeout))
+    } else {
+        Either::Right(futures::future::pending())
+    }
+}
+
+/// Until `done`, fire `timeout` after connection start.
+fn maybe_deadline(
+    started: time::Instant,
+    timeout: time::Duration,
+    done: bool,
+) -> impl Future<Output = ()> {
+    if done {
+        Either::Right(futures::future::pending())
     } else {
+        Either::Left(time::sleep_until(started + timeout))
+    }
+}
+
+fn maybe_rekey(
+    preference: &Preference,
+    keyed_at: Option<time::Instant>,
+    rekeying: bool,
+) -> impl Future<Output = ()> {
+    let deadline = keyed_at.and_then(|at| at.checked_add(*preference.rekey_interval()));
+    match deadline {
+        Some(deadline) if !rekeying => Either::Left(time::sleep_until(deadline)),
+        _ => Either::Right(futures::future::pending()),
+    }
+}
+
+/// Wake up when the next keepalive probe is due.
+fn maybe_keepalive(
+    preference: &Preference,
+    keyed_at: Option<time::Instant>,
+    since: time::Instant,
+) -> impl Future<Output = ()> {
+    match preference.keepalive() {
+        Some((interval, _)) if keyed_at.is_some() => {
+            Either::Left(time::sleep_until(since + *interval))
+        }
+        _ => Either::Right(futures::future::pending()),
+    }
+}
+
+/// Wake up when the next padding message is due.
+fn maybe_pad(
+    keyed_at: Option<time::Instant>,
+    padding_at: Option<time::Instant>,
+) -> impl Future<Output = ()> {
+    match padding_at {
+        Some(at) if keyed_at.is_some() => Either::Left(time::sleep_until(at)),
+        _ => Either::Right(futures::future::pending()),
+    }
+}
+
+fn random_u32() -> u32 {
+    use ring::rand::{SecureRandom as _, SystemRandom};
+    let mut r = [0; 4];
+    SystemRandom::new().fill(&mut r).unwrap();
+    u32::from_ne_bytes(r)
+}
+
+/// Next padding time, uniformly between half and one and a half `interval` from now.
+fn next_padding(interval: time::Duration) -> time::Instant {
+    let jitter = interval.mul_f64(random_u32() as f64 / u32::MAX as f64);
+    time::Instant::now() + interval / 2 + jitter
+}
+
+fn maybe_stall(
+    preference: &Preference,
+    last_progress: time::Instant,
+    output_pending: bool,
+) -> impl Future<Output = ()> {
+    match preference.stall_timeout() {
+        Some(timeout) if output_pending => {
+            Either::Left(time::sleep_until(last_progress + *timeout))
+        }
+        _ => Either::Right(futures::future::pending()),
+    }
+}
+
+/// Poll memory pressure while window replenish is held back.
+fn maybe_resume_windows(starved: &HashSet<u32>) -> impl Future<Output = ()> {
+    if starved.is_empty() {
         Either::Right(futures::future::pending())
+    } else {
+        Either::Left(time::sleep(RESUME_INTERVAL))
+    }
+}
+
+/// Wake up when the first unanswered open we sent expires.
+fn maybe_expire_opens(pending_opens: &HashMap<u32, PendingOpen>) -> impl Future<Output = ()> {
+    let deadline = pending_opens
+        .values()
+        .filter(|open| open.reply.is_some())
+        .map(|open| open.deadline)
+        .min();
+    match deadline {
+        Some(deadline) => Either::Left(time::sleep_until(deadline)),
+        None => Either::Right(futures::future::pending()),
+    }
+}
+
+/// Wake up when the oldest unused channel expires.
+fn maybe_reap(
+    preference: &Preference,
+    unused: &HashMap<u32, time::Instant>,
+) -> impl Future<Output = ()> {
+    match unused.values().min() {
+        Some(opened) => Either::Left(time::sleep_until(
+            *opened + *preference.unused_channel_timeout(),
+        )),
+        None => Either::Right(futures::future::pending()),
     }
 }
 
@@ -116,10 +293,63 @@ where
     channels: HashMap<u32, Channel<Pty>>,
     output_readers: OutputReaderMap,
     completions: TaskStream,
-    msg_queue_tx: mpsc::UnboundedSender<Msg>,
-    msg_queue_rx: mpsc::UnboundedReceiver<Msg>,
+    msg_queue_tx: MsgQueue,
+    msg_queue_rx: mpsc::Receiver<(u32, Msg, Charge)>,
+    scheduler: Scheduler<(Msg, Charge)>,
+    request_tx: mpsc::UnboundedSender<Request>,
+    request_rx: mpsc::UnboundedReceiver<Request>,
+    pending_replies: HashMap<u32, VecDeque<oneshot::Sender<bool>>>,
+    exits: HashMap<u32, Arc<AtomicBool>>,
+    registry: Registry,
+    warnings: Warnings,
+    phases: Phases,
+    identity: Identity,
+    control_rx: mpsc::UnboundedReceiver<Control>,
+    control_tx: mpsc::UnboundedSender<Control>,
+    /// Passed to global request handlers.
+    global_handle: GlobalHandle,
+    /// Channels we opened awaiting the client's answer.
+    pending_opens: HashMap<u32, PendingOpen>,
+    /// Our channel ids and the client's id of each, until we sent close.
+    channel_table: ChannelTable,
+    /// Close of channels we opened, held until the client's EOF.
+    deferred_closes: HashMap<u32, (Msg, Charge)>,
+    admin_closed: HashSet<u32>,
+    /// Close handshake of each channel.
+    closing: Closing,
+    /// Channels whose EOF is queued. Later output is dropped.
+    eof_queued: HashSet<u32>,
+    unused: HashMap<u32, time::Instant>,
+    windows: HashMap<u32, LocalWindow>,
+    /// Window replenish held back while memory pressure is high.
+    starved: HashSet<u32>,
+    window_changes: HashMap<u32, mpsc::UnboundedSender<WindowSize>>,
+    userauth_requested: bool,
+    memory: Memory,
+    channel_charges: HashMap<u32, Charge>,
+    /// Our KEXINIT awaiting the client's.
     first_kexinit: Option<msg::kexinit::Kexinit>,
+    languages: Languages,
+    /// Client sent `ext-info-c` on the initial key exchange.
+    ext_info: bool,
+    /// Client sent `no-more-sessions@openssh.com`.
+    no_more_sessions: bool,
+    /// Channels the client opened so far.
+    channels_opened: u64,
+    last_progress: time::Instant,
+    /// Last message received.
+    last_received: time::Instant,
+    /// Last message or keepalive probe, whichever is later.
+    keepalive_since: time::Instant,
+    /// Keepalive probes sent since the last message.
+    keepalive_missed: u32,
+    /// Next traffic padding, `None` unless enabled.
+    padding_at: Option<time::Instant>,
     auth_state: on_userauth_request::AuthState,
+    /// Disconnect sent or received. Loop stops.
+    disconnected: bool,
+    /// Parent of the channel spans.
+    span: Span,
 }
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
@@ -128,13 +358,27 @@ where
     E: Into<HandlerError> + Send + 'static,
 {
     pub(super) fn new(
-        io: MsgStream<IO>,
+        mut io: MsgStream<IO>,
         c_version: String,
         s_version: String,
         preference: Arc<Preference>,
         handlers: Handlers<E, Pty>,
+        controller: Controller,
     ) -> Self {
-        let (msg_queue_tx, msg_queue_rx) = mpsc::unbounded();
+        let (msg_queue_tx, msg_queue_rx) = mpsc::channel(*preference.send_queue_size());
+        let (request_tx, request_rx) = mpsc::unbounded();
+        let memory = controller.memory.with_limit(*preference.memory_limit());
+        let auth_state = on_userauth_request::AuthState::new(
+            handlers.keyboard_interactive_enabled(),
+            memory.charge(0),
+        );
+        let global_handle = controller.handle();
+        io.set_observer(preference.observer().clone());
+        io.set_memory(&memory);
+        controller.identity.set_client_version(&c_version);
+        let padding_at = preference
+            .traffic_padding()
+            .map(|(interval, _)| next_padding(interval));
 
         Self {
             io,
@@ -147,20 +391,126 @@ where
             completions: Arc::new(Mutex::new(CompletionStream::new())),
             msg_queue_tx,
             msg_queue_rx,
+            scheduler: Default::default(),
+            request_tx,
+            request_rx,
+            pending_replies: Default::default(),
+            exits: Default::default(),
+            registry: controller.registry,
+            warnings: controller.warnings,
+            phases: controller.phases,
+            identity: controller.identity,
+            control_rx: controller.control,
+            control_tx: controller.control_tx,
+            global_handle,
+            pending_opens: Default::default(),
+            channel_table: ChannelTable::default(),
+            deferred_closes: Default::default(),
+            admin_closed: Default::default(),
+            closing: Default::default(),
+            eof_queued: Default::default(),
+            unused: Default::default(),
+            windows: Default::default(),
+            starved: Default::default(),
+            window_changes: Default::default(),
+            userauth_requested: false,
+            memory,
+            channel_charges: Default::default(),
             first_kexinit: None,
-            auth_state: on_userauth_request::AuthState::new(),
+            languages: Default::default(),
+            ext_info: false,
+            no_more_sessions: false,
+            channels_opened: 0,
+            last_progress: time::Instant::now(),
+            last_received: time::Instant::now(),
+            keepalive_since: time::Instant::now(),
+            keepalive_missed: 0,
+            padding_at,
+            auth_state,
+            disconnected: false,
+            span: Span::none(),
+        }
+    }
+
+    /// Run handlers of channels in child spans of `span`.
+    pub(super) fn in_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Start authenticated as `user`, skipping the auth exchange.
+    pub(super) fn authenticated_as(mut self, user: &str) -> Self {
+        self.auth_state.authenticate(user, "none");
+        self.identity.authenticate(user, "none");
+        self
+    }
+
+    fn handler_error<ERR: Into<HandlerError>>(&self, err: ERR) -> SshError {
+        SshError::HandlerError(sanitize(
+            err,
+            *self.preference.error_limit(),
+            self.preference.observer(),
+        ))
+    }
+
+    /// Tolerate `warning` unless its kind is escalated.
+    fn protocol_warning(&self, warning: ProtocolWarning) -> Result<(), SshError> {
+        self.warnings.record(&warning);
+        self.preference.observer().protocol_warning(&warning);
+        if self
+            .preference
+            .escalated_warnings()
+            .contains(&warning.kind())
+        {
+            return Err(SshError::ProtocolWarning(warning));
         }
+        Ok(())
     }
 
+    /// Buffer message. Flushed by [`Self::msg_loop`] while it keeps reading.
+    ///
+    /// Channel messages are addressed by our id and sent to the client's.
     async fn send<M: Into<Msg>>(&mut self, msg: M) -> Result<(), SshError> {
-        self.io.send(msg.into()).await
+        let mut msg = msg.into();
+        let closing = matches!(msg, Msg::ChannelClose(..));
+        match &msg {
+            Msg::ChannelEof(msg) => self.closing.eof_sent(*msg.recipient_channel()),
+            Msg::ChannelClose(msg) => self.closing.close_sent(*msg.recipient_channel()),
+            _ => {}
+        }
+        if let Some(channel) = msg.recipient_channel_mut() {
+            let remote = if closing {
+                self.channel_table.release(*channel)
+            } else {
+                self.channel_table.remote(*channel)
+            };
+            if let Some(remote) = remote {
+                *channel = remote;
+            }
+        }
+        self.io.feed(msg).await
+    }
+
+    fn output_pending(&self) -> bool {
+        self.io.get_ref().tx_pending() > 0 || !self.scheduler.is_empty()
+    }
+
+    fn report_stall(&self) {
+        let channels = self.channels.keys().collect::<Vec<_>>();
+        error!(
+            "connection stalled: tx_pending={} queued={:?} channels={:?} memory={}",
+            self.io.get_ref().tx_pending(),
+            self.scheduler.depths(),
+            channels,
+            self.memory.used(),
+        );
     }
 
     async fn new_output(
         &mut self,
         channel: u32,
         type_code: Option<DataTypeCode>,
-    ) -> Result<(SshOutput, oneshot::Receiver<()>), SshError> {
+    ) -> Result<(SshOutput, ReaderClosed), SshError> {
         let output_readers = self.output_readers.clone();
         let mut output_readers = output_readers.lock().await;
 
@@ -178,8 +528,8 @@ where
     async fn spawn_shell_handler<F, ERR>(
         &mut self,
         channel: u32,
-        stdout_closed: oneshot::Receiver<()>,
-        stderr_closed: oneshot::Receiver<()>,
+        stdout_closed: ReaderClosed,
+        stderr_closed: ReaderClosed,
         fut: F,
     ) where
         F: Future<Output = Result<u32, ERR>> + Send + 'static,
@@ -187,35 +537,46 @@ where
     {
         let completions = self.completions.clone();
         let mut completions = completions.lock().await;
+        let limit = *self.preference.error_limit();
+        let observer = self.preference.observer().clone();
 
         let fut = async move {
             debug!("spawn handler {}", channel);
-            let r = fut.map_err(Into::into).await?;
+            let r = fut.map_err(|e| sanitize(e, limit, &observer)).await?;
             debug!("done spawn handler {}", channel);
             Ok::<_, HandlerError>(Some(r))
-        };
-        completions.push((channel, true, vec![stdout_closed, stderr_closed]), fut);
+        }
+        .instrument(trace::channel_span(&self.span, channel));
+        let exited = self.exits.entry(channel).or_default().clone();
+        completions.push(
+            (channel, Some(exited), vec![stdout_closed, stderr_closed]),
+            fut,
+        );
+        self.closing.started(channel);
     }
 
-    async fn spawn_handler<F, ERR>(
-        &mut self,
-        channel: u32,
-        output_closed: oneshot::Receiver<()>,
-        fut: F,
-    ) where
+    async fn spawn_handler<F, ERR>(&mut self, channel: u32, output_closed: ReaderClosed, fut: F)
+    where
         F: Future<Output = Result<(), ERR>> + Send + 'static,
         ERR: Into<HandlerError>,
     {
         let completions = self.completions.clone();
         let mut completions = completions.lock().await;
+        let limit = *self.preference.error_limit();
+        let observer = self.preference.observer().clone();
 
         let fut = async move {
             debug!("spawn handler {}", channel);
-            fut.map_err(Into::into).await?;
+            fut.map_err(|e| sanitize(e, limit, &observer)).await?;
             debug!("done spawn handler {}", channel);
             Ok(None)
-        };
-        completions.push((channel, true, vec![output_closed]), fut);
+        }
+        .instrument(trace::channel_span(&self.span, channel));
+        completions.push(
+            (channel, Some(Default::default()), vec![output_closed]),
+            fut,
+        );
+        self.closing.started(channel);
     }
 
     pub(super) async fn run(mut self) -> Result<(), SshError> {
@@ -225,52 +586,506 @@ where
         let result = self.r#loop().await;
         if let Err(e) = &result {
             error!("error ocurred {}", e);
+            self.terminate().await;
+            if let Err(e) = self.abort_channels().await {
+                error!("failed to send exit-signal: {}", e)
+            }
             let t = e.reason_code().unwrap_or(ReasonCode::ProtocolError);
-            let msg = Disconnect::new(t, "error occurred".into(), "".into());
+            // Tell the client what failed to match, nothing else.
+            let description = match e {
+                SshError::Negotiate(e) => e.to_string(),
+                _ => "error occurred".into(),
+            };
+            let msg = Disconnect::new(t, description, "".into());
             if let Err(e) = self.send(msg).await {
                 error!("failed to send disconnect: {}", e)
             }
+        } else if self.disconnected {
+            self.terminate().await;
+        }
+        if let Some(summary) = self.warnings.summary() {
+            warn!("protocol warnings: {}", summary);
         }
         debug!("connection done.");
         self.io.close().await.ok();
         result
     }
 
+    /// Stop accepting handler output, so handle senders fail fast with
+    /// [`SshError::ConnectionClosing`] and pipe writes with broken pipe.
+    async fn terminate(&mut self) {
+        self.request_rx.close();
+        self.control_rx.close();
+        self.msg_queue_rx.close();
+        *self.output_readers.lock().await = ReaderMap::new();
+    }
+
     async fn r#loop(&mut self) -> Result<(), SshError> {
-        let first_kexinit = self.preference.to_kexinit();
+        let first_kexinit = self.preference.to_kexinit()?;
         self.send(first_kexinit.clone()).await?;
         self.first_kexinit = Some(first_kexinit);
 
         let reader = self.output_readers.clone();
         let tasks = self.completions.clone();
         let msg_queue_tx = self.msg_queue_tx.clone();
+        let memory = self.memory.clone();
 
         tokio::select! {
             result = self.msg_loop() => result,
-            result = Self::data_output_loop(reader, msg_queue_tx.clone()) => result,
-            result = Self::task_loop(tasks, msg_queue_tx) => result,
+            result = Self::data_output_loop(reader, msg_queue_tx.clone(), memory.clone()) => result,
+            result = Self::task_loop(tasks, msg_queue_tx, memory) => result,
         }
     }
 
     async fn msg_loop(&mut self) -> Result<(), SshError> {
         loop {
-            let timeout = maybe_timeout(&self.preference);
+            let timeout = maybe_timeout(&self.preference, self.last_received);
             tokio::pin!(timeout);
+            let stall = maybe_stall(&self.preference, self.last_progress, self.output_pending());
+            tokio::pin!(stall);
+            let reap = maybe_reap(&self.preference, &self.unused);
+            tokio::pin!(reap);
+            let expire_opens = maybe_expire_opens(&self.pending_opens);
+            tokio::pin!(expire_opens);
+            let resume = maybe_resume_windows(&self.starved);
+            tokio::pin!(resume);
+            let keyed_at = *self.io.get_ref().state().keyed_at();
+            let rekey = maybe_rekey(&self.preference, keyed_at, self.first_kexinit.is_some());
+            tokio::pin!(rekey);
+            let keepalive = maybe_keepalive(&self.preference, keyed_at, self.keepalive_since);
+            tokio::pin!(keepalive);
+            let started = self.phases.started();
+            let authenticated = self.auth_state.authenticated().is_some();
+            let handshake = maybe_deadline(
+                started,
+                *self.preference.handshake_timeout(),
+                keyed_at.is_some() || authenticated,
+            );
+            tokio::pin!(handshake);
+            let login = maybe_deadline(started, *self.preference.auth_timeout(), authenticated);
+            tokio::pin!(login);
+            let pad = maybe_pad(keyed_at, self.padding_at);
+            tokio::pin!(pad);
+            // Hold back channel output until buffered bytes drain, so control
+            // messages never wait behind a peer that is not reading.
+            // Nothing is scheduled while our key exchange is in progress.
+            let writable =
+                self.io.get_ref().tx_pending() <= MAXIMUM_PACKET_SIZE && !self.io.holding_non_kex();
+            // Nothing else may produce output once no more can be held back.
+            let holdable = !self.io.hold_full();
 
             tokio::select! {
-                msg = self.io.next() => {match msg {
-                    Some(msg) => self.handle_msg(&msg?).await?,
-                    None => return Ok(()),
-                }}
-                Some(msg) = self.msg_queue_rx.next() => self.send(msg).await?,
-                _ = &mut timeout => return Err(SshError::Timeout)
+                progress = self.io.next_or_flush() => {
+                    self.last_progress = time::Instant::now();
+                    match progress {
+                        Duplex::Received(Some(msg)) => {
+                            self.last_received = self.last_progress;
+                            self.keepalive_since = self.last_progress;
+                            self.keepalive_missed = 0;
+                            self.handle_msg(&msg?).await?
+                        }
+                        Duplex::Received(None) => return Ok(()),
+                        Duplex::Flushed(result) => result?,
+                    }
+                }
+                // Left queued while the socket backs up, so senders wait.
+                Some(queued) = self.msg_queue_rx.next(), if !self.scheduler.is_ready() && writable => {
+                    self.drain_requests();
+                    self.enqueue(queued);
+                }
+                Some(request) = self.request_rx.next() => self.push_request(request),
+                Some(control) = self.control_rx.next(), if holdable => self.on_control(control).await?,
+                _ = future::ready(()), if self.scheduler.is_ready() && writable => {
+                    self.drain_requests();
+                    while let Ok(queued) = self.msg_queue_rx.try_recv() {
+                        self.enqueue(queued);
+                    }
+                    if let Some((msg, _charge)) = self.scheduler.pop() {
+                        if let Msg::ChannelData(..) = msg {
+                            self.phases.mark(Phase::ChannelDataOut);
+                        }
+                        self.send(msg).await?;
+                    }
+                }
+                _ = &mut reap, if holdable => self.reap_unused_channels()?,
+                _ = &mut expire_opens => self.expire_opens(),
+                _ = &mut resume, if holdable => self.resume_windows().await?,
+                _ = &mut rekey => self.start_rekey().await?,
+                _ = &mut keepalive, if holdable => self.send_keepalive().await?,
+                _ = &mut pad => self.send_padding().await?,
+                _ = &mut timeout => return Err(SshError::Timeout),
+                _ = &mut handshake => return Err(SshError::HandshakeTimeout),
+                _ = &mut login => return Err(SshError::AuthTimeout),
+                _ = &mut stall => {
+                    self.report_stall();
+                    return Err(SshError::Stalled(self.last_progress.elapsed()));
+                }
+            }
+
+            self.auth_state.recharge();
+            if self.memory.pressure() == Pressure::Exceeded {
+                return Err(SshError::MemoryLimitExceeded(self.memory.used()));
+            }
+            if self.disconnected {
+                return Ok(());
+            }
+            let state = self.io.get_ref().state();
+            if state.keyed_at().is_some()
+                && self.first_kexinit.is_none()
+                && state.transferred() >= *self.preference.rekey_limit()
+            {
+                self.start_rekey().await?;
+            }
+        }
+    }
+
+    /// Send KEXINIT after the initial key exchange.
+    ///
+    /// Connection layer output is held back until our NEWKEYS.
+    async fn start_rekey(&mut self) -> Result<(), SshError> {
+        debug!("re-keying");
+        let kexinit = self.preference.to_kexinit()?;
+        self.send(kexinit.clone()).await?;
+        self.io.hold_non_kex(true)?;
+        self.first_kexinit = Some(kexinit);
+        Ok(())
+    }
+
+    /// Tell an `ext-info-c` client which signatures publickey auth verifies.
+    async fn send_ext_info(&mut self) -> Result<(), SshError> {
+        use msg::ext_info::ExtInfo;
+
+        if !self.ext_info {
+            return Ok(());
+        }
+        let algorithms = Verifier::signature_algorithms()
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>()
+            .join(",");
+        let extensions = vec![("server-sig-algs".into(), Bytes::from(algorithms))];
+        self.send(ExtInfo::new(extensions)).await
+    }
+
+    /// Probe the client, giving up after too many unanswered probes.
+    ///
+    /// Any message received counts as answer.
+    async fn send_keepalive(&mut self) -> Result<(), SshError> {
+        use msg::global_request::{GlobalRequest, Type};
+
+        let max_missed = self.preference.keepalive().map_or(0, |(_, max)| max);
+        if self.keepalive_missed >= max_missed {
+            warn!("no answer to {} keepalive probes", self.keepalive_missed);
+            return Err(SshError::Timeout);
+        }
+        self.keepalive_missed += 1;
+        self.keepalive_since = time::Instant::now();
+        self.send(GlobalRequest::new(true, Type::Keepalive)).await
+    }
+
+    /// Send SSH_MSG_IGNORE of random length and content.
+    async fn send_padding(&mut self) -> Result<(), SshError> {
+        use msg::ignore::Ignore;
+        use ring::rand::{SecureRandom as _, SystemRandom};
+
+        let (interval, max_len) = match self.preference.traffic_padding() {
+            Some(padding) => *padding,
+            None => return Ok(()),
+        };
+        self.padding_at = Some(next_padding(interval));
+        let mut data = vec![0; random_u32() as usize % (max_len + 1)];
+        SystemRandom::new().fill(&mut data).map_err(SshError::any)?;
+        self.send(Ignore::new(data.into())).await
+    }
+
+    /// Handle of a session channel writing to `outputs`.
+    fn channel_handle(&mut self, channel: u32, outputs: Vec<ReaderClosed>) -> ChannelHandle {
+        let priority = self.scheduler.priority(channel);
+        let exited = self.exits.entry(channel).or_default().clone();
+        let raw = RawAccess::new(
+            self.control_tx.clone(),
+            self.msg_queue_tx.clone(),
+            self.memory.clone(),
+        );
+        let identity = self.identity.clone();
+        ChannelHandle::new(
+            channel,
+            priority,
+            self.request_tx.clone(),
+            exited,
+            raw,
+            identity,
+            outputs,
+        )
+    }
+
+    /// Report `exit-signal` KILL for channels whose handler never completed.
+    async fn abort_channels(&mut self) -> Result<(), SshError> {
+        use msg::channel_close::ChannelClose;
+        use msg::channel_request::{ChannelRequest, ExitSignal, Type};
+
+        let aborted = self
+            .exits
+            .iter()
+            .filter(|(_, exited)| !exited.swap(true, Ordering::SeqCst))
+            .map(|(channel, _)| *channel)
+            .collect::<Vec<_>>();
+        for channel in aborted {
+            warn!("channel {} aborted.", channel);
+            let signal = ExitSignal::new(
+                Signal::Kill.to_string(),
+                false,
+                "connection aborted".into(),
+                "".into(),
+            );
+            let msg = ChannelRequest::new(channel, false, Type::ExitSignal(signal));
+            self.send(msg).await?;
+            self.send(ChannelClose::new(channel)).await?;
+        }
+        Ok(())
+    }
+
+    fn on_channel_reply(&mut self, channel: u32, success: bool) -> Result<(), SshError> {
+        let reply = self
+            .pending_replies
+            .get_mut(&channel)
+            .and_then(VecDeque::pop_front);
+        if let Some(reply) = reply {
+            reply.send(success).ok();
+            Ok(())
+        } else {
+            self.protocol_warning(ProtocolWarning::UnexpectedChannelReply { channel, success })
+        }
+    }
+
+    async fn on_control(&mut self, control: Control) -> Result<(), SshError> {
+        match control {
+            Control::CloseChannel(channel, reason) => self.close_channel(channel, &reason),
+            Control::Close(channel, closed) => self.close_by_handler(channel, closed),
+            Control::Detach(channel, frames) => self.detach_channel(channel, frames),
+            Control::Consumed(channel, len) => self.consume_window(channel, len).await?,
+            Control::Disconnect(reason, description) => {
+                self.disconnect(reason, description).await?
+            }
+            Control::Shutdown(reason, description) => self.shutdown(reason, description).await?,
+            Control::Open(typ, reply) => self.open_channel(typ, reply).await?,
+            Control::Banner(message) => self.send_banner(message).await?,
+            Control::Debug(always_display, message) => {
+                self.send(msg::debug::Debug::new(always_display, message, "".into()))
+                    .await?
+            }
+            Control::Reply(channel, success) => self.reply_deferred(channel, success).await?,
+        }
+        Ok(())
+    }
+
+    /// Route further data to `frames`. Dropping the pipe lets the detached
+    /// channel drain bytes written before.
+    fn detach_channel(&mut self, channel: u32, frames: mpsc::UnboundedSender<(Bytes, Charge)>) {
+        match self.channels.get_mut(&channel) {
+            Some(Channel::Session(_, stdin @ Some(_), _, _, _, _)) => {
+                debug!("channel: {} detached.", channel);
+                *stdin = Some(Stdin::Detached(frames));
+            }
+            _ => debug!("channel: {} detached after eof or close.", channel),
+        }
+    }
+
+    /// Handler took `len` bytes, replenish window if due.
+    ///
+    /// Held back while memory pressure is high, so clients stop sending.
+    async fn consume_window(&mut self, channel: u32, len: usize) -> Result<(), SshError> {
+        if let Some(window) = self.windows.get_mut(&channel) {
+            window.consume(len);
+            if self.memory.pressure() >= Pressure::High {
+                self.starved.insert(channel);
+                return Ok(());
+            }
+            self.replenish_window(channel).await?;
+        }
+        Ok(())
+    }
+
+    async fn replenish_window(&mut self, channel: u32) -> Result<(), SshError> {
+        use msg::channel_window_adjust::ChannelWindowAdjust;
+
+        let bytes_to_add = self
+            .windows
+            .get_mut(&channel)
+            .and_then(LocalWindow::replenish);
+        if let Some(bytes_to_add) = bytes_to_add {
+            self.send(ChannelWindowAdjust::new(channel, bytes_to_add))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Replenish windows held back once memory pressure eased.
+    async fn resume_windows(&mut self) -> Result<(), SshError> {
+        if self.memory.pressure() >= Pressure::High {
+            return Ok(());
+        }
+        for channel in std::mem::take(&mut self.starved) {
+            self.replenish_window(channel).await?;
+        }
+        Ok(())
+    }
+
+    /// Close channel from our side without reporting exit status.
+    fn close_channel(&mut self, channel: u32, reason: &str) {
+        if !self.channels.contains_key(&channel) || self.admin_closed.contains(&channel) {
+            warn!("close channel {}: not open", channel);
+            return;
+        }
+        warn!("close channel {}: {}", channel, reason);
+        self.queue_close(channel);
+    }
+
+    /// Close channel for its handler, resolving `closed` on the client's close.
+    fn close_by_handler(&mut self, channel: u32, closed: oneshot::Sender<()>) {
+        if !self.channels.contains_key(&channel) {
+            closed.send(()).ok();
+            return;
+        }
+        if !self.admin_closed.contains(&channel) {
+            debug!("channel: {} closed by handler.", channel);
+            self.queue_close(channel);
+        }
+        self.closing.wait(channel, closed);
+    }
+
+    /// Queue EOF and close, dropping output queued from now on.
+    fn queue_close(&mut self, channel: u32) {
+        use msg::channel_close::ChannelClose;
+        use msg::channel_eof::ChannelEof;
+
+        if let Some(exited) = self.exits.get(&channel) {
+            exited.store(true, Ordering::SeqCst);
+        }
+        self.unused.remove(&channel);
+        // after output already queued for this channel.
+        self.push((
+            channel,
+            ChannelEof::new(channel).into(),
+            self.memory.charge(0),
+        ));
+        self.push((
+            channel,
+            ChannelClose::new(channel).into(),
+            self.memory.charge(0),
+        ));
+        self.admin_closed.insert(channel);
+    }
+
+    /// Close session channels unused since open for too long.
+    fn reap_unused_channels(&mut self) -> Result<(), SshError> {
+        let timeout = *self.preference.unused_channel_timeout();
+        let now = time::Instant::now();
+        let expired = self
+            .unused
+            .iter()
+            .filter(|(_, opened)| **opened + timeout <= now)
+            .map(|(channel, opened)| (*channel, now - *opened))
+            .collect::<Vec<_>>();
+        for (channel, idle) in expired {
+            self.close_channel(channel, "unused");
+            self.protocol_warning(ProtocolWarning::UnusedChannel { channel, idle })?;
+        }
+        Ok(())
+    }
+
+    /// Fail openers the client left unanswered.
+    fn expire_opens(&mut self) {
+        let now = time::Instant::now();
+        for (channel, open) in &mut self.pending_opens {
+            if open.deadline > now {
+                continue;
+            }
+            if let Some(reply) = open.reply.take() {
+                debug!("channel: {} open unanswered.", channel);
+                let err = SshError::ChannelOpenFailed("no reply from client".into());
+                reply.send(Err(err)).ok();
+            }
+        }
+    }
+
+    fn push_request(&mut self, (channel, msg, reply): Request) {
+        if let Some(reply) = reply {
+            self.pending_replies
+                .entry(channel)
+                .or_default()
+                .push_back(reply);
+        }
+        self.push((channel, msg.into(), self.memory.charge(0)));
+    }
+
+    /// Queue requests sent so far, so they precede handler completion.
+    fn drain_requests(&mut self) {
+        while let Ok(request) = self.request_rx.try_recv() {
+            self.push_request(request);
+        }
+    }
+
+    /// Queue output.
+    ///
+    /// Close of a channel we opened waits for the client's EOF, so data the
+    /// client still sends is delivered.
+    fn enqueue(&mut self, (channel, msg, charge): (u32, Msg, Charge)) {
+        if let Msg::ChannelClose(..) = &msg {
+            // Last message of a completed handler.
+            self.closing.finished(channel);
+            if let Some(Channel::Outbound(_, Some(..))) = self.channels.get(&channel) {
+                debug!("channel: {} close after client eof.", channel);
+                self.deferred_closes.insert(channel, (msg, charge));
+                return;
             }
         }
+        self.push((channel, msg, charge));
+    }
+
+    /// Queue output as is. Handles mark exit reported themselves.
+    fn push(&mut self, (channel, msg, charge): (u32, Msg, Charge)) {
+        if self.admin_closed.contains(&channel) {
+            debug!("channel: {} closed by handle, drop {}", channel, msg.name());
+            return;
+        }
+        if self.closing.is_closing(channel) {
+            debug!("channel: {} close sent, drop {}", channel, msg.name());
+            return;
+        }
+        match &msg {
+            Msg::ChannelEof(..) if !self.eof_queued.insert(channel) => {
+                debug!("channel: {} eof already sent.", channel);
+                return;
+            }
+            Msg::ChannelData(..) | Msg::ChannelExtendedData(..)
+                if self.eof_queued.contains(&channel) =>
+            {
+                warn!("channel: {} eof sent, drop {}", channel, msg.name());
+                return;
+            }
+            _ => {}
+        }
+
+        let size = match &msg {
+            Msg::ChannelData(msg) => msg.data().len(),
+            Msg::ChannelExtendedData(msg) => msg.data().len(),
+            _ => 0,
+        };
+        if let Some(stats) = self.registry.get(channel) {
+            match &msg {
+                Msg::ChannelEof(..) => stats.set_state(ChannelState::Eof),
+                Msg::ChannelClose(..) => stats.set_state(ChannelState::Closing),
+                _ => stats.sent(size),
+            }
+        }
+        self.scheduler.push(channel, (msg, charge), size);
     }
 
     async fn data_output_loop(
         mut read: OutputReaderMap,
-        mut queue: mpsc::UnboundedSender<Msg>,
+        mut queue: MsgQueue,
+        memory: Memory,
     ) -> Result<(), SshError> {
         use msg::channel_data::ChannelData;
         use msg::channel_extended_data::ChannelExtendedData;
@@ -280,12 +1095,14 @@ where
 
             match (type_code, buf) {
                 (Some(data_type), Some(buf)) => {
+                    let charge = memory.charge(buf.len());
                     let msg = ChannelExtendedData::new(channel_id, data_type, buf).into();
-                    queue.send(msg).await?;
+                    queue.send((channel_id, msg, charge)).await?;
                 }
                 (None, Some(buf)) => {
+                    let charge = memory.charge(buf.len());
                     let msg = ChannelData::new(channel_id, buf).into();
-                    queue.send(msg).await?;
+                    queue.send((channel_id, msg, charge)).await?;
                 }
                 (type_code, None) => {
                     debug!("channel: {}, type: {:?} reach eof.", channel_id, type_code)
@@ -297,60 +1114,127 @@ where
 
     async fn task_loop(
         mut tasks: TaskStream,
-        mut queue: mpsc::UnboundedSender<Msg>,
+        mut queue: MsgQueue,
+        memory: Memory,
     ) -> Result<(), SshError> {
         use msg::channel_close::ChannelClose;
         use msg::channel_eof::ChannelEof;
         use msg::channel_request::{ChannelRequest, Type};
 
         while let Some(completed) = tasks.lock_next().await {
-            let ((channel_id, notify_status, output_closed), status) = completed;
+            let ((channel_id, exited, output_closed), status) = completed;
 
             for f in output_closed {
                 f.await.ok();
             }
 
             let msg = ChannelEof::new(channel_id).into();
-            queue.send(msg).await?;
+            queue.send((channel_id, msg, memory.charge(0))).await?;
 
-            if notify_status {
+            // Unless the handler reported exit itself.
+            if exited.is_some_and(|exited| !exited.swap(true, Ordering::SeqCst)) {
                 let status = match status {
                     Ok(Some(status)) => status,
                     Err(_) | Ok(None) => 255,
                 };
                 let typ = Type::ExitStatus(status);
                 let msg = ChannelRequest::new(channel_id, false, typ).into();
-                queue.send(msg).await?;
+                queue.send((channel_id, msg, memory.charge(0))).await?;
             }
 
             let msg = ChannelClose::new(channel_id).into();
-            queue.send(msg).await?;
+            queue.send((channel_id, msg, memory.charge(0))).await?;
 
             status.map_err(SshError::HandlerError)?;
         }
         Ok(())
     }
 
+    /// Refuse connection protocol requests until auth succeeded.
+    async fn refuse_unauthenticated(&mut self, msg: &Msg) -> Result<bool, SshError> {
+        use msg::channel_failure::ChannelFailure;
+        use msg::channel_open_failure::{ChannelOpenFailure, ReasonCode};
+        use msg::request_failure::RequestFailure;
+
+        if self.auth_state.authenticated().is_some() {
+            return Ok(false);
+        }
+        match msg {
+            Msg::ChannelOpen(msg) => {
+                warn!("refuse channel open before auth");
+                let msg = ChannelOpenFailure::new(
+                    *msg.sender_channel(),
+                    ReasonCode::AdministrativeryProhibited,
+                    "not authenticated".into(),
+                    "en-US".into(),
+                );
+                self.send(msg).await?;
+            }
+            Msg::ChannelRequest(msg) => {
+                warn!("refuse channel request before auth");
+                if *msg.want_reply() {
+                    self.send(ChannelFailure::new(*msg.recipient_channel()))
+                        .await?;
+                }
+            }
+            Msg::GlobalRequest(msg) => {
+                warn!("refuse global request before auth");
+                if *msg.want_reply() {
+                    self.send(RequestFailure::new()).await?;
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
     async fn handle_msg(&mut self, msg: &msg::Msg) -> Result<(), SshError> {
+        // In dispatch order, so a request never races the reaper.
+        match &msg {
+            Msg::ChannelRequest(msg) => self.unused.remove(msg.recipient_channel()),
+            Msg::ChannelData(msg) => self.unused.remove(msg.recipient_channel()),
+            Msg::ChannelExtendedData(msg) => self.unused.remove(msg.recipient_channel()),
+            Msg::ChannelWindowAdjust(msg) => self.unused.remove(msg.recipient_channel()),
+            _ => None,
+        };
+
+        if self.refuse_unauthenticated(msg).await? {
+            return Ok(());
+        }
+        if let Some(chid) = msg.recipient_channel() {
+            // Sent before the client saw the close.
+            if !self.channels.contains_key(&chid) && self.closing.recently_closed(chid) {
+                debug!("channel: {} closed, drop {}.", chid, msg.name());
+                return Ok(());
+            }
+        }
+
         match &msg {
             Msg::Kexinit(msg) => self.on_kexinit(msg).await?,
             Msg::ServiceRequest(msg) => self.on_service_request(msg).await?,
             Msg::UserauthRequest(msg) => self.on_userauth_request(msg).await?,
+            Msg::UserauthInfoResponse(msg) => self.on_userauth_info_response(msg).await?,
             Msg::GlobalRequest(msg) => self.on_global_request(msg).await?,
             Msg::ChannelOpen(msg) => self.on_channel_open(msg).await?,
+            Msg::ChannelOpenConfirmation(msg) => self.on_channel_open_confirmation(msg).await?,
+            Msg::ChannelOpenFailure(msg) => self.on_channel_open_failure(msg)?,
             Msg::ChannelData(msg) => self.on_channel_data(msg).await?,
+            Msg::ChannelExtendedData(msg) => self.on_channel_extended_data(msg).await?,
             Msg::ChannelEof(msg) => self.on_channel_eof(msg).await?,
             Msg::ChannelClose(msg) => self.on_channel_close(msg).await?,
             Msg::ChannelWindowAdjust(msg) => self.on_channel_window_adjust(msg).await?,
             Msg::ChannelRequest(msg) => self.on_channel_request(msg).await?,
-            Msg::Disconnect(..) => {}
+            Msg::ChannelSuccess(msg) => self.on_channel_reply(*msg.recipient_channel(), true)?,
+            Msg::ChannelFailure(msg) => self.on_channel_reply(*msg.recipient_channel(), false)?,
+            Msg::Disconnect(msg) => self.on_disconnect(msg).await?,
+            // Only keepalive probes want a global reply.
+            Msg::RequestSuccess(..) | Msg::RequestFailure(..) => {}
             Msg::Ignore(..) => {}
-            Msg::Unimplemented(..) => {}
+            Msg::Unimplemented(msg) => self.on_unimplemented(msg),
             x => {
-                warn!("UNHANDLED {:?}", x);
+                warn!("UNHANDLED {}", x.name());
 
-                let last_seq = self.io.get_ref().state().ctos().seq();
-                let m = msg::unimplemented::Unimplemented::new(last_seq);
+                let m = msg::unimplemented::Unimplemented::new(self.io.last_seq());
                 self.send(m).await?;
             }
         }
@@ -358,3 +1242,1614 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::{
+        service_request, session_open, session_open_with, tcpip_forward, userauth_request,
+    };
+    use crate::WarningKind;
+
+    use super::test_support::{
+        client_handshake, scripted, scripted_unauthenticated, xor_preference,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reap_unused_channel() {
+        use msg::channel_request::{ChannelRequest, Modes, PtyReq, Type};
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (handle, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(session_open(0)).await.unwrap();
+            theirs.send(session_open(1)).await.unwrap();
+            time::sleep(time::Duration::from_secs(59)).await;
+            let pty = PtyReq::new("xterm".into(), 80, 24, 0, 0, Modes::default());
+            let msg = ChannelRequest::new(1, true, Type::PtyReq(pty));
+            theirs.send(msg.into()).await.unwrap();
+            time::sleep(time::Duration::from_secs(2)).await;
+            theirs.close().await.unwrap();
+
+            let mut closed = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                if let Msg::ChannelClose(msg) = msg {
+                    closed.push(*msg.recipient_channel());
+                }
+            }
+            closed
+        };
+        let (result, closed) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(closed, vec![0]);
+        assert_eq!(handle.warnings()[&WarningKind::UnusedChannel], 1);
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_refused() {
+        use msg::channel_open_failure::ReasonCode;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        let script = vec![
+            session_open(0),
+            tcpip_forward(0, true),
+            ChannelRequest::new(0, true, Type::Shell(())).into(),
+        ];
+        let (result, received, handle) =
+            scripted_unauthenticated(PreferenceBuilder::default(), Handlers::new(), script).await;
+        result.unwrap();
+        assert!(handle.channels().is_empty());
+        match &received[..] {
+            [Msg::Kexinit(..), Msg::ChannelOpenFailure(open), Msg::RequestFailure(..), Msg::ChannelFailure(..)] =>
+            {
+                assert!(matches!(
+                    open.reason_code(),
+                    ReasonCode::AdministrativeryProhibited
+                ))
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_phase_timings() {
+        use futures::FutureExt as _;
+        use msg::channel_data::ChannelData;
+        use msg::channel_eof::ChannelEof;
+        use msg::channel_request::{ChannelRequest, Type};
+        use time::Duration;
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        use crate::connection::Connection;
+        use crate::SessionContext;
+
+        let mut preference = PreferenceBuilder::default();
+        // Paused time would jump to the re-key deadline while stdio is pending.
+        preference.name("server").rekey_interval(Duration::MAX);
+        let (preference, c_kexinit) = xor_preference(preference).await;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_none(|_| {
+            async {
+                time::sleep(Duration::from_secs(2)).await;
+                Ok(true)
+            }
+            .boxed()
+        });
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                tokio::io::copy(&mut stdin, &mut stdout).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let server = async {
+            let connection = Connection::new(ours, preference.clone(), None);
+            let connection = connection.accept().await.unwrap();
+            let handle = connection.handle();
+            connection.run(handlers).await.unwrap();
+            handle.phase_timings()
+        };
+
+        let client = async {
+            time::sleep(Duration::from_secs(1)).await;
+            theirs.write_all(b"SSH-2.0-client\r\n").await.unwrap();
+            while theirs.read_u8().await.unwrap() != b'\n' {}
+
+            time::sleep(Duration::from_secs(2)).await;
+            let mut theirs = MsgStream::new(theirs);
+            let delay = Duration::from_secs(4);
+            client_handshake(&mut theirs, c_kexinit, &preference, delay).await;
+
+            time::sleep(Duration::from_secs(1)).await;
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            theirs
+                .send(userauth_request("alice", &["none"], None))
+                .await
+                .unwrap();
+            theirs.send(session_open(0)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"echo"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+            time::sleep(Duration::from_secs(3)).await;
+            let data = ChannelData::new(0, Bytes::from_static(b"hi"));
+            theirs.send(data.into()).await.unwrap();
+            theirs.send(ChannelEof::new(0).into()).await.unwrap();
+            while let Some(Ok(msg)) = theirs.next().await {
+                if let Msg::ChannelClose(..) = msg {
+                    break;
+                }
+            }
+            theirs.close().await.unwrap();
+        };
+        let (timings, _) = tokio::join!(server, client);
+
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(timings.version_exchange(), secs(1));
+        assert_eq!(timings.first_kexinit(), &secs(3));
+        assert_eq!(timings.kex(), secs(4));
+        assert_eq!(timings.first_userauth_request(), &secs(8));
+        assert_eq!(timings.auth(), secs(2));
+        assert_eq!(timings.first_channel_data_in(), &secs(11));
+        assert_eq!(timings.first_channel_data_out(), &secs(11));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_window_held_under_pressure() {
+        use msg::channel_data::ChannelData;
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut preference = PreferenceBuilder::default();
+        preference.channel_window_size(1024).memory_limit(100_000);
+        let preference = Arc::new(preference.build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        )
+        .authenticated();
+        let hog = runner.memory.charge(75_000);
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(session_open(0)).await.unwrap();
+            let data = ChannelData::new(0, Bytes::from(vec![0; 600]));
+            theirs.send(data.into()).await.unwrap();
+            while !matches!(
+                theirs.next().await,
+                Some(Ok(Msg::ChannelOpenConfirmation(..)))
+            ) {}
+            let held = time::timeout(time::Duration::from_secs(1), theirs.next()).await;
+            assert!(held.is_err());
+
+            drop(hog);
+            match theirs.next().await {
+                Some(Ok(Msg::ChannelWindowAdjust(msg))) => assert_eq!(*msg.bytes_to_add(), 600),
+                msg => panic!("{:?}", msg),
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let (result, ()) = tokio::join!(runner.run(), client);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stdin_flood_exceeds_limit() {
+        use futures::FutureExt as _;
+
+        use msg::channel_data::ChannelData;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            // Never reads stdin.
+            let stdio = ctx.take_stdio().unwrap();
+            async move {
+                future::pending::<()>().await;
+                drop(stdio);
+                Ok(0)
+            }
+            .boxed()
+        });
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut preference = PreferenceBuilder::default();
+        preference.memory_limit(100_000);
+        let preference = Arc::new(preference.build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            for ours in 0..2 {
+                theirs.send(session_open(ours)).await.unwrap();
+                let channel = loop {
+                    if let Some(Ok(Msg::ChannelOpenConfirmation(msg))) = theirs.next().await {
+                        break *msg.sender_channel();
+                    }
+                };
+                let exec = Type::Exec(Bytes::from_static(b"sink"));
+                let request = ChannelRequest::new(channel, false, exec);
+                theirs.send(request.into()).await.unwrap();
+                for _ in 0..2 {
+                    let data = ChannelData::new(channel, Bytes::from(vec![0; 30_000]));
+                    if theirs.send(data.into()).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let (result, ()) = tokio::join!(runner.run(), client);
+        assert!(
+            matches!(result, Err(SshError::MemoryLimitExceeded(used)) if used > 100_000),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_returns_to_baseline() {
+        use futures::FutureExt as _;
+
+        use msg::channel_close::ChannelClose;
+        use msg::channel_data::ChannelData;
+        use msg::channel_eof::ChannelEof;
+        use msg::channel_request::{ChannelRequest, Type};
+        use msg::global_request::{self, GlobalRequest};
+
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                tokio::io::copy(&mut stdin, &mut stdout).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut preference = PreferenceBuilder::default();
+        preference.memory_limit(1 << 20);
+        let preference = Arc::new(preference.build().await.unwrap());
+        let (handle, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+        let baseline = handle.memory_used();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            for round in 0..3 {
+                let mut ids = vec![];
+                for ours in 0..2 {
+                    theirs.send(session_open(ours)).await.unwrap();
+                    let channel = loop {
+                        if let Some(Ok(Msg::ChannelOpenConfirmation(msg))) = theirs.next().await {
+                            break *msg.sender_channel();
+                        }
+                    };
+                    ids.push(channel);
+                    let exec = Type::Exec(Bytes::from_static(b"cat"));
+                    let request = ChannelRequest::new(channel, false, exec);
+                    theirs.send(request.into()).await.unwrap();
+                    for _ in 0..4 {
+                        let data = ChannelData::new(channel, Bytes::from(vec![round; 10_000]));
+                        theirs.send(data.into()).await.unwrap();
+                    }
+                    theirs.send(ChannelEof::new(channel).into()).await.unwrap();
+                }
+                let mut open = 2;
+                while open > 0 {
+                    if let Some(Ok(Msg::ChannelClose(msg))) = theirs.next().await {
+                        let channel = ids[*msg.recipient_channel() as usize];
+                        theirs
+                            .send(ChannelClose::new(channel).into())
+                            .await
+                            .unwrap();
+                        open -= 1;
+                    }
+                }
+                // Answered once the closes above were handled.
+                let keepalive = GlobalRequest::new(true, global_request::Type::Keepalive);
+                theirs.send(keepalive.into()).await.unwrap();
+                while !matches!(theirs.next().await, Some(Ok(Msg::RequestSuccess(..)))) {}
+                assert_eq!(handle.memory_used(), baseline, "round {}", round);
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let (result, ()) = tokio::join!(runner.run(), client);
+        result.unwrap();
+    }
+
+    fn script() -> Vec<Msg> {
+        use msg::channel_data::ChannelData;
+        use msg::channel_success::ChannelSuccess;
+
+        vec![
+            service_request("ssh-userauth"),
+            service_request("ssh-userauth"),
+            ChannelSuccess::new(3).into(),
+            ChannelData::new(7, Bytes::from_static(b"x")).into(),
+            ChannelData::new(7, Bytes::from_static(b"y")).into(),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_warnings_tolerated() {
+        let (result, received, handle) = scripted(PreferenceBuilder::default(), script()).await;
+        result.unwrap();
+        assert!(!received.iter().any(|m| matches!(m, Msg::Disconnect(..))));
+
+        let warnings = handle.warnings();
+        assert_eq!(warnings[&WarningKind::DuplicateServiceRequest], 1);
+        assert_eq!(warnings[&WarningKind::UnexpectedChannelReply], 1);
+        assert_eq!(warnings[&WarningKind::UnknownChannel], 2);
+    }
+
+    #[tokio::test]
+    async fn test_warning_escalated() {
+        let mut preference = PreferenceBuilder::default();
+        preference.escalate_warning(WarningKind::UnknownChannel);
+        let (result, received, handle) = scripted(preference, script()).await;
+        assert!(matches!(
+            result,
+            Err(SshError::ProtocolWarning(ProtocolWarning::UnknownChannel {
+                channel: 7,
+                ..
+            }))
+        ));
+        assert!(matches!(received.last(), Some(Msg::Disconnect(..))));
+
+        let warnings = handle.warnings();
+        assert_eq!(warnings[&WarningKind::DuplicateServiceRequest], 1);
+        assert_eq!(warnings[&WarningKind::UnexpectedChannelReply], 1);
+        assert_eq!(warnings[&WarningKind::UnknownChannel], 1);
+    }
+
+    #[tokio::test]
+    async fn test_detached_echo() {
+        use futures::FutureExt as _;
+        use msg::channel_data::ChannelData;
+        use msg::channel_eof::ChannelEof;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::connection::window::DEFAULT_WINDOW_SIZE;
+        use crate::{DetachError, SessionContext};
+
+        const TOTAL: usize = 50 * 1024 * 1024;
+        const CHUNK: usize = 32 * 1024;
+        fn pattern(offset: usize, len: usize) -> Vec<u8> {
+            (offset..offset + len).map(|i| (i % 251) as u8).collect()
+        }
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            let detached = ctx.detach().unwrap();
+            assert!(ctx.take_stdio().is_none());
+            assert!(matches!(ctx.detach(), Err(DetachError::AlreadyDetached(0))));
+            async move {
+                let (sink, stream) = detached.split();
+                stream.forward(sink).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let (mut tx, mut rx) = MsgStream::new(theirs).split();
+        let (adjust_tx, mut adjust_rx) = mpsc::unbounded();
+        let writer = async move {
+            // Echo exceeds no window, only ours is under test.
+            tx.send(session_open_with(0, u32::MAX, 0x8000))
+                .await
+                .unwrap();
+            // Written to stdin before the handler detaches.
+            let pre = ChannelData::new(0, Bytes::from_static(b"pre"));
+            tx.send(pre.into()).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"echo"));
+            tx.send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+
+            let mut window = DEFAULT_WINDOW_SIZE as usize - 3;
+            let mut sent = 0;
+            while sent < TOTAL {
+                while window < CHUNK {
+                    window += adjust_rx.next().await.unwrap() as usize;
+                }
+                let data = ChannelData::new(0, pattern(sent, CHUNK).into());
+                tx.send(data.into()).await.unwrap();
+                window -= CHUNK;
+                sent += CHUNK;
+            }
+            tx.send(ChannelEof::new(0).into()).await.unwrap();
+            tx
+        };
+        let reader = async move {
+            let mut received = vec![];
+            let mut events = vec![];
+            while let Some(Ok(msg)) = rx.next().await {
+                match msg {
+                    Msg::ChannelData(data) => received.extend_from_slice(data.data()),
+                    Msg::ChannelWindowAdjust(adjust) => {
+                        adjust_tx.unbounded_send(*adjust.bytes_to_add()).ok();
+                    }
+                    Msg::ChannelEof(..) => events.push(format!("eof {}", received.len())),
+                    Msg::ChannelRequest(req) => match req.typ() {
+                        Type::ExitStatus(status) => events.push(format!("exit {}", status)),
+                        x => panic!("{:?}", x),
+                    },
+                    Msg::ChannelClose(..) => {
+                        events.push("close".into());
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            (received, events, rx)
+        };
+        let client = async move {
+            let (tx, (received, events, rx)) = tokio::join!(writer, reader);
+            tx.reunite(rx).unwrap().close().await.unwrap();
+            (received, events)
+        };
+        let (result, (received, events)) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        assert_eq!(received.len(), TOTAL + 3);
+        assert_eq!(&received[..3], b"pre");
+        assert!(received[3..] == pattern(0, TOTAL)[..]);
+        let eof = format!("eof {}", TOTAL + 3);
+        assert_eq!(events, vec![eof, "exit 0".into(), "close".into()]);
+    }
+
+    #[tokio::test]
+    async fn test_fair_channel_output() {
+        use futures::FutureExt as _;
+        use msg::channel_request::{ChannelRequest, Type};
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::SessionContext;
+
+        const BULK: usize = 2 * 1024 * 1024;
+        const CHUNK: usize = 32 * 1024;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, command: std::ffi::OsString| {
+            let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                if command == "bulk" {
+                    for _ in 0..BULK / CHUNK {
+                        stdout.write_all(&[b'x'; CHUNK]).await?;
+                    }
+                } else {
+                    stdout.write_all(b"pong").await?;
+                }
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut preference = PreferenceBuilder::default();
+        // Room for the whole backlog, so only scheduling decides the order.
+        preference.send_queue_size(1024);
+        let preference = Arc::new(preference.build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let exec = |channel, command: &'static [u8]| {
+                let typ = Type::Exec(Bytes::from_static(command));
+                Msg::from(ChannelRequest::new(channel, false, typ))
+            };
+            theirs
+                .send(session_open_with(0, u32::MAX, 0x8000))
+                .await
+                .unwrap();
+            theirs.send(exec(0, b"bulk")).await.unwrap();
+            // Bulk output backs up while the client lags behind, then the
+            // interactive channel starts.
+            let mut frames = vec![];
+            while frames.is_empty() {
+                if let Msg::ChannelData(data) = theirs.next().await.unwrap().unwrap() {
+                    frames.push((0, data.data().len()));
+                }
+            }
+            time::sleep(time::Duration::from_millis(100)).await;
+            theirs
+                .send(session_open_with(1, u32::MAX, 0x8000))
+                .await
+                .unwrap();
+            theirs.send(exec(1, b"ping")).await.unwrap();
+
+            let mut closed = 0;
+            while closed < 2 {
+                match theirs.next().await.unwrap().unwrap() {
+                    Msg::ChannelData(data) => {
+                        frames.push((*data.recipient_channel(), data.data().len()))
+                    }
+                    Msg::ChannelClose(..) => closed += 1,
+                    _ => {}
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            frames
+        };
+        let (result, frames) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        let bulk = frames.iter().filter(|(channel, _)| *channel == 0);
+        assert_eq!(bulk.map(|(_, len)| len).sum::<usize>(), BULK);
+        let pong = frames
+            .iter()
+            .position(|(channel, _)| *channel == 1)
+            .unwrap();
+        let before = frames[..pong].iter().map(|(_, len)| len).sum::<usize>();
+        // Behind what was on its way already, not the whole backlog.
+        assert!(before < BULK / 4, "{} bytes before pong", before);
+        assert_eq!(frames[pong], (1, 4));
+    }
+
+    #[tokio::test]
+    async fn test_handler_disconnect() {
+        use futures::FutureExt as _;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::{DisconnectReason, SessionContext};
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|ctx: SessionContext, _| {
+            async move {
+                ctx.disconnect(DisconnectReason::ByApplication, "bye")?;
+                futures::future::pending::<()>().await;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(session_open(0)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"sleep"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+            // Connection stops without the client closing it.
+            let mut received = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                received.push(msg);
+            }
+            received
+        };
+        let (result, received) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        match received.last() {
+            Some(Msg::Disconnect(msg)) => {
+                assert_eq!(msg.reason_code(), &DisconnectReason::ByApplication);
+                assert_eq!(msg.description(), "bye");
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_output() {
+        use futures::FutureExt as _;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::{DisconnectReason, SessionContext};
+
+        let (handle, controller) = global_handle();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(move |ctx: SessionContext, _| {
+            let handle = handle.clone();
+            async move {
+                for chunk in [&b"one"[..], b"two", b"three"] {
+                    ctx.send_stderr(Bytes::from_static(chunk)).await?;
+                }
+                // Queued output is still in flight.
+                handle.shutdown(DisconnectReason::ByApplication, "bye")?;
+                futures::future::pending::<()>().await;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(session_open(0)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"sleep"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+            let mut received = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                received.push(msg);
+            }
+            received
+        };
+        let (result, received) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        let stderr = received
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::ChannelExtendedData(data) => Some(data.data().clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(stderr, vec!["one", "two", "three"]);
+        match received.last() {
+            Some(Msg::Disconnect(msg)) => {
+                assert_eq!(msg.reason_code(), &DisconnectReason::ByApplication);
+                assert_eq!(msg.description(), "bye");
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_window() {
+        use futures::FutureExt as _;
+        use msg::channel_request::{ChannelRequest, Type};
+        use msg::channel_window_adjust::ChannelWindowAdjust;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::SessionContext;
+
+        const TOTAL: usize = 100 * 1024;
+        const WINDOW: u32 = 1000;
+        const PACKET: u32 = 300;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            let (_, mut stdout, mut stderr) = ctx.take_stdio().unwrap();
+            async move {
+                stdout.write_all(&[1; TOTAL]).await?;
+                stderr.write_all(&[2; TOTAL]).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs
+                .send(session_open_with(0, WINDOW, PACKET))
+                .await
+                .unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"cat"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+
+            let mut window = WINDOW as usize;
+            let mut received = [0, 0];
+            while let Some(Ok(msg)) = theirs.next().await {
+                let (index, len) = match msg {
+                    Msg::ChannelData(data) => (0, data.data().len()),
+                    Msg::ChannelExtendedData(data) => (1, data.data().len()),
+                    Msg::ChannelClose(..) => break,
+                    _ => continue,
+                };
+                assert!(len <= PACKET as usize);
+                assert!(len <= window, "{} exceeds window {}", len, window);
+                window -= len;
+                received[index] += len;
+                // Replenish in small steps once half is used.
+                if window < WINDOW as usize / 2 {
+                    let adjust = ChannelWindowAdjust::new(0, WINDOW - window as u32);
+                    theirs.send(adjust.into()).await.unwrap();
+                    window = WINDOW as usize;
+                }
+            }
+            theirs.close().await.unwrap();
+            received
+        };
+        let (result, received) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(received, [TOTAL, TOTAL]);
+    }
+
+    /// Events the client sees on a channel running `exec` with `handlers`.
+    async fn exec_events(handlers: Handlers<anyhow::Error>) -> Vec<String> {
+        use msg::channel_request::{ChannelRequest, Type};
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(session_open(5)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"true"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+
+            let mut events = vec![];
+            let mut received = 0;
+            while let Some(Ok(msg)) = theirs.next().await {
+                if !matches!(msg, Msg::ChannelData(..)) && received > 0 {
+                    events.push(format!("data {}", received));
+                    received = 0;
+                }
+                match msg {
+                    Msg::ChannelData(msg) => received += msg.data().len(),
+                    Msg::ChannelExtendedData(..) => events.push("stderr".into()),
+                    Msg::ChannelEof(..) => events.push("eof".into()),
+                    Msg::ChannelRequest(req) => match req.typ() {
+                        Type::ExitStatus(status) => events.push(format!("exit {}", status)),
+                        Type::ExitSignal(signal) => {
+                            events.push(format!("signal {}", signal.name()))
+                        }
+                        x => panic!("{:?}", x),
+                    },
+                    Msg::ChannelClose(..) => {
+                        events.push("close".into());
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            theirs.close().await.unwrap();
+            events
+        };
+        let (result, events) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        events
+    }
+
+    #[tokio::test]
+    async fn test_send_eof_then_exit_status() {
+        use futures::FutureExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            let (_, mut stdout, stderr) = ctx.take_stdio().unwrap();
+            async move {
+                stdout.write_all(b"out").await?;
+                drop((stdout, stderr));
+                ctx.send_eof().await?;
+                assert!(matches!(
+                    ctx.send_eof().await,
+                    Err(SshError::EofAlreadySent(0))
+                ));
+                assert!(matches!(
+                    ctx.send_stderr(Bytes::from_static(b"err")).await,
+                    Err(SshError::EofAlreadySent(0))
+                ));
+                ctx.send_exit_status(3).await?;
+                assert!(matches!(
+                    ctx.send_exit_status(4).await,
+                    Err(SshError::ExitAlreadySent(0))
+                ));
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let events = exec_events(handlers).await;
+        assert_eq!(events, vec!["data 3", "eof", "exit 3", "close"]);
+    }
+
+    /// Handler writing `len` bytes to stdout, then reporting exit with `report`.
+    fn exit_after_output<F>(len: usize, report: F) -> Handlers<anyhow::Error>
+    where
+        F: Fn(crate::SessionContext) -> futures::future::BoxFuture<'static, Result<(), SshError>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        use futures::FutureExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::SessionContext;
+
+        let report = Arc::new(report);
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(move |mut ctx: SessionContext, _| {
+            let (_, mut stdout, stderr) = ctx.take_stdio().unwrap();
+            let report = report.clone();
+            async move {
+                stdout.write_all(&vec![b'x'; len]).await?;
+                drop((stdout, stderr));
+                report(ctx).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        handlers
+    }
+
+    #[tokio::test]
+    async fn test_exit_status_after_output() {
+        use futures::FutureExt as _;
+
+        // More than the pipe and send queue hold, so output is still in flight.
+        let handlers = exit_after_output(256 * 1024, |ctx| {
+            async move { ctx.send_exit_status(7).await }.boxed()
+        });
+        let events = exec_events(handlers).await;
+        assert_eq!(events, vec!["data 262144", "exit 7", "eof", "close"]);
+    }
+
+    #[tokio::test]
+    async fn test_exit_signal_after_output() {
+        use futures::FutureExt as _;
+
+        let handlers = exit_after_output(256 * 1024, |ctx| {
+            async move { ctx.send_exit_signal(Signal::Term, false, "").await }.boxed()
+        });
+        let events = exec_events(handlers).await;
+        assert_eq!(events, vec!["data 262144", "signal TERM", "eof", "close"]);
+    }
+
+    #[tokio::test]
+    async fn test_close_by_handler() {
+        use futures::FutureExt as _;
+        use msg::channel_close::ChannelClose;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let (closed_tx, mut closed_rx) = mpsc::unbounded();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(move |mut ctx: SessionContext, _| {
+            let closed_tx = closed_tx.clone();
+            async move {
+                let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+                ctx.close().await?;
+                closed_tx.unbounded_send(()).unwrap();
+                tokio::io::AsyncWriteExt::write_all(&mut stdout, b"dropped").await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(session_open(5)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"true"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+
+            let mut events = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                match msg {
+                    Msg::ChannelEof(..) => events.push("eof"),
+                    Msg::ChannelRequest(..) => events.push("request"),
+                    Msg::ChannelClose(..) => {
+                        events.push("close");
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            assert!(closed_rx.try_recv().is_err());
+            theirs.send(ChannelClose::new(0).into()).await.unwrap();
+            closed_rx.next().await.unwrap();
+            // Let the completed handler's output reach the runner.
+            time::sleep(time::Duration::from_millis(50)).await;
+            theirs.close().await.unwrap();
+            while let Some(Ok(msg)) = theirs.next().await {
+                events.push(msg.name());
+            }
+            events
+        };
+        let (result, events) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(events, vec!["eof", "close"]);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_after_disconnect() {
+        use futures::FutureExt as _;
+        use msg::channel_data::ChannelData;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let (error_tx, error_rx) = oneshot::channel();
+        let error_tx = std::sync::Mutex::new(Some(error_tx));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(move |ctx: SessionContext, _| {
+            let error_tx = error_tx.lock().unwrap().take().unwrap();
+            // Keeps producing regardless of the connection state.
+            tokio::spawn(async move {
+                loop {
+                    let sent = ctx.send_request("x@example.com", false, Bytes::new());
+                    if let Err(e) = sent.await {
+                        error_tx.send(e).unwrap();
+                        return;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            });
+            future::pending().boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut preference = PreferenceBuilder::default();
+        preference.escalate_warning(WarningKind::UnknownChannel);
+        let preference = Arc::new(preference.build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(session_open(0)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"loop"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+            let mut received = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                if let Msg::ChannelRequest(..) = msg {
+                    if !received
+                        .iter()
+                        .any(|m| matches!(m, Msg::ChannelRequest(..)))
+                    {
+                        let data = ChannelData::new(9, Bytes::from_static(b"x"));
+                        theirs.send(data.into()).await.unwrap();
+                    }
+                }
+                received.push(msg);
+            }
+            received
+        };
+        let (result, received) = tokio::join!(runner.run(), client);
+        assert!(result.is_err());
+        assert!(matches!(received.last(), Some(Msg::Disconnect(..))));
+        assert!(matches!(
+            error_rx.await.unwrap(),
+            SshError::ConnectionClosing
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_eow_frame() {
+        use futures::FutureExt as _;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|ctx: SessionContext, _| {
+            async move {
+                ctx.send_eow().await?;
+                future::pending().await
+            }
+            .boxed()
+        });
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            // Our channel 5 is the server's channel 0.
+            theirs.send(session_open(5)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"eow"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+            let frame = loop {
+                let payload = theirs.get_mut().next().await.unwrap().unwrap();
+                if payload[0] == 98 {
+                    break payload;
+                }
+            };
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            frame
+        };
+        let (result, frame) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        let mut expect = vec![98, 0, 0, 0, 5, 0, 0, 0, 15];
+        expect.extend_from_slice(b"eow@openssh.com");
+        expect.push(0);
+        assert_eq!(&frame[..], &expect[..]);
+    }
+
+    #[tokio::test]
+    async fn test_deferred_reply() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let (go_tx, go_rx) = oneshot::channel::<()>();
+        let go_rx = Arc::new(StdMutex::new(Some(go_rx)));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(move |mut ctx: SessionContext, prog: std::ffi::OsString| {
+            let responder = ctx.defer_reply().unwrap();
+            let go_rx = go_rx.lock().unwrap().take();
+            async move {
+                if prog == "wait" {
+                    go_rx.unwrap().await?;
+                    responder.success()?;
+                }
+                // Otherwise dropped unresolved.
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let exec = |channel, prog: &'static [u8]| -> Msg {
+                let prog = Type::Exec(Bytes::from_static(prog));
+                ChannelRequest::new(channel, true, prog).into()
+            };
+            for msg in [
+                session_open(0),
+                session_open(1),
+                exec(0, b"wait"),
+                exec(1, b"drop"),
+            ] {
+                theirs.send(msg).await.unwrap();
+            }
+            // The dropped reply fails while the other is still pending.
+            loop {
+                match theirs.next().await.unwrap().unwrap() {
+                    Msg::ChannelFailure(failure) => {
+                        assert_eq!(*failure.recipient_channel(), 1);
+                        break;
+                    }
+                    Msg::ChannelSuccess(..) => panic!("replied before spawn"),
+                    _ => {}
+                }
+            }
+            go_tx.send(()).unwrap();
+            loop {
+                match theirs.next().await.unwrap().unwrap() {
+                    Msg::ChannelSuccess(success) => {
+                        assert_eq!(*success.recipient_channel(), 0);
+                        break;
+                    }
+                    Msg::ChannelFailure(..) => panic!("failed twice"),
+                    _ => {}
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let (result, _) = tokio::join!(runner.run(), client);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_two_channels_routed() {
+        use futures::FutureExt as _;
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        use msg::channel_data::ChannelData;
+        use msg::channel_eof::ChannelEof;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, prog: std::ffi::OsString| {
+            let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                let mut input = vec![];
+                stdin.read_to_end(&mut input).await?;
+                stdout.write_all(prog.to_str().unwrap().as_bytes()).await?;
+                stdout.write_all(&input).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            // Client ids differing from the ones we allocate.
+            theirs.send(session_open(7)).await.unwrap();
+            theirs.send(session_open(3)).await.unwrap();
+            let mut ids = HashMap::new();
+            while ids.len() < 2 {
+                if let Some(Ok(Msg::ChannelOpenConfirmation(ok))) = theirs.next().await {
+                    ids.insert(*ok.recipient_channel(), *ok.sender_channel());
+                }
+            }
+            assert_ne!(ids[&7], ids[&3]);
+            for (client, prog, input) in [(7, "a:", "seven"), (3, "b:", "three")] {
+                let server = ids[&client];
+                let exec = Type::Exec(Bytes::from(prog));
+                theirs
+                    .send(ChannelRequest::new(server, false, exec).into())
+                    .await
+                    .unwrap();
+                let data = ChannelData::new(server, Bytes::from(input));
+                theirs.send(data.into()).await.unwrap();
+                theirs.send(ChannelEof::new(server).into()).await.unwrap();
+            }
+
+            let mut outputs = HashMap::<u32, Vec<u8>>::new();
+            let mut closed = 0;
+            while closed < 2 {
+                match theirs.next().await {
+                    Some(Ok(Msg::ChannelData(data))) => outputs
+                        .entry(*data.recipient_channel())
+                        .or_default()
+                        .extend_from_slice(data.data()),
+                    Some(Ok(Msg::ChannelClose(close))) => {
+                        assert!(ids.contains_key(close.recipient_channel()));
+                        closed += 1;
+                    }
+                    Some(Ok(..)) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            outputs
+        };
+        let (result, outputs) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(outputs[&7], b"a:seven");
+        assert_eq!(outputs[&3], b"b:three");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_keepalive() {
+        use msg::global_request::Type;
+        use msg::request_failure::RequestFailure;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.keepalive(time::Duration::from_secs(10), 2);
+        let (preference, c_kexinit) = xor_preference(preference).await;
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let zero = time::Duration::ZERO;
+            client_handshake(&mut theirs, c_kexinit, &preference, zero).await;
+            let mut probes = vec![];
+            let started = time::Instant::now();
+            while let Some(Ok(msg)) = theirs.next().await {
+                if let Msg::GlobalRequest(request) = msg {
+                    assert!(matches!(request.typ(), Type::Keepalive));
+                    assert!(*request.want_reply());
+                    probes.push(started.elapsed().as_secs());
+                    // Answer the first probe only.
+                    if probes.len() == 1 {
+                        theirs.send(RequestFailure::new().into()).await.unwrap();
+                    }
+                }
+            }
+            (probes, started.elapsed().as_secs())
+        };
+        let (result, (probes, elapsed)) = tokio::join!(runner.run(), client);
+        assert!(matches!(result, Err(SshError::Timeout)));
+        assert_eq!(probes, vec![10, 20, 30]);
+        assert_eq!(elapsed, 40);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_traffic_padding() {
+        let mut preference = PreferenceBuilder::default();
+        preference.traffic_padding(time::Duration::from_secs(10), 64);
+        let (preference, c_kexinit) = xor_preference(preference).await;
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let zero = time::Duration::ZERO;
+            client_handshake(&mut theirs, c_kexinit, &preference, zero).await;
+            let started = time::Instant::now();
+            let deadline = started + time::Duration::from_secs(100);
+            let mut padding = vec![];
+            while let Ok(Some(Ok(msg))) = time::timeout_at(deadline, theirs.next()).await {
+                if let Msg::Ignore(ignore) = msg {
+                    padding.push((started.elapsed(), ignore.data().len()));
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            padding
+        };
+        let (result, padding) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert!(
+            (100 / 15..=100 / 5).contains(&padding.len()),
+            "{:?}",
+            padding
+        );
+        assert!(padding.iter().all(|(_, len)| *len <= 64));
+        for pair in padding.windows(2) {
+            let gap = pair[1].0 - pair[0].0;
+            assert!(gap >= time::Duration::from_secs(5), "{:?}", gap);
+            assert!(gap <= time::Duration::from_secs(15), "{:?}", gap);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_debug() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (handle, controller) = global_handle();
+        handle.send_debug(true, "quota exceeded").unwrap();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let debug = loop {
+                match theirs.next().await.unwrap().unwrap() {
+                    Msg::Debug(debug) => break debug,
+                    Msg::Kexinit(..) => {}
+                    x => panic!("{:?}", x),
+                }
+            };
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            debug
+        };
+        let (result, debug) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert!(*debug.always_display());
+        assert_eq!(debug.message(), "quota exceeded");
+        assert_eq!(debug.language_tag(), "");
+    }
+
+    #[tokio::test]
+    async fn test_observer() {
+        use futures::FutureExt as _;
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Mutex as StdMutex;
+
+        use crate::msg::channel_close::ChannelClose;
+        use crate::msg::channel_window_adjust::ChannelWindowAdjust;
+        use crate::{AuthOutcome, ChannelKind, ConnectionObserver, PasswordResult};
+
+        #[derive(Default)]
+        struct Recorder {
+            received: AtomicUsize,
+            sent: AtomicUsize,
+            events: StdMutex<Vec<String>>,
+        }
+
+        impl ConnectionObserver for Recorder {
+            fn on_packet_received(&self, len: usize) {
+                self.received.fetch_add(len, Ordering::SeqCst);
+            }
+
+            fn on_packet_sent(&self, len: usize) {
+                self.sent.fetch_add(len, Ordering::SeqCst);
+            }
+
+            fn on_auth_result(&self, user: &str, method: &str, outcome: AuthOutcome) {
+                let event = format!("auth {} {} {:?}", user, method, outcome);
+                self.events.lock().unwrap().push(event);
+            }
+
+            fn on_channel_open(&self, channel: u32, kind: ChannelKind) {
+                let event = format!("open {} {:?}", channel, kind);
+                self.events.lock().unwrap().push(event);
+            }
+
+            fn on_channel_close(&self, channel: u32) {
+                let event = format!("close {}", channel);
+                self.events.lock().unwrap().push(event);
+            }
+
+            fn on_protocol_warning(&self, warning: &ProtocolWarning) {
+                let event = format!("warning {:?}", warning.kind());
+                self.events.lock().unwrap().push(event);
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut preference = PreferenceBuilder::default();
+        preference.observer(recorder.clone());
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_password(|user_name, _| {
+            let result = if user_name == "bob" {
+                PasswordResult::Ok
+            } else {
+                PasswordResult::Failure
+            };
+            async move { Ok(result) }.boxed()
+        });
+        let script = vec![
+            service_request("ssh-userauth"),
+            // Method query, not an attempt.
+            userauth_request("eve", &["none"], None),
+            userauth_request("eve", &["password"], Some("guess")),
+            userauth_request("bob", &["password"], Some("secret")),
+            session_open(0),
+            ChannelWindowAdjust::new(0, u32::MAX).into(),
+            ChannelClose::new(0).into(),
+        ];
+        let (result, _, _) = scripted_unauthenticated(preference, handlers, script).await;
+        result.unwrap();
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                "auth eve password Failure",
+                "auth bob password Success",
+                "open 0 Session",
+                "warning WindowOverAdjust",
+                "close 0",
+            ]
+        );
+        // Unencrypted: length, padding length, payload and padding, no mac.
+        let received = recorder.received.load(Ordering::SeqCst);
+        assert!(received >= 6 * 16, "{}", received);
+        assert_eq!(received % 8, 0);
+        assert!(recorder.sent.load(Ordering::SeqCst) > 0);
+    }
+
+    /// Client sending an ignore message after each of `gaps`, then EOF.
+    ///
+    /// Returns how long the connection ran.
+    async fn idle_client(
+        preference: PreferenceBuilder,
+        gaps: Vec<u64>,
+    ) -> (Result<(), SshError>, time::Duration) {
+        use crate::msg::ignore::Ignore;
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(preference.build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        )
+        .authenticated();
+        let started = time::Instant::now();
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            for gap in gaps {
+                time::sleep(time::Duration::from_secs(gap)).await;
+                if theirs.send(Ignore::new(Bytes::new()).into()).await.is_err() {
+                    return;
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let run = async move {
+            let result = runner.run().await;
+            (result, started.elapsed())
+        };
+        let ((result, elapsed), ()) = tokio::join!(run, client);
+        (result, elapsed)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_timeout() {
+        let (result, elapsed) = idle_client(PreferenceBuilder::default(), vec![24 * 60 * 60]).await;
+        result.unwrap();
+        assert!(elapsed >= time::Duration::from_secs(24 * 60 * 60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inactivity_timeout() {
+        let mut preference = PreferenceBuilder::default();
+        preference.timeout(time::Duration::from_secs(10));
+        let (result, _) = idle_client(preference, vec![6, 6, 6, 6]).await;
+        result.unwrap();
+
+        let mut preference = PreferenceBuilder::default();
+        preference.timeout(time::Duration::from_secs(10));
+        let (result, elapsed) = idle_client(preference, vec![6, 6, 60]).await;
+        assert!(matches!(result, Err(SshError::Timeout)));
+        assert_eq!(elapsed.as_secs(), 22);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::trace::debug;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::msg::channel_close::ChannelClose;
@@ -10,12 +11,78 @@ where
     IO: AsyncRead + AsyncWrite + Unpin + Send,
     E: Into<HandlerError> + Send + 'static,
 {
+    /// Answer with our close unless already sent, then forget the channel.
+    ///
+    /// Output the handler still produces is dropped.
     pub(super) async fn on_channel_close(
         &mut self,
         channel_close: &ChannelClose,
     ) -> Result<(), SshError> {
         let chid = channel_close.recipient_channel();
-        self.channels.remove(chid);
+        let open = self.channels.remove(chid).is_some();
+        self.channel_charges.remove(chid);
+        self.scheduler.remove(*chid);
+        self.pending_replies.remove(chid);
+        self.exits.remove(chid);
+        self.registry.remove(*chid);
+        self.preference.observer().channel_close(*chid);
+        self.unused.remove(chid);
+        self.windows.remove(chid);
+        self.starved.remove(chid);
+        self.window_changes.remove(chid);
+        self.deferred_closes.remove(chid);
+        self.eof_queued.remove(chid);
+        let known = open || self.closing.is_closing(*chid);
+        if known && self.closing.close_received(*chid) {
+            debug!("channel: {} closed by client, reply close.", chid);
+            self.send(ChannelClose::new(*chid)).await?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::future;
+
+    use crate::connection::run::test_support::scripted_with;
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::session_open;
+    use crate::WarningKind;
+
+    #[tokio::test]
+    async fn test_close_reply() {
+        use futures::FutureExt as _;
+        use msg::channel_close::ChannelClose;
+        use msg::channel_data::ChannelData;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        // Handler never completes, close is answered anyway.
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|_, _| future::pending().boxed());
+        let exec = Type::Exec(Bytes::from_static(b"sleep"));
+        let script = vec![
+            session_open(5),
+            ChannelRequest::new(0, false, exec).into(),
+            ChannelClose::new(0).into(),
+            ChannelData::new(0, Bytes::from_static(b"late")).into(),
+            ChannelClose::new(0).into(),
+        ];
+        let mut preference = PreferenceBuilder::default();
+        preference.escalate_warning(WarningKind::UnknownChannel);
+        let (result, received, handle) = scripted_with(preference, handlers, script).await;
+        result.unwrap();
+        assert!(handle.warnings().is_empty());
+        let closes = received
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::ChannelClose(msg) => Some(*msg.recipient_channel()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(closes, vec![5]);
+    }
+}
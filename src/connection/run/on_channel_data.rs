@@ -1,10 +1,13 @@
-use log::warn;
+use crate::trace::debug;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::msg::channel_data::ChannelData;
-use crate::HandlerError;
+use crate::{HandlerError, ProtocolWarning};
 
-use super::{Channel, Runner, SshError};
+use super::{Channel, Phase, Runner, SshError, Stdin, MAXIMUM_DATA_SIZE};
+
+/// Bytes a stdin pipe holds before writes wait, the Linux default.
+const STDIN_PIPE_CAPACITY: usize = 64 * 1024;
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
 where
@@ -15,19 +18,194 @@ where
         &mut self,
         channel_data: &ChannelData,
     ) -> Result<(), SshError> {
-        let chid = channel_data.recipient_channel();
-        let data = channel_data.data().as_ref();
-        if let Some(channel) = self.channels.get_mut(chid) {
-            match channel {
-                Channel::Session(_, stdin, _, _, _) | Channel::DirectTcpip(_, stdin) => match stdin
+        self.phases.mark(Phase::ChannelDataIn);
+        let chid = *channel_data.recipient_channel();
+        let mut data = channel_data.data().clone();
+        match self.receive_data(chid, data.len(), "data")? {
+            Some(accepted) => data.truncate(accepted),
+            None => return Ok(()),
+        }
+
+        match self.channels.get_mut(&chid) {
+            Some(Channel::Session(_, Some(Stdin::Pipe(stdin, charge)), _, _, _, _))
+            | Some(Channel::DirectTcpip(_, Some(Stdin::Pipe(stdin, charge))))
+            | Some(Channel::Outbound(_, Some(Stdin::Pipe(stdin, charge)))) => {
+                // Reads by the handler are not seen, so assume the pipe stays full.
+                charge.resize((charge.size() + data.len()).min(STDIN_PIPE_CAPACITY));
+                stdin.write_all(&data).await?;
+            }
+            Some(Channel::Session(_, Some(Stdin::Detached(frames)), _, _, _, _)) => {
+                // Consumed once taken from the detached channel, unless it is gone.
+                let len = data.len();
+                if frames
+                    .unbounded_send((data, self.memory.charge(len)))
+                    .is_ok()
                 {
-                    Some(stdin) => {
-                        stdin.write_all(&data).await?;
-                    }
-                    None => warn!("closed channel {}", chid),
-                },
+                    return Ok(());
+                }
+                debug!("channel: {} detached channel dropped, discard data.", chid);
+                return self.consume_window(chid, len).await;
+            }
+            _ => {}
+        }
+
+        self.consume_window(chid, data.len()).await
+    }
+
+    /// Account `len` bytes received on `chid` against its window.
+    ///
+    /// Returns the accepted length, or `None` if the channel takes no data.
+    pub(super) fn receive_data(
+        &mut self,
+        chid: u32,
+        len: usize,
+        message: &'static str,
+    ) -> Result<Option<usize>, SshError> {
+        if let Some(stats) = self.registry.get(chid) {
+            stats.received(len);
+        }
+        if self.closing.is_closing(chid) {
+            // Sent before the client saw our close, the handler is gone.
+            debug!("channel: {} close sent, drop {}.", chid, message);
+            return Ok(None);
+        }
+        let open = match self.channels.get(&chid) {
+            Some(Channel::Session(_, stdin, _, _, _, _))
+            | Some(Channel::DirectTcpip(_, stdin))
+            | Some(Channel::Outbound(_, stdin)) => stdin.is_some(),
+            None => {
+                self.protocol_warning(ProtocolWarning::UnknownChannel {
+                    channel: chid,
+                    message,
+                })?;
+                return Ok(None);
+            }
+        };
+        if !open {
+            self.protocol_warning(ProtocolWarning::DataAfterEof {
+                channel: chid,
+                bytes: len,
+            })?;
+            return Ok(None);
+        }
+
+        if len > MAXIMUM_DATA_SIZE as usize {
+            self.protocol_warning(ProtocolWarning::PacketExceeded {
+                channel: chid,
+                bytes: len,
+                maximum: MAXIMUM_DATA_SIZE,
+            })?;
+        }
+        let window = self.windows.get_mut(&chid).expect("window of open channel");
+        let remaining = window.remaining();
+        let accepted = window.receive(len);
+        if accepted < len {
+            self.protocol_warning(ProtocolWarning::WindowExceeded {
+                channel: chid,
+                bytes: len,
+                remaining,
+            })?;
+        }
+        Ok(Some(accepted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use crate::connection::run::test_support::scripted;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::session_open;
+    use crate::{SshError, WarningKind};
+
+    fn window_script() -> Vec<Msg> {
+        use msg::channel_data::ChannelData;
+
+        vec![
+            session_open(0),
+            ChannelData::new(0, Bytes::from(vec![0; 600])).into(),
+            ChannelData::new(0, Bytes::from(vec![0; 2000])).into(),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_window_exceeded() {
+        let mut preference = PreferenceBuilder::default();
+        preference.channel_window_size(1024);
+        let (result, received, handle) = scripted(preference, window_script()).await;
+        result.unwrap();
+
+        let adjusts = received
+            .iter()
+            .filter_map(|m| match m {
+                Msg::ChannelWindowAdjust(m) => Some(*m.bytes_to_add()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(adjusts, vec![600, 1024]);
+        assert_eq!(handle.warnings()[&WarningKind::WindowExceeded], 1);
+    }
+
+    #[tokio::test]
+    async fn test_window_exceeded_escalated() {
+        let mut preference = PreferenceBuilder::default();
+        preference
+            .channel_window_size(1024)
+            .escalate_warning(WarningKind::WindowExceeded);
+        let (result, _, _) = scripted(preference, window_script()).await;
+        assert!(matches!(
+            result,
+            Err(SshError::ProtocolWarning(ProtocolWarning::WindowExceeded {
+                channel: 0,
+                bytes: 2000,
+                remaining: 1024,
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_packet_exceeded() {
+        use msg::channel_data::ChannelData;
+        use msg::disconnect::ReasonCode;
+
+        let len = MAXIMUM_DATA_SIZE as usize + 1;
+        let script = || {
+            vec![
+                session_open(0),
+                ChannelData::new(0, Bytes::from(vec![0; len])).into(),
+            ]
+        };
+        let mut preference = PreferenceBuilder::default();
+        preference.tolerate_warning(WarningKind::PacketExceeded);
+        let (result, received, handle) = scripted(preference, script()).await;
+        result.unwrap();
+        assert_eq!(handle.warnings()[&WarningKind::PacketExceeded], 1);
+        assert!(!received.iter().any(|m| matches!(m, Msg::Disconnect(..))));
+
+        let mut escalated = PreferenceBuilder::default();
+        escalated
+            .tolerate_warning(WarningKind::PacketExceeded)
+            .escalate_warning(WarningKind::PacketExceeded);
+        for preference in [PreferenceBuilder::default(), escalated] {
+            let (result, received, _) = scripted(preference, script()).await;
+            assert!(matches!(
+                result,
+                Err(SshError::ProtocolWarning(ProtocolWarning::PacketExceeded {
+                    channel: 0,
+                    maximum: MAXIMUM_DATA_SIZE,
+                    ..
+                }))
+            ));
+            match received.last() {
+                Some(Msg::Disconnect(msg)) => {
+                    assert_eq!(msg.reason_code(), &ReasonCode::ProtocolError)
+                }
+                x => panic!("{:?}", x),
             }
         }
-        Ok(())
     }
 }
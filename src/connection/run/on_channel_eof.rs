@@ -1,9 +1,9 @@
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
 
 use crate::msg::channel_eof::ChannelEof;
-use crate::HandlerError;
+use crate::{ChannelState, HandlerError, ProtocolWarning};
 
-use super::{Channel, Runner, SshError};
+use super::{Channel, Runner, SshError, Stdin};
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
 where
@@ -15,15 +15,88 @@ where
         channel_eof: &ChannelEof,
     ) -> Result<(), SshError> {
         let chid = channel_eof.recipient_channel();
-        if let Some(channel) = self.channels.get_mut(chid) {
-            match channel {
-                Channel::Session(_, stdin, _, _, _) | Channel::DirectTcpip(_, stdin) => {
-                    if let Some(mut stdin) = stdin.take() {
-                        stdin.shutdown().await?;
-                    }
-                }
+        if let Some(stats) = self.registry.get(*chid) {
+            stats.set_state(ChannelState::Eof);
+        }
+        let stdin = match self.channels.get_mut(chid) {
+            Some(Channel::Session(_, stdin, _, _, _, _))
+            | Some(Channel::DirectTcpip(_, stdin))
+            | Some(Channel::Outbound(_, stdin)) => stdin,
+            None => {
+                return self.protocol_warning(ProtocolWarning::UnknownChannel {
+                    channel: *chid,
+                    message: "eof",
+                })
+            }
+        };
+        let stdin = stdin.take();
+        self.closing.eof_received(*chid);
+        if let Some((msg, charge)) = self.deferred_closes.remove(chid) {
+            self.push((*chid, msg, charge));
+        }
+        match stdin {
+            Some(Stdin::Pipe(mut stdin, _)) => {
+                stdin.shutdown().await?;
+                Ok(())
             }
+            Some(Stdin::Detached(..)) => Ok(()),
+            None => self.protocol_warning(ProtocolWarning::DuplicateEof { channel: *chid }),
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::channel::mpsc;
+    use futures::future;
+    use futures::stream::StreamExt as _;
+
+    use crate::connection::run::test_support::scripted_with;
+    use crate::handlers::Handlers;
+    use crate::msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::session_open;
+    use crate::WarningKind;
+
+    #[tokio::test]
+    async fn test_data_after_eof() {
+        use futures::FutureExt as _;
+        use msg::channel_data::ChannelData;
+        use msg::channel_eof::ChannelEof;
+        use msg::channel_request::{ChannelRequest, Type};
+        use tokio::io::AsyncReadExt as _;
+
+        use crate::SessionContext;
+
+        let (input_tx, mut input_rx) = mpsc::unbounded();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(move |mut ctx: SessionContext, _| {
+            let (mut stdin, _, _) = ctx.take_stdio().unwrap();
+            let input_tx = input_tx.clone();
+            tokio::spawn(async move {
+                let mut input = vec![];
+                stdin.read_to_end(&mut input).await.unwrap();
+                input_tx.unbounded_send(input).unwrap();
+            });
+            future::pending().boxed()
+        });
+
+        let exec = Type::Exec(Bytes::from_static(b"cat"));
+        let script = vec![
+            session_open(0),
+            ChannelRequest::new(0, false, exec).into(),
+            ChannelData::new(0, Bytes::from_static(b"in")).into(),
+            ChannelEof::new(0).into(),
+            ChannelData::new(0, Bytes::from_static(b"late")).into(),
+            ChannelEof::new(0).into(),
+        ];
+        let (result, _, handle) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+        assert_eq!(input_rx.next().await.unwrap(), b"in");
+        let warnings = handle.warnings();
+        assert_eq!(warnings[&WarningKind::DataAfterEof], 1);
+        assert_eq!(warnings[&WarningKind::DuplicateEof], 1);
     }
 }
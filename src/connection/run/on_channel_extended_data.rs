@@ -0,0 +1,151 @@
+use crate::trace::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::handlers::sanitize;
+use crate::msg::channel_extended_data::ChannelExtendedData;
+use crate::HandlerError;
+
+use super::{Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) async fn on_channel_extended_data(
+        &mut self,
+        extended_data: &ChannelExtendedData,
+    ) -> Result<(), SshError> {
+        let chid = *extended_data.recipient_channel();
+        let mut data = extended_data.data().clone();
+        match self.receive_data(chid, data.len(), "extended data")? {
+            Some(accepted) => data.truncate(accepted),
+            None => return Ok(()),
+        }
+
+        let len = data.len();
+        let code = extended_data.data_type_code().code();
+        match self
+            .handlers
+            .dispatch_channel_extended_data(chid, code, data)
+        {
+            Some(fut) => {
+                if let Err(err) = fut.await {
+                    warn!(
+                        "{}",
+                        sanitize(
+                            err,
+                            *self.preference.error_limit(),
+                            self.preference.observer()
+                        )
+                    );
+                }
+            }
+            None => debug!("channel: {} discard extended data type {}.", chid, code),
+        }
+        self.consume_window(chid, len).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::run::Runner;
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::session_open;
+
+    #[tokio::test]
+    async fn test_extended_data() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_eof::ChannelEof;
+        use msg::channel_extended_data::{ChannelExtendedData, DataTypeCode};
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let captured = Arc::new(StdMutex::new(vec![]));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        let sink = captured.clone();
+        handlers.on_channel_extended_data(move |channel, data_type, data| {
+            sink.lock().unwrap().push((channel, data_type, data));
+            async { Ok(()) }.boxed()
+        });
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            let (mut stdin, _, _) = ctx.take_stdio().unwrap();
+            async move {
+                tokio::io::copy(&mut stdin, &mut tokio::io::sink()).await?;
+                ctx.send_stderr(Bytes::from_static(b"err")).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let exec = Type::Exec(Bytes::from_static(b"cat"));
+        let script = vec![
+            session_open(0),
+            ChannelRequest::new(0, false, exec).into(),
+            ChannelExtendedData::new(0, DataTypeCode::Stderr, Bytes::from_static(b"in")).into(),
+            ChannelExtendedData::new(0, DataTypeCode::Unknown(7), Bytes::from_static(b"x")).into(),
+            ChannelEof::new(0).into(),
+        ];
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            for msg in script {
+                theirs.send(msg).await.unwrap();
+            }
+            let mut received = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                let close = matches!(msg, Msg::ChannelClose(..));
+                received.push(msg);
+                if close {
+                    break;
+                }
+            }
+            theirs.close().await.unwrap();
+            received
+        };
+        let (result, received) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        assert_eq!(
+            *captured.lock().unwrap(),
+            vec![
+                (0, 1, Bytes::from_static(b"in")),
+                (0, 7, Bytes::from_static(b"x"))
+            ]
+        );
+        assert!(!received.iter().any(|m| matches!(m, Msg::Unimplemented(..))));
+        let stderr = received
+            .iter()
+            .position(|m| matches!(m, Msg::ChannelExtendedData(d) if &d.data()[..] == b"err"))
+            .unwrap();
+        let eof = received
+            .iter()
+            .position(|m| matches!(m, Msg::ChannelEof(..)))
+            .unwrap();
+        assert!(stderr < eof);
+    }
+}
@@ -1,15 +1,20 @@
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
-use log::debug;
+use crate::trace::{debug, warn};
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time;
 
 use crate::msg::channel_open::{ChannelOpen, DirectTcpip, Type};
 use crate::msg::channel_open_confirmation::ChannelOpenConfirmation;
 use crate::msg::channel_open_failure::{ChannelOpenFailure, ReasonCode};
-use crate::HandlerError;
+use crate::{ChannelKind, HandlerError, Handlers, SshOutput};
 
-use super::{Channel, Runner, SshError, SshInput};
+use super::{
+    Channel, LocalWindow, OpenReply, PendingOpen, Pressure, RemoteWindow, Runner, SshError,
+    SshInput, Stdin, CHANNEL_COST, MAXIMUM_DATA_SIZE,
+};
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
 where
@@ -20,9 +25,45 @@ where
         &mut self,
         channel_open: &ChannelOpen,
     ) -> Result<(), SshError> {
+        if self.memory.pressure() >= Pressure::Critical {
+            warn!("memory pressure, reject channel open");
+            let msg = ChannelOpenFailure::new(
+                *channel_open.sender_channel(),
+                ReasonCode::ResourceShortage,
+                "resource shortage".into(),
+                "en-US".into(),
+            );
+            self.send(msg).await?;
+            return Ok(());
+        }
+
+        let total = self.preference.max_total_channels();
+        if self.channels.len() >= *self.preference.max_channels()
+            || total.is_some_and(|total| self.channels_opened >= total)
+        {
+            warn!("too many channels, reject channel open");
+            let msg = ChannelOpenFailure::new(
+                *channel_open.sender_channel(),
+                ReasonCode::ResourceShortage,
+                "too many channels".into(),
+                "en-US".into(),
+            );
+            self.send(msg).await?;
+            return Ok(());
+        }
+
         match channel_open.typ() {
             Type::Session(..) => self.on_channel_open_session(channel_open).await,
             Type::DirectTcpip(item) => self.on_channel_open_direct_tcpip(channel_open, item).await,
+            Type::DirectStreamlocal(item) => {
+                let socket_path = item.socket_path().clone();
+                self.on_channel_open_direct(
+                    channel_open,
+                    ChannelKind::DirectStreamlocal,
+                    |h, i, o| h.dispatch_direct_streamlocal(socket_path, i, o),
+                )
+                .await
+            }
             x => {
                 debug!("unknown channel type {:?}", x);
 
@@ -42,34 +83,96 @@ where
         &mut self,
         channel_open: &ChannelOpen,
     ) -> Result<(), SshError> {
-        let chid = *channel_open.sender_channel();
+        if self.no_more_sessions {
+            warn!("session opened after no-more-sessions, rejected");
+            let msg = ChannelOpenFailure::new(
+                *channel_open.sender_channel(),
+                ReasonCode::AdministrativeryProhibited,
+                "no more sessions".into(),
+                "en-US".into(),
+            );
+            self.send(msg).await?;
+            return Ok(());
+        }
+        let chid = match self.allocate_channel(channel_open).await? {
+            Some(chid) => chid,
+            None => return Ok(()),
+        };
         let (r, w) = tokio_pipe::pipe()?;
         let stdin_rx = SshInput::new(r);
 
         let env = HashMap::new();
-        let channel = Channel::Session(chid, Some(w), Some(stdin_rx), env, None);
-        if let Entry::Vacant(entry) = self.channels.entry(chid) {
-            entry.insert(channel);
+        let (resize_tx, resize_rx) = mpsc::unbounded();
+        let channel = Channel::Session(
+            chid,
+            Some(Stdin::Pipe(w, self.memory.charge(0))),
+            Some(stdin_rx),
+            env,
+            None,
+            Some(resize_rx),
+        );
+        self.channels.insert(chid, channel);
+        self.window_changes.insert(chid, resize_tx);
+        self.registry.open(chid, ChannelKind::Session);
+        self.preference
+            .observer()
+            .channel_open(chid, ChannelKind::Session);
+        self.unused.insert(chid, time::Instant::now());
+        let window = LocalWindow::new(*self.preference.channel_window_size());
+        self.windows.insert(chid, window);
+        let remote = RemoteWindow::new(
+            *channel_open.initial_window_size(),
+            *channel_open.maximum_packet_size(),
+        );
+        self.scheduler.set_window(chid, remote);
+        self.admin_closed.remove(&chid);
+        let charge = self.memory.charge(CHANNEL_COST);
+        self.channel_charges.insert(chid, charge);
 
-            let ok = ChannelOpenConfirmation::new(
-                *channel_open.sender_channel(),
-                *channel_open.sender_channel(),
-                *channel_open.initial_window_size(),
-                *channel_open.maximum_packet_size(),
-                "".into(),
-            );
-            self.send(ok).await?;
-        } else {
-            // already exists
+        let ok = ChannelOpenConfirmation::new(
+            *channel_open.sender_channel(),
+            chid,
+            *self.preference.channel_window_size(),
+            MAXIMUM_DATA_SIZE,
+            "".into(),
+        );
+        self.send(ok).await?;
+        Ok(())
+    }
+
+    /// Our id for the channel the client opens, bound to the client's id.
+    ///
+    /// Refuses the open if the client's id still addresses an open channel.
+    async fn allocate_channel(
+        &mut self,
+        channel_open: &ChannelOpen,
+    ) -> Result<Option<u32>, SshError> {
+        let remote = *channel_open.sender_channel();
+        if self.channel_table.remote_in_use(remote) {
             let msg = ChannelOpenFailure::new(
-                *channel_open.sender_channel(),
+                remote,
                 ReasonCode::AdministrativeryProhibited,
                 "already opened".into(),
                 "en-US".into(),
             );
             self.send(msg).await?;
+            return Ok(None);
         }
-        Ok(())
+        let chid = self.next_channel_id();
+        debug!("channel: client channel {} opened as {}", remote, chid);
+        self.channel_table.bind(chid, remote);
+        self.channels_opened += 1;
+        Ok(Some(chid))
+    }
+
+    fn next_channel_id(&mut self) -> u32 {
+        self.closing.expire(time::Instant::now());
+        let channels = &self.channels;
+        let pending_opens = &self.pending_opens;
+        let closing = &self.closing;
+        self.channel_table.allocate(|id| {
+            channels.contains_key(&id) || pending_opens.contains_key(&id) || closing.reserved(id)
+        })
     }
 
     async fn on_channel_open_direct_tcpip(
@@ -77,47 +180,260 @@ where
         channel_open: &ChannelOpen,
         _item: &DirectTcpip,
     ) -> Result<(), SshError> {
-        let chid = *channel_open.sender_channel();
+        self.on_channel_open_direct(channel_open, ChannelKind::DirectTcpip, |h, i, o| {
+            h.dispatch_direct_tcpip(i, o)
+        })
+        .await
+    }
+
+    /// Open a forwarding channel, its data passed to the handler `dispatch` picks.
+    async fn on_channel_open_direct<D>(
+        &mut self,
+        channel_open: &ChannelOpen,
+        kind: ChannelKind,
+        dispatch: D,
+    ) -> Result<(), SshError>
+    where
+        D: FnOnce(
+            &mut Handlers<E, Pty>,
+            SshInput,
+            SshOutput,
+        ) -> Option<BoxFuture<'static, Result<(), E>>>,
+    {
+        let chid = match self.allocate_channel(channel_open).await? {
+            Some(chid) => chid,
+            None => return Ok(()),
+        };
 
         let (input_r, input_w) = tokio_pipe::pipe()?;
         let input = SshInput::new(input_r);
+        let (output_r, output_w) = tokio_pipe::pipe()?;
+        let output = SshOutput::new(output_w);
 
-        let (output, output_closed) = self.new_output(chid, None).await?;
-
-        let channel = Channel::DirectTcpip(chid, Some(input_w));
-        if let Entry::Vacant(entry) = self.channels.entry(chid) {
-            entry.insert(channel);
-
-            if let Some(fut) = self.handlers.dispatch_direct_tcpip(input, output) {
-                self.spawn_handler(chid, output_closed, fut).await;
-                let msg = ChannelOpenConfirmation::new(
-                    *channel_open.sender_channel(),
-                    *channel_open.sender_channel(),
-                    *channel_open.initial_window_size(),
-                    *channel_open.maximum_packet_size(),
-                    "".into(),
-                );
-                self.send(msg).await?;
-            } else {
-                // FIXME unimplemented
+        let fut = match dispatch(&mut self.handlers, input, output) {
+            Some(fut) => fut,
+            None => {
+                self.channel_table.release(chid);
                 let msg = ChannelOpenFailure::new(
                     *channel_open.sender_channel(),
                     ReasonCode::AdministrativeryProhibited,
-                    "already opened".into(),
+                    "not handled".into(),
                     "en-US".into(),
                 );
                 self.send(msg).await?;
+                return Ok(());
             }
-        } else {
-            // already exists
-            let msg = ChannelOpenFailure::new(
-                *channel_open.sender_channel(),
-                ReasonCode::AdministrativeryProhibited,
-                "already opened".into(),
-                "en-US".into(),
-            );
-            self.send(msg).await?;
+        };
+        let output_closed = self
+            .output_readers
+            .lock()
+            .await
+            .insert((chid, None), output_r);
+
+        let channel = Channel::DirectTcpip(chid, Some(Stdin::Pipe(input_w, self.memory.charge(0))));
+        self.channels.insert(chid, channel);
+        self.registry.open(chid, kind);
+        self.preference.observer().channel_open(chid, kind);
+        let window = LocalWindow::new(*self.preference.channel_window_size());
+        self.windows.insert(chid, window);
+        let remote = RemoteWindow::new(
+            *channel_open.initial_window_size(),
+            *channel_open.maximum_packet_size(),
+        );
+        self.scheduler.set_window(chid, remote);
+        self.admin_closed.remove(&chid);
+        let charge = self.memory.charge(CHANNEL_COST);
+        self.channel_charges.insert(chid, charge);
+
+        self.spawn_handler(chid, output_closed, fut).await;
+        let msg = ChannelOpenConfirmation::new(
+            *channel_open.sender_channel(),
+            chid,
+            *self.preference.channel_window_size(),
+            MAXIMUM_DATA_SIZE,
+            "".into(),
+        );
+        self.send(msg).await
+    }
+
+    /// Send channel open on behalf of a handle.
+    ///
+    /// `reply` is answered once the client confirmed or refused, or the
+    /// channel open timeout passed.
+    pub(super) async fn open_channel(
+        &mut self,
+        typ: Type,
+        reply: OpenReply,
+    ) -> Result<(), SshError> {
+        if self.memory.pressure() >= Pressure::Critical {
+            warn!("memory pressure, do not open channel");
+            let err = SshError::ChannelOpenFailed("resource shortage".into());
+            reply.send(Err(err)).ok();
+            return Ok(());
         }
-        Ok(())
+
+        let chid = self.next_channel_id();
+        debug!("channel: {} open {:?}", chid, typ);
+
+        let kind = match typ {
+            Type::ForwardedTcpip(..) => ChannelKind::ForwardedTcpip,
+            Type::X11(..) => ChannelKind::X11,
+            Type::AuthAgent(..) => ChannelKind::AuthAgent,
+            Type::ForwardedStreamlocal(..) => ChannelKind::ForwardedStreamlocal,
+            _ => ChannelKind::Outbound,
+        };
+        let open = PendingOpen {
+            kind,
+            deadline: time::Instant::now() + *self.preference.channel_open_timeout(),
+            reply: Some(reply),
+        };
+        self.pending_opens.insert(chid, open);
+        let msg = ChannelOpen::new(
+            chid,
+            *self.preference.channel_window_size(),
+            MAXIMUM_DATA_SIZE,
+            typ,
+        );
+        self.send(msg).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::run::test_support::{scripted, scripted_with};
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::session_open;
+
+    /// Confirmed opens as `true` and refused ones as `false`, in order.
+    fn open_results(received: &[Msg]) -> Vec<bool> {
+        use msg::channel_open_failure::ReasonCode;
+
+        received
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::ChannelOpenConfirmation(..) => Some(true),
+                Msg::ChannelOpenFailure(failure) => {
+                    assert!(matches!(
+                        failure.reason_code(),
+                        ReasonCode::ResourceShortage
+                    ));
+                    Some(false)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_max_channels() {
+        use msg::channel_close::ChannelClose;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.max_channels(2);
+        let script = vec![
+            session_open(0),
+            session_open(1),
+            session_open(2),
+            ChannelClose::new(0).into(),
+            session_open(3),
+        ];
+        let (result, received, handle) = scripted_with(preference, Handlers::new(), script).await;
+        result.unwrap();
+        assert_eq!(open_results(&received), vec![true, true, false, true]);
+        assert_eq!(handle.channels().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_total_channels() {
+        use msg::channel_close::ChannelClose;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.max_total_channels(2);
+        let script = vec![
+            session_open(0),
+            session_open(1),
+            ChannelClose::new(0).into(),
+            session_open(2),
+        ];
+        let (result, received, _) = scripted_with(preference, Handlers::new(), script).await;
+        result.unwrap();
+        assert_eq!(open_results(&received), vec![true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_direct_streamlocal() {
+        use futures::FutureExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use msg::channel_close::ChannelClose;
+        use msg::channel_open::{ChannelOpen, DirectStreamlocal, Type};
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_direct_streamlocal(|socket_path: String, _, mut output: SshOutput| {
+            async move {
+                output.write_all(socket_path.as_bytes()).await?;
+                Ok(())
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (handle, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let item = DirectStreamlocal::new("/run/app.sock".into(), "".into(), 0);
+            let open = ChannelOpen::new(3, 1024, 1024, Type::DirectStreamlocal(item));
+            theirs.send(open.into()).await.unwrap();
+            let mut data = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                match msg {
+                    Msg::ChannelData(msg) => data.extend_from_slice(msg.data()),
+                    Msg::ChannelClose(..) => {
+                        theirs.send(ChannelClose::new(3).into()).await.unwrap();
+                        break;
+                    }
+                    Msg::ChannelOpenFailure(..) => panic!("refused"),
+                    _ => {}
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            data
+        };
+        let (result, data) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(data, b"/run/app.sock");
+        drop(handle);
+
+        // Refused without a handler.
+        let item = DirectStreamlocal::new("/run/app.sock".into(), "".into(), 0);
+        let open = ChannelOpen::new(3, 1024, 1024, Type::DirectStreamlocal(item));
+        let (result, received, handle) =
+            scripted(PreferenceBuilder::default(), vec![open.into()]).await;
+        result.unwrap();
+        assert!(received
+            .iter()
+            .any(|m| matches!(m, Msg::ChannelOpenFailure(..))));
+        assert!(handle.channels().is_empty());
     }
 }
@@ -0,0 +1,266 @@
+use futures::future;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::channel_close::ChannelClose;
+use crate::msg::channel_open_confirmation::ChannelOpenConfirmation;
+use crate::{HandlerError, ProtocolWarning};
+
+use super::{
+    Channel, LocalWindow, PendingOpen, RemoteWindow, Runner, SshError, SshInput, Stdin,
+    CHANNEL_COST,
+};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) async fn on_channel_open_confirmation(
+        &mut self,
+        confirmation: &ChannelOpenConfirmation,
+    ) -> Result<(), SshError> {
+        let chid = *confirmation.recipient_channel();
+        let (kind, reply) = match self.pending_opens.remove(&chid) {
+            Some(PendingOpen {
+                kind,
+                reply: Some(reply),
+                ..
+            }) => (kind, reply),
+            Some(PendingOpen { reply: None, .. }) => {
+                log::debug!("channel: {} confirmed after timeout, closing.", chid);
+                self.channel_table
+                    .bind(chid, *confirmation.sender_channel());
+                return self.send(ChannelClose::new(chid)).await;
+            }
+            None => {
+                return self.protocol_warning(ProtocolWarning::UnknownChannel {
+                    channel: chid,
+                    message: "open confirmation",
+                })
+            }
+        };
+
+        let (input_r, input_w) = tokio_pipe::pipe()?;
+        let (output, output_closed) = self.new_output(chid, None).await?;
+
+        let channel = Channel::Outbound(chid, Some(Stdin::Pipe(input_w, self.memory.charge(0))));
+        self.channels.insert(chid, channel);
+        self.channel_table
+            .bind(chid, *confirmation.sender_channel());
+        self.registry.open(chid, kind);
+        self.preference.observer().channel_open(chid, kind);
+        let window = LocalWindow::new(*self.preference.channel_window_size());
+        self.windows.insert(chid, window);
+        let remote = RemoteWindow::new(
+            *confirmation.initial_window_size(),
+            *confirmation.maximum_packet_size(),
+        );
+        self.scheduler.set_window(chid, remote);
+        self.admin_closed.remove(&chid);
+        let charge = self.memory.charge(CHANNEL_COST);
+        self.channel_charges.insert(chid, charge);
+
+        // No handler runs. EOF once output is dropped, close after the client's EOF.
+        let completions = self.completions.clone();
+        completions
+            .lock()
+            .await
+            .push((chid, None, vec![output_closed]), future::ok(None));
+        self.closing.started(chid);
+
+        if reply.send(Ok((SshInput::new(input_r), output))).is_err() {
+            log::debug!("channel: {} opener gone.", chid);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::channel::oneshot;
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+    use tokio::time;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::run::Runner;
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::tcpip_forward;
+    use crate::{GlobalHandle, SshError};
+
+    #[tokio::test]
+    async fn test_forwarded_tcpip() {
+        use futures::FutureExt as _;
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        use msg::channel_close::ChannelClose;
+        use msg::channel_data::ChannelData;
+        use msg::channel_eof::ChannelEof;
+        use msg::channel_open::Type;
+        use msg::channel_open_confirmation::ChannelOpenConfirmation;
+        use msg::channel_open_failure::{ChannelOpenFailure, ReasonCode};
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let done_tx = Arc::new(std::sync::Mutex::new(Some(done_tx)));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_tcpip_forward(move |_, port, handle: GlobalHandle| {
+            let done_tx = done_tx.lock().unwrap().take().unwrap();
+            tokio::spawn(async move {
+                let refused = handle
+                    .open_forwarded_tcpip("localhost", port, "::1", 40000)
+                    .await;
+                assert!(matches!(refused, Err(SshError::ChannelOpenFailed(..))));
+
+                let (mut input, mut output) = handle
+                    .open_forwarded_tcpip("localhost", port, "::1", 40001)
+                    .await
+                    .unwrap();
+                output.write_all(b"hello").await.unwrap();
+                drop(output);
+                let mut buf = vec![];
+                input.read_to_end(&mut buf).await.unwrap();
+                done_tx.send(buf).unwrap();
+            });
+            async move { Ok(Some(port)) }.boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(tcpip_forward(8080, true)).await.unwrap();
+            let mut opens = vec![];
+            let mut received = vec![];
+            let mut server = None;
+            while let Some(Ok(msg)) = theirs.next().await {
+                match &msg {
+                    Msg::ChannelOpen(open) => {
+                        let chid = *open.sender_channel();
+                        server = Some(chid);
+                        if let Type::ForwardedTcpip(item) = open.typ() {
+                            opens.push((*item.port(), *item.originator_port()));
+                        }
+                        if opens.len() == 1 {
+                            let reason = ReasonCode::ConnectFailed;
+                            let msg = ChannelOpenFailure::new(chid, reason, "".into(), "".into());
+                            theirs.send(msg.into()).await.unwrap();
+                        } else {
+                            let msg =
+                                ChannelOpenConfirmation::new(chid, 7, 1024, 1024, Bytes::new());
+                            theirs.send(msg.into()).await.unwrap();
+                            let data = ChannelData::new(chid, Bytes::from_static(b"ping"));
+                            theirs.send(data.into()).await.unwrap();
+                            theirs.send(ChannelEof::new(chid).into()).await.unwrap();
+                        }
+                    }
+                    // Output sent after our close would be discarded.
+                    Msg::ChannelEof(..) => {
+                        received.push(msg);
+                        let close = ChannelClose::new(server.unwrap());
+                        theirs.send(close.into()).await.unwrap();
+                    }
+                    Msg::ChannelClose(..) => {
+                        received.push(msg);
+                        break;
+                    }
+                    _ => received.push(msg),
+                }
+            }
+            theirs.close().await.unwrap();
+            (opens, received)
+        };
+        let (result, (opens, received)) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        assert_eq!(opens, vec![(8080, 40000), (8080, 40001)]);
+        assert_eq!(done_rx.await.unwrap(), b"ping");
+        let recipients = received
+            .iter()
+            .filter_map(|m| match m {
+                Msg::ChannelData(m) => Some(("data", *m.recipient_channel())),
+                Msg::ChannelEof(m) => Some(("eof", *m.recipient_channel())),
+                Msg::ChannelClose(m) => Some(("close", *m.recipient_channel())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(recipients, vec![("data", 7), ("eof", 7), ("close", 7)]);
+        assert!(received
+            .iter()
+            .any(|m| matches!(m, Msg::ChannelData(m) if &m.data()[..] == b"hello")));
+    }
+
+    #[tokio::test]
+    async fn test_open_channel_timeout() {
+        use msg::channel_open::Type;
+        use msg::channel_open_confirmation::ChannelOpenConfirmation;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.channel_open_timeout(time::Duration::from_millis(50));
+        let preference = Arc::new(preference.build().await.unwrap());
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (handle, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        )
+        .authenticated();
+
+        let opener = tokio::spawn(async move {
+            let data = Bytes::from_static(b"\x00\x00\x00\x01x");
+            handle.open_channel("test@example.com", data).await
+        });
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let chid = loop {
+                match theirs.next().await.unwrap().unwrap() {
+                    Msg::Kexinit(..) => {}
+                    Msg::ChannelOpen(open) => {
+                        match open.typ() {
+                            Type::Unknown(name, data) => {
+                                assert_eq!(name, "test@example.com");
+                                assert_eq!(&data[..], b"\x00\x00\x00\x01x");
+                            }
+                            x => panic!("{:?}", x),
+                        }
+                        break *open.sender_channel();
+                    }
+                    x => panic!("{:?}", x),
+                }
+            };
+            let opened = opener.await.unwrap();
+            assert!(matches!(opened, Err(SshError::ChannelOpenFailed(..))));
+
+            // Confirmed too late, so closed right away.
+            let msg = ChannelOpenConfirmation::new(chid, 9, 1024, 1024, Bytes::new());
+            theirs.send(msg.into()).await.unwrap();
+            match theirs.next().await.unwrap().unwrap() {
+                Msg::ChannelClose(close) => assert_eq!(*close.recipient_channel(), 9),
+                x => panic!("{:?}", x),
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let (result, _) = tokio::join!(runner.run(), client);
+        result.unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::channel_open_failure::ChannelOpenFailure;
+use crate::{HandlerError, ProtocolWarning};
+
+use super::{Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) fn on_channel_open_failure(
+        &mut self,
+        failure: &ChannelOpenFailure,
+    ) -> Result<(), SshError> {
+        let chid = *failure.recipient_channel();
+        match self.pending_opens.remove(&chid) {
+            Some(open) => {
+                let reason = format!("{:?}: {}", failure.reason_code(), failure.description());
+                log::debug!("channel: {} open failed {}", chid, reason);
+                if let Some(reply) = open.reply {
+                    reply.send(Err(SshError::ChannelOpenFailed(reason))).ok();
+                }
+                Ok(())
+            }
+            None => self.protocol_warning(ProtocolWarning::UnknownChannel {
+                channel: chid,
+                message: "open failure",
+            }),
+        }
+    }
+}
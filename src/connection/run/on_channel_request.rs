@@ -1,13 +1,17 @@
+use std::ffi::OsString;
 use std::os::unix::ffi::OsStringExt;
 
+use bytes::Bytes;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::msg::channel_extended_data::DataTypeCode;
 use crate::msg::channel_failure::ChannelFailure;
-use crate::msg::channel_request::{ChannelRequest, PtyReq, Type};
+use crate::msg::channel_request::{ChannelRequest, PtyReq, Type, WindowChange, X11Req};
 use crate::msg::channel_success::ChannelSuccess;
 
-use crate::HandlerError;
+use crate::connection::ReplySlot;
+use crate::handlers::sanitize;
+use crate::{ChannelMode, HandlerError, SessionContext, Signal, WindowSize, X11Request};
 
 use super::{Channel, Runner, SshError};
 
@@ -23,11 +27,22 @@ where
         match channel_request.typ() {
             Type::Shell(..) => self.on_channel_request_shell(channel_request).await,
             Type::Exec(prog) => self.on_channel_request_exec(channel_request, prog).await,
+            Type::Subsystem(name) => {
+                self.on_channel_request_subsystem(channel_request, name)
+                    .await
+            }
             Type::Env(env) => {
                 self.on_channel_request_env(channel_request, env.name(), env.value())
                     .await
             }
             Type::PtyReq(pty) => self.on_channel_request_pty(channel_request, pty).await,
+            Type::X11Req(x11) => self.on_channel_request_x11(channel_request, x11).await,
+            Type::AuthAgentReq(..) => self.on_channel_request_agent(channel_request).await,
+            Type::Signal(name) => self.on_channel_request_signal(channel_request, name).await,
+            Type::WindowChange(size) => {
+                self.on_channel_request_window_change(channel_request, size)
+                    .await
+            }
             _ => {
                 let r = ChannelFailure::new(*channel_request.recipient_channel());
                 self.send(r).await?;
@@ -42,27 +57,35 @@ where
     ) -> Result<(), SshError> {
         let channel = *channel_request.recipient_channel();
 
-        if let Some(Channel::Session(_, _, stdin, env, pty)) = self.channels.get_mut(&channel) {
+        if let Some(Channel::Session(_, _, stdin, env, pty, resizes)) =
+            self.channels.get_mut(&channel)
+        {
             let env = env.clone();
             let pty = pty.take();
+            let resizes = resizes.take();
             let stdin = stdin.take().unwrap();
 
             let (stdout, stdout_closed) = self.new_output(channel, None).await?;
             let (stderr, stderr_closed) =
                 self.new_output(channel, Some(DataTypeCode::Stderr)).await?;
 
-            if let Some(fut) = self
-                .handlers
-                .dispatch_channel_shell(stdin, stdout, stderr, env, pty)
-            {
+            let outputs = vec![stdout_closed.clone(), stderr_closed.clone()];
+            let handle = self.channel_handle(channel, outputs);
+            let languages = self.languages.clone();
+            let ctx =
+                SessionContext::new(stdin, stdout, stderr, env, pty, resizes, handle, languages);
+            let reply = ctx.reply_slot();
+            let success = if let Some(fut) = self.handlers.dispatch_channel_shell(ctx) {
+                if let Some(stats) = self.registry.get(channel) {
+                    stats.set_mode(ChannelMode::Shell);
+                }
                 self.spawn_shell_handler(channel, stdout_closed, stderr_closed, fut)
                     .await;
-                let r = ChannelSuccess::new(*channel_request.recipient_channel());
-                self.send(r).await?;
+                true
             } else {
-                let r = ChannelFailure::new(*channel_request.recipient_channel());
-                self.send(r).await?;
-            }
+                false
+            };
+            self.reply_unless_deferred(channel, &reply, success).await?;
         } else {
             let r = ChannelFailure::new(*channel_request.recipient_channel());
             self.send(r).await?;
@@ -77,9 +100,12 @@ where
     ) -> Result<(), SshError> {
         let channel = *channel_request.recipient_channel();
 
-        if let Some(Channel::Session(_, _, stdin, env, pty)) = self.channels.get_mut(&channel) {
+        if let Some(Channel::Session(_, _, stdin, env, pty, resizes)) =
+            self.channels.get_mut(&channel)
+        {
             let env = env.clone();
             let pty = pty.take();
+            let resizes = resizes.take();
             let stdin = stdin.take().unwrap();
 
             let (stdout, stdout_closed) = self.new_output(channel, None).await?;
@@ -88,18 +114,70 @@ where
 
             let prog = std::ffi::OsString::from_vec(prog.to_vec());
 
-            if let Some(fut) = self
+            let outputs = vec![stdout_closed.clone(), stderr_closed.clone()];
+            let handle = self.channel_handle(channel, outputs);
+            let languages = self.languages.clone();
+            let ctx =
+                SessionContext::new(stdin, stdout, stderr, env, pty, resizes, handle, languages);
+            let command = prog.clone();
+            let reply = ctx.reply_slot();
+            let success = if let Some(fut) = self.handlers.dispatch_channel_exec(ctx, prog) {
+                if let Some(stats) = self.registry.get(channel) {
+                    stats.set_mode(ChannelMode::Exec(command));
+                }
+                self.spawn_shell_handler(channel, stdout_closed, stderr_closed, fut)
+                    .await;
+                true
+            } else {
+                false
+            };
+            self.reply_unless_deferred(channel, &reply, success).await?;
+        } else {
+            let r = ChannelFailure::new(*channel_request.recipient_channel());
+            self.send(r).await?;
+        }
+        Ok(())
+    }
+
+    pub(super) async fn on_channel_request_subsystem(
+        &mut self,
+        channel_request: &ChannelRequest,
+        name: &str,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+
+        if let Some(Channel::Session(_, _, stdin, env, pty, resizes)) =
+            self.channels.get_mut(&channel)
+        {
+            let env = env.clone();
+            let pty = pty.take();
+            let resizes = resizes.take();
+            let stdin = stdin.take().unwrap();
+
+            let (stdout, stdout_closed) = self.new_output(channel, None).await?;
+            let (stderr, stderr_closed) =
+                self.new_output(channel, Some(DataTypeCode::Stderr)).await?;
+
+            let outputs = vec![stdout_closed.clone(), stderr_closed.clone()];
+            let handle = self.channel_handle(channel, outputs);
+            let languages = self.languages.clone();
+            let ctx =
+                SessionContext::new(stdin, stdout, stderr, env, pty, resizes, handle, languages);
+            let reply = ctx.reply_slot();
+            let success = if let Some(fut) = self
                 .handlers
-                .dispatch_channel_exec(stdin, stdout, stderr, prog, env, pty)
+                .dispatch_channel_subsystem(ctx, name.to_string())
             {
+                if let Some(stats) = self.registry.get(channel) {
+                    stats.set_mode(ChannelMode::Subsystem(name.to_string()));
+                }
                 self.spawn_shell_handler(channel, stdout_closed, stderr_closed, fut)
                     .await;
-                let r = ChannelSuccess::new(*channel_request.recipient_channel());
-                self.send(r).await?;
+                true
             } else {
-                let r = ChannelFailure::new(*channel_request.recipient_channel());
-                self.send(r).await?;
-            }
+                false
+            };
+            self.reply_unless_deferred(channel, &reply, success).await?;
         } else {
             let r = ChannelFailure::new(*channel_request.recipient_channel());
             self.send(r).await?;
@@ -107,21 +185,195 @@ where
         Ok(())
     }
 
+    /// Answer now unless the handler took the reply with
+    /// [`SessionContext::defer_reply`].
+    async fn reply_unless_deferred(
+        &mut self,
+        channel: u32,
+        reply: &ReplySlot,
+        success: bool,
+    ) -> Result<(), SshError> {
+        let responder = reply.lock().unwrap().take();
+        match responder {
+            Some(responder) => {
+                responder.disarm();
+                self.reply_deferred(channel, success).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Answer a shell, exec or subsystem request, unless the channel is gone.
+    pub(super) async fn reply_deferred(
+        &mut self,
+        channel: u32,
+        success: bool,
+    ) -> Result<(), SshError> {
+        if !self.channels.contains_key(&channel) {
+            return Ok(());
+        }
+        if success {
+            self.send(ChannelSuccess::new(channel)).await
+        } else {
+            self.send(ChannelFailure::new(channel)).await
+        }
+    }
+
     pub(super) async fn on_channel_request_env(
         &mut self,
         channel_request: &ChannelRequest,
         name: &str,
-        value: &str,
+        value: &Bytes,
     ) -> Result<(), SshError> {
         let channel = *channel_request.recipient_channel();
 
-        if let Some(Channel::Session(_, _, _, ref mut env, _)) = self.channels.get_mut(&channel) {
-            env.insert(name.to_owned(), value.to_owned());
-            let r = ChannelSuccess::new(*channel_request.recipient_channel());
-            self.send(r).await?;
+        let accepted = if let Some(Channel::Session(..)) = self.channels.get(&channel) {
+            match self
+                .handlers
+                .dispatch_channel_env(channel, name.to_owned(), value.clone())
+            {
+                Some(fut) => fut.await.unwrap_or_else(|err| {
+                    log::warn!(
+                        "{}",
+                        sanitize(
+                            err,
+                            *self.preference.error_limit(),
+                            self.preference.observer()
+                        )
+                    );
+                    false
+                }),
+                None => true,
+            }
         } else {
-            let r = ChannelFailure::new(*channel_request.recipient_channel());
-            self.send(r).await?;
+            false
+        };
+        if accepted {
+            if let Some(Channel::Session(_, _, _, env, _, _)) = self.channels.get_mut(&channel) {
+                env.insert(name.to_owned(), OsString::from_vec(value.to_vec()));
+            }
+        }
+
+        if *channel_request.want_reply() {
+            if accepted {
+                self.send(ChannelSuccess::new(channel)).await?;
+            } else {
+                self.send(ChannelFailure::new(channel)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) async fn on_channel_request_x11(
+        &mut self,
+        channel_request: &ChannelRequest,
+        x11: &X11Req,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+
+        let accepted = if let Some(Channel::Session(..)) = self.channels.get(&channel) {
+            let request = X11Request::new(
+                *x11.single_connection(),
+                x11.x11_auth_protocol().clone(),
+                x11.x11_auth_cookie().clone(),
+                *x11.x11_screen_number(),
+            );
+            let handle = self.global_handle.clone();
+            match self
+                .handlers
+                .dispatch_channel_x11_request(channel, request, handle)
+            {
+                Some(fut) => fut.await.map_err(|e| self.handler_error(e))?,
+                None => false,
+            }
+        } else {
+            false
+        };
+
+        if *channel_request.want_reply() {
+            if accepted {
+                self.send(ChannelSuccess::new(channel)).await?;
+            } else {
+                self.send(ChannelFailure::new(channel)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) async fn on_channel_request_agent(
+        &mut self,
+        channel_request: &ChannelRequest,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+
+        let accepted = if let Some(Channel::Session(..)) = self.channels.get(&channel) {
+            let handle = self.global_handle.clone();
+            match self
+                .handlers
+                .dispatch_channel_agent_forward_request(channel, handle)
+            {
+                Some(fut) => fut.await.map_err(|e| self.handler_error(e))?,
+                None => false,
+            }
+        } else {
+            false
+        };
+
+        if *channel_request.want_reply() {
+            if accepted {
+                self.send(ChannelSuccess::new(channel)).await?;
+            } else {
+                self.send(ChannelFailure::new(channel)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Never answered, `want_reply` is false per RFC 4254.
+    pub(super) async fn on_channel_request_signal(
+        &mut self,
+        channel_request: &ChannelRequest,
+        name: &str,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+        if let Some(Channel::Session(..)) = self.channels.get(&channel) {
+            let signal = name.parse::<Signal>().unwrap_or_else(|e| match e {});
+            let handle = self.global_handle.clone();
+            if let Some(fut) = self
+                .handlers
+                .dispatch_channel_signal(channel, signal, handle)
+            {
+                fut.await.map_err(|e| self.handler_error(e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward resize to the session. Never answered unless a reply is wanted.
+    pub(super) async fn on_channel_request_window_change(
+        &mut self,
+        channel_request: &ChannelRequest,
+        size: &WindowChange,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+        let size = WindowSize::new(
+            *size.width(),
+            *size.height(),
+            *size.width_px(),
+            *size.height_px(),
+        );
+        // Fails once the session dropped its receiver.
+        let delivered = self
+            .window_changes
+            .get(&channel)
+            .is_some_and(|tx| tx.unbounded_send(size).is_ok());
+
+        if *channel_request.want_reply() {
+            if delivered {
+                self.send(ChannelSuccess::new(channel)).await?;
+            } else {
+                self.send(ChannelFailure::new(channel)).await?;
+            }
         }
         Ok(())
     }
@@ -139,14 +391,15 @@ where
         let height_px = ptyreq.height_px();
         let modes = ptyreq.modes();
 
-        if let Some(Channel::Session(_, _, _, _, ref mut pty)) = self.channels.get_mut(&channel) {
+        if let Some(Channel::Session(_, _, _, _, ref mut pty, _)) = self.channels.get_mut(&channel)
+        {
             if let Some(fut) = self.handlers.dispatch_channel_pty_req(
                 term.to_owned(),
                 *width,
                 *height,
                 *width_px,
                 *height_px,
-                modes.into_iter().cloned().collect(),
+                modes.clone().into_vec(),
             ) {
                 match fut.await {
                     Ok(p) => {
@@ -155,7 +408,14 @@ where
                         self.send(r).await?;
                     }
                     Err(err) => {
-                        log::warn!("{}", err.into());
+                        log::warn!(
+                            "{}",
+                            sanitize(
+                                err,
+                                *self.preference.error_limit(),
+                                self.preference.observer()
+                            )
+                        );
                         let r = ChannelFailure::new(*channel_request.recipient_channel());
                         self.send(r).await?;
                     }
@@ -171,3 +431,395 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::run::test_support::{scripted, scripted_with};
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::session_open;
+
+    #[tokio::test]
+    async fn test_channel_x11_request() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Type, X11Req};
+
+        use crate::X11Request;
+
+        let requests = Arc::new(StdMutex::new(vec![]));
+        let captured = requests.clone();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_x11_request(move |channel, request: X11Request, _| {
+            let accepted = *request.screen_number() == 0;
+            captured.lock().unwrap().push((channel, request));
+            async move { Ok(accepted) }.boxed()
+        });
+
+        let request = |screen| -> Msg {
+            let cookie = Bytes::from_static(b"0123456789abcdef");
+            let x11 = X11Req::new(true, "MIT-MAGIC-COOKIE-1".into(), cookie, screen);
+            ChannelRequest::new(0, true, Type::X11Req(x11)).into()
+        };
+        let script = vec![session_open(0), request(0), request(1)];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        let replies = received
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::ChannelSuccess(..) => Some(true),
+                Msg::ChannelFailure(..) => Some(false),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(replies, vec![true, false]);
+        let requests = requests.lock().unwrap();
+        let (channel, request) = &requests[0];
+        assert_eq!(*channel, 0);
+        assert!(*request.single_connection());
+        assert_eq!(request.auth_protocol(), "MIT-MAGIC-COOKIE-1");
+        assert_eq!(&request.auth_cookie()[..], b"0123456789abcdef");
+    }
+
+    #[tokio::test]
+    async fn test_channel_signal() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Type};
+
+        let signals = Arc::new(StdMutex::new(vec![]));
+        let captured = signals.clone();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_signal(move |channel, signal: Signal, _| {
+            captured.lock().unwrap().push((channel, signal));
+            async { Ok(()) }.boxed()
+        });
+
+        let signal = |channel, name: &str| -> Msg {
+            ChannelRequest::new(channel, true, Type::Signal(name.into())).into()
+        };
+        // Channel 1 is not open.
+        let script = vec![
+            session_open(0),
+            signal(0, "INT"),
+            signal(1, "TERM"),
+            signal(0, "XCPU@example.com"),
+        ];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        assert!(!received
+            .iter()
+            .any(|msg| matches!(msg, Msg::ChannelSuccess(..) | Msg::ChannelFailure(..))));
+        assert_eq!(
+            *signals.lock().unwrap(),
+            vec![
+                (0, Signal::Int),
+                (0, Signal::Other("XCPU@example.com".into()))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_agent_forward_request() {
+        use futures::FutureExt as _;
+
+        use msg::channel_request::{ChannelRequest, Type};
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers
+            .on_channel_agent_forward_request(|channel, _| async move { Ok(channel == 0) }.boxed());
+
+        let request =
+            |channel| -> Msg { ChannelRequest::new(channel, true, Type::AuthAgentReq(())).into() };
+        // Channel 1 is not open, so it is refused without calling the handler.
+        let script = vec![session_open(0), request(0), request(1)];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        let replies = received
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::ChannelSuccess(..) => Some(true),
+                Msg::ChannelFailure(..) => Some(false),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(replies, vec![true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_channel_env() {
+        use futures::FutureExt as _;
+        use std::os::unix::ffi::OsStrExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Env, Type};
+
+        use crate::SessionContext;
+
+        let env = Arc::new(StdMutex::new(None));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        let seen = Arc::new(StdMutex::new(vec![]));
+        let record = seen.clone();
+        handlers.on_channel_env(move |channel, name: String, _| {
+            record.lock().unwrap().push(channel);
+            async move { Ok(name != "SECRET") }.boxed()
+        });
+        let captured = env.clone();
+        handlers.on_channel_exec(move |ctx: SessionContext, _| {
+            *captured.lock().unwrap() = Some(ctx.env().clone());
+            async { Ok(0) }.boxed()
+        });
+
+        let request = |want_reply, name: &str, value: &'static [u8]| -> Msg {
+            let env = Env::new(name.into(), Bytes::from_static(value));
+            ChannelRequest::new(0, want_reply, Type::Env(env)).into()
+        };
+        let exec = Type::Exec(Bytes::from_static(b"env"));
+        let script = vec![
+            session_open(0),
+            request(true, "LANG", b"C"),
+            request(true, "SECRET", b"x"),
+            request(false, "LC_ALL", b"\xff"),
+            request(false, "SECRET", b"y"),
+            ChannelRequest::new(0, false, exec).into(),
+        ];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        let replies = received
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::ChannelSuccess(..) => Some(true),
+                Msg::ChannelFailure(..) => Some(false),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Last one answers exec.
+        assert_eq!(replies, vec![true, false, true]);
+
+        let env = env.lock().unwrap().take().unwrap();
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["LANG"], "C");
+        assert_eq!(env["LC_ALL"].as_bytes(), b"\xff");
+        assert_eq!(*seen.lock().unwrap(), vec![0; 4]);
+    }
+
+    #[tokio::test]
+    async fn test_subsystem_request() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let requested = Arc::new(StdMutex::new(vec![]));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        let captured = requested.clone();
+        handlers.on_channel_subsystem(move |_: SessionContext, name| {
+            captured.lock().unwrap().push(name);
+            async { Ok(0) }.boxed()
+        });
+
+        let script = || {
+            let sftp = Type::Subsystem("sftp".into());
+            vec![session_open(0), ChannelRequest::new(0, true, sftp).into()]
+        };
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script()).await;
+        result.unwrap();
+        assert!(received
+            .iter()
+            .any(|msg| matches!(msg, Msg::ChannelSuccess(..))));
+        assert_eq!(*requested.lock().unwrap(), vec!["sftp".to_string()]);
+
+        // Refused without handler.
+        let (result, received, _) = scripted(PreferenceBuilder::default(), script()).await;
+        result.unwrap();
+        assert!(received
+            .iter()
+            .any(|msg| matches!(msg, Msg::ChannelFailure(..))));
+        assert!(!received
+            .iter()
+            .any(|msg| matches!(msg, Msg::ChannelSuccess(..))));
+    }
+
+    #[tokio::test]
+    async fn test_pty_request() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Modes, PtyReq, Type};
+
+        let requested = Arc::new(StdMutex::new(vec![]));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        let captured = requested.clone();
+        handlers.on_channel_pty_request(move |term, width, height, width_px, height_px, modes| {
+            let request = (term, width, height, width_px, height_px, modes);
+            captured.lock().unwrap().push(request);
+            async { Ok(()) }.boxed()
+        });
+
+        let modes = Modes::new(vec![(53, 0), (129, 38400)]);
+        let pty = PtyReq::new("xterm-256color".into(), 120, 40, 960, 640, modes);
+        let script = vec![
+            session_open(0),
+            ChannelRequest::new(0, true, Type::PtyReq(pty)).into(),
+        ];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        assert!(received
+            .iter()
+            .any(|msg| matches!(msg, Msg::ChannelSuccess(..))));
+        let requested = requested.lock().unwrap();
+        assert_eq!(
+            *requested,
+            vec![(
+                "xterm-256color".to_string(),
+                120,
+                40,
+                960,
+                640,
+                vec![(53, 0), (129, 38400)]
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_window_change() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Type, WindowChange};
+
+        use crate::{SessionContext, WindowSize};
+
+        let sizes = Arc::new(StdMutex::new(vec![]));
+        let captured = sizes.clone();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(move |mut ctx: SessionContext, _| {
+            let mut resizes = ctx.take_window_changes().unwrap();
+            assert!(ctx.take_window_changes().is_none());
+            let captured = captured.clone();
+            async move {
+                for _ in 0..2 {
+                    let size = resizes.next().await.unwrap();
+                    captured.lock().unwrap().push(size);
+                }
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let exec = Type::Exec(Bytes::from_static(b"top"));
+            let script = vec![
+                session_open(0),
+                ChannelRequest::new(0, false, exec).into(),
+                ChannelRequest::new(
+                    0,
+                    false,
+                    Type::WindowChange(WindowChange::new(80, 24, 0, 0)),
+                )
+                .into(),
+                ChannelRequest::new(
+                    0,
+                    true,
+                    Type::WindowChange(WindowChange::new(132, 43, 1056, 688)),
+                )
+                .into(),
+            ];
+            for msg in script {
+                theirs.send(msg).await.unwrap();
+            }
+
+            let mut replies = 0;
+            while let Some(Ok(msg)) = theirs.next().await {
+                match msg {
+                    Msg::ChannelSuccess(..) | Msg::ChannelFailure(..) => replies += 1,
+                    Msg::ChannelClose(..) => break,
+                    _ => {}
+                }
+            }
+            theirs.close().await.unwrap();
+            replies
+        };
+        let (result, replies) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        // Exec and the second resize.
+        assert_eq!(replies, 2);
+        assert_eq!(
+            *sizes.lock().unwrap(),
+            vec![
+                WindowSize::new(80, 24, 0, 0),
+                WindowSize::new(132, 43, 1056, 688)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_non_utf8() {
+        use futures::FutureExt as _;
+        use std::os::unix::ffi::OsStrExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::channel_request::{ChannelRequest, Type};
+
+        let captured = Arc::new(StdMutex::new(None));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        let sink = captured.clone();
+        handlers.on_channel_exec(move |_, prog: std::ffi::OsString| {
+            *sink.lock().unwrap() = Some(prog);
+            async { Ok(0) }.boxed()
+        });
+
+        let exec = Type::Exec(Bytes::from_static(b"ls \xff"));
+        let script = vec![session_open(0), ChannelRequest::new(0, true, exec).into()];
+        let (result, _, handle) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        let prog = captured.lock().unwrap().take().unwrap();
+        assert_eq!(prog.as_bytes(), b"ls \xff");
+        assert_eq!(prog.to_string_lossy(), "ls \u{fffd}");
+        let mode = handle.channels()[0].mode().clone().unwrap();
+        assert_eq!(mode, crate::ChannelMode::Exec(prog));
+        assert_eq!(mode.command_lossy().unwrap(), "ls \u{fffd}");
+    }
+}
@@ -1,7 +1,7 @@
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::msg::channel_window_adjust::ChannelWindowAdjust;
-use crate::HandlerError;
+use crate::{HandlerError, ProtocolWarning};
 
 use super::{Runner, SshError};
 
@@ -14,11 +14,60 @@ where
         &mut self,
         channel_window_adjust: &ChannelWindowAdjust,
     ) -> Result<(), SshError> {
-        // FIXME window adjust management
-        let m = ChannelWindowAdjust::new(
-            *channel_window_adjust.recipient_channel(),
-            *channel_window_adjust.bytes_to_add(),
-        );
-        self.send(m).await
+        let chid = *channel_window_adjust.recipient_channel();
+        if !self.channels.contains_key(&chid) {
+            return self.protocol_warning(ProtocolWarning::UnknownChannel {
+                channel: chid,
+                message: "window adjust",
+            });
+        }
+
+        let bytes_to_add = *channel_window_adjust.bytes_to_add();
+        if !self.scheduler.adjust_window(chid, bytes_to_add) {
+            return self.protocol_warning(ProtocolWarning::WindowOverAdjust {
+                channel: chid,
+                bytes_to_add,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::run::test_support::{scripted, scripted_with};
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::session_open;
+    use crate::WarningKind;
+
+    #[tokio::test]
+    async fn test_window_adjust_not_echoed() {
+        use msg::channel_window_adjust::ChannelWindowAdjust;
+
+        let script = vec![session_open(0), ChannelWindowAdjust::new(0, 0x1000).into()];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), Handlers::new(), script).await;
+        result.unwrap();
+        match &received[..] {
+            [Msg::Kexinit(..), Msg::ChannelOpenConfirmation(..)] => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_over_adjust() {
+        use msg::channel_window_adjust::ChannelWindowAdjust;
+
+        let script = vec![
+            session_open(0),
+            ChannelWindowAdjust::new(0, 0x1000).into(),
+            ChannelWindowAdjust::new(0, u32::MAX).into(),
+        ];
+        let (result, received, handle) = scripted(PreferenceBuilder::default(), script).await;
+        result.unwrap();
+        assert!(!received.iter().any(|m| matches!(m, Msg::Disconnect(..))));
+        assert_eq!(handle.warnings()[&WarningKind::WindowOverAdjust], 1);
     }
 }
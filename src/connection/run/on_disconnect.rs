@@ -0,0 +1,214 @@
+use crate::trace::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::handlers::sanitize;
+use crate::msg::disconnect::Disconnect;
+use crate::{DisconnectReason, HandlerError};
+
+use super::{Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    /// Client disconnected. Nothing is sent afterwards.
+    pub(super) async fn on_disconnect(&mut self, disconnect: &Disconnect) -> Result<(), SshError> {
+        let reason = disconnect.reason_code().clone();
+        let description = disconnect.description().clone();
+        debug!("client disconnected {:?}: {}", reason, description);
+        self.disconnected = true;
+
+        if let Some(fut) = self.handlers.dispatch_disconnected(reason, description) {
+            if let Err(err) = fut.await {
+                warn!(
+                    "{}",
+                    sanitize(
+                        err,
+                        *self.preference.error_limit(),
+                        self.preference.observer()
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Disconnect requested through a handle. Channels still running are
+    /// reported killed first.
+    pub(super) async fn disconnect(
+        &mut self,
+        reason: DisconnectReason,
+        description: String,
+    ) -> Result<(), SshError> {
+        debug!("disconnect {:?}: {}", reason, description);
+        self.disconnected = true;
+        self.abort_channels().await?;
+        self.send(Disconnect::new(reason, description, "".into()))
+            .await
+    }
+
+    /// Shutdown requested through a handle. Output taken from handlers so
+    /// far goes out first, unless it waits for the client's window.
+    pub(super) async fn shutdown(
+        &mut self,
+        reason: DisconnectReason,
+        description: String,
+    ) -> Result<(), SshError> {
+        debug!("shutdown {:?}: {}", reason, description);
+        self.terminate().await;
+        self.drain_requests();
+        while let Ok(queued) = self.msg_queue_rx.try_recv() {
+            self.enqueue(queued);
+        }
+        while let Some((msg, _charge)) = self.scheduler.pop() {
+            self.send(msg).await?;
+        }
+        self.disconnect(reason, description).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::prelude::*;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::run::test_support::scripted_with;
+    use crate::connection::run::Runner;
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::{service_request, session_open};
+    use crate::{DisconnectReason, GlobalHandle};
+
+    /// Open `channels` shells that never return, then `stop` the connection.
+    ///
+    /// Returns what the client received after the shells started.
+    async fn stop_with_shells<F>(channels: u32, stop: F) -> Vec<Msg>
+    where
+        F: FnOnce(&GlobalHandle),
+    {
+        use futures::FutureExt as _;
+        use msg::channel_request::{ChannelRequest, Type};
+
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_shell(|_: SessionContext| future::pending().boxed());
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (handle, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            for channel in 0..channels {
+                theirs.send(session_open(5 + channel)).await.unwrap();
+                let shell = ChannelRequest::new(channel, true, Type::Shell(()));
+                theirs.send(shell.into()).await.unwrap();
+            }
+            let mut started = 0;
+            while started < channels {
+                if let Msg::ChannelSuccess(..) = theirs.next().await.unwrap().unwrap() {
+                    started += 1;
+                }
+            }
+            stop(&handle);
+            let mut received = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                received.push(msg);
+            }
+            received
+        };
+        let (result, received) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        received
+    }
+
+    /// Client channels told they were killed, each followed by its close.
+    fn killed(received: &[Msg]) -> Vec<u32> {
+        use msg::channel_request::Type;
+
+        let mut killed = vec![];
+        for (msg, next) in received.iter().zip(received.iter().skip(1)) {
+            if let (Msg::ChannelRequest(req), Msg::ChannelClose(close)) = (msg, next) {
+                if let Type::ExitSignal(signal) = req.typ() {
+                    assert_eq!(signal.name(), "KILL");
+                    assert_eq!(req.recipient_channel(), close.recipient_channel());
+                    killed.push(*req.recipient_channel());
+                }
+            }
+        }
+        killed
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_kills_channels() {
+        let received = stop_with_shells(1, |handle| {
+            handle
+                .shutdown(DisconnectReason::ByApplication, "bye")
+                .unwrap()
+        })
+        .await;
+        assert_eq!(killed(&received), vec![5]);
+        assert!(matches!(received.last(), Some(Msg::Disconnect(..))));
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use msg::disconnect::{Disconnect, ReasonCode};
+
+        let reasons = Arc::new(StdMutex::new(vec![]));
+        let captured = reasons.clone();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_disconnected(move |reason, description| {
+            captured.lock().unwrap().push((reason, description));
+            async { Ok(()) }.boxed()
+        });
+
+        let disconnect = Disconnect::new(ReasonCode::ByApplication, "bye".into(), "".into());
+        let script = vec![disconnect.into(), service_request("ssh-userauth")];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+        assert_eq!(
+            *reasons.lock().unwrap(),
+            vec![(ReasonCode::ByApplication, "bye".to_string())]
+        );
+        assert!(!received
+            .iter()
+            .any(|msg| matches!(msg, Msg::ServiceAccept(..))));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_kills_channels() {
+        let received = stop_with_shells(2, |handle| {
+            handle
+                .disconnect(DisconnectReason::ByApplication, "bye")
+                .unwrap()
+        })
+        .await;
+        let mut killed = killed(&received);
+        killed.sort();
+        assert_eq!(killed, vec![5, 6]);
+        match received.last() {
+            Some(Msg::Disconnect(msg)) => assert_eq!(msg.description(), "bye"),
+            x => panic!("{:?}", x),
+        }
+    }
+}
@@ -1,9 +1,16 @@
+use std::convert::TryFrom;
+
+use bytes::{BufMut as _, Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::msg::global_request::{GlobalRequest, Type};
+use crate::handlers::sanitize;
+use crate::key;
+use crate::msg::global_request::{GlobalRequest, Hostkeys, Type};
 use crate::msg::request_failure::RequestFailure;
+use crate::msg::request_success::RequestSuccess;
+use crate::pack::Pack as _;
 
-use crate::HandlerError;
+use crate::{GlobalResponse, HandlerError};
 
 use super::{Runner, SshError};
 
@@ -16,23 +23,466 @@ where
         &mut self,
         global_request: &GlobalRequest,
     ) -> Result<(), SshError> {
-        match global_request.typ() {
-            Type::TcpipForward(..) => {
-                log::debug!("not implemented for tcpip forward.");
-                let r = RequestFailure::new();
-                self.send(r).await?;
+        let reply = match global_request.typ() {
+            Type::TcpipForward(item) => {
+                let address = item.address_to_bind().clone();
+                let requested = *item.port_number_to_bind();
+                let bound = match u16::try_from(requested) {
+                    Ok(port) => self.on_tcpip_forward(address, port).await,
+                    Err(..) => None,
+                };
+                bound.map(|port| {
+                    // Only a requested port 0 is answered with the bound one.
+                    let mut data = BytesMut::new();
+                    if requested == 0 {
+                        data.put_u32(port as u32);
+                    }
+                    data.freeze()
+                })
+            }
+            Type::CancelTcpipForward(item) => {
+                let address = item.address_to_bind().clone();
+                let cancelled = match u16::try_from(*item.port_number_to_bind()) {
+                    Ok(port) => self.on_cancel_tcpip_forward(address, port).await,
+                    Err(..) => false,
+                };
+                cancelled.then(Bytes::new)
+            }
+            Type::StreamlocalForward(item) => {
+                let socket_path = item.socket_path().clone();
+                self.on_streamlocal_forward(socket_path)
+                    .await
+                    .then(Bytes::new)
+            }
+            Type::CancelStreamlocalForward(item) => {
+                let socket_path = item.socket_path().clone();
+                self.on_cancel_streamlocal_forward(socket_path)
+                    .await
+                    .then(Bytes::new)
+            }
+            // Client probing whether we are alive.
+            Type::Keepalive => Some(Bytes::new()),
+            Type::NoMoreSessions => {
+                self.no_more_sessions = true;
+                Some(Bytes::new())
             }
-            Type::CancelTcpipForward(..) => {
-                log::debug!("not implemented for cancel tcpip forward.");
-                let r = RequestFailure::new();
-                self.send(r).await?;
+            // Only ever sent by servers.
+            Type::Hostkeys(..) => None,
+            Type::HostkeysProve(item) => self.prove_hostkeys(item),
+            Type::Unknown(name, data) => {
+                self.on_unknown_global_request(name.clone(), data.clone())
+                    .await?
             }
-            Type::Unknown(..) => {
-                log::debug!("unknown request.");
-                let r = RequestFailure::new();
-                self.send(r).await?;
+        };
+
+        if !*global_request.want_reply() {
+            return Ok(());
+        }
+        match reply {
+            Some(data) => self.send(RequestSuccess::new(data)).await,
+            None => self.send(RequestFailure::new()).await,
+        }
+    }
+
+    /// Announce every host key, as OpenSSH does after auth.
+    pub(super) async fn send_hostkeys(&mut self) -> Result<(), SshError> {
+        let keys = self
+            .preference
+            .hostkeys()
+            .keys()
+            .map(|key| key.publickey().blob())
+            .collect();
+        let typ = Type::Hostkeys(Hostkeys::new(keys));
+        self.send(GlobalRequest::new(false, typ)).await
+    }
+
+    /// Signatures over the session proving we hold each requested key.
+    fn prove_hostkeys(&self, hostkeys: &Hostkeys) -> Option<Bytes> {
+        if !*self.preference.hostkeys_update() {
+            return None;
+        }
+        let session_id = self.identity.session_id()?;
+        let negotiated = self.identity.algorithms()?;
+        let mut signatures = BytesMut::new();
+        for blob in hostkeys.keys() {
+            let key = match self.preference.hostkeys().lookup_blob(blob) {
+                Some(key) => key,
+                None => {
+                    log::debug!("hostkeys prove for unknown key.");
+                    return None;
+                }
+            };
+            // RSA signs as negotiated if that was RSA, like OpenSSH does.
+            let algorithm = match key.name() {
+                key::Algorithm::SshRsa => {
+                    let negotiated = negotiated.server_host_key_algorithm();
+                    if negotiated.key_algorithm() == key::Algorithm::SshRsa {
+                        negotiated.clone()
+                    } else {
+                        key::Algorithm::RsaSha2_512
+                    }
+                }
+                name => name,
+            };
+            let mut target = BytesMut::new();
+            "hostkeys-prove-00@openssh.com".pack(&mut target);
+            session_id.pack(&mut target);
+            blob.pack(&mut target);
+            key.sign(&algorithm, &target.freeze()).pack(&mut signatures);
+        }
+        Some(signatures.freeze())
+    }
+
+    async fn on_unknown_global_request(
+        &mut self,
+        name: String,
+        data: Bytes,
+    ) -> Result<Option<Bytes>, SshError> {
+        let handle = self.global_handle.clone();
+        let response = match self
+            .handlers
+            .dispatch_global_request(name.clone(), data, handle)
+        {
+            Some(fut) => fut.await.map_err(|e| self.handler_error(e))?,
+            None => {
+                log::debug!("unknown request {}.", name);
+                GlobalResponse::Failure
+            }
+        };
+        Ok(match response {
+            GlobalResponse::Success(data) => Some(data),
+            GlobalResponse::Failure => None,
+        })
+    }
+
+    /// Refuse the request on handler error, the connection stays up.
+    fn forward_failed<T: Default>(&self, err: E) -> T {
+        log::warn!(
+            "{}",
+            sanitize(
+                err,
+                *self.preference.error_limit(),
+                self.preference.observer()
+            )
+        );
+        T::default()
+    }
+
+    async fn on_tcpip_forward(&mut self, address: String, port: u16) -> Option<u16> {
+        let handle = self.global_handle.clone();
+        match self.handlers.dispatch_tcpip_forward(address, port, handle) {
+            Some(fut) => fut.await.unwrap_or_else(|e| self.forward_failed(e)),
+            None => {
+                log::debug!("tcpip forward not handled.");
+                None
+            }
+        }
+    }
+
+    async fn on_cancel_tcpip_forward(&mut self, address: String, port: u16) -> bool {
+        match self.handlers.dispatch_cancel_tcpip_forward(address, port) {
+            Some(fut) => fut.await.unwrap_or_else(|e| self.forward_failed(e)),
+            None => {
+                log::debug!("cancel tcpip forward not handled.");
+                false
+            }
+        }
+    }
+
+    async fn on_streamlocal_forward(&mut self, socket_path: String) -> bool {
+        let handle = self.global_handle.clone();
+        match self
+            .handlers
+            .dispatch_streamlocal_forward(socket_path, handle)
+        {
+            Some(fut) => fut.await.unwrap_or_else(|e| self.forward_failed(e)),
+            None => {
+                log::debug!("streamlocal forward not handled.");
+                false
+            }
+        }
+    }
+
+    async fn on_cancel_streamlocal_forward(&mut self, socket_path: String) -> bool {
+        match self
+            .handlers
+            .dispatch_cancel_streamlocal_forward(socket_path)
+        {
+            Some(fut) => fut.await.unwrap_or_else(|e| self.forward_failed(e)),
+            None => {
+                log::debug!("cancel streamlocal forward not handled.");
+                false
             }
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::channel::oneshot;
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::run::test_support::{scripted, scripted_with};
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::{session_open, tcpip_forward};
+    use crate::GlobalHandle;
+
+    #[tokio::test]
+    async fn test_tcpip_forward() {
+        use futures::FutureExt as _;
+
+        use msg::global_request::{CancelTcpipForward, GlobalRequest, Type};
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_tcpip_forward(|address, port, _| {
+            assert_eq!(address, "localhost");
+            let bound = match port {
+                0 => Some(4321),
+                1 => None,
+                port => Some(port),
+            };
+            async move { Ok(bound) }.boxed()
+        });
+        handlers.on_cancel_tcpip_forward(|_, port| async move { Ok(port == 22) }.boxed());
+
+        let cancel = |port| {
+            let item = CancelTcpipForward::new("localhost".into(), port);
+            Msg::from(GlobalRequest::new(true, Type::CancelTcpipForward(item)))
+        };
+        let script = vec![
+            tcpip_forward(0, true),
+            tcpip_forward(22, true),
+            tcpip_forward(1, true),
+            tcpip_forward(0x10000, true),
+            tcpip_forward(0, false),
+            cancel(22),
+            cancel(23),
+        ];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        let replies = received
+            .iter()
+            .filter_map(|m| match m {
+                Msg::RequestSuccess(..) | Msg::RequestFailure(..) => {
+                    let mut buf = BytesMut::new();
+                    crate::pack::Pack::pack(m, &mut buf);
+                    Some(buf.freeze())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replies,
+            vec![
+                Bytes::from_static(&[81, 0, 0, 0x10, 0xe1]),
+                Bytes::from_static(&[81]),
+                Bytes::from_static(&[82]),
+                Bytes::from_static(&[82]),
+                Bytes::from_static(&[81]),
+                Bytes::from_static(&[82]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamlocal_forward() {
+        use futures::FutureExt as _;
+
+        use msg::channel_open::Type as OpenType;
+        use msg::channel_open_failure::{ChannelOpenFailure, ReasonCode};
+        use msg::global_request::{GlobalRequest, StreamlocalForward, Type};
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let done_tx = Arc::new(std::sync::Mutex::new(Some(done_tx)));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_streamlocal_forward(move |socket_path: String, handle: GlobalHandle| {
+            let listening = socket_path == "/tmp/fwd.sock";
+            if listening {
+                let done_tx = done_tx.lock().unwrap().take().unwrap();
+                tokio::spawn(async move {
+                    let refused = handle.open_forwarded_streamlocal(&socket_path).await;
+                    done_tx.send(refused.is_err()).unwrap();
+                });
+            }
+            async move { Ok(listening) }.boxed()
+        });
+        handlers.on_cancel_streamlocal_forward(|socket_path: String| {
+            async move { Ok(socket_path == "/tmp/fwd.sock") }.boxed()
+        });
+
+        let request = |path: &str, cancel| {
+            let item = StreamlocalForward::new(path.into());
+            let typ = if cancel {
+                Type::CancelStreamlocalForward(item)
+            } else {
+                Type::StreamlocalForward(item)
+            };
+            Msg::from(GlobalRequest::new(true, typ))
+        };
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        )
+        .authenticated();
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(request("/tmp/fwd.sock", false)).await.unwrap();
+            theirs
+                .send(request("/tmp/other.sock", false))
+                .await
+                .unwrap();
+            theirs.send(request("/tmp/fwd.sock", true)).await.unwrap();
+            let mut replies = vec![];
+            let mut opens = vec![];
+            while replies.len() < 3 || opens.is_empty() {
+                match theirs.next().await.unwrap().unwrap() {
+                    Msg::RequestSuccess(..) => replies.push(true),
+                    Msg::RequestFailure(..) => replies.push(false),
+                    Msg::ChannelOpen(open) => {
+                        if let OpenType::ForwardedStreamlocal(item) = open.typ() {
+                            opens.push(item.socket_path().clone());
+                        }
+                        let reason = ReasonCode::ConnectFailed;
+                        let chid = *open.sender_channel();
+                        let msg = ChannelOpenFailure::new(chid, reason, "".into(), "".into());
+                        theirs.send(msg.into()).await.unwrap();
+                    }
+                    _ => {}
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            (replies, opens)
+        };
+        let (result, (replies, opens)) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(replies, vec![true, false, true]);
+        assert_eq!(opens, vec!["/tmp/fwd.sock"]);
+        assert!(done_rx.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_global_request() {
+        use futures::FutureExt as _;
+
+        use msg::global_request::{GlobalRequest, Type};
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_global_request(|name: String, data, _| {
+            let response = match name.as_str() {
+                "echo@example.com" => GlobalResponse::Success(data),
+                _ => GlobalResponse::Failure,
+            };
+            async move { Ok(response) }.boxed()
+        });
+
+        let request = |name: &str, want_reply| {
+            let typ = Type::Unknown(name.into(), Bytes::from_static(b"\x01\x02"));
+            Msg::from(GlobalRequest::new(want_reply, typ))
+        };
+        let script = vec![
+            request("echo@example.com", true),
+            request("other@example.com", true),
+            request("echo@example.com", false),
+            GlobalRequest::new(true, Type::NoMoreSessions).into(),
+            session_open(0),
+        ];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        let replies = received
+            .iter()
+            .filter_map(|m| match m {
+                Msg::RequestSuccess(..) | Msg::RequestFailure(..) => {
+                    let mut buf = BytesMut::new();
+                    crate::pack::Pack::pack(m, &mut buf);
+                    Some(buf.freeze())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replies,
+            vec![
+                Bytes::from_static(&[81, 1, 2]),
+                Bytes::from_static(&[82]),
+                Bytes::from_static(&[81]),
+            ]
+        );
+        assert!(received
+            .iter()
+            .any(|m| matches!(m, Msg::ChannelOpenFailure(..))));
+    }
+
+    #[tokio::test]
+    async fn test_tcpip_forward_unhandled() {
+        let (result, received, _) =
+            scripted(PreferenceBuilder::default(), vec![tcpip_forward(0, true)]).await;
+        result.unwrap();
+        assert!(received
+            .iter()
+            .any(|m| matches!(m, Msg::RequestFailure(..))));
+    }
+
+    #[tokio::test]
+    async fn test_tcpip_forward_error() {
+        use futures::FutureExt as _;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_tcpip_forward(|_, port, _| {
+            async move {
+                anyhow::ensure!(port != 1, "address in use");
+                Ok(Some(port))
+            }
+            .boxed()
+        });
+        let script = vec![tcpip_forward(1, true), tcpip_forward(2, true)];
+        let (result, received, _) =
+            scripted_with(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+        let replies = received
+            .iter()
+            .filter(|m| matches!(m, Msg::RequestSuccess(..) | Msg::RequestFailure(..)))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            &replies[..],
+            [Msg::RequestFailure(..), Msg::RequestSuccess(..)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_keepalive() {
+        use msg::global_request::{GlobalRequest, Type};
+
+        let script = vec![
+            GlobalRequest::new(true, Type::Keepalive).into(),
+            GlobalRequest::new(false, Type::Keepalive).into(),
+        ];
+        let (result, received, _) = scripted(PreferenceBuilder::default(), script).await;
+        result.unwrap();
+        let replies = received
+            .iter()
+            .filter(|m| matches!(m, Msg::RequestSuccess(..) | Msg::RequestFailure(..)))
+            .collect::<Vec<_>>();
+        assert!(matches!(replies[..], [Msg::RequestSuccess(..)]));
     }
 }
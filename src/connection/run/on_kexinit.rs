@@ -1,15 +1,16 @@
+use crate::trace::debug;
+use futures::sink::SinkExt as _;
 use futures::stream::TryStreamExt as _;
-use log::debug;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::kex::Kex;
+use crate::kex::{self, Kex};
 use crate::msg::kexinit::Kexinit;
 use crate::msg::new_keys::NewKeys;
-use crate::msg::Msg;
+use crate::msg::{Msg, MsgName as _};
 use crate::negotiate::negotiate;
 use crate::HandlerError;
 
-use super::{Runner, SshError};
+use super::{Phase, Runner, SshError};
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
 where
@@ -17,17 +18,40 @@ where
     E: Into<HandlerError> + Send + 'static,
 {
     pub(super) async fn on_kexinit(&mut self, kexinit: &Kexinit) -> Result<(), SshError> {
+        self.phases.mark(Phase::Kexinit);
         let c_kexinit = kexinit;
+        let first = self.identity.session_id().is_none();
         let s_kexinit = if self.first_kexinit.is_some() {
             self.first_kexinit.take().unwrap()
         } else {
-            let s_kexinit = self.preference.to_kexinit();
+            let s_kexinit = self.preference.to_kexinit()?;
             self.send(s_kexinit.clone()).await?;
             s_kexinit
         };
+        self.io.flush().await?;
 
-        let algorithm = negotiate(&c_kexinit, &self.preference)?;
+        let algorithm = negotiate(c_kexinit, &self.preference)?;
         debug!("algorithm: {:?}", algorithm);
+        self.languages = algorithm.languages().clone();
+
+        // Strict KEX allows nothing but the key exchange until NEWKEYS,
+        // starting with KEXINIT as the very first message.
+        let strict = first && c_kexinit.kex_algorithms().contains(kex::STRICT_CLIENT);
+        if strict {
+            if self.io.get_ref().state().ctos().seq() != 1 {
+                let msg = "strict kex: KEXINIT was not the first message";
+                return Err(SshError::Protocol(msg.into()));
+            }
+            debug!("strict kex");
+            self.io.get_mut().state_mut().enable_strict_kex();
+        }
+        // Otherwise channel data sent by the client before its KEXINIT may
+        // still follow. Keep it for the main loop instead of failing the key exchange.
+        self.io.defer_non_kex(!strict);
+        if *c_kexinit.first_kex_packet_follows() && !algorithm.guessed_by(c_kexinit) {
+            debug!("ignore wrongly guessed kex packet");
+            self.io.skip_packet().await?;
+        }
 
         let hostkey = self
             .preference
@@ -42,22 +66,486 @@ where
                 &mut self.io,
                 &self.c_version,
                 &self.s_version,
-                &c_kexinit,
+                c_kexinit,
                 &s_kexinit,
                 hostkey,
+                algorithm.server_host_key_algorithm(),
             )
             .await?;
         debug!("Done kex. {:?}", kex);
 
+        // Each direction switches keys right after its NEWKEYS.
+        let state = self.io.get_mut().state_mut();
+        state.stage_keys(&hash, key.as_bytes(), &kex, &algorithm)?;
+        self.identity.set_session_id(state.session_id());
+        self.identity.set_algorithms(&algorithm);
+        if let Some(keylog) = self.preference.keylog() {
+            let cookie = *c_kexinit.cookie();
+            keylog.log(
+                cookie,
+                state.session_id(),
+                algorithm.kex_algorithm(),
+                key.as_bytes(),
+            );
+        }
+        self.send(NewKeys::new()).await?;
+        if first {
+            // EXT_INFO may only be the very next packet.
+            self.ext_info = c_kexinit.kex_algorithms().contains(kex::EXT_INFO_CLIENT);
+            self.send_ext_info().await?;
+        }
+        self.io.hold_non_kex(false)?;
+        self.io.flush().await?;
+
         match self.io.try_next().await? {
-            Some(Msg::NewKeys(..)) => {}
-            Some(msg) => return Err(SshError::UnexpectedMsg(format!("{:?}", msg))),
+            Some(Msg::NewKeys(..)) => self.phases.mark(Phase::NewKeys),
+            Some(msg) => return Err(SshError::UnexpectedMsg(msg.name().into())),
             None => return Err(SshError::NoPacketReceived),
         };
-        self.send(NewKeys::new()).await?;
+        self.io.defer_non_kex(false);
+        self.preference.observer().kex_complete(&algorithm);
 
-        let state = self.io.get_mut().state_mut();
-        state.change_key(&hash, &key, &kex, &algorithm)?;
+        if first {
+            let client_version = self.c_version.clone();
+            if let Some(fut) = self
+                .handlers
+                .dispatch_handshake_complete(client_version, algorithm)
+            {
+                fut.await.map_err(|e| self.handler_error(e))?;
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::stream::StreamExt as _;
+    use tokio::time;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::run::test_support::{
+        client_handshake, client_rekey, run_xor_handshake, scripted, with_kex, xor_preference,
+        XorFactory,
+    };
+    use crate::handlers::Handlers;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::{service_request, userauth_request};
+    use crate::SshError;
+
+    #[tokio::test]
+    async fn test_negotiate_failure() {
+        use crate::DisconnectReason;
+
+        let mut client = PreferenceBuilder::default();
+        client.add_compression_algorithm(crate::Compression::Zlib);
+        let c_kexinit = client.build().await.unwrap().to_kexinit().unwrap();
+
+        let script = vec![c_kexinit.into()];
+        let (result, received, _) = scripted(PreferenceBuilder::default(), script).await;
+        match result {
+            Err(SshError::Negotiate(e)) => {
+                assert_eq!(e.what(), &crate::AlgorithmKind::CompressionC2s);
+                assert_eq!(e.client(), &["zlib"]);
+            }
+            x => panic!("{:?}", x),
+        }
+        match received.last() {
+            Some(Msg::Disconnect(msg)) => {
+                assert_eq!(msg.reason_code(), &DisconnectReason::KeyExchangeFailed);
+                assert_eq!(
+                    msg.description(),
+                    "no matching compression client to server algorithm \
+                     (client: zlib; server: none,zlib@openssh.com)"
+                );
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_kex_guess() {
+        use crate::kex;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.add_kex_algorithm(kex::Algorithm::Curve25519Sha256);
+        let (preference, c_kexinit) = xor_preference(preference).await;
+        let kex = ["diffie-hellman-group14-sha256", "curve25519-sha256"];
+        let c_kexinit = with_kex(&c_kexinit, &kex, true);
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (handle, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let (hash, _) =
+                client_handshake(&mut theirs, c_kexinit, &preference, time::Duration::ZERO).await;
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            hash
+        };
+        let (result, hash) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(handle.session_id(), Some(hash));
+    }
+
+    #[tokio::test]
+    async fn test_strict_kex() {
+        let (preference, c_kexinit) = xor_preference(PreferenceBuilder::default()).await;
+        let kex = ["curve25519-sha256", "kex-strict-c-v00@openssh.com"];
+        let c_kexinit = with_kex(&c_kexinit, &kex, false);
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            client_handshake(&mut theirs, c_kexinit, &preference, time::Duration::ZERO).await;
+            // Sequence numbers restarted in both directions, or MACs fail.
+            assert_eq!(theirs.get_ref().state().stoc().seq(), 0);
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            let accepted = matches!(theirs.next().await, Some(Ok(Msg::ServiceAccept(..))));
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            accepted
+        };
+        let (result, accepted) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn test_strict_kex_unexpected() {
+        use crate::msg::ignore::Ignore;
+
+        let (preference, c_kexinit) = xor_preference(PreferenceBuilder::default()).await;
+        let kex = ["curve25519-sha256", "kex-strict-c-v00@openssh.com"];
+        let c_kexinit = with_kex(&c_kexinit, &kex, false);
+
+        // Before KEXINIT, and between KEXINIT and NEWKEYS.
+        for before in [true, false] {
+            let (ours, theirs) = tokio::io::duplex(64 * 1024);
+            let (_, controller) = global_handle();
+            let runner = Runner::new(
+                MsgStream::new(ours),
+                "SSH-2.0-client".into(),
+                "SSH-2.0-server".into(),
+                preference.clone(),
+                Handlers::<anyhow::Error>::new(),
+                controller,
+            );
+
+            let c_kexinit = c_kexinit.clone();
+            let client = async move {
+                let mut theirs = MsgStream::new(theirs);
+                if before {
+                    theirs.send(Ignore::new(Bytes::new()).into()).await.unwrap();
+                }
+                theirs.send(c_kexinit.into()).await.unwrap();
+                if !before {
+                    theirs.send(service_request("ssh-userauth")).await.unwrap();
+                }
+                let mut disconnected = false;
+                while let Some(Ok(msg)) = theirs.next().await {
+                    disconnected |= matches!(msg, Msg::Disconnect(..));
+                }
+                disconnected
+            };
+            let (result, disconnected) = tokio::join!(runner.run(), client);
+            assert!(result.is_err(), "{:?}", result);
+            assert!(disconnected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ext_info() {
+        use futures::FutureExt as _;
+
+        use crate::PasswordResult;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_password(|_, _| async { Ok(PasswordResult::Ok) }.boxed());
+        let mut preference = PreferenceBuilder::default();
+        preference.ext_info_in_auth(true);
+        let (preference, c_kexinit) = xor_preference(preference).await;
+        let c_kexinit = with_kex(&c_kexinit, &["curve25519-sha256", "ext-info-c"], false);
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            handlers,
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            client_handshake(&mut theirs, c_kexinit, &preference, time::Duration::ZERO).await;
+            let first = theirs.next().await;
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            let request = userauth_request("alice", &["password"], Some("secret"));
+            theirs.send(request).await.unwrap();
+            let mut msgs = vec![];
+            while let Some(Ok(msg)) = theirs.next().await {
+                let done = matches!(msg, Msg::UserauthSuccess(..));
+                msgs.push(msg);
+                if done {
+                    break;
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            (first, msgs)
+        };
+        let (result, (first, msgs)) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        let ext_info = match first {
+            Some(Ok(Msg::ExtInfo(ext_info))) => ext_info,
+            x => panic!("{:?}", x),
+        };
+        assert_eq!(
+            ext_info.extensions(),
+            &[(
+                "server-sig-algs".to_string(),
+                Bytes::from("ssh-ed25519,rsa-sha2-512,rsa-sha2-256,ssh-rsa")
+            )]
+        );
+        assert!(
+            matches!(
+                &msgs[..],
+                [
+                    Msg::ServiceAccept(..),
+                    Msg::ExtInfo(..),
+                    Msg::UserauthSuccess(..)
+                ]
+            ),
+            "{:?}",
+            msgs
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_cipher_handshake() {
+        let mut preference = PreferenceBuilder::default();
+        preference
+            .register_cipher("xor@example.com", Arc::new(XorFactory))
+            .unwrap();
+        assert!(matches!(
+            preference.register_cipher("xor@example.com", Arc::new(XorFactory)),
+            Err(SshError::AlgorithmExists(..))
+        ));
+        assert!(matches!(
+            preference.register_cipher("aes128-ctr", Arc::new(XorFactory)),
+            Err(SshError::AlgorithmExists(..))
+        ));
+
+        let (accepted, ..) = run_xor_handshake(PreferenceBuilder::default()).await;
+        assert!(accepted);
+    }
+
+    #[tokio::test]
+    async fn test_rekey_after_limit() {
+        let mut preference = PreferenceBuilder::default();
+        preference.rekey_limit(1);
+        let (preference, c_kexinit) = xor_preference(preference).await;
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let zero = time::Duration::ZERO;
+            let (first, _) =
+                client_handshake(&mut theirs, c_kexinit.clone(), &preference, zero).await;
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            assert!(matches!(
+                theirs.next().await,
+                Some(Ok(Msg::ServiceAccept(..)))
+            ));
+
+            // Answered only after the server initiated key exchange.
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            let (second, _) = client_handshake(&mut theirs, c_kexinit, &preference, zero).await;
+            assert!(matches!(
+                theirs.next().await,
+                Some(Ok(Msg::ServiceAccept(..)))
+            ));
+            assert_ne!(first, second);
+            assert_eq!(theirs.get_ref().state().session_id(), &first[..]);
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let (result, ()) = tokio::join!(runner.run(), client);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_rekey_interleaved() {
+        use futures::FutureExt as _;
+
+        use crate::msg::channel_data::ChannelData;
+        use crate::msg::channel_eof::ChannelEof;
+        use crate::msg::channel_request::{ChannelRequest, Type};
+        use crate::test_support::session_open;
+        use crate::SessionContext;
+
+        let (preference, c_kexinit) = xor_preference(PreferenceBuilder::default()).await;
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                tokio::io::copy(&mut stdin, &mut stdout).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            handlers,
+            controller,
+        )
+        .authenticated();
+
+        let data = |bytes| Msg::from(ChannelData::new(0, Bytes::from_static(bytes)));
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let zero = time::Duration::ZERO;
+            let (first, _) =
+                client_handshake(&mut theirs, c_kexinit.clone(), &preference, zero).await;
+            theirs.send(session_open(0)).await.unwrap();
+            let exec = Type::Exec(Bytes::from_static(b"cat"));
+            theirs
+                .send(ChannelRequest::new(0, false, exec).into())
+                .await
+                .unwrap();
+            theirs.send(data(b"a")).await.unwrap();
+
+            // Sent before our KEXINIT reached the server.
+            let interleaved = vec![data(b"b")];
+            let (second, _, mut received) =
+                client_rekey(&mut theirs, c_kexinit, &preference, interleaved).await;
+            assert_ne!(first, second);
+            theirs.send(data(b"c")).await.unwrap();
+            theirs.send(ChannelEof::new(0).into()).await.unwrap();
+            while let Some(Ok(msg)) = theirs.next().await {
+                let closed = matches!(msg, Msg::ChannelClose(..));
+                received.push(msg);
+                if closed {
+                    break;
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            received
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    Msg::ChannelData(data) => Some(data.data().clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .concat()
+        };
+        let (result, echoed) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(echoed, b"abc");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rekey_after_interval() {
+        let mut preference = PreferenceBuilder::default();
+        preference.rekey_interval(time::Duration::from_secs(60));
+        let (preference, c_kexinit) = xor_preference(preference).await;
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            Handlers::<anyhow::Error>::new(),
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let zero = time::Duration::ZERO;
+            client_handshake(&mut theirs, c_kexinit.clone(), &preference, zero).await;
+            let started = time::Instant::now();
+            client_handshake(&mut theirs, c_kexinit, &preference, zero).await;
+            assert!(started.elapsed() >= time::Duration::from_secs(60));
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+        };
+        let (result, ()) = tokio::join!(runner.run(), client);
+        result.unwrap();
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keylog() {
+        use crate::kex::Algorithm::Curve25519Sha256;
+        use crate::keylog::entry;
+
+        let unset = PreferenceBuilder::default().build().await.unwrap();
+        assert!(unset.keylog().is_none());
+
+        let out = Shared::default();
+        let mut preference = PreferenceBuilder::default();
+        preference.keylog(out.clone());
+        let (accepted, hash, secret, cookie) = run_xor_handshake(preference).await;
+        assert!(accepted);
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, entry(cookie, &hash, &Curve25519Sha256, &secret));
+    }
+}
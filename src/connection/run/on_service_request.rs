@@ -2,7 +2,7 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::msg::service_accept::ServiceAccept;
 use crate::msg::service_request::{ServiceRequest, SSH_CONNECTION, SSH_USERAUTH};
-use crate::HandlerError;
+use crate::{DisconnectReason, HandlerError, ProtocolWarning};
 
 use super::{Runner, SshError};
 
@@ -23,16 +23,92 @@ where
     }
 
     async fn on_userauth(&mut self) -> Result<(), SshError> {
+        if self.userauth_requested {
+            self.protocol_warning(ProtocolWarning::DuplicateServiceRequest {
+                service: SSH_USERAUTH.into(),
+            })?;
+        }
+        self.userauth_requested = true;
         let accept = ServiceAccept::new(SSH_USERAUTH.into());
         self.send(accept).await?;
-        Ok(())
+        self.flush_banners().await
     }
 
     async fn on_connection(&mut self) -> Result<(), SshError> {
-        Err(SshError::UnacceptableService(SSH_CONNECTION.into()))
+        self.reject_service(SSH_CONNECTION).await
     }
 
     async fn on_unknown_service(&mut self, name: &str) -> Result<(), SshError> {
-        Err(SshError::UnacceptableService(name.into()))
+        let accepted = match self.handlers.dispatch_service_request(name.into()) {
+            Some(fut) => fut.await.map_err(|e| self.handler_error(e))?,
+            None => false,
+        };
+        if accepted {
+            self.send(ServiceAccept::new(name.into())).await
+        } else {
+            self.reject_service(name).await
+        }
+    }
+
+    /// Close the connection as RFC 4253 section 10 allows, not as an error.
+    async fn reject_service(&mut self, name: &str) -> Result<(), SshError> {
+        let description = format!("service {} not available", name);
+        self.disconnect(DisconnectReason::ServiceNotAvailable, description)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::run::test_support::scripted_unauthenticated;
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::service_request;
+
+    #[tokio::test]
+    async fn test_unknown_service() {
+        use msg::disconnect::ReasonCode;
+
+        for name in ["ssh-connection", "example@example.com"] {
+            let (result, received, _) = scripted_unauthenticated(
+                PreferenceBuilder::default(),
+                Handlers::new(),
+                vec![service_request(name)],
+            )
+            .await;
+            result.unwrap();
+            match received.last() {
+                Some(Msg::Disconnect(msg)) => {
+                    assert_eq!(msg.reason_code(), &ReasonCode::ServiceNotAvailable);
+                    assert!(msg.description().contains(name));
+                }
+                msg => panic!("{:?}", msg),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_service() {
+        use futures::FutureExt as _;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers
+            .on_service_request(|name| futures::future::ok(name == "example@example.com").boxed());
+        let script = vec![
+            service_request("example@example.com"),
+            service_request("ssh-connection"),
+        ];
+        let (result, received, _) =
+            scripted_unauthenticated(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+        let received = received
+            .into_iter()
+            .filter(|msg| !matches!(msg, Msg::Kexinit(..)))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            &received[..],
+            [Msg::ServiceAccept(..), Msg::Disconnect(..)]
+        ));
     }
 }
@@ -0,0 +1,58 @@
+use crate::trace::warn;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::unimplemented::Unimplemented;
+use crate::HandlerError;
+
+use super::Runner;
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    /// Client does not know one of our messages. Only logged.
+    pub(super) fn on_unimplemented(&mut self, unimplemented: &Unimplemented) {
+        let seq = *unimplemented.pkt_seq();
+        match self.io.sent_id(seq) {
+            Some(id) => warn!("client does not implement our message {} (seq {})", id, seq),
+            None => warn!("client does not implement our message with seq {}", seq),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::connection::run::test_support::scripted;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::test_support::service_request;
+
+    #[tokio::test]
+    async fn test_unimplemented_seq() {
+        use crate::msg::ignore::Ignore;
+        use crate::pack::Unpack as _;
+
+        let unknown = |id| Msg::unpack(&mut Bytes::from(vec![id, 1, 2, 3])).unwrap();
+        let script = vec![
+            unknown(200),
+            Ignore::new(Bytes::new()).into(),
+            unknown(201),
+            service_request("ssh-userauth"),
+        ];
+        let (result, received, _) = scripted(PreferenceBuilder::default(), script).await;
+        result.unwrap();
+
+        let seqs = received
+            .iter()
+            .filter_map(|m| match m {
+                Msg::Unimplemented(m) => Some(*m.pkt_seq()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, [0, 2]);
+        assert!(received.iter().any(|m| matches!(m, Msg::ServiceAccept(..))));
+    }
+}
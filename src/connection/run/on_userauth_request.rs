@@ -1,45 +1,197 @@
+use std::collections::HashMap;
+
 use futures::sink::SinkExt as _;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::msg::userauth_banner::UserauthBanner;
 use crate::msg::userauth_failure::UserauthFailure;
+use crate::msg::userauth_info_request::UserauthInfoRequest;
+use crate::msg::userauth_info_response::UserauthInfoResponse;
 use crate::msg::userauth_passwd_changereq::UserauthPasswdChangereq;
 use crate::msg::userauth_pk_ok::UserauthPkOk;
 use crate::msg::userauth_request::{Hostbased, Method, Password, Publickey, UserauthRequest};
 use crate::msg::userauth_success::UserauthSuccess;
-use crate::msg::UserauthPkMsg;
+use crate::msg::{UserauthInfoMsg, UserauthPkMsg};
 use crate::pack::Pack;
-use crate::{HandlerError, PasswordResult};
+use crate::trace::{self, debug, info, warn};
+use crate::{
+    AuthMethod, AuthOutcome, AuthResult, HandlerError, InfoRequest, KeyboardInteractiveResult,
+    PasswordResult,
+};
 use bytes::Bytes;
-use log::debug;
 
-use super::{Runner, SshError};
+use super::{Charge, Phase, Runner, SshError};
+
+/// Keyboard-interactive info request awaiting a response.
+#[derive(Debug)]
+struct PendingInfoRequest {
+    user_name: String,
+    /// Echo flag per outstanding prompt.
+    echo: Vec<bool>,
+}
 
-const SUPPORTED_METHODS: &[&str] = &["publickey", "password", "hostbased"];
+/// Methods succeeded so far and those of which one must follow.
+#[derive(Debug)]
+struct Partial {
+    user_name: String,
+    succeeded: Vec<&'static str>,
+    required: Vec<AuthMethod>,
+}
 
 #[derive(Debug)]
 pub(super) struct AuthState {
-    remaining: Vec<&'static str>,
-    accepted_publickey: Option<(String, crate::PublicKey)>,
+    /// Methods handled on this connection.
+    supported: Vec<AuthMethod>,
+    /// Supported methods not failed yet.
+    remaining: Vec<AuthMethod>,
+    /// Methods the handler advertises to the current user, all if `None`.
+    advertised: Option<Vec<AuthMethod>>,
+    /// Partial success so far, restricting further methods.
+    partial: Option<Partial>,
+    /// Key accepted by a query and the handler's result for it.
+    accepted_publickey: Option<(String, crate::PublicKey, AuthResult)>,
+    pending_info_request: Option<PendingInfoRequest>,
+    /// User name and method of the first accepted attempt.
+    authenticated: Option<(String, &'static str)>,
+    /// User name and method name of the latest request.
+    attempt: Option<(String, String)>,
+    /// Rejected attempts so far.
+    failures: u32,
+    /// Rejected attempts by method.
+    failures_by_method: HashMap<AuthMethod, u32>,
+    /// Banners awaiting the `ssh-userauth` service request.
+    pending_banners: Vec<String>,
+    /// Bytes held for attempts in progress and pending banners.
+    charge: Charge,
 }
 
 impl AuthState {
-    pub(super) fn new() -> Self {
+    /// Offer keyboard-interactive only if `keyboard_interactive`.
+    pub(super) fn new(keyboard_interactive: bool, charge: Charge) -> Self {
+        let supported = AuthMethod::ALL
+            .iter()
+            .filter(|m| keyboard_interactive || **m != AuthMethod::KeyboardInteractive)
+            .cloned()
+            .collect::<Vec<_>>();
         Self {
-            remaining: Vec::from(SUPPORTED_METHODS),
+            remaining: supported.clone(),
+            supported,
+            advertised: None,
+            partial: None,
             accepted_publickey: None,
+            pending_info_request: None,
+            authenticated: None,
+            attempt: None,
+            failures: 0,
+            failures_by_method: HashMap::new(),
+            pending_banners: vec![],
+            charge,
+        }
+    }
+
+    /// Charge what is currently held for pending requests.
+    pub(super) fn recharge(&mut self) {
+        let banners = self.pending_banners.iter().map(String::len).sum::<usize>();
+        let attempt = self.attempt.as_ref().map_or(0, |(u, m)| u.len() + m.len());
+        let publickey = self
+            .accepted_publickey
+            .as_ref()
+            .map_or(0, |(u, key, _)| u.len() + key.blob_len());
+        let info = self
+            .pending_info_request
+            .as_ref()
+            .map_or(0, |p| p.user_name.len() + p.echo.len());
+        let partial = self.partial.as_ref().map_or(0, |p| p.user_name.len());
+        self.charge
+            .resize(banners + attempt + publickey + info + partial);
+    }
+
+    /// Whether requests for `method` are handled.
+    ///
+    /// After partial success, the required methods replace those advertised.
+    fn allows(&self, method: AuthMethod) -> bool {
+        let offered = match (&self.partial, &self.advertised) {
+            (Some(partial), _) => partial.required.contains(&method),
+            (None, Some(advertised)) => advertised.contains(&method),
+            (None, None) => true,
+        };
+        offered && self.supported.contains(&method)
+    }
+
+    /// Record `method` succeeded, one of `required` must follow.
+    ///
+    /// Methods failed before are offered again.
+    fn succeed_partially(
+        &mut self,
+        user_name: &str,
+        method: &'static str,
+        required: Vec<AuthMethod>,
+    ) {
+        let partial = self.partial.get_or_insert_with(|| Partial {
+            user_name: user_name.into(),
+            succeeded: vec![],
+            required: vec![],
+        });
+        partial.succeeded.push(method);
+        partial.required = required;
+        self.remaining = self.supported.clone();
+    }
+
+    /// Forget partial success of another user.
+    fn switch_user(&mut self, user_name: &str) {
+        if let Some(partial) = &self.partial {
+            if partial.user_name != user_name {
+                debug!(
+                    "drop partial success of {} by {:?}",
+                    partial.user_name, partial.succeeded
+                );
+                self.partial = None;
+            }
         }
     }
 
-    fn consume(&mut self, method: &str) {
+    /// Methods succeeded partially before the current one.
+    fn succeeded(&self) -> &[&'static str] {
+        self.partial
+            .as_ref()
+            .map_or(&[], |partial| &partial.succeeded[..])
+    }
+
+    fn consume(&mut self, method: AuthMethod) {
         self.remaining.retain(|m| *m != method);
     }
 
-    fn remaining(&self) -> &[&'static str] {
-        &self.remaining
+    fn remaining(&self) -> Vec<&'static str> {
+        self.remaining
+            .iter()
+            .filter(|m| self.allows(**m))
+            .map(|m| m.name())
+            .collect()
+    }
+
+    /// Count a rejected attempt of `method`, returning whether `max` is reached.
+    ///
+    /// `None` for a request of no usable method, e.g. one not offered.
+    fn reject(&mut self, method: Option<AuthMethod>, max: u32) -> bool {
+        if let Some(method) = method {
+            *self.failures_by_method.entry(method).or_default() += 1;
+        }
+        self.failures += 1;
+        self.failures >= max
     }
 
-    fn done(&mut self) {
+    /// Transition to authenticated. Only the first call succeeds.
+    pub(super) fn authenticate(&mut self, user_name: &str, method: &'static str) -> bool {
+        if self.authenticated.is_some() {
+            return false;
+        }
+        self.authenticated = Some((user_name.into(), method));
         self.remaining.clear();
+        true
+    }
+
+    pub(super) fn authenticated(&self) -> Option<&(String, &'static str)> {
+        self.authenticated.as_ref()
     }
 }
 
@@ -52,7 +204,40 @@ where
         &mut self,
         userauth_request: &UserauthRequest,
     ) -> Result<(), SshError> {
+        self.phases.mark(Phase::UserauthRequest);
         let user_name = userauth_request.user_name();
+        if let Some((accepted, _)) = self.auth_state.authenticated() {
+            // RFC 4252 5.1: ignore requests after success.
+            debug!(
+                "ignore auth request for {} after {} accepted",
+                user_name, accepted
+            );
+            return Ok(());
+        }
+        // RFC 4256 3.4: a new request abandons outstanding prompts.
+        self.auth_state.pending_info_request = None;
+        self.auth_state.switch_user(user_name);
+        self.auth_state.attempt =
+            Some((user_name.clone(), userauth_request.method().name().into()));
+        self.auth_state.advertised =
+            if let Some(fut) = self.handlers.dispatch_auth_methods(user_name.into()) {
+                Some(fut.await.map_err(|e| self.handler_error(e))?)
+            } else {
+                None
+            };
+        let method = match userauth_request.method() {
+            Method::Publickey(..) => Some(AuthMethod::Publickey),
+            Method::Password(..) => Some(AuthMethod::Password),
+            Method::Hostbased(..) => Some(AuthMethod::Hostbased),
+            Method::KeyboardInteractive(..) => Some(AuthMethod::KeyboardInteractive),
+            Method::None | Method::Unknown(..) => None,
+        };
+        if let Some(method) = method {
+            if !self.auth_state.allows(method) {
+                debug!("{} not offered to {}", method.name(), user_name);
+                return self.send_failure(None).await;
+            }
+        }
         match userauth_request.method() {
             Method::None => self.on_userauth_none(user_name).await,
 
@@ -76,43 +261,148 @@ where
                     .await
             }
 
+            Method::KeyboardInteractive(..) => {
+                self.on_userauth_keyboard_interactive(user_name).await
+            }
+
             x => {
-                debug!("unknown auth method {:?}", x);
+                debug!("unknown auth method {}", x.name());
                 self.send_failure(None).await
             }
         }
     }
 
-    async fn send_success(&mut self) -> Result<(), SshError> {
-        self.auth_state.done();
+    async fn send_success(
+        &mut self,
+        user_name: &str,
+        method: &'static str,
+    ) -> Result<(), SshError> {
+        self.drain_control().await?;
+        if !self.auth_state.authenticate(user_name, method) {
+            warn!("drop duplicate accept of {} by {}", user_name, method);
+            return Ok(());
+        }
+        self.identity.authenticate(user_name, method);
+        trace::record_user(&self.span, user_name);
+        self.preference
+            .observer()
+            .auth_result(user_name, method, AuthOutcome::Success);
+        self.phases.mark(Phase::UserauthSuccess);
+        let timings = self.phases.timings();
+        info!(
+            "setup done: user={} method={} partial={:?} version_exchange={:?} kex={:?} auth={:?} total={:?}",
+            user_name,
+            method,
+            self.auth_state.succeeded(),
+            timings.version_exchange(),
+            timings.kex(),
+            timings.auth(),
+            timings.userauth_success(),
+        );
+        if *self.preference.ext_info_in_auth() {
+            self.send_ext_info().await?;
+        }
         self.send(UserauthSuccess::new()).await?;
+        if *self.preference.hostkeys_update() {
+            self.send_hostkeys().await?;
+        }
         Ok(())
     }
 
-    async fn send_failure(&mut self, consume: Option<&'static str>) -> Result<(), SshError> {
+    async fn send_failure(&mut self, consume: Option<AuthMethod>) -> Result<(), SshError> {
         if let Some(consume) = consume {
             self.auth_state.consume(consume);
         }
+        if let Some((user_name, method)) = &self.auth_state.attempt {
+            self.preference
+                .observer()
+                .auth_result(user_name, method, AuthOutcome::Failure);
+        }
+        let max = *self.preference.max_auth_attempts();
+        if self.auth_state.reject(consume, max) {
+            debug!(
+                "auth failures by method: {:?}",
+                self.auth_state.failures_by_method
+            );
+            return Err(SshError::TooManyAuthFailures(self.auth_state.failures));
+        }
+        self.send_methods().await
+    }
+
+    /// Reject `method` as not applicable, without counting an attempt.
+    async fn send_unsupported(&mut self, method: AuthMethod) -> Result<(), SshError> {
+        debug!("{} not applicable", method.name());
+        self.auth_state.consume(method);
+        self.send_methods().await
+    }
+
+    /// Send remaining methods without counting an attempt.
+    async fn send_methods(&mut self) -> Result<(), SshError> {
+        self.send_remaining(false).await
+    }
+
+    /// Report `method` succeeded, requiring one of `required` next.
+    async fn send_partial(
+        &mut self,
+        user_name: &str,
+        method: &'static str,
+        required: Vec<AuthMethod>,
+    ) -> Result<(), SshError> {
+        debug!("{} partially authenticated by {}", user_name, method);
+        self.preference
+            .observer()
+            .auth_result(user_name, method, AuthOutcome::Partial);
+        self.auth_state
+            .succeed_partially(user_name, method, required);
+        self.send_remaining(true).await
+    }
+
+    async fn send_remaining(&mut self, partial_success: bool) -> Result<(), SshError> {
+        self.drain_control().await?;
         let methods = self.auth_state.remaining();
-        let msg = UserauthFailure::new(methods.iter().cloned().collect(), false);
+        let msg = UserauthFailure::new(methods.into_iter().collect(), partial_success);
         self.send(msg).await?;
         Ok(())
     }
 
     async fn on_userauth_none(&mut self, user_name: &str) -> Result<(), SshError> {
-        let user_name = user_name.into();
-
-        let r = if let Some(fut) = self.handlers.dispatch_auth_none(user_name) {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+        let r = if let Some(fut) = self.handlers.dispatch_auth_none(user_name.into()) {
+            fut.await.map_err(|e| self.handler_error(e))?
         } else {
-            false
+            AuthResult::Failure
         };
 
-        if r {
-            self.send_success().await
+        match r {
+            AuthResult::Ok => self.send_success(user_name, "none").await,
+            AuthResult::Partial(required) => self.send_partial(user_name, "none", required).await,
+            AuthResult::Failure | AuthResult::Unsupported => self.send_methods().await,
+        }
+    }
+
+    pub(super) async fn send_banner(&mut self, message: String) -> Result<(), SshError> {
+        if self.auth_state.authenticated().is_some() {
+            debug!("drop banner after auth accepted");
+        } else if !self.userauth_requested {
+            self.auth_state.pending_banners.push(message);
         } else {
-            self.send_failure(None).await
+            self.send(UserauthBanner::new(message, "".into())).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle controls queued so far, so banners a handler sent precede its result.
+    async fn drain_control(&mut self) -> Result<(), SshError> {
+        while let Ok(control) = self.control_rx.try_recv() {
+            self.on_control(control).await?;
         }
+        Ok(())
+    }
+
+    pub(super) async fn flush_banners(&mut self) -> Result<(), SshError> {
+        for message in std::mem::take(&mut self.auth_state.pending_banners) {
+            self.send_banner(message).await?;
+        }
+        Ok(())
     }
 
     async fn on_userauth_publickey_nosig(
@@ -122,7 +412,7 @@ where
     ) -> Result<(), SshError> {
         let algorithm = item.algorithm();
         let publickey = item.blob();
-        if algorithm != publickey.algorithm() {
+        if !publickey.supports(algorithm) {
             return Err(SshError::AlgorithmMismatch(
                 algorithm.into(),
                 item.blob().algorithm().into(),
@@ -133,19 +423,21 @@ where
             .handlers
             .dispatch_auth_publickey(user_name.into(), publickey.clone())
         {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+            fut.await.map_err(|e| self.handler_error(e))?
         } else {
-            false
+            AuthResult::Failure
         };
 
-        if r {
-            self.auth_state.accepted_publickey = Some((user_name.into(), publickey.clone()));
-            let m = UserauthPkOk::new(item.algorithm().into(), item.blob().clone()).into();
-            self.io.context::<UserauthPkMsg>().send(m).await?;
-        } else {
-            self.send_failure(Some("publickey")).await?;
-        };
-        Ok(())
+        match r {
+            AuthResult::Failure => self.send_failure(Some(AuthMethod::Publickey)).await,
+            AuthResult::Unsupported => self.send_unsupported(AuthMethod::Publickey).await,
+            r => {
+                self.auth_state.accepted_publickey = Some((user_name.into(), publickey.clone(), r));
+                let m = UserauthPkOk::new(item.algorithm().into(), item.blob().clone()).into();
+                self.io.context::<UserauthPkMsg>().send(m).await?;
+                Ok(())
+            }
+        }
     }
 
     async fn on_userauth_publickey_sig(
@@ -178,17 +470,17 @@ where
         if verifier.verify(&signature) {
             let algorithm = item.algorithm();
             let publickey = item.blob();
-            if algorithm != publickey.algorithm() {
+            if !publickey.supports(algorithm) {
                 return Err(SshError::AlgorithmMismatch(
                     algorithm.into(),
                     item.blob().algorithm().into(),
                 ));
             }
 
-            let r = if let Some((accepted_username, accepted_publickey)) =
-                self.auth_state.accepted_publickey.take()
-            {
-                if accepted_username == user_name && &accepted_publickey == publickey {
+            let r = match self.auth_state.accepted_publickey.take() {
+                Some((accepted_username, accepted_publickey, accepted))
+                    if accepted_username == user_name && &accepted_publickey == publickey =>
+                {
                     if let Some(fut) = self
                         .handlers
                         .dispatch_auth_publickey_signature_verified_after_accepted(
@@ -196,34 +488,34 @@ where
                             publickey.clone(),
                         )
                     {
-                        fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+                        fut.await.map_err(|e| self.handler_error(e))?
                     } else {
-                        true
+                        accepted
+                    }
+                }
+                _ => {
+                    if let Some(fut) = self
+                        .handlers
+                        .dispatch_auth_publickey(user_name.into(), publickey.clone())
+                    {
+                        fut.await.map_err(|e| self.handler_error(e))?
+                    } else {
+                        AuthResult::Failure
                     }
-                } else if let Some(fut) = self
-                    .handlers
-                    .dispatch_auth_publickey(user_name.into(), publickey.clone())
-                {
-                    fut.await.map_err(|e| SshError::HandlerError(e.into()))?
-                } else {
-                    false
                 }
-            } else if let Some(fut) = self
-                .handlers
-                .dispatch_auth_publickey(user_name.into(), publickey.clone())
-            {
-                fut.await.map_err(|e| SshError::HandlerError(e.into()))?
-            } else {
-                false
             };
 
-            if r {
-                self.send_success().await
-            } else {
-                self.send_failure(Some("publickey")).await
+            let method = AuthMethod::Publickey;
+            match r {
+                AuthResult::Ok => self.send_success(user_name, method.name()).await,
+                AuthResult::Partial(required) => {
+                    self.send_partial(user_name, method.name(), required).await
+                }
+                AuthResult::Failure => self.send_failure(Some(method)).await,
+                AuthResult::Unsupported => self.send_unsupported(method).await,
             }
         } else {
-            self.send_failure(Some("publickey")).await
+            self.send_failure(Some(AuthMethod::Publickey)).await
         }
     }
 
@@ -236,18 +528,22 @@ where
         let password = item.password().into();
 
         let r = if let Some(fut) = self.handlers.dispatch_auth_password(username, password) {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+            fut.await.map_err(|e| self.handler_error(e))?
         } else {
             PasswordResult::Failure
         };
 
         match r {
-            PasswordResult::Ok => self.send_success().await,
+            PasswordResult::Ok => {
+                self.send_success(user_name, AuthMethod::Password.name())
+                    .await
+            }
             PasswordResult::PasswordChangeRequired(message) => {
                 let m = UserauthPasswdChangereq::new(message, "".into());
                 self.send(m).await
             }
-            PasswordResult::Failure => self.send_failure(Some("password")).await,
+            PasswordResult::Failure => self.send_failure(Some(AuthMethod::Password)).await,
+            PasswordResult::Unsupported => self.send_unsupported(AuthMethod::Password).await,
         }
     }
 
@@ -264,18 +560,22 @@ where
             self.handlers
                 .dispatch_auth_change_password(username, oldpassword, newpassword)
         {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+            fut.await.map_err(|e| self.handler_error(e))?
         } else {
             PasswordResult::Failure
         };
 
         match r {
-            PasswordResult::Ok => self.send_success().await,
+            PasswordResult::Ok => {
+                self.send_success(user_name, AuthMethod::Password.name())
+                    .await
+            }
             PasswordResult::PasswordChangeRequired(message) => {
                 let m = UserauthPasswdChangereq::new(message, "".into());
                 self.send(m).await
             }
-            PasswordResult::Failure => self.send_failure(Some("password")).await,
+            PasswordResult::Failure => self.send_failure(Some(AuthMethod::Password)).await,
+            PasswordResult::Unsupported => self.send_unsupported(AuthMethod::Password).await,
         }
     }
 
@@ -312,7 +612,7 @@ where
             let hostname = item.client_hostname().into();
             let algorithm = item.algorithm();
             let publickey = item.client_hostkey();
-            if algorithm != item.client_hostkey().algorithm() {
+            if !publickey.supports(algorithm) {
                 return Err(SshError::AlgorithmMismatch(
                     algorithm.into(),
                     item.client_hostkey().algorithm().into(),
@@ -323,18 +623,693 @@ where
                 self.handlers
                     .dispatch_auth_hostbased(username, hostname, publickey.clone())
             {
-                fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+                fut.await.map_err(|e| self.handler_error(e))?
             } else {
                 false
             };
 
             if r {
-                self.send_success().await
+                self.send_success(user_name, AuthMethod::Hostbased.name())
+                    .await
             } else {
-                self.send_failure(Some("hostbased")).await
+                self.send_failure(Some(AuthMethod::Hostbased)).await
+            }
+        } else {
+            self.send_failure(Some(AuthMethod::Hostbased)).await
+        }
+    }
+
+    async fn on_userauth_keyboard_interactive(&mut self, user_name: &str) -> Result<(), SshError> {
+        let request = if let Some(fut) = self
+            .handlers
+            .dispatch_auth_keyboard_interactive_start(user_name.into())
+        {
+            fut.await.map_err(|e| self.handler_error(e))?
+        } else {
+            return self
+                .send_failure(Some(AuthMethod::KeyboardInteractive))
+                .await;
+        };
+        self.send_info_request(user_name, request).await
+    }
+
+    async fn send_info_request(
+        &mut self,
+        user_name: &str,
+        request: InfoRequest,
+    ) -> Result<(), SshError> {
+        let (name, instruction, prompts) = request.into_parts();
+        self.auth_state.pending_info_request = Some(PendingInfoRequest {
+            user_name: user_name.into(),
+            echo: prompts.iter().map(|(_, echo)| *echo).collect(),
+        });
+        let m = UserauthInfoRequest::new(name, instruction, "".into(), prompts).into();
+        self.io.context::<UserauthInfoMsg>().send(m).await?;
+        Ok(())
+    }
+
+    pub(super) async fn on_userauth_info_response(
+        &mut self,
+        info_response: &UserauthInfoResponse,
+    ) -> Result<(), SshError> {
+        if self.auth_state.authenticated().is_some() {
+            debug!("ignore info response after auth accepted");
+            return Ok(());
+        }
+        let pending = match self.auth_state.pending_info_request.take() {
+            Some(pending) => pending,
+            None => {
+                warn!("info response without info request");
+                return self.send_failure(None).await;
             }
+        };
+        let responses = info_response.responses();
+        if responses.len() != pending.echo.len() {
+            warn!(
+                "{} responses for {} prompts",
+                responses.len(),
+                pending.echo.len()
+            );
+            return self.send_failure(None).await;
+        }
+
+        let user_name = pending.user_name;
+        let r = if let Some(fut) = self
+            .handlers
+            .dispatch_auth_keyboard_interactive_response(user_name.clone(), responses.clone())
+        {
+            fut.await.map_err(|e| self.handler_error(e))?
         } else {
-            self.send_failure(Some("hostbased")).await
+            KeyboardInteractiveResult::Failure
+        };
+
+        match r {
+            KeyboardInteractiveResult::Ok => {
+                self.send_success(&user_name, AuthMethod::KeyboardInteractive.name())
+                    .await
+            }
+            KeyboardInteractiveResult::MorePrompts(request) => {
+                self.send_info_request(&user_name, request).await
+            }
+            KeyboardInteractiveResult::Failure => {
+                self.send_failure(Some(AuthMethod::KeyboardInteractive))
+                    .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use bytes::{BufMut as _, Bytes, BytesMut};
+    use futures::stream::StreamExt as _;
+    use tokio::time;
+
+    use crate::connection::global_handle::global_handle;
+    use crate::connection::memory::Memory;
+    use crate::connection::run::test_support::{
+        client_handshake, scripted_unauthenticated, xor_preference,
+    };
+    use crate::handlers::Handlers;
+    use crate::msg::{self, Msg};
+    use crate::preference::PreferenceBuilder;
+    use crate::stream::msg::MsgStream;
+    use crate::test_support::{service_request, session_open, userauth_request};
+    use crate::SshError;
+
+    #[test]
+    fn test_authenticate_once() {
+        let mut state = AuthState::new(false, Memory::default().charge(0));
+        assert!(state.authenticated().is_none());
+        assert!(state.authenticate("alice", "none"));
+        assert!(!state.authenticate("bob", "password"));
+        assert_eq!(state.authenticated(), Some(&("alice".into(), "none")));
+        assert!(state.remaining().is_empty());
+    }
+
+    #[test]
+    fn test_recharge_pending() {
+        let memory = Memory::default();
+        let mut state = AuthState::new(false, memory.charge(0));
+        state.pending_banners.push("x".repeat(1000));
+        state.attempt = Some(("alice".into(), "password".into()));
+        state.recharge();
+        assert_eq!(memory.used(), 1000 + 5 + 8);
+
+        state.pending_banners.clear();
+        state.attempt = None;
+        state.recharge();
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_auth_single_success() {
+        use futures::FutureExt as _;
+        use std::sync::atomic::AtomicUsize;
+
+        use crate::PasswordResult;
+
+        let password_calls = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_none(|_| async { Ok(true) }.boxed());
+        let calls = password_calls.clone();
+        handlers.on_auth_password(move |_, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Ok(PasswordResult::Ok) }.boxed()
+        });
+
+        let script = vec![
+            service_request("ssh-userauth"),
+            userauth_request("alice", &["none"], None),
+            userauth_request("bob", &["password"], Some("secret")),
+            session_open(0),
+        ];
+        let (result, received, _) =
+            scripted_unauthenticated(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        let successes = received
+            .iter()
+            .filter(|m| matches!(m, Msg::UserauthSuccess(..)))
+            .count();
+        assert_eq!(successes, 1);
+        assert_eq!(password_calls.load(Ordering::SeqCst), 0);
+        assert!(!received
+            .iter()
+            .any(|m| matches!(m, Msg::UserauthFailure(..))));
+        assert!(received
+            .iter()
+            .any(|m| matches!(m, Msg::ChannelOpenConfirmation(..))));
+    }
+
+    #[tokio::test]
+    async fn test_max_auth_attempts() {
+        use futures::FutureExt as _;
+
+        use crate::{DisconnectReason, PasswordResult};
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_password(|_, _| async { Ok(PasswordResult::Failure) }.boxed());
+
+        let mut preference = PreferenceBuilder::default();
+        preference.max_auth_attempts(3);
+        let mut script = vec![
+            service_request("ssh-userauth"),
+            userauth_request("alice", &["none"], None),
+        ];
+        for _ in 0..4 {
+            script.push(userauth_request("alice", &["password"], Some("wrong")));
+        }
+        let (result, received, _) = scripted_unauthenticated(preference, handlers, script).await;
+        assert!(matches!(result, Err(SshError::TooManyAuthFailures(3))));
+
+        // Method none is not counted, the third password rejection disconnects.
+        let failures = received
+            .iter()
+            .filter(|m| matches!(m, Msg::UserauthFailure(..)))
+            .count();
+        assert_eq!(failures, 3);
+        match received.last() {
+            Some(Msg::Disconnect(msg)) => assert_eq!(
+                msg.reason_code(),
+                &DisconnectReason::NoMoreAuthMethodsAvailable
+            ),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_unsupported_not_counted() {
+        use futures::FutureExt as _;
+
+        use crate::PasswordResult;
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_password(|_, _| async { Ok(PasswordResult::Unsupported) }.boxed());
+
+        let mut preference = PreferenceBuilder::default();
+        preference.max_auth_attempts(2);
+        let mut script = vec![service_request("ssh-userauth")];
+        for _ in 0..3 {
+            script.push(userauth_request("alice", &["none"], None));
+            script.push(userauth_request("alice", &["password"], Some("secret")));
+        }
+        let (result, received, _) = scripted_unauthenticated(preference, handlers, script).await;
+        result.unwrap();
+
+        // Every probe and inapplicable password is answered, none counted.
+        let failures = received
+            .iter()
+            .filter_map(|m| match m {
+                Msg::UserauthFailure(msg) => Some(format!("{:?}", msg)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(failures.len(), 6);
+        assert!(failures[0].contains("password"), "{}", failures[0]);
+        for failure in &failures[1..] {
+            assert!(!failure.contains("password"), "{}", failure);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_user() {
+        use futures::FutureExt as _;
+        use msg::channel_request::{ChannelRequest, Type};
+        use std::sync::Mutex as StdMutex;
+
+        use crate::SessionContext;
+
+        let served = Arc::new(StdMutex::new(None));
+        let captured = served.clone();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_none(|_| async { Ok(true) }.boxed());
+        handlers.on_channel_exec(move |ctx: SessionContext, _| {
+            *captured.lock().unwrap() = Some(ctx.username());
+            async { Ok(0) }.boxed()
+        });
+        let exec = Type::Exec(Bytes::from_static(b"id"));
+        let script = vec![
+            service_request("ssh-userauth"),
+            userauth_request("bob", &["none"], None),
+            session_open(0),
+            ChannelRequest::new(0, false, exec).into(),
+        ];
+        let (result, _, handle) =
+            scripted_unauthenticated(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+        assert_eq!(handle.username().as_deref(), Some("bob"));
+        assert_eq!(handle.auth_method(), Some("none"));
+        assert_eq!(served.lock().unwrap().as_deref(), Some("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_banner() {
+        use futures::FutureExt as _;
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (handle, controller) = global_handle();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        let banners = handle.clone();
+        handlers.on_auth_none(move |user_name| {
+            banners
+                .send_banner(&format!("welcome {}", user_name))
+                .unwrap();
+            async { Ok(true) }.boxed()
+        });
+        // Held back until the userauth service is requested.
+        handle.send_banner("legal notice").unwrap();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let mut received = vec![];
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            theirs
+                .send(userauth_request("alice", &["none"], None))
+                .await
+                .unwrap();
+            while let Some(Ok(msg)) = theirs.next().await {
+                let done = matches!(msg, Msg::UserauthSuccess(..));
+                received.push(msg);
+                if done {
+                    break;
+                }
+            }
+            handle.send_banner("too late").unwrap();
+            theirs.close().await.unwrap();
+            while let Some(Ok(msg)) = theirs.next().await {
+                received.push(msg);
+            }
+            received
+        };
+        let (result, received) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        let banners = received
+            .iter()
+            .filter_map(|m| match m {
+                Msg::UserauthBanner(banner) => Some(banner.message().as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(banners, vec!["legal notice", "welcome alice"]);
+        let accept = received
+            .iter()
+            .position(|m| matches!(m, Msg::ServiceAccept(..)))
+            .unwrap();
+        let first_banner = received
+            .iter()
+            .position(|m| matches!(m, Msg::UserauthBanner(..)))
+            .unwrap();
+        let success = received
+            .iter()
+            .position(|m| matches!(m, Msg::UserauthSuccess(..)))
+            .unwrap();
+        assert!(accept < first_banner && first_banner < success);
+    }
+
+    #[tokio::test]
+    async fn test_keyboard_interactive() {
+        use futures::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+
+        use crate::msg::userauth_info_response::UserauthInfoResponse;
+        use crate::msg::UserauthInfoMsg;
+        use crate::{InfoRequest, KeyboardInteractiveResult};
+
+        let answers = Arc::new(StdMutex::new(vec![]));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_keyboard_interactive_start(|_| {
+            let request = InfoRequest::new("otp", "")
+                .prompt("Password: ", false)
+                .prompt("Code: ", true);
+            async { Ok(request) }.boxed()
+        });
+        let captured = answers.clone();
+        handlers.on_auth_keyboard_interactive_response(move |user, responses: Vec<String>| {
+            let r = if responses.len() == 2 {
+                KeyboardInteractiveResult::MorePrompts(
+                    InfoRequest::new("", "").prompt("PIN: ", false),
+                )
+            } else {
+                KeyboardInteractiveResult::Ok
+            };
+            captured.lock().unwrap().push((user, responses));
+            async { Ok(r) }.boxed()
+        });
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference,
+            handlers,
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            theirs
+                .send(userauth_request("bob", &["none"], None))
+                .await
+                .unwrap();
+            loop {
+                match theirs.next().await.unwrap().unwrap() {
+                    Msg::UserauthFailure(failure) => {
+                        assert!(format!("{:?}", failure).contains("keyboard-interactive"));
+                        break;
+                    }
+                    Msg::Kexinit(..) | Msg::ServiceAccept(..) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+
+            let method = ["keyboard-interactive", "", ""];
+            theirs
+                .send(userauth_request("bob", &method, None))
+                .await
+                .unwrap();
+            let mut prompts = vec![];
+            for responses in [vec!["wrong"], vec!["secret", "123"], vec!["42"]] {
+                let UserauthInfoMsg::UserauthInfoRequest(request) = theirs
+                    .context::<UserauthInfoMsg>()
+                    .next()
+                    .await
+                    .unwrap()
+                    .unwrap()
+                else {
+                    panic!()
+                };
+                prompts.push(request.prompts().clone());
+                let responses = responses.into_iter().map(String::from).collect();
+                theirs
+                    .send(UserauthInfoResponse::new(responses).into())
+                    .await
+                    .unwrap();
+                if prompts.len() == 1 {
+                    // Count mismatch fails, then the client starts over.
+                    assert!(matches!(
+                        theirs.next().await,
+                        Some(Ok(Msg::UserauthFailure(..)))
+                    ));
+                    theirs
+                        .send(userauth_request("bob", &method, None))
+                        .await
+                        .unwrap();
+                }
+            }
+            assert!(matches!(
+                theirs.next().await,
+                Some(Ok(Msg::UserauthSuccess(..)))
+            ));
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            prompts
+        };
+        let (result, prompts) = tokio::join!(runner.run(), client);
+        result.unwrap();
+
+        let first = vec![("Password: ".into(), false), ("Code: ".into(), true)];
+        assert_eq!(
+            prompts,
+            vec![first.clone(), first, vec![("PIN: ".into(), false)]]
+        );
+        assert_eq!(
+            *answers.lock().unwrap(),
+            vec![
+                ("bob".to_string(), vec!["secret".to_string(), "123".into()]),
+                ("bob".to_string(), vec!["42".to_string()]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keyboard_interactive_not_offered() {
+        let script = vec![
+            service_request("ssh-userauth"),
+            userauth_request("bob", &["keyboard-interactive", "", ""], None),
+        ];
+        let (result, received, _) =
+            scripted_unauthenticated(PreferenceBuilder::default(), Handlers::new(), script).await;
+        result.unwrap();
+
+        let failures = received
+            .iter()
+            .filter(|m| matches!(m, Msg::UserauthFailure(..)))
+            .map(|m| format!("{:?}", m))
+            .collect::<Vec<_>>();
+        assert_eq!(failures.len(), 1);
+        assert!(!failures[0].contains("keyboard-interactive"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_methods_advertised() {
+        use futures::FutureExt as _;
+        use std::sync::atomic::AtomicUsize;
+
+        use crate::{AuthMethod, PasswordResult};
+
+        let password_calls = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_methods(|_| async { Ok(vec![AuthMethod::Publickey]) }.boxed());
+        let calls = password_calls.clone();
+        handlers.on_auth_password(move |_, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Ok(PasswordResult::Ok) }.boxed()
+        });
+
+        let script = vec![
+            service_request("ssh-userauth"),
+            userauth_request("alice", &["none"], None),
+            userauth_request("alice", &["password"], Some("secret")),
+        ];
+        let (result, received, _) =
+            scripted_unauthenticated(PreferenceBuilder::default(), handlers, script).await;
+        result.unwrap();
+
+        assert_eq!(password_calls.load(Ordering::SeqCst), 0);
+        let failures = received
+            .iter()
+            .filter(|m| matches!(m, Msg::UserauthFailure(..)))
+            .map(|m| format!("{:?}", m))
+            .collect::<Vec<_>>();
+        assert_eq!(failures.len(), 2);
+        for failure in failures {
+            assert!(
+                failure.contains(r#"NameList(["publickey"])"#),
+                "{}",
+                failure
+            );
         }
+        assert!(!received
+            .iter()
+            .any(|m| matches!(m, Msg::UserauthSuccess(..))));
+    }
+
+    /// `publickey` request signed by `pair` over `session_id`.
+    fn publickey_request(pair: &ring::signature::Ed25519KeyPair, session_id: &[u8]) -> Msg {
+        use crate::pack::{Pack as _, Unpack as _};
+        use ring::signature::KeyPair as _;
+
+        let mut blob = BytesMut::new();
+        "ssh-ed25519".pack(&mut blob);
+        Bytes::copy_from_slice(pair.public_key().as_ref()).pack(&mut blob);
+        let blob = blob.freeze();
+
+        let mut buf = BytesMut::new();
+        Bytes::copy_from_slice(session_id).pack(&mut buf);
+        let signed_from = buf.len();
+        buf.put_u8(50);
+        "alice".pack(&mut buf);
+        "ssh-connection".pack(&mut buf);
+        "publickey".pack(&mut buf);
+        true.pack(&mut buf);
+        "ssh-ed25519".pack(&mut buf);
+        blob.pack(&mut buf);
+
+        let mut signature = BytesMut::new();
+        "ssh-ed25519".pack(&mut signature);
+        Bytes::copy_from_slice(pair.sign(&buf).as_ref()).pack(&mut signature);
+        signature.freeze().pack(&mut buf);
+        Msg::unpack(&mut buf.split_off(signed_from).freeze()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_publickey_signature_verified() {
+        use futures::FutureExt as _;
+        use ring::rand::SystemRandom;
+        use ring::signature::Ed25519KeyPair;
+        use std::sync::atomic::AtomicUsize;
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        let counter = calls.clone();
+        handlers.on_auth_publickey(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { Ok(true) }.boxed()
+        });
+
+        let (preference, c_kexinit) = xor_preference(PreferenceBuilder::default()).await;
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (_, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            handlers,
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let (session_id, _) =
+                client_handshake(&mut theirs, c_kexinit, &preference, time::Duration::ZERO).await;
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            let mut replies = vec![];
+            // Signed over another session, then over ours.
+            for session_id in [&b"forged"[..], &session_id[..]] {
+                let request = publickey_request(&pair, session_id);
+                theirs.send(request).await.unwrap();
+                loop {
+                    match theirs.next().await.unwrap().unwrap() {
+                        Msg::ServiceAccept(..) => {}
+                        Msg::UserauthFailure(..) => break replies.push(false),
+                        Msg::UserauthSuccess(..) => break replies.push(true),
+                        x => panic!("{:?}", x),
+                    }
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            replies
+        };
+        let (result, replies) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(replies, vec![false, true]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_publickey_then_password() {
+        use futures::FutureExt as _;
+        use ring::rand::SystemRandom;
+        use ring::signature::Ed25519KeyPair;
+
+        use crate::{AuthMethod, AuthResult, PasswordResult};
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_methods(|_| async { Ok(vec![AuthMethod::Publickey]) }.boxed());
+        handlers.on_auth_publickey(|_, _| {
+            async { Ok(AuthResult::Partial(vec![AuthMethod::Password])) }.boxed()
+        });
+        handlers.on_auth_password(|_, _| async { Ok(PasswordResult::Ok) }.boxed());
+
+        let (preference, c_kexinit) = xor_preference(PreferenceBuilder::default()).await;
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (handle, controller) = global_handle();
+        let runner = Runner::new(
+            MsgStream::new(ours),
+            "SSH-2.0-client".into(),
+            "SSH-2.0-server".into(),
+            preference.clone(),
+            handlers,
+            controller,
+        );
+
+        let client = async move {
+            let mut theirs = MsgStream::new(theirs);
+            let (session_id, _) =
+                client_handshake(&mut theirs, c_kexinit, &preference, time::Duration::ZERO).await;
+            theirs.send(service_request("ssh-userauth")).await.unwrap();
+            let requests = vec![
+                userauth_request("alice", &["password"], Some("secret")),
+                publickey_request(&pair, &session_id),
+                userauth_request("alice", &["password"], Some("secret")),
+            ];
+            let mut replies = vec![];
+            for request in requests {
+                theirs.send(request).await.unwrap();
+                loop {
+                    match theirs.next().await.unwrap().unwrap() {
+                        Msg::ServiceAccept(..) => {}
+                        x @ Msg::UserauthFailure(..) => break replies.push(format!("{:?}", x)),
+                        Msg::UserauthSuccess(..) => break replies.push("success".into()),
+                        x => panic!("{:?}", x),
+                    }
+                }
+            }
+            theirs.close().await.unwrap();
+            while let Some(Ok(..)) = theirs.next().await {}
+            replies
+        };
+        let (result, replies) = tokio::join!(runner.run(), client);
+        result.unwrap();
+        assert_eq!(replies.len(), 3);
+        assert!(replies[0].contains(r#"NameList(["publickey"]), partial_success: false"#));
+        assert!(replies[1].contains(r#"NameList(["password"]), partial_success: true"#));
+        assert_eq!(replies[2], "success");
+        assert_eq!(handle.username(), Some("alice".into()));
     }
 }
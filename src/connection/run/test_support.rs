@@ -0,0 +1,283 @@
+//! Runner driven by a scripted client, for tests of the handlers.
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::sink::SinkExt as _;
+use futures::stream::StreamExt as _;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time;
+
+use crate::connection::global_handle::global_handle;
+use crate::handlers::{HandlerError, Handlers};
+use crate::msg::Msg;
+use crate::preference::{Preference, PreferenceBuilder};
+use crate::stream::msg::MsgStream;
+use crate::test_support::service_request;
+use crate::{GlobalHandle, SshError};
+
+use super::Runner;
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    /// Skip auth for tests of the connection protocol.
+    pub(super) fn authenticated(self) -> Self {
+        self.authenticated_as("alice")
+    }
+}
+
+/// Run against a client sending `script`, then EOF.
+pub(super) async fn scripted(
+    preference: PreferenceBuilder,
+    script: Vec<Msg>,
+) -> (Result<(), SshError>, Vec<Msg>, GlobalHandle) {
+    scripted_with(preference, Handlers::<anyhow::Error>::new(), script).await
+}
+
+/// Run already authenticated against a client sending `script`, then EOF.
+pub(super) async fn scripted_with(
+    preference: PreferenceBuilder,
+    handlers: Handlers<anyhow::Error>,
+    script: Vec<Msg>,
+) -> (Result<(), SshError>, Vec<Msg>, GlobalHandle) {
+    run_script(preference, handlers, script, true).await
+}
+
+/// Like [`scripted_with`], with auth still to be done by `script`.
+pub(super) async fn scripted_unauthenticated(
+    preference: PreferenceBuilder,
+    handlers: Handlers<anyhow::Error>,
+    script: Vec<Msg>,
+) -> (Result<(), SshError>, Vec<Msg>, GlobalHandle) {
+    run_script(preference, handlers, script, false).await
+}
+
+async fn run_script(
+    preference: PreferenceBuilder,
+    handlers: Handlers<anyhow::Error>,
+    script: Vec<Msg>,
+    authenticated: bool,
+) -> (Result<(), SshError>, Vec<Msg>, GlobalHandle) {
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    let preference = Arc::new(preference.build().await.unwrap());
+    let (handle, controller) = global_handle();
+    let mut runner = Runner::new(
+        MsgStream::new(ours),
+        "SSH-2.0-client".into(),
+        "SSH-2.0-server".into(),
+        preference,
+        handlers,
+        controller,
+    );
+    if authenticated {
+        runner = runner.authenticated();
+    }
+
+    let client = async move {
+        let mut theirs = MsgStream::new(theirs);
+        for msg in script {
+            theirs.send(msg).await.unwrap();
+        }
+        theirs.close().await.unwrap();
+        let mut received = vec![];
+        while let Some(Ok(msg)) = theirs.next().await {
+            received.push(msg);
+        }
+        received
+    };
+    let (result, received) = tokio::join!(runner.run(), client);
+    (result, received, handle)
+}
+
+#[derive(Debug)]
+struct Xor(Vec<u8>, usize);
+
+impl crate::CustomCipher for Xor {
+    fn update(&mut self, target: &mut [u8]) -> Result<(), SshError> {
+        for b in target {
+            *b ^= self.0[self.1 % self.0.len()];
+            self.1 += 1;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct XorFactory;
+
+impl crate::CipherFactory for XorFactory {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn key_length(&self) -> usize {
+        16
+    }
+
+    fn new_for_encrypt(
+        &self,
+        key: &[u8],
+        _: &[u8],
+    ) -> Result<Box<dyn crate::CustomCipher>, SshError> {
+        Ok(Box::new(Xor(key.to_vec(), 0)))
+    }
+
+    fn new_for_decrypt(
+        &self,
+        key: &[u8],
+        _: &[u8],
+    ) -> Result<Box<dyn crate::CustomCipher>, SshError> {
+        Ok(Box::new(Xor(key.to_vec(), 0)))
+    }
+}
+
+/// Server preference with `xor@example.com` and matching client kexinit.
+pub(super) async fn xor_preference(
+    mut preference: PreferenceBuilder,
+) -> (Arc<Preference>, crate::msg::kexinit::Kexinit) {
+    use crate::kex;
+
+    preference
+        .register_cipher("xor@example.com", Arc::new(XorFactory))
+        .unwrap();
+    let preference = Arc::new(preference.build().await.unwrap());
+    let xor = preference
+        .cipher_algorithms()
+        .iter()
+        .find(|c| c.as_ref() == "xor@example.com")
+        .unwrap()
+        .clone();
+
+    let mut client_preference = PreferenceBuilder::default();
+    client_preference
+        .add_kex_algorithm(kex::Algorithm::Curve25519Sha256)
+        .add_cipher_algorithm(xor);
+    let c_kexinit = client_preference
+        .build()
+        .await
+        .unwrap()
+        .to_kexinit()
+        .unwrap();
+    (preference, c_kexinit)
+}
+
+/// `c_kexinit` offering `kex` instead, guessing a kex packet if `guess`.
+pub(super) fn with_kex(
+    c_kexinit: &crate::msg::kexinit::Kexinit,
+    kex: &[&str],
+    guess: bool,
+) -> crate::msg::kexinit::Kexinit {
+    crate::msg::kexinit::KexinitBuilder::default()
+        .cookie(*c_kexinit.cookie())
+        .kex_algorithms(kex.iter().copied().collect())
+        .server_host_key_algorithms(c_kexinit.server_host_key_algorithms().clone())
+        .cipher_algorithms_c2s(c_kexinit.cipher_algorithms_c2s().clone())
+        .cipher_algorithms_s2c(c_kexinit.cipher_algorithms_s2c().clone())
+        .mac_algorithms_c2s(c_kexinit.mac_algorithms_c2s().clone())
+        .mac_algorithms_s2c(c_kexinit.mac_algorithms_s2c().clone())
+        .compression_algorithms_c2s(c_kexinit.compression_algorithms_c2s().clone())
+        .compression_algorithms_s2c(c_kexinit.compression_algorithms_s2c().clone())
+        .languages_c2s(c_kexinit.languages_c2s().clone())
+        .languages_s2c(c_kexinit.languages_s2c().clone())
+        .first_kex_packet_follows(guess)
+        .build()
+        .unwrap()
+}
+
+/// Key exchange as client, pausing `delay` before NEWKEYS.
+///
+/// Returns exchange hash and shared secret.
+pub(super) async fn client_handshake<IO>(
+    theirs: &mut MsgStream<IO>,
+    c_kexinit: crate::msg::kexinit::Kexinit,
+    preference: &Preference,
+    delay: time::Duration,
+) -> (Bytes, Bytes)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let versions = ("SSH-2.0-client", "SSH-2.0-server");
+    crate::test_support::client_handshake(theirs, versions, c_kexinit, preference, delay).await
+}
+
+/// Key exchange started by the client, sending `interleaved` right after its KEXINIT.
+///
+/// Returns exchange hash, shared secret and messages received before the
+/// server's KEXINIT.
+pub(super) async fn client_rekey<IO>(
+    theirs: &mut MsgStream<IO>,
+    c_kexinit: crate::msg::kexinit::Kexinit,
+    preference: &Preference,
+    interleaved: Vec<Msg>,
+) -> (Bytes, Bytes, Vec<Msg>)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let versions = ("SSH-2.0-client", "SSH-2.0-server");
+    crate::test_support::client_rekey(theirs, versions, c_kexinit, preference, interleaved).await
+}
+
+/// Handshake, request `ssh-userauth` and close.
+///
+/// Returns if it was accepted, exchange hash, shared secret and client cookie.
+pub(super) async fn run_xor_handshake(preference: PreferenceBuilder) -> (bool, Bytes, Bytes, u128) {
+    use futures::FutureExt as _;
+
+    let (preference, c_kexinit) = xor_preference(preference).await;
+    let cookie = *c_kexinit.cookie();
+
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    let (handle, controller) = global_handle();
+    let completed = Arc::new(std::sync::Mutex::new(vec![]));
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    let record = completed.clone();
+    handlers.on_handshake_complete(
+        move |client_version, algorithms: crate::NegotiatedAlgorithms| {
+            let kex = algorithms.kex_algorithm().as_ref().to_string();
+            record.lock().unwrap().push((client_version, kex));
+            futures::future::ok(()).boxed()
+        },
+    );
+    let runner = Runner::new(
+        MsgStream::new(ours),
+        "SSH-2.0-client".into(),
+        "SSH-2.0-server".into(),
+        preference.clone(),
+        handlers,
+        controller,
+    );
+
+    let client = async move {
+        let mut theirs = MsgStream::new(theirs);
+        let (hash, secret) =
+            client_handshake(&mut theirs, c_kexinit, &preference, time::Duration::ZERO).await;
+        theirs.send(service_request("ssh-userauth")).await.unwrap();
+        let accepted = matches!(theirs.next().await, Some(Ok(Msg::ServiceAccept(..))));
+        theirs.close().await.unwrap();
+        while let Some(Ok(..)) = theirs.next().await {}
+        (accepted, hash, secret)
+    };
+    let (result, (accepted, hash, secret)) = tokio::join!(runner.run(), client);
+    result.unwrap();
+    assert_eq!(handle.session_id(), Some(hash.clone()));
+    assert_eq!(handle.client_version(), "SSH-2.0-client");
+    let algorithms = handle.algorithms().unwrap();
+    assert_eq!(
+        algorithms.kex_algorithm(),
+        &crate::kex::Algorithm::Curve25519Sha256
+    );
+    assert_eq!(
+        algorithms.cipher_algorithm_c2s().as_ref(),
+        "xor@example.com"
+    );
+    assert_eq!(
+        *completed.lock().unwrap(),
+        [(
+            "SSH-2.0-client".to_string(),
+            "curve25519-sha256".to_string()
+        )]
+    );
+    (accepted, hash, secret, cookie)
+}
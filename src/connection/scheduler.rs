@@ -0,0 +1,318 @@
+//! Deficit round-robin scheduling of channel output.
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use super::window::RemoteWindow;
+
+/// Bytes granted per round for priority 1.
+const QUANTUM: usize = 16 * 1024;
+
+/// Channel priority shared with handlers. (default: 1)
+#[derive(Debug, Clone)]
+pub(crate) struct Priority(Arc<AtomicU32>);
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU32::new(1)))
+    }
+}
+
+impl Priority {
+    pub(crate) fn set(&self, weight: u32) {
+        self.0.store(weight.max(1), Ordering::Relaxed)
+    }
+
+    fn quantum(&self) -> usize {
+        self.0.load(Ordering::Relaxed) as usize * QUANTUM
+    }
+}
+
+/// Queued item which can be sent in parts.
+pub(crate) trait Split {
+    /// Split off the first `at` bytes.
+    fn split_to(&mut self, at: usize) -> Self;
+}
+
+#[derive(Debug)]
+struct Lane<T> {
+    items: VecDeque<(T, usize)>,
+    deficit: usize,
+    granted: bool,
+    priority: Priority,
+    /// `None` sends without flow control.
+    window: Option<RemoteWindow>,
+    /// Waiting for window. Not in `active` meanwhile.
+    blocked: bool,
+}
+
+impl<T> Lane<T> {
+    fn new(priority: Priority) -> Self {
+        Self {
+            items: VecDeque::new(),
+            deficit: 0,
+            granted: false,
+            priority,
+            window: None,
+            blocked: false,
+        }
+    }
+}
+
+/// Per-channel queues drained in weighted round-robin order.
+///
+/// Items of one channel keep their order. Sized items of a channel with a
+/// remote window are split to fit it and wait while it is exhausted.
+#[derive(Debug)]
+pub(crate) struct Scheduler<T> {
+    lanes: HashMap<u32, Lane<T>>,
+    active: VecDeque<u32>,
+}
+
+impl<T> Default for Scheduler<T> {
+    fn default() -> Self {
+        Self {
+            lanes: HashMap::new(),
+            active: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Split> Scheduler<T> {
+    fn lane(&mut self, channel: u32) -> &mut Lane<T> {
+        self.lanes
+            .entry(channel)
+            .or_insert_with(|| Lane::new(Priority::default()))
+    }
+
+    /// Priority handle of the channel.
+    pub(crate) fn priority(&mut self, channel: u32) -> Priority {
+        self.lane(channel).priority.clone()
+    }
+
+    /// Apply flow control to the channel from now on.
+    pub(crate) fn set_window(&mut self, channel: u32, window: RemoteWindow) {
+        self.lane(channel).window = Some(window);
+    }
+
+    /// Client granted more window for the channel.
+    ///
+    /// `false` if the window went beyond 2^32 - 1 and was capped.
+    pub(crate) fn adjust_window(&mut self, channel: u32, bytes_to_add: u32) -> bool {
+        let lane = match self.lanes.get_mut(&channel) {
+            Some(lane) => lane,
+            None => return true,
+        };
+        let fits = match &mut lane.window {
+            Some(window) => window.adjust(bytes_to_add),
+            None => true,
+        };
+        if lane.blocked {
+            lane.blocked = false;
+            self.active.push_back(channel);
+        }
+        fits
+    }
+
+    /// Nothing queued, sendable or not.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lanes.values().all(|lane| lane.items.is_empty())
+    }
+
+    /// Some item can be sent now.
+    pub(crate) fn is_ready(&self) -> bool {
+        !self.active.is_empty()
+    }
+
+    /// Enqueue item which costs `size` bytes.
+    pub(crate) fn push(&mut self, channel: u32, item: T, size: usize) {
+        let lane = self.lane(channel);
+        let activate = lane.items.is_empty() && !lane.blocked;
+        lane.items.push_back((item, size));
+        if activate {
+            self.active.push_back(channel);
+        }
+    }
+
+    /// Dequeue next sendable item.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        loop {
+            let channel = *self.active.front()?;
+            let lane = self.lanes.get_mut(&channel).unwrap();
+            if !lane.granted {
+                lane.deficit += lane.priority.quantum();
+                lane.granted = true;
+            }
+
+            let size = lane.items.front().unwrap().1;
+            let sendable = match &lane.window {
+                Some(window) if size > 0 => window.sendable(size),
+                _ => size,
+            };
+            if size > 0 && sendable == 0 {
+                lane.deficit = 0;
+                lane.granted = false;
+                lane.blocked = true;
+                self.active.pop_front();
+                continue;
+            }
+            if sendable > lane.deficit {
+                lane.granted = false;
+                self.active.rotate_left(1);
+                continue;
+            }
+
+            let item = if sendable < size {
+                let head = lane.items.front_mut().unwrap();
+                head.1 -= sendable;
+                head.0.split_to(sendable)
+            } else {
+                lane.items.pop_front().unwrap().0
+            };
+            if let Some(window) = &mut lane.window {
+                window.consume(sendable);
+            }
+            lane.deficit -= sendable;
+            if lane.items.is_empty() {
+                lane.deficit = 0;
+                lane.granted = false;
+                self.active.pop_front();
+            }
+            return Some(item);
+        }
+    }
+
+    /// Number of queued items per channel, blocked ones included.
+    pub(crate) fn depths(&self) -> Vec<(u32, usize)> {
+        let mut depths = self
+            .lanes
+            .iter()
+            .filter(|(_, lane)| !lane.items.is_empty())
+            .map(|(channel, lane)| (*channel, lane.items.len()))
+            .collect::<Vec<_>>();
+        depths.sort_unstable();
+        depths
+    }
+
+    /// Forget the channel once its queue is drained.
+    pub(crate) fn remove(&mut self, channel: u32) {
+        if let Some(lane) = self.lanes.get(&channel) {
+            if lane.items.is_empty() {
+                self.lanes.remove(&channel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Split for u32 {
+        fn split_to(&mut self, _: usize) -> Self {
+            *self
+        }
+    }
+
+    impl Split for Vec<u8> {
+        fn split_to(&mut self, at: usize) -> Self {
+            let rest = self.split_off(at);
+            std::mem::replace(self, rest)
+        }
+    }
+
+    fn drain(scheduler: &mut Scheduler<u32>) -> Vec<u32> {
+        let mut result = vec![];
+        while let Some(item) = scheduler.pop() {
+            result.push(item);
+        }
+        result
+    }
+
+    #[test]
+    fn test_fifo_per_channel() {
+        let mut scheduler = Scheduler::default();
+        for n in 0..3 {
+            scheduler.push(0, n, 10);
+        }
+        assert_eq!(scheduler.depths(), vec![(0, 3)]);
+        assert_eq!(drain(&mut scheduler), vec![0, 1, 2]);
+        assert!(scheduler.is_empty());
+        assert!(scheduler.depths().is_empty());
+    }
+
+    #[test]
+    fn test_interleave() {
+        let mut scheduler = Scheduler::default();
+        for _ in 0..8 {
+            scheduler.push(1, 1, 32 * 1024);
+        }
+        for _ in 0..4 {
+            scheduler.push(2, 2, 100);
+        }
+        let order = drain(&mut scheduler);
+        let first = order.iter().position(|c| *c == 2).unwrap();
+        assert!(first <= 1, "{:?}", order);
+    }
+
+    #[test]
+    fn test_priority() {
+        fn last_interactive(weight: u32) -> usize {
+            let mut scheduler = Scheduler::default();
+            scheduler.priority(2).set(weight);
+            for _ in 0..8 {
+                scheduler.push(1, 1, QUANTUM);
+            }
+            for _ in 0..4 {
+                scheduler.push(2, 2, QUANTUM);
+            }
+            let order = drain(&mut scheduler);
+            order.iter().rposition(|c| *c == 2).unwrap()
+        }
+
+        let normal = last_interactive(1);
+        let high = last_interactive(4);
+        assert_eq!(normal, 7);
+        assert_eq!(high, 4);
+    }
+
+    #[test]
+    fn test_zero_sized() {
+        let mut scheduler = Scheduler::default();
+        scheduler.push(0, 0, 1024 * 1024);
+        scheduler.push(0, 1, 0);
+        assert_eq!(drain(&mut scheduler), vec![0, 1]);
+        scheduler.remove(0);
+        assert!(scheduler.lanes.is_empty());
+    }
+
+    #[test]
+    fn test_window() {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_window(1, RemoteWindow::new(10, 4));
+        scheduler.push(1, vec![1; 12], 12);
+        scheduler.push(1, vec![], 0);
+        scheduler.push(2, vec![2; 3], 3);
+
+        let mut sent = vec![];
+        while let Some(item) = scheduler.pop() {
+            sent.push(item);
+        }
+        assert_eq!(sent, vec![vec![1; 4], vec![1; 4], vec![1; 2], vec![2; 3]],);
+        assert!(!scheduler.is_ready());
+        assert!(!scheduler.is_empty());
+        assert_eq!(scheduler.depths(), vec![(1, 2)]);
+
+        scheduler.adjust_window(1, 5);
+        assert!(scheduler.is_ready());
+        assert_eq!(scheduler.pop(), Some(vec![1; 2]));
+        assert_eq!(scheduler.pop(), Some(vec![]));
+        assert_eq!(scheduler.pop(), None);
+        assert!(scheduler.is_empty());
+
+        scheduler.adjust_window(1, 5);
+        scheduler.push(1, vec![1; 1], 1);
+        assert!(scheduler.is_ready());
+        assert_eq!(scheduler.pop(), Some(vec![1; 1]));
+    }
+}
@@ -0,0 +1,144 @@
+//! Connection setup latency per protocol phase.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use getset::Getters;
+use tokio::time::Instant;
+
+/// Transition point of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Established,
+    Kexinit,
+    NewKeys,
+    UserauthRequest,
+    UserauthSuccess,
+    ChannelDataIn,
+    ChannelDataOut,
+}
+
+/// When each phase was first reached, relative to connection start.
+///
+/// `None` until reached.
+#[derive(Debug, Clone, Getters)]
+pub struct PhaseTimings {
+    /// Wall clock connection start, for correlation only.
+    #[get = "pub"]
+    started_at: SystemTime,
+
+    /// Version exchange done.
+    #[get = "pub"]
+    established: Option<Duration>,
+
+    /// First KEXINIT received.
+    #[get = "pub"]
+    first_kexinit: Option<Duration>,
+
+    /// First NEWKEYS received.
+    #[get = "pub"]
+    newkeys: Option<Duration>,
+
+    #[get = "pub"]
+    first_userauth_request: Option<Duration>,
+
+    #[get = "pub"]
+    userauth_success: Option<Duration>,
+
+    #[get = "pub"]
+    first_channel_data_in: Option<Duration>,
+
+    #[get = "pub"]
+    first_channel_data_out: Option<Duration>,
+}
+
+fn between(from: Option<Duration>, to: Option<Duration>) -> Option<Duration> {
+    Some(to?.saturating_sub(from?))
+}
+
+impl PhaseTimings {
+    fn new() -> Self {
+        Self {
+            started_at: SystemTime::now(),
+            established: None,
+            first_kexinit: None,
+            newkeys: None,
+            first_userauth_request: None,
+            userauth_success: None,
+            first_channel_data_in: None,
+            first_channel_data_out: None,
+        }
+    }
+
+    /// Version exchange duration.
+    pub fn version_exchange(&self) -> Option<Duration> {
+        self.established
+    }
+
+    /// First KEXINIT to NEWKEYS.
+    pub fn kex(&self) -> Option<Duration> {
+        between(self.first_kexinit, self.newkeys)
+    }
+
+    /// First auth request to success.
+    pub fn auth(&self) -> Option<Duration> {
+        between(self.first_userauth_request, self.userauth_success)
+    }
+
+    fn slot(&mut self, phase: Phase) -> &mut Option<Duration> {
+        match phase {
+            Phase::Established => &mut self.established,
+            Phase::Kexinit => &mut self.first_kexinit,
+            Phase::NewKeys => &mut self.newkeys,
+            Phase::UserauthRequest => &mut self.first_userauth_request,
+            Phase::UserauthSuccess => &mut self.userauth_success,
+            Phase::ChannelDataIn => &mut self.first_channel_data_in,
+            Phase::ChannelDataOut => &mut self.first_channel_data_out,
+        }
+    }
+}
+
+/// Shared phase recorder. Started on creation.
+#[derive(Debug, Clone)]
+pub(crate) struct Phases(Arc<Mutex<(Instant, PhaseTimings)>>);
+
+impl Phases {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new((Instant::now(), PhaseTimings::new()))))
+    }
+
+    /// Connection start.
+    pub(crate) fn started(&self) -> Instant {
+        self.0.lock().unwrap().0
+    }
+
+    /// Record `phase` unless already reached.
+    pub(crate) fn mark(&self, phase: Phase) {
+        let mut inner = self.0.lock().unwrap();
+        let elapsed = inner.0.elapsed();
+        inner.1.slot(phase).get_or_insert(elapsed);
+    }
+
+    pub(crate) fn timings(&self) -> PhaseTimings {
+        self.0.lock().unwrap().1.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mark_first() {
+        let phases = Phases::new();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        phases.mark(Phase::Kexinit);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        phases.mark(Phase::Kexinit);
+        phases.mark(Phase::NewKeys);
+
+        let timings = phases.timings();
+        assert_eq!(timings.first_kexinit(), &Some(Duration::from_secs(1)));
+        assert_eq!(timings.kex(), Some(Duration::from_secs(2)));
+        assert_eq!(timings.auth(), None);
+    }
+}
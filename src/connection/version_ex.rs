@@ -1,54 +1,104 @@
+use std::time::Duration;
+
 use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
 
 use crate::SshError;
 
-const MAX_BUFFER: usize = 255;
+/// Maximum identification line length, CR LF included (RFC 4253 section 4.2).
+const MAX_LINE: usize = 255;
 
-async fn vex_recv<IO>(mut io: IO) -> Result<String, SshError>
+/// Next line including its `\n`, or the first `max` bytes if none came by then.
+async fn read_line<IO>(io: &mut IO, max: usize) -> Result<Vec<u8>, SshError>
 where
     IO: AsyncRead + Unpin,
 {
-    let mut buf = Vec::with_capacity(MAX_BUFFER);
-    loop {
+    let mut buf = Vec::with_capacity(MAX_LINE);
+    while buf.len() < max {
         let b = io.read_u8().await?;
         buf.push(b);
         if b == b'\n' {
             break;
         }
     }
+    Ok(buf)
+}
+
+/// Receive the identification string, skipping up to `lines` lines or `bytes`
+/// bytes sent before it.
+async fn vex_recv<IO>(mut io: IO, (lines, bytes): (usize, usize)) -> Result<String, SshError>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut skipped = (0, 0);
+    loop {
+        let line = read_line(&mut io, MAX_LINE.max(bytes - skipped.1)).await?;
+        if line.starts_with(b"SSH-") {
+            if line.len() > MAX_LINE || !line.ends_with(b"\n") {
+                return Err(SshError::VersionTooLong);
+            }
+            let line = &line[..line.len() - 1];
+            return parse(line.strip_suffix(b"\r").unwrap_or(line)); // bare LF for old libssh
+        }
 
-    let result = match &buf[..] {
-        [result @ .., b'\r', b'\n'] => result,
-        [result @ .., b'\n'] => result, // for old libssh
-        x => {
-            return Err(SshError::InvalidVersion(
-                String::from_utf8_lossy(x).to_string(),
-            ))
+        skipped = (skipped.0 + 1, skipped.1 + line.len());
+        if !line.ends_with(b"\n") || skipped.0 > lines || skipped.1 > bytes {
+            return Err(SshError::PreBannerTooLong);
         }
-    };
-    let result = String::from_utf8_lossy(&result);
-    if !result.starts_with("SSH-2.0-") {
-        return Err(SshError::InvalidVersion(result.to_string()));
+        log::debug!(
+            "skipped line before version: {:?}",
+            String::from_utf8_lossy(&line)
+        );
     }
-    Ok(result.to_string())
 }
 
-async fn vex_send<IO>(mut io: IO, name: &str) -> Result<String, SshError>
+/// Identification string `SSH-protoversion-softwareversion [comments]`.
+fn parse(line: &[u8]) -> Result<String, SshError> {
+    let line = String::from_utf8_lossy(line).to_string();
+    let mut parts = line.splitn(3, '-').skip(1);
+    match (parts.next(), parts.next()) {
+        // 1.99 is 2.0 with SSH-1 fallback (RFC 4253 section 5.1).
+        (Some("2.0"), Some(..)) | (Some("1.99"), Some(..)) => Ok(line),
+        (Some(..), Some(..)) => Err(SshError::UnsupportedVersion(line)),
+        _ => Err(SshError::InvalidVersion(line)),
+    }
+}
+
+async fn vex_send<IO>(mut io: IO, version: &str) -> Result<String, SshError>
 where
     IO: AsyncWrite + Unpin,
 {
-    let name = format!("SSH-2.0-{}", name);
-    io.write_all(format!("{}\r\n", name).as_bytes()).await?;
-    Ok(name)
+    io.write_all(format!("{}\r\n", version).as_bytes()).await?;
+    Ok(version.into())
 }
 
-pub(crate) async fn vex<IO>(io: IO, name: &str) -> Result<(String, String), SshError>
+pub(crate) async fn vex<IO>(
+    io: IO,
+    version: &str,
+    await_first: Option<Duration>,
+    pre_banner: (usize, usize),
+) -> Result<(String, String), SshError>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
     let (rx, tx) = split(io);
-    let (recv, send) = tokio::try_join!(vex_recv(rx), vex_send(tx, name))?;
-    Ok((recv, send))
+    if let Some(cap) = await_first {
+        let recv = vex_recv(rx, pre_banner);
+        tokio::pin!(recv);
+        let received = tokio::select! {
+            recv = &mut recv => Some(recv?),
+            _ = time::sleep(cap) => None,
+        };
+        let send = vex_send(tx, version).await?;
+        let recv = match received {
+            Some(recv) => recv,
+            None => recv.await?,
+        };
+        Ok((recv, send))
+    } else {
+        let (recv, send) = tokio::try_join!(vex_recv(rx, pre_banner), vex_send(tx, version))?;
+        Ok((recv, send))
+    }
 }
 
 #[cfg(test)]
@@ -58,13 +108,17 @@ mod tests {
     use tokio_test::io::Builder;
     use tokio_test::*;
 
+    const LIMIT: (usize, usize) = (10, 4096);
+
     #[tokio::test]
     async fn test_vex() {
         let mock = Builder::new()
             .read(b"SSH-2.0-ssh\r\n")
             .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let (r, x) = super::vex(mock, "ssssh").await.unwrap();
+        let (r, x) = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT)
+            .await
+            .unwrap();
         assert_eq!(&r, "SSH-2.0-ssh");
         assert_eq!(&x, "SSH-2.0-ssssh");
     }
@@ -75,7 +129,9 @@ mod tests {
             .read(b"SSH-2.0-ssh\r\na")
             .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let (r, x) = super::vex(&mut mock, "ssssh").await.unwrap();
+        let (r, x) = super::vex(&mut mock, "SSH-2.0-ssssh", None, LIMIT)
+            .await
+            .unwrap();
         assert_eq!(&r, "SSH-2.0-ssh");
         assert_eq!(&x, "SSH-2.0-ssssh");
 
@@ -87,7 +143,7 @@ mod tests {
     #[tokio::test]
     async fn test_vex_empty() {
         let mock = Builder::new().read(b"").write(b"SSH-2.0-ssssh\r\n").build();
-        let result = super::vex(mock, "ssssh").await;
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
         assert_err!(result);
     }
 
@@ -97,16 +153,14 @@ mod tests {
             .read(&[0; 256])
             .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let result = super::vex(mock, "ssssh").await;
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
         assert_err!(result);
     }
 
     #[tokio::test]
     async fn test_vex_ioerr() {
-        let mock = Builder::new()
-            .read_error(io::Error::new(io::ErrorKind::Other, ""))
-            .build();
-        let result = super::vex(mock, "ssssh").await;
+        let mock = Builder::new().read_error(io::Error::other("")).build();
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
         assert_err!(result);
     }
 
@@ -116,7 +170,9 @@ mod tests {
             .read(b"SSH-2.0-ssh\n")
             .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let (r, x) = super::vex(mock, "ssssh").await.unwrap();
+        let (r, x) = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT)
+            .await
+            .unwrap();
         assert_eq!(&r, "SSH-2.0-ssh");
         assert_eq!(&x, "SSH-2.0-ssssh");
     }
@@ -124,16 +180,137 @@ mod tests {
     #[tokio::test]
     async fn test_vex_invalid_version() {
         let mock = Builder::new().read(b"S\r\n").build();
-        let result = super::vex(mock, "ssssh").await;
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
         assert_err!(result);
     }
 
     #[tokio::test]
-    async fn test_vex_ioerr2() {
+    async fn test_vex_junk_first() {
+        let mock = Builder::new()
+            .read(b"\r\nGET / HTTP/1.1\r\nHost: x\n")
+            .read(b"SSH-2.0-ssh proxied\r\n")
+            .write(b"SSH-2.0-ssssh\r\n")
+            .build();
+        let (r, _) = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(&r, "SSH-2.0-ssh proxied");
+    }
+
+    #[tokio::test]
+    async fn test_vex_split_reads() {
+        let mock = Builder::new()
+            .read(b"SS")
+            .read(b"H-2.0-s")
+            .read(b"sh\r")
+            .read(b"\n")
+            .write(b"SSH-2.0-ssssh\r\n")
+            .build();
+        let (r, _) = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(&r, "SSH-2.0-ssh");
+    }
+
+    #[tokio::test]
+    async fn test_vex_too_much_junk() {
+        let mock = Builder::new().read(&b"junk\r\n".repeat(11)).build();
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
+        assert!(
+            matches!(result, Err(SshError::PreBannerTooLong)),
+            "{:?}",
+            result
+        );
+
+        let mock = Builder::new().read(&[b'x'; 255]).build();
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, (10, 100)).await;
+        assert!(
+            matches!(result, Err(SshError::PreBannerTooLong)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vex_banner_limit() {
+        // 255 bytes with CR LF is the maximum.
+        let banner = format!("SSH-2.0-{}\r\n", "x".repeat(245));
         let mock = Builder::new()
-            .write_error(io::Error::new(io::ErrorKind::Other, ""))
+            .read(banner.as_bytes())
+            .write(b"SSH-2.0-ssssh\r\n")
+            .build();
+        let (r, _) = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(r.len(), 253);
+
+        let banner = format!("SSH-2.0-{}\r\n", "x".repeat(246));
+        let mock = Builder::new().read(banner.as_bytes()).build();
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
+        assert!(
+            matches!(result, Err(SshError::VersionTooLong)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vex_protocol_version() {
+        let mock = Builder::new()
+            .read(b"SSH-1.99-ssh\r\n")
+            .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let result = super::vex(mock, "ssssh").await;
+        let (r, _) = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(&r, "SSH-1.99-ssh");
+
+        let mock = Builder::new().read(b"SSH-1.5-ssh\r\n").build();
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
+        assert!(
+            matches!(&result, Err(SshError::UnsupportedVersion(v)) if v == "SSH-1.5-ssh"),
+            "{:?}",
+            result
+        );
+
+        let mock = Builder::new().read(b"SSH-2.0\r\n").build();
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
+        assert!(
+            matches!(result, Err(SshError::InvalidVersion(..))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vex_await_first() {
+        let mock = Builder::new()
+            .wait(Duration::from_millis(50))
+            .read(b"SSH-2.0-ssh\r\n")
+            .write(b"SSH-2.0-ssssh\r\n")
+            .build();
+        let cap = Some(Duration::from_secs(60));
+        let (r, x) = super::vex(mock, "SSH-2.0-ssssh", cap, LIMIT).await.unwrap();
+        assert_eq!(&r, "SSH-2.0-ssh");
+        assert_eq!(&x, "SSH-2.0-ssssh");
+    }
+
+    #[tokio::test]
+    async fn test_vex_await_first_cap_expired() {
+        let mock = Builder::new()
+            .write(b"SSH-2.0-ssssh\r\n")
+            .read(b"SSH-2.0-ssh\r\n")
+            .build();
+        let cap = Some(Duration::from_millis(10));
+        let (r, x) = super::vex(mock, "SSH-2.0-ssssh", cap, LIMIT).await.unwrap();
+        assert_eq!(&r, "SSH-2.0-ssh");
+        assert_eq!(&x, "SSH-2.0-ssssh");
+    }
+
+    #[tokio::test]
+    async fn test_vex_ioerr2() {
+        let mock = Builder::new().write_error(io::Error::other("")).build();
+        let result = super::vex(mock, "SSH-2.0-ssssh", None, LIMIT).await;
         assert_err!(result);
     }
 }
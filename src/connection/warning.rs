@@ -0,0 +1,171 @@
+//! Tolerated peer protocol violations.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::trace::warn;
+use thiserror::Error;
+
+/// Logged occurrences per kind. Further ones are only counted.
+const LOG_LIMIT: u64 = 8;
+
+/// Kind of [`ProtocolWarning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    UnknownChannel,
+    DataAfterEof,
+    DuplicateEof,
+    UnexpectedChannelReply,
+    DuplicateServiceRequest,
+    UnusedChannel,
+    WindowExceeded,
+    PacketExceeded,
+    WindowOverAdjust,
+}
+
+/// Peer behavior violating the protocol which is tolerated by default,
+/// except [`Self::PacketExceeded`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProtocolWarning {
+    /// Channel message for a channel which is not open. Ignored.
+    #[error("{message} for unknown channel {channel}")]
+    UnknownChannel { channel: u32, message: &'static str },
+
+    /// Data received after client sent EOF. Dropped.
+    #[error("{bytes} bytes after eof on channel {channel}")]
+    DataAfterEof { channel: u32, bytes: usize },
+
+    /// EOF received twice. Ignored.
+    #[error("duplicate eof on channel {channel}")]
+    DuplicateEof { channel: u32 },
+
+    /// Channel success or failure without a pending request. Ignored.
+    #[error("unexpected channel reply for {channel}: {success}")]
+    UnexpectedChannelReply { channel: u32, success: bool },
+
+    /// Service requested again. Accepted again.
+    #[error("duplicate service request {service:?}")]
+    DuplicateServiceRequest { service: String },
+
+    /// Channel never used since open. Closed.
+    #[error("channel {channel} unused for {idle:?}")]
+    UnusedChannel { channel: u32, idle: Duration },
+
+    /// Data beyond the window we advertised. Truncated to the window.
+    #[error("{bytes} bytes exceed window {remaining} on channel {channel}")]
+    WindowExceeded {
+        channel: u32,
+        bytes: usize,
+        remaining: u32,
+    },
+
+    /// Data larger than the maximum packet we advertised. Disconnects by
+    /// default, accepted if tolerated.
+    #[error("{bytes} bytes exceed maximum packet {maximum} on channel {channel}")]
+    PacketExceeded {
+        channel: u32,
+        bytes: usize,
+        maximum: u32,
+    },
+
+    /// Window adjust growing our send window beyond 2^32 - 1. Capped.
+    #[error("window adjust by {bytes_to_add} overflows window on channel {channel}")]
+    WindowOverAdjust { channel: u32, bytes_to_add: u32 },
+}
+
+impl ProtocolWarning {
+    pub fn kind(&self) -> WarningKind {
+        match self {
+            Self::UnknownChannel { .. } => WarningKind::UnknownChannel,
+            Self::DataAfterEof { .. } => WarningKind::DataAfterEof,
+            Self::DuplicateEof { .. } => WarningKind::DuplicateEof,
+            Self::UnexpectedChannelReply { .. } => WarningKind::UnexpectedChannelReply,
+            Self::DuplicateServiceRequest { .. } => WarningKind::DuplicateServiceRequest,
+            Self::UnusedChannel { .. } => WarningKind::UnusedChannel,
+            Self::WindowExceeded { .. } => WarningKind::WindowExceeded,
+            Self::PacketExceeded { .. } => WarningKind::PacketExceeded,
+            Self::WindowOverAdjust { .. } => WarningKind::WindowOverAdjust,
+        }
+    }
+}
+
+/// Per-connection warning counters.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Warnings(Arc<Mutex<HashMap<WarningKind, u64>>>);
+
+impl Warnings {
+    /// Count and log `warning`, suppressing logs after [`LOG_LIMIT`] of one kind.
+    pub(crate) fn record(&self, warning: &ProtocolWarning) {
+        let count = {
+            let mut counts = self.0.lock().unwrap();
+            let count = counts.entry(warning.kind()).or_default();
+            *count += 1;
+            *count
+        };
+        if count < LOG_LIMIT {
+            warn!("protocol warning: {}", warning);
+        } else if count == LOG_LIMIT {
+            warn!(
+                "protocol warning: {} (suppress further {:?})",
+                warning,
+                warning.kind()
+            );
+        }
+    }
+
+    pub(crate) fn counts(&self) -> HashMap<WarningKind, u64> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Counts of all kinds in one line, e.g. `DuplicateEof=20 UnknownChannel=1`.
+    /// `None` if there were none.
+    pub(crate) fn summary(&self) -> Option<String> {
+        let mut counts = self
+            .counts()
+            .into_iter()
+            .map(|(kind, count)| format!("{:?}={}", kind, count))
+            .collect::<Vec<_>>();
+        if counts.is_empty() {
+            return None;
+        }
+        counts.sort();
+        Some(counts.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let warnings = Warnings::default();
+        for channel in 0..20 {
+            warnings.record(&ProtocolWarning::DuplicateEof { channel });
+        }
+        warnings.record(&ProtocolWarning::DataAfterEof {
+            channel: 0,
+            bytes: 3,
+        });
+
+        let counts = warnings.counts();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&WarningKind::DuplicateEof], 20);
+        assert_eq!(counts[&WarningKind::DataAfterEof], 1);
+        assert_eq!(
+            warnings.summary().as_deref(),
+            Some("DataAfterEof=1 DuplicateEof=20")
+        );
+        assert_eq!(Warnings::default().summary(), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let warning = ProtocolWarning::UnknownChannel {
+            channel: 3,
+            message: "data",
+        };
+        assert_eq!(warning.kind(), WarningKind::UnknownChannel);
+        assert_eq!(warning.to_string(), "data for unknown channel 3");
+    }
+}
@@ -0,0 +1,187 @@
+//! Flow control windows per channel.
+
+/// Default initial window advertised in channel open confirmations.
+pub(crate) const DEFAULT_WINDOW_SIZE: u32 = 2 * 1024 * 1024;
+
+/// Maximum data packet advertised in channel open confirmations.
+pub(crate) const MAXIMUM_DATA_SIZE: u32 = 32 * 1024;
+
+/// Local window of one channel.
+///
+/// `granted` counts the initial window plus every adjust we sent, `received`
+/// the bytes accepted and `consumed` the bytes handed to the handler. Window
+/// is only replenished for consumed bytes, so buffered and still grantable
+/// bytes never exceed the initial window.
+#[derive(Debug)]
+pub(crate) struct LocalWindow {
+    initial: u32,
+    granted: u64,
+    received: u64,
+    consumed: u64,
+}
+
+impl LocalWindow {
+    pub(crate) fn new(initial: u32) -> Self {
+        Self {
+            initial,
+            granted: initial as u64,
+            received: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Bytes client may still send.
+    pub(crate) fn remaining(&self) -> u32 {
+        (self.granted - self.received) as u32
+    }
+
+    /// Accept up to `len` bytes, returning the accepted length.
+    pub(crate) fn receive(&mut self, len: usize) -> usize {
+        let accepted = len.min(self.remaining() as usize);
+        self.received += accepted as u64;
+        self.check();
+        accepted
+    }
+
+    /// Handler took `len` of the received bytes.
+    pub(crate) fn consume(&mut self, len: usize) {
+        self.consumed += len as u64;
+        self.check();
+    }
+
+    /// Window to add once at least half of the initial window was consumed.
+    pub(crate) fn replenish(&mut self) -> Option<u32> {
+        let grantable = self.consumed + self.initial as u64 - self.granted;
+        if grantable < (self.initial as u64).div_ceil(2) {
+            return None;
+        }
+        self.granted += grantable;
+        self.check();
+        Some(grantable as u32)
+    }
+
+    fn check(&self) {
+        debug_assert!(self.consumed <= self.received);
+        debug_assert!(self.received <= self.granted);
+        debug_assert!(self.granted - self.consumed <= self.initial as u64);
+    }
+}
+
+/// Window the client advertised for data we send.
+#[derive(Debug)]
+pub(crate) struct RemoteWindow {
+    remaining: u32,
+    maximum_packet: usize,
+}
+
+impl RemoteWindow {
+    pub(crate) fn new(initial: u32, maximum_packet: u32) -> Self {
+        Self {
+            remaining: initial,
+            maximum_packet: (maximum_packet as usize).max(1),
+        }
+    }
+
+    /// Bytes of `size` which may be sent in one data message now.
+    pub(crate) fn sendable(&self, size: usize) -> usize {
+        size.min(self.remaining as usize).min(self.maximum_packet)
+    }
+
+    /// Sent `len` bytes. At most [`Self::sendable`].
+    pub(crate) fn consume(&mut self, len: usize) {
+        debug_assert!(len <= self.remaining as usize);
+        self.remaining -= len as u32;
+    }
+
+    /// Client sent window adjust. Total window is capped to 2^32 - 1,
+    /// returning `false` if the adjust went beyond.
+    pub(crate) fn adjust(&mut self, bytes_to_add: u32) -> bool {
+        match self.remaining.checked_add(bytes_to_add) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                true
+            }
+            None => {
+                self.remaining = u32::MAX;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let mut window = LocalWindow::new(100);
+        assert_eq!(window.receive(60), 60);
+        assert_eq!(window.receive(60), 40);
+        assert_eq!(window.remaining(), 0);
+        window.consume(40);
+        assert_eq!(window.replenish(), None);
+        window.consume(60);
+        assert_eq!(window.replenish(), Some(100));
+        assert_eq!(window.remaining(), 100);
+        assert_eq!(window.replenish(), None);
+    }
+
+    #[test]
+    fn test_remote_window() {
+        let mut window = RemoteWindow::new(100, 30);
+        assert_eq!(window.sendable(10), 10);
+        assert_eq!(window.sendable(50), 30);
+        window.consume(30);
+        window.consume(30);
+        window.consume(30);
+        assert_eq!(window.sendable(50), 10);
+        window.consume(10);
+        assert_eq!(window.sendable(50), 0);
+        assert!(window.adjust(u32::MAX));
+        assert_eq!(window.sendable(50), 30);
+        assert!(!window.adjust(1));
+        window.consume(30);
+        assert_eq!(window.remaining, u32::MAX - 30);
+
+        assert_eq!(RemoteWindow::new(10, 0).sendable(5), 1);
+    }
+
+    /// xorshift, deterministic across runs.
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_window_random() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..200 {
+            let initial = (next(&mut seed) % 4096 + 1) as u32;
+            let mut window = LocalWindow::new(initial);
+            let mut buffered = 0;
+            let mut accepted = 0;
+            let mut granted = initial as u64;
+            for _ in 0..500 {
+                match next(&mut seed) % 3 {
+                    0 => {
+                        let n = window.receive((next(&mut seed) % 2048) as usize);
+                        buffered += n;
+                        accepted += n as u64;
+                    }
+                    1 => {
+                        let n = (next(&mut seed) as usize) % (buffered + 1);
+                        window.consume(n);
+                        buffered -= n;
+                    }
+                    _ => granted += window.replenish().unwrap_or(0) as u64,
+                }
+                assert!(buffered <= initial as usize);
+                assert!(accepted <= granted);
+                assert_eq!(accepted + window.remaining() as u64, granted);
+            }
+        }
+    }
+}
@@ -7,8 +7,40 @@ use thiserror::Error;
 use crate::msg::disconnect::ReasonCode;
 use crate::pack::UnpackError;
 
+/// Broad category of [`SshError`], e.g. to pick a log level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// Transport I/O failed.
+    Io,
+
+    /// Nothing received in time.
+    Timeout,
+
+    /// Peer violated the protocol, or packets failed to decode.
+    Protocol,
+
+    /// Key exchange or algorithm negotiation failed.
+    Kex,
+
+    /// Authentication or service request rejected.
+    Auth,
+
+    /// Channel already closed or refused.
+    Channel,
+
+    /// Server configuration is invalid.
+    Config,
+
+    /// Handler returned an error.
+    Handler,
+
+    Other,
+}
+
 /// SSH errors.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum SshError {
     #[error(transparent)]
     IoError(#[from] io::Error),
@@ -22,14 +54,23 @@ pub enum SshError {
     #[error("too long version identifier")]
     VersionTooLong,
 
+    #[error("unsupported protocol version: {0:?}")]
+    UnsupportedVersion(String),
+
+    #[error("too many lines before version identifier")]
+    PreBannerTooLong,
+
     #[error(transparent)]
     UnpackError(#[from] UnpackError),
 
     #[error("too large packet length {0}")]
     TooLargePacket(usize),
 
-    #[error("not matched {0:?}")]
-    NegotiateNotMatched(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+
+    #[error(transparent)]
+    Negotiate(#[from] crate::NegotiateError),
 
     #[error("unknown algorithm {0}")]
     UnknownAlgorithm(String),
@@ -49,9 +90,15 @@ pub enum SshError {
     #[error("unexpected eof")]
     KexUnexpectedEof,
 
+    #[error("no group exchange group within {0}..={1} bits")]
+    KexGroupNotFound(u32, u32),
+
     #[error("kex error: {0}")]
     KexError(#[source] Box<dyn Error + Send + Sync + 'static>),
 
+    #[error("escalated protocol warning: {0}")]
+    ProtocolWarning(crate::ProtocolWarning),
+
     #[error("unexpected error {0}")]
     UnexpectedMsg(String),
 
@@ -70,12 +117,60 @@ pub enum SshError {
     #[error("unsupported key file format")]
     UnsupportedKeyFileFormat,
 
+    #[error("encrypted key file is not supported (cipher {0})")]
+    EncryptedKeyFile(String),
+
     #[error("timeout")]
     Timeout,
 
+    #[error("handshake timeout")]
+    HandshakeTimeout,
+
+    #[error("auth timeout")]
+    AuthTimeout,
+
+    #[error("algorithm {0} already exists")]
+    AlgorithmExists(String),
+
+    #[error("algorithm {0} invalid: {1}")]
+    InvalidAlgorithm(String, String),
+
+    #[error("unknown algorithms {}", .0.join(","))]
+    UnknownAlgorithms(Vec<String>),
+
+    #[error("no host key for any configured host key algorithm")]
+    NoHostKeyAlgorithm,
+
+    #[error("invalid server version: {0:?}")]
+    InvalidServerVersion(String),
+
     #[error("algorithm mismatch {0} != {1}")]
     AlgorithmMismatch(String, String),
 
+    #[error("channel {0} closed")]
+    ChannelClosed(u32),
+
+    #[error("connection closing")]
+    ConnectionClosing,
+
+    #[error("exit status of channel {0} already sent")]
+    ExitAlreadySent(u32),
+
+    #[error("eof of channel {0} already sent")]
+    EofAlreadySent(u32),
+
+    #[error("memory limit exceeded ({0} bytes in use)")]
+    MemoryLimitExceeded(usize),
+
+    #[error("connection stalled for {0:?}")]
+    Stalled(std::time::Duration),
+
+    #[error("channel open failed: {0}")]
+    ChannelOpenFailed(String),
+
+    #[error("too many authentication failures ({0})")]
+    TooManyAuthFailures(u32),
+
     #[error(transparent)]
     Any(Box<dyn Error + Send + Sync + 'static>),
 }
@@ -87,28 +182,113 @@ impl SshError {
             Self::InvalidVersion(..) => None,
             Self::VersionUnexpectedEof(..) => None,
             Self::VersionTooLong => None,
+            Self::UnsupportedVersion(..) => None,
+            Self::PreBannerTooLong => None,
             Self::UnpackError(..) => Some(ReasonCode::ProtocolError),
             Self::TooLargePacket(..) => Some(ReasonCode::ProtocolError),
-            Self::NegotiateNotMatched(..) => Some(ReasonCode::KeyExchangeFailed),
+            Self::Protocol(..) => Some(ReasonCode::ProtocolError),
+            Self::Negotiate(..) => Some(ReasonCode::KeyExchangeFailed),
             Self::UnknownAlgorithm(..) => Some(ReasonCode::ProtocolError),
             Self::CompressionError(..) => Some(ReasonCode::CompressionError),
             Self::CipherError(..) => Some(ReasonCode::ProtocolError),
             Self::MacError(..) => Some(ReasonCode::MacError),
             Self::KexUnexpectedMsg(..) => Some(ReasonCode::KeyExchangeFailed),
             Self::KexUnexpectedEof => Some(ReasonCode::KeyExchangeFailed),
+            Self::KexGroupNotFound(..) => Some(ReasonCode::KeyExchangeFailed),
             Self::KexError(..) => Some(ReasonCode::KeyExchangeFailed),
+            Self::ProtocolWarning(..) => Some(ReasonCode::ProtocolError),
             Self::UnexpectedMsg(..) => Some(ReasonCode::ProtocolError),
             Self::NoPacketReceived => Some(ReasonCode::ProtocolError),
             Self::ChannelError(..) => Some(ReasonCode::ServiceNotAvailable),
             Self::UnacceptableService(..) => Some(ReasonCode::ServiceNotAvailable),
             Self::HandlerError(..) => Some(ReasonCode::ByApplication),
             Self::UnsupportedKeyFileFormat => None,
+            Self::EncryptedKeyFile(..) => None,
             Self::Timeout => Some(ReasonCode::ConnectionLost),
+            Self::HandshakeTimeout => Some(ReasonCode::ConnectionLost),
+            Self::AuthTimeout => Some(ReasonCode::ConnectionLost),
+            Self::AlgorithmExists(..) => None,
+            Self::InvalidAlgorithm(..) => None,
+            Self::UnknownAlgorithms(..) => None,
+            Self::NoHostKeyAlgorithm => None,
+            Self::InvalidServerVersion(..) => None,
             Self::AlgorithmMismatch(..) => Some(ReasonCode::ProtocolError),
+            Self::ChannelClosed(..) => None,
+            Self::ConnectionClosing => None,
+            Self::ExitAlreadySent(..) => None,
+            Self::EofAlreadySent(..) => None,
+            Self::MemoryLimitExceeded(..) => Some(ReasonCode::ByApplication),
+            Self::Stalled(..) => Some(ReasonCode::ConnectionLost),
+            Self::ChannelOpenFailed(..) => None,
+            Self::TooManyAuthFailures(..) => Some(ReasonCode::NoMoreAuthMethodsAvailable),
             Self::Any(..) => None,
         }
     }
 
+    /// Category of this error. New variants fall into existing kinds.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{ErrorKind, SshError};
+    ///
+    /// fn report(e: &SshError) {
+    ///     match e.kind() {
+    ///         ErrorKind::Io | ErrorKind::Timeout => log::debug!("{}", e),
+    ///         _ => log::warn!("{}", e),
+    ///     }
+    /// }
+    /// # report(&SshError::Timeout);
+    /// ```
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::IoError(..) => ErrorKind::Io,
+            Self::InvalidVersion(..) => ErrorKind::Protocol,
+            Self::VersionUnexpectedEof(..) => ErrorKind::Protocol,
+            Self::VersionTooLong => ErrorKind::Protocol,
+            Self::UnsupportedVersion(..) => ErrorKind::Protocol,
+            Self::PreBannerTooLong => ErrorKind::Protocol,
+            Self::UnpackError(..) => ErrorKind::Protocol,
+            Self::TooLargePacket(..) => ErrorKind::Protocol,
+            Self::Protocol(..) => ErrorKind::Protocol,
+            Self::Negotiate(..) => ErrorKind::Kex,
+            Self::UnknownAlgorithm(..) => ErrorKind::Protocol,
+            Self::CompressionError(..) => ErrorKind::Protocol,
+            Self::CipherError(..) => ErrorKind::Protocol,
+            Self::MacError(..) => ErrorKind::Protocol,
+            Self::KexUnexpectedMsg(..) => ErrorKind::Kex,
+            Self::KexUnexpectedEof => ErrorKind::Kex,
+            Self::KexGroupNotFound(..) => ErrorKind::Kex,
+            Self::KexError(..) => ErrorKind::Kex,
+            Self::ProtocolWarning(..) => ErrorKind::Protocol,
+            Self::UnexpectedMsg(..) => ErrorKind::Protocol,
+            Self::NoPacketReceived => ErrorKind::Protocol,
+            Self::ChannelError(..) => ErrorKind::Channel,
+            Self::UnacceptableService(..) => ErrorKind::Auth,
+            Self::HandlerError(..) => ErrorKind::Handler,
+            Self::UnsupportedKeyFileFormat => ErrorKind::Config,
+            Self::EncryptedKeyFile(..) => ErrorKind::Config,
+            Self::Timeout => ErrorKind::Timeout,
+            Self::HandshakeTimeout => ErrorKind::Timeout,
+            Self::AuthTimeout => ErrorKind::Timeout,
+            Self::AlgorithmExists(..) => ErrorKind::Config,
+            Self::InvalidAlgorithm(..) => ErrorKind::Config,
+            Self::UnknownAlgorithms(..) => ErrorKind::Config,
+            Self::NoHostKeyAlgorithm => ErrorKind::Config,
+            Self::InvalidServerVersion(..) => ErrorKind::Config,
+            Self::AlgorithmMismatch(..) => ErrorKind::Protocol,
+            Self::ChannelClosed(..) => ErrorKind::Channel,
+            Self::ConnectionClosing => ErrorKind::Channel,
+            Self::ExitAlreadySent(..) => ErrorKind::Channel,
+            Self::EofAlreadySent(..) => ErrorKind::Channel,
+            Self::MemoryLimitExceeded(..) => ErrorKind::Other,
+            Self::Stalled(..) => ErrorKind::Timeout,
+            Self::ChannelOpenFailed(..) => ErrorKind::Channel,
+            Self::TooManyAuthFailures(..) => ErrorKind::Auth,
+            Self::Any(..) => ErrorKind::Other,
+        }
+    }
+
     pub(crate) fn cipher_error<E>(err: E) -> Self
     where
         E: Error + Send + Sync + 'static,
@@ -137,3 +317,24 @@ impl SshError {
         Self::Any(Box::new(err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        let e = SshError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert_eq!(
+            SshError::Stalled(Default::default()).kind(),
+            ErrorKind::Timeout
+        );
+        assert_eq!(SshError::TooManyAuthFailures(6).kind(), ErrorKind::Auth);
+
+        // Cause stays reachable through the kind.
+        let e = SshError::kex_error(io::Error::other("bad group"));
+        assert_eq!(e.kind(), ErrorKind::Kex);
+        assert_eq!(e.source().unwrap().to_string(), "bad group");
+    }
+}
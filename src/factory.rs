@@ -0,0 +1,159 @@
+//! Per-connection [`Handlers`] construction.
+
+use std::fmt;
+use std::sync::Arc;
+
+use getset::Getters;
+
+use crate::handlers::{HandlerError, Handlers};
+
+/// Information about an established connection.
+#[derive(Debug, Clone, Getters)]
+pub struct ConnectionInfo {
+    /// Client version string.
+    #[get = "pub"]
+    client_version: String,
+
+    /// Server version string.
+    #[get = "pub"]
+    server_version: String,
+
+    /// Client quirks applied to this connection.
+    #[get = "pub"]
+    quirks: Vec<String>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(client_version: String, server_version: String, quirks: Vec<String>) -> Self {
+        Self {
+            client_version,
+            server_version,
+            quirks,
+        }
+    }
+}
+
+/// Create [`Handlers`] for each connection.
+///
+/// Implemented for any `Fn(&ConnectionInfo) -> Handlers<E, Pty>`.
+pub trait HandlerFactory<E, Pty = ()>
+where
+    E: Into<HandlerError> + Send + 'static,
+{
+    fn create(&self, info: &ConnectionInfo) -> Handlers<E, Pty>;
+}
+
+impl<F, E, Pty> HandlerFactory<E, Pty> for F
+where
+    F: Fn(&ConnectionInfo) -> Handlers<E, Pty>,
+    E: Into<HandlerError> + Send + 'static,
+{
+    fn create(&self, info: &ConnectionInfo) -> Handlers<E, Pty> {
+        self(info)
+    }
+}
+
+/// [`HandlerFactory`] sharing one application state between all connections.
+///
+/// # Example
+///
+/// ```
+/// use std::collections::HashSet;
+/// use std::sync::Arc;
+/// use futures::FutureExt as _;
+/// use ssssh::{Handlers, SharedStateFactory};
+///
+/// let users = Arc::new(vec!["bob".to_string()].into_iter().collect::<HashSet<_>>());
+/// let factory = SharedStateFactory::new(users, |users, _info| {
+///     let mut handlers = Handlers::<anyhow::Error>::new();
+///     handlers.on_auth_none(move |username| {
+///         let ok = users.contains(&username);
+///         async move { Ok(ok) }.boxed()
+///     });
+///     handlers
+/// });
+/// ```
+pub struct SharedStateFactory<S, F> {
+    state: Arc<S>,
+    f: F,
+}
+
+impl<S, F> SharedStateFactory<S, F> {
+    pub fn new<E, Pty>(state: Arc<S>, f: F) -> Self
+    where
+        F: Fn(Arc<S>, &ConnectionInfo) -> Handlers<E, Pty>,
+        E: Into<HandlerError> + Send + 'static,
+    {
+        Self { state, f }
+    }
+
+    /// Shared state.
+    pub fn state(&self) -> &Arc<S> {
+        &self.state
+    }
+}
+
+impl<S, F> fmt::Debug for SharedStateFactory<S, F>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedStateFactory")
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl<S, F, E, Pty> HandlerFactory<E, Pty> for SharedStateFactory<S, F>
+where
+    F: Fn(Arc<S>, &ConnectionInfo) -> Handlers<E, Pty>,
+    E: Into<HandlerError> + Send + 'static,
+{
+    fn create(&self, info: &ConnectionInfo) -> Handlers<E, Pty> {
+        (self.f)(self.state.clone(), info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_shared_state() {
+        let state = Arc::new(Mutex::new(vec![]));
+        let factory = SharedStateFactory::new(state.clone(), |state: Arc<Mutex<Vec<_>>>, info| {
+            let ptr = Arc::as_ptr(&state) as usize;
+            state
+                .lock()
+                .unwrap()
+                .push((ptr, info.client_version().clone()));
+            Handlers::<anyhow::Error>::new()
+        });
+
+        factory.create(&ConnectionInfo::new(
+            "SSH-2.0-a".into(),
+            "SSH-2.0-s".into(),
+            vec![],
+        ));
+        factory.create(&ConnectionInfo::new(
+            "SSH-2.0-b".into(),
+            "SSH-2.0-s".into(),
+            vec![],
+        ));
+
+        let seen = state.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].0, Arc::as_ptr(&state) as usize);
+        assert_eq!(seen[0].0, seen[1].0);
+        assert_eq!(seen[0].1, "SSH-2.0-a");
+        assert_eq!(seen[1].1, "SSH-2.0-b");
+        assert!(Arc::ptr_eq(factory.state(), &state));
+    }
+
+    #[test]
+    fn test_closure() {
+        let factory = |_: &ConnectionInfo| Handlers::<anyhow::Error>::new();
+        factory.create(&ConnectionInfo::new("".into(), "".into(), vec![]));
+    }
+}
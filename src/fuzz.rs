@@ -0,0 +1,89 @@
+//! Entry points of the fuzz targets under `fuzz/`.
+//!
+//! Each decodes arbitrary input, and must return instead of panicking or
+//! looping forever.
+use bytes::Bytes;
+use futures::stream::StreamExt as _;
+
+use crate::msg::{GexMsg, Msg, UserauthInfoMsg, UserauthPkMsg};
+use crate::pack::Unpack as _;
+use crate::stream::bpp::BppStream;
+use crate::SshError;
+
+/// Decode `data` as packets received before any key exchange.
+///
+/// Fails on the first invalid packet, or on a packet cut short by the end.
+pub fn bpp(data: &[u8]) -> Result<(), SshError> {
+    let mut io = BppStream::new(data);
+    futures::executor::block_on(async {
+        while let Some(payload) = io.next().await {
+            payload?;
+        }
+        Ok(())
+    })
+}
+
+/// Decode `data` as a message payload, in every context.
+pub fn msg(data: &[u8]) {
+    let data = Bytes::copy_from_slice(data);
+    drop(Msg::unpack(&mut data.clone()));
+    drop(GexMsg::unpack(&mut data.clone()));
+    drop(UserauthPkMsg::unpack(&mut data.clone()));
+    drop(UserauthInfoMsg::unpack(&mut data.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inputs known to have crashed or hung a decoder.
+    const REGRESSIONS: &[&[u8]] = &[
+        // Closed within a packet, spun forever.
+        b"\0\0\0\x0c\x0a\x05",
+        // Largest packet length.
+        b"\xff\xff\xff\xff\x0a\x05",
+        // Padding longer than the packet.
+        b"\0\0\0\x0c\xff\x05\0\0\0\0\0\0\0\0\0\0",
+        // Padding as long as the packet.
+        b"\0\0\0\x0c\x0c\x05\0\0\0\0\0\0\0\0\0\0",
+        // Packet shorter than its padding length byte needs.
+        b"\0\0\0\x01\x04",
+    ];
+
+    #[test]
+    fn test_regressions() {
+        for input in REGRESSIONS {
+            assert!(bpp(input).is_err(), "{:?}", input);
+            msg(input);
+        }
+    }
+
+    /// Deterministic xorshift, so failures reproduce.
+    fn random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// Smoke run of both targets, random bytes and mutated valid packets.
+    #[test]
+    fn test_smoke() {
+        let mut state = 0x5353_5353_5353_5353;
+        for _ in 0..5000 {
+            let len = (random(&mut state) % 64) as usize;
+            let mut input = (0..len)
+                .map(|_| random(&mut state) as u8)
+                .collect::<Vec<_>>();
+            bpp(&input).ok();
+            msg(&input);
+
+            let mut packet = b"\0\0\0\x0c\x0a\x5e\0\0\0\0\0\0\0\0\0\0".to_vec();
+            let at = (random(&mut state) as usize) % packet.len();
+            packet[at] = random(&mut state) as u8;
+            packet.append(&mut input);
+            bpp(&packet).ok();
+            msg(&packet[5..]);
+        }
+    }
+}
@@ -4,46 +4,442 @@ use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::ffi::OsString;
 use std::fmt;
+use std::sync::{Arc, Mutex};
 
-use futures::future::BoxFuture;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::future::{BoxFuture, FutureExt as _, TryFutureExt as _};
+use getset::Getters;
 
-use crate::{PublicKey, SshInput, SshOutput};
+use crate::connection::{ChannelHandle, ReplySlot};
+use crate::observer::Observer;
+use crate::{
+    ChannelResponder, DetachError, DetachedChannel, DisconnectReason, GlobalHandle,
+    HandlerErrorFault, Languages, NegotiatedAlgorithms, PublicKey, Signal, SshError, SshInput,
+    SshOutput,
+};
 
 pub(crate) type HandlerError = Box<dyn StdError + Send + Sync + 'static>;
 
+/// Default cap of formatted handler error message in bytes.
+pub(crate) const DEFAULT_ERROR_LIMIT: usize = 1024;
+
+/// Handler error with message formatted ahead of time.
+///
+/// Display never calls into handler code again.
+pub(crate) struct SanitizedError {
+    message: String,
+    source: Option<HandlerError>,
+}
+
+impl fmt::Debug for SanitizedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SanitizedError")
+            .field(&self.message)
+            .finish()
+    }
+}
+
+impl fmt::Display for SanitizedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for SanitizedError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as _)
+    }
+}
+
+/// String writer which stops at `limit` bytes (on a UTF-8 boundary).
+struct Capped {
+    buf: String,
+    limit: usize,
+    truncated: bool,
+}
+
+impl fmt::Write for Capped {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let rest = self.limit - self.buf.len();
+        if s.len() <= rest {
+            self.buf.push_str(s);
+            return Ok(());
+        }
+        let mut end = rest;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf.push_str(&s[..end]);
+        self.truncated = true;
+        Err(fmt::Error)
+    }
+}
+
+/// Convert and format handler error defensively.
+///
+/// Panics while converting or formatting are caught,
+/// and the message is truncated to `limit` bytes.
+/// Either is reported to `observer`.
+pub(crate) fn sanitize<E>(err: E, limit: usize, observer: &Observer) -> HandlerError
+where
+    E: Into<HandlerError>,
+{
+    use std::fmt::Write as _;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let err = match catch_unwind(AssertUnwindSafe(|| err.into())) {
+        Ok(err) => err,
+        Err(..) => {
+            crate::trace::warn!("handler error conversion panicked");
+            observer.handler_error_fault(HandlerErrorFault::ConversionPanicked);
+            return Box::new(SanitizedError {
+                message: "<handler error conversion panicked>".into(),
+                source: None,
+            });
+        }
+    };
+
+    let mut capped = Capped {
+        buf: String::new(),
+        limit,
+        truncated: false,
+    };
+    let formatted = catch_unwind(AssertUnwindSafe(|| {
+        write!(&mut capped, "{}", err).ok();
+    }));
+    let message = match formatted {
+        Ok(()) if capped.truncated => {
+            crate::trace::warn!("handler error message truncated to {} bytes", limit);
+            observer.handler_error_fault(HandlerErrorFault::Truncated);
+            capped.buf + "..."
+        }
+        Ok(()) => capped.buf,
+        Err(..) => {
+            crate::trace::warn!("handler error formatting panicked");
+            observer.handler_error_fault(HandlerErrorFault::FormattingPanicked);
+            "<handler error formatting panicked>".into()
+        }
+    };
+    Box::new(SanitizedError {
+        message,
+        source: Some(err),
+    })
+}
+
+/// Terminal size from a `window-change` request.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct WindowSize {
+    /// Columns.
+    #[get = "pub"]
+    width: u32,
+
+    /// Rows.
+    #[get = "pub"]
+    height: u32,
+
+    #[get = "pub"]
+    width_px: u32,
+
+    #[get = "pub"]
+    height_px: u32,
+}
+
+impl WindowSize {
+    pub(crate) fn new(width: u32, height: u32, width_px: u32, height_px: u32) -> Self {
+        Self {
+            width,
+            height,
+            width_px,
+            height_px,
+        }
+    }
+}
+
+/// Parameters of an `x11-req` channel request.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct X11Request {
+    /// Forward only a single connection.
+    #[get = "pub"]
+    single_connection: bool,
+
+    /// e.g. `MIT-MAGIC-COOKIE-1`.
+    #[get = "pub"]
+    auth_protocol: String,
+
+    /// Hex encoded, as sent by the client.
+    #[get = "pub"]
+    auth_cookie: Bytes,
+
+    #[get = "pub"]
+    screen_number: u32,
+}
+
+impl X11Request {
+    pub(crate) fn new(
+        single_connection: bool,
+        auth_protocol: String,
+        auth_cookie: Bytes,
+        screen_number: u32,
+    ) -> Self {
+        Self {
+            single_connection,
+            auth_protocol,
+            auth_cookie,
+            screen_number,
+        }
+    }
+}
+
 /// Context for SSH Session.
 pub struct SessionContext<Pty = ()> {
     stdio: Option<(SshInput, SshOutput, SshOutput)>,
-    env: HashMap<String, String>,
+    env: HashMap<String, OsString>,
     pty: Option<Pty>,
+    window_changes: Option<mpsc::UnboundedReceiver<WindowSize>>,
+    handle: ChannelHandle,
+    languages: Languages,
+    reply: ReplySlot,
 }
 
 impl<Pty> SessionContext<Pty> {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         stdin: SshInput,
         stdout: SshOutput,
         stderr: SshOutput,
-        env: HashMap<String, String>,
+        env: HashMap<String, OsString>,
         pty: Option<Pty>,
+        window_changes: Option<mpsc::UnboundedReceiver<WindowSize>>,
+        handle: ChannelHandle,
+        languages: Languages,
     ) -> Self {
+        let reply = Arc::new(Mutex::new(Some(handle.responder())));
         Self {
             stdio: Some((stdin, stdout, stderr)),
             env,
             pty,
+            window_changes,
+            handle,
+            languages,
+            reply,
         }
     }
 
+    pub(crate) fn reply_slot(&self) -> ReplySlot {
+        self.reply.clone()
+    }
+
+    /// Answer the shell, exec or subsystem request later with the returned
+    /// responder, instead of success as soon as the handler was called.
+    ///
+    /// Call it before the handler returns its future, later the request is
+    /// already answered and `None` is returned. Other messages are processed
+    /// meanwhile. Replies to requests sent after this one on the same channel
+    /// may overtake it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{Handlers, SessionContext};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_exec(|mut ctx: SessionContext, command| {
+    ///     let responder = ctx.defer_reply().unwrap();
+    ///     async move {
+    ///         match std::process::Command::new(command).spawn() {
+    ///             Ok(mut child) => {
+    ///                 responder.success()?;
+    ///                 Ok(child.wait()?.code().unwrap_or(1) as u32)
+    ///             }
+    ///             Err(_) => {
+    ///                 responder.failure()?;
+    ///                 Ok(1)
+    ///             }
+    ///         }
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn defer_reply(&mut self) -> Option<ChannelResponder> {
+        self.reply.lock().unwrap().take()
+    }
+
     pub fn take_stdio(&mut self) -> Option<(SshInput, SshOutput, SshOutput)> {
         self.stdio.take()
     }
 
-    pub fn env(&self) -> &HashMap<String, String> {
+    /// Exchange ChannelData payloads directly instead of through stdio.
+    ///
+    /// Data received so far is handed over. Fails if stdio was taken or
+    /// channel already detached.
+    pub fn detach(&mut self) -> Result<DetachedChannel, DetachError> {
+        let channel = self.handle.channel();
+        match self.stdio.take() {
+            Some(stdio) => self.handle.detach(stdio),
+            None if self.handle.detached() => Err(DetachError::AlreadyDetached(channel)),
+            None => Err(DetachError::StdioTaken(channel)),
+        }
+    }
+
+    /// Variables the client set and the env handler accepted, values as sent.
+    pub fn env(&self) -> &HashMap<String, OsString> {
         &self.env
     }
 
     pub fn take_pty(&mut self) -> Option<Pty> {
         self.pty.take()
     }
+
+    /// Terminal resizes the client reports from now on, to apply to the pty.
+    ///
+    /// Includes resizes received since the channel opened. Ends when the
+    /// channel closes.
+    pub fn take_window_changes(&mut self) -> Option<mpsc::UnboundedReceiver<WindowSize>> {
+        self.window_changes.take()
+    }
+
+    /// User name the connection authenticated as.
+    pub fn username(&self) -> String {
+        self.handle.username()
+    }
+
+    /// Version line the client sent, e.g. `SSH-2.0-OpenSSH_8.9`.
+    pub fn client_version(&self) -> String {
+        self.handle.client_version()
+    }
+
+    /// Algorithms of the latest key exchange.
+    pub fn algorithms(&self) -> Option<NegotiatedAlgorithms> {
+        self.handle.algorithms()
+    }
+
+    /// Language tags the client sent in kexinit.
+    pub fn client_languages(&self) -> &Languages {
+        &self.languages
+    }
+
+    /// Set output scheduling weight of this channel. (default: 1)
+    ///
+    /// A channel with weight `n` may send `n` times as much data per round
+    /// as a channel with weight 1 when several channels have output pending.
+    pub fn set_priority(&self, weight: u32) {
+        self.handle.set_priority(weight)
+    }
+
+    /// Send arbitrary channel request to client.
+    ///
+    /// If `want_reply`, resolves with client's reply (success or failure).
+    /// Otherwise resolves with `None` as soon as the request is queued.
+    pub async fn send_request(
+        &self,
+        name: &str,
+        want_reply: bool,
+        payload: Bytes,
+    ) -> Result<Option<bool>, SshError> {
+        self.handle.send_request(name, want_reply, payload).await
+    }
+
+    /// Report exit `status` now, before the handler returns.
+    ///
+    /// Sent after output queued so far. Once stdio was taken, waits like
+    /// [`send_eof`](Self::send_eof) until stdout and stderr were dropped, so
+    /// drop both first. The status returned by the handler is not sent
+    /// afterwards. Fails if exit was already reported for this channel.
+    pub async fn send_exit_status(&self, status: u32) -> Result<(), SshError> {
+        let drain = self.stdio.is_none();
+        self.handle.send_exit_status(status, drain).await
+    }
+
+    /// Report termination by `signal` instead of an exit status.
+    ///
+    /// Ordered and waits like [`send_exit_status`](Self::send_exit_status).
+    /// The status returned by the handler is not sent afterwards.
+    /// Fails if exit was already reported for this channel.
+    pub async fn send_exit_signal(
+        &self,
+        signal: Signal,
+        core_dumped: bool,
+        message: &str,
+    ) -> Result<(), SshError> {
+        let drain = self.stdio.is_none();
+        self.handle
+            .send_exit_signal(signal, core_dumped, message, drain)
+            .await
+    }
+
+    /// Send `data` to the client as stderr extended data, bypassing the stderr pipe.
+    ///
+    /// Sent before the channel's EOF if called before the handler returns.
+    /// Not ordered with data written to stdio. Waits while the connection's
+    /// send queue is full, see [`ServerBuilder::send_queue_size`](crate::ServerBuilder::send_queue_size).
+    /// Fails after [`send_eof`](Self::send_eof).
+    pub async fn send_stderr(&self, data: Bytes) -> Result<(), SshError> {
+        self.handle.send_extended_data(data).await
+    }
+
+    /// Send SSH_MSG_DISCONNECT and stop the connection.
+    ///
+    /// Channels whose handler still runs get `exit-signal` KILL and close
+    /// first. [`Connection::run`](crate::Connection::run) returns `Ok` afterwards.
+    pub fn disconnect(&self, reason: DisconnectReason, description: &str) -> Result<(), SshError> {
+        self.handle.disconnect(reason, description)
+    }
+
+    /// Tell the client no more output follows, before reporting exit status.
+    ///
+    /// Waits until stdout and stderr were dropped and everything written to
+    /// them is queued, so drop both first. EOF is not sent again when the
+    /// handler returns. Fails if EOF was already sent.
+    pub async fn send_eof(&self) -> Result<(), SshError> {
+        self.handle.send_eof().await
+    }
+
+    /// Close the channel now, waiting for the client to close its side.
+    ///
+    /// Sends EOF and close. Output written afterwards is dropped, and no
+    /// exit status is reported unless sent before. Fails if the connection
+    /// ends first.
+    pub async fn close(&self) -> Result<(), SshError> {
+        self.handle.close().await
+    }
+
+    /// Send `eow@openssh.com` (end of write) request to client.
+    pub async fn send_eow(&self) -> Result<(), SshError> {
+        self.send_request("eow@openssh.com", false, Bytes::new())
+            .await?;
+        Ok(())
+    }
+}
+
+/// None and publickey authentication result.
+///
+/// Handlers may return `bool` instead, `true` being [`AuthResult::Ok`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+    /// Ok
+    Ok,
+
+    /// Succeeded, but one of `remaining` methods must succeed as well.
+    Partial(Vec<AuthMethod>),
+
+    /// Failed to authenticate
+    Failure,
+
+    /// Method not applicable to this user, e.g. no keys on file.
+    ///
+    /// Unlike [`Failure`](Self::Failure), not counted toward the maximum
+    /// auth attempts. The method is no longer advertised.
+    Unsupported,
+}
+
+impl From<bool> for AuthResult {
+    fn from(v: bool) -> Self {
+        if v {
+            Self::Ok
+        } else {
+            Self::Failure
+        }
+    }
 }
 
 /// Password authentication result.
@@ -57,26 +453,133 @@ pub enum PasswordResult {
 
     /// Failed to authenticate password
     Failure,
+
+    /// User has no password, e.g. a key only account.
+    ///
+    /// Unlike [`Failure`](Self::Failure), not counted toward the maximum
+    /// auth attempts. Password is no longer advertised.
+    Unsupported,
 }
 
-pub trait AuthNoneHandler: Send {
+/// Prompts of one keyboard-interactive round trip.
+#[derive(Debug, Clone, Default)]
+pub struct InfoRequest {
+    name: String,
+    instruction: String,
+    prompts: Vec<(String, bool)>,
+}
+
+impl InfoRequest {
+    /// Request titled `name`, shown with `instruction`. Both may be empty.
+    pub fn new(name: impl Into<String>, instruction: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            instruction: instruction.into(),
+            prompts: vec![],
+        }
+    }
+
+    /// Add a prompt. Client echoes the answer as typed if `echo`.
+    pub fn prompt(mut self, prompt: impl Into<String>, echo: bool) -> Self {
+        self.prompts.push((prompt.into(), echo));
+        self
+    }
+
+    pub(crate) fn into_parts(self) -> (String, String, Vec<(String, bool)>) {
+        (self.name, self.instruction, self.prompts)
+    }
+}
+
+/// Keyboard-interactive authentication result.
+#[derive(Debug)]
+pub enum KeyboardInteractiveResult {
+    /// Ok
+    Ok,
+
+    /// Ask the client again
+    MorePrompts(InfoRequest),
+
+    /// Failed to authenticate
+    Failure,
+}
+
+/// User authentication method the server may advertise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    Publickey,
+    Password,
+    Hostbased,
+    KeyboardInteractive,
+}
+
+impl AuthMethod {
+    /// Every method, in advertised order.
+    pub(crate) const ALL: &'static [AuthMethod] = &[
+        Self::Publickey,
+        Self::Password,
+        Self::Hostbased,
+        Self::KeyboardInteractive,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Publickey => "publickey",
+            Self::Password => "password",
+            Self::Hostbased => "hostbased",
+            Self::KeyboardInteractive => "keyboard-interactive",
+        }
+    }
+}
+
+impl AsRef<str> for AuthMethod {
+    fn as_ref(&self) -> &str {
+        self.name()
+    }
+}
+
+pub trait AuthMethodsHandler: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
-    fn handle(&mut self, username: String) -> BoxFuture<'static, Result<bool, Self::Error>>;
+    fn handle(
+        &mut self,
+        username: String,
+    ) -> BoxFuture<'static, Result<Vec<AuthMethod>, Self::Error>>;
 }
 
-impl<F, E> AuthNoneHandler for F
+impl<F, E> AuthMethodsHandler for F
 where
-    F: Fn(String) -> BoxFuture<'static, Result<bool, E>> + Send,
+    F: Fn(String) -> BoxFuture<'static, Result<Vec<AuthMethod>, E>> + Send,
     E: Into<HandlerError> + Send + 'static,
 {
     type Error = E;
 
-    fn handle(&mut self, username: String) -> BoxFuture<'static, Result<bool, Self::Error>> {
+    fn handle(
+        &mut self,
+        username: String,
+    ) -> BoxFuture<'static, Result<Vec<AuthMethod>, Self::Error>> {
         self(username)
     }
 }
 
+pub trait AuthNoneHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, username: String) -> BoxFuture<'static, Result<AuthResult, Self::Error>>;
+}
+
+impl<F, E, R> AuthNoneHandler for F
+where
+    F: Fn(String) -> BoxFuture<'static, Result<R, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+    R: Into<AuthResult> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, username: String) -> BoxFuture<'static, Result<AuthResult, Self::Error>> {
+        self(username).map_ok(Into::into).boxed()
+    }
+}
+
 pub trait AuthPublickeyHandler: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
@@ -84,13 +587,14 @@ pub trait AuthPublickeyHandler: Send {
         &mut self,
         username: String,
         publickey: PublicKey,
-    ) -> BoxFuture<'static, Result<bool, Self::Error>>;
+    ) -> BoxFuture<'static, Result<AuthResult, Self::Error>>;
 }
 
-impl<F, E> AuthPublickeyHandler for F
+impl<F, E, R> AuthPublickeyHandler for F
 where
-    F: Fn(String, PublicKey) -> BoxFuture<'static, Result<bool, E>> + Send,
+    F: Fn(String, PublicKey) -> BoxFuture<'static, Result<R, E>> + Send,
     E: Into<HandlerError> + Send + 'static,
+    R: Into<AuthResult> + Send + 'static,
 {
     type Error = E;
 
@@ -98,8 +602,8 @@ where
         &mut self,
         username: String,
         publickey: PublicKey,
-    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
-        self(username, publickey)
+    ) -> BoxFuture<'static, Result<AuthResult, Self::Error>> {
+        self(username, publickey).map_ok(Into::into).boxed()
     }
 }
 
@@ -185,6 +689,50 @@ where
     }
 }
 
+pub trait AuthKeyboardInteractiveStartHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, username: String) -> BoxFuture<'static, Result<InfoRequest, Self::Error>>;
+}
+
+impl<F, E> AuthKeyboardInteractiveStartHandler for F
+where
+    F: Fn(String) -> BoxFuture<'static, Result<InfoRequest, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, username: String) -> BoxFuture<'static, Result<InfoRequest, Self::Error>> {
+        self(username)
+    }
+}
+
+pub trait AuthKeyboardInteractiveResponseHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        username: String,
+        responses: Vec<String>,
+    ) -> BoxFuture<'static, Result<KeyboardInteractiveResult, Self::Error>>;
+}
+
+impl<F, E> AuthKeyboardInteractiveResponseHandler for F
+where
+    F: Fn(String, Vec<String>) -> BoxFuture<'static, Result<KeyboardInteractiveResult, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        username: String,
+        responses: Vec<String>,
+    ) -> BoxFuture<'static, Result<KeyboardInteractiveResult, Self::Error>> {
+        self(username, responses)
+    }
+}
+
 pub trait ChannelRequestPtyHandler<Pty>: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
@@ -195,13 +743,13 @@ pub trait ChannelRequestPtyHandler<Pty>: Send {
         height: u32,
         width_px: u32,
         height_px: u32,
-        modes: Vec<u8>,
+        modes: Vec<(u8, u32)>,
     ) -> BoxFuture<'static, Result<Pty, Self::Error>>;
 }
 
 impl<F, E, Pty> ChannelRequestPtyHandler<Pty> for F
 where
-    F: Fn(String, u32, u32, u32, u32, Vec<u8>) -> BoxFuture<'static, Result<Pty, E>> + Send,
+    F: Fn(String, u32, u32, u32, u32, Vec<(u8, u32)>) -> BoxFuture<'static, Result<Pty, E>> + Send,
     E: Into<HandlerError> + Send + 'static,
 {
     type Error = E;
@@ -213,125 +761,851 @@ where
         height: u32,
         width_px: u32,
         height_px: u32,
-        modes: Vec<u8>,
+        modes: Vec<(u8, u32)>,
     ) -> BoxFuture<'static, Result<Pty, Self::Error>> {
         self(term, width, height, width_px, height_px, modes)
     }
 }
 
-pub trait ChannelShellHandler<Pty>: Send {
+pub trait ChannelEnvHandler: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
-    fn handle(&mut self, ctx: SessionContext<Pty>) -> BoxFuture<'static, Result<u32, Self::Error>>;
+    fn handle(
+        &mut self,
+        channel: u32,
+        name: String,
+        value: Bytes,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>>;
 }
 
-impl<F, E, Pty> ChannelShellHandler<Pty> for F
+impl<F, E> ChannelEnvHandler for F
 where
-    F: Fn(SessionContext<Pty>) -> BoxFuture<'static, Result<u32, E>> + Send,
+    F: Fn(u32, String, Bytes) -> BoxFuture<'static, Result<bool, E>> + Send,
     E: Into<HandlerError> + Send + 'static,
 {
     type Error = E;
 
-    fn handle(&mut self, ctx: SessionContext<Pty>) -> BoxFuture<'static, Result<u32, Self::Error>> {
-        self(ctx)
+    fn handle(
+        &mut self,
+        channel: u32,
+        name: String,
+        value: Bytes,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(channel, name, value)
     }
 }
 
-pub trait ChannelExecHandler<Pty>: Send {
+pub trait ChannelX11RequestHandler: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
     fn handle(
         &mut self,
-        ctx: SessionContext<Pty>,
-        prog: OsString,
-    ) -> BoxFuture<'static, Result<u32, Self::Error>>;
+        channel: u32,
+        request: X11Request,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>>;
 }
 
-impl<F, E, Pty> ChannelExecHandler<Pty> for F
+impl<F, E> ChannelX11RequestHandler for F
 where
-    F: Fn(SessionContext<Pty>, OsString) -> BoxFuture<'static, Result<u32, E>> + Send,
+    F: Fn(u32, X11Request, GlobalHandle) -> BoxFuture<'static, Result<bool, E>> + Send,
     E: Into<HandlerError> + Send + 'static,
 {
     type Error = E;
 
     fn handle(
         &mut self,
-        ctx: SessionContext<Pty>,
-        prog: OsString,
-    ) -> BoxFuture<'static, Result<u32, Self::Error>> {
-        self(ctx, prog)
+        channel: u32,
+        request: X11Request,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(channel, request, handle)
     }
 }
 
-pub trait ChannelDirectTcpIpHandler: Send {
+pub trait ChannelAgentForwardRequestHandler: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
     fn handle(
         &mut self,
-        ingress: SshInput,
-        egress: SshOutput,
-    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+        channel: u32,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>>;
 }
 
-impl<F, E> ChannelDirectTcpIpHandler for F
+impl<F, E> ChannelAgentForwardRequestHandler for F
 where
-    F: Fn(SshInput, SshOutput) -> BoxFuture<'static, Result<(), E>> + Send,
+    F: Fn(u32, GlobalHandle) -> BoxFuture<'static, Result<bool, E>> + Send,
     E: Into<HandlerError> + Send + 'static,
 {
     type Error = E;
 
     fn handle(
         &mut self,
-        ingress: SshInput,
-        egress: SshOutput,
-    ) -> BoxFuture<'static, Result<(), Self::Error>> {
-        self(ingress, egress)
+        channel: u32,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(channel, handle)
     }
 }
 
-/// SSH callback handlers collections.
-#[derive(Default)]
-pub struct Handlers<E, Pty = ()>
+pub trait ChannelSignalHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        channel: u32,
+        signal: Signal,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> ChannelSignalHandler for F
 where
+    F: Fn(u32, Signal, GlobalHandle) -> BoxFuture<'static, Result<(), E>> + Send,
     E: Into<HandlerError> + Send + 'static,
 {
-    auth_none: Option<Box<dyn AuthNoneHandler<Error = E>>>,
-    auth_publickey: Option<Box<dyn AuthPublickeyHandler<Error = E>>>,
-    auth_publickey_signature_verified_after_accepted:
-        Option<Box<dyn AuthPublickeyHandler<Error = E>>>,
-    auth_password: Option<Box<dyn AuthPasswordHandler<Error = E>>>,
-    auth_change_password: Option<Box<dyn AuthChangePasswordHandler<Error = E>>>,
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        channel: u32,
+        signal: Signal,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(channel, signal, handle)
+    }
+}
+
+pub trait ChannelShellHandler<Pty>: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, ctx: SessionContext<Pty>) -> BoxFuture<'static, Result<u32, Self::Error>>;
+}
+
+impl<F, E, Pty> ChannelShellHandler<Pty> for F
+where
+    F: Fn(SessionContext<Pty>) -> BoxFuture<'static, Result<u32, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, ctx: SessionContext<Pty>) -> BoxFuture<'static, Result<u32, Self::Error>> {
+        self(ctx)
+    }
+}
+
+pub trait ChannelExecHandler<Pty>: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        prog: OsString,
+    ) -> BoxFuture<'static, Result<u32, Self::Error>>;
+}
+
+impl<F, E, Pty> ChannelExecHandler<Pty> for F
+where
+    F: Fn(SessionContext<Pty>, OsString) -> BoxFuture<'static, Result<u32, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        prog: OsString,
+    ) -> BoxFuture<'static, Result<u32, Self::Error>> {
+        self(ctx, prog)
+    }
+}
+
+pub trait ChannelSubsystemHandler<Pty>: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        name: String,
+    ) -> BoxFuture<'static, Result<u32, Self::Error>>;
+}
+
+impl<F, E, Pty> ChannelSubsystemHandler<Pty> for F
+where
+    F: Fn(SessionContext<Pty>, String) -> BoxFuture<'static, Result<u32, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        name: String,
+    ) -> BoxFuture<'static, Result<u32, Self::Error>> {
+        self(ctx, name)
+    }
+}
+
+pub trait ChannelExtendedDataHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        channel: u32,
+        data_type: u32,
+        data: Bytes,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> ChannelExtendedDataHandler for F
+where
+    F: Fn(u32, u32, Bytes) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        channel: u32,
+        data_type: u32,
+        data: Bytes,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(channel, data_type, data)
+    }
+}
+
+pub trait ChannelDirectTcpIpHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        ingress: SshInput,
+        egress: SshOutput,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> ChannelDirectTcpIpHandler for F
+where
+    F: Fn(SshInput, SshOutput) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        ingress: SshInput,
+        egress: SshOutput,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(ingress, egress)
+    }
+}
+
+pub trait TcpipForwardHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        address: String,
+        port: u16,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<Option<u16>, Self::Error>>;
+}
+
+impl<F, E> TcpipForwardHandler for F
+where
+    F: Fn(String, u16, GlobalHandle) -> BoxFuture<'static, Result<Option<u16>, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        address: String,
+        port: u16,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<Option<u16>, Self::Error>> {
+        self(address, port, handle)
+    }
+}
+
+pub trait CancelTcpipForwardHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        address: String,
+        port: u16,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>>;
+}
+
+impl<F, E> CancelTcpipForwardHandler for F
+where
+    F: Fn(String, u16) -> BoxFuture<'static, Result<bool, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        address: String,
+        port: u16,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(address, port)
+    }
+}
+
+pub trait ChannelDirectStreamlocalHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        socket_path: String,
+        ingress: SshInput,
+        egress: SshOutput,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> ChannelDirectStreamlocalHandler for F
+where
+    F: Fn(String, SshInput, SshOutput) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        socket_path: String,
+        ingress: SshInput,
+        egress: SshOutput,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(socket_path, ingress, egress)
+    }
+}
+
+pub trait StreamlocalForwardHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        socket_path: String,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>>;
+}
+
+impl<F, E> StreamlocalForwardHandler for F
+where
+    F: Fn(String, GlobalHandle) -> BoxFuture<'static, Result<bool, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        socket_path: String,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(socket_path, handle)
+    }
+}
+
+pub trait CancelStreamlocalForwardHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, socket_path: String) -> BoxFuture<'static, Result<bool, Self::Error>>;
+}
+
+impl<F, E> CancelStreamlocalForwardHandler for F
+where
+    F: Fn(String) -> BoxFuture<'static, Result<bool, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, socket_path: String) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(socket_path)
+    }
+}
+
+/// Reply to a global request without a built-in meaning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlobalResponse {
+    /// Request succeeded, with request specific reply data.
+    Success(Bytes),
+    /// Request refused or not understood.
+    Failure,
+}
+
+pub trait GlobalRequestHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        name: String,
+        data: Bytes,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<GlobalResponse, Self::Error>>;
+}
+
+impl<F, E> GlobalRequestHandler for F
+where
+    F: Fn(String, Bytes, GlobalHandle) -> BoxFuture<'static, Result<GlobalResponse, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        name: String,
+        data: Bytes,
+        handle: GlobalHandle,
+    ) -> BoxFuture<'static, Result<GlobalResponse, Self::Error>> {
+        self(name, data, handle)
+    }
+}
+
+pub trait DisconnectedHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        reason: DisconnectReason,
+        description: String,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> DisconnectedHandler for F
+where
+    F: Fn(DisconnectReason, String) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        reason: DisconnectReason,
+        description: String,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(reason, description)
+    }
+}
+
+pub trait ServiceRequestHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, name: String) -> BoxFuture<'static, Result<bool, Self::Error>>;
+}
+
+impl<F, E> ServiceRequestHandler for F
+where
+    F: Fn(String) -> BoxFuture<'static, Result<bool, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, name: String) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(name)
+    }
+}
+
+pub trait HandshakeCompleteHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        client_version: String,
+        algorithms: NegotiatedAlgorithms,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> HandshakeCompleteHandler for F
+where
+    F: Fn(String, NegotiatedAlgorithms) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        client_version: String,
+        algorithms: NegotiatedAlgorithms,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(client_version, algorithms)
+    }
+}
+
+/// SSH callback handlers collections.
+#[derive(Default)]
+pub struct Handlers<E, Pty = ()>
+where
+    E: Into<HandlerError> + Send + 'static,
+{
+    auth_methods: Option<Box<dyn AuthMethodsHandler<Error = E>>>,
+    auth_none: Option<Box<dyn AuthNoneHandler<Error = E>>>,
+    auth_publickey: Option<Box<dyn AuthPublickeyHandler<Error = E>>>,
+    auth_publickey_signature_verified_after_accepted:
+        Option<Box<dyn AuthPublickeyHandler<Error = E>>>,
+    auth_password: Option<Box<dyn AuthPasswordHandler<Error = E>>>,
+    auth_change_password: Option<Box<dyn AuthChangePasswordHandler<Error = E>>>,
     auth_hostbased: Option<Box<dyn AuthHostbasedHandler<Error = E>>>,
+    auth_keyboard_interactive_start:
+        Option<Box<dyn AuthKeyboardInteractiveStartHandler<Error = E>>>,
+    auth_keyboard_interactive_response:
+        Option<Box<dyn AuthKeyboardInteractiveResponseHandler<Error = E>>>,
+
+    channel_pty_request: Option<Box<dyn ChannelRequestPtyHandler<Pty, Error = E>>>,
+    channel_env: Option<Box<dyn ChannelEnvHandler<Error = E>>>,
+    channel_x11_request: Option<Box<dyn ChannelX11RequestHandler<Error = E>>>,
+    channel_agent_forward_request: Option<Box<dyn ChannelAgentForwardRequestHandler<Error = E>>>,
+    channel_signal: Option<Box<dyn ChannelSignalHandler<Error = E>>>,
+    channel_shell: Option<Box<dyn ChannelShellHandler<Pty, Error = E>>>,
+    channel_exec: Option<Box<dyn ChannelExecHandler<Pty, Error = E>>>,
+    channel_subsystem: Option<Box<dyn ChannelSubsystemHandler<Pty, Error = E>>>,
+    channel_extended_data: Option<Box<dyn ChannelExtendedDataHandler<Error = E>>>,
+    channel_direct_tcpip: Option<Box<dyn ChannelDirectTcpIpHandler<Error = E>>>,
+    tcpip_forward: Option<Box<dyn TcpipForwardHandler<Error = E>>>,
+    cancel_tcpip_forward: Option<Box<dyn CancelTcpipForwardHandler<Error = E>>>,
+    channel_direct_streamlocal: Option<Box<dyn ChannelDirectStreamlocalHandler<Error = E>>>,
+    streamlocal_forward: Option<Box<dyn StreamlocalForwardHandler<Error = E>>>,
+    cancel_streamlocal_forward: Option<Box<dyn CancelStreamlocalForwardHandler<Error = E>>>,
+    global_request: Option<Box<dyn GlobalRequestHandler<Error = E>>>,
+    disconnected: Option<Box<dyn DisconnectedHandler<Error = E>>>,
+    handshake_complete: Option<Box<dyn HandshakeCompleteHandler<Error = E>>>,
+    service_request: Option<Box<dyn ServiceRequestHandler<Error = E>>>,
+}
+
+impl<E, Pty> Handlers<E, Pty>
+where
+    E: Into<HandlerError> + Send + 'static,
+{
+    /// Construct new Handlers instance.
+    pub fn new() -> Self {
+        Self {
+            auth_methods: None,
+            auth_none: None,
+            auth_publickey: None,
+            auth_publickey_signature_verified_after_accepted: None,
+            auth_password: None,
+            auth_change_password: None,
+            auth_hostbased: None,
+            auth_keyboard_interactive_start: None,
+            auth_keyboard_interactive_response: None,
+            channel_pty_request: None,
+            channel_env: None,
+            channel_x11_request: None,
+            channel_agent_forward_request: None,
+            channel_signal: None,
+            channel_shell: None,
+            channel_exec: None,
+            channel_subsystem: None,
+            channel_extended_data: None,
+            channel_direct_tcpip: None,
+            tcpip_forward: None,
+            cancel_tcpip_forward: None,
+            channel_direct_streamlocal: None,
+            streamlocal_forward: None,
+            cancel_streamlocal_forward: None,
+            global_request: None,
+            disconnected: None,
+            handshake_complete: None,
+            service_request: None,
+        }
+    }
+
+    /// Register handler deciding which methods to advertise to a user.
+    ///
+    /// Consulted for every user authentication request. Methods not listed
+    /// are rejected without calling their handler. Keyboard-interactive is
+    /// never advertised without its handlers. After [`AuthResult::Partial`],
+    /// its remaining methods apply instead. If not registered, every other
+    /// method is advertised.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{AuthMethod, Handlers};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_methods(|_| {
+    ///     async move { Ok(vec![AuthMethod::Publickey]) }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_methods<H>(&mut self, handler: H)
+    where
+        H: AuthMethodsHandler<Error = E> + 'static,
+    {
+        self.auth_methods = Some(Box::new(handler))
+    }
+
+    /// Register None user authentication method handler.
+    ///
+    /// Returns `bool` or [`AuthResult`]. With [`AuthResult::Partial`] one
+    /// of the remaining methods must succeed before auth completes.
+    ///
+    /// Clients send none first, often repeatedly, to learn the methods they
+    /// may use. So a rejection only answers with the advertised methods and
+    /// is never counted toward the maximum auth attempts.
+    ///
+    /// If not registered, return none authentication failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_none(|username| {
+    ///     async move {
+    ///         Ok(username == "bob")
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_none<H>(&mut self, handler: H)
+    where
+        H: AuthNoneHandler<Error = E> + 'static,
+    {
+        self.auth_none = Some(Box::new(handler))
+    }
+
+    /// Register Publickey user authentication method handler.
+    ///
+    /// Returns `bool` or [`AuthResult`]. With [`AuthResult::Partial`] one
+    /// of the remaining methods must succeed before auth completes.
+    ///
+    /// If not registered, return publickey authentication failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_publickey(|username, publickey: ssssh::PublicKey| {
+    ///     async move {
+    ///         let authorized_rsa_key =
+    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
+    ///         Ok(username == "bob" && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_publickey<H>(&mut self, handler: H)
+    where
+        H: AuthPublickeyHandler<Error = E> + 'static,
+    {
+        self.auth_publickey = Some(Box::new(handler))
+    }
+
+    /// Register Publickey user authentication method handler.
+    /// It handles When the verification of the message signature of the previously accepted public key is successful.
+    ///
+    /// If not registered, the result of [`Self::on_auth_publickey`] applies.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_publickey(|username, publickey: ssssh::PublicKey| {
+    ///     async move {
+    ///         let authorized_rsa_key =
+    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
+    ///         Ok(username == "bob" && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///     }.boxed()
+    /// });
+    /// handlers.on_auth_publickey_signature_verified_after_accepted(|username, publickey:
+    /// ssssh::PublicKey| {
+    ///     async move {
+    ///         let authorized_rsa_key =
+    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
+    ///         Ok(username == "bob" && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_publickey_signature_verified_after_accepted<H>(&mut self, handler: H)
+    where
+        H: AuthPublickeyHandler<Error = E> + 'static,
+    {
+        self.auth_publickey_signature_verified_after_accepted = Some(Box::new(handler))
+    }
+
+    /// Register Password user authentication method handler.
+    ///
+    /// If not registered, return password authentication failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use ssssh::PasswordResult;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_password(|username, password| {
+    ///     async move {
+    ///         Ok(if username == "bob" && password == "frosty-tricolor1-fabulous-unsent" {
+    ///             PasswordResult::Ok
+    ///         } else {
+    ///             PasswordResult::Failure
+    ///         })
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_password<H>(&mut self, handler: H)
+    where
+        H: AuthPasswordHandler<Error = E> + 'static,
+    {
+        self.auth_password = Some(Box::new(handler))
+    }
+
+    /// Register Change Password user authentication method handler.
+    ///
+    /// If not registered, return change password failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use ssssh::PasswordResult;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_change_password(|username: String, oldpassword: String, newpassword:
+    /// String| {
+    ///     async move {
+    ///         let result = do_change_password(&username, &oldpassword, &newpassword);
+    ///         Ok(if result {
+    ///             PasswordResult::Ok
+    ///         } else {
+    ///             PasswordResult::Failure
+    ///         })
+    ///     }.boxed()
+    /// });
+    /// # fn do_change_password(_: &str, _: &str, _: &str) -> bool {
+    /// #  true
+    /// # }
+    /// ```
+    pub fn on_auth_change_password<H>(&mut self, handler: H)
+    where
+        H: AuthChangePasswordHandler<Error = E> + 'static,
+    {
+        self.auth_change_password = Some(Box::new(handler))
+    }
+
+    /// Register Hostbased user authentication method handler.
+    ///
+    /// If not registered, return hostbased authentication failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_hostbased(|username, hostname, publickey: ssssh::PublicKey| {
+    ///     async move {
+    ///         let authorized_rsa_key =
+    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
+    ///         Ok(username == "bob" && hostname == "localhost." && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_hostbased<H>(&mut self, handler: H)
+    where
+        H: AuthHostbasedHandler<Error = E> + 'static,
+    {
+        self.auth_hostbased = Some(Box::new(handler))
+    }
 
-    channel_pty_request: Option<Box<dyn ChannelRequestPtyHandler<Pty, Error = E>>>,
-    channel_shell: Option<Box<dyn ChannelShellHandler<Pty, Error = E>>>,
-    channel_exec: Option<Box<dyn ChannelExecHandler<Pty, Error = E>>>,
-    channel_direct_tcpip: Option<Box<dyn ChannelDirectTcpIpHandler<Error = E>>>,
-}
+    /// Register Keyboard-interactive user authentication method start handler.
+    ///
+    /// Returns the first prompts for the user. The method is only offered
+    /// once both this and [`Self::on_auth_keyboard_interactive_response`]
+    /// are registered.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{Handlers, InfoRequest};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_keyboard_interactive_start(|_| {
+    ///     async move {
+    ///         Ok(InfoRequest::new("", "").prompt("One-time password: ", false))
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_keyboard_interactive_start<H>(&mut self, handler: H)
+    where
+        H: AuthKeyboardInteractiveStartHandler<Error = E> + 'static,
+    {
+        self.auth_keyboard_interactive_start = Some(Box::new(handler))
+    }
 
-impl<E, Pty> Handlers<E, Pty>
-where
-    E: Into<HandlerError> + Send + 'static,
-{
-    /// Construct new Handlers instance.
-    pub fn new() -> Self {
-        Self {
-            auth_none: None,
-            auth_publickey: None,
-            auth_publickey_signature_verified_after_accepted: None,
-            auth_password: None,
-            auth_change_password: None,
-            auth_hostbased: None,
-            channel_pty_request: None,
-            channel_shell: None,
-            channel_exec: None,
-            channel_direct_tcpip: None,
-        }
+    /// Register Keyboard-interactive user authentication method response handler.
+    ///
+    /// Receives one answer per prompt of the last request.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{Handlers, KeyboardInteractiveResult};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_keyboard_interactive_response(|username, responses: Vec<String>| {
+    ///     async move {
+    ///         Ok(if username == "bob" && responses == ["123456"] {
+    ///             KeyboardInteractiveResult::Ok
+    ///         } else {
+    ///             KeyboardInteractiveResult::Failure
+    ///         })
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_keyboard_interactive_response<H>(&mut self, handler: H)
+    where
+        H: AuthKeyboardInteractiveResponseHandler<Error = E> + 'static,
+    {
+        self.auth_keyboard_interactive_response = Some(Box::new(handler))
     }
 
-    /// Register None user authentication method handler.
+    /// Register Request pty handler.
     ///
-    /// If not registered, return none authentication failure.
+    /// Terminal modes are decoded as (opcode, argument) pairs of RFC 4254
+    /// section 8, without the terminating `TTY_OP_END`.
+    ///
+    /// If not registered, channel returns failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error, Pty>::new();
+    /// handlers.on_channel_pty_request(|term: String, width, height, width_px, height_px, modes:
+    /// Vec<(u8, u32)> | {
+    ///     async move {
+    ///         let pty: Pty = openpty(&term, width, height, width_px, height_px, &modes);
+    ///         Ok(pty)
+    ///     }.boxed()
+    /// });
+    /// struct Pty {
+    ///     // ...
+    /// }
+    /// # fn openpty(_: &str, _: u32, _: u32, _: u32, _:u32, _:&[(u8, u32)]) -> Pty {
+    /// #     Pty {}
+    /// # }
+    /// ```
+    pub fn on_channel_pty_request<H>(&mut self, handler: H)
+    where
+        H: ChannelRequestPtyHandler<Pty, Error = E> + 'static,
+    {
+        self.channel_pty_request = Some(Box::new(handler))
+    }
+
+    /// Register env channel request handler.
+    ///
+    /// Called with the channel id, name and raw value. Accepted variables
+    /// are available from [`SessionContext::env`] of that channel, values
+    /// byte for byte. If not registered, every variable is accepted.
     ///
     /// # Example
     ///
@@ -339,22 +1613,108 @@ where
     /// use ssssh::Handlers;
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_auth_none(|username| {
+    /// handlers.on_channel_env(|_channel, name: String, _value| {
+    ///     async move { Ok(name == "LANG" || name.starts_with("LC_")) }.boxed()
+    /// });
+    /// ```
+    pub fn on_channel_env<H>(&mut self, handler: H)
+    where
+        H: ChannelEnvHandler<Error = E> + 'static,
+    {
+        self.channel_env = Some(Box::new(handler))
+    }
+
+    /// Register `x11-req` channel request handler.
+    ///
+    /// Called with the session channel and the client's X11 parameters.
+    /// Returns whether forwarding is accepted. ssssh does not proxy X11
+    /// itself: once a local X client connects to the display set up by the
+    /// handler, open a channel back with [`GlobalHandle::open_x11`].
+    ///
+    /// If not registered, request returns failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{GlobalHandle, Handlers, X11Request};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_x11_request(|channel, request: X11Request, _: GlobalHandle| {
     ///     async move {
-    ///         Ok(username == "bob")
+    ///         println!("{} {} {}", channel, request.auth_protocol(), request.screen_number());
+    ///         Ok(true)
     ///     }.boxed()
     /// });
     /// ```
-    pub fn on_auth_none<H>(&mut self, handler: H)
+    pub fn on_channel_x11_request<H>(&mut self, handler: H)
     where
-        H: AuthNoneHandler<Error = E> + 'static,
+        H: ChannelX11RequestHandler<Error = E> + 'static,
     {
-        self.auth_none = Some(Box::new(handler))
+        self.channel_x11_request = Some(Box::new(handler))
     }
 
-    /// Register Publickey user authentication method handler.
+    /// Register `auth-agent-req@openssh.com` channel request handler.
     ///
-    /// If not registered, return publickey authentication failure.
+    /// Called with the session channel the client asked agent forwarding
+    /// for. Returns whether forwarding is accepted. ssssh does not run an
+    /// agent socket itself: when a local program connects to the socket set
+    /// up by the handler, open a channel back with
+    /// [`GlobalHandle::open_agent`] and relay the bytes.
+    ///
+    /// If not registered, request returns failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{GlobalHandle, Handlers};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_agent_forward_request(|channel, _: GlobalHandle| {
+    ///     async move {
+    ///         println!("agent forwarding on {}", channel);
+    ///         Ok(true)
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_channel_agent_forward_request<H>(&mut self, handler: H)
+    where
+        H: ChannelAgentForwardRequestHandler<Error = E> + 'static,
+    {
+        self.channel_agent_forward_request = Some(Box::new(handler))
+    }
+
+    /// Register `signal` channel request handler.
+    ///
+    /// Called with the session channel and the signal the client wants
+    /// delivered, e.g. on Ctrl+C without a pty. Delivering it to whatever the
+    /// channel runs is up to the handler. Never answered, as RFC 4254
+    /// specifies.
+    ///
+    /// If not registered, signals are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{GlobalHandle, Handlers, Signal};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_signal(|channel, signal: Signal, _: GlobalHandle| {
+    ///     async move {
+    ///         println!("{} SIG{}", channel, signal);
+    ///         Ok(())
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_channel_signal<H>(&mut self, handler: H)
+    where
+        H: ChannelSignalHandler<Error = E> + 'static,
+    {
+        self.channel_signal = Some(Box::new(handler))
+    }
+
+    /// Register Shell channel handler.
+    ///
+    /// If not registered, channel returns failure.
     ///
     /// # Example
     ///
@@ -362,25 +1722,32 @@ where
     /// use ssssh::Handlers;
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_auth_publickey(|username, publickey: ssssh::PublicKey| {
+    /// handlers.on_channel_shell(|mut ctx: ssssh::SessionContext<_>| {
     ///     async move {
-    ///         let authorized_rsa_key =
-    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
-    ///         Ok(username == "bob" && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///         let (stdin, stdout, stderr) = ctx.take_stdio().unwrap();
+    ///         let process = do_exec_shell(stdin, stdout, stderr);
+    ///         let exit_code = process.await;
+    ///         Ok(exit_code)
     ///     }.boxed()
     /// });
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_exec_shell(_: SshInput, _: SshOutput, _:SshOutput) -> u32 {
+    /// #     0
+    /// # }
     /// ```
-    pub fn on_auth_publickey<H>(&mut self, handler: H)
+    pub fn on_channel_shell<H>(&mut self, handler: H)
     where
-        H: AuthPublickeyHandler<Error = E> + 'static,
+        H: ChannelShellHandler<Pty, Error = E> + 'static,
     {
-        self.auth_publickey = Some(Box::new(handler))
+        self.channel_shell = Some(Box::new(handler))
     }
 
-    /// Register Publickey user authentication method handler.
-    /// It handles When the verification of the message signature of the previously accepted public key is successful.
+    /// Register Exec channel handler.
     ///
-    /// If not registered, return publickey authentication success.
+    /// The command is passed as sent, which need not be UTF-8. Its bytes are
+    /// available through `OsStrExt::as_bytes`, `to_string_lossy` converts.
+    ///
+    /// If not registered, channel returns failure.
     ///
     /// # Example
     ///
@@ -388,149 +1755,290 @@ where
     /// use ssssh::Handlers;
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_auth_publickey(|username, publickey: ssssh::PublicKey| {
+    /// handlers.on_channel_exec(|mut ctx: ssssh::SessionContext<_>, prog| {
     ///     async move {
-    ///         let authorized_rsa_key =
-    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
-    ///         Ok(username == "bob" && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///         let (stdin, stdout, stderr) = ctx.take_stdio().unwrap();
+    ///         let process = do_exec(prog, stdin, stdout, stderr);
+    ///         let exit_code = process.await;
+    ///         Ok(exit_code)
     ///     }.boxed()
     /// });
-    /// handlers.on_auth_publickey_signature_verified_after_accepted(|username, publickey:
-    /// ssssh::PublicKey| {
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_exec(_: std::ffi::OsString, _: SshInput, _: SshOutput, _:SshOutput) -> u32 {
+    /// #     0
+    /// # }
+    /// ```
+    pub fn on_channel_exec<H>(&mut self, handler: H)
+    where
+        H: ChannelExecHandler<Pty, Error = E> + 'static,
+    {
+        self.channel_exec = Some(Box::new(handler))
+    }
+
+    /// Register subsystem handler, e.g. for `sftp`.
+    ///
+    /// Requests for subsystems are refused while unregistered.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_subsystem(|mut ctx: ssssh::SessionContext<_>, name| {
+    ///     async move {
+    ///         if name != "sftp" {
+    ///             return Ok(1);
+    ///         }
+    ///         let (stdin, stdout, _) = ctx.take_stdio().unwrap();
+    ///         Ok(do_sftp(stdin, stdout).await)
+    ///     }.boxed()
+    /// });
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_sftp(_: SshInput, _: SshOutput) -> u32 {
+    /// #     0
+    /// # }
+    /// ```
+    pub fn on_channel_subsystem<H>(&mut self, handler: H)
+    where
+        H: ChannelSubsystemHandler<Pty, Error = E> + 'static,
+    {
+        self.channel_subsystem = Some(Box::new(handler))
+    }
+
+    /// Register extended data handler, called with channel, data type code and data.
+    ///
+    /// Awaited before the next message is processed. If not registered,
+    /// extended data is discarded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_extended_data(|channel, data_type, data: bytes::Bytes| {
+    ///     async move {
+    ///         println!("{} {} {:?}", channel, data_type, data);
+    ///         Ok(())
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_channel_extended_data<H>(&mut self, handler: H)
+    where
+        H: ChannelExtendedDataHandler<Error = E> + 'static,
+    {
+        self.channel_extended_data = Some(Box::new(handler))
+    }
+
+    /// Register Direct TCP/IP channel handler.
+    ///
+    /// If not registered, channel returns failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_direct_tcpip(|input, output| {
+    ///     async move {
+    ///         do_proxy(input, output).await;
+    ///         Ok(())
+    ///     }.boxed()
+    /// });
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_proxy(_: SshInput, _: SshOutput) {
+    /// # }
+    /// ```
+    pub fn on_channel_direct_tcpip<H>(&mut self, handler: H)
+    where
+        H: ChannelDirectTcpIpHandler<Error = E> + 'static,
+    {
+        self.channel_direct_tcpip = Some(Box::new(handler))
+    }
+
+    /// Register `tcpip-forward` global request handler.
+    ///
+    /// Called with the address and port to bind. Returns the bound port, or
+    /// `None` to refuse. The bound port is reported to the client if it
+    /// requested port 0. Connections accepted later are forwarded through
+    /// [`GlobalHandle::open_forwarded_tcpip`], which must not be awaited
+    /// within the handler itself.
+    ///
+    /// If not registered or on error, request returns failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// use tokio::net::TcpListener;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_tcpip_forward(|address: String, port, handle: ssssh::GlobalHandle| {
     ///     async move {
-    ///         let authorized_rsa_key =
-    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
-    ///         Ok(username == "bob" && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///         let listener = TcpListener::bind((address.as_str(), port)).await?;
+    ///         let bound = listener.local_addr()?.port();
+    ///         tokio::spawn(async move {
+    ///             while let Ok((_socket, peer)) = listener.accept().await {
+    ///                 let ip = peer.ip().to_string();
+    ///                 let (_input, _output) = handle
+    ///                     .open_forwarded_tcpip(&address, bound, &ip, peer.port())
+    ///                     .await?;
+    ///             }
+    ///             Ok::<_, ssssh::SshError>(())
+    ///         });
+    ///         Ok(Some(bound))
     ///     }.boxed()
     /// });
     /// ```
-    pub fn on_auth_publickey_signature_verified_after_accepted<H>(&mut self, handler: H)
+    pub fn on_tcpip_forward<H>(&mut self, handler: H)
     where
-        H: AuthPublickeyHandler<Error = E> + 'static,
+        H: TcpipForwardHandler<Error = E> + 'static,
     {
-        self.auth_publickey_signature_verified_after_accepted = Some(Box::new(handler))
+        self.tcpip_forward = Some(Box::new(handler))
     }
 
-    /// Register Password user authentication method handler.
+    /// Register `cancel-tcpip-forward` global request handler.
     ///
-    /// If not registered, return password authentication failure.
+    /// Returns whether the forwarding was cancelled.
+    ///
+    /// If not registered or on error, request returns failure.
     ///
     /// # Example
     ///
     /// ```
     /// use ssssh::Handlers;
-    /// use ssssh::PasswordResult;
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_auth_password(|username, password| {
+    /// handlers.on_cancel_tcpip_forward(|address, port| {
     ///     async move {
-    ///         Ok(if username == "bob" && password == "frosty-tricolor1-fabulous-unsent" {
-    ///             PasswordResult::Ok
-    ///         } else {
-    ///             PasswordResult::Failure
-    ///         })
+    ///         println!("stop listening on {}:{}", address, port);
+    ///         Ok(true)
     ///     }.boxed()
     /// });
     /// ```
-    pub fn on_auth_password<H>(&mut self, handler: H)
+    pub fn on_cancel_tcpip_forward<H>(&mut self, handler: H)
     where
-        H: AuthPasswordHandler<Error = E> + 'static,
+        H: CancelTcpipForwardHandler<Error = E> + 'static,
     {
-        self.auth_password = Some(Box::new(handler))
+        self.cancel_tcpip_forward = Some(Box::new(handler))
     }
 
-    /// Register Change Password user authentication method handler.
+    /// Register `direct-streamlocal@openssh.com` channel handler, as for
+    /// `ssh -L 8080:/run/app.sock`.
     ///
-    /// If not registered, return change password failure.
+    /// Called with the Unix domain socket path to connect to.
+    ///
+    /// If not registered, channel returns failure.
     ///
     /// # Example
     ///
     /// ```
     /// use ssssh::Handlers;
-    /// use ssssh::PasswordResult;
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_auth_change_password(|username: String, oldpassword: String, newpassword:
-    /// String| {
+    /// handlers.on_channel_direct_streamlocal(|socket_path: String, input, output| {
     ///     async move {
-    ///         let result = do_change_password(&username, &oldpassword, &newpassword);
-    ///         Ok(if result {
-    ///             PasswordResult::Ok
-    ///         } else {
-    ///             PasswordResult::Failure
-    ///         })
+    ///         let socket = tokio::net::UnixStream::connect(&socket_path).await?;
+    ///         do_proxy(socket, input, output).await;
+    ///         Ok(())
     ///     }.boxed()
     /// });
-    /// # fn do_change_password(_: &str, _: &str, _: &str) -> bool {
-    /// #  true
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_proxy(_: tokio::net::UnixStream, _: SshInput, _: SshOutput) {
     /// # }
     /// ```
-    pub fn on_auth_change_password<H>(&mut self, handler: H)
+    pub fn on_channel_direct_streamlocal<H>(&mut self, handler: H)
     where
-        H: AuthChangePasswordHandler<Error = E> + 'static,
+        H: ChannelDirectStreamlocalHandler<Error = E> + 'static,
     {
-        self.auth_change_password = Some(Box::new(handler))
+        self.channel_direct_streamlocal = Some(Box::new(handler))
     }
 
-    /// Register Hostbased user authentication method handler.
+    /// Register `streamlocal-forward@openssh.com` global request handler,
+    /// as for `ssh -R /tmp/fwd.sock:localhost:8080`.
     ///
-    /// If not registered, return hostbased authentication failure.
+    /// Called with the Unix domain socket path to listen on. Returns whether
+    /// it is listening. Connections accepted later are forwarded through
+    /// [`GlobalHandle::open_forwarded_streamlocal`], which must not be
+    /// awaited within the handler itself.
+    ///
+    /// If not registered or on error, request returns failure.
     ///
     /// # Example
     ///
     /// ```
     /// use ssssh::Handlers;
     /// use futures::FutureExt as _;
+    /// use tokio::net::UnixListener;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_auth_hostbased(|username, hostname, publickey: ssssh::PublicKey| {
+    /// handlers.on_streamlocal_forward(|socket_path: String, handle: ssssh::GlobalHandle| {
     ///     async move {
-    ///         let authorized_rsa_key =
-    ///         "AAAAB3NzaC1yc2EAAAADAQABAAABgQCsuW6XTH7zcwyQN9gKj3yVp9wg/4Hx5KL4YMXFBcjovr0KCA8NPvuYYn3WCyCO4zYoq4YrtjkS3XwRILjWo8Vx5zZcJL+zdGVLmQ5BNSWmvYAgcbpQrdftvk8y2SvMJHgK51g9cpumC8/D9yzOjNg1rlWQ0QZzDaUr0ugzQdL5KVXtTX3Mm3rjKhSy9coG7nJADv40R4tUiwJy0oorOn+E8y4lCdcNQnIxgME0WzgZ6NEJHU4s3cJY1OddWHRImunGLAJsSoAuHqpp8qtyuC8R+o+VcuqGLxXGCPoNNsy186dy7nGMCmGz+nJoNGR6jh+gHyHimGjqUticafo5NiY6J9uNjzh5HLg0B17iTR1iIDWDFyB3IRyNphnwEKl7OutNWvlk584b3USvTsVjBenNXKe181fE8s3hFs5B88NzXHoJuC+/L8/Y/tu24xckkt8ySCgRUHRJy9FOzmmpmaIeUZ9xB+IaQgn6Cue5tAzjeoa3wqyjlbV8lekK7DXlPOk=";
-    ///         Ok(username == "bob" && hostname == "localhost." && publickey.algorithm() == "ssh-rsa" && publickey.to_string() == authorized_rsa_key)
+    ///         let listener = UnixListener::bind(&socket_path)?;
+    ///         tokio::spawn(async move {
+    ///             while let Ok((_socket, _)) = listener.accept().await {
+    ///                 let (_input, _output) = handle
+    ///                     .open_forwarded_streamlocal(&socket_path)
+    ///                     .await?;
+    ///             }
+    ///             Ok::<_, ssssh::SshError>(())
+    ///         });
+    ///         Ok(true)
     ///     }.boxed()
     /// });
     /// ```
-    pub fn on_auth_hostbased<H>(&mut self, handler: H)
+    pub fn on_streamlocal_forward<H>(&mut self, handler: H)
     where
-        H: AuthHostbasedHandler<Error = E> + 'static,
+        H: StreamlocalForwardHandler<Error = E> + 'static,
     {
-        self.auth_hostbased = Some(Box::new(handler))
+        self.streamlocal_forward = Some(Box::new(handler))
     }
 
-    /// Register Request pty handler.
+    /// Register `cancel-streamlocal-forward@openssh.com` global request handler.
     ///
-    /// If not registered, channel returns failure.
+    /// Returns whether the forwarding was cancelled.
+    ///
+    /// If not registered or on error, request returns failure.
+    pub fn on_cancel_streamlocal_forward<H>(&mut self, handler: H)
+    where
+        H: CancelStreamlocalForwardHandler<Error = E> + 'static,
+    {
+        self.cancel_streamlocal_forward = Some(Box::new(handler))
+    }
+
+    /// Register handler for global requests not handled by ssssh itself.
+    ///
+    /// Called with the request name and its undecoded data, e.g. for vendor
+    /// extensions. The reply is sent only if the client wants one.
+    ///
+    /// If not registered, request returns failure.
     ///
     /// # Example
     ///
     /// ```
-    /// use ssssh::Handlers;
+    /// use ssssh::{GlobalResponse, Handlers};
     /// use futures::FutureExt as _;
-    /// let mut handlers = Handlers::<anyhow::Error, Pty>::new();
-    /// handlers.on_channel_pty_request(|term: String, width, height, width_px, height_px, modes:
-    /// Vec<u8> | {
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_global_request(|name: String, data, _| {
     ///     async move {
-    ///         let pty: Pty = openpty(&term, width, height, width_px, height_px, &modes);
-    ///         Ok(pty)
+    ///         match name.as_str() {
+    ///             "echo@example.com" => Ok(GlobalResponse::Success(data)),
+    ///             _ => Ok(GlobalResponse::Failure),
+    ///         }
     ///     }.boxed()
     /// });
-    /// struct Pty {
-    ///     // ...
-    /// }
-    /// # fn openpty(_: &str, _: u32, _: u32, _: u32, _:u32, _:&[u8]) -> Pty {
-    /// #     Pty {}
-    /// # }
     /// ```
-    pub fn on_channel_pty_request<H>(&mut self, handler: H)
+    pub fn on_global_request<H>(&mut self, handler: H)
     where
-        H: ChannelRequestPtyHandler<Pty, Error = E> + 'static,
+        H: GlobalRequestHandler<Error = E> + 'static,
     {
-        self.channel_pty_request = Some(Box::new(handler))
+        self.global_request = Some(Box::new(handler))
     }
 
-    /// Register Shell channel handler.
+    /// Register handler called when the client sent disconnect.
     ///
-    /// If not registered, channel returns failure.
+    /// Called with its reason and description before the connection stops.
     ///
     /// # Example
     ///
@@ -538,87 +2046,89 @@ where
     /// use ssssh::Handlers;
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_channel_shell(|mut ctx: ssssh::SessionContext<_>| {
+    /// handlers.on_disconnected(|reason, description| {
     ///     async move {
-    ///         let (stdin, stdout, stderr) = ctx.take_stdio().unwrap();
-    ///         let process = do_exec_shell(stdin, stdout, stderr);
-    ///         let exit_code = process.await;
-    ///         Ok(exit_code)
+    ///         println!("{:?} {}", reason, description);
+    ///         Ok(())
     ///     }.boxed()
     /// });
-    /// # use ssssh::{SshInput, SshOutput};
-    /// # async fn do_exec_shell(_: SshInput, _: SshOutput, _:SshOutput) -> u32 {
-    /// #     0
-    /// # }
     /// ```
-    pub fn on_channel_shell<H>(&mut self, handler: H)
+    pub fn on_disconnected<H>(&mut self, handler: H)
     where
-        H: ChannelShellHandler<Pty, Error = E> + 'static,
+        H: DisconnectedHandler<Error = E> + 'static,
     {
-        self.channel_shell = Some(Box::new(handler))
+        self.disconnected = Some(Box::new(handler))
     }
 
-    /// Register Exec channel handler.
+    /// Register handler called once the first key exchange completed.
     ///
-    /// If not registered, channel returns failure.
+    /// Called with the client's version line and the negotiated algorithms
+    /// before any service request is handled. Returning an error stops the
+    /// connection, e.g. to refuse clients that negotiated weak algorithms.
     ///
     /// # Example
     ///
     /// ```
-    /// use ssssh::Handlers;
+    /// use ssssh::{Handlers, NegotiatedAlgorithms};
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_channel_exec(|mut ctx: ssssh::SessionContext<_>, prog| {
+    /// handlers.on_handshake_complete(|client_version, algorithms: NegotiatedAlgorithms| {
     ///     async move {
-    ///         let (stdin, stdout, stderr) = ctx.take_stdio().unwrap();
-    ///         let process = do_exec(prog, stdin, stdout, stderr);
-    ///         let exit_code = process.await;
-    ///         Ok(exit_code)
+    ///         println!(
+    ///             "{} {} {}/{}",
+    ///             client_version,
+    ///             algorithms.kex_algorithm().as_ref(),
+    ///             algorithms.cipher_algorithm_c2s().as_ref(),
+    ///             algorithms.mac_algorithm_c2s().as_ref(),
+    ///         );
+    ///         Ok(())
     ///     }.boxed()
     /// });
-    /// # use ssssh::{SshInput, SshOutput};
-    /// # async fn do_exec(_: std::ffi::OsString, _: SshInput, _: SshOutput, _:SshOutput) -> u32 {
-    /// #     0
-    /// # }
     /// ```
-    pub fn on_channel_exec<H>(&mut self, handler: H)
+    pub fn on_handshake_complete<H>(&mut self, handler: H)
     where
-        H: ChannelExecHandler<Pty, Error = E> + 'static,
+        H: HandshakeCompleteHandler<Error = E> + 'static,
     {
-        self.channel_exec = Some(Box::new(handler))
+        self.handshake_complete = Some(Box::new(handler))
     }
 
-    /// Register Direct TCP/IP channel handler.
+    /// Register handler deciding whether to accept a service other than
+    /// `ssh-userauth`.
     ///
-    /// If not registered, channel returns failure.
+    /// Accepted services are answered with SSH_MSG_SERVICE_ACCEPT, handling
+    /// what follows is up to the embedder. Without a handler, or if it
+    /// returns `false`, the connection is closed with
+    /// `SSH_DISCONNECT_SERVICE_NOT_AVAILABLE`. `ssh-connection` is never
+    /// offered, it is only available through authentication.
     ///
     /// # Example
     ///
     /// ```
     /// use ssssh::Handlers;
-    /// use futures::FutureExt as _;
+    /// use futures::future::{ok, FutureExt as _};
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_channel_direct_tcpip(|input, output| {
-    ///     async move {
-    ///         do_proxy(input, output).await;
-    ///         Ok(())
-    ///     }.boxed()
-    /// });
-    /// # use ssssh::{SshInput, SshOutput};
-    /// # async fn do_proxy(_: SshInput, _: SshOutput) {
-    /// # }
+    /// handlers.on_service_request(|name| ok(name == "example@example.com").boxed());
     /// ```
-    pub fn on_channel_direct_tcpip<H>(&mut self, handler: H)
+    pub fn on_service_request<H>(&mut self, handler: H)
     where
-        H: ChannelDirectTcpIpHandler<Error = E> + 'static,
+        H: ServiceRequestHandler<Error = E> + 'static,
     {
-        self.channel_direct_tcpip = Some(Box::new(handler))
+        self.service_request = Some(Box::new(handler))
+    }
+
+    pub(crate) fn dispatch_auth_methods(
+        &mut self,
+        username: String,
+    ) -> Option<BoxFuture<'static, Result<Vec<AuthMethod>, E>>> {
+        self.auth_methods
+            .as_mut()
+            .map(|handler| handler.handle(username))
     }
 
     pub(crate) fn dispatch_auth_none(
         &mut self,
         username: String,
-    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+    ) -> Option<BoxFuture<'static, Result<AuthResult, E>>> {
         self.auth_none
             .as_mut()
             .map(|handler| handler.handle(username))
@@ -628,7 +2138,7 @@ where
         &mut self,
         username: String,
         publickey: PublicKey,
-    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+    ) -> Option<BoxFuture<'static, Result<AuthResult, E>>> {
         self.auth_publickey
             .as_mut()
             .map(|handler| handler.handle(username, publickey))
@@ -638,7 +2148,7 @@ where
         &mut self,
         username: String,
         publickey: PublicKey,
-    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+    ) -> Option<BoxFuture<'static, Result<AuthResult, E>>> {
         self.auth_publickey_signature_verified_after_accepted
             .as_mut()
             .map(|handler| handler.handle(username, publickey))
@@ -676,6 +2186,30 @@ where
             .map(|handler| handler.handle(username, hostname, publickey))
     }
 
+    pub(crate) fn keyboard_interactive_enabled(&self) -> bool {
+        self.auth_keyboard_interactive_start.is_some()
+            && self.auth_keyboard_interactive_response.is_some()
+    }
+
+    pub(crate) fn dispatch_auth_keyboard_interactive_start(
+        &mut self,
+        username: String,
+    ) -> Option<BoxFuture<'static, Result<InfoRequest, E>>> {
+        self.auth_keyboard_interactive_start
+            .as_mut()
+            .map(|handler| handler.handle(username))
+    }
+
+    pub(crate) fn dispatch_auth_keyboard_interactive_response(
+        &mut self,
+        username: String,
+        responses: Vec<String>,
+    ) -> Option<BoxFuture<'static, Result<KeyboardInteractiveResult, E>>> {
+        self.auth_keyboard_interactive_response
+            .as_mut()
+            .map(|handler| handler.handle(username, responses))
+    }
+
     pub(crate) fn dispatch_channel_pty_req(
         &mut self,
         term: String,
@@ -683,44 +2217,94 @@ where
         height: u32,
         width_px: u32,
         height_px: u32,
-        modes: Vec<u8>,
+        modes: Vec<(u8, u32)>,
     ) -> Option<BoxFuture<'static, Result<Pty, E>>> {
         self.channel_pty_request
             .as_mut()
             .map(|handler| handler.handle(term, width, height, width_px, height_px, modes))
     }
 
+    pub(crate) fn dispatch_channel_env(
+        &mut self,
+        channel: u32,
+        name: String,
+        value: Bytes,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.channel_env
+            .as_mut()
+            .map(|handler| handler.handle(channel, name, value))
+    }
+
+    pub(crate) fn dispatch_channel_x11_request(
+        &mut self,
+        channel: u32,
+        request: X11Request,
+        handle: GlobalHandle,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.channel_x11_request
+            .as_mut()
+            .map(|handler| handler.handle(channel, request, handle))
+    }
+
+    pub(crate) fn dispatch_channel_agent_forward_request(
+        &mut self,
+        channel: u32,
+        handle: GlobalHandle,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.channel_agent_forward_request
+            .as_mut()
+            .map(|handler| handler.handle(channel, handle))
+    }
+
+    pub(crate) fn dispatch_channel_signal(
+        &mut self,
+        channel: u32,
+        signal: Signal,
+        handle: GlobalHandle,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.channel_signal
+            .as_mut()
+            .map(|handler| handler.handle(channel, signal, handle))
+    }
+
     pub(crate) fn dispatch_channel_shell(
         &mut self,
-        stdin: SshInput,
-        stdout: SshOutput,
-        stderr: SshOutput,
-        env: HashMap<String, String>,
-        pty: Option<Pty>,
+        ctx: SessionContext<Pty>,
     ) -> Option<BoxFuture<'static, Result<u32, E>>> {
-        if let Some(handler) = &mut self.channel_shell {
-            let ctx = SessionContext::new(stdin, stdout, stderr, env, pty);
-            Some(handler.handle(ctx))
-        } else {
-            None
-        }
+        self.channel_shell
+            .as_mut()
+            .map(|handler| handler.handle(ctx))
     }
 
     pub(crate) fn dispatch_channel_exec(
         &mut self,
-        stdin: SshInput,
-        stdout: SshOutput,
-        stderr: SshOutput,
+        ctx: SessionContext<Pty>,
         prog: OsString,
-        env: HashMap<String, String>,
-        pty: Option<Pty>,
     ) -> Option<BoxFuture<'static, Result<u32, E>>> {
-        if let Some(handler) = &mut self.channel_exec {
-            let ctx = SessionContext::new(stdin, stdout, stderr, env, pty);
-            Some(handler.handle(ctx, prog))
-        } else {
-            None
-        }
+        self.channel_exec
+            .as_mut()
+            .map(|handler| handler.handle(ctx, prog))
+    }
+
+    pub(crate) fn dispatch_channel_subsystem(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        name: String,
+    ) -> Option<BoxFuture<'static, Result<u32, E>>> {
+        self.channel_subsystem
+            .as_mut()
+            .map(|handler| handler.handle(ctx, name))
+    }
+
+    pub(crate) fn dispatch_channel_extended_data(
+        &mut self,
+        channel: u32,
+        data_type: u32,
+        data: Bytes,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.channel_extended_data
+            .as_mut()
+            .map(|handler| handler.handle(channel, data_type, data))
     }
 
     pub(crate) fn dispatch_direct_tcpip(
@@ -732,6 +2316,97 @@ where
             .as_mut()
             .map(|handler| handler.handle(ingress, egress))
     }
+
+    pub(crate) fn dispatch_tcpip_forward(
+        &mut self,
+        address: String,
+        port: u16,
+        handle: GlobalHandle,
+    ) -> Option<BoxFuture<'static, Result<Option<u16>, E>>> {
+        self.tcpip_forward
+            .as_mut()
+            .map(|handler| handler.handle(address, port, handle))
+    }
+
+    pub(crate) fn dispatch_cancel_tcpip_forward(
+        &mut self,
+        address: String,
+        port: u16,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.cancel_tcpip_forward
+            .as_mut()
+            .map(|handler| handler.handle(address, port))
+    }
+
+    pub(crate) fn dispatch_direct_streamlocal(
+        &mut self,
+        socket_path: String,
+        ingress: SshInput,
+        egress: SshOutput,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.channel_direct_streamlocal
+            .as_mut()
+            .map(|handler| handler.handle(socket_path, ingress, egress))
+    }
+
+    pub(crate) fn dispatch_streamlocal_forward(
+        &mut self,
+        socket_path: String,
+        handle: GlobalHandle,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.streamlocal_forward
+            .as_mut()
+            .map(|handler| handler.handle(socket_path, handle))
+    }
+
+    pub(crate) fn dispatch_cancel_streamlocal_forward(
+        &mut self,
+        socket_path: String,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.cancel_streamlocal_forward
+            .as_mut()
+            .map(|handler| handler.handle(socket_path))
+    }
+
+    pub(crate) fn dispatch_global_request(
+        &mut self,
+        name: String,
+        data: Bytes,
+        handle: GlobalHandle,
+    ) -> Option<BoxFuture<'static, Result<GlobalResponse, E>>> {
+        self.global_request
+            .as_mut()
+            .map(|handler| handler.handle(name, data, handle))
+    }
+
+    pub(crate) fn dispatch_disconnected(
+        &mut self,
+        reason: DisconnectReason,
+        description: String,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.disconnected
+            .as_mut()
+            .map(|handler| handler.handle(reason, description))
+    }
+
+    pub(crate) fn dispatch_handshake_complete(
+        &mut self,
+        client_version: String,
+        algorithms: NegotiatedAlgorithms,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.handshake_complete
+            .as_mut()
+            .map(|handler| handler.handle(client_version, algorithms))
+    }
+
+    pub(crate) fn dispatch_service_request(
+        &mut self,
+        name: String,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.service_request
+            .as_mut()
+            .map(|handler| handler.handle(name))
+    }
 }
 
 impl<E, Pty> fmt::Debug for Handlers<E, Pty>
@@ -742,3 +2417,75 @@ where
         write!(f, "Handlers")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct PanicError;
+
+    impl fmt::Display for PanicError {
+        fn fmt(&self, _: &mut fmt::Formatter<'_>) -> fmt::Result {
+            panic!("boom")
+        }
+    }
+
+    impl StdError for PanicError {}
+
+    #[test]
+    fn test_sanitize_panic() {
+        let err = sanitize(PanicError, DEFAULT_ERROR_LIMIT, &Observer::default());
+        assert_eq!(err.to_string(), "<handler error formatting panicked>");
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_sanitize_huge() {
+        let message = "x".repeat(10 * 1024 * 1024);
+        let err = sanitize(message, DEFAULT_ERROR_LIMIT, &Observer::default());
+        let s = err.to_string();
+        assert_eq!(s.len(), DEFAULT_ERROR_LIMIT + 3);
+        assert!(s.ends_with("..."));
+        assert!(format!("{:?}", err).len() < DEFAULT_ERROR_LIMIT * 2);
+    }
+
+    #[test]
+    fn test_sanitize_utf8_boundary() {
+        let err = sanitize("\u{3042}".repeat(10), 4, &Observer::default());
+        assert_eq!(err.to_string(), "\u{3042}...");
+    }
+
+    #[test]
+    fn test_sanitize_observed() {
+        use crate::ConnectionObserver;
+
+        #[derive(Default)]
+        struct Faults(Mutex<Vec<HandlerErrorFault>>);
+
+        impl ConnectionObserver for Faults {
+            fn on_handler_error_fault(&self, fault: HandlerErrorFault) {
+                self.0.lock().unwrap().push(fault);
+            }
+        }
+
+        let faults = Arc::new(Faults::default());
+        let observer = Observer::new(faults.clone());
+        sanitize(PanicError, DEFAULT_ERROR_LIMIT, &observer);
+        sanitize("x".repeat(10), 4, &observer);
+        sanitize("short", DEFAULT_ERROR_LIMIT, &observer);
+        assert_eq!(
+            *faults.0.lock().unwrap(),
+            [
+                HandlerErrorFault::FormattingPanicked,
+                HandlerErrorFault::Truncated
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sanitize_short() {
+        let err = sanitize("short", DEFAULT_ERROR_LIMIT, &Observer::default());
+        assert_eq!(err.to_string(), "short");
+    }
+}
@@ -1,6 +1,7 @@
 //! Hostkey
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 
 use bytes::{Buf, Bytes};
 use futures::future::{ok, ready};
@@ -19,6 +20,7 @@ use crate::SshError;
 enum BuilderOperation {
     LoadFromFile(PathBuf),
     Generate,
+    Add(HostKey),
 }
 
 #[derive(Debug, Default)]
@@ -38,22 +40,54 @@ impl HostKeysBuilder {
         self
     }
 
+    pub(crate) fn add(&mut self, hostkey: HostKey) -> &mut Self {
+        self.operations.push(BuilderOperation::Add(hostkey));
+        self
+    }
+
     pub(crate) async fn build(&self) -> Result<HostKeys, SshError> {
         let mut hostkeys = HostKeys::new();
         for op in &self.operations {
             match op {
                 BuilderOperation::LoadFromFile(path) => hostkeys.load(path).await?,
                 BuilderOperation::Generate => hostkeys.generate()?,
+                BuilderOperation::Add(hostkey) => hostkeys.add(hostkey.0.clone()),
             }
         }
         Ok(hostkeys)
     }
 }
 
+/// Host key held by the caller, e.g. to reuse across servers.
+#[derive(Clone)]
+pub struct HostKey(Arc<Key>);
+
+impl HostKey {
+    /// Generate a new key of type `algorithm`.
+    pub fn generate(algorithm: &Algorithm) -> Result<Self, SshError> {
+        Ok(Self(Arc::new(Key::gen(algorithm)?)))
+    }
+
+    /// Key type.
+    pub fn algorithm(&self) -> Algorithm {
+        self.0.name()
+    }
+
+    pub fn publickey(&self) -> PublicKey {
+        self.0.publickey()
+    }
+}
+
+impl std::fmt::Debug for HostKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("HostKey").field(&self.algorithm()).finish()
+    }
+}
+
 /// HostKey collection
 #[derive(Debug)]
 pub(crate) struct HostKeys {
-    hostkeys: LinkedHashMap<Algorithm, Key>,
+    hostkeys: LinkedHashMap<Algorithm, Arc<Key>>,
 }
 
 impl HostKeys {
@@ -64,19 +98,40 @@ impl HostKeys {
     }
 
     pub(crate) fn insert(&mut self, hostkey: Key) {
+        self.add(Arc::new(hostkey));
+    }
+
+    fn add(&mut self, hostkey: Arc<Key>) {
         self.hostkeys.insert(hostkey.name(), hostkey);
     }
 
+    /// Key signing with signature algorithm `name`.
     pub(crate) fn lookup(&self, name: &Algorithm) -> Option<&Key> {
-        self.hostkeys.get(name)
+        self.hostkeys.get(&name.key_algorithm()).map(AsRef::as_ref)
+    }
+
+    /// Key whose public key blob is `blob`.
+    pub(crate) fn lookup_blob(&self, blob: &[u8]) -> Option<&Key> {
+        self.keys().find(|key| key.publickey().blob() == blob)
     }
 
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.hostkeys.values().map(AsRef::as_ref)
+    }
+
+    /// Signature algorithms of all keys, `ssh-rsa` keys as `rsa-sha2-*` first.
     pub(crate) fn names(&self) -> Vec<Algorithm> {
-        self.hostkeys.keys().cloned().collect()
+        self.hostkeys
+            .keys()
+            .flat_map(Algorithm::signature_algorithms)
+            .collect()
     }
 
     pub(crate) fn generate(&mut self) -> Result<(), SshError> {
         for name in &Algorithm::defaults() {
+            if name != &name.key_algorithm() {
+                continue;
+            }
             let hostkey = Key::gen(name)?;
             self.insert(hostkey);
         }
@@ -109,15 +164,18 @@ impl HostKeys {
         if data.len() < AUTH_MAGIC.len() {
             return Err(SshError::UnsupportedKeyFileFormat);
         }
-        let auth_magic = (&mut data).copy_to_bytes(AUTH_MAGIC.len());
+        let auth_magic = data.copy_to_bytes(AUTH_MAGIC.len());
         if auth_magic != AUTH_MAGIC {
             return Err(SshError::UnsupportedKeyFileFormat);
         }
 
         let cipher = String::unpack(&mut data)?;
+        if cipher != "none" {
+            return Err(SshError::EncryptedKeyFile(cipher));
+        }
         let kdf_name = String::unpack(&mut data)?;
         let kdf = String::unpack(&mut data)?;
-        if (cipher.as_str(), kdf_name.as_str(), kdf.as_str()) != ("none", "none", "") {
+        if (kdf_name.as_str(), kdf.as_str()) != ("none", "") {
             return Err(SshError::UnsupportedKeyFileFormat);
         }
 
@@ -147,9 +205,90 @@ impl HostKeys {
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn test_add() {
+        let hostkey = HostKey::generate(&Algorithm::RsaSha2_256).unwrap();
+        assert_eq!(hostkey.algorithm(), Algorithm::SshRsa);
+
+        let hostkeys = HostKeysBuilder::default()
+            .add(hostkey.clone())
+            .build()
+            .await
+            .unwrap();
+        let key = hostkeys.lookup(&Algorithm::RsaSha2_512).unwrap();
+        assert_eq!(key.publickey(), hostkey.publickey());
+        assert!(hostkeys.lookup(&Algorithm::SshEd25519).is_none());
+    }
+
     #[tokio::test]
     async fn incorrect_host_key() {
         let mut hostkeys = HostKeys::new();
         hostkeys.load("Cargo.toml").await.unwrap_err();
     }
+
+    fn keygen(name: &str, passphrase: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ssssh-{}-{}", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let status = std::process::Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", passphrase, "-f"])
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        path
+    }
+
+    #[tokio::test]
+    async fn ssh_keygen_round_trip() {
+        let path = keygen("roundtrip", "");
+        let mut hostkeys = HostKeys::new();
+        hostkeys.load(&path).await.unwrap();
+        let key = hostkeys.lookup(&Algorithm::SshEd25519).unwrap();
+
+        let pub_file = std::fs::read_to_string(path.with_extension("pub")).unwrap();
+        let expected = pub_file.split_whitespace().nth(1).unwrap();
+        assert_eq!(key.publickey(), expected.parse().unwrap());
+
+        let data = Bytes::from_static(b"signed data");
+        let signature = key.sign(&Algorithm::SshEd25519, &data);
+        let mut verifier = key.publickey().verifier().unwrap();
+        crate::pack::Put::put(&mut verifier, &data);
+        assert!(verifier.verify(&signature));
+
+        std::fs::remove_file(path.with_extension("pub")).ok();
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn encrypted_key_file() {
+        let path = keygen("encrypted", "passphrase");
+        let mut hostkeys = HostKeys::new();
+        let err = hostkeys.load(&path).await.unwrap_err();
+        assert!(matches!(err, SshError::EncryptedKeyFile(cipher) if cipher == "aes256-ctr"));
+
+        std::fs::remove_file(path.with_extension("pub")).ok();
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn rsa_signature_algorithms() {
+        let mut hostkeys = HostKeys::new();
+        hostkeys.load("tests/rsa").await.unwrap();
+        assert_eq!(
+            hostkeys.names(),
+            vec![
+                Algorithm::RsaSha2_512,
+                Algorithm::RsaSha2_256,
+                Algorithm::SshRsa
+            ]
+        );
+        for name in hostkeys.names() {
+            assert_eq!(hostkeys.lookup(&name).unwrap().name(), Algorithm::SshRsa);
+        }
+        assert!(hostkeys.lookup(&Algorithm::SshEd25519).is_none());
+
+        hostkeys.generate().unwrap();
+        assert_eq!(hostkeys.hostkeys.len(), 2);
+        assert_eq!(hostkeys.names().len(), 4);
+    }
 }
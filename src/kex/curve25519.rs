@@ -6,6 +6,7 @@ use ring::rand::SystemRandom;
 use tokio_stream::StreamExt as _;
 
 use crate::msg::kex_ecdh_reply::KexEcdhReply;
+use crate::msg::MsgName as _;
 use crate::pack::{Mpint, Pack};
 
 use super::*;
@@ -26,7 +27,7 @@ impl KexTrait for Curve25519Sha256 {
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, SharedSecret), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
@@ -41,7 +42,7 @@ impl KexTrait for Curve25519Sha256 {
 
             let kex_ecdh_init = match io.next().await {
                 Some(Ok(Msg::KexEcdhInit(msg))) => msg,
-                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(format!("{:?}", msg))),
+                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(msg.name().into())),
                 Some(Err(e)) => return Err(e),
                 None => return Err(SshError::KexUnexpectedEof),
             };
@@ -65,7 +66,7 @@ impl KexTrait for Curve25519Sha256 {
 
             let hash = hasher.finish();
 
-            let signature = env.hostkey.sign(&hash);
+            let signature = env.hostkey.sign(env.hostkey_algorithm, &hash);
 
             let mut server_ephemeral_public_key = server_ephemeral_public_key.as_ref();
             let kex_ecdh_reply = KexEcdhReply::new(
@@ -76,7 +77,7 @@ impl KexTrait for Curve25519Sha256 {
 
             io.send(kex_ecdh_reply.into()).await?;
 
-            Ok((hash, key))
+            Ok((hash, SharedSecret::new(key)))
         }
         .boxed()
     }
@@ -99,13 +100,7 @@ mod tests {
             t
         }
 
-        let io = tokio::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/null")
-            .await
-            .unwrap();
-        let io = tokio::io::BufStream::new(io);
+        let (io, _peer) = crate::test_support::duplex_pair();
         let mut io = crate::stream::msg::MsgStream::new(io);
 
         let hostkey = crate::key::Key::gen(&crate::key::Algorithm::SshRsa).unwrap();
@@ -114,12 +109,14 @@ mod tests {
             .build()
             .await
             .unwrap()
-            .to_kexinit();
+            .to_kexinit()
+            .unwrap();
         let s_kexinit = crate::preference::PreferenceBuilder::default()
             .build()
             .await
             .unwrap()
-            .to_kexinit();
+            .to_kexinit()
+            .unwrap();
 
         let kex = assert(Curve25519Sha256::new());
         let env = Env {
@@ -128,7 +125,8 @@ mod tests {
             c_kexinit: &to_msg_bytes(&c_kexinit),
             s_kexinit: &to_msg_bytes(&s_kexinit),
             hostkey: &hostkey,
+            hostkey_algorithm: &crate::key::Algorithm::RsaSha2_512,
         };
-        assert(kex.kex(&mut io, env));
+        drop(assert(kex.kex(&mut io, env)));
     }
 }
@@ -0,0 +1,108 @@
+//! Key exchanges registered by applications.
+use std::fmt;
+
+use bytes::Bytes;
+use futures::future::FutureExt as _;
+use futures::sink::SinkExt as _;
+use tokio_stream::StreamExt as _;
+
+use crate::msg::kex_ecdh_reply::KexEcdhReply;
+use crate::msg::MsgName as _;
+use crate::negotiate::Registered;
+use crate::pack::{Mpint, Pack};
+
+use super::*;
+
+/// Hash of a registered key exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KexHash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// Creates [`CustomKex`] instances for a registered kex name.
+///
+/// Exchanges run like `curve25519-sha256`
+/// ([rfc5656](https://tools.ietf.org/html/rfc5656#section-4)): the client's
+/// public value arrives in `SSH_MSG_KEX_ECDH_INIT`, ours is answered in
+/// `SSH_MSG_KEX_ECDH_REPLY`, and the shared secret is hashed as mpint.
+pub trait KexFactory: fmt::Debug + Send + Sync + 'static {
+    /// Hash of the exchange hash and key derivation.
+    fn hash(&self) -> KexHash;
+
+    /// Instance with a fresh ephemeral key, for one exchange.
+    fn create(&self) -> Result<Box<dyn CustomKex>, SshError>;
+}
+
+/// Ephemeral server side of one exchange.
+pub trait CustomKex: fmt::Debug + Send {
+    /// Our public value sent to the client.
+    fn public_key(&self) -> Bytes;
+
+    /// Shared secret with the client's public value. Fails on invalid input.
+    fn agree(self: Box<Self>, client_public_key: &[u8]) -> Result<Bytes, SshError>;
+}
+
+/// Exchange through a registered factory.
+#[derive(Debug)]
+pub(crate) struct Custom(Registered<dyn KexFactory>);
+
+impl Custom {
+    pub(super) fn new(registered: Registered<dyn KexFactory>) -> Self {
+        Self(registered)
+    }
+
+    pub(super) fn hasher(&self) -> Hasher {
+        match self.0.factory().hash() {
+            KexHash::Sha1 => Hasher::sha1(),
+            KexHash::Sha256 => Hasher::sha256(),
+            KexHash::Sha512 => Hasher::sha512(),
+        }
+    }
+
+    pub(super) fn kex<'a, IO>(
+        &'a self,
+        io: &'a mut MsgStream<IO>,
+        env: Env<'a>,
+    ) -> BoxFuture<'a, Result<(Bytes, SharedSecret), SshError>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        async move {
+            let mut hasher = self.hasher();
+
+            env.c_version.pack(&mut hasher);
+            env.s_version.pack(&mut hasher);
+            env.c_kexinit.pack(&mut hasher);
+            env.s_kexinit.pack(&mut hasher);
+            env.hostkey.publickey().pack(&mut hasher);
+
+            let kex_ecdh_init = match io.next().await {
+                Some(Ok(Msg::KexEcdhInit(msg))) => msg,
+                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(msg.name().into())),
+                Some(Err(e)) => return Err(e),
+                None => return Err(SshError::KexUnexpectedEof),
+            };
+            let client_public_key = kex_ecdh_init.ephemeral_public_key();
+            client_public_key.pack(&mut hasher);
+
+            let ephemeral = self.0.factory().create()?;
+            let server_public_key = ephemeral.public_key();
+            server_public_key.pack(&mut hasher);
+
+            let key = ephemeral.agree(client_public_key)?;
+            Mpint::new(key.clone()).pack(&mut hasher);
+
+            let hash = hasher.finish();
+
+            let signature = env.hostkey.sign(env.hostkey_algorithm, &hash);
+            let kex_ecdh_reply =
+                KexEcdhReply::new(env.hostkey.publickey(), server_public_key, signature);
+            io.send(kex_ecdh_reply.into()).await?;
+
+            Ok((hash, SharedSecret::new(key)))
+        }
+        .boxed()
+    }
+}
@@ -9,7 +9,7 @@ use tokio_stream::StreamExt as _;
 use crate::msg::kex_dh_gex_group::KexDhGexGroup;
 use crate::msg::kex_dh_gex_reply::KexDhGexReply;
 use crate::msg::kex_ecdh_reply::KexEcdhReply;
-use crate::msg::GexMsg;
+use crate::msg::{GexMsg, MsgName as _};
 use crate::pack::{Mpint, Pack};
 
 use super::*;
@@ -104,7 +104,7 @@ where
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, SharedSecret), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
@@ -119,7 +119,7 @@ where
             // FIXME use kexdh_init
             let kexdh_init = match io.next().await {
                 Some(Ok(Msg::KexEcdhInit(msg))) => msg,
-                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(format!("{:?}", msg))),
+                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(msg.name().into())),
                 Some(Err(e)) => return Err(e),
                 None => return Err(SshError::KexUnexpectedEof),
             };
@@ -142,13 +142,13 @@ where
 
             let h = hasher.finish();
 
-            let signature = env.hostkey.sign(&h);
+            let signature = env.hostkey.sign(env.hostkey_algorithm, &h);
 
             let reply = KexEcdhReply::new(env.hostkey.publickey(), f, signature);
 
             io.send(reply.into()).await?;
 
-            Ok((h, k))
+            Ok((h, SharedSecret::new(k)))
         }
         .boxed()
     }
@@ -178,6 +178,23 @@ fn gen_y() -> Result<BigNum, SshError> {
     Ok(y)
 }
 
+/// Group sizes in bits offered for group exchange. RFC 3526 safe primes.
+const GROUP_BITS: &[u32] = &[2048, 3072, 4096];
+
+/// Range assumed for `SSH_MSG_KEX_DH_GEX_REQUEST_OLD`.
+const GROUP_MIN: u32 = 2048;
+const GROUP_MAX: u32 = 8192;
+
+/// Smallest group of at least `n` bits within `min..=max`, else the largest within.
+fn choose_group(min: u32, n: u32, max: u32) -> Option<u32> {
+    let mut candidates = GROUP_BITS.iter().filter(|bits| (min..=max).contains(*bits));
+    candidates
+        .clone()
+        .find(|bits| **bits >= n)
+        .or_else(|| candidates.next_back())
+        .cloned()
+}
+
 #[derive(Debug)]
 pub(crate) struct DiffieHellmanGroupExchange<H> {
     _phantom: PhantomData<H>,
@@ -202,7 +219,7 @@ where
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, SharedSecret), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
@@ -216,40 +233,27 @@ where
             env.s_kexinit.pack(&mut hasher);
             env.hostkey.publickey().pack(&mut hasher);
 
-            let range = match io.next().await {
+            let (min, n, max) = match io.next().await {
                 Some(Ok(GexMsg::KexDhGexRequestOld(msg))) => {
                     msg.n().pack(&mut hasher);
-                    *msg.n()..=*msg.n()
+                    (GROUP_MIN, *msg.n(), GROUP_MAX)
                 }
                 Some(Ok(GexMsg::KexDhGexRequest(msg))) => {
                     msg.min().pack(&mut hasher);
                     msg.n().pack(&mut hasher);
                     msg.max().pack(&mut hasher);
-                    *msg.min()..=*msg.max()
+                    (*msg.min(), *msg.n(), *msg.max())
                 }
-                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(format!("{:?}", msg))),
+                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(msg.name().into())),
                 Some(Err(e)) => return Err(e),
                 None => return Err(SshError::KexUnexpectedEof),
             };
 
-            let p = if range.contains(&8192) {
-                BigNum::get_rfc3526_prime_8192()
-            } else if range.contains(&6144) {
-                BigNum::get_rfc3526_prime_6144()
-            } else if range.contains(&4096) {
-                BigNum::get_rfc3526_prime_4096()
-            } else if range.contains(&3072) {
-                BigNum::get_rfc3526_prime_3072()
-            } else if range.contains(&2048) {
-                BigNum::get_rfc3526_prime_2048()
-            } else if range.contains(&1536) {
-                BigNum::get_rfc3526_prime_1536()
-            } else if range.contains(&1024) {
-                BigNum::get_rfc2409_prime_1024()
-            } else if range.contains(&768) {
-                BigNum::get_rfc2409_prime_768()
-            } else {
-                todo!()
+            let p = match choose_group(min, n, max) {
+                Some(2048) => BigNum::get_rfc3526_prime_2048(),
+                Some(3072) => BigNum::get_rfc3526_prime_3072(),
+                Some(4096) => BigNum::get_rfc3526_prime_4096(),
+                _ => return Err(SshError::KexGroupNotFound(min, max)),
             }
             .map_err(SshError::kex_error)?;
             Mpint::new(p.to_vec()).pack(&mut hasher);
@@ -262,7 +266,7 @@ where
 
             let kex_dh_gex_init = match io.next().await {
                 Some(Ok(GexMsg::KexDhGexInit(msg))) => msg,
-                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(format!("{:?}", msg))),
+                Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(msg.name().into())),
                 Some(Err(e)) => return Err(e),
                 None => return Err(SshError::KexUnexpectedEof),
             };
@@ -283,12 +287,12 @@ where
 
             let h = hasher.finish();
 
-            let signature = env.hostkey.sign(&h);
+            let signature = env.hostkey.sign(env.hostkey_algorithm, &h);
 
             let reply = KexDhGexReply::new(env.hostkey.publickey(), f, signature);
             io.send(reply.into()).await?;
 
-            Ok((h, k))
+            Ok((h, SharedSecret::new(k)))
         }
         .boxed()
     }
@@ -298,19 +302,24 @@ where
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_choose_group() {
+        assert_eq!(choose_group(1024, 2048, 8192), Some(2048));
+        assert_eq!(choose_group(1024, 3000, 8192), Some(3072));
+        assert_eq!(choose_group(2048, 7680, 8192), Some(4096));
+        assert_eq!(choose_group(1024, 1024, 3072), Some(2048));
+        assert_eq!(choose_group(3072, 3072, 3072), Some(3072));
+        assert_eq!(choose_group(1024, 1024, 1536), None);
+        assert_eq!(choose_group(4096, 2048, 2048), None);
+    }
+
     #[tokio::test]
     async fn test_kex_send() {
         fn assert<T: Send>(t: T) -> T {
             t
         }
 
-        let io = tokio::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/null")
-            .await
-            .unwrap();
-        let io = tokio::io::BufStream::new(io);
+        let (io, _peer) = crate::test_support::duplex_pair();
         let mut io = crate::stream::msg::MsgStream::new(io);
 
         let hostkey = crate::key::Key::gen(&crate::key::Algorithm::SshRsa).unwrap();
@@ -319,12 +328,14 @@ mod tests {
             .build()
             .await
             .unwrap()
-            .to_kexinit();
+            .to_kexinit()
+            .unwrap();
         let s_kexinit = crate::preference::PreferenceBuilder::default()
             .build()
             .await
             .unwrap()
-            .to_kexinit();
+            .to_kexinit()
+            .unwrap();
 
         let kex = assert(DiffieHellmanGroup14Sha1::new());
         let env = Env {
@@ -333,7 +344,8 @@ mod tests {
             c_kexinit: &to_msg_bytes(&c_kexinit),
             s_kexinit: &to_msg_bytes(&s_kexinit),
             hostkey: &hostkey,
+            hostkey_algorithm: &crate::key::Algorithm::RsaSha2_512,
         };
-        assert(kex.kex(&mut io, env));
+        drop(assert(kex.kex(&mut io, env)));
     }
 }
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::str::FromStr;
 
 use bytes::{Buf, Bytes, BytesMut};
@@ -5,17 +6,31 @@ use futures::future::BoxFuture;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::hash::Hasher;
-use crate::key::Key;
+use crate::key::{self, Key};
 use crate::msg::kexinit::Kexinit;
 use crate::msg::Msg;
-use crate::negotiate::{AlgorithmName, UnknownNameError};
+use crate::negotiate::{AlgorithmName, Registered, UnknownNameError};
 use crate::pack::Pack;
 use crate::stream::msg::MsgStream;
 use crate::SshError;
 
+pub use custom::{CustomKex, KexFactory, KexHash};
+
 mod curve25519;
+mod custom;
 mod diffie_hellman;
 
+/// Strict KEX marker advertised by the server, not a real kex algorithm.
+///
+/// [kex-strict](https://github.com/openssh/openssh-portable/blob/master/PROTOCOL)
+pub(crate) const STRICT_SERVER: &str = "kex-strict-s-v00@openssh.com";
+
+/// Strict KEX marker advertised by the client.
+pub(crate) const STRICT_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+
+/// Client accepts [EXT_INFO](https://tools.ietf.org/html/rfc8308).
+pub(crate) const EXT_INFO_CLIENT: &str = "ext-info-c";
+
 /// SSH key exchange algorithms.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Algorithm {
@@ -42,6 +57,9 @@ pub enum Algorithm {
 
     /// `diffie-hellman-group-exchange-sha256`
     DiffieHellmanGroupExchangeSha256,
+
+    /// Registered by application.
+    Custom(Registered<dyn KexFactory>),
 }
 
 impl AsRef<str> for Algorithm {
@@ -55,6 +73,7 @@ impl AsRef<str> for Algorithm {
             Self::DiffieHellmanGroup18Sha512 => "diffie-hellman-group18-sha512",
             Self::DiffieHellmanGroupExchangeSha1 => "diffie-hellman-group-exchange-sha1",
             Self::DiffieHellmanGroupExchangeSha256 => "diffie-hellman-group-exchange-sha256",
+            Self::Custom(item) => item.name(),
         }
     }
 }
@@ -99,6 +118,29 @@ struct Env<'a> {
     c_kexinit: &'a Bytes,
     s_kexinit: &'a Bytes,
     hostkey: &'a Key,
+    /// Negotiated host key signature algorithm.
+    hostkey_algorithm: &'a key::Algorithm,
+}
+
+/// Shared secret K of a key exchange. Never printed.
+pub(crate) struct SharedSecret(Bytes);
+
+impl SharedSecret {
+    fn new(secret: Bytes) -> Self {
+        Self(secret)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedSecret")
+            .field(&format_args!("<redacted>"))
+            .finish()
+    }
 }
 
 trait KexTrait: Sized {
@@ -110,7 +152,7 @@ trait KexTrait: Sized {
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, SharedSecret), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send;
 }
@@ -132,6 +174,7 @@ pub(crate) enum Kex {
     DiffieHellmanGroup18Sha512(diffie_hellman::DiffieHellmanGroup18Sha512),
     DiffieHellmanGroupExchangeSha1(diffie_hellman::DiffieHellmanGroupExchangeSha1),
     DiffieHellmanGroupExchangeSha256(diffie_hellman::DiffieHellmanGroupExchangeSha256),
+    Custom(custom::Custom),
 }
 
 impl Kex {
@@ -157,6 +200,7 @@ impl Kex {
             Self::DiffieHellmanGroupExchangeSha256(..) => {
                 diffie_hellman::DiffieHellmanGroupExchangeSha256::hasher()
             }
+            Self::Custom(item) => item.hasher(),
         }
     }
 
@@ -180,9 +224,11 @@ impl Kex {
             Algorithm::DiffieHellmanGroupExchangeSha256 => {
                 Self::DiffieHellmanGroupExchangeSha256(KexTrait::new())
             }
+            Algorithm::Custom(item) => Self::Custom(custom::Custom::new(item.clone())),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn kex<IO>(
         &self,
         io: &mut MsgStream<IO>,
@@ -191,7 +237,8 @@ impl Kex {
         c_kexinit: &Kexinit,
         s_kexinit: &Kexinit,
         hostkey: &Key,
-    ) -> Result<(Bytes, Bytes), SshError>
+        hostkey_algorithm: &key::Algorithm,
+    ) -> Result<(Bytes, SharedSecret), SshError>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
@@ -203,6 +250,7 @@ impl Kex {
             c_kexinit: &c_kexinit,
             s_kexinit: &s_kexinit,
             hostkey,
+            hostkey_algorithm,
         };
 
         Ok(match self {
@@ -214,6 +262,7 @@ impl Kex {
             Self::DiffieHellmanGroup18Sha512(item) => item.kex(io, env).await?,
             Self::DiffieHellmanGroupExchangeSha1(item) => item.kex(io, env).await?,
             Self::DiffieHellmanGroupExchangeSha256(item) => item.kex(io, env).await?,
+            Self::Custom(item) => item.kex(io, env).await?,
         })
     }
 }
@@ -222,9 +271,6 @@ impl Kex {
 mod tests {
     use super::*;
 
-    trait AssertSendSync: Send + Sync + 'static {}
-    impl AssertSendSync for Kex {}
-
     #[test]
     fn test_send() {
         fn assert<T: Send + Sync + 'static>() {}
@@ -232,36 +278,47 @@ mod tests {
         assert::<Kex>();
     }
 
+    #[test]
+    fn test_shared_secret_debug() {
+        let secret = SharedSecret::new(Bytes::from_static(b"top secret"));
+        assert_eq!(format!("{:?}", secret), "SharedSecret(<redacted>)");
+        assert_eq!(&secret.as_bytes()[..], b"top secret");
+    }
+
     #[tokio::test]
     async fn test_kex_send() {
         fn assert<T: Send>(t: T) -> T {
             t
         }
 
-        let io = tokio::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/null")
-            .await
-            .unwrap();
-        let io = tokio::io::BufStream::new(io);
+        let (io, _peer) = crate::test_support::duplex_pair();
         let mut io = crate::stream::msg::MsgStream::new(io);
 
-        let hostkey = Key::gen(&crate::key::Algorithm::SshRsa).unwrap();
+        let hostkey = Key::gen(&key::Algorithm::SshRsa).unwrap();
 
         let c_kexinit = crate::preference::PreferenceBuilder::default()
             .build()
             .await
             .unwrap()
-            .to_kexinit();
+            .to_kexinit()
+            .unwrap();
         let s_kexinit = crate::preference::PreferenceBuilder::default()
             .build()
             .await
             .unwrap()
-            .to_kexinit();
+            .to_kexinit()
+            .unwrap();
 
         let kex = assert(Kex::new(&Algorithm::Curve25519Sha256));
-        let _ = assert(kex.kex(&mut io, "", "", &c_kexinit, &s_kexinit, &hostkey));
+        drop(assert(kex.kex(
+            &mut io,
+            "",
+            "",
+            &c_kexinit,
+            &s_kexinit,
+            &hostkey,
+            &key::Algorithm::RsaSha2_512,
+        )));
     }
 
     #[test]
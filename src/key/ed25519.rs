@@ -6,11 +6,19 @@ use ring::signature::{Ed25519KeyPair, KeyPair as _, UnparsedPublicKey, ED25519};
 
 use super::*;
 
-#[derive(Debug)]
 pub(crate) struct Ed25519 {
     pair: Ed25519KeyPair,
 }
 
+/// Public key only, the private half is never printed.
+impl fmt::Debug for Ed25519 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ed25519")
+            .field("publickey", &self.publickey())
+            .finish()
+    }
+}
+
 impl KeyTrait for Ed25519 {
     const NAME: Algorithm = Algorithm::SshEd25519;
 
@@ -38,7 +46,7 @@ impl KeyTrait for Ed25519 {
         b.freeze()
     }
 
-    fn sign(&self, target: &Bytes) -> Bytes {
+    fn sign(&self, _: &Algorithm, target: &Bytes) -> Bytes {
         let sign = self.pair.sign(target.as_ref());
         let mut sign = sign.as_ref();
         sign.copy_to_bytes(sign.remaining())
@@ -74,8 +82,8 @@ impl VerifierTrait for Ed25519Verifier {
         self.buf.extend_from_slice(data);
     }
 
-    fn verify(&self, signature: &[u8]) -> bool {
-        self.pk.verify(&self.buf, signature).is_ok()
+    fn verify(&self, algorithm: &str, signature: &[u8]) -> bool {
+        algorithm == Self::NAME.as_ref() && self.pk.verify(&self.buf, signature).is_ok()
     }
 }
 
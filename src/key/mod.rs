@@ -25,6 +25,30 @@ pub enum Algorithm {
 
     /// `ssh-rsa`
     SshRsa,
+
+    /// `rsa-sha2-256`, signature algorithm of `ssh-rsa` keys
+    RsaSha2_256,
+
+    /// `rsa-sha2-512`, signature algorithm of `ssh-rsa` keys
+    RsaSha2_512,
+}
+
+impl Algorithm {
+    /// Key type signing with this algorithm.
+    pub(crate) fn key_algorithm(&self) -> Self {
+        match self {
+            Self::SshEd25519 => Self::SshEd25519,
+            Self::SshRsa | Self::RsaSha2_256 | Self::RsaSha2_512 => Self::SshRsa,
+        }
+    }
+
+    /// Signature algorithms of this key type, preferred first.
+    pub(crate) fn signature_algorithms(&self) -> Vec<Self> {
+        match self.key_algorithm() {
+            Self::SshRsa => vec![Self::RsaSha2_512, Self::RsaSha2_256, Self::SshRsa],
+            key => vec![key],
+        }
+    }
 }
 
 impl AsRef<str> for Algorithm {
@@ -32,6 +56,8 @@ impl AsRef<str> for Algorithm {
         match self {
             Self::SshEd25519 => "ssh-ed25519",
             Self::SshRsa => "ssh-rsa",
+            Self::RsaSha2_256 => "rsa-sha2-256",
+            Self::RsaSha2_512 => "rsa-sha2-512",
         }
     }
 }
@@ -43,6 +69,8 @@ impl FromStr for Algorithm {
         match s {
             "ssh-ed25519" => Ok(Self::SshEd25519),
             "ssh-rsa" => Ok(Self::SshRsa),
+            "rsa-sha2-256" => Ok(Self::RsaSha2_256),
+            "rsa-sha2-512" => Ok(Self::RsaSha2_512),
             x => Err(UnknownNameError(x.into())),
         }
     }
@@ -50,7 +78,12 @@ impl FromStr for Algorithm {
 
 impl AlgorithmName for Algorithm {
     fn defaults() -> Vec<Self> {
-        vec![Self::SshEd25519, Self::SshRsa]
+        vec![
+            Self::SshEd25519,
+            Self::RsaSha2_512,
+            Self::RsaSha2_256,
+            Self::SshRsa,
+        ]
     }
 }
 
@@ -58,6 +91,21 @@ impl AlgorithmName for Algorithm {
 #[derive(Debug, Clone)]
 pub(crate) struct Signature(String, Bytes);
 
+impl Signature {
+    #[cfg(test)]
+    pub(crate) fn new(algorithm: String, signature: Bytes) -> Self {
+        Self(algorithm, signature)
+    }
+
+    pub(crate) fn algorithm(&self) -> &str {
+        &self.0
+    }
+
+    pub(crate) fn signature(&self) -> &Bytes {
+        &self.1
+    }
+}
+
 impl Pack for Signature {
     fn pack<P: Put>(&self, buf: &mut P) {
         let mut b = BytesMut::new();
@@ -84,7 +132,8 @@ trait VerifierTrait: Sized {
 
     fn update(&mut self, data: &[u8]);
 
-    fn verify(&self, signature: &[u8]) -> bool;
+    /// Verify `signature` made by signature algorithm `algorithm`.
+    fn verify(&self, algorithm: &str, signature: &[u8]) -> bool;
 }
 
 #[derive(Debug)]
@@ -94,18 +143,28 @@ pub(crate) enum Verifier {
 }
 
 impl Verifier {
+    /// Signature algorithms of the key types we can verify.
+    pub(crate) fn signature_algorithms() -> Vec<Algorithm> {
+        [Algorithm::SshEd25519, Algorithm::SshRsa]
+            .iter()
+            .flat_map(Algorithm::signature_algorithms)
+            .collect()
+    }
+
     fn new(name: &str, pk: &[u8]) -> Result<Self, SshError> {
         match Algorithm::from_str(name) {
             Ok(Algorithm::SshEd25519) => Ok(Self::Ed25519(ed25519::Ed25519Verifier::new(pk)?)),
             Ok(Algorithm::SshRsa) => Ok(Self::Rsa(rsa::RsaVerifier::new(pk)?)),
+            // Signature algorithm names never name a public key.
+            Ok(x) => Err(SshError::UnknownAlgorithm(x.as_ref().into())),
             Err(x) => Err(SshError::UnknownAlgorithm(x.0)),
         }
     }
 
     pub(crate) fn verify(&self, signature: &Signature) -> bool {
         match self {
-            Self::Ed25519(item) => item.verify(&signature.1),
-            Self::Rsa(item) => item.verify(&signature.1),
+            Self::Ed25519(item) => item.verify(&signature.0, &signature.1),
+            Self::Rsa(item) => item.verify(&signature.0, &signature.1),
         }
     }
 }
@@ -119,6 +178,16 @@ impl Put for Verifier {
     }
 }
 
+/// Algorithm specific fields of a public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKeyParams {
+    /// `ssh-ed25519` 32 byte key
+    Ed25519(Bytes),
+
+    /// `ssh-rsa` exponent and modulus, as unsigned big endian
+    Rsa { e: Bytes, n: Bytes },
+}
+
 /// Public key
 // TODO Is a simple byte comparison all right?
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -132,6 +201,80 @@ impl PublicKey {
     pub fn algorithm(&self) -> &str {
         &self.0
     }
+
+    /// Length of [`blob`](Self::blob).
+    pub(crate) fn blob_len(&self) -> usize {
+        4 + self.0.len() + self.1.len()
+    }
+
+    /// Key blob in wire format, algorithm name included.
+    pub fn blob(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.0.pack(&mut buf);
+        buf.extend_from_slice(&self.1);
+        buf.freeze()
+    }
+
+    /// Parse algorithm specific fields. `None` for other or malformed keys.
+    pub fn params(&self) -> Option<PublicKeyParams> {
+        let mut buf = self.1.clone();
+        let params = match Algorithm::from_str(&self.0).ok()? {
+            Algorithm::SshEd25519 => {
+                let key = Bytes::unpack(&mut buf).ok()?;
+                if key.len() != 32 {
+                    return None;
+                }
+                PublicKeyParams::Ed25519(key)
+            }
+            Algorithm::SshRsa => {
+                let e = Bytes::unpack(&mut buf).ok()?;
+                let n = Bytes::unpack(&mut buf).ok()?;
+                let unsigned = |mut b: Bytes| {
+                    while b.first() == Some(&0) {
+                        b.advance(1);
+                    }
+                    b
+                };
+                PublicKeyParams::Rsa {
+                    e: unsigned(e),
+                    n: unsigned(n),
+                }
+            }
+            _ => return None,
+        };
+        if buf.has_remaining() {
+            return None;
+        }
+        Some(params)
+    }
+
+    /// Parse OpenSSH one-line format, e.g. `ssh-ed25519 AAAA... comment`.
+    ///
+    /// Returns the key and comment, which may be empty.
+    pub fn from_openssh(line: &str) -> Result<(Self, String), PublicKeyParseError> {
+        let mut fields = line.trim().splitn(3, char::is_whitespace);
+        let algorithm = fields.next().ok_or(PublicKeyParseError)?;
+        let publickey = fields.next().ok_or(PublicKeyParseError)?.parse::<Self>()?;
+        if publickey.algorithm() != algorithm {
+            return Err(PublicKeyParseError);
+        }
+        let comment = fields.next().unwrap_or_default().trim().to_string();
+        Ok((publickey, comment))
+    }
+
+    /// Render OpenSSH one-line format, as in `authorized_keys`.
+    pub fn to_openssh(&self, comment: &str) -> String {
+        if comment.is_empty() {
+            format!("{} {}", self.0, self)
+        } else {
+            format!("{} {} {}", self.0, self, comment)
+        }
+    }
+
+    /// Whether signature algorithm `algorithm` signs with this key type.
+    pub(crate) fn supports(&self, algorithm: &str) -> bool {
+        Algorithm::from_str(algorithm).is_ok_and(|a| a.key_algorithm().as_ref() == self.0)
+    }
 }
 
 impl Pack for PublicKey {
@@ -185,8 +328,8 @@ pub(crate) trait KeyTrait: Into<Key> + Sized {
     /// Get hostkey's public key
     fn publickey(&self) -> Bytes;
 
-    /// Sign by hostkey
-    fn sign(&self, target: &Bytes) -> Bytes;
+    /// Sign by hostkey with signature algorithm `algorithm`
+    fn sign(&self, algorithm: &Algorithm, target: &Bytes) -> Bytes;
 
     fn parse(buf: &[u8]) -> Result<Self, SshError>;
 }
@@ -204,16 +347,16 @@ pub(crate) enum Key {
 impl Key {
     /// Generate hostkey by algorithm name
     pub(crate) fn gen(name: &Algorithm) -> Result<Self, SshError> {
-        match name {
+        match name.key_algorithm() {
             Algorithm::SshEd25519 => Ok(ed25519::Ed25519::gen()?.into()),
-            Algorithm::SshRsa => Ok(rsa::Rsa::gen()?.into()),
+            _ => Ok(rsa::Rsa::gen()?.into()),
         }
     }
 
     pub(crate) fn parse(name: &Algorithm, data: &[u8]) -> Result<Self, SshError> {
-        match name {
+        match name.key_algorithm() {
             Algorithm::SshEd25519 => Ok(ed25519::Ed25519::parse(data)?.into()),
-            Algorithm::SshRsa => Ok(rsa::Rsa::parse(data)?.into()),
+            _ => Ok(rsa::Rsa::parse(data)?.into()),
         }
     }
 
@@ -234,12 +377,13 @@ impl Key {
         }
     }
 
-    /// Sign by hostkey
-    pub(crate) fn sign(&self, target: &Bytes) -> Signature {
-        let name = self.name().as_ref().into();
+    /// Sign by hostkey with negotiated signature algorithm `algorithm`
+    pub(crate) fn sign(&self, algorithm: &Algorithm, target: &Bytes) -> Signature {
+        debug_assert_eq!(algorithm.key_algorithm(), self.name());
+        let name = algorithm.as_ref().into();
         match self {
-            Self::Ed25519(item) => Signature(name, item.sign(target)),
-            Self::Rsa(item) => Signature(name, item.sign(target)),
+            Self::Ed25519(item) => Signature(name, item.sign(algorithm, target)),
+            Self::Rsa(item) => Signature(name, item.sign(algorithm, target)),
         }
     }
 }
@@ -261,7 +405,7 @@ mod tests {
     fn test_signature() {
         let b = Bytes::from("Hello, World!");
         let k = Key::gen(&Algorithm::SshEd25519).unwrap();
-        let sign = k.sign(&b);
+        let sign = k.sign(&Algorithm::SshEd25519, &b);
 
         let mut b = BytesMut::new();
         sign.pack(&mut b);
@@ -278,13 +422,35 @@ mod tests {
         PublicKey::unpack(&mut b).unwrap();
     }
 
+    #[test]
+    fn test_debug_redacted() {
+        use ring::signature::{Ed25519KeyPair, KeyPair as _};
+
+        let seed = [7; 32];
+        let pair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let pk = Bytes::copy_from_slice(pair.public_key().as_ref());
+        let mut sk = seed.to_vec();
+        sk.extend_from_slice(&pk);
+        let mut buf = BytesMut::new();
+        pk.pack(&mut buf);
+        Bytes::from(sk).pack(&mut buf);
+        let key = Key::Ed25519(ed25519::Ed25519::parse(&buf).unwrap());
+
+        let debug = format!("{:?}", key);
+        assert!(debug.contains("publickey"), "{}", debug);
+        assert!(!debug.contains(&"\\x07".repeat(seed.len())), "{}", debug);
+
+        let debug = format!("{:?}", Key::gen(&Algorithm::SshRsa).unwrap());
+        assert!(debug.starts_with("Rsa(Rsa { publickey: "), "{}", debug);
+    }
+
     #[test]
     fn test_ed25519() {
         use ring::signature::*;
 
         let b = Bytes::from("Hello, World!");
         let k = Key::gen(&Algorithm::SshEd25519).unwrap();
-        let sign = k.sign(&b).1;
+        let sign = k.sign(&Algorithm::SshEd25519, &b).1;
         let pubkey = Bytes::unpack(&mut k.publickey().1).unwrap();
 
         let pubkey = UnparsedPublicKey::new(&ED25519, &pubkey);
@@ -301,9 +467,10 @@ mod tests {
 
         let b = Bytes::from("Hello, World!");
         let k = Key::gen(&Algorithm::SshRsa).unwrap();
-        let sign = k.sign(&b).1;
 
-        let mut pubkey = k.publickey().1;
+        let publickey = k.publickey();
+        assert_eq!(publickey.algorithm(), "ssh-rsa");
+        let mut pubkey = publickey.1;
         let e = Bytes::unpack(&mut pubkey).unwrap();
         let n = Bytes::unpack(&mut pubkey).unwrap();
         let e = BigNum::from_slice(&e).unwrap();
@@ -311,8 +478,79 @@ mod tests {
         let pubkey = Rsa::from_public_components(n, e).unwrap();
         let pubkey = PKey::from_rsa(pubkey).unwrap();
 
-        let verifier = Verifier::new(MessageDigest::sha1(), &pubkey).unwrap();
-        verifier.verify(&sign).unwrap();
+        for (algorithm, digest) in [
+            (Algorithm::SshRsa, MessageDigest::sha1()),
+            (Algorithm::RsaSha2_256, MessageDigest::sha256()),
+            (Algorithm::RsaSha2_512, MessageDigest::sha512()),
+        ] {
+            let sign = k.sign(&algorithm, &b);
+            assert_eq!(sign.algorithm(), algorithm.as_ref());
+
+            let mut verifier = Verifier::new(digest, &pubkey).unwrap();
+            verifier.update(&b).unwrap();
+            assert!(verifier.verify(&sign.1).unwrap(), "{:?}", algorithm);
+
+            let mut verifier = k.publickey().verifier().unwrap();
+            verifier.put(&b);
+            assert!(verifier.verify(&sign));
+        }
+    }
+
+    #[test]
+    fn test_supports() {
+        let rsa = Key::gen(&Algorithm::SshRsa).unwrap().publickey();
+        assert!(rsa.supports("ssh-rsa"));
+        assert!(rsa.supports("rsa-sha2-256"));
+        assert!(rsa.supports("rsa-sha2-512"));
+        assert!(!rsa.supports("ssh-ed25519"));
+        assert!(!rsa.supports("unknown"));
+
+        let ed25519 = Key::gen(&Algorithm::SshEd25519).unwrap().publickey();
+        assert!(ed25519.supports("ssh-ed25519"));
+        assert!(!ed25519.supports("rsa-sha2-512"));
+    }
+
+    #[test]
+    fn test_params() {
+        let k = Key::gen(&Algorithm::SshEd25519).unwrap();
+        match k.publickey().params() {
+            Some(PublicKeyParams::Ed25519(key)) => assert_eq!(key.len(), 32),
+            x => panic!("{:?}", x),
+        }
+
+        let k = Key::gen(&Algorithm::SshRsa).unwrap();
+        match k.publickey().params() {
+            Some(PublicKeyParams::Rsa { e, n }) => {
+                assert_eq!(&e[..], &[1, 0, 1]);
+                assert_ne!(n[0], 0);
+            }
+            x => panic!("{:?}", x),
+        }
+
+        let dss = PublicKey("ssh-dss".into(), Bytes::new());
+        assert_eq!(dss.params(), None);
+    }
+
+    #[test]
+    fn test_openssh() {
+        let line = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V user@example.net";
+        let (publickey, comment) = PublicKey::from_openssh(line).unwrap();
+        assert_eq!(publickey.algorithm(), "ssh-ed25519");
+        assert_eq!(comment, "user@example.net");
+        assert_eq!(publickey.to_openssh(&comment), line);
+        let mut b = BytesMut::new();
+        publickey.blob().pack(&mut b);
+        assert_eq!(PublicKey::unpack(&mut b).unwrap(), publickey);
+
+        let (publickey, comment) = PublicKey::from_openssh(&publickey.to_openssh("")).unwrap();
+        assert_eq!(comment, "");
+        assert_eq!(publickey.to_openssh(""), line.rsplit_once(' ').unwrap().0);
+
+        PublicKey::from_openssh(
+            "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V",
+        )
+        .unwrap_err();
+        PublicKey::from_openssh("ssh-ed25519").unwrap_err();
     }
 
     #[test]
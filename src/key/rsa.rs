@@ -10,11 +10,19 @@ use openssl::sign::Verifier;
 use super::*;
 use crate::pack::Mpint;
 
-#[derive(Debug)]
 pub(crate) struct Rsa {
     pair: OpenSslRsa<Private>,
 }
 
+/// Public key only, the private half is never printed.
+impl fmt::Debug for Rsa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rsa")
+            .field("publickey", &self.publickey())
+            .finish()
+    }
+}
+
 impl KeyTrait for Rsa {
     const NAME: Algorithm = Algorithm::SshRsa;
 
@@ -30,9 +38,14 @@ impl KeyTrait for Rsa {
         b.freeze()
     }
 
-    fn sign(&self, target: &Bytes) -> Bytes {
+    fn sign(&self, algorithm: &Algorithm, target: &Bytes) -> Bytes {
+        let digest = match algorithm {
+            Algorithm::RsaSha2_256 => MessageDigest::sha256(),
+            Algorithm::RsaSha2_512 => MessageDigest::sha512(),
+            _ => MessageDigest::sha1(),
+        };
         let pkey = PKey::from_rsa(self.pair.clone()).unwrap();
-        let mut signer = Signer::new(MessageDigest::sha1(), &pkey).unwrap();
+        let mut signer = Signer::new(digest, &pkey).unwrap();
         signer.set_rsa_padding(Padding::PKCS1).unwrap();
         signer.update(target.as_ref()).unwrap();
         signer.sign_to_vec().unwrap().into()
@@ -56,11 +69,11 @@ impl KeyTrait for Rsa {
 
         aux.checked_sub(&q, BigNum::from_u32(1).map_err(SshError::any)?.as_ref())
             .map_err(SshError::any)?;
-        dmq1.nnmod(&consttime, &aux, &mut cx)
+        dmq1.nnmod(consttime, &aux, &mut cx)
             .map_err(SshError::any)?;
         aux.checked_sub(&p, BigNum::from_u32(1).map_err(SshError::any)?.as_ref())
             .map_err(SshError::any)?;
-        dmp1.nnmod(&consttime, &aux, &mut cx)
+        dmp1.nnmod(consttime, &aux, &mut cx)
             .map_err(SshError::any)?;
 
         let pair = OpenSslRsa::from_private_components(n, e, d, p, q, dmp1, dmq1, iqmp)
@@ -106,11 +119,17 @@ impl VerifierTrait for RsaVerifier {
         self.buf.extend_from_slice(data);
     }
 
-    fn verify(&self, signature: &[u8]) -> bool {
-        let mut verifier = Verifier::new(MessageDigest::sha1(), &self.key).unwrap();
+    fn verify(&self, algorithm: &str, signature: &[u8]) -> bool {
+        let digest = match algorithm {
+            "ssh-rsa" => MessageDigest::sha1(),
+            "rsa-sha2-256" => MessageDigest::sha256(),
+            "rsa-sha2-512" => MessageDigest::sha512(),
+            _ => return false,
+        };
+        let mut verifier = Verifier::new(digest, &self.key).unwrap();
         verifier.set_rsa_padding(Padding::PKCS1).unwrap();
         verifier.update(&self.buf).unwrap();
-        verifier.verify(signature).unwrap()
+        verifier.verify(signature).unwrap_or(false)
     }
 }
 
@@ -0,0 +1,96 @@
+//! Key log for decrypting own captures with Wireshark.
+//!
+//! **Security sensitive.** Anyone holding the log can decrypt every logged
+//! connection. Never enable outside debugging.
+use std::fmt::{self, Write as _};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::trace::warn;
+
+use crate::kex;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        write!(s, "{:02x}", b).unwrap();
+        s
+    })
+}
+
+/// One entry in the Wireshark SSH dissector key log format.
+///
+/// The dissector matches the client kexinit cookie and ignores `#` lines.
+pub(crate) fn entry(
+    cookie: u128,
+    session_id: &[u8],
+    kex: &kex::Algorithm,
+    secret: &[u8],
+) -> String {
+    format!(
+        "# session {} kex {}\n{} SHARED_SECRET {}\n",
+        hex(session_id),
+        kex.as_ref(),
+        hex(&cookie.to_be_bytes()),
+        hex(secret)
+    )
+}
+
+/// Shared key log writer.
+#[derive(Clone)]
+pub(crate) struct KeyLog(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl fmt::Debug for KeyLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KeyLog").finish()
+    }
+}
+
+impl KeyLog {
+    pub(crate) fn new<W>(out: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        Self(Arc::new(Mutex::new(Box::new(out))))
+    }
+
+    /// Append and flush the secret of one key exchange. Failures are only logged.
+    pub(crate) fn log(&self, cookie: u128, session_id: &[u8], kex: &kex::Algorithm, secret: &[u8]) {
+        let entry = entry(cookie, session_id, kex, secret);
+        let mut out = self.0.lock().unwrap();
+        if let Err(e) = out.write_all(entry.as_bytes()).and_then(|_| out.flush()) {
+            warn!("failed to write key log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log() {
+        let out = Shared::default();
+        let keylog = KeyLog::new(out.clone());
+        keylog.log(
+            0x0123_4567_89ab_cdef_0011_2233_4455_6677,
+            &[0xde, 0xad, 0xbe, 0xef],
+            &kex::Algorithm::Curve25519Sha256,
+            &[0x00, 0x01, 0xfe, 0xff],
+        );
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, include_str!("../tests/vectors/keylog.txt"));
+    }
+}
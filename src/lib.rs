@@ -41,30 +41,71 @@
 //! ```
 
 pub use cipher::Algorithm as Cipher;
+pub use cipher::{CipherFactory, CustomCipher};
 pub use comp::Algorithm as Compression;
-pub use connection::{Connection, SshInput, SshOutput};
-pub use error::SshError;
+pub use connection::{
+    ChannelInfoSnapshot, ChannelKind, ChannelMode, ChannelResponder, ChannelState, ChannelStream,
+    Connection, DetachError, DetachedChannel, GlobalHandle, PhaseTimings, ProtocolWarning,
+    SshInput, SshOutput, WarningKind,
+};
+pub use error::{ErrorKind, SshError};
+pub use factory::{ConnectionInfo, HandlerFactory, SharedStateFactory};
 pub use handlers::*;
+pub use hostkey::HostKey;
 pub use kex::Algorithm as Kex;
-pub use key::{Algorithm as Key, PublicKey, PublicKeyParseError};
+pub use kex::{CustomKex, KexFactory, KexHash};
+pub use key::{Algorithm as Key, PublicKey, PublicKeyParams, PublicKeyParseError};
 pub use mac::Algorithm as Mac;
-pub use server::{Builder as ServerBuilder, Server};
+pub use mac::{CustomMac, MacFactory};
+pub use msg::disconnect::ReasonCode as DisconnectReason;
+pub use negotiate::{
+    Algorithm as NegotiatedAlgorithms, AlgorithmKind, Languages, NegotiateError, Registered,
+};
+pub use observer::{AuthOutcome, ConnectionObserver, HandlerErrorFault};
+pub use quirks::Quirk;
+pub use random::Random;
+#[cfg(feature = "replay")]
+pub use replay::{Direction, ReplayDriver, ReplayError, ReplayRecorder};
+pub use server::{BuildError, Builder as ServerBuilder, Server};
+pub use signal::Signal;
+pub use stealth::Stealth;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use stream::bpp::BppStream;
 
 pub mod authorized_keys;
 mod cipher;
 mod comp;
 mod connection;
 mod error;
+mod factory;
+#[cfg(any(test, feature = "fuzz"))]
+#[doc(hidden)]
+pub mod fuzz;
 mod handlers;
 mod hash;
 mod hostkey;
 mod kex;
 mod key;
+mod keylog;
 mod mac;
 mod msg;
 mod negotiate;
+mod observer;
 mod pack;
 mod preference;
+mod quirks;
+mod random;
+#[cfg(feature = "replay")]
+mod replay;
 mod server;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+mod signal;
+pub mod ssh_signature;
 mod state;
+mod stealth;
 mod stream;
+#[cfg(test)]
+mod test_support;
+mod trace;
@@ -0,0 +1,29 @@
+//! MACs registered by applications.
+use std::fmt;
+
+use bytes::Bytes;
+
+use crate::SshError;
+
+/// Creates [`CustomMac`] instances for a registered MAC name.
+pub trait MacFactory: fmt::Debug + Send + Sync + 'static {
+    /// Integrity key length in bytes derived by key exchange.
+    fn key_length(&self) -> usize;
+
+    /// Tag length in bytes appended to each packet.
+    fn tag_length(&self) -> usize;
+
+    /// Instance for one direction.
+    fn create(&self, key: &[u8]) -> Box<dyn CustomMac>;
+}
+
+/// MAC of one direction.
+///
+/// `plain` is the unencrypted packet including its length field.
+pub trait CustomMac: fmt::Debug + Send + Sync {
+    /// Tag of exactly [`MacFactory::tag_length`] bytes.
+    fn sign(&self, seq: u32, plain: &[u8]) -> Result<Bytes, SshError>;
+
+    /// Must fail unless `tag` was created by [`CustomMac::sign`] for the same input.
+    fn verify(&self, seq: u32, plain: &[u8], tag: &[u8]) -> Result<(), SshError>;
+}
@@ -2,9 +2,12 @@ use std::str::FromStr;
 
 use bytes::Bytes;
 
-use crate::negotiate::{AlgorithmName, UnknownNameError};
+use crate::negotiate::{AlgorithmName, Registered, UnknownNameError};
 use crate::SshError;
 
+pub use custom::{CustomMac, MacFactory};
+
+mod custom;
 mod none;
 mod sha;
 
@@ -22,6 +25,9 @@ pub enum Algorithm {
 
     /// `hmac-sha1`
     HmacSha1,
+
+    /// Registered by application.
+    Custom(Registered<dyn MacFactory>),
 }
 
 impl AsRef<str> for Algorithm {
@@ -31,6 +37,7 @@ impl AsRef<str> for Algorithm {
             Self::HmacSha256 => "hmac-sha2-256",
             Self::HmacSha512 => "hmac-sha2-512",
             Self::HmacSha1 => "hmac-sha1",
+            Self::Custom(item) => item.name(),
         }
     }
 }
@@ -68,6 +75,7 @@ pub(crate) enum Mac {
     HmacSha256(sha::HmacSha256),
     HmacSha512(sha::HmacSha512),
     HmacSha1(sha::HmacSha1),
+    Custom(Box<dyn CustomMac>, usize),
 }
 
 impl Mac {
@@ -81,24 +89,31 @@ impl Mac {
             Algorithm::HmacSha256 => Self::HmacSha256(sha::HmacSha256::new(key)),
             Algorithm::HmacSha512 => Self::HmacSha512(sha::HmacSha512::new(key)),
             Algorithm::HmacSha1 => Self::HmacSha1(sha::HmacSha1::new(key)),
+            Algorithm::Custom(item) => {
+                Self::Custom(item.factory().create(key), item.factory().tag_length())
+            }
         }
     }
 
+    /// Integrity key length.
     pub(crate) fn len_by_name(name: &Algorithm) -> usize {
         match name {
             Algorithm::None => none::None::LEN,
             Algorithm::HmacSha256 => sha::HmacSha256::LEN,
             Algorithm::HmacSha512 => sha::HmacSha512::LEN,
             Algorithm::HmacSha1 => sha::HmacSha1::LEN,
+            Algorithm::Custom(item) => item.factory().key_length(),
         }
     }
 
+    /// Tag length.
     pub(crate) fn len(&self) -> usize {
         match self {
             Self::None(..) => none::None::LEN,
             Self::HmacSha256(..) => sha::HmacSha256::LEN,
             Self::HmacSha512(..) => sha::HmacSha512::LEN,
             Self::HmacSha1(..) => sha::HmacSha1::LEN,
+            Self::Custom(_, len) => *len,
         }
     }
 
@@ -108,6 +123,7 @@ impl Mac {
             Self::HmacSha256(item) => item.sign(seq, plain),
             Self::HmacSha512(item) => item.sign(seq, plain),
             Self::HmacSha1(item) => item.sign(seq, plain),
+            Self::Custom(item, _) => item.sign(seq, plain),
         }
     }
 
@@ -117,6 +133,7 @@ impl Mac {
             Self::HmacSha256(item) => item.verify(seq, plain, tag),
             Self::HmacSha512(item) => item.verify(seq, plain, tag),
             Self::HmacSha1(item) => item.verify(seq, plain, tag),
+            Self::Custom(item, _) => item.verify(seq, plain, tag),
         }
     }
 }
@@ -159,6 +176,43 @@ mod tests {
         Mac::new_none();
     }
 
+    #[test]
+    fn test_hmac_sha2_512() {
+        let name = &Algorithm::HmacSha512;
+
+        let k = Bytes::from(vec![0; Mac::len_by_name(name)]);
+
+        let src = BytesMut::from("Hello, world!");
+        let tag = Mac::new(name, &k).sign(0, &src).unwrap();
+        assert_eq!(tag.len(), 64);
+        Mac::new(name, &k).verify(0, &src, &tag).unwrap();
+    }
+
+    #[test]
+    fn test_flipped_bit() {
+        for name in &[
+            Algorithm::HmacSha256,
+            Algorithm::HmacSha512,
+            Algorithm::HmacSha1,
+        ] {
+            let k = Bytes::from(vec![7; Mac::len_by_name(name)]);
+            let mac = Mac::new(name, &k);
+
+            let src = BytesMut::from("Hello, world!");
+            let tag = mac.sign(3, &src).unwrap();
+            assert_eq!(tag.len(), mac.len());
+
+            let mut flipped = tag.to_vec();
+            flipped[0] ^= 1;
+            mac.verify(3, &src, &flipped).unwrap_err();
+            let mut flipped = src.to_vec();
+            flipped[5] ^= 0x80;
+            mac.verify(3, &flipped, &tag).unwrap_err();
+            mac.verify(4, &src, &tag).unwrap_err();
+            mac.verify(3, &src, &tag[..tag.len() - 1]).unwrap_err();
+        }
+    }
+
     #[test]
     fn test_hmac_sha1() {
         let name = &Algorithm::HmacSha1;
@@ -172,6 +226,62 @@ mod tests {
         Mac::new_none();
     }
 
+    #[derive(Debug)]
+    struct Sum(u32);
+
+    impl CustomMac for Sum {
+        fn sign(&self, seq: u32, plain: &[u8]) -> Result<Bytes, SshError> {
+            let sum = plain
+                .iter()
+                .fold(self.0 ^ seq, |s, b| s.wrapping_add(*b as u32));
+            Ok(Bytes::copy_from_slice(&sum.to_be_bytes()))
+        }
+
+        fn verify(&self, seq: u32, plain: &[u8], tag: &[u8]) -> Result<(), SshError> {
+            if self.sign(seq, plain)? != tag {
+                return Err(SshError::MacError("sum mismatch".into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct SumFactory;
+
+    impl MacFactory for SumFactory {
+        fn key_length(&self) -> usize {
+            4
+        }
+
+        fn tag_length(&self) -> usize {
+            4
+        }
+
+        fn create(&self, key: &[u8]) -> Box<dyn CustomMac> {
+            let mut k = [0; 4];
+            k.copy_from_slice(key);
+            Box::new(Sum(u32::from_be_bytes(k)))
+        }
+    }
+
+    #[test]
+    fn test_custom() {
+        use crate::negotiate::Registered;
+        use std::sync::Arc;
+
+        let name = &Algorithm::Custom(Registered::new("sum@example.com", Arc::new(SumFactory)));
+        assert_eq!(name.as_ref(), "sum@example.com");
+
+        let k = Bytes::from(vec![1; Mac::len_by_name(name)]);
+
+        let src = BytesMut::from("Hello, world!");
+        let mac = Mac::new(name, &k);
+        assert_eq!(mac.len(), 4);
+        let tag = mac.sign(0, &src).unwrap();
+        Mac::new(name, &k).verify(0, &src, &tag).unwrap();
+        Mac::new(name, &k).verify(1, &src, &tag).unwrap_err();
+    }
+
     #[test]
     fn test_parse() {
         for name in Algorithm::defaults() {
@@ -60,7 +60,7 @@ where
     const LEN: usize = T::LEN;
 
     fn new(key: &[u8]) -> Self {
-        let key = Key::new(T::algorithm(), &key);
+        let key = Key::new(T::algorithm(), key);
         Self {
             key,
             _phantom: PhantomData,
@@ -70,7 +70,7 @@ where
     fn sign(&self, seq: u32, plain: &[u8]) -> Result<Bytes, SshError> {
         let mut cx = Context::with_key(&self.key);
         cx.update(&seq.to_be_bytes());
-        cx.update(&plain);
+        cx.update(plain);
         let sign = cx.sign();
         let mut sign = sign.as_ref();
         Ok(sign.copy_to_bytes(sign.remaining()))
@@ -79,7 +79,7 @@ where
     fn verify(&self, seq: u32, plain: &[u8], tag: &[u8]) -> Result<(), SshError> {
         let mut buf = BytesMut::new();
         buf.extend_from_slice(&seq.to_be_bytes());
-        buf.extend_from_slice(&plain);
+        buf.extend_from_slice(plain);
         hmac::verify(&self.key, &buf, tag).map_err(SshError::mac_error)?;
         Ok(())
     }
@@ -1,41 +1,27 @@
 use derive_new::new;
-use getset::Getters;
+use getset::{Getters, MutGetters};
 
 use super::*;
 
-#[derive(Debug, new, Getters)]
+#[derive(Debug, new, Getters, MutGetters)]
 pub(crate) struct ChannelData {
     #[get = "pub(crate)"]
+    #[get_mut = "pub(crate)"]
     recipient_channel: u32,
     #[get = "pub(crate)"]
     data: Bytes,
 }
 
-impl MsgItem for ChannelData {
-    const ID: u8 = 94;
-}
-
-impl Pack for ChannelData {
-    fn pack<P: Put>(&self, buf: &mut P) {
-        self.recipient_channel.pack(buf);
-        self.data.pack(buf);
-    }
-}
-
-impl Unpack for ChannelData {
-    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
-        let recipient_channel = Unpack::unpack(buf)?;
-        let data = Unpack::unpack(buf)?;
-
-        Ok(Self {
-            recipient_channel,
-            data,
-        })
+impl ChannelData {
+    /// Split off the first `at` bytes as a message of their own.
+    pub(crate) fn split_to(&mut self, at: usize) -> Self {
+        Self::new(self.recipient_channel, self.data.split_to(at))
     }
 }
 
-impl From<ChannelData> for Msg {
-    fn from(v: ChannelData) -> Self {
-        Self::ChannelData(v)
+msg_item! {
+    Msg::ChannelData(94) {
+        recipient_channel,
+        data,
     }
 }
@@ -1,4 +1,5 @@
 use derive_new::new;
+use getset::{Getters, MutGetters};
 
 use super::*;
 
@@ -8,13 +9,18 @@ pub(crate) enum DataTypeCode {
     Unknown(u32),
 }
 
-impl Pack for DataTypeCode {
-    fn pack<P: Put>(&self, buf: &mut P) {
+impl DataTypeCode {
+    pub(crate) fn code(&self) -> u32 {
         match self {
             Self::Stderr => 1,
             Self::Unknown(v) => *v,
         }
-        .pack(buf)
+    }
+}
+
+impl Pack for DataTypeCode {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        self.code().pack(buf)
     }
 }
 
@@ -27,41 +33,32 @@ impl Unpack for DataTypeCode {
     }
 }
 
-#[derive(Debug, new)]
+#[derive(Debug, new, Getters, MutGetters)]
 pub(crate) struct ChannelExtendedData {
+    #[get = "pub(crate)"]
+    #[get_mut = "pub(crate)"]
     recipient_channel: u32,
+    #[get = "pub(crate)"]
     data_type_code: DataTypeCode,
+    #[get = "pub(crate)"]
     data: Bytes,
 }
 
-impl MsgItem for ChannelExtendedData {
-    const ID: u8 = 95;
-}
-
-impl Pack for ChannelExtendedData {
-    fn pack<P: Put>(&self, buf: &mut P) {
-        self.recipient_channel.pack(buf);
-        self.data_type_code.pack(buf);
-        self.data.pack(buf);
-    }
-}
-
-impl Unpack for ChannelExtendedData {
-    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
-        let recipient_channel = Unpack::unpack(buf)?;
-        let data_type_code = Unpack::unpack(buf)?;
-        let data = Unpack::unpack(buf)?;
-
-        Ok(Self {
-            recipient_channel,
-            data_type_code,
-            data,
-        })
+impl ChannelExtendedData {
+    /// Split off the first `at` bytes as a message of their own.
+    pub(crate) fn split_to(&mut self, at: usize) -> Self {
+        Self::new(
+            self.recipient_channel,
+            self.data_type_code.clone(),
+            self.data.split_to(at),
+        )
     }
 }
 
-impl From<ChannelExtendedData> for Msg {
-    fn from(v: ChannelExtendedData) -> Self {
-        Self::ChannelExtendedData(v)
+msg_item! {
+    Msg::ChannelExtendedData(95) {
+        recipient_channel,
+        data_type_code,
+        data,
     }
 }
@@ -1,8 +1,9 @@
+use derive_new::new;
 use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct X11 {
     #[get = "pub(crate)"]
     originator_address: String,
@@ -29,7 +30,7 @@ impl Unpack for X11 {
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct ForwardedTcpip {
     #[get = "pub(crate)"]
     address: String,
@@ -107,16 +108,81 @@ impl Unpack for DirectTcpip {
     }
 }
 
+/// `direct-streamlocal@openssh.com`, OpenSSH PROTOCOL section 2.4.
+#[derive(Debug, Getters, new)]
+pub(crate) struct DirectStreamlocal {
+    #[get = "pub(crate)"]
+    socket_path: String,
+
+    /// Reserved, sent empty.
+    reserved: String,
+
+    /// Reserved, sent as 0.
+    reserved2: u32,
+}
+
+impl Pack for DirectStreamlocal {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        self.socket_path.pack(buf);
+        self.reserved.pack(buf);
+        self.reserved2.pack(buf);
+    }
+}
+
+impl Unpack for DirectStreamlocal {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let socket_path = Unpack::unpack(buf)?;
+        let reserved = Unpack::unpack(buf)?;
+        let reserved2 = Unpack::unpack(buf)?;
+        Ok(Self {
+            socket_path,
+            reserved,
+            reserved2,
+        })
+    }
+}
+
+/// `forwarded-streamlocal@openssh.com`, OpenSSH PROTOCOL section 2.4.
+#[derive(Debug, Getters, new)]
+pub(crate) struct ForwardedStreamlocal {
+    #[get = "pub(crate)"]
+    socket_path: String,
+
+    /// Reserved, sent empty.
+    reserved: String,
+}
+
+impl Pack for ForwardedStreamlocal {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        self.socket_path.pack(buf);
+        self.reserved.pack(buf);
+    }
+}
+
+impl Unpack for ForwardedStreamlocal {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let socket_path = Unpack::unpack(buf)?;
+        let reserved = Unpack::unpack(buf)?;
+        Ok(Self {
+            socket_path,
+            reserved,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum Type {
     Session(()),
     X11(X11),
     ForwardedTcpip(ForwardedTcpip),
     DirectTcpip(DirectTcpip),
+    AuthAgent(()),
+    DirectStreamlocal(DirectStreamlocal),
+    ForwardedStreamlocal(ForwardedStreamlocal),
     Unknown(String, Bytes),
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct ChannelOpen {
     #[get = "pub(crate)"]
     sender_channel: u32,
@@ -142,6 +208,9 @@ impl Pack for ChannelOpen {
             Type::X11(..) => "x11",
             Type::ForwardedTcpip(..) => "forwarded-tcpip",
             Type::DirectTcpip(..) => "direct-tcpip",
+            Type::AuthAgent(..) => "auth-agent@openssh.com",
+            Type::DirectStreamlocal(..) => "direct-streamlocal@openssh.com",
+            Type::ForwardedStreamlocal(..) => "forwarded-streamlocal@openssh.com",
             Type::Unknown(name, _) => name.as_str(),
         };
 
@@ -155,8 +224,11 @@ impl Pack for ChannelOpen {
             Type::X11(item) => item.pack(buf),
             Type::ForwardedTcpip(item) => item.pack(buf),
             Type::DirectTcpip(item) => item.pack(buf),
+            Type::AuthAgent(..) => {}
+            Type::DirectStreamlocal(item) => item.pack(buf),
+            Type::ForwardedStreamlocal(item) => item.pack(buf),
             Type::Unknown(_, item) => {
-                buf.put(&item);
+                buf.put(item);
             }
         }
     }
@@ -173,6 +245,9 @@ impl Unpack for ChannelOpen {
             "x11" => Type::X11(Unpack::unpack(buf)?),
             "forwarded-tcpip" => Type::ForwardedTcpip(Unpack::unpack(buf)?),
             "direct-tcpip" => Type::DirectTcpip(Unpack::unpack(buf)?),
+            "auth-agent@openssh.com" => Type::AuthAgent(()),
+            "direct-streamlocal@openssh.com" => Type::DirectStreamlocal(Unpack::unpack(buf)?),
+            "forwarded-streamlocal@openssh.com" => Type::ForwardedStreamlocal(Unpack::unpack(buf)?),
             v => Type::Unknown(v.to_string(), buf.copy_to_bytes(buf.remaining())),
         };
 
@@ -190,3 +265,65 @@ impl From<ChannelOpen> for Msg {
         Self::ChannelOpen(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Message body after the id byte, laid out as OpenSSH `ssh -L` sends it.
+    fn direct_streamlocal_wire() -> Vec<u8> {
+        let mut wire = vec![0, 0, 0, 30];
+        wire.extend_from_slice(b"direct-streamlocal@openssh.com");
+        wire.extend_from_slice(&[0, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0x80, 0]);
+        wire.extend_from_slice(&[0, 0, 0, 12]);
+        wire.extend_from_slice(b"/run/app.sok");
+        wire.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        wire
+    }
+
+    #[test]
+    fn test_direct_streamlocal_round_trip() {
+        let wire = direct_streamlocal_wire();
+        let msg = ChannelOpen::unpack(&mut Bytes::from(wire.clone())).unwrap();
+        assert_eq!(*msg.sender_channel(), 2);
+        assert_eq!(*msg.initial_window_size(), 0x20_0000);
+        assert_eq!(*msg.maximum_packet_size(), 0x8000);
+        match msg.typ() {
+            Type::DirectStreamlocal(item) => assert_eq!(item.socket_path(), "/run/app.sok"),
+            x => panic!("{:?}", x),
+        }
+
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+        assert_eq!(&buf[..], &wire[..]);
+    }
+
+    #[test]
+    fn test_direct_streamlocal_truncated() {
+        // Missing the reserved uint32.
+        let mut wire = direct_streamlocal_wire();
+        wire.truncate(wire.len() - 4);
+        assert!(ChannelOpen::unpack(&mut Bytes::from(wire)).is_err());
+    }
+
+    #[test]
+    fn test_forwarded_streamlocal_round_trip() {
+        let item = ForwardedStreamlocal::new("/tmp/fwd.sock".into(), "".into());
+        let msg = ChannelOpen::new(5, 1024, 512, Type::ForwardedStreamlocal(item));
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+
+        let mut expect = vec![0, 0, 0, 33];
+        expect.extend_from_slice(b"forwarded-streamlocal@openssh.com");
+        expect.extend_from_slice(&[0, 0, 0, 5, 0, 0, 4, 0, 0, 0, 2, 0]);
+        expect.extend_from_slice(&[0, 0, 0, 13]);
+        expect.extend_from_slice(b"/tmp/fwd.sock");
+        expect.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(&buf[..], &expect[..]);
+
+        match ChannelOpen::unpack(&mut buf.freeze()).unwrap().typ() {
+            Type::ForwardedStreamlocal(item) => assert_eq!(item.socket_path(), "/tmp/fwd.sock"),
+            x => panic!("{:?}", x),
+        }
+    }
+}
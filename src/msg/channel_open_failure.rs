@@ -1,4 +1,5 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
@@ -37,45 +38,25 @@ impl Unpack for ReasonCode {
     }
 }
 
-#[derive(Debug, new)]
+#[derive(Debug, new, Getters)]
 pub(crate) struct ChannelOpenFailure {
+    #[get = "pub(crate)"]
     recipient_channel: u32,
-    reason_code: ReasonCode,
-    description: String,
-    language_tag: String,
-}
 
-impl MsgItem for ChannelOpenFailure {
-    const ID: u8 = 92;
-}
-
-impl Pack for ChannelOpenFailure {
-    fn pack<P: Put>(&self, buf: &mut P) {
-        self.recipient_channel.pack(buf);
-        self.reason_code.pack(buf);
-        self.description.pack(buf);
-        self.language_tag.pack(buf);
-    }
-}
+    #[get = "pub(crate)"]
+    reason_code: ReasonCode,
 
-impl Unpack for ChannelOpenFailure {
-    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
-        let recipient_channel = Unpack::unpack(buf)?;
-        let reason_code = Unpack::unpack(buf)?;
-        let description = Unpack::unpack(buf)?;
-        let language_tag = Unpack::unpack(buf)?;
+    #[get = "pub(crate)"]
+    description: String,
 
-        Ok(Self {
-            recipient_channel,
-            reason_code,
-            description,
-            language_tag,
-        })
-    }
+    language_tag: String,
 }
 
-impl From<ChannelOpenFailure> for Msg {
-    fn from(v: ChannelOpenFailure) -> Self {
-        Self::ChannelOpenFailure(v)
+msg_item! {
+    Msg::ChannelOpenFailure(92) {
+        recipient_channel,
+        reason_code,
+        description,
+        language_tag,
     }
 }
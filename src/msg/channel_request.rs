@@ -1,8 +1,59 @@
 use derive_new::new;
-use getset::Getters;
+use getset::{Getters, MutGetters};
 
 use super::*;
 
+/// Terminal mode opcode ending the encoded list.
+const TTY_OP_END: u8 = 0;
+
+/// Opcodes from 160 on have no defined argument, parsing stops there.
+const TTY_OP_UNDEFINED: u8 = 160;
+
+/// Encoded terminal modes of RFC 4254 section 8 as (opcode, argument).
+///
+/// Unknown opcodes below 160 are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Modes(Vec<(u8, u32)>);
+
+impl Modes {
+    #[cfg(test)]
+    pub(crate) fn new(modes: Vec<(u8, u32)>) -> Self {
+        Self(modes)
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<(u8, u32)> {
+        self.0
+    }
+}
+
+impl Pack for Modes {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        let mut b = BytesMut::new();
+        for (opcode, argument) in &self.0 {
+            opcode.pack(&mut b);
+            argument.pack(&mut b);
+        }
+        TTY_OP_END.pack(&mut b);
+        b.freeze().pack(buf)
+    }
+}
+
+impl Unpack for Modes {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let mut buf = Bytes::unpack(buf)?;
+        let mut modes = vec![];
+        // Missing TTY_OP_END tolerated.
+        while buf.has_remaining() {
+            let opcode = u8::unpack(&mut buf)?;
+            if opcode == TTY_OP_END || opcode >= TTY_OP_UNDEFINED {
+                break;
+            }
+            modes.push((opcode, Unpack::unpack(&mut buf)?));
+        }
+        Ok(Self(modes))
+    }
+}
+
 #[derive(Debug, Getters, new)]
 pub(crate) struct PtyReq {
     #[get = "pub(crate)"]
@@ -16,7 +67,7 @@ pub(crate) struct PtyReq {
     #[get = "pub(crate)"]
     height_px: u32,
     #[get = "pub(crate)"]
-    modes: Bytes,
+    modes: Modes,
 }
 
 impl Pack for PtyReq {
@@ -91,8 +142,9 @@ impl Unpack for X11Req {
 pub(crate) struct Env {
     #[get = "pub(crate)"]
     name: String,
+    /// Not necessarily UTF-8.
     #[get = "pub(crate)"]
-    value: String,
+    value: Bytes,
 }
 
 impl Pack for Env {
@@ -198,12 +250,14 @@ pub(crate) enum Type {
     Signal(String),
     ExitStatus(u32),
     ExitSignal(ExitSignal),
+    AuthAgentReq(()),
     Unknown(String, Bytes),
 }
 
-#[derive(Debug, Getters, new)]
+#[derive(Debug, Getters, new, MutGetters)]
 pub(crate) struct ChannelRequest {
     #[get = "pub(crate)"]
+    #[get_mut = "pub(crate)"]
     recipient_channel: u32,
 
     #[get = "pub(crate)"]
@@ -232,7 +286,8 @@ impl Pack for ChannelRequest {
             Type::Signal(..) => "signal",
             Type::ExitStatus(..) => "exit-status",
             Type::ExitSignal(..) => "exit-signal",
-            Type::Unknown(name, ..) => &*name,
+            Type::AuthAgentReq(..) => "auth-agent-req@openssh.com",
+            Type::Unknown(name, ..) => name,
         }
         .pack(buf);
         self.want_reply.pack(buf);
@@ -249,7 +304,8 @@ impl Pack for ChannelRequest {
             Type::Signal(item) => item.pack(buf),
             Type::ExitStatus(item) => item.pack(buf),
             Type::ExitSignal(item) => item.pack(buf),
-            Type::Unknown(_, data) => buf.put(&data),
+            Type::AuthAgentReq(..) => {}
+            Type::Unknown(_, data) => buf.put(data),
         }
     }
 }
@@ -272,6 +328,7 @@ impl Unpack for ChannelRequest {
             "signal" => Type::Signal(Unpack::unpack(buf)?),
             "exit-status" => Type::ExitStatus(Unpack::unpack(buf)?),
             "exit-signal" => Type::ExitSignal(Unpack::unpack(buf)?),
+            "auth-agent-req@openssh.com" => Type::AuthAgentReq(()),
             x => Type::Unknown(x.into(), buf.copy_to_bytes(buf.remaining())),
         };
 
@@ -288,3 +345,100 @@ impl From<ChannelRequest> for Msg {
         Self::ChannelRequest(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unpack_modes(blob: &[u8]) -> Result<Modes, UnpackError> {
+        let mut buf = BytesMut::new();
+        Bytes::copy_from_slice(blob).pack(&mut buf);
+        Modes::unpack(&mut buf.freeze())
+    }
+
+    #[test]
+    fn test_x11_req_round_trip() {
+        let cookie = Bytes::from_static(b"\x00\xffcookie");
+        let x11 = X11Req::new(true, "MIT-MAGIC-COOKIE-1".into(), cookie.clone(), 2);
+        let msg = ChannelRequest::new(0, true, Type::X11Req(x11));
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+
+        let mut expect = vec![0, 0, 0, 0, 0, 0, 0, 7];
+        expect.extend_from_slice(b"x11-req");
+        expect.extend_from_slice(&[1, 1, 0, 0, 0, 18]);
+        expect.extend_from_slice(b"MIT-MAGIC-COOKIE-1");
+        expect.extend_from_slice(&[0, 0, 0, 8, 0, 0xff]);
+        expect.extend_from_slice(b"cookie");
+        expect.extend_from_slice(&[0, 0, 0, 2]);
+        assert_eq!(&buf[..], &expect[..]);
+
+        match ChannelRequest::unpack(&mut buf.freeze()).unwrap().typ() {
+            Type::X11Req(x11) => {
+                assert!(*x11.single_connection());
+                assert_eq!(x11.x11_auth_protocol(), "MIT-MAGIC-COOKIE-1");
+                assert_eq!(x11.x11_auth_cookie(), &cookie);
+                assert_eq!(*x11.x11_screen_number(), 2);
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_auth_agent_req_round_trip() {
+        let msg = ChannelRequest::new(0, false, Type::AuthAgentReq(()));
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+
+        let mut expect = vec![0, 0, 0, 0, 0, 0, 0, 26];
+        expect.extend_from_slice(b"auth-agent-req@openssh.com");
+        expect.push(0);
+        assert_eq!(&buf[..], &expect[..]);
+
+        match ChannelRequest::unpack(&mut buf.freeze()).unwrap().typ() {
+            Type::AuthAgentReq(()) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_exec_bytes_round_trip() {
+        let command = Bytes::from_static(b"cat caf\xe9 \xff");
+        let msg = ChannelRequest::new(0, true, Type::Exec(command.clone()));
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+        match ChannelRequest::unpack(&mut buf.freeze()).unwrap().typ() {
+            Type::Exec(received) => assert_eq!(received, &command),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_modes_round_trip() {
+        // VINTR ^C, ECHO on, TTY_OP_OSPEED 38400
+        let modes = Modes::new(vec![(1, 3), (53, 1), (129, 38400)]);
+        let mut buf = BytesMut::new();
+        modes.pack(&mut buf);
+        assert_eq!(buf.len(), 4 + 3 * 5 + 1);
+        assert_eq!(buf[buf.len() - 1], TTY_OP_END);
+        assert_eq!(Modes::unpack(&mut buf.freeze()).unwrap(), modes);
+    }
+
+    #[test]
+    fn test_modes_end() {
+        let modes = unpack_modes(&[53, 0, 0, 0, 1, TTY_OP_END, 54, 0, 0, 0, 1]).unwrap();
+        assert_eq!(modes.into_vec(), vec![(53, 1)]);
+
+        let modes = unpack_modes(&[53, 0, 0, 0, 1]).unwrap();
+        assert_eq!(modes.into_vec(), vec![(53, 1)]);
+        assert!(unpack_modes(&[]).unwrap().into_vec().is_empty());
+
+        assert!(unpack_modes(&[53, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_modes_unknown_opcode() {
+        let modes = unpack_modes(&[99, 0, 0, 0, 7, 160, 1, 2, 3, 53, 0, 0, 0, 1]).unwrap();
+        assert_eq!(modes.into_vec(), vec![(99, 7)]);
+    }
+}
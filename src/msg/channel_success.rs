@@ -1,32 +1,17 @@
 use derive_new::new;
+use getset::{Getters, MutGetters};
 
 use super::*;
 
-#[derive(Debug, new)]
+#[derive(Debug, new, Getters, MutGetters)]
 pub(crate) struct ChannelSuccess {
+    #[get = "pub(crate)"]
+    #[get_mut = "pub(crate)"]
     recipient_channel: u32,
 }
 
-impl MsgItem for ChannelSuccess {
-    const ID: u8 = 99;
-}
-
-impl Pack for ChannelSuccess {
-    fn pack<P: Put>(&self, buf: &mut P) {
-        self.recipient_channel.pack(buf);
-    }
-}
-
-impl Unpack for ChannelSuccess {
-    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
-        let recipient_channel = Unpack::unpack(buf)?;
-
-        Ok(Self { recipient_channel })
-    }
-}
-
-impl From<ChannelSuccess> for Msg {
-    fn from(v: ChannelSuccess) -> Self {
-        Self::ChannelSuccess(v)
+msg_item! {
+    Msg::ChannelSuccess(99) {
+        recipient_channel,
     }
 }
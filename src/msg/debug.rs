@@ -1,11 +1,15 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, new)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct Debug {
+    #[get = "pub(crate)"]
     always_display: bool,
+    #[get = "pub(crate)"]
     message: String,
+    #[get = "pub(crate)"]
     language_tag: String,
 }
 
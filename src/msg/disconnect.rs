@@ -1,9 +1,11 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug)]
-pub(crate) enum ReasonCode {
+/// SSH_MSG_DISCONNECT reason code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReasonCode {
     HostNotAllowedToConnect,
     ProtocolError,
     KeyExchangeFailed,
@@ -70,9 +72,11 @@ impl Unpack for ReasonCode {
     }
 }
 
-#[derive(Debug, new)]
+#[derive(Debug, new, Getters)]
 pub(crate) struct Disconnect {
+    #[get = "pub(crate)"]
     reason_code: ReasonCode,
+    #[get = "pub(crate)"]
     description: String,
     language_tag: String,
 }
@@ -108,3 +112,34 @@ impl From<Disconnect> for Msg {
         Self::Disconnect(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_reason_code_round_trip() {
+        for code in 0..=16 {
+            let mut buf = BytesMut::new();
+            code.pack(&mut buf);
+            let reason = ReasonCode::unpack(&mut buf.freeze()).unwrap();
+            let known = !matches!(reason, ReasonCode::Unknown(..));
+            assert_eq!(known, (1..=15).contains(&code), "{:?}", reason);
+
+            let mut buf = BytesMut::new();
+            reason.pack(&mut buf);
+            assert_eq!(u32::unpack(&mut buf.freeze()).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn test_disconnect_round_trip() {
+        let msg = Disconnect::new(ReasonCode::Unknown(0x100), "bye".into(), "en".into());
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+        let msg = Disconnect::unpack(&mut buf.freeze()).unwrap();
+        assert_eq!(msg.reason_code(), &ReasonCode::Unknown(0x100));
+        assert_eq!(msg.description(), "bye");
+    }
+}
@@ -0,0 +1,45 @@
+use derive_new::new;
+use getset::Getters;
+
+use super::*;
+
+/// [SSH_MSG_EXT_INFO](https://tools.ietf.org/html/rfc8308#section-2.3)
+#[derive(Debug, Getters, new)]
+pub(crate) struct ExtInfo {
+    #[get = "pub(crate)"]
+    extensions: Vec<(String, Bytes)>,
+}
+
+impl MsgItem for ExtInfo {
+    const ID: u8 = 7;
+}
+
+impl Pack for ExtInfo {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        (self.extensions.len() as u32).pack(buf);
+        for (name, value) in &self.extensions {
+            name.pack(buf);
+            value.pack(buf);
+        }
+    }
+}
+
+impl Unpack for ExtInfo {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let len = u32::unpack(buf)?;
+        let mut extensions = vec![];
+        for _ in 0..len {
+            let name = Unpack::unpack(buf)?;
+            let value = Unpack::unpack(buf)?;
+            extensions.push((name, value));
+        }
+
+        Ok(Self { extensions })
+    }
+}
+
+impl From<ExtInfo> for Msg {
+    fn from(v: ExtInfo) -> Self {
+        Self::ExtInfo(v)
+    }
+}
@@ -1,10 +1,14 @@
+use derive_new::new;
 use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct TcpipForward {
+    #[get = "pub(crate)"]
     address_to_bind: String,
+
+    #[get = "pub(crate)"]
     port_number_to_bind: u32,
 }
 
@@ -27,9 +31,12 @@ impl Unpack for TcpipForward {
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct CancelTcpipForward {
+    #[get = "pub(crate)"]
     address_to_bind: String,
+
+    #[get = "pub(crate)"]
     port_number_to_bind: u32,
 }
 
@@ -52,14 +59,69 @@ impl Unpack for CancelTcpipForward {
     }
 }
 
+/// `streamlocal-forward@openssh.com`, OpenSSH PROTOCOL section 2.4.
+#[derive(Debug, Getters, new)]
+pub(crate) struct StreamlocalForward {
+    #[get = "pub(crate)"]
+    socket_path: String,
+}
+
+impl Pack for StreamlocalForward {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        self.socket_path.pack(buf);
+    }
+}
+
+impl Unpack for StreamlocalForward {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let socket_path = Unpack::unpack(buf)?;
+
+        Ok(Self { socket_path })
+    }
+}
+
+/// Host keys as of `hostkeys-00@openssh.com` and its prove request.
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub(crate) struct Hostkeys(Vec<Bytes>);
+
+impl Hostkeys {
+    pub(crate) fn keys(&self) -> &[Bytes] {
+        &self.0
+    }
+}
+
+impl Pack for Hostkeys {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        for key in &self.0 {
+            key.pack(buf);
+        }
+    }
+}
+
+impl Unpack for Hostkeys {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let mut keys = vec![];
+        while buf.has_remaining() {
+            keys.push(Unpack::unpack(buf)?);
+        }
+        Ok(Self(keys))
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum Type {
     TcpipForward(TcpipForward),
     CancelTcpipForward(CancelTcpipForward),
+    StreamlocalForward(StreamlocalForward),
+    CancelStreamlocalForward(StreamlocalForward),
+    Keepalive,
+    NoMoreSessions,
+    Hostkeys(Hostkeys),
+    HostkeysProve(Hostkeys),
     Unknown(String, Bytes),
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct GlobalRequest {
     #[get = "pub(crate)"]
     want_reply: bool,
@@ -77,7 +139,13 @@ impl Pack for GlobalRequest {
         match &self.typ {
             Type::TcpipForward(..) => "tcpip-forward",
             Type::CancelTcpipForward(..) => "cancel-tcpip-forward",
-            Type::Unknown(t, ..) => &*t,
+            Type::StreamlocalForward(..) => "streamlocal-forward@openssh.com",
+            Type::CancelStreamlocalForward(..) => "cancel-streamlocal-forward@openssh.com",
+            Type::Keepalive => "keepalive@openssh.com",
+            Type::NoMoreSessions => "no-more-sessions@openssh.com",
+            Type::Hostkeys(..) => "hostkeys-00@openssh.com",
+            Type::HostkeysProve(..) => "hostkeys-prove-00@openssh.com",
+            Type::Unknown(t, ..) => t,
         }
         .pack(buf);
 
@@ -86,7 +154,10 @@ impl Pack for GlobalRequest {
         match &self.typ {
             Type::TcpipForward(x) => x.pack(buf),
             Type::CancelTcpipForward(x) => x.pack(buf),
-            Type::Unknown(_, x) => buf.put(&x),
+            Type::StreamlocalForward(x) | Type::CancelStreamlocalForward(x) => x.pack(buf),
+            Type::Keepalive | Type::NoMoreSessions => {}
+            Type::Hostkeys(x) | Type::HostkeysProve(x) => x.pack(buf),
+            Type::Unknown(_, x) => buf.put(x),
         }
     }
 }
@@ -98,6 +169,14 @@ impl Unpack for GlobalRequest {
         let typ = match &*typ {
             "tcpip-forward" => Type::TcpipForward(Unpack::unpack(buf)?),
             "cancel-tcpip-forward" => Type::CancelTcpipForward(Unpack::unpack(buf)?),
+            "streamlocal-forward@openssh.com" => Type::StreamlocalForward(Unpack::unpack(buf)?),
+            "cancel-streamlocal-forward@openssh.com" => {
+                Type::CancelStreamlocalForward(Unpack::unpack(buf)?)
+            }
+            "keepalive@openssh.com" => Type::Keepalive,
+            "no-more-sessions@openssh.com" => Type::NoMoreSessions,
+            "hostkeys-00@openssh.com" => Type::Hostkeys(Unpack::unpack(buf)?),
+            "hostkeys-prove-00@openssh.com" => Type::HostkeysProve(Unpack::unpack(buf)?),
             x => Type::Unknown(x.to_string(), buf.copy_to_bytes(buf.remaining())),
         };
 
@@ -110,3 +189,69 @@ impl From<GlobalRequest> for Msg {
         Self::GlobalRequest(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut as _, BytesMut};
+
+    #[test]
+    fn test_hostkeys_round_trip() {
+        let keys = Hostkeys::new(vec![Bytes::from_static(b"k1"), Bytes::from_static(b"k2")]);
+        let msg = GlobalRequest::new(true, Type::HostkeysProve(keys.clone()));
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+        let msg = GlobalRequest::unpack(&mut buf.freeze()).unwrap();
+        assert!(*msg.want_reply());
+        match msg.typ() {
+            Type::HostkeysProve(received) => assert_eq!(received, &keys),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_streamlocal_forward_round_trip() {
+        // Laid out as OpenSSH `ssh -R /tmp/fwd.sock:...` sends it, after the id byte.
+        let mut wire = vec![0, 0, 0, 31];
+        wire.extend_from_slice(b"streamlocal-forward@openssh.com");
+        wire.extend_from_slice(&[1, 0, 0, 0, 13]);
+        wire.extend_from_slice(b"/tmp/fwd.sock");
+        let msg = GlobalRequest::unpack(&mut Bytes::from(wire.clone())).unwrap();
+        assert!(*msg.want_reply());
+        match msg.typ() {
+            Type::StreamlocalForward(item) => assert_eq!(item.socket_path(), "/tmp/fwd.sock"),
+            x => panic!("{:?}", x),
+        }
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+        assert_eq!(&buf[..], &wire[..]);
+
+        let item = StreamlocalForward::new("/tmp/fwd.sock".into());
+        let msg = GlobalRequest::new(false, Type::CancelStreamlocalForward(item));
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+        let msg = GlobalRequest::unpack(&mut buf.freeze()).unwrap();
+        match msg.typ() {
+            Type::CancelStreamlocalForward(item) => {
+                assert_eq!(item.socket_path(), "/tmp/fwd.sock")
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_unknown_keeps_data() {
+        let mut buf = BytesMut::new();
+        "vendor@example.com".pack(&mut buf);
+        false.pack(&mut buf);
+        buf.put_slice(b"opaque");
+        let msg = GlobalRequest::unpack(&mut buf.freeze()).unwrap();
+        match msg.typ() {
+            Type::Unknown(name, data) => {
+                assert_eq!(name, "vendor@example.com");
+                assert_eq!(data, &Bytes::from_static(b"opaque"));
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+}
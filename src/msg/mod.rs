@@ -5,6 +5,48 @@ use bytes::{Buf, Bytes, BytesMut};
 use crate::pack::NameList;
 use crate::pack::{Pack, Put, Unpack, UnpackError};
 
+/// Message id, [`Pack`], [`Unpack`] and conversion into the `Msg` enum of
+/// a message struct, from its fields in wire order.
+///
+/// Each field packs as its type does. A field after `..` is the rest of the
+/// payload, taken as is.
+macro_rules! msg_item {
+    (
+        $msg:ident :: $name:ident ( $id:expr ) {
+            $($field:ident,)*
+            $(.. $rest:ident,)?
+        }
+    ) => {
+        impl MsgItem<$msg> for $name {
+            const ID: u8 = $id;
+        }
+
+        impl Pack for $name {
+            fn pack<P: Put>(&self, buf: &mut P) {
+                $(self.$field.pack(buf);)*
+                $(buf.put(&self.$rest);)?
+            }
+        }
+
+        impl Unpack for $name {
+            fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+                $(let $field = Unpack::unpack(buf)?;)*
+                $(let $rest = buf.copy_to_bytes(buf.remaining());)?
+                Ok(Self {
+                    $($field,)*
+                    $($rest,)?
+                })
+            }
+        }
+
+        impl From<$name> for $msg {
+            fn from(v: $name) -> Self {
+                Self::$name(v)
+            }
+        }
+    };
+}
+
 pub(crate) mod channel_close;
 pub(crate) mod channel_data;
 pub(crate) mod channel_eof;
@@ -18,6 +60,7 @@ pub(crate) mod channel_success;
 pub(crate) mod channel_window_adjust;
 pub(crate) mod debug;
 pub(crate) mod disconnect;
+pub(crate) mod ext_info;
 pub(crate) mod global_request;
 pub(crate) mod ignore;
 pub(crate) mod kex_dh_gex_group;
@@ -37,6 +80,8 @@ pub(crate) mod unimplemented;
 pub(crate) mod unknown;
 pub(crate) mod userauth_banner;
 pub(crate) mod userauth_failure;
+pub(crate) mod userauth_info_request;
+pub(crate) mod userauth_info_response;
 pub(crate) mod userauth_passwd_changereq;
 pub(crate) mod userauth_pk_ok;
 pub(crate) mod userauth_request;
@@ -51,7 +96,12 @@ trait MsgItem<M = Msg>: Pack + Unpack + Into<M> {
     }
 }
 
-pub(crate) trait ContextualMsg: Into<Msg> + Pack + Unpack + fmt::Debug {}
+pub(crate) trait ContextualMsg: Into<Msg> + Pack + Unpack + MsgName + fmt::Debug {}
+
+/// Message type, for diagnostics leaving out the content.
+pub(crate) trait MsgName {
+    fn name(&self) -> &'static str;
+}
 
 macro_rules! Msg {
     (
@@ -81,6 +131,15 @@ macro_rules! Msg {
             }
         }
 
+        impl MsgName for $ty {
+            fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$name(..) => stringify!($name),)+
+                    Self::Unknown(..) => "Unknown",
+                }
+            }
+        }
+
         impl Pack for $ty {
             fn pack<P: Put>(&self, buf: &mut P) {
                 match self {
@@ -99,6 +158,12 @@ macro_rules! Msg {
                     $(<$type as MsgItem<$ty>>::ID => <$type as Unpack>::unpack(buf)?.into(),)+
                     v => Self::Unknown(v, Unpack::unpack(buf)?),
                 };
+                if buf.has_remaining() {
+                    return Err(UnpackError::TrailingBytes {
+                        msg: result.name(),
+                        len: buf.remaining(),
+                    });
+                }
                 Ok(result)
             }
         }
@@ -113,6 +178,7 @@ Msg! {
         Debug(debug::Debug),
         ServiceRequest(service_request::ServiceRequest),
         ServiceAccept(service_accept::ServiceAccept),
+        ExtInfo(ext_info::ExtInfo),
         Kexinit(kexinit::BoxKexinit),
         NewKeys(new_keys::NewKeys),
         KexEcdhInit(kex_ecdh_init::KexEcdhInit),
@@ -122,6 +188,7 @@ Msg! {
         UserauthSuccess(userauth_success::UserauthSuccess),
         UserauthBanner(userauth_banner::UserauthBanner),
         UserauthPasswdChangereq(userauth_passwd_changereq::UserauthPasswdChangereq), // FIXME
+        UserauthInfoResponse(userauth_info_response::UserauthInfoResponse),
         GlobalRequest(global_request::GlobalRequest),
         RequestSuccess(request_success::RequestSuccess),
         RequestFailure(request_failure::RequestFailure),
@@ -155,6 +222,43 @@ Msg! {
     }
 }
 
+Msg! {
+    UserauthInfoMsg {
+        UserauthInfoRequest(userauth_info_request::UserauthInfoRequest),
+    }
+}
+
+impl Msg {
+    /// Recipient of channel messages sent after the channel was opened.
+    pub(crate) fn recipient_channel_mut(&mut self) -> Option<&mut u32> {
+        match self {
+            Self::ChannelWindowAdjust(msg) => Some(msg.recipient_channel_mut()),
+            Self::ChannelData(msg) => Some(msg.recipient_channel_mut()),
+            Self::ChannelExtendedData(msg) => Some(msg.recipient_channel_mut()),
+            Self::ChannelEof(msg) => Some(msg.recipient_channel_mut()),
+            Self::ChannelClose(msg) => Some(msg.recipient_channel_mut()),
+            Self::ChannelRequest(msg) => Some(msg.recipient_channel_mut()),
+            Self::ChannelSuccess(msg) => Some(msg.recipient_channel_mut()),
+            Self::ChannelFailure(msg) => Some(msg.recipient_channel_mut()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn recipient_channel(&self) -> Option<u32> {
+        match self {
+            Self::ChannelWindowAdjust(msg) => Some(*msg.recipient_channel()),
+            Self::ChannelData(msg) => Some(*msg.recipient_channel()),
+            Self::ChannelExtendedData(msg) => Some(*msg.recipient_channel()),
+            Self::ChannelEof(msg) => Some(*msg.recipient_channel()),
+            Self::ChannelClose(msg) => Some(*msg.recipient_channel()),
+            Self::ChannelRequest(msg) => Some(*msg.recipient_channel()),
+            Self::ChannelSuccess(msg) => Some(*msg.recipient_channel()),
+            Self::ChannelFailure(msg) => Some(*msg.recipient_channel()),
+            _ => None,
+        }
+    }
+}
+
 impl ContextualMsg for GexMsg {}
 
 impl From<GexMsg> for Msg {
@@ -171,6 +275,14 @@ impl From<UserauthPkMsg> for Msg {
     }
 }
 
+impl ContextualMsg for UserauthInfoMsg {}
+
+impl From<UserauthInfoMsg> for Msg {
+    fn from(v: UserauthInfoMsg) -> Self {
+        v.into_unknown()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,4 +293,262 @@ mod tests {
 
         assert::<Msg>();
     }
+
+    #[test]
+    fn test_name() {
+        let msg = Msg::from(channel_close::ChannelClose::new(1));
+        assert_eq!(msg.name(), "ChannelClose");
+
+        // Type only, never the password.
+        let mut buf = Bytes::from_static(
+            b"\x32\0\0\0\x05alice\0\0\0\x0essh-connection\0\0\0\x08password\0\0\0\0\x06secret",
+        );
+        let msg = Msg::unpack(&mut buf).unwrap();
+        assert_eq!(msg.name(), "UserauthRequest");
+
+        let msg = Msg::unpack(&mut Bytes::from_static(&[200, 1, 2])).unwrap();
+        assert_eq!(msg.name(), "Unknown");
+    }
+
+    #[test]
+    fn test_pack_eow() {
+        use bytes::BytesMut;
+        use channel_request::{ChannelRequest, Type};
+
+        let msg = Msg::from(ChannelRequest::new(
+            1,
+            false,
+            Type::Unknown("eow@openssh.com".into(), Bytes::new()),
+        ));
+        let mut buf = BytesMut::new();
+        msg.pack(&mut buf);
+
+        let mut expect = vec![98, 0, 0, 0, 1, 0, 0, 0, 15];
+        expect.extend_from_slice(b"eow@openssh.com");
+        expect.push(0);
+        assert_eq!(&buf[..], &expect[..]);
+    }
+
+    /// Channel messages and their exact encoding.
+    fn golden() -> Vec<(Msg, &'static [u8])> {
+        use channel_extended_data::DataTypeCode;
+        use channel_open_failure::ReasonCode;
+
+        vec![
+            (
+                channel_open_confirmation::ChannelOpenConfirmation::new(
+                    1,
+                    2,
+                    0x20_0000,
+                    0x8000,
+                    Bytes::from_static(b"xy"),
+                )
+                .into(),
+                b"\x5b\0\0\0\x01\0\0\0\x02\0\x20\0\0\0\0\x80\0xy",
+            ),
+            (
+                channel_open_failure::ChannelOpenFailure::new(
+                    1,
+                    ReasonCode::ConnectFailed,
+                    "no".into(),
+                    "en".into(),
+                )
+                .into(),
+                b"\x5c\0\0\0\x01\0\0\0\x02\0\0\0\x02no\0\0\0\x02en",
+            ),
+            (
+                channel_window_adjust::ChannelWindowAdjust::new(3, 0x1_0000).into(),
+                b"\x5d\0\0\0\x03\0\x01\0\0",
+            ),
+            (
+                channel_data::ChannelData::new(3, Bytes::from_static(b"hi")).into(),
+                b"\x5e\0\0\0\x03\0\0\0\x02hi",
+            ),
+            (
+                channel_extended_data::ChannelExtendedData::new(
+                    3,
+                    DataTypeCode::Stderr,
+                    Bytes::from_static(b"err"),
+                )
+                .into(),
+                b"\x5f\0\0\0\x03\0\0\0\x01\0\0\0\x03err",
+            ),
+            (channel_eof::ChannelEof::new(4).into(), b"\x60\0\0\0\x04"),
+            (
+                channel_close::ChannelClose::new(4).into(),
+                b"\x61\0\0\0\x04",
+            ),
+            (
+                channel_success::ChannelSuccess::new(5).into(),
+                b"\x63\0\0\0\x05",
+            ),
+            (
+                channel_failure::ChannelFailure::new(5).into(),
+                b"\x64\0\0\0\x05",
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_golden() {
+        for (msg, expect) in golden() {
+            let name = msg.name();
+            let mut buf = BytesMut::new();
+            msg.pack(&mut buf);
+            assert_eq!(&buf[..], expect, "{}", name);
+
+            let msg = Msg::unpack(&mut Bytes::from_static(expect)).unwrap();
+            assert_eq!(msg.name(), name);
+            let mut buf = BytesMut::new();
+            msg.pack(&mut buf);
+            assert_eq!(&buf[..], expect, "{}", name);
+        }
+    }
+
+    macro_rules! pack {
+        ($buf:expr; $($field:expr),+) => {{
+            $($field.pack($buf);)+
+        }};
+    }
+
+    /// Well formed payload of every message type, and if it ends with data
+    /// taken as is.
+    fn samples() -> Vec<(Bytes, bool)> {
+        fn payload(id: u8, fields: impl FnOnce(&mut BytesMut)) -> Bytes {
+            let mut buf = BytesMut::new();
+            id.pack(&mut buf);
+            fields(&mut buf);
+            buf.freeze()
+        }
+        let names = ["a", "b"].iter().copied().collect::<NameList>();
+
+        vec![
+            (payload(1, |b| pack!(b; 2u32, "bye", "")), false),
+            (payload(2, |b| "x".pack(b)), false),
+            (payload(3, |b| 7u32.pack(b)), false),
+            (payload(4, |b| pack!(b; true, "msg", "")), false),
+            (payload(5, |b| "ssh-userauth".pack(b)), false),
+            (payload(6, |b| "ssh-userauth".pack(b)), false),
+            (
+                payload(7, |b| pack!(b; 1u32, "server-sig-algs", "ssh-ed25519")),
+                false,
+            ),
+            (
+                payload(20, |b| {
+                    0u128.pack(b);
+                    for _ in 0..10 {
+                        names.pack(b);
+                    }
+                    pack!(b; false, 0u32);
+                }),
+                false,
+            ),
+            (payload(21, |_| {}), false),
+            (payload(30, |b| Bytes::from_static(&[7; 32]).pack(b)), false),
+            (
+                payload(31, |b| {
+                    let mut key = BytesMut::new();
+                    pack!(&mut key; "ssh-ed25519", Bytes::from_static(&[1; 32]));
+                    let mut signature = BytesMut::new();
+                    pack!(&mut signature; "ssh-ed25519", Bytes::from_static(&[2; 64]));
+                    pack!(b; key.freeze(), Bytes::from_static(&[7; 32]), signature.freeze());
+                }),
+                false,
+            ),
+            (
+                payload(
+                    50,
+                    |b| pack!(b; "alice", "ssh-connection", "password", false, "secret"),
+                ),
+                false,
+            ),
+            (payload(51, |b| pack!(b; names.clone(), false)), false),
+            (payload(52, |_| {}), false),
+            (payload(53, |b| pack!(b; "welcome", "")), false),
+            (payload(60, |b| pack!(b; "expired", "")), false),
+            (payload(61, |b| pack!(b; 2u32, "one", "two")), false),
+            (
+                payload(
+                    80,
+                    |b| pack!(b; "tcpip-forward", true, "localhost", 8080u32),
+                ),
+                false,
+            ),
+            (payload(81, |_| {}), true),
+            (payload(82, |_| {}), false),
+            (
+                payload(90, |b| pack!(b; "session", 0u32, 1024u32, 1024u32)),
+                false,
+            ),
+            (
+                payload(91, |b| pack!(b; 0u32, 1u32, 1024u32, 1024u32)),
+                true,
+            ),
+            (payload(92, |b| pack!(b; 0u32, 2u32, "no", "")), false),
+            (payload(93, |b| pack!(b; 0u32, 1024u32)), false),
+            (payload(94, |b| pack!(b; 0u32, "data")), false),
+            (payload(95, |b| pack!(b; 0u32, 1u32, "data")), false),
+            (payload(96, |b| 0u32.pack(b)), false),
+            (payload(97, |b| 0u32.pack(b)), false),
+            (
+                payload(98, |b| pack!(b; 0u32, "exit-status", false, 0u32)),
+                false,
+            ),
+            (payload(99, |b| 0u32.pack(b)), false),
+            (payload(100, |b| 0u32.pack(b)), false),
+        ]
+    }
+
+    #[test]
+    fn test_samples_round_trip() {
+        for (sample, _) in samples() {
+            let msg = Msg::unpack(&mut sample.clone()).unwrap();
+            assert!(!matches!(msg, Msg::Unknown(..)), "{:?}", msg);
+            let mut buf = BytesMut::new();
+            msg.pack(&mut buf);
+            assert_eq!(buf.freeze(), sample);
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        for (sample, _) in samples() {
+            for len in 0..sample.len() {
+                let result = Msg::unpack(&mut sample.slice(..len));
+                assert!(result.is_err(), "{:?} {:?}", &sample[..len], result);
+            }
+        }
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        for (sample, rest) in samples() {
+            let name = Msg::unpack(&mut sample.clone()).unwrap().name();
+            let mut buf = BytesMut::from(&sample[..]);
+            buf.extend_from_slice(b"junk");
+            match Msg::unpack(&mut buf.freeze()) {
+                Ok(..) if rest => {}
+                Err(e) => assert_eq!(e, UnpackError::TrailingBytes { msg: name, len: 4 }),
+                result => panic!("{:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_length() {
+        let mut data = Bytes::from_static(b"\x5e\0\0\0\0\xff\xff\xff\xffdata");
+        assert_eq!(
+            Msg::unpack(&mut data).unwrap_err(),
+            UnpackError::UnexpectedEof
+        );
+
+        // Any field may claim the largest length, nothing may panic.
+        for (sample, _) in samples() {
+            for at in 1..sample.len().saturating_sub(3) {
+                let mut buf = BytesMut::from(&sample[..]);
+                buf[at..at + 4].copy_from_slice(&[0xff; 4]);
+                let _ = Msg::unpack(&mut buf.freeze());
+            }
+        }
+    }
 }
@@ -1,9 +1,11 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, new)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct Unimplemented {
+    #[get = "pub(crate)"]
     pkt_seq: u32,
 }
 
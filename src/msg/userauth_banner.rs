@@ -1,10 +1,13 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, new)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct UserauthBanner {
+    #[get = "pub(crate)"]
     message: String,
+    #[get = "pub(crate)"]
     language_tag: String,
 }
 
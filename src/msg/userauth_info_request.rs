@@ -0,0 +1,59 @@
+use derive_new::new;
+use getset::Getters;
+
+use super::*;
+
+#[derive(Debug, Getters, new)]
+pub(crate) struct UserauthInfoRequest {
+    name: String,
+    instruction: String,
+    language_tag: String,
+    /// Prompt and whether the response is echoed.
+    #[get = "pub(crate)"]
+    prompts: Vec<(String, bool)>,
+}
+
+impl MsgItem<UserauthInfoMsg> for UserauthInfoRequest {
+    const ID: u8 = 60;
+}
+
+impl Pack for UserauthInfoRequest {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        self.name.pack(buf);
+        self.instruction.pack(buf);
+        self.language_tag.pack(buf);
+        (self.prompts.len() as u32).pack(buf);
+        for (prompt, echo) in &self.prompts {
+            prompt.pack(buf);
+            echo.pack(buf);
+        }
+    }
+}
+
+impl Unpack for UserauthInfoRequest {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let name = Unpack::unpack(buf)?;
+        let instruction = Unpack::unpack(buf)?;
+        let language_tag = Unpack::unpack(buf)?;
+        let num_prompts = u32::unpack(buf)?;
+        let mut prompts = vec![];
+        for _ in 0..num_prompts {
+            let prompt = Unpack::unpack(buf)?;
+            let echo = Unpack::unpack(buf)?;
+            prompts.push((prompt, echo));
+        }
+
+        Ok(Self {
+            name,
+            instruction,
+            language_tag,
+            prompts,
+        })
+    }
+}
+
+impl From<UserauthInfoRequest> for UserauthInfoMsg {
+    fn from(v: UserauthInfoRequest) -> Self {
+        Self::UserauthInfoRequest(v)
+    }
+}
@@ -0,0 +1,66 @@
+use derive_new::new;
+use getset::Getters;
+
+use super::*;
+
+#[derive(Getters, new)]
+pub(crate) struct UserauthInfoResponse {
+    #[get = "pub(crate)"]
+    responses: Vec<String>,
+}
+
+impl fmt::Debug for UserauthInfoResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserauthInfoResponse")
+            .field(
+                "responses",
+                &format_args!("<{} redacted>", self.responses.len()),
+            )
+            .finish()
+    }
+}
+
+impl MsgItem for UserauthInfoResponse {
+    const ID: u8 = 61;
+}
+
+impl Pack for UserauthInfoResponse {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        (self.responses.len() as u32).pack(buf);
+        for response in &self.responses {
+            response.pack(buf);
+        }
+    }
+}
+
+impl Unpack for UserauthInfoResponse {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let num_responses = u32::unpack(buf)?;
+        let mut responses = vec![];
+        for _ in 0..num_responses {
+            responses.push(Unpack::unpack(buf)?);
+        }
+
+        Ok(Self { responses })
+    }
+}
+
+impl From<UserauthInfoResponse> for Msg {
+    fn from(v: UserauthInfoResponse) -> Self {
+        Self::UserauthInfoResponse(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_redacted() {
+        let msg = UserauthInfoResponse::new(vec!["hunter2".into(), "123456".into()]);
+        let debug = format!("{:?}", Msg::from(msg));
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(!debug.contains("123456"), "{}", debug);
+        assert!(debug.contains("<2 redacted>"), "{}", debug);
+    }
+}
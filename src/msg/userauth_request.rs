@@ -45,7 +45,7 @@ impl Unpack for Publickey {
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Getters)]
 pub(crate) struct Password {
     #[get = "pub(crate)"]
     password: String,
@@ -54,6 +54,16 @@ pub(crate) struct Password {
     newpassword: Option<String>,
 }
 
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = format_args!("<redacted>");
+        f.debug_struct("Password")
+            .field("password", &redacted)
+            .field("newpassword", &self.newpassword.as_ref().map(|_| redacted))
+            .finish()
+    }
+}
+
 impl Pack for Password {
     fn pack<P: Put>(&self, buf: &mut P) {
         self.newpassword.is_some().pack(buf);
@@ -127,15 +137,59 @@ impl Unpack for Hostbased {
     }
 }
 
+#[derive(Debug, Getters)]
+pub(crate) struct KeyboardInteractive {
+    #[get = "pub(crate)"]
+    language_tag: String,
+
+    /// Comma separated hints, may be ignored.
+    #[get = "pub(crate)"]
+    submethods: String,
+}
+
+impl Pack for KeyboardInteractive {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        self.language_tag.pack(buf);
+        self.submethods.pack(buf);
+    }
+}
+
+impl Unpack for KeyboardInteractive {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let language_tag = Unpack::unpack(buf)?;
+        let submethods = Unpack::unpack(buf)?;
+
+        Ok(Self {
+            language_tag,
+            submethods,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum Method {
     None,
     Publickey(Publickey),
     Password(Password),
     Hostbased(Hostbased),
+    KeyboardInteractive(KeyboardInteractive),
     Unknown(String, Bytes),
 }
 
+impl Method {
+    /// Method name on the wire.
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Self::None => "none",
+            Self::Publickey(..) => "publickey",
+            Self::Password(..) => "password",
+            Self::Hostbased(..) => "hostbased",
+            Self::KeyboardInteractive(..) => "keyboard-interactive",
+            Self::Unknown(name, _) => name,
+        }
+    }
+}
+
 impl Pack for Method {
     fn pack<P: Put>(&self, buf: &mut P) {
         match self {
@@ -152,6 +206,10 @@ impl Pack for Method {
                 "hostbased".pack(buf);
                 item.pack(buf)
             }
+            Self::KeyboardInteractive(item) => {
+                "keyboard-interactive".pack(buf);
+                item.pack(buf)
+            }
             Self::Unknown(name, item) => {
                 name.pack(buf);
                 buf.put(item);
@@ -168,6 +226,7 @@ impl Unpack for Method {
             "publickey" => Self::Publickey(Unpack::unpack(buf)?),
             "password" => Self::Password(Unpack::unpack(buf)?),
             "hostbased" => Self::Hostbased(Unpack::unpack(buf)?),
+            "keyboard-interactive" => Self::KeyboardInteractive(Unpack::unpack(buf)?),
             x => Self::Unknown(x.into(), buf.copy_to_bytes(buf.remaining())),
         })
     }
@@ -214,3 +273,37 @@ impl From<UserauthRequest> for Msg {
         Self::UserauthRequest(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password_request(newpassword: Option<&str>) -> UserauthRequest {
+        let mut buf = BytesMut::new();
+        "alice".pack(&mut buf);
+        "ssh-connection".pack(&mut buf);
+        "password".pack(&mut buf);
+        newpassword.is_some().pack(&mut buf);
+        "hunter2".pack(&mut buf);
+        if let Some(newpassword) = newpassword {
+            newpassword.pack(&mut buf);
+        }
+        UserauthRequest::unpack(&mut buf.freeze()).unwrap()
+    }
+
+    #[test]
+    fn test_password_debug_redacted() {
+        let msg = password_request(None);
+        let debug = format!("{:?}", msg);
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(debug.contains("password: <redacted>"), "{}", debug);
+        assert!(debug.contains("newpassword: None"), "{}", debug);
+        let debug = format!("{:?}", Msg::from(msg));
+        assert!(!debug.contains("hunter2"), "{}", debug);
+
+        let debug = format!("{:?}", password_request(Some("correct horse")));
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(!debug.contains("correct horse"), "{}", debug);
+        assert!(debug.contains("newpassword: Some(<redacted>)"), "{}", debug);
+    }
+}
@@ -1,5 +1,7 @@
+use std::fmt;
 use std::hash;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use derive_builder::Builder;
 use getset::Getters;
@@ -15,6 +17,103 @@ use crate::{cipher, comp, kex, key, mac};
 #[error("unknown algorithm name {0}")]
 pub struct UnknownNameError(pub(crate) String);
 
+/// Negotiated algorithm lists of kexinit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlgorithmKind {
+    Kex,
+    HostKey,
+    CipherC2s,
+    CipherS2c,
+    MacC2s,
+    MacS2c,
+    CompressionC2s,
+    CompressionS2c,
+}
+
+impl fmt::Display for AlgorithmKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Kex => "kex",
+            Self::HostKey => "host key",
+            Self::CipherC2s => "cipher client to server",
+            Self::CipherS2c => "cipher server to client",
+            Self::MacC2s => "mac client to server",
+            Self::MacS2c => "mac server to client",
+            Self::CompressionC2s => "compression client to server",
+            Self::CompressionS2c => "compression server to client",
+        };
+        f.write_str(name)
+    }
+}
+
+/// No algorithm of list `what` offered by both sides.
+#[derive(Debug, Clone, PartialEq, Eq, Error, Getters)]
+#[error("no matching {what} algorithm (client: {}; server: {})", .client.join(","), .server.join(","))]
+pub struct NegotiateError {
+    #[get = "pub"]
+    what: AlgorithmKind,
+
+    #[get = "pub"]
+    client: Vec<String>,
+
+    #[get = "pub"]
+    server: Vec<String>,
+}
+
+/// Algorithm implemented outside of this crate.
+///
+/// Compared and hashed by name only.
+pub struct Registered<F: ?Sized> {
+    name: String,
+    factory: Arc<F>,
+}
+
+impl<F: ?Sized> Registered<F> {
+    pub(crate) fn new(name: &str, factory: Arc<F>) -> Self {
+        Self {
+            name: name.into(),
+            factory,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn factory(&self) -> &F {
+        &self.factory
+    }
+}
+
+impl<F: ?Sized> Clone for Registered<F> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            factory: self.factory.clone(),
+        }
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Registered<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Registered").field(&self.name).finish()
+    }
+}
+
+impl<F: ?Sized> PartialEq for Registered<F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl<F: ?Sized> Eq for Registered<F> {}
+
+impl<F: ?Sized> hash::Hash for Registered<F> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state)
+    }
+}
+
 pub(crate) trait AlgorithmName:
     FromStr<Err = UnknownNameError> + AsRef<str> + Clone + PartialEq + Eq + hash::Hash
 {
@@ -25,27 +124,65 @@ pub(crate) trait AlgorithmName:
     }
 }
 
-#[derive(Debug, Builder, Getters)]
-pub(crate) struct Algorithm {
-    #[get = "pub(crate)"]
+/// Language tags sent by the client in kexinit.
+///
+/// Informational only. Never affects negotiation.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Getters)]
+pub struct Languages {
+    /// Client to server language tags.
+    #[get = "pub"]
+    client_to_server: Vec<String>,
+
+    /// Server to client language tags.
+    #[get = "pub"]
+    server_to_client: Vec<String>,
+}
+
+impl Languages {
+    pub(crate) fn new(client_to_server: Vec<String>, server_to_client: Vec<String>) -> Self {
+        Self {
+            client_to_server,
+            server_to_client,
+        }
+    }
+}
+
+/// Algorithms negotiated by a key exchange.
+#[derive(Debug, Clone, Builder, Getters)]
+pub struct Algorithm {
+    #[get = "pub"]
     kex_algorithm: kex::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     server_host_key_algorithm: key::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     cipher_algorithm_c2s: cipher::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     cipher_algorithm_s2c: cipher::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     mac_algorithm_c2s: mac::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     mac_algorithm_s2c: mac::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     compression_algorithm_c2s: comp::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     compression_algorithm_s2c: comp::Algorithm,
+    #[get = "pub"]
+    languages: Languages,
+}
+
+impl Algorithm {
+    /// Whether the client's preferred kex and host key algorithms were chosen.
+    ///
+    /// Otherwise a packet the client guessed after KEXINIT must be ignored.
+    pub(crate) fn guessed_by(&self, c_kexinit: &Kexinit) -> bool {
+        let first = |list: &NameList| list.iter().next().cloned();
+        first(c_kexinit.kex_algorithms()).as_deref() == Some(self.kex_algorithm.as_ref())
+            && first(c_kexinit.server_host_key_algorithms()).as_deref()
+                == Some(self.server_host_key_algorithm.as_ref())
+    }
 }
 
-fn decide<N>(l: &[N], r: &NameList) -> Result<N, SshError>
+fn decide<N>(what: AlgorithmKind, l: &[N], r: &NameList) -> Result<N, NegotiateError>
 where
     N: AlgorithmName,
 {
@@ -54,8 +191,10 @@ where
         .flat_map(|r| l.iter().filter(move |l| r.as_str() == l.as_ref()))
         .next();
 
-    found.map(ToOwned::to_owned).ok_or_else(|| {
-        SshError::NegotiateNotMatched(r.iter().map(AsRef::as_ref).collect::<Vec<_>>().join(","))
+    found.map(ToOwned::to_owned).ok_or_else(|| NegotiateError {
+        what,
+        client: r.iter().cloned().collect(),
+        server: l.iter().map(|l| l.as_ref().to_string()).collect(),
     })
 }
 
@@ -65,45 +204,68 @@ pub(crate) fn negotiate(
 ) -> Result<Algorithm, SshError> {
     let mut builder = AlgorithmBuilder::default();
 
-    let kex_algorithm = decide(preference.kex_algorithms(), c_kexinit.kex_algorithms())?;
+    let kex_algorithm = decide(
+        AlgorithmKind::Kex,
+        preference.kex_algorithms(),
+        c_kexinit.kex_algorithms(),
+    )?;
     builder.kex_algorithm(kex_algorithm);
 
     let server_host_key_algorithm = decide(
-        &preference.hostkeys().names(),
+        AlgorithmKind::HostKey,
+        preference.host_key_algorithms(),
         c_kexinit.server_host_key_algorithms(),
     )?;
     builder.server_host_key_algorithm(server_host_key_algorithm);
 
     let cipher_algorithm_c2s = decide(
+        AlgorithmKind::CipherC2s,
         preference.cipher_algorithms(),
         c_kexinit.cipher_algorithms_c2s(),
     )?;
     builder.cipher_algorithm_c2s(cipher_algorithm_c2s);
 
     let cipher_algorithm_s2c = decide(
+        AlgorithmKind::CipherS2c,
         preference.cipher_algorithms(),
         c_kexinit.cipher_algorithms_s2c(),
     )?;
     builder.cipher_algorithm_s2c(cipher_algorithm_s2c);
 
-    let mac_algorithm_c2s = decide(preference.mac_algorithms(), c_kexinit.mac_algorithms_c2s())?;
+    let mac_algorithm_c2s = decide(
+        AlgorithmKind::MacC2s,
+        preference.mac_algorithms(),
+        c_kexinit.mac_algorithms_c2s(),
+    )?;
     builder.mac_algorithm_c2s(mac_algorithm_c2s);
 
-    let mac_algorithm_s2c = decide(preference.mac_algorithms(), c_kexinit.mac_algorithms_s2c())?;
+    let mac_algorithm_s2c = decide(
+        AlgorithmKind::MacS2c,
+        preference.mac_algorithms(),
+        c_kexinit.mac_algorithms_s2c(),
+    )?;
     builder.mac_algorithm_s2c(mac_algorithm_s2c);
 
     let compression_algorithm_c2s = decide(
+        AlgorithmKind::CompressionC2s,
         preference.compression_algorithms(),
         c_kexinit.compression_algorithms_c2s(),
     )?;
     builder.compression_algorithm_c2s(compression_algorithm_c2s);
 
     let compression_algorithm_s2c = decide(
+        AlgorithmKind::CompressionS2c,
         preference.compression_algorithms(),
         c_kexinit.compression_algorithms_s2c(),
     )?;
     builder.compression_algorithm_s2c(compression_algorithm_s2c);
 
+    let languages = Languages::new(
+        c_kexinit.languages_c2s().iter().cloned().collect(),
+        c_kexinit.languages_s2c().iter().cloned().collect(),
+    );
+    builder.languages(languages);
+
     Ok(builder.build().unwrap())
 }
 
@@ -112,33 +274,83 @@ mod tests {
     use super::*;
 
     fn list<'a, V: AsRef<[&'a str]>>(v: V) -> NameList {
-        v.as_ref().into_iter().map(ToOwned::to_owned).collect()
+        v.as_ref().iter().map(ToOwned::to_owned).collect()
     }
 
     #[test]
     fn test_decide() {
         use mac::Algorithm::*;
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha1"]));
+        let r = decide(AlgorithmKind::MacC2s, &[HmacSha1], &list(["hmac-sha1"]));
         assert_eq!(r.unwrap(), HmacSha1);
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha2-256"]));
-        assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
+        let r = decide(AlgorithmKind::MacC2s, &[HmacSha1], &list(["hmac-sha2-256"]));
+        let e = r.unwrap_err();
+        assert_eq!(e.what(), &AlgorithmKind::MacC2s);
+        assert_eq!(e.client(), &["hmac-sha2-256"]);
+        assert_eq!(e.server(), &["hmac-sha1"]);
+        assert_eq!(
+            e.to_string(),
+            "no matching mac client to server algorithm (client: hmac-sha2-256; server: hmac-sha1)"
+        );
 
-        let r = decide(&[] as &[mac::Algorithm], &list([]));
-        assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
+        let r = decide(AlgorithmKind::MacC2s, &[] as &[mac::Algorithm], &list([]));
+        assert!(r.is_err());
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha2-256", "hmac-sha1"]));
+        let r = decide(
+            AlgorithmKind::MacC2s,
+            &[HmacSha1],
+            &list(["hmac-sha2-256", "hmac-sha1"]),
+        );
         assert_eq!(r.unwrap(), HmacSha1);
 
         let r = decide(
+            AlgorithmKind::MacC2s,
             &[HmacSha1, HmacSha256],
             &list(["hmac-sha2-256", "hmac-sha1"]),
         );
         assert_eq!(r.unwrap(), HmacSha256);
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha2-256", "none"]));
-        assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
+        let r = decide(
+            AlgorithmKind::MacC2s,
+            &[HmacSha1],
+            &list(["hmac-sha2-256", "none"]),
+        );
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_decide_hostkey() {
+        use key::Algorithm::*;
+
+        let rsa = [RsaSha2_512, RsaSha2_256, SshRsa];
+        let r = decide(
+            AlgorithmKind::HostKey,
+            &rsa,
+            &list(["rsa-sha2-256", "rsa-sha2-512"]),
+        );
+        assert_eq!(r.unwrap(), RsaSha2_256);
+
+        let r = decide(
+            AlgorithmKind::HostKey,
+            &rsa,
+            &list(["rsa-sha2-512", "rsa-sha2-256"]),
+        );
+        assert_eq!(r.unwrap(), RsaSha2_512);
+
+        let r = decide(
+            AlgorithmKind::HostKey,
+            &rsa,
+            &list(["ssh-rsa", "rsa-sha2-512"]),
+        );
+        assert_eq!(r.unwrap(), SshRsa);
+
+        let r = decide(
+            AlgorithmKind::HostKey,
+            &[SshEd25519, SshRsa],
+            &list(["rsa-sha2-512"]),
+        );
+        assert!(r.is_err());
     }
 
     #[tokio::test]
@@ -166,4 +378,139 @@ mod tests {
 
         negotiate(&c_kexinit, &preference).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_negotiate_per_direction() {
+        use bytes::Bytes;
+
+        use crate::kex::Kex;
+        use crate::state::State;
+
+        let c_kexinit = crate::msg::kexinit::KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list(["ssh-ed25519"]))
+            .cipher_algorithms_c2s(list(["none"]))
+            .cipher_algorithms_s2c(list(["aes256-ctr"]))
+            .mac_algorithms_c2s(list(["hmac-sha2-256"]))
+            .mac_algorithms_s2c(list(["hmac-sha2-512"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["zlib@openssh.com"]))
+            .languages_c2s(list([""]))
+            .languages_s2c(list([""]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap();
+
+        let preference = crate::preference::PreferenceBuilder::default()
+            .ciphers(&["aes256-ctr", "none"])
+            .unwrap()
+            .macs(&["hmac-sha2-512", "hmac-sha2-256"])
+            .unwrap()
+            .compressions(&["zlib@openssh.com", "none"])
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let algorithm = negotiate(&c_kexinit, &preference).unwrap();
+        assert_eq!(algorithm.cipher_algorithm_c2s().as_ref(), "none");
+        assert_eq!(algorithm.cipher_algorithm_s2c().as_ref(), "aes256-ctr");
+        assert_eq!(algorithm.mac_algorithm_c2s().as_ref(), "hmac-sha2-256");
+        assert_eq!(algorithm.mac_algorithm_s2c().as_ref(), "hmac-sha2-512");
+        assert_eq!(algorithm.compression_algorithm_c2s().as_ref(), "none");
+        assert_eq!(
+            algorithm.compression_algorithm_s2c().as_ref(),
+            "zlib@openssh.com"
+        );
+
+        let mut state = State::new();
+        let kex = Kex::new(algorithm.kex_algorithm());
+        let (hash, secret) = (Bytes::from_static(b"hash"), Bytes::from_static(b"secret"));
+        state.stage_keys(&hash, &secret, &kex, &algorithm).unwrap();
+        state.ctos_mut().switch_keys().unwrap();
+        state.stoc_mut().switch_keys().unwrap();
+        assert_eq!(state.ctos().cipher().block_size(), 8);
+        assert_eq!(state.stoc().cipher().block_size(), 16);
+        assert_eq!(state.ctos().mac().len(), 32);
+        assert_eq!(state.stoc().mac().len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_guessed_by() {
+        let kexinit = |kex: &[&str], hostkey: &[&str]| {
+            crate::msg::kexinit::KexinitBuilder::default()
+                .cookie(0)
+                .kex_algorithms(list(kex))
+                .server_host_key_algorithms(list(hostkey))
+                .cipher_algorithms_c2s(list(["aes256-ctr"]))
+                .cipher_algorithms_s2c(list(["aes256-ctr"]))
+                .mac_algorithms_c2s(list(["hmac-sha2-256"]))
+                .mac_algorithms_s2c(list(["hmac-sha2-256"]))
+                .compression_algorithms_c2s(list(["none"]))
+                .compression_algorithms_s2c(list(["none"]))
+                .languages_c2s(list([]))
+                .languages_s2c(list([]))
+                .first_kex_packet_follows(true)
+                .build()
+                .unwrap()
+        };
+        let preference = crate::preference::PreferenceBuilder::default()
+            .add_kex_algorithm(kex::Algorithm::Curve25519Sha256)
+            .host_key_algorithms(&["ssh-ed25519"])
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let c_kexinit = kexinit(&["curve25519-sha256"], &["ssh-ed25519"]);
+        let algorithm = negotiate(&c_kexinit, &preference).unwrap();
+        assert!(algorithm.guessed_by(&c_kexinit));
+
+        let c_kexinit = kexinit(
+            &["diffie-hellman-group14-sha256", "curve25519-sha256"],
+            &["ssh-ed25519"],
+        );
+        let algorithm = negotiate(&c_kexinit, &preference).unwrap();
+        assert!(!algorithm.guessed_by(&c_kexinit));
+
+        let c_kexinit = kexinit(&["curve25519-sha256"], &["rsa-sha2-512", "ssh-ed25519"]);
+        let algorithm = negotiate(&c_kexinit, &preference).unwrap();
+        assert!(!algorithm.guessed_by(&c_kexinit));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_languages_mismatch() {
+        let c_kexinit = crate::msg::kexinit::KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list(["ssh-ed25519"]))
+            .cipher_algorithms_c2s(list(["aes256-ctr"]))
+            .cipher_algorithms_s2c(list(["aes256-ctr"]))
+            .mac_algorithms_c2s(list(["hmac-sha2-256"]))
+            .mac_algorithms_s2c(list(["hmac-sha2-256"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["none"]))
+            .languages_c2s(list(["ja-JP", "fr"]))
+            .languages_s2c(list(["de"]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap();
+
+        let preference = crate::preference::PreferenceBuilder::default()
+            .languages(&["en-US"])
+            .build()
+            .await
+            .unwrap();
+
+        let algorithm = negotiate(&c_kexinit, &preference).unwrap();
+        assert_eq!(
+            algorithm.languages().client_to_server(),
+            &["ja-JP".to_string(), "fr".to_string()]
+        );
+        assert_eq!(
+            algorithm.languages().server_to_client(),
+            &["de".to_string()]
+        );
+    }
 }
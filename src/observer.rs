@@ -0,0 +1,131 @@
+//! Instrumentation hooks for metrics.
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{ChannelKind, NegotiatedAlgorithms, ProtocolWarning};
+
+/// Outcome of one authentication attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthOutcome {
+    Success,
+    /// Method accepted, another one must follow.
+    Partial,
+    /// Attempt rejected, counted toward the maximum auth attempts.
+    Failure,
+}
+
+/// Defect of a handler error, caught while reporting it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandlerErrorFault {
+    /// Message longer than the limit, truncated.
+    Truncated,
+    /// Converting the handler's error type panicked.
+    ConversionPanicked,
+    /// Formatting the message panicked.
+    FormattingPanicked,
+}
+
+/// Receives events of every connection of a server, e.g. to count them.
+///
+/// Set with [`ServerBuilder::observer`](crate::ServerBuilder::observer).
+/// Called from the event loop, so implementations must return quickly and
+/// never block. Every method does nothing by default.
+pub trait ConnectionObserver: Send + Sync {
+    /// Packet of `len` bytes received, as on the wire.
+    #[inline]
+    fn on_packet_received(&self, _len: usize) {}
+
+    /// Packet of `len` bytes sent, as on the wire.
+    #[inline]
+    fn on_packet_sent(&self, _len: usize) {}
+
+    /// Authentication attempt of `user` by `method` decided.
+    #[inline]
+    fn on_auth_result(&self, _user: &str, _method: &str, _outcome: AuthOutcome) {}
+
+    /// Channel `channel` opened, by either side.
+    #[inline]
+    fn on_channel_open(&self, _channel: u32, _kind: ChannelKind) {}
+
+    /// Channel `channel` closed by both sides.
+    #[inline]
+    fn on_channel_close(&self, _channel: u32) {}
+
+    /// Key exchange done, initial or re-key.
+    #[inline]
+    fn on_kex_complete(&self, _algorithms: &NegotiatedAlgorithms) {}
+
+    /// Peer protocol violation tolerated, or escalated to a disconnect.
+    #[inline]
+    fn on_protocol_warning(&self, _warning: &ProtocolWarning) {}
+
+    /// Handler error message truncated, or its conversion or formatting panicked.
+    #[inline]
+    fn on_handler_error_fault(&self, _fault: HandlerErrorFault) {}
+}
+
+/// Shared observer, `None` unless set.
+#[derive(Clone, Default)]
+pub(crate) struct Observer(Option<Arc<dyn ConnectionObserver>>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Observer").field(&self.0.is_some()).finish()
+    }
+}
+
+impl Observer {
+    pub(crate) fn new(observer: Arc<dyn ConnectionObserver>) -> Self {
+        Self(Some(observer))
+    }
+
+    #[inline]
+    pub(crate) fn packet_received(&self, len: usize) {
+        if let Some(observer) = &self.0 {
+            observer.on_packet_received(len);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn packet_sent(&self, len: usize) {
+        if let Some(observer) = &self.0 {
+            observer.on_packet_sent(len);
+        }
+    }
+
+    pub(crate) fn auth_result(&self, user: &str, method: &str, outcome: AuthOutcome) {
+        if let Some(observer) = &self.0 {
+            observer.on_auth_result(user, method, outcome);
+        }
+    }
+
+    pub(crate) fn channel_open(&self, channel: u32, kind: ChannelKind) {
+        if let Some(observer) = &self.0 {
+            observer.on_channel_open(channel, kind);
+        }
+    }
+
+    pub(crate) fn channel_close(&self, channel: u32) {
+        if let Some(observer) = &self.0 {
+            observer.on_channel_close(channel);
+        }
+    }
+
+    pub(crate) fn kex_complete(&self, algorithms: &NegotiatedAlgorithms) {
+        if let Some(observer) = &self.0 {
+            observer.on_kex_complete(algorithms);
+        }
+    }
+
+    pub(crate) fn protocol_warning(&self, warning: &ProtocolWarning) {
+        if let Some(observer) = &self.0 {
+            observer.on_protocol_warning(warning);
+        }
+    }
+
+    pub(crate) fn handler_error_fault(&self, fault: HandlerErrorFault) {
+        if let Some(observer) = &self.0 {
+            observer.on_handler_error_fault(fault);
+        }
+    }
+}
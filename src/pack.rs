@@ -10,6 +10,9 @@ pub enum UnpackError {
     #[error("unexpected eof")]
     UnexpectedEof,
 
+    #[error("{len} trailing bytes after {msg}")]
+    TrailingBytes { msg: &'static str, len: usize },
+
     #[error(transparent)]
     FromUtf8Error(#[from] FromUtf8Error),
 }
@@ -32,6 +35,16 @@ pub(crate) trait Unpack: Sized {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError>;
 }
 
+/// Length prefix of a string, checked against the bytes left before anything
+/// is allocated. Payloads are bounded, so a bogus length fails here.
+fn unpack_len<B: Buf>(buf: &mut B) -> Result<usize, UnpackError> {
+    let len = u32::unpack(buf)? as usize;
+    if buf.remaining() < len {
+        return Err(UnpackError::UnexpectedEof);
+    }
+    Ok(len)
+}
+
 impl Pack for bool {
     fn pack<P: Put>(&self, buf: &mut P) {
         (if *self { 1u8 } else { 0u8 }).pack(buf);
@@ -76,6 +89,22 @@ impl Unpack for u32 {
     }
 }
 
+impl Pack for u64 {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        buf.put(&self.to_be_bytes());
+    }
+}
+
+impl Unpack for u64 {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        if buf.remaining() < 8 {
+            return Err(UnpackError::UnexpectedEof);
+        }
+
+        Ok(buf.get_u64())
+    }
+}
+
 // TODO needs u128? only cookie@kexinit
 
 impl Pack for u128 {
@@ -110,11 +139,7 @@ impl Pack for String {
 
 impl Unpack for String {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
-        let len = u32::unpack(buf)? as usize;
-        if buf.remaining() < len {
-            return Err(UnpackError::UnexpectedEof);
-        }
-
+        let len = unpack_len(buf)?;
         let s = buf.copy_to_bytes(len);
         let s = String::from_utf8(s.to_vec())?;
         Ok(s)
@@ -164,9 +189,13 @@ impl Unpack for Mpint {
 pub(crate) struct NameList(Vec<String>);
 
 impl NameList {
-    pub(crate) fn iter(&self) -> std::slice::Iter<String> {
+    pub(crate) fn iter(&self) -> std::slice::Iter<'_, String> {
         self.0.iter()
     }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|n| n == name)
+    }
 }
 
 impl<A> FromIterator<A> for NameList
@@ -189,6 +218,9 @@ impl Pack for NameList {
 impl Unpack for NameList {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
         let s = String::unpack(buf)?;
+        if s.is_empty() {
+            return Ok(Self(vec![]));
+        }
         let s = s.split(',').map(Into::into).collect();
         Ok(Self(s))
     }
@@ -197,17 +229,13 @@ impl Unpack for NameList {
 impl Pack for Bytes {
     fn pack<P: Put>(&self, buf: &mut P) {
         (self.len() as u32).pack(buf);
-        buf.put(&self);
+        buf.put(self);
     }
 }
 
 impl Unpack for Bytes {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
-        let len = u32::unpack(buf)? as usize;
-        if buf.remaining() < len {
-            return Err(UnpackError::UnexpectedEof);
-        }
-
+        let len = unpack_len(buf)?;
         let b = buf.copy_to_bytes(len);
         Ok(b)
     }
@@ -224,7 +252,7 @@ mod tests {
         assert_eq!(&*b, &[1][..]);
 
         let r = bool::unpack(&mut b.freeze()).unwrap();
-        assert_eq!(r, true);
+        assert!(r);
     }
 
     #[test]
@@ -255,6 +283,20 @@ mod tests {
         assert_eq!(r, Err(UnpackError::UnexpectedEof));
     }
 
+    #[test]
+    fn test_u64() {
+        let mut b = BytesMut::new();
+        0x0102030405060708u64.pack(&mut b);
+        assert_eq!(&*b, &[1, 2, 3, 4, 5, 6, 7, 8][..]);
+
+        let r = u64::unpack(&mut b.freeze()).unwrap();
+        assert_eq!(r, 0x0102030405060708);
+
+        let mut b = Bytes::from("abcdefg");
+        let r = u64::unpack(&mut b);
+        assert_eq!(r, Err(UnpackError::UnexpectedEof));
+    }
+
     #[test]
     fn test_u128() {
         let mut b = BytesMut::new();
@@ -379,6 +421,13 @@ mod tests {
 
         let r = NameList::unpack(&mut b.freeze()).unwrap();
         assert_eq!(r, NameList(vec!["a".into(), "b".into()]));
+
+        let mut b = BytesMut::new();
+        NameList(vec![]).pack(&mut b);
+        assert_eq!(&*b, &[0, 0, 0, 0][..]);
+
+        let r = NameList::unpack(&mut b.freeze()).unwrap();
+        assert_eq!(r, NameList(vec![]));
     }
 
     #[test]
@@ -1,26 +1,107 @@
+use std::collections::HashSet;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
 use getset::Getters;
 
 use crate::cipher;
 use crate::comp;
+use crate::connection::window;
+use crate::handlers::DEFAULT_ERROR_LIMIT;
 use crate::hostkey::{HostKeys, HostKeysBuilder};
 use crate::kex;
+use crate::key;
+use crate::keylog::KeyLog;
 use crate::mac;
 use crate::msg::kexinit::{Kexinit, KexinitBuilder};
-use crate::negotiate::AlgorithmName;
-use crate::SshError;
+use crate::negotiate::{AlgorithmName, Registered};
+use crate::observer::{ConnectionObserver, Observer};
+use crate::pack::NameList;
+use crate::quirks::{self, ClientQuirks, Quirk};
+use crate::random::{self, Random};
+use crate::stealth::Stealth;
+use crate::{SshError, WarningKind};
+
+/// Session channels without any request or data are closed after this.
+const DEFAULT_UNUSED_CHANNEL_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_CHANNEL_OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Re-key after this many bytes in either direction (RFC 4253 section 9).
+const DEFAULT_REKEY_LIMIT: u64 = 1 << 30;
+
+/// Re-key after keys are this old.
+const DEFAULT_REKEY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Lines and bytes accepted before the client's identification string.
+const DEFAULT_PRE_BANNER_LIMIT: (usize, usize) = (10, 4096);
+
+/// Rejected auth attempts before disconnect, as OpenSSH `MaxAuthTries`.
+const DEFAULT_MAX_AUTH_ATTEMPTS: u32 = 6;
+
+/// Channels open at once per connection.
+const DEFAULT_MAX_CHANNELS: usize = 64;
+
+/// Handler output messages queued before senders wait.
+const DEFAULT_SEND_QUEUE_SIZE: usize = 64;
+
+/// Protocol warnings disconnecting unless tolerated.
+const ESCALATED_WARNINGS: &[WarningKind] = &[WarningKind::PacketExceeded];
+
+/// Bounds of traffic padding, so it never floods the connection.
+const MIN_PADDING_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_PADDING_LEN: usize = 4096;
+
+/// Block sizes of registered ciphers, padding must fit one byte (RFC 4253 section 6).
+const MINIMUM_BLOCK_SIZE: usize = 8;
+const MAXIMUM_BLOCK_SIZE: usize = 255;
+
+/// Longest key or IV of a registered cipher, as `SSH_DIGEST_MAX_LENGTH` of OpenSSH.
+const MAXIMUM_KEY_LENGTH: usize = 64;
 
 #[derive(Debug, Default)]
 pub(crate) struct PreferenceBuilder {
     kex_algorithms: Vec<kex::Algorithm>,
     hostkeys: HostKeysBuilder,
+    host_key_algorithms: Vec<key::Algorithm>,
     cipher_algorithms: Vec<cipher::Algorithm>,
     mac_algorithms: Vec<mac::Algorithm>,
     compression_algorithms: Vec<comp::Algorithm>,
+    custom_kexes: Vec<kex::Algorithm>,
+    custom_ciphers: Vec<cipher::Algorithm>,
+    custom_macs: Vec<mac::Algorithm>,
     name: Option<String>,
+    version_comment: Option<String>,
     timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    unused_channel_timeout: Option<Duration>,
+    channel_open_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    auth_timeout: Option<Duration>,
+    rekey_limit: Option<u64>,
+    rekey_interval: Option<Duration>,
+    keepalive: Option<(Duration, u32)>,
+    traffic_padding: Option<(Duration, usize)>,
+    max_auth_attempts: Option<u32>,
+    max_channels: Option<usize>,
+    max_total_channels: Option<u64>,
+    ext_info_in_auth: bool,
+    hostkeys_update: Option<bool>,
+    pre_banner_limit: Option<(usize, usize)>,
+    channel_window_size: Option<u32>,
+    memory_limit: Option<usize>,
+    send_queue_size: Option<usize>,
+    error_limit: Option<usize>,
+    languages: Vec<String>,
+    quirks: ClientQuirks,
+    escalated_warnings: HashSet<WarningKind>,
+    tolerated_warnings: HashSet<WarningKind>,
+    keylog: Option<KeyLog>,
+    observer: Observer,
+    random: Option<Arc<dyn Random>>,
+    stealth: Stealth,
 }
 
 impl PreferenceBuilder {
@@ -44,44 +125,312 @@ impl PreferenceBuilder {
         self
     }
 
+    /// Registered custom key exchanges may be named too.
+    pub(crate) fn kex_algorithms(&mut self, names: &[&str]) -> Result<&mut Self, SshError> {
+        let custom = &self.custom_kexes;
+        self.kex_algorithms = lookup_all(names, |name| {
+            let found = custom.iter().find(|k| k.as_ref() == name).cloned();
+            name.parse().ok().or(found)
+        })?;
+        Ok(self)
+    }
+
+    pub(crate) fn host_key_algorithms(&mut self, names: &[&str]) -> Result<&mut Self, SshError> {
+        self.host_key_algorithms = lookup_all(names, |name| name.parse().ok())?;
+        Ok(self)
+    }
+
+    /// Registered custom ciphers may be named too.
+    pub(crate) fn ciphers(&mut self, names: &[&str]) -> Result<&mut Self, SshError> {
+        let custom = &self.custom_ciphers;
+        self.cipher_algorithms = lookup_all(names, |name| {
+            let found = custom.iter().find(|c| c.as_ref() == name).cloned();
+            name.parse().ok().or(found)
+        })?;
+        Ok(self)
+    }
+
+    /// Registered custom MACs may be named too.
+    pub(crate) fn macs(&mut self, names: &[&str]) -> Result<&mut Self, SshError> {
+        let custom = &self.custom_macs;
+        self.mac_algorithms = lookup_all(names, |name| {
+            let found = custom.iter().find(|m| m.as_ref() == name).cloned();
+            name.parse().ok().or(found)
+        })?;
+        Ok(self)
+    }
+
+    pub(crate) fn compressions(&mut self, names: &[&str]) -> Result<&mut Self, SshError> {
+        self.compression_algorithms = lookup_all(names, |name| name.parse().ok())?;
+        Ok(self)
+    }
+
+    /// Offer key exchange `name` after the other kex algorithms.
+    pub(crate) fn register_kex(
+        &mut self,
+        name: &str,
+        factory: Arc<dyn kex::KexFactory>,
+    ) -> Result<&mut Self, SshError> {
+        let taken = name.parse::<kex::Algorithm>().is_ok()
+            || self.custom_kexes.iter().any(|k| k.as_ref() == name);
+        if taken {
+            return Err(SshError::AlgorithmExists(name.into()));
+        }
+        let algorithm = kex::Algorithm::Custom(Registered::new(name, factory));
+        self.custom_kexes.push(algorithm);
+        Ok(self)
+    }
+
+    /// Offer cipher `name` after the other cipher algorithms.
+    pub(crate) fn register_cipher(
+        &mut self,
+        name: &str,
+        factory: Arc<dyn cipher::CipherFactory>,
+    ) -> Result<&mut Self, SshError> {
+        let taken = name.parse::<cipher::Algorithm>().is_ok()
+            || self.custom_ciphers.iter().any(|c| c.as_ref() == name);
+        if taken {
+            return Err(SshError::AlgorithmExists(name.into()));
+        }
+        let invalid = |reason: &str| SshError::InvalidAlgorithm(name.into(), reason.into());
+        if !(MINIMUM_BLOCK_SIZE..=MAXIMUM_BLOCK_SIZE).contains(&factory.block_size()) {
+            return Err(invalid("block size must be 8 to 255"));
+        }
+        if !(1..=MAXIMUM_KEY_LENGTH).contains(&factory.key_length()) {
+            return Err(invalid("key length must be 1 to 64"));
+        }
+        if factory.iv_length() > MAXIMUM_KEY_LENGTH {
+            return Err(invalid("iv length must be at most 64"));
+        }
+        let algorithm = cipher::Algorithm::Custom(Registered::new(name, factory));
+        self.custom_ciphers.push(algorithm);
+        Ok(self)
+    }
+
+    /// Offer MAC `name` after the other MAC algorithms.
+    pub(crate) fn register_mac(
+        &mut self,
+        name: &str,
+        factory: Arc<dyn mac::MacFactory>,
+    ) -> Result<&mut Self, SshError> {
+        let taken = name.parse::<mac::Algorithm>().is_ok()
+            || self.custom_macs.iter().any(|m| m.as_ref() == name);
+        if taken {
+            return Err(SshError::AlgorithmExists(name.into()));
+        }
+        let algorithm = mac::Algorithm::Custom(Registered::new(name, factory));
+        self.custom_macs.push(algorithm);
+        Ok(self)
+    }
+
     pub(crate) fn name(&mut self, name: &str) -> &mut Self {
         self.name = Some(name.to_string());
         self
     }
 
+    pub(crate) fn version_comment(&mut self, comment: &str) -> &mut Self {
+        self.version_comment = Some(comment.to_string());
+        self
+    }
+
     pub(crate) fn timeout(&mut self, timeout: Duration) -> &mut Self {
         self.timeout = Some(timeout);
         self
     }
 
+    pub(crate) fn stall_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn unused_channel_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.unused_channel_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn channel_open_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.channel_open_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn handshake_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn auth_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.auth_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn rekey_limit(&mut self, bytes: u64) -> &mut Self {
+        self.rekey_limit = Some(bytes);
+        self
+    }
+
+    pub(crate) fn rekey_interval(&mut self, interval: Duration) -> &mut Self {
+        self.rekey_interval = Some(interval);
+        self
+    }
+
+    pub(crate) fn keepalive(&mut self, interval: Duration, max_missed: u32) -> &mut Self {
+        self.keepalive = Some((interval, max_missed));
+        self
+    }
+
+    pub(crate) fn traffic_padding(&mut self, interval: Duration, max_len: usize) -> &mut Self {
+        self.traffic_padding = Some((interval, max_len));
+        self
+    }
+
+    pub(crate) fn max_auth_attempts(&mut self, attempts: u32) -> &mut Self {
+        self.max_auth_attempts = Some(attempts);
+        self
+    }
+
+    pub(crate) fn max_channels(&mut self, channels: usize) -> &mut Self {
+        self.max_channels = Some(channels);
+        self
+    }
+
+    pub(crate) fn max_total_channels(&mut self, channels: u64) -> &mut Self {
+        self.max_total_channels = Some(channels);
+        self
+    }
+
+    pub(crate) fn ext_info_in_auth(&mut self, enable: bool) -> &mut Self {
+        self.ext_info_in_auth = enable;
+        self
+    }
+
+    pub(crate) fn hostkeys_update(&mut self, enable: bool) -> &mut Self {
+        self.hostkeys_update = Some(enable);
+        self
+    }
+
+    pub(crate) fn pre_banner_limit(&mut self, lines: usize, bytes: usize) -> &mut Self {
+        self.pre_banner_limit = Some((lines, bytes));
+        self
+    }
+
+    pub(crate) fn channel_window_size(&mut self, size: u32) -> &mut Self {
+        self.channel_window_size = Some(size);
+        self
+    }
+
+    pub(crate) fn memory_limit(&mut self, limit: usize) -> &mut Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    pub(crate) fn send_queue_size(&mut self, size: usize) -> &mut Self {
+        self.send_queue_size = Some(size);
+        self
+    }
+
+    pub(crate) fn error_limit(&mut self, limit: usize) -> &mut Self {
+        self.error_limit = Some(limit);
+        self
+    }
+
+    pub(crate) fn languages(&mut self, languages: &[&str]) -> &mut Self {
+        self.languages = languages.iter().map(ToString::to_string).collect();
+        self
+    }
+
+    pub(crate) fn keylog<W>(&mut self, out: W) -> &mut Self
+    where
+        W: std::io::Write + Send + 'static,
+    {
+        self.keylog = Some(KeyLog::new(out));
+        self
+    }
+
+    pub(crate) fn observer(&mut self, observer: Arc<dyn ConnectionObserver>) -> &mut Self {
+        self.observer = Observer::new(observer);
+        self
+    }
+
+    pub(crate) fn add_client_quirk(&mut self, quirk: Quirk) -> &mut Self {
+        self.quirks.add(quirk);
+        self
+    }
+
+    pub(crate) fn default_client_quirks(&mut self, enable: bool) -> &mut Self {
+        self.quirks.use_defaults(enable);
+        self
+    }
+
+    pub(crate) fn escalate_warning(&mut self, kind: WarningKind) -> &mut Self {
+        self.tolerated_warnings.remove(&kind);
+        self.escalated_warnings.insert(kind);
+        self
+    }
+
+    pub(crate) fn tolerate_warning(&mut self, kind: WarningKind) -> &mut Self {
+        self.escalated_warnings.remove(&kind);
+        self.tolerated_warnings.insert(kind);
+        self
+    }
+
+    pub(crate) fn random(&mut self, random: Arc<dyn Random>) -> &mut Self {
+        self.random = Some(random);
+        self
+    }
+
+    pub(crate) fn stealth(&mut self, stealth: Stealth) -> &mut Self {
+        self.stealth = stealth;
+        self
+    }
+
     pub(crate) fn hostkeys_from_path<P: AsRef<Path>>(&mut self, file: P) -> &mut Self {
         self.hostkeys.load_from_file(file);
         self
     }
 
+    pub(crate) fn add_hostkey(&mut self, hostkey: crate::HostKey) -> &mut Self {
+        self.hostkeys.add(hostkey);
+        self
+    }
+
     pub(crate) fn hostkeys_generate(&mut self) -> &mut Self {
         self.hostkeys.generate();
         self
     }
 
     pub(crate) async fn build(&self) -> Result<Preference, SshError> {
-        let kex_algorithms = if self.kex_algorithms.is_empty() {
+        let mut kex_algorithms = if self.kex_algorithms.is_empty() {
             kex::Algorithm::defaults()
         } else {
             self.kex_algorithms.clone()
         };
+        for custom in &self.custom_kexes {
+            if !kex_algorithms.contains(custom) {
+                kex_algorithms.push(custom.clone());
+            }
+        }
 
-        let cipher_algorithms = if self.cipher_algorithms.is_empty() {
+        let mut cipher_algorithms = if self.cipher_algorithms.is_empty() {
             cipher::Algorithm::defaults()
         } else {
             self.cipher_algorithms.clone()
         };
+        for custom in &self.custom_ciphers {
+            if !cipher_algorithms.contains(custom) {
+                cipher_algorithms.push(custom.clone());
+            }
+        }
 
-        let mac_algorithms = if self.mac_algorithms.is_empty() {
+        let mut mac_algorithms = if self.mac_algorithms.is_empty() {
             mac::Algorithm::defaults()
         } else {
             self.mac_algorithms.clone()
         };
+        for custom in &self.custom_macs {
+            if !mac_algorithms.contains(custom) {
+                mac_algorithms.push(custom.clone());
+            }
+        }
 
         let compression_algorithms = if self.compression_algorithms.is_empty() {
             comp::Algorithm::defaults()
@@ -89,33 +438,130 @@ impl PreferenceBuilder {
             self.compression_algorithms.clone()
         };
 
-        let name = self.name.clone().unwrap_or_else(|| "sssh".into());
+        let version = if self.stealth.minimal_banner {
+            "SSH-2.0-unknown".into()
+        } else {
+            let name = self.name.as_deref().unwrap_or("sssh");
+            compose_version(name, self.version_comment.as_deref())?
+        };
         let timeout = self.timeout;
+        let stall_timeout = self.stall_timeout;
+        let unused_channel_timeout = self
+            .unused_channel_timeout
+            .unwrap_or(DEFAULT_UNUSED_CHANNEL_TIMEOUT);
+        let channel_open_timeout = self
+            .channel_open_timeout
+            .unwrap_or(DEFAULT_CHANNEL_OPEN_TIMEOUT);
+        let handshake_timeout = self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+        let auth_timeout = self.auth_timeout.unwrap_or(DEFAULT_AUTH_TIMEOUT);
+        let rekey_limit = self.rekey_limit.unwrap_or(DEFAULT_REKEY_LIMIT);
+        let rekey_interval = self.rekey_interval.unwrap_or(DEFAULT_REKEY_INTERVAL);
+        let keepalive = self.keepalive;
+        let traffic_padding = self.traffic_padding.map(|(interval, max_len)| {
+            (
+                interval.max(MIN_PADDING_INTERVAL),
+                max_len.min(MAX_PADDING_LEN),
+            )
+        });
+        let max_auth_attempts = self.max_auth_attempts.unwrap_or(DEFAULT_MAX_AUTH_ATTEMPTS);
+        let max_channels = self.max_channels.unwrap_or(DEFAULT_MAX_CHANNELS);
+        let max_total_channels = self.max_total_channels;
+        let ext_info_in_auth = self.ext_info_in_auth;
+        let hostkeys_update = self.hostkeys_update.unwrap_or(true);
+        let pre_banner_limit = self.pre_banner_limit.unwrap_or(DEFAULT_PRE_BANNER_LIMIT);
+        let channel_window_size = self
+            .channel_window_size
+            .unwrap_or(window::DEFAULT_WINDOW_SIZE);
+        let memory_limit = self.memory_limit;
+        let send_queue_size = self.send_queue_size.unwrap_or(DEFAULT_SEND_QUEUE_SIZE);
+        let error_limit = self.error_limit.unwrap_or(DEFAULT_ERROR_LIMIT);
+        let languages = self.languages.clone();
+        let quirks = self.quirks.clone();
+        let mut escalated_warnings = self.escalated_warnings.clone();
+        escalated_warnings.extend(
+            ESCALATED_WARNINGS
+                .iter()
+                .filter(|kind| !self.tolerated_warnings.contains(kind)),
+        );
+        let keylog = self.keylog.clone();
+        let observer = self.observer.clone();
+        let random = self
+            .random
+            .clone()
+            .unwrap_or_else(|| Arc::new(random::System::new()));
+        let stealth = self.stealth.clone();
 
         let mut hostkeys = self.hostkeys.build().await?;
         if hostkeys.names().is_empty() {
             hostkeys.generate()?;
         }
+        // Configured order, restricted to algorithms some key can sign with.
+        let host_key_algorithms = if self.host_key_algorithms.is_empty() {
+            hostkeys.names()
+        } else {
+            let names = hostkeys.names();
+            let algorithms = self
+                .host_key_algorithms
+                .iter()
+                .filter(|a| names.contains(a))
+                .cloned()
+                .collect::<Vec<_>>();
+            if algorithms.is_empty() {
+                return Err(SshError::NoHostKeyAlgorithm);
+            }
+            algorithms
+        };
 
         Ok(Preference {
             kex_algorithms,
-            hostkeys,
+            hostkeys: Arc::new(hostkeys),
+            host_key_algorithms,
             cipher_algorithms,
             mac_algorithms,
             compression_algorithms,
-            name,
+            version,
             timeout,
+            stall_timeout,
+            unused_channel_timeout,
+            channel_open_timeout,
+            handshake_timeout,
+            auth_timeout,
+            rekey_limit,
+            rekey_interval,
+            keepalive,
+            traffic_padding,
+            max_auth_attempts,
+            max_channels,
+            max_total_channels,
+            ext_info_in_auth,
+            hostkeys_update,
+            pre_banner_limit,
+            channel_window_size,
+            memory_limit,
+            send_queue_size,
+            error_limit,
+            languages,
+            quirks,
+            escalated_warnings,
+            keylog,
+            observer,
+            random,
+            stealth,
         })
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Clone, Getters)]
 pub(crate) struct Preference {
     #[get = "pub(crate)"]
     kex_algorithms: Vec<kex::Algorithm>,
 
     #[get = "pub(crate)"]
-    hostkeys: HostKeys,
+    hostkeys: Arc<HostKeys>,
+
+    /// Signature algorithms offered, preferred first.
+    #[get = "pub(crate)"]
+    host_key_algorithms: Vec<key::Algorithm>,
 
     #[get = "pub(crate)"]
     cipher_algorithms: Vec<cipher::Algorithm>,
@@ -126,79 +572,490 @@ pub(crate) struct Preference {
     #[get = "pub(crate)"]
     compression_algorithms: Vec<comp::Algorithm>,
 
+    /// Identification string sent, without CR LF.
     #[get = "pub(crate)"]
-    name: String,
+    version: String,
 
     #[get = "pub(crate)"]
     timeout: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    stall_timeout: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    unused_channel_timeout: Duration,
+
+    #[get = "pub(crate)"]
+    channel_open_timeout: Duration,
+
+    /// Connection start to the first NEWKEYS.
+    #[get = "pub(crate)"]
+    handshake_timeout: Duration,
+
+    /// Connection start to auth success.
+    #[get = "pub(crate)"]
+    auth_timeout: Duration,
+
+    #[get = "pub(crate)"]
+    rekey_limit: u64,
+
+    #[get = "pub(crate)"]
+    rekey_interval: Duration,
+
+    /// Probe interval and unanswered probes tolerated.
+    #[get = "pub(crate)"]
+    keepalive: Option<(Duration, u32)>,
+
+    /// Mean interval and maximum payload of SSH_MSG_IGNORE sent as padding.
+    #[get = "pub(crate)"]
+    traffic_padding: Option<(Duration, usize)>,
+
+    #[get = "pub(crate)"]
+    max_auth_attempts: u32,
+
+    /// Channels open at once, in either direction.
+    #[get = "pub(crate)"]
+    max_channels: usize,
+
+    /// Channels the client may open over the connection's lifetime.
+    #[get = "pub(crate)"]
+    max_total_channels: Option<u64>,
+
+    /// Repeat EXT_INFO right before USERAUTH_SUCCESS.
+    #[get = "pub(crate)"]
+    ext_info_in_auth: bool,
+
+    /// Announce host keys with `hostkeys-00@openssh.com` after auth.
+    #[get = "pub(crate)"]
+    hostkeys_update: bool,
+
+    /// Lines and bytes skipped at most before the client's identification string.
+    #[get = "pub(crate)"]
+    pre_banner_limit: (usize, usize),
+
+    /// Initial window advertised per channel.
+    #[get = "pub(crate)"]
+    channel_window_size: u32,
+
+    #[get = "pub(crate)"]
+    memory_limit: Option<usize>,
+
+    #[get = "pub(crate)"]
+    send_queue_size: usize,
+
+    #[get = "pub(crate)"]
+    error_limit: usize,
+
+    #[get = "pub(crate)"]
+    languages: Vec<String>,
+
+    quirks: ClientQuirks,
+
+    #[get = "pub(crate)"]
+    escalated_warnings: HashSet<WarningKind>,
+
+    #[get = "pub(crate)"]
+    keylog: Option<KeyLog>,
+
+    #[get = "pub(crate)"]
+    observer: Observer,
+
+    random: Arc<dyn Random>,
+
+    #[get = "pub(crate)"]
+    stealth: Stealth,
+}
+
+/// `SSH-2.0-softwareversion SP comments` (RFC 4253 section 4.2).
+///
+/// softwareversion is printable US-ASCII without whitespace or minus sign,
+/// comments are printable US-ASCII and the line fits 255 bytes with CR LF.
+fn compose_version(name: &str, comment: Option<&str>) -> Result<String, SshError> {
+    let mut version = format!("SSH-2.0-{}", name);
+    if let Some(comment) = comment {
+        version.push(' ');
+        version.push_str(comment);
+    }
+    let name_ok = !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b'-');
+    let comment_ok = comment
+        .is_none_or(|c| !c.is_empty() && c.bytes().all(|b| b.is_ascii_graphic() || b == b' '));
+    if !name_ok || !comment_ok || version.len() + 2 > 255 {
+        return Err(SshError::InvalidServerVersion(version));
+    }
+    Ok(version)
+}
+
+/// Algorithms named by `names` in order, or every name `lookup` misses.
+fn lookup_all<T, F>(names: &[&str], lookup: F) -> Result<Vec<T>, SshError>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    let mut algorithms = vec![];
+    let mut unknown = vec![];
+    for name in names {
+        match lookup(name) {
+            Some(algorithm) => algorithms.push(algorithm),
+            None => unknown.push(name.to_string()),
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(SshError::UnknownAlgorithms(unknown));
+    }
+    Ok(algorithms)
 }
 
-fn generate_cookie() -> u128 {
-    use ring::rand::{SecureRandom as _, SystemRandom};
+fn generate_cookie(random: &dyn Random) -> Result<u128, SshError> {
     let mut cookie = 0u128.to_ne_bytes();
-    SystemRandom::new().fill(&mut cookie).unwrap();
-    u128::from_ne_bytes(cookie)
+    random.fill(&mut cookie)?;
+    Ok(u128::from_ne_bytes(cookie))
+}
+
+/// Shuffle names in place (Fisher-Yates).
+fn shuffle(names: &mut [String], random: &dyn Random) -> Result<(), SshError> {
+    for i in (1..names.len()).rev() {
+        let mut r = [0; 4];
+        random.fill(&mut r)?;
+        let j = u32::from_ne_bytes(r) as usize % (i + 1);
+        names.swap(i, j);
+    }
+    Ok(())
 }
 
 impl Preference {
-    pub(crate) fn to_kexinit(&self) -> Kexinit {
-        let cookie = generate_cookie();
+    /// Preference adjusted by quirks matching client `version`.
+    ///
+    /// Returns `None` if no quirk matches.
+    pub(crate) fn for_client(&self, version: &str) -> Option<(Self, Vec<String>)> {
+        let matched = self.quirks.lookup(version);
+        if matched.is_empty() {
+            return None;
+        }
+
+        let mut preference = self.clone();
+        preference.kex_algorithms = quirks::apply(&matched, &self.kex_algorithms);
+        preference.cipher_algorithms = quirks::apply(&matched, &self.cipher_algorithms);
+        preference.mac_algorithms = quirks::apply(&matched, &self.mac_algorithms);
+        preference.compression_algorithms = quirks::apply(&matched, &self.compression_algorithms);
+        Some((preference, quirks::describe(&matched)))
+    }
 
-        KexinitBuilder::default()
+    fn names<'a, T, I>(&self, algorithms: I) -> Result<NameList, SshError>
+    where
+        T: AlgorithmName + 'a,
+        I: IntoIterator<Item = &'a T>,
+    {
+        let mut names = algorithms
+            .into_iter()
+            .map(AlgorithmName::to_string)
+            .collect::<Vec<_>>();
+        if self.stealth.randomize_kexinit_order {
+            shuffle(&mut names, &*self.random)?;
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    /// Fails only if no random bytes are available.
+    pub(crate) fn to_kexinit(&self) -> Result<Kexinit, SshError> {
+        let cookie = generate_cookie(&*self.random)?;
+
+        Ok(KexinitBuilder::default()
             .cookie(cookie)
             .kex_algorithms(
-                self.kex_algorithms
-                    .iter()
-                    .map(AlgorithmName::to_string)
-                    .collect(),
-            )
-            .server_host_key_algorithms(
-                self.hostkeys
-                    .names()
-                    .iter()
-                    .map(AlgorithmName::to_string)
-                    .collect(),
-            )
-            .cipher_algorithms_c2s(
-                self.cipher_algorithms
-                    .iter()
-                    .map(AlgorithmName::to_string)
-                    .collect(),
-            )
-            .cipher_algorithms_s2c(
-                self.cipher_algorithms
-                    .iter()
-                    .map(AlgorithmName::to_string)
-                    .collect(),
-            )
-            .mac_algorithms_c2s(
-                self.mac_algorithms
-                    .iter()
-                    .map(AlgorithmName::to_string)
-                    .collect(),
-            )
-            .mac_algorithms_s2c(
-                self.mac_algorithms
+                self.names(&self.kex_algorithms)?
                     .iter()
-                    .map(AlgorithmName::to_string)
+                    .cloned()
+                    .chain(Some(kex::STRICT_SERVER.into()))
                     .collect(),
             )
-            .compression_algorithms_c2s(
-                self.compression_algorithms
-                    .iter()
-                    .map(AlgorithmName::to_string)
-                    .collect(),
-            )
-            .compression_algorithms_s2c(
-                self.compression_algorithms
-                    .iter()
-                    .map(AlgorithmName::to_string)
-                    .collect(),
-            )
-            .languages_c2s(Vec::<String>::new().into_iter().collect())
-            .languages_s2c(Vec::<String>::new().into_iter().collect())
+            .server_host_key_algorithms(self.names(&self.host_key_algorithms)?)
+            .cipher_algorithms_c2s(self.names(&self.cipher_algorithms)?)
+            .cipher_algorithms_s2c(self.names(&self.cipher_algorithms)?)
+            .mac_algorithms_c2s(self.names(&self.mac_algorithms)?)
+            .mac_algorithms_s2c(self.names(&self.mac_algorithms)?)
+            .compression_algorithms_c2s(self.names(&self.compression_algorithms)?)
+            .compression_algorithms_s2c(self.names(&self.compression_algorithms)?)
+            .languages_c2s(self.languages.iter().cloned().collect())
+            .languages_s2c(self.languages.iter().cloned().collect())
             .first_kex_packet_follows(false)
             .build()
+            .unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(names: &NameList) -> Vec<String> {
+        let mut names = names.iter().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_randomize_kexinit_order() {
+        let preference = PreferenceBuilder::default()
+            .stealth(Stealth::default().randomize_kexinit_order(true))
+            .build()
+            .await
+            .unwrap();
+        let plain = PreferenceBuilder::default().build().await.unwrap();
+
+        let first = preference.to_kexinit().unwrap();
+        let mut differ = false;
+        for _ in 0..32 {
+            let next = preference.to_kexinit().unwrap();
+            assert_eq!(
+                sorted(next.kex_algorithms()),
+                sorted(first.kex_algorithms())
+            );
+            assert_eq!(
+                sorted(next.mac_algorithms_s2c()),
+                sorted(plain.to_kexinit().unwrap().mac_algorithms_s2c())
+            );
+            differ |= next
+                .kex_algorithms()
+                .iter()
+                .ne(first.kex_algorithms().iter());
+        }
+        assert!(differ);
+    }
+
+    #[tokio::test]
+    async fn test_injected_random() {
+        #[derive(Debug)]
+        struct Counter(std::sync::atomic::AtomicU8);
+
+        impl Random for Counter {
+            fn fill(&self, dest: &mut [u8]) -> Result<(), SshError> {
+                use std::sync::atomic::Ordering;
+
+                for b in dest {
+                    *b = self.0.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+        }
+
+        #[derive(Debug)]
+        struct Exhausted;
+
+        impl Random for Exhausted {
+            fn fill(&self, _: &mut [u8]) -> Result<(), SshError> {
+                Err(SshError::kex_error(ring::error::Unspecified))
+            }
+        }
+
+        let kexinit = |seed: u8| async move {
+            PreferenceBuilder::default()
+                .stealth(Stealth::default().randomize_kexinit_order(true))
+                .random(Arc::new(Counter(seed.into())))
+                .build()
+                .await
+                .unwrap()
+                .to_kexinit()
+                .unwrap()
+        };
+        let (a, b) = (kexinit(0).await, kexinit(0).await);
+        assert_eq!(a.cookie(), b.cookie());
+        assert_eq!(a.kex_algorithms(), b.kex_algorithms());
+        assert_ne!(a.cookie(), kexinit(1).await.cookie());
+
+        let preference = PreferenceBuilder::default()
+            .random(Arc::new(Exhausted))
+            .build()
+            .await
+            .unwrap();
+        assert!(matches!(
+            preference.to_kexinit(),
+            Err(SshError::KexError(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_named_algorithms() {
+        let names = |list: &NameList| list.iter().cloned().collect::<Vec<_>>();
+
+        let preference = PreferenceBuilder::default()
+            .kex_algorithms(&["curve25519-sha256", "diffie-hellman-group14-sha256"])
+            .unwrap()
+            .ciphers(&["aes256-ctr", "aes128-ctr"])
+            .unwrap()
+            .macs(&["hmac-sha2-512"])
+            .unwrap()
+            .compressions(&["none"])
+            .unwrap()
+            .host_key_algorithms(&["rsa-sha2-256", "ssh-ed25519", "ssh-rsa"])
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let kexinit = preference.to_kexinit().unwrap();
+        assert_eq!(
+            names(kexinit.kex_algorithms()),
+            [
+                "curve25519-sha256",
+                "diffie-hellman-group14-sha256",
+                "kex-strict-s-v00@openssh.com"
+            ]
+        );
+        assert_eq!(
+            names(kexinit.cipher_algorithms_s2c()),
+            ["aes256-ctr", "aes128-ctr"]
+        );
+        assert_eq!(names(kexinit.mac_algorithms_c2s()), ["hmac-sha2-512"]);
+        assert_eq!(names(kexinit.compression_algorithms_c2s()), ["none"]);
+        assert_eq!(
+            names(kexinit.server_host_key_algorithms()),
+            ["rsa-sha2-256", "ssh-ed25519", "ssh-rsa"]
+        );
+
+        let err = PreferenceBuilder::default()
+            .macs(&["hmac-sha2-256", "hmac-md5", "umac-64@openssh.com"])
+            .unwrap_err();
+        assert!(
+            matches!(&err, SshError::UnknownAlgorithms(names) if names == &["hmac-md5", "umac-64@openssh.com"]),
+            "{:?}",
+            err
+        );
+
+        // Only an ed25519 key, but only rsa offered.
+        let hostkey = crate::HostKey::generate(&key::Algorithm::SshEd25519).unwrap();
+        let err = PreferenceBuilder::default()
+            .add_hostkey(hostkey)
+            .host_key_algorithms(&["rsa-sha2-512"])
             .unwrap()
+            .build()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SshError::NoHostKeyAlgorithm), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_languages_roundtrip() {
+        use crate::pack::{Pack as _, Unpack as _};
+
+        let preference = PreferenceBuilder::default()
+            .languages(&["en-US", "ja-JP"])
+            .build()
+            .await
+            .unwrap();
+        let mut buf = bytes::BytesMut::new();
+        preference.to_kexinit().unwrap().pack(&mut buf);
+        let kexinit = Kexinit::unpack(&mut buf.freeze()).unwrap();
+        let expect = vec!["en-US".to_string(), "ja-JP".to_string()];
+        assert_eq!(sorted(kexinit.languages_c2s()), expect);
+        assert_eq!(sorted(kexinit.languages_s2c()), expect);
+
+        let plain = PreferenceBuilder::default().build().await.unwrap();
+        let mut buf = bytes::BytesMut::new();
+        plain.to_kexinit().unwrap().pack(&mut buf);
+        let kexinit = Kexinit::unpack(&mut buf.freeze()).unwrap();
+        assert!(sorted(kexinit.languages_c2s()).is_empty());
+        assert!(sorted(kexinit.languages_s2c()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_for_client() {
+        let preference = PreferenceBuilder::default()
+            .add_client_quirk(Quirk::prefix("SSH-2.0-dropbear_2014").avoid("aes256-ctr"))
+            .build()
+            .await
+            .unwrap();
+
+        assert!(preference.for_client("SSH-2.0-OpenSSH_8.9").is_none());
+
+        let (quirked, matched) = preference.for_client("SSH-2.0-dropbear_2014.63").unwrap();
+        assert_eq!(matched, vec!["SSH-2.0-dropbear_2014*".to_string()]);
+        assert!(preference
+            .cipher_algorithms()
+            .contains(&cipher::Algorithm::Aes256Ctr));
+        assert!(!quirked
+            .cipher_algorithms()
+            .contains(&cipher::Algorithm::Aes256Ctr));
+        assert_eq!(quirked.mac_algorithms(), preference.mac_algorithms());
+    }
+
+    #[tokio::test]
+    async fn test_for_client_empty_fallback() {
+        let preference = PreferenceBuilder::default()
+            .add_cipher_algorithm(cipher::Algorithm::Aes128Ctr)
+            .add_client_quirk(Quirk::exact("SSH-2.0-broken").avoid("aes128-ctr"))
+            .build()
+            .await
+            .unwrap();
+
+        let (quirked, _) = preference.for_client("SSH-2.0-broken").unwrap();
+        assert_eq!(quirked.cipher_algorithms(), &[cipher::Algorithm::Aes128Ctr]);
+    }
+
+    #[tokio::test]
+    async fn test_minimal_banner() {
+        let preference = PreferenceBuilder::default()
+            .name("secret-version")
+            .stealth(Stealth::default().minimal_banner(true))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(preference.version(), "SSH-2.0-unknown");
+    }
+
+    #[derive(Debug)]
+    struct Shaped(usize, usize, usize);
+
+    impl cipher::CipherFactory for Shaped {
+        fn block_size(&self) -> usize {
+            self.0
+        }
+
+        fn key_length(&self) -> usize {
+            self.1
+        }
+
+        fn iv_length(&self) -> usize {
+            self.2
+        }
+
+        fn new_for_encrypt(
+            &self,
+            _: &[u8],
+            _: &[u8],
+        ) -> Result<Box<dyn cipher::CustomCipher>, SshError> {
+            unimplemented!()
+        }
+
+        fn new_for_decrypt(
+            &self,
+            _: &[u8],
+            _: &[u8],
+        ) -> Result<Box<dyn cipher::CustomCipher>, SshError> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_register_invalid_cipher() {
+        let mut preference = PreferenceBuilder::default();
+        for (i, sizes) in [
+            (4, 16, 4),
+            (256, 16, 16),
+            (8, 0, 8),
+            (8, 65, 8),
+            (8, 16, 65),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let name = format!("bad{}@example.com", i);
+            let &(block, key, iv) = sizes;
+            let factory = Arc::new(Shaped(block, key, iv));
+            assert!(matches!(
+                preference.register_cipher(&name, factory),
+                Err(SshError::InvalidAlgorithm(..))
+            ));
+        }
+        preference
+            .register_cipher("good@example.com", Arc::new(Shaped(8, 64, 0)))
+            .unwrap();
     }
 }
@@ -0,0 +1,193 @@
+//! Per client software algorithm adjustments.
+use crate::trace::warn;
+
+use crate::negotiate::AlgorithmName;
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+}
+
+/// Algorithm adjustment for clients whose version string matches.
+///
+/// # Example
+///
+/// ```
+/// use ssssh::Quirk;
+///
+/// let quirk = Quirk::prefix("SSH-2.0-dropbear_2014").avoid("aes256-ctr");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Quirk {
+    pattern: Pattern,
+    avoid: Vec<String>,
+    prefer: Vec<String>,
+}
+
+impl Quirk {
+    /// Match client version string exactly.
+    pub fn exact(version: &str) -> Self {
+        Self::new(Pattern::Exact(version.into()))
+    }
+
+    /// Match client version strings starting with `prefix`.
+    pub fn prefix(prefix: &str) -> Self {
+        Self::new(Pattern::Prefix(prefix.into()))
+    }
+
+    fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            avoid: vec![],
+            prefer: vec![],
+        }
+    }
+
+    /// Never offer algorithm `name`.
+    pub fn avoid(mut self, name: &str) -> Self {
+        self.avoid.push(name.into());
+        self
+    }
+
+    /// Move algorithm `name` to the front of its list, if offered.
+    pub fn prefer(mut self, name: &str) -> Self {
+        self.prefer.push(name.into());
+        self
+    }
+
+    fn matches(&self, version: &str) -> bool {
+        match &self.pattern {
+            Pattern::Exact(v) => version == v,
+            Pattern::Prefix(p) => version.starts_with(p.as_str()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.pattern {
+            Pattern::Exact(v) => v.clone(),
+            Pattern::Prefix(p) => format!("{}*", p),
+        }
+    }
+}
+
+/// Known broken clients.
+fn defaults() -> Vec<Quirk> {
+    vec![
+        // Old OpenSSH sends obsolete group exchange requests.
+        Quirk::prefix("SSH-2.0-OpenSSH_2.")
+            .avoid("diffie-hellman-group-exchange-sha1")
+            .avoid("diffie-hellman-group-exchange-sha256"),
+        Quirk::prefix("SSH-2.0-OpenSSH_3.0")
+            .avoid("diffie-hellman-group-exchange-sha1")
+            .avoid("diffie-hellman-group-exchange-sha256"),
+        Quirk::prefix("SSH-2.0-OpenSSH_3.1")
+            .avoid("diffie-hellman-group-exchange-sha1")
+            .avoid("diffie-hellman-group-exchange-sha256"),
+    ]
+}
+
+/// Quirks table consulted after version exchange.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientQuirks {
+    quirks: Vec<Quirk>,
+    defaults: bool,
+}
+
+impl Default for ClientQuirks {
+    fn default() -> Self {
+        Self {
+            quirks: vec![],
+            defaults: true,
+        }
+    }
+}
+
+impl ClientQuirks {
+    pub(crate) fn add(&mut self, quirk: Quirk) {
+        self.quirks.push(quirk);
+    }
+
+    pub(crate) fn use_defaults(&mut self, enable: bool) {
+        self.defaults = enable;
+    }
+
+    /// Matching quirks, configured ones first.
+    pub(crate) fn lookup(&self, version: &str) -> Vec<Quirk> {
+        let defaults = if self.defaults { defaults() } else { vec![] };
+        self.quirks
+            .iter()
+            .chain(defaults.iter())
+            .filter(|q| q.matches(version))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Names of matched quirks for logs.
+pub(crate) fn describe(quirks: &[Quirk]) -> Vec<String> {
+    quirks.iter().map(Quirk::describe).collect()
+}
+
+/// Apply quirks to one algorithm list.
+///
+/// Falls back to `algorithms` unchanged if nothing would remain.
+pub(crate) fn apply<N: AlgorithmName>(quirks: &[Quirk], algorithms: &[N]) -> Vec<N> {
+    let mut result = algorithms
+        .iter()
+        .filter(|a| {
+            !quirks
+                .iter()
+                .any(|q| q.avoid.iter().any(|n| n == a.as_ref()))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if result.is_empty() && !algorithms.is_empty() {
+        warn!("quirks {:?} leave no algorithm, ignored", describe(quirks));
+        return algorithms.to_vec();
+    }
+
+    for name in quirks.iter().flat_map(|q| q.prefer.iter()).rev() {
+        if let Some(pos) = result.iter().position(|a| a.as_ref() == name) {
+            let a = result.remove(pos);
+            result.insert(0, a);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::Algorithm::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut quirks = ClientQuirks::default();
+        quirks.add(Quirk::prefix("SSH-2.0-dropbear_2014").avoid("aes256-ctr"));
+        quirks.add(Quirk::exact("SSH-2.0-PuTTY_Release_0.70"));
+
+        assert_eq!(quirks.lookup("SSH-2.0-dropbear_2014.63").len(), 1);
+        assert!(quirks.lookup("SSH-2.0-dropbear_2019.78").is_empty());
+        assert_eq!(quirks.lookup("SSH-2.0-PuTTY_Release_0.70").len(), 1);
+        assert!(quirks.lookup("SSH-2.0-PuTTY_Release_0.701").is_empty());
+
+        assert_eq!(quirks.lookup("SSH-2.0-OpenSSH_3.0p1").len(), 1);
+        quirks.use_defaults(false);
+        assert!(quirks.lookup("SSH-2.0-OpenSSH_3.0p1").is_empty());
+    }
+
+    #[test]
+    fn test_apply() {
+        let quirks = [Quirk::prefix("").avoid("aes256-ctr").prefer("aes128-ctr")];
+        let r = apply(&quirks, &[Aes256Ctr, Aes192Ctr, Aes128Ctr]);
+        assert_eq!(r, vec![Aes128Ctr, Aes192Ctr]);
+    }
+
+    #[test]
+    fn test_apply_empty_fallback() {
+        let quirks = [Quirk::prefix("").avoid("aes256-ctr").avoid("aes128-ctr")];
+        let r = apply(&quirks, &[Aes256Ctr, Aes128Ctr]);
+        assert_eq!(r, vec![Aes256Ctr, Aes128Ctr]);
+    }
+}
@@ -0,0 +1,31 @@
+//! Random bytes for the kexinit, pluggable for tests and audited sources.
+use std::fmt;
+
+use ring::rand::{SecureRandom as _, SystemRandom};
+
+use crate::SshError;
+
+/// Source of the kexinit cookie and shuffled name-list order.
+///
+/// Set with [`ServerBuilder::random`](crate::ServerBuilder::random).
+/// The operating system's generator is used by default.
+pub trait Random: fmt::Debug + Send + Sync + 'static {
+    /// Fill all of `dest`, or fail.
+    fn fill(&self, dest: &mut [u8]) -> Result<(), SshError>;
+}
+
+/// Operating system's generator.
+#[derive(Debug)]
+pub(crate) struct System(SystemRandom);
+
+impl System {
+    pub(crate) fn new() -> Self {
+        Self(SystemRandom::new())
+    }
+}
+
+impl Random for System {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), SshError> {
+        self.0.fill(dest).map_err(SshError::kex_error)
+    }
+}
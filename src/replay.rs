@@ -0,0 +1,324 @@
+//! Record decrypted message exchange and replay it against [`Handlers`].
+//!
+//! Recordings are text, one message per line:
+//! `<direction> <sequence> <millis> <base64 payload>`,
+//! where direction `<` is client to server and `>` is server to client.
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::future::{self, TryFutureExt as _};
+use futures::sink::SinkExt as _;
+use futures::stream::TryStreamExt as _;
+use thiserror::Error;
+
+use crate::connection::Connection;
+use crate::handlers::{HandlerError, Handlers};
+use crate::preference::PreferenceBuilder;
+use crate::stream::bpp::BppStream;
+use crate::SshError;
+
+/// Transport layer messages bound to the real handshake. (kexinit .. 49)
+fn is_kex(payload: &[u8]) -> bool {
+    matches!(payload.first(), Some(20..=49))
+}
+
+/// Message direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to server.
+    Inbound,
+    /// Server to client.
+    Outbound,
+}
+
+impl Direction {
+    fn symbol(self) -> char {
+        match self {
+            Self::Inbound => '<',
+            Self::Outbound => '>',
+        }
+    }
+}
+
+/// One recorded message.
+#[derive(Debug, Clone)]
+struct Record {
+    direction: Direction,
+    payload: Bytes,
+}
+
+/// Writes every message of a connection. Attach with [`Connection::record`].
+pub struct ReplayRecorder {
+    out: Box<dyn Write + Send + Sync>,
+    started: Instant,
+    inbound: u32,
+    outbound: u32,
+}
+
+impl fmt::Debug for ReplayRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayRecorder")
+            .field("inbound", &self.inbound)
+            .field("outbound", &self.outbound)
+            .finish()
+    }
+}
+
+impl ReplayRecorder {
+    pub fn new<W>(out: W) -> Self
+    where
+        W: Write + Send + Sync + 'static,
+    {
+        Self {
+            out: Box::new(out),
+            started: Instant::now(),
+            inbound: 0,
+            outbound: 0,
+        }
+    }
+
+    /// Write one message. Failures are logged, never fail the connection.
+    pub(crate) fn record(&mut self, direction: Direction, payload: &[u8]) {
+        let seq = match direction {
+            Direction::Inbound => &mut self.inbound,
+            Direction::Outbound => &mut self.outbound,
+        };
+        let line = format!(
+            "{} {} {} {}\n",
+            direction.symbol(),
+            seq,
+            self.started.elapsed().as_millis(),
+            base64::encode(payload)
+        );
+        *seq = seq.wrapping_add(1);
+        if let Err(e) = self.out.write_all(line.as_bytes()) {
+            log::warn!("failed to record message: {}", e);
+        }
+    }
+}
+
+/// Replay failure.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("malformed recording at line {0}")]
+    Malformed(usize),
+
+    #[error("outbound message {index} differs: expected {expected}, got {actual}")]
+    Mismatch {
+        index: usize,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("connection ended before outbound message {0}")]
+    UnexpectedEnd(usize),
+
+    #[error("unexpected outbound message {0}")]
+    Unexpected(String),
+
+    #[error(transparent)]
+    SshError(#[from] SshError),
+}
+
+fn describe(payload: &[u8]) -> String {
+    match payload.first() {
+        Some(id) => format!("message {} ({} bytes)", id, payload.len()),
+        None => "empty message".into(),
+    }
+}
+
+/// Feed a recording to [`Handlers`] over an in-memory transport.
+///
+/// No key exchange runs and key exchange messages of the recording are skipped.
+/// Inbound messages are sent in order, each once the outbound messages recorded
+/// before it were received and matched.
+///
+/// # Example
+///
+/// ```
+/// use ssssh::ReplayDriver;
+///
+/// let driver = ReplayDriver::parse("< 0 0 BQAAAAxzc2gtdXNlcmF1dGg=\n")
+///     .unwrap()
+///     .ignore_message(93);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReplayDriver {
+    records: Vec<Record>,
+    ignored: Vec<u8>,
+    user: Option<String>,
+}
+
+impl ReplayDriver {
+    pub fn parse(recording: &str) -> Result<Self, ReplayError> {
+        let mut records = vec![];
+        for (n, line) in recording.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let direction = match fields.next() {
+                Some("<") => Direction::Inbound,
+                Some(">") => Direction::Outbound,
+                _ => return Err(ReplayError::Malformed(n + 1)),
+            };
+            let payload = fields
+                .nth(2)
+                .and_then(|p| base64::decode(p).ok())
+                .ok_or(ReplayError::Malformed(n + 1))?;
+            records.push(Record {
+                direction,
+                payload: payload.into(),
+            });
+        }
+        Ok(Self {
+            records,
+            ignored: vec![],
+            user: None,
+        })
+    }
+
+    /// Like [`Self::parse`], with the connection already authenticated as
+    /// `user`, for recordings without the auth exchange.
+    pub fn authenticated(recording: &str, user: &str) -> Result<Self, ReplayError> {
+        let mut driver = Self::parse(recording)?;
+        driver.user = Some(user.into());
+        Ok(driver)
+    }
+
+    /// Neither send nor compare messages with id `id`.
+    pub fn ignore_message(mut self, id: u8) -> Self {
+        self.ignored.push(id);
+        self
+    }
+
+    fn replayed(&self, payload: &[u8]) -> bool {
+        !is_kex(payload) && !payload.first().is_some_and(|id| self.ignored.contains(id))
+    }
+
+    /// Replay against `handlers`, returning the connection result.
+    pub async fn run<E, Pty>(&self, handlers: Handlers<E, Pty>) -> Result<(), ReplayError>
+    where
+        E: Into<HandlerError> + Send + 'static,
+    {
+        // Host keys are generated per run, so announcing them never matches.
+        let preference = PreferenceBuilder::default()
+            .hostkeys_update(false)
+            .build()
+            .await?;
+        let preference = Arc::new(preference);
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let connection = Connection::established(
+            ours,
+            "SSH-2.0-replay".into(),
+            preference.version().clone(),
+            preference,
+            self.user.clone(),
+        );
+
+        let mut client = BppStream::new(theirs);
+        let server = connection.run(handlers).map_err(ReplayError::from);
+        let client = async move {
+            let records = self.records.iter().filter(|r| self.replayed(&r.payload));
+            for (index, record) in records.enumerate() {
+                match record.direction {
+                    Direction::Inbound => client.send(&record.payload[..]).await?,
+                    Direction::Outbound => {
+                        let actual = self
+                            .next_outbound(&mut client)
+                            .await?
+                            .ok_or(ReplayError::UnexpectedEnd(index))?;
+                        if actual != record.payload {
+                            return Err(ReplayError::Mismatch {
+                                index,
+                                expected: describe(&record.payload),
+                                actual: describe(&actual),
+                            });
+                        }
+                    }
+                }
+            }
+            client.close().await?;
+            if let Some(extra) = self.next_outbound(&mut client).await? {
+                return Err(ReplayError::Unexpected(describe(&extra)));
+            }
+            Ok(())
+        };
+
+        let (server, client) = future::join(server, client).await;
+        client?;
+        server
+    }
+
+    async fn next_outbound<IO>(
+        &self,
+        client: &mut BppStream<IO>,
+    ) -> Result<Option<Bytes>, ReplayError>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        while let Some(payload) = client.try_next().await? {
+            if self.replayed(&payload) {
+                return Ok(Some(payload));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> Self {
+        Self::SshError(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_parse() {
+        let out = Shared::default();
+        let mut recorder = ReplayRecorder::new(out.clone());
+        recorder.record(Direction::Inbound, b"\x05abc");
+        recorder.record(Direction::Outbound, b"\x06");
+        recorder.record(Direction::Inbound, b"\x14kex");
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("< 0 "));
+        assert!(lines[1].starts_with("> 0 "));
+        assert!(lines[2].starts_with("< 1 "));
+
+        let driver = ReplayDriver::parse(&text).unwrap();
+        assert_eq!(driver.records.len(), 3);
+        assert_eq!(driver.records[0].payload, Bytes::from_static(b"\x05abc"));
+        assert!(!driver.replayed(&driver.records[2].payload));
+        assert!(!driver.clone().ignore_message(5).replayed(b"\x05abc"));
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let err = ReplayDriver::parse("< 0 0 BQ==\n? 0 0 BQ==\n").unwrap_err();
+        assert!(matches!(err, ReplayError::Malformed(2)));
+        let err = ReplayDriver::parse("< 0 0\n").unwrap_err();
+        assert!(matches!(err, ReplayError::Malformed(1)));
+    }
+}
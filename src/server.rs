@@ -1,20 +1,31 @@
-use std::marker::PhantomData;
+use std::fmt;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
+use futures::future::{self, Future};
 use futures::ready;
+use futures::stream::{FuturesUnordered, StreamExt as _};
 use thiserror::Error;
 use tokio::io;
 use tokio::net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::{unix::SocketAddr as UnixSocketAddr, UnixListener, UnixStream};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinError;
 use tokio_stream::wrappers::TcpListenerStream;
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
 use tokio_stream::Stream;
 
 use crate::connection::{Accept, Connection};
+use crate::factory::HandlerFactory;
+use crate::handlers::HandlerError;
 use crate::preference::{Preference, PreferenceBuilder};
-use crate::SshError;
+use crate::{DisconnectReason, SshError};
 
 #[derive(Debug, Error)]
 pub enum BuildError {
@@ -28,10 +39,24 @@ pub enum BuildError {
     SshError(#[from] SshError),
 }
 
+type AfterBindHook = Box<dyn FnOnce() -> io::Result<()> + Send>;
+
+#[derive(Default)]
+struct AfterBind(Mutex<Option<AfterBindHook>>);
+
+impl fmt::Debug for AfterBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let set = self.0.lock().is_ok_and(|hook| hook.is_some());
+        f.debug_tuple("AfterBind").field(&set).finish()
+    }
+}
+
 /// Server instance builder.
 #[derive(Debug, Default)]
 pub struct Builder {
     preference: PreferenceBuilder,
+    after_bind: AfterBind,
+    handshake_limit: Option<usize>,
 }
 
 impl Builder {
@@ -45,6 +70,92 @@ impl Builder {
         self
     }
 
+    /// Offer kex algorithms `names` in this order, like sshd_config `KexAlgorithms`.
+    ///
+    /// Fails listing every name not implemented. Order drives negotiation.
+    /// Registered key exchanges may be named, unnamed ones are still offered last.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::ServerBuilder;
+    ///
+    /// let mut builder = ServerBuilder::default();
+    /// builder
+    ///     .kex_algorithms(&["curve25519-sha256"])?
+    ///     .ciphers(&["aes256-ctr", "aes128-ctr"])?
+    ///     .macs(&["hmac-sha2-512", "hmac-sha2-256"])?;
+    /// assert!(builder.macs(&["hmac-sha1", "hmac-md5"]).is_err());
+    /// # Ok::<_, ssssh::BuildError>(())
+    /// ```
+    pub fn kex_algorithms(&mut self, names: &[&str]) -> Result<&mut Self, BuildError> {
+        self.preference.kex_algorithms(names)?;
+        Ok(self)
+    }
+
+    /// Offer ciphers `names` in this order, like sshd_config `Ciphers`.
+    ///
+    /// Registered ciphers may be named, unnamed ones are still offered last.
+    pub fn ciphers(&mut self, names: &[&str]) -> Result<&mut Self, BuildError> {
+        self.preference.ciphers(names)?;
+        Ok(self)
+    }
+
+    /// Offer MACs `names` in this order, like sshd_config `MACs`.
+    ///
+    /// Registered MACs may be named, unnamed ones are still offered last.
+    pub fn macs(&mut self, names: &[&str]) -> Result<&mut Self, BuildError> {
+        self.preference.macs(names)?;
+        Ok(self)
+    }
+
+    /// Offer compression algorithms `names` in this order.
+    pub fn compressions(&mut self, names: &[&str]) -> Result<&mut Self, BuildError> {
+        self.preference.compressions(names)?;
+        Ok(self)
+    }
+
+    /// Offer host key signature algorithms `names` in this order, like
+    /// sshd_config `HostKeyAlgorithms`.
+    ///
+    /// Names without a matching host key are left out. Building fails if none is left.
+    pub fn host_key_algorithms(&mut self, names: &[&str]) -> Result<&mut Self, BuildError> {
+        self.preference.host_key_algorithms(names)?;
+        Ok(self)
+    }
+
+    /// Offer custom key exchange `name`. Fails if the name is already taken.
+    pub fn register_kex(
+        &mut self,
+        name: &str,
+        factory: Arc<dyn crate::KexFactory>,
+    ) -> Result<&mut Self, BuildError> {
+        self.preference.register_kex(name, factory)?;
+        Ok(self)
+    }
+
+    /// Offer custom cipher `name`. Fails if the name is already taken, or
+    /// its block size is not 8 to 255 bytes, or its key or IV is longer
+    /// than 64 bytes.
+    pub fn register_cipher(
+        &mut self,
+        name: &str,
+        factory: Arc<dyn crate::CipherFactory>,
+    ) -> Result<&mut Self, BuildError> {
+        self.preference.register_cipher(name, factory)?;
+        Ok(self)
+    }
+
+    /// Offer custom MAC `name`. Fails if the name is already taken.
+    pub fn register_mac(
+        &mut self,
+        name: &str,
+        factory: Arc<dyn crate::MacFactory>,
+    ) -> Result<&mut Self, BuildError> {
+        self.preference.register_mac(name, factory)?;
+        Ok(self)
+    }
+
     pub fn add_mac_algorithm(&mut self, name: crate::Mac) -> &mut Self {
         self.preference.add_mac_algorithm(name);
         self
@@ -55,26 +166,316 @@ impl Builder {
         self
     }
 
+    /// Software version sent as `SSH-2.0-<name>`. (default: sssh)
+    ///
+    /// Must be printable US-ASCII without spaces or `-`, otherwise `build`
+    /// fails with [`SshError::InvalidServerVersion`].
     pub fn name(&mut self, name: &str) -> &mut Self {
         self.preference.name(name);
         self
     }
 
+    /// Comment sent after the software version, separated by a space. (default: none)
+    ///
+    /// Must be printable US-ASCII, spaces allowed, and keep the line within
+    /// 255 bytes. Not sent with [`Stealth::minimal_banner`](crate::Stealth::minimal_banner).
+    pub fn version_comment(&mut self, comment: &str) -> &mut Self {
+        self.preference.version_comment(comment);
+        self
+    }
+
+    /// Approximate per-connection memory budget in bytes. (default: unlimited)
+    ///
+    /// Channel windows stop being replenished at 75% of the budget, new
+    /// channels are rejected at 90%, and the connection is disconnected once
+    /// it is exceeded. See [`GlobalHandle::memory_used`](crate::GlobalHandle::memory_used).
+    pub fn memory_limit(&mut self, limit: usize) -> &mut Self {
+        self.preference.memory_limit(limit);
+        self
+    }
+
+    /// Handler output messages queued per connection before handlers wait. (default: 64)
+    ///
+    /// Stdio pipes are read and [`DetachedChannel`](crate::DetachedChannel) or
+    /// [`SessionContext::send_stderr`](crate::SessionContext::send_stderr) sends
+    /// complete only as the queue drains, and it is only drained while the
+    /// socket keeps up. Channel window flow control applies after the queue:
+    /// output beyond the client's window waits in the connection, counted
+    /// against [`memory_limit`](Self::memory_limit), while other channels go on.
+    pub fn send_queue_size(&mut self, size: usize) -> &mut Self {
+        self.preference.send_queue_size(size);
+        self
+    }
+
+    /// Maximum length of formatted handler error messages. (default: 1024)
+    ///
+    /// Longer messages are truncated before reaching logs or the wire.
+    pub fn handler_error_limit(&mut self, limit: usize) -> &mut Self {
+        self.preference.error_limit(limit);
+        self
+    }
+
+    /// Language tags advertised in kexinit. (default: none)
+    ///
+    /// Informational only. Clients never fail on mismatch.
+    pub fn languages(&mut self, languages: &[&str]) -> &mut Self {
+        self.preference.languages(languages);
+        self
+    }
+
+    /// Adjust algorithms for clients matching `quirk`.
+    ///
+    /// Checked before the compiled-in quirks.
+    pub fn add_client_quirk(&mut self, quirk: crate::Quirk) -> &mut Self {
+        self.preference.add_client_quirk(quirk);
+        self
+    }
+
+    /// Apply compiled-in quirks for known broken clients. (default: on)
+    pub fn default_client_quirks(&mut self, enable: bool) -> &mut Self {
+        self.preference.default_client_quirks(enable);
+        self
+    }
+
+    /// Disconnect on protocol warnings of `kind` instead of tolerating them.
+    pub fn escalate_warning(&mut self, kind: crate::WarningKind) -> &mut Self {
+        self.preference.escalate_warning(kind);
+        self
+    }
+
+    /// Tolerate protocol warnings of `kind` disconnecting by default, i.e.
+    /// [`WarningKind::PacketExceeded`](crate::WarningKind::PacketExceeded).
+    pub fn tolerate_warning(&mut self, kind: crate::WarningKind) -> &mut Self {
+        self.preference.tolerate_warning(kind);
+        self
+    }
+
+    /// Append the secret of every key exchange to `out`. (default: off)
+    ///
+    /// **Security sensitive debug feature.** Anyone holding the log can
+    /// decrypt captured traffic of the logged connections. Lines follow the
+    /// Wireshark SSH dissector key log format and are flushed one by one.
+    pub fn keylog<W>(&mut self, out: W) -> &mut Self
+    where
+        W: std::io::Write + Send + 'static,
+    {
+        self.preference.keylog(out);
+        self
+    }
+
+    /// Report packets, auth results, channels and key exchanges of every
+    /// connection to `observer`, e.g. to export metrics. (default: none)
+    ///
+    /// See `examples/metrics.rs`.
+    pub fn observer(&mut self, observer: Arc<dyn crate::ConnectionObserver>) -> &mut Self {
+        self.preference.observer(observer);
+        self
+    }
+
+    /// Take the kexinit cookie and shuffled order from `random`. (default: the
+    /// operating system's generator)
+    ///
+    /// Connections fail instead of panicking when `random` does.
+    pub fn random(&mut self, random: Arc<dyn crate::Random>) -> &mut Self {
+        self.preference.random(random);
+        self
+    }
+
+    /// Reduce what scanners learn before key exchange. (default: all off)
+    pub fn stealth(&mut self, stealth: crate::Stealth) -> &mut Self {
+        self.preference.stealth(stealth);
+        self
+    }
+
+    /// Load host keys from an unencrypted OpenSSH private key file at build time.
+    ///
+    /// Keys persist across restarts unlike [`generate_hostkeys`](Self::generate_hostkeys).
+    /// Passphrase protected files fail with [`SshError::EncryptedKeyFile`].
     pub fn hostkeys_from_path<P: AsRef<Path>>(&mut self, file: P) -> &mut Self {
         self.preference.hostkeys_from_path(file);
         self
     }
 
+    /// Serve `hostkey`. Of keys of the same type, the last added or loaded one is used.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{HostKey, Key, ServerBuilder};
+    ///
+    /// let hostkey = HostKey::generate(&Key::SshEd25519)?;
+    /// let publickey = hostkey.publickey(); // e.g. for known_hosts
+    /// ServerBuilder::default().add_hostkey(hostkey);
+    /// # Ok::<_, ssssh::SshError>(())
+    /// ```
+    pub fn add_hostkey(&mut self, hostkey: crate::HostKey) -> &mut Self {
+        self.preference.add_hostkey(hostkey);
+        self
+    }
+
     pub fn generate_hostkeys(&mut self) -> &mut Self {
         self.preference.hostkeys_generate();
         self
     }
 
+    /// Disconnect with [`SshError::Timeout`] once nothing was received for
+    /// `timeout`. Every packet received restarts it. (default: off)
     pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
         self.preference.timeout(timeout);
         self
     }
 
+    /// Disconnect when output is pending but nothing was sent or received for `timeout`. (default: off)
+    pub fn stall_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.stall_timeout(timeout);
+        self
+    }
+
+    /// Run `hook` once the listener is bound, before accepting connections.
+    ///
+    /// Intended for dropping privileges. Its error aborts [`Builder::build`].
+    /// Runs on the first build only.
+    pub fn after_bind<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        self.after_bind = AfterBind(Mutex::new(Some(Box::new(hook))));
+        self
+    }
+
+    /// Disconnect with [`SshError::HandshakeTimeout`] unless version exchange
+    /// and the first key exchange completed within `timeout` after connect.
+    /// (default: 30s)
+    pub fn handshake_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.handshake_timeout(timeout);
+        self
+    }
+
+    /// Disconnect with [`SshError::AuthTimeout`] unless the client
+    /// authenticated within `timeout` after connect. (default: 120s)
+    pub fn auth_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.auth_timeout(timeout);
+        self
+    }
+
+    /// Close session channels which got no request, data or window adjust
+    /// within `timeout` after open. (default: 60s)
+    pub fn unused_channel_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.unused_channel_timeout(timeout);
+        self
+    }
+
+    /// Start a key exchange once either direction sent `bytes` under the
+    /// current keys. (default: 1 GiB)
+    pub fn rekey_limit(&mut self, bytes: u64) -> &mut Self {
+        self.preference.rekey_limit(bytes);
+        self
+    }
+
+    /// Start a key exchange once the current keys are `interval` old. (default: 1h)
+    ///
+    /// `Duration::MAX` never re-keys by time.
+    pub fn rekey_interval(&mut self, interval: Duration) -> &mut Self {
+        self.preference.rekey_interval(interval);
+        self
+    }
+
+    /// Send `keepalive@openssh.com` once nothing was received for `interval`.
+    /// Disconnect with [`SshError::Timeout`] after `max_missed` probes in a
+    /// row went unanswered. (default: off)
+    pub fn keepalive(&mut self, interval: Duration, max_missed: u32) -> &mut Self {
+        self.preference.keepalive(interval, max_missed);
+        self
+    }
+
+    /// Send SSH_MSG_IGNORE with up to `max_len` random bytes at random
+    /// intervals averaging `interval`, to blur traffic analysis. (default: off)
+    ///
+    /// Starts after the first key exchange. Never more often than every
+    /// `interval / 2`, `interval` is at least 100ms and `max_len` at most 4096.
+    pub fn traffic_padding(&mut self, interval: Duration, max_len: usize) -> &mut Self {
+        self.preference.traffic_padding(interval, max_len);
+        self
+    }
+
+    /// Send EXT_INFO again right before USERAUTH_SUCCESS, as RFC 8308 allows. (default: off)
+    ///
+    /// OpenSSH clients before 9.6 disconnect on it.
+    pub fn ext_info_in_auth(&mut self, enable: bool) -> &mut Self {
+        self.preference.ext_info_in_auth(enable);
+        self
+    }
+
+    /// Announce all host keys with `hostkeys-00@openssh.com` after
+    /// USERAUTH_SUCCESS and prove ownership on request. (default: on)
+    ///
+    /// Lets OpenSSH clients with `UpdateHostKeys` learn new keys before the
+    /// old ones are retired.
+    pub fn hostkeys_update(&mut self, enable: bool) -> &mut Self {
+        self.preference.hostkeys_update(enable);
+        self
+    }
+
+    /// Fail channel opens we sent, e.g. by
+    /// [`GlobalHandle::open_channel`](crate::GlobalHandle::open_channel),
+    /// which the client did not answer within `timeout`. (default: 30s)
+    pub fn channel_open_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.channel_open_timeout(timeout);
+        self
+    }
+
+    /// Skip at most `lines` lines or `bytes` bytes a client sends before its
+    /// identification string, e.g. proxy or HTTP noise. (default: 10 lines, 4096 bytes)
+    ///
+    /// More fails with [`SshError::PreBannerTooLong`].
+    pub fn pre_banner_limit(&mut self, lines: usize, bytes: usize) -> &mut Self {
+        self.preference.pre_banner_limit(lines, bytes);
+        self
+    }
+
+    /// Disconnect with [`SshError::TooManyAuthFailures`] once `attempts`
+    /// auth requests were rejected. (default: 6)
+    ///
+    /// Method `none` is not counted.
+    pub fn max_auth_attempts(&mut self, attempts: u32) -> &mut Self {
+        self.preference.max_auth_attempts(attempts);
+        self
+    }
+
+    /// Channels open at once per connection. (default: 64)
+    ///
+    /// Channels opened by the client beyond it are refused with
+    /// `SSH_OPEN_RESOURCE_SHORTAGE`, the connection goes on. Closed channels
+    /// no longer count. Channels are never opened before auth succeeded.
+    pub fn max_channels(&mut self, channels: usize) -> &mut Self {
+        self.preference.max_channels(channels);
+        self
+    }
+
+    /// Channels the client may open over a connection's lifetime. (default: unlimited)
+    ///
+    /// Later opens are refused as beyond [`max_channels`](Self::max_channels).
+    pub fn max_total_channels(&mut self, channels: u64) -> &mut Self {
+        self.preference.max_total_channels(channels);
+        self
+    }
+
+    /// Receive window advertised per channel. (default: 2 MiB)
+    ///
+    /// Window is replenished as the handler consumes data.
+    pub fn channel_window_size(&mut self, size: u32) -> &mut Self {
+        self.preference.channel_window_size(size);
+        self
+    }
+
+    /// Connections in version exchange at once under [`Server::serve`]. (default: unlimited)
+    ///
+    /// Further connections wait in the listen backlog. `0` is taken as `1`.
+    pub fn handshake_limit(&mut self, limit: usize) -> &mut Self {
+        self.handshake_limit = Some(limit);
+        self
+    }
+
     pub async fn build<A>(
         &self,
         addr: A,
@@ -83,28 +484,285 @@ impl Builder {
         A: ToSocketAddrs,
     {
         let preference = self.preference.build().await?;
-        let preference = Arc::new(preference);
 
         let addr = lookup_host(addr).await?.next();
         if let Some(addr) = addr {
             let io = TcpListener::bind(addr).await?;
-            Ok(Server {
-                io: TcpListenerStream::new(io),
-                preference,
-                _stream: PhantomData,
-            })
+            self.run_after_bind()?;
+            let peer = |io: &TcpStream| io.peer_addr().ok().map(|addr| addr.to_string());
+            Ok(self.server(TcpListenerStream::new(io), preference, peer))
         } else {
             Err(BuildError::Unresolved)
         }
     }
+
+    /// Like [`build`](Self::build), listening on Unix domain socket `path`.
+    ///
+    /// `path` must not exist yet.
+    #[cfg(unix)]
+    pub async fn build_unix<P>(
+        &self,
+        path: P,
+    ) -> Result<Server<UnixListenerStream, UnixStream>, BuildError>
+    where
+        P: AsRef<Path>,
+    {
+        let preference = self.preference.build().await?;
+        let io = UnixListener::bind(path)?;
+        self.run_after_bind()?;
+        Ok(self.server(UnixListenerStream::new(io), preference, |_| None))
+    }
+
+    /// Like [`build`](Self::build), accepting connections from `listener`,
+    /// e.g. a socket activated one, or in-memory streams in tests.
+    ///
+    /// The [after bind](Self::after_bind) hook is not run.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use futures::prelude::*;
+    /// use ssssh::ServerBuilder;
+    ///
+    /// # async fn run() -> anyhow::Result<()> {
+    /// let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    /// let listener = stream::iter(vec![Ok(ours)]);
+    /// let mut server = ServerBuilder::default().build_with_listener(listener).await?;
+    /// let conn = server.next().await.unwrap()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn build_with_listener<L, S>(&self, listener: L) -> Result<Server<L, S>, BuildError>
+    where
+        L: Stream<Item = io::Result<S>> + Unpin,
+        S: io::AsyncRead + io::AsyncWrite + Unpin,
+    {
+        let preference = self.preference.build().await?;
+        Ok(self.server(listener, preference, |_| None))
+    }
+
+    fn run_after_bind(&self) -> io::Result<()> {
+        let hook = self.after_bind.0.lock().unwrap().take();
+        if let Some(hook) = hook {
+            hook()?;
+        }
+        Ok(())
+    }
+
+    fn server<L, S>(
+        &self,
+        io: L,
+        preference: Preference,
+        peer: fn(&S) -> Option<String>,
+    ) -> Server<L, S> {
+        Server {
+            io,
+            preference: Arc::new(preference),
+            handshake_limit: self.handshake_limit,
+            peer,
+        }
+    }
 }
 
 /// SSH server instance.
+///
+/// Stream of incoming connections, not yet version exchanged. An accept
+/// error (e.g. too many open files) is yielded as is and the stream goes on,
+/// so skip or log it instead of ending the loop. Ends only with the listener.
+///
+/// # Example
+///
+/// ```no_run
+/// use futures::prelude::*;
+/// use ssssh::{Handlers, ServerBuilder};
+///
+/// # async fn run() -> anyhow::Result<()> {
+/// let server = ServerBuilder::default().build("[::1]:2222").await?;
+/// server
+///     .for_each_concurrent(64, |conn| async move {
+///         let result = async {
+///             let conn = conn?.accept().await?;
+///             conn.run(Handlers::<anyhow::Error>::new()).await?;
+///             Ok::<_, anyhow::Error>(())
+///         };
+///         if let Err(e) = result.await {
+///             log::warn!("{}", e);
+///         }
+///     })
+///     .await;
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Debug)]
 pub struct Server<L, S> {
     io: L,
     preference: Arc<Preference>,
-    _stream: PhantomData<S>,
+    handshake_limit: Option<usize>,
+    /// Remote address of an accepted stream, for diagnostics.
+    peer: fn(&S) -> Option<String>,
+}
+
+impl Server<TcpListenerStream, TcpStream> {
+    /// Address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.as_ref().local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl Server<UnixListenerStream, UnixStream> {
+    /// Address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<UnixSocketAddr> {
+        self.io.as_ref().local_addr()
+    }
+}
+
+impl<L, S> Server<L, S>
+where
+    L: Stream<Item = io::Result<S>> + Unpin,
+    S: io::AsyncRead + io::AsyncWrite + Unpin + Send + 'static,
+{
+    /// Identification string sent to clients, without CR LF.
+    pub fn version(&self) -> &str {
+        self.preference.version()
+    }
+
+    /// Connection over `io` accepted elsewhere, with this server's settings.
+    pub fn connection(&self, io: S) -> Connection<Accept<S>> {
+        let remote = (self.peer)(&io);
+        Connection::new(io, self.preference.clone(), remote.as_deref())
+    }
+
+    /// Accept connections and run each on its own task with `factory`.
+    ///
+    /// Neither version exchange nor a running connection holds up the next
+    /// accept. Accept, handshake and run errors, handler panics included, are
+    /// passed to `on_error`. Returns once the listener ended and every
+    /// connection finished.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ssssh::{ConnectionInfo, Handlers, ServerBuilder};
+    ///
+    /// # async fn run() -> anyhow::Result<()> {
+    /// let server = ServerBuilder::default()
+    ///     .handshake_limit(64)
+    ///     .build("[::1]:2222")
+    ///     .await?;
+    /// let factory = |_: &ConnectionInfo| Handlers::<anyhow::Error>::new();
+    /// server.serve(factory, |e| log::warn!("{}", e)).await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn serve<F, E, Pty, C>(self, factory: F, on_error: C)
+    where
+        F: HandlerFactory<E, Pty> + Send + Sync + 'static,
+        E: Into<HandlerError> + Send + 'static,
+        Pty: Send + 'static,
+        C: FnMut(SshError),
+    {
+        self.serve_until(factory, on_error, future::pending()).await
+    }
+
+    /// [`serve`](Self::serve) until `shutdown` resolves, then stop accepting
+    /// and [shut down](crate::GlobalHandle::shutdown) every connection with
+    /// `SSH_DISCONNECT_BY_APPLICATION`. Connections still in version exchange
+    /// are dropped.
+    ///
+    /// Returns once every connection finished.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ssssh::{ConnectionInfo, Handlers, ServerBuilder};
+    ///
+    /// # async fn run() -> anyhow::Result<()> {
+    /// let server = ServerBuilder::default().build("[::1]:2222").await?;
+    /// let factory = |_: &ConnectionInfo| Handlers::<anyhow::Error>::new();
+    /// let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+    /// let stopped = async {
+    ///     stopped.await.ok();
+    /// };
+    /// server
+    ///     .serve_until(factory, |e| log::warn!("{}", e), stopped)
+    ///     .await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn serve_until<F, E, Pty, C, D>(mut self, factory: F, mut on_error: C, shutdown: D)
+    where
+        F: HandlerFactory<E, Pty> + Send + Sync + 'static,
+        E: Into<HandlerError> + Send + 'static,
+        Pty: Send + 'static,
+        C: FnMut(SshError),
+        D: Future<Output = ()>,
+    {
+        let factory = Arc::new(factory);
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        tokio::pin!(shutdown);
+        let limit = self
+            .handshake_limit
+            .map_or(Semaphore::MAX_PERMITS, |n| n.max(1));
+        let handshakes = Arc::new(Semaphore::new(limit));
+        let mut connections = FuturesUnordered::new();
+
+        loop {
+            let accept = async {
+                // Never closed.
+                let permit = handshakes.clone().acquire_owned().await.unwrap();
+                self.next().await.map(|conn| (permit, conn))
+            };
+            tokio::select! {
+                accepted = accept => match accepted {
+                    Some((permit, Ok(conn))) => {
+                        let factory = factory.clone();
+                        let mut shutdown = shutdown_rx.clone();
+                        connections.push(tokio::spawn(async move {
+                            // Not yet established, nothing to tell the client.
+                            let conn = tokio::select! {
+                                conn = conn.accept() => conn,
+                                _ = shutdown.changed() => return Ok(()),
+                            };
+                            drop(permit);
+                            let conn = conn?;
+                            let handle = conn.handle();
+                            let run = conn.run_with(&*factory);
+                            tokio::pin!(run);
+                            tokio::select! {
+                                result = &mut run => return result,
+                                _ = shutdown.changed() => {}
+                            }
+                            let reason = DisconnectReason::ByApplication;
+                            handle.shutdown(reason, "server shutting down").ok();
+                            run.await
+                        }));
+                    }
+                    Some((_, Err(e))) => on_error(e.into()),
+                    None => break,
+                },
+                _ = &mut shutdown => {
+                    shutdown_tx.send(()).ok();
+                    break;
+                }
+                Some(joined) = connections.next() => {
+                    if let Err(e) = flatten(joined) {
+                        on_error(e);
+                    }
+                }
+            }
+        }
+
+        while let Some(joined) = connections.next().await {
+            if let Err(e) = flatten(joined) {
+                on_error(e);
+            }
+        }
+    }
+}
+
+fn flatten(joined: Result<Result<(), SshError>, JoinError>) -> Result<(), SshError> {
+    joined.map_err(SshError::any)?
 }
 
 impl<L, S> Stream for Server<L, S>
@@ -118,7 +776,10 @@ where
         let this = self.get_mut();
         let result = ready!(Pin::new(&mut this.io).poll_next(cx));
         if let Some(stream) = result {
-            Poll::Ready(Some(Ok(Connection::new(stream?, this.preference.clone()))))
+            let stream = stream?;
+            let remote = (this.peer)(&stream);
+            let conn = Connection::new(stream, this.preference.clone(), remote.as_deref());
+            Poll::Ready(Some(Ok(conn)))
         } else {
             Poll::Ready(None)
         }
@@ -144,6 +805,97 @@ mod tests {
         assert!(err.is_err())
     }
 
+    #[tokio::test]
+    async fn test_after_bind_error() {
+        let err = Builder::default()
+            .after_bind(|| Err(io::ErrorKind::PermissionDenied.into()))
+            .build("[::1]:0")
+            .await;
+        match err {
+            Err(BuildError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_addr() {
+        let server = Builder::default().build("[::1]:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_build_unix() {
+        let path = std::env::temp_dir().join(format!("ssssh-{}.sock", std::process::id()));
+        let server = Builder::default().build_unix(&path).await.unwrap();
+        assert_eq!(server.local_addr().unwrap().as_pathname(), Some(&*path));
+        assert!(Builder::default().build_unix(&path).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_build_with_listener() {
+        use futures::prelude::*;
+
+        let (ours, theirs) = io::duplex(1024);
+        let mut server = Builder::default()
+            .name("duplex")
+            .build_with_listener(stream::iter(vec![Ok(ours)]))
+            .await
+            .unwrap();
+        let conn = server.next().await.unwrap().unwrap();
+        assert!(server.next().await.is_none());
+
+        let (conn, _) = tokio::join!(conn.accept(), exchange_version(theirs));
+        let info = conn.unwrap().info();
+        assert_eq!(info.client_version(), "SSH-2.0-client");
+        assert_eq!(info.server_version(), "SSH-2.0-duplex");
+
+        let (ours, theirs) = io::duplex(1024);
+        let (conn, _) = tokio::join!(server.connection(ours).accept(), exchange_version(theirs));
+        assert_eq!(conn.unwrap().info().server_version(), "SSH-2.0-duplex");
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let server = Builder::default()
+            .name("ssssh_1.0")
+            .version_comment("Debian 1")
+            .build("[::1]:0")
+            .await
+            .unwrap();
+        assert_eq!(server.version(), "SSH-2.0-ssssh_1.0 Debian 1");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_version() {
+        let long = "x".repeat(250);
+        let cases = [
+            ("", None),
+            ("ssssh 1.0", None),
+            ("ssssh-1.0", None),
+            ("ssssh\r\n", None),
+            ("sssh\u{e9}", None),
+            ("ssssh", Some("")),
+            ("ssssh", Some("line\nbreak")),
+            ("ssssh", Some(long.as_str())),
+        ];
+        for (name, comment) in cases {
+            let mut builder = Builder::default();
+            builder.name(name);
+            if let Some(comment) = comment {
+                builder.version_comment(comment);
+            }
+            match builder.build("[::1]:0").await {
+                Err(BuildError::SshError(SshError::InvalidServerVersion(..))) => {}
+                Err(e) => panic!("{:?}: {}", name, e),
+                Ok(_) => panic!("{:?} {:?} accepted", name, comment),
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_end() {
         use futures::prelude::*;
@@ -152,7 +904,8 @@ mod tests {
         let mut server = Server {
             io: stream,
             preference: Arc::new(PreferenceBuilder::default().build().await.unwrap()),
-            _stream: PhantomData,
+            handshake_limit: None,
+            peer: |_| None,
         };
         assert!(server.next().await.is_none())
     }
@@ -166,8 +919,193 @@ mod tests {
         let mut server = Server {
             io: stream,
             preference: Arc::new(PreferenceBuilder::default().build().await.unwrap()),
-            _stream: PhantomData,
+            handshake_limit: None,
+            peer: |_| None,
         };
         assert!(server.next().await.unwrap().is_err())
     }
+
+    fn server_over<L>(io: L, preference: Arc<Preference>) -> Server<L, io::DuplexStream> {
+        Server {
+            io,
+            preference,
+            handshake_limit: None,
+            peer: |_| None,
+        }
+    }
+
+    /// Client side of the version exchange.
+    async fn exchange_version(mut io: io::DuplexStream) -> io::DuplexStream {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        io.write_all(b"SSH-2.0-client\r\n").await.unwrap();
+        while io.read_u8().await.unwrap() != b'\n' {}
+        io
+    }
+
+    #[tokio::test]
+    async fn test_transient_error() {
+        use futures::prelude::*;
+
+        let (a, _a) = io::duplex(1024);
+        let (b, _b) = io::duplex(1024);
+        let accepts = vec![Ok(a), Err(io::ErrorKind::Other.into()), Ok(b)];
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let mut server = server_over(stream::iter(accepts), preference);
+
+        assert!(server.next().await.unwrap().is_ok());
+        assert!(server.next().await.unwrap().is_err());
+        assert!(server.next().await.unwrap().is_ok());
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_take_until() {
+        use futures::channel::{mpsc, oneshot};
+        use futures::prelude::*;
+
+        let (listener, accepts) = mpsc::unbounded();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let server = server_over(accepts, preference).take_until(shutdown_rx);
+
+        let (ours, theirs) = io::duplex(1024);
+        listener.unbounded_send(Ok(ours)).unwrap();
+        let mut handshakes = vec![];
+        let accepting = server.for_each(|conn| {
+            handshakes.push(tokio::spawn(conn.unwrap().accept()));
+            future::ready(())
+        });
+        let driver = async {
+            // Shut down while the accepted connection still waits for the client.
+            tokio::task::yield_now().await;
+            shutdown_tx.send(()).unwrap();
+            let (late, _) = io::duplex(1024);
+            listener.unbounded_send(Ok(late)).ok();
+        };
+        future::join(accepting, driver).await;
+        assert_eq!(handshakes.len(), 1);
+
+        let _theirs = exchange_version(theirs).await;
+        let established = handshakes.pop().unwrap().await.unwrap();
+        assert!(established.is_ok());
+    }
+
+    fn no_handlers(_: &crate::ConnectionInfo) -> crate::Handlers<anyhow::Error> {
+        crate::Handlers::new()
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        use futures::channel::mpsc;
+
+        let (listener, accepts) = mpsc::unbounded();
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let server = server_over(accepts, preference);
+        let mut errors = vec![];
+        let serving = server.serve(no_handlers, |e| errors.push(e));
+
+        let client = async {
+            let (stalled, _stalled) = io::duplex(1024);
+            let (second, theirs) = io::duplex(1024);
+            listener.unbounded_send(Ok(stalled)).unwrap();
+            listener
+                .unbounded_send(Err(io::ErrorKind::Other.into()))
+                .unwrap();
+            listener.unbounded_send(Ok(second)).unwrap();
+            // Handshakes with the first client still pending.
+            let theirs = exchange_version(theirs).await;
+            drop(theirs);
+            listener.close_channel();
+        };
+        futures::future::join(serving, client).await;
+
+        // Accept error and the stalled client hanging up. The second ended cleanly.
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().all(|e| matches!(e, SshError::IoError(..))));
+    }
+
+    #[tokio::test]
+    async fn test_serve_until() {
+        use futures::channel::{mpsc, oneshot};
+        use futures::FutureExt as _;
+        use tokio::io::AsyncReadExt as _;
+
+        let (listener, accepts) = mpsc::unbounded();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let server = server_over(accepts, preference);
+        let mut errors = vec![];
+        let shutdown = shutdown_rx.map(|_| ());
+        let serving = server.serve_until(no_handlers, |e| errors.push(e), shutdown);
+
+        let client = async {
+            let (stalled, _stalled) = io::duplex(1024);
+            let (established, theirs) = io::duplex(64 * 1024);
+            listener.unbounded_send(Ok(stalled)).unwrap();
+            listener.unbounded_send(Ok(established)).unwrap();
+            let mut theirs = exchange_version(theirs).await;
+            shutdown_tx.send(()).unwrap();
+            // KEXINIT, then DISCONNECT, then EOF.
+            let mut received = vec![];
+            theirs.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (_, received) = futures::future::join(serving, client).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        // Unencrypted packets: length, padding length, message id.
+        let mut packets = vec![];
+        let mut rest = &received[..];
+        while rest.len() > 5 {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            packets.push(rest[5]);
+            rest = &rest[4 + len..];
+        }
+        assert_eq!(packets, vec![20, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_serve_handshake_limit() {
+        use futures::channel::mpsc;
+        use tokio::io::AsyncWriteExt as _;
+
+        let (listener, accepts) = mpsc::unbounded();
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let mut server = server_over(accepts, preference);
+        server.handshake_limit = Some(1);
+        let serving = server.serve(no_handlers, |_| {});
+
+        let client = async {
+            let (first, mut first_theirs) = io::duplex(1024);
+            let (second, theirs) = io::duplex(1024);
+            listener.unbounded_send(Ok(first)).unwrap();
+            listener.unbounded_send(Ok(second)).unwrap();
+            listener.close_channel();
+
+            let second = tokio::spawn(exchange_version(theirs));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(!second.is_finished());
+
+            first_theirs.write_all(b"SSH-2.0-client\r\n").await.unwrap();
+            second.await.unwrap();
+        };
+        futures::future::join(serving, client).await;
+    }
+
+    #[tokio::test]
+    async fn test_serve_panic() {
+        let (ours, theirs) = io::duplex(1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let server = server_over(futures::stream::iter(vec![Ok(ours)]), preference);
+        let factory = |_: &crate::ConnectionInfo| -> crate::Handlers<anyhow::Error> {
+            panic!("factory failed")
+        };
+        let mut errors = vec![];
+        let serving = server.serve(factory, |e| errors.push(e));
+        futures::future::join(serving, exchange_version(theirs)).await;
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], SshError::Any(..)), "{:?}", errors);
+    }
 }
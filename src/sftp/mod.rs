@@ -0,0 +1,551 @@
+//! SFTP version 3 server.
+//!
+//! Run [`serve`] on the stdio of a `sftp` subsystem channel, with a
+//! [`SftpHandler`] for the storage.
+//!
+//! # Example
+//!
+//! ```
+//! use futures::FutureExt as _;
+//! use ssssh::sftp::{self, SftpHandler};
+//! use ssssh::Handlers;
+//!
+//! struct Empty;
+//! impl SftpHandler for Empty {}
+//!
+//! let mut handlers = Handlers::<anyhow::Error>::new();
+//! handlers.on_channel_subsystem(|mut ctx: ssssh::SessionContext, name| {
+//!     async move {
+//!         if name != "sftp" {
+//!             return Ok(1);
+//!         }
+//!         let (stdin, stdout, _) = ctx.take_stdio().unwrap();
+//!         sftp::serve(Empty, stdin, stdout).await?;
+//!         Ok(0)
+//!     }
+//!     .boxed()
+//! });
+//! ```
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use futures::future::{BoxFuture, FutureExt as _};
+use getset::Getters;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use crate::pack::Unpack as _;
+use crate::SshError;
+
+use packet::{Request, Response};
+
+mod packet;
+
+/// Protocol version we speak.
+const SFTP_VERSION: u32 = 3;
+
+/// Packets larger than this are rejected.
+const MAXIMUM_PACKET_SIZE: usize = 256 * 1024;
+
+/// Reads are answered with at most this many bytes.
+const MAXIMUM_READ_LENGTH: u32 = 64 * 1024;
+
+/// `SSH_FX_*` status code.
+///
+/// Handlers fail with one of these. `io::Error`s convert by kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Ok,
+    Eof,
+    NoSuchFile,
+    PermissionDenied,
+    Failure,
+    BadMessage,
+    NoConnection,
+    ConnectionLost,
+    OpUnsupported,
+}
+
+impl StatusCode {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Ok => 0,
+            Self::Eof => 1,
+            Self::NoSuchFile => 2,
+            Self::PermissionDenied => 3,
+            Self::Failure => 4,
+            Self::BadMessage => 5,
+            Self::NoConnection => 6,
+            Self::ConnectionLost => 7,
+            Self::OpUnsupported => 8,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Self::Ok => "Success",
+            Self::Eof => "End of file",
+            Self::NoSuchFile => "No such file",
+            Self::PermissionDenied => "Permission denied",
+            Self::Failure => "Failure",
+            Self::BadMessage => "Bad message",
+            Self::NoConnection => "No connection",
+            Self::ConnectionLost => "Connection lost",
+            Self::OpUnsupported => "Operation unsupported",
+        }
+    }
+}
+
+impl From<io::Error> for StatusCode {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::NoSuchFile,
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            io::ErrorKind::UnexpectedEof => Self::Eof,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Self::BadMessage,
+            io::ErrorKind::Unsupported => Self::OpUnsupported,
+            _ => Self::Failure,
+        }
+    }
+}
+
+/// `pflags` of an open request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn read(&self) -> bool {
+        self.0 & 0x01 != 0
+    }
+
+    pub fn write(&self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    pub fn append(&self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    pub fn create(&self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    pub fn truncate(&self) -> bool {
+        self.0 & 0x10 != 0
+    }
+
+    pub fn exclusive(&self) -> bool {
+        self.0 & 0x20 != 0
+    }
+}
+
+/// File attributes. Absent ones are not sent or were not requested.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub size: Option<u64>,
+    pub uid_gid: Option<(u32, u32)>,
+    /// Mode including the file type bits.
+    pub permissions: Option<u32>,
+    pub atime_mtime: Option<(u32, u32)>,
+}
+
+/// Entry of a directory listing.
+#[derive(Debug, Clone, Getters, derive_new::new)]
+pub struct Name {
+    #[get = "pub"]
+    filename: String,
+
+    /// `ls -l` style line, shown by clients as is.
+    #[get = "pub"]
+    longname: String,
+
+    #[get = "pub"]
+    attrs: FileAttributes,
+}
+
+/// Storage behind a SFTP session.
+///
+/// Handles are opaque to the client. Requests are handled one at a time, in
+/// order. Every operation is `OpUnsupported` until implemented.
+pub trait SftpHandler: Send {
+    fn open(
+        &mut self,
+        _filename: String,
+        _flags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        unsupported()
+    }
+
+    fn close(&mut self, _handle: Bytes) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    /// Read up to `len` bytes. Empty data is end of file.
+    fn read(
+        &mut self,
+        _handle: Bytes,
+        _offset: u64,
+        _len: u32,
+    ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        unsupported()
+    }
+
+    fn write(
+        &mut self,
+        _handle: Bytes,
+        _offset: u64,
+        _data: Bytes,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    fn stat(&mut self, _path: String) -> BoxFuture<'_, Result<FileAttributes, StatusCode>> {
+        unsupported()
+    }
+
+    /// Without following symbolic links. Defaults to `stat`.
+    fn lstat(&mut self, path: String) -> BoxFuture<'_, Result<FileAttributes, StatusCode>> {
+        self.stat(path)
+    }
+
+    fn fstat(&mut self, _handle: Bytes) -> BoxFuture<'_, Result<FileAttributes, StatusCode>> {
+        unsupported()
+    }
+
+    fn setstat(
+        &mut self,
+        _path: String,
+        _attrs: FileAttributes,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    fn fsetstat(
+        &mut self,
+        _handle: Bytes,
+        _attrs: FileAttributes,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    fn opendir(&mut self, _path: String) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        unsupported()
+    }
+
+    /// Next entries of the directory. Empty is end of listing.
+    fn readdir(&mut self, _handle: Bytes) -> BoxFuture<'_, Result<Vec<Name>, StatusCode>> {
+        unsupported()
+    }
+
+    fn remove(&mut self, _filename: String) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    fn mkdir(
+        &mut self,
+        _path: String,
+        _attrs: FileAttributes,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    fn rmdir(&mut self, _path: String) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    /// Canonical absolute path. Defaults to [`normalize`].
+    fn realpath(&mut self, path: String) -> BoxFuture<'_, Result<String, StatusCode>> {
+        futures::future::ok(normalize(&path)).boxed()
+    }
+
+    fn rename(
+        &mut self,
+        _oldpath: String,
+        _newpath: String,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+
+    fn readlink(&mut self, _path: String) -> BoxFuture<'_, Result<String, StatusCode>> {
+        unsupported()
+    }
+
+    /// Arguments in wire order. OpenSSH clients send the target first.
+    fn symlink(
+        &mut self,
+        _first: String,
+        _second: String,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        unsupported()
+    }
+}
+
+fn unsupported<'a, T: Send + 'a>() -> BoxFuture<'a, Result<T, StatusCode>> {
+    futures::future::err(StatusCode::OpUnsupported).boxed()
+}
+
+/// Lexically normalized absolute path. `..` stops at the root.
+pub fn normalize(path: &str) -> String {
+    let mut parts = vec![];
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Serve SFTP until the client closes its side.
+pub async fn serve<H, R, W>(mut handler: H, mut reader: R, mut writer: W) -> Result<(), SshError>
+where
+    H: SftpHandler,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut init = match read_packet(&mut reader).await? {
+        Some(init) => init,
+        None => return Ok(()),
+    };
+    if u8::unpack(&mut init)? != packet::SSH_FXP_INIT {
+        return Err(SshError::Protocol("sftp init expected".into()));
+    }
+    crate::trace::debug!("sftp client version {}", u32::unpack(&mut init)?);
+    writer.write_all(&packet::version(SFTP_VERSION)).await?;
+    writer.flush().await?;
+
+    while let Some(mut payload) = read_packet(&mut reader).await? {
+        let typ = u8::unpack(&mut payload)?;
+        let id = u32::unpack(&mut payload)?;
+        let response = match Request::unpack(typ, &mut payload) {
+            Ok(request) => dispatch(&mut handler, request).await,
+            Err(e) => {
+                crate::trace::debug!("malformed sftp request {}: {}", typ, e);
+                Response::Status(StatusCode::BadMessage)
+            }
+        };
+        writer.write_all(&response.pack(id)).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Next packet payload, `None` once the client closed between packets.
+async fn read_packet<R>(reader: &mut R) -> Result<Option<Bytes>, SshError>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAXIMUM_PACKET_SIZE {
+        return Err(SshError::TooLargePacket(len));
+    }
+    let mut buf = BytesMut::new();
+    buf.resize(len, 0);
+    reader.read_exact(&mut buf).await?;
+    Ok(Some(buf.freeze()))
+}
+
+async fn dispatch<H>(handler: &mut H, request: Request) -> Response
+where
+    H: SftpHandler,
+{
+    let ok = |()| Response::Status(StatusCode::Ok);
+    let name =
+        |path: String| Response::Name(vec![Name::new(path.clone(), path, Default::default())]);
+
+    let result = match request {
+        Request::Open(filename, flags, attrs) => handler
+            .open(filename, flags, attrs)
+            .await
+            .map(Response::Handle),
+        Request::Close(handle) => handler.close(handle).await.map(ok),
+        Request::Read(handle, offset, len) => {
+            let len = len.min(MAXIMUM_READ_LENGTH);
+            match handler.read(handle, offset, len).await {
+                Ok(data) if data.is_empty() => Err(StatusCode::Eof),
+                Ok(mut data) => {
+                    data.truncate(len as usize);
+                    Ok(Response::Data(data))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Request::Write(handle, offset, data) => handler.write(handle, offset, data).await.map(ok),
+        Request::Lstat(path) => handler.lstat(path).await.map(Response::Attrs),
+        Request::Fstat(handle) => handler.fstat(handle).await.map(Response::Attrs),
+        Request::Setstat(path, attrs) => handler.setstat(path, attrs).await.map(ok),
+        Request::Fsetstat(handle, attrs) => handler.fsetstat(handle, attrs).await.map(ok),
+        Request::Opendir(path) => handler.opendir(path).await.map(Response::Handle),
+        Request::Readdir(handle) => match handler.readdir(handle).await {
+            Ok(names) if names.is_empty() => Err(StatusCode::Eof),
+            Ok(names) => Ok(Response::Name(names)),
+            Err(e) => Err(e),
+        },
+        Request::Remove(filename) => handler.remove(filename).await.map(ok),
+        Request::Mkdir(path, attrs) => handler.mkdir(path, attrs).await.map(ok),
+        Request::Rmdir(path) => handler.rmdir(path).await.map(ok),
+        Request::Realpath(path) => handler.realpath(path).await.map(name),
+        Request::Stat(path) => handler.stat(path).await.map(Response::Attrs),
+        Request::Rename(oldpath, newpath) => handler.rename(oldpath, newpath).await.map(ok),
+        Request::Readlink(path) => handler.readlink(path).await.map(name),
+        Request::Symlink(first, second) => handler.symlink(first, second).await.map(ok),
+        Request::Unknown(typ) => {
+            crate::trace::debug!("unsupported sftp request {}", typ);
+            Err(StatusCode::OpUnsupported)
+        }
+    };
+    result.unwrap_or_else(Response::Status)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf as _, BufMut as _};
+
+    use super::*;
+
+    struct Hello;
+
+    impl SftpHandler for Hello {
+        fn open(
+            &mut self,
+            filename: String,
+            _: OpenFlags,
+            _: FileAttributes,
+        ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+            let result = match filename.as_str() {
+                "/hello" => Ok(Bytes::from("h")),
+                _ => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+            };
+            futures::future::ready(result).boxed()
+        }
+
+        fn read(
+            &mut self,
+            _: Bytes,
+            offset: u64,
+            _: u32,
+        ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+            // Ignores the requested length.
+            let data = Bytes::from_static(b"hello, world").slice((offset as usize).min(12)..);
+            futures::future::ok(data).boxed()
+        }
+    }
+
+    fn request(typ: u8, id: Option<u32>, body: &[u8]) -> Vec<u8> {
+        let mut payload = vec![typ];
+        if let Some(id) = id {
+            payload.put_u32(id);
+        }
+        payload.extend_from_slice(body);
+        let mut buf = (payload.len() as u32).to_be_bytes().to_vec();
+        buf.extend(payload);
+        buf
+    }
+
+    fn string(s: &[u8]) -> Vec<u8> {
+        let mut buf = (s.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(s);
+        buf
+    }
+
+    fn read(handle: &[u8], offset: u64, len: u32) -> Vec<u8> {
+        let mut body = string(handle);
+        body.put_u64(offset);
+        body.put_u32(len);
+        body
+    }
+
+    /// Send `requests`, close and collect (type, id, rest) of each response.
+    async fn exchange(requests: Vec<Vec<u8>>) -> Vec<(u8, u32, Bytes)> {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let (server_read, server_write) = tokio::io::split(server);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        for request in requests {
+            client_write.write_all(&request).await.unwrap();
+        }
+        client_write.shutdown().await.unwrap();
+        drop(client_write);
+
+        serve(Hello, server_read, server_write).await.unwrap();
+
+        let mut responses = vec![];
+        while let Some(mut payload) = read_packet(&mut client_read).await.unwrap() {
+            let typ = payload.get_u8();
+            let id = payload.get_u32();
+            responses.push((typ, id, payload));
+        }
+        responses
+    }
+
+    fn status(code: u32) -> Bytes {
+        let mut buf = code.to_be_bytes().to_vec();
+        let message = match code {
+            1 => "End of file",
+            2 => "No such file",
+            5 => "Bad message",
+            8 => "Operation unsupported",
+            _ => unreachable!(),
+        };
+        buf.extend(string(message.as_bytes()));
+        buf.extend(string(b""));
+        buf.into()
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let mut open = string(b"/hello");
+        open.put_u32(1);
+        open.put_u32(0);
+        let responses = exchange(vec![
+            request(1, None, &3u32.to_be_bytes()),
+            request(3, Some(1), &open),
+            request(5, Some(2), &read(b"h", 7, 3)),
+            request(5, Some(3), &read(b"h", 12, 3)),
+            request(3, Some(4), &string(b"/nothing")),
+            request(99, Some(5), b""),
+        ])
+        .await;
+
+        // Version reply has no id, its version takes the place.
+        assert_eq!(responses[0], (2, 3, Bytes::new()));
+        assert_eq!(responses[1], (102, 1, Bytes::from(string(b"h"))));
+        // Truncated to the requested length.
+        assert_eq!(responses[2], (103, 2, Bytes::from(string(b"wor"))));
+        assert_eq!(responses[3], (101, 3, status(1)));
+        // Missing pflags and attrs.
+        assert_eq!(responses[4], (101, 4, status(5)));
+        assert_eq!(responses[5], (101, 5, status(8)));
+    }
+
+    #[tokio::test]
+    async fn test_serve_not_found() {
+        let mut open = string(b"/nothing");
+        open.put_u32(1);
+        open.put_u32(0);
+        let responses = exchange(vec![
+            request(1, None, &3u32.to_be_bytes()),
+            request(3, Some(9), &open),
+        ])
+        .await;
+        assert_eq!(responses[1], (101, 9, status(2)));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("."), "/");
+        assert_eq!(normalize("a/./b//c/"), "/a/b/c");
+        assert_eq!(normalize("/a/../../b"), "/b");
+    }
+}
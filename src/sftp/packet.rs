@@ -0,0 +1,259 @@
+//! SFTP v3 packets (draft-ietf-secsh-filexfer-02)
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::pack::{Pack, Put, Unpack, UnpackError};
+
+use super::{FileAttributes, Name, OpenFlags, StatusCode};
+
+pub(super) const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_READLINK: u8 = 19;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x0000_0001;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x0000_0002;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x0000_0004;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x0000_0008;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+impl Pack for FileAttributes {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        if self.uid_gid.is_some() {
+            flags |= SSH_FILEXFER_ATTR_UIDGID;
+        }
+        if self.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        if self.atime_mtime.is_some() {
+            flags |= SSH_FILEXFER_ATTR_ACMODTIME;
+        }
+        flags.pack(buf);
+
+        if let Some(size) = self.size {
+            size.pack(buf);
+        }
+        if let Some((uid, gid)) = self.uid_gid {
+            uid.pack(buf);
+            gid.pack(buf);
+        }
+        if let Some(permissions) = self.permissions {
+            permissions.pack(buf);
+        }
+        if let Some((atime, mtime)) = self.atime_mtime {
+            atime.pack(buf);
+            mtime.pack(buf);
+        }
+    }
+}
+
+impl Unpack for FileAttributes {
+    fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
+        let flags = u32::unpack(buf)?;
+        let mut attrs = Self::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(u64::unpack(buf)?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((u32::unpack(buf)?, u32::unpack(buf)?));
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(u32::unpack(buf)?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            attrs.atime_mtime = Some((u32::unpack(buf)?, u32::unpack(buf)?));
+        }
+        // Extensions are skipped.
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..u32::unpack(buf)? {
+                Bytes::unpack(buf)?;
+                Bytes::unpack(buf)?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// Client request, without its request id.
+#[derive(Debug)]
+pub(super) enum Request {
+    Open(String, OpenFlags, FileAttributes),
+    Close(Bytes),
+    Read(Bytes, u64, u32),
+    Write(Bytes, u64, Bytes),
+    Lstat(String),
+    Fstat(Bytes),
+    Setstat(String, FileAttributes),
+    Fsetstat(Bytes, FileAttributes),
+    Opendir(String),
+    Readdir(Bytes),
+    Remove(String),
+    Mkdir(String, FileAttributes),
+    Rmdir(String),
+    Realpath(String),
+    Stat(String),
+    Rename(String, String),
+    Readlink(String),
+    Symlink(String, String),
+    Unknown(u8),
+}
+
+impl Request {
+    pub(super) fn unpack<B: Buf>(typ: u8, buf: &mut B) -> Result<Self, UnpackError> {
+        Ok(match typ {
+            SSH_FXP_OPEN => Self::Open(
+                Unpack::unpack(buf)?,
+                OpenFlags(Unpack::unpack(buf)?),
+                Unpack::unpack(buf)?,
+            ),
+            SSH_FXP_CLOSE => Self::Close(Unpack::unpack(buf)?),
+            SSH_FXP_READ => Self::Read(
+                Unpack::unpack(buf)?,
+                Unpack::unpack(buf)?,
+                Unpack::unpack(buf)?,
+            ),
+            SSH_FXP_WRITE => Self::Write(
+                Unpack::unpack(buf)?,
+                Unpack::unpack(buf)?,
+                Unpack::unpack(buf)?,
+            ),
+            SSH_FXP_LSTAT => Self::Lstat(Unpack::unpack(buf)?),
+            SSH_FXP_FSTAT => Self::Fstat(Unpack::unpack(buf)?),
+            SSH_FXP_SETSTAT => Self::Setstat(Unpack::unpack(buf)?, Unpack::unpack(buf)?),
+            SSH_FXP_FSETSTAT => Self::Fsetstat(Unpack::unpack(buf)?, Unpack::unpack(buf)?),
+            SSH_FXP_OPENDIR => Self::Opendir(Unpack::unpack(buf)?),
+            SSH_FXP_READDIR => Self::Readdir(Unpack::unpack(buf)?),
+            SSH_FXP_REMOVE => Self::Remove(Unpack::unpack(buf)?),
+            SSH_FXP_MKDIR => Self::Mkdir(Unpack::unpack(buf)?, Unpack::unpack(buf)?),
+            SSH_FXP_RMDIR => Self::Rmdir(Unpack::unpack(buf)?),
+            SSH_FXP_REALPATH => Self::Realpath(Unpack::unpack(buf)?),
+            SSH_FXP_STAT => Self::Stat(Unpack::unpack(buf)?),
+            SSH_FXP_RENAME => Self::Rename(Unpack::unpack(buf)?, Unpack::unpack(buf)?),
+            SSH_FXP_READLINK => Self::Readlink(Unpack::unpack(buf)?),
+            SSH_FXP_SYMLINK => Self::Symlink(Unpack::unpack(buf)?, Unpack::unpack(buf)?),
+            typ => Self::Unknown(typ),
+        })
+    }
+}
+
+/// Server response, sent with the id of its request.
+#[derive(Debug)]
+pub(super) enum Response {
+    Status(StatusCode),
+    Handle(Bytes),
+    Data(Bytes),
+    Name(Vec<Name>),
+    Attrs(FileAttributes),
+}
+
+impl Response {
+    pub(super) fn pack(&self, id: u32) -> Bytes {
+        let mut buf = BytesMut::new();
+        match self {
+            Self::Status(code) => {
+                SSH_FXP_STATUS.pack(&mut buf);
+                id.pack(&mut buf);
+                code.code().pack(&mut buf);
+                code.message().pack(&mut buf);
+                "".pack(&mut buf);
+            }
+            Self::Handle(handle) => {
+                SSH_FXP_HANDLE.pack(&mut buf);
+                id.pack(&mut buf);
+                handle.pack(&mut buf);
+            }
+            Self::Data(data) => {
+                SSH_FXP_DATA.pack(&mut buf);
+                id.pack(&mut buf);
+                data.pack(&mut buf);
+            }
+            Self::Name(names) => {
+                SSH_FXP_NAME.pack(&mut buf);
+                id.pack(&mut buf);
+                (names.len() as u32).pack(&mut buf);
+                for name in names {
+                    name.filename.pack(&mut buf);
+                    name.longname.pack(&mut buf);
+                    name.attrs.pack(&mut buf);
+                }
+            }
+            Self::Attrs(attrs) => {
+                SSH_FXP_ATTRS.pack(&mut buf);
+                id.pack(&mut buf);
+                attrs.pack(&mut buf);
+            }
+        }
+        frame(buf)
+    }
+}
+
+/// `SSH_FXP_VERSION` without extensions.
+pub(super) fn version(version: u32) -> Bytes {
+    let mut buf = BytesMut::new();
+    SSH_FXP_VERSION.pack(&mut buf);
+    version.pack(&mut buf);
+    frame(buf)
+}
+
+fn frame(payload: BytesMut) -> Bytes {
+    let mut buf = BytesMut::with_capacity(payload.len() + 4);
+    (payload.len() as u32).pack(&mut buf);
+    buf.put(&payload);
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attrs() {
+        let attrs = FileAttributes {
+            size: Some(3),
+            permissions: Some(0o100644),
+            atime_mtime: Some((1, 2)),
+            ..Default::default()
+        };
+        let mut b = BytesMut::new();
+        attrs.pack(&mut b);
+        assert_eq!(b.len(), 4 + 8 + 4 + 8);
+        assert_eq!(FileAttributes::unpack(&mut b.freeze()).unwrap(), attrs);
+
+        // uid/gid and one extension pair.
+        let mut b = BytesMut::new();
+        (SSH_FILEXFER_ATTR_UIDGID | SSH_FILEXFER_ATTR_EXTENDED).pack(&mut b);
+        1000u32.pack(&mut b);
+        100u32.pack(&mut b);
+        1u32.pack(&mut b);
+        "name@example.com".pack(&mut b);
+        "value".pack(&mut b);
+        let mut b = b.freeze();
+        let attrs = FileAttributes::unpack(&mut b).unwrap();
+        assert_eq!(attrs.uid_gid, Some((1000, 100)));
+        assert!(!b.has_remaining());
+    }
+}
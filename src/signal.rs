@@ -0,0 +1,94 @@
+//! Signal names used by `signal` and `exit-signal` channel requests.
+//!
+//! [rfc4254](https://tools.ietf.org/html/rfc4254#section-6.10)
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+/// Signal name without `SIG` prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Signal {
+    Abrt,
+    Alrm,
+    Fpe,
+    Hup,
+    Ill,
+    Int,
+    Kill,
+    Pipe,
+    Quit,
+    Segv,
+    Term,
+    Usr1,
+    Usr2,
+    /// Local extension. (e.g. `XCPU@example.com`)
+    Other(String),
+}
+
+impl AsRef<str> for Signal {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Abrt => "ABRT",
+            Self::Alrm => "ALRM",
+            Self::Fpe => "FPE",
+            Self::Hup => "HUP",
+            Self::Ill => "ILL",
+            Self::Int => "INT",
+            Self::Kill => "KILL",
+            Self::Pipe => "PIPE",
+            Self::Quit => "QUIT",
+            Self::Segv => "SEGV",
+            Self::Term => "TERM",
+            Self::Usr1 => "USR1",
+            Self::Usr2 => "USR2",
+            Self::Other(name) => name,
+        }
+    }
+}
+
+impl FromStr for Signal {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "ABRT" => Self::Abrt,
+            "ALRM" => Self::Alrm,
+            "FPE" => Self::Fpe,
+            "HUP" => Self::Hup,
+            "ILL" => Self::Ill,
+            "INT" => Self::Int,
+            "KILL" => Self::Kill,
+            "PIPE" => Self::Pipe,
+            "QUIT" => Self::Quit,
+            "SEGV" => Self::Segv,
+            "TERM" => Self::Term,
+            "USR1" => Self::Usr1,
+            "USR2" => Self::Usr2,
+            x => Self::Other(x.into()),
+        })
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for name in &["TERM", "KILL", "USR2", "XCPU@example.com"] {
+            let signal = name.parse::<Signal>().unwrap();
+            assert_eq!(signal.as_ref(), *name);
+        }
+        assert_eq!("SEGV".parse::<Signal>().unwrap(), Signal::Segv);
+        assert_eq!(
+            "WINCH".parse::<Signal>().unwrap(),
+            Signal::Other("WINCH".into())
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Standalone SSH signature verification.
+//!
+//! Same implementation as the one used for publickey / hostbased user authentication.
+//!
+//! # Example
+//!
+//! ```
+//! use ssssh::ssh_signature;
+//!
+//! # let (publickey, signature) = (&[][..], &[][..]);
+//! let result = ssh_signature::verify(publickey, Some(b"session id"), b"data", signature);
+//! assert!(result.is_err());
+//! ```
+use bytes::{Buf as _, Bytes, BytesMut};
+use thiserror::Error;
+
+use crate::key::{PublicKey, Signature};
+use crate::pack::{Pack, Put as _, Unpack};
+
+/// Signature verification error.
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("malformed blob")]
+    Malformed,
+
+    #[error("unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("bad signature")]
+    BadSignature,
+}
+
+fn unpack_blob<T: Unpack>(blob: &[u8]) -> Result<T, VerifyError> {
+    let mut buf = BytesMut::new();
+    Bytes::copy_from_slice(blob).pack(&mut buf);
+    let mut buf = buf.freeze();
+    let item = T::unpack(&mut buf).map_err(|_| VerifyError::Malformed)?;
+    if buf.has_remaining() {
+        return Err(VerifyError::Malformed);
+    }
+    Ok(item)
+}
+
+/// Parse signature blob (`string algorithm`, `string signature`).
+///
+/// Returns signature algorithm name and raw signature bytes.
+pub fn parse_signature_blob(blob: &[u8]) -> Result<(String, Bytes), VerifyError> {
+    let signature = unpack_blob::<Signature>(blob)?;
+    Ok((signature.algorithm().into(), signature.signature().clone()))
+}
+
+/// Verify signature blob over `signed_data` by public key blob.
+///
+/// If `session_context` is given (e.g. session identifier for user authentication),
+/// it is prepended to `signed_data` as SSH string.
+///
+/// Supports `ssh-ed25519`, `ssh-rsa`, `rsa-sha2-256` and `rsa-sha2-512` signatures.
+pub fn verify(
+    public_key_blob: &[u8],
+    session_context: Option<&[u8]>,
+    signed_data: &[u8],
+    signature_blob: &[u8],
+) -> Result<(), VerifyError> {
+    let publickey = unpack_blob::<PublicKey>(public_key_blob)?;
+    let signature = unpack_blob::<Signature>(signature_blob)?;
+
+    let algorithm = publickey.algorithm().to_string();
+    let mut verifier = publickey
+        .verifier()
+        .map_err(|_| VerifyError::UnsupportedAlgorithm(algorithm))?;
+    if let Some(context) = session_context {
+        Bytes::copy_from_slice(context).pack(&mut verifier);
+    }
+    verifier.put(signed_data);
+
+    if verifier.verify(&signature) {
+        Ok(())
+    } else {
+        Err(VerifyError::BadSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack_blob<T: Pack>(item: &T) -> Bytes {
+        let mut buf = BytesMut::new();
+        item.pack(&mut buf);
+        let mut buf = buf.freeze();
+        Bytes::unpack(&mut buf).unwrap()
+    }
+
+    #[test]
+    fn test_ed25519() {
+        use crate::key::{Algorithm, Key};
+
+        let key = Key::gen(&Algorithm::SshEd25519).unwrap();
+        let publickey = pack_blob(&key.publickey());
+
+        let mut data = BytesMut::new();
+        Bytes::from_static(b"session").pack(&mut data);
+        data.extend_from_slice(b"payload");
+        let signature = pack_blob(&key.sign(&Algorithm::SshEd25519, &data.freeze()));
+
+        verify(&publickey, Some(b"session"), b"payload", &signature).unwrap();
+        verify(&publickey, Some(b"session"), b"tampered", &signature).unwrap_err();
+        verify(&publickey, None, b"payload", &signature).unwrap_err();
+
+        let (algorithm, _) = parse_signature_blob(&signature).unwrap();
+        assert_eq!(algorithm, "ssh-ed25519");
+    }
+
+    #[test]
+    fn test_rsa_sha2() {
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::sign::Signer;
+
+        let pair = Rsa::generate(2048).unwrap();
+        let mut blob = BytesMut::new();
+        "ssh-rsa".pack(&mut blob);
+        crate::pack::Mpint::new(pair.e().to_vec()).pack(&mut blob);
+        crate::pack::Mpint::new(pair.n().to_vec()).pack(&mut blob);
+        let publickey = blob.freeze();
+
+        let pkey = PKey::from_rsa(pair).unwrap();
+        for (name, digest) in [
+            ("rsa-sha2-256", MessageDigest::sha256()),
+            ("rsa-sha2-512", MessageDigest::sha512()),
+        ] {
+            let mut signer = Signer::new(digest, &pkey).unwrap();
+            signer.update(b"payload").unwrap();
+            let sig = signer.sign_to_vec().unwrap();
+            let signature = pack_blob(&Signature::new(name.into(), sig.clone().into()));
+
+            verify(&publickey, None, b"payload", &signature).unwrap();
+            verify(&publickey, None, b"tampered", &signature).unwrap_err();
+
+            // algorithm name must match digest
+            let other = if name == "rsa-sha2-256" {
+                "rsa-sha2-512"
+            } else {
+                "rsa-sha2-256"
+            };
+            let signature = pack_blob(&Signature::new(other.into(), sig.into()));
+            verify(&publickey, None, b"payload", &signature).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        parse_signature_blob(b"\x00\x00").unwrap_err();
+        assert!(matches!(
+            verify(b"", None, b"", b""),
+            Err(VerifyError::Malformed)
+        ));
+    }
+}
@@ -2,6 +2,7 @@ use std::num::Wrapping;
 
 use bytes::{Bytes, BytesMut};
 use getset::{Getters, MutGetters};
+use tokio::time::Instant;
 
 use crate::cipher::Cipher;
 use crate::comp::Compression;
@@ -11,10 +12,34 @@ use crate::negotiate::Algorithm;
 use crate::pack::{Mpint, Pack, Put};
 use crate::SshError;
 
+/// Keys of one direction, derived by key exchange.
+#[derive(Debug)]
+pub(crate) struct Keys {
+    cipher: Cipher,
+    mac: Mac,
+    comp: Compression,
+}
+
+impl Keys {
+    pub(crate) fn new(cipher: Cipher, mac: Mac, comp: Compression) -> Self {
+        Self { cipher, mac, comp }
+    }
+}
+
 #[derive(Debug, Getters, MutGetters)]
 pub(crate) struct OneWayState {
     seq: Wrapping<u32>,
 
+    /// Sequence number of the last packet.
+    last_seq: u32,
+
+    /// Applied right after NEWKEYS of this direction.
+    pending: Option<Keys>,
+
+    /// Packet bytes under the current keys.
+    #[get = "pub(crate)"]
+    transferred: u64,
+
     #[get = "pub(crate)"]
     #[get_mut = "pub(crate)"]
     cipher: Cipher,
@@ -23,28 +48,75 @@ pub(crate) struct OneWayState {
     mac: Mac,
 
     #[get = "pub(crate)"]
+    #[get_mut = "pub(crate)"]
     comp: Compression,
+
+    /// Delayed compression is active for current and later keys.
+    authenticated: bool,
+
+    /// Strict KEX: sequence number restarts at each NEWKEYS.
+    strict: bool,
 }
 
 impl OneWayState {
     fn new() -> Self {
         Self {
             seq: Wrapping(0),
+            last_seq: 0,
+            pending: None,
+            transferred: 0,
             cipher: Cipher::new_none(),
             mac: Mac::new_none(),
             comp: Compression::new_none(),
+            authenticated: false,
+            strict: false,
         }
     }
 
     pub(crate) fn get_and_inc_seq(&mut self) -> u32 {
         let r = self.seq;
         self.seq += Wrapping(1);
+        self.last_seq = r.0;
         r.0
     }
 
     pub(crate) fn seq(&self) -> u32 {
         self.seq.0
     }
+
+    /// Sequence number of the last packet, unaffected by strict KEX resets.
+    pub(crate) fn last_seq(&self) -> u32 {
+        self.last_seq
+    }
+
+    /// Packet of `len` bytes passed.
+    pub(crate) fn count(&mut self, len: usize) {
+        self.transferred += len as u64;
+    }
+
+    /// Keys to use after the next NEWKEYS.
+    pub(crate) fn stage(&mut self, keys: Keys) {
+        self.pending = Some(keys);
+    }
+
+    /// NEWKEYS passed under current keys. Fails unless keys are staged.
+    pub(crate) fn switch_keys(&mut self) -> Result<(), SshError> {
+        let Keys { cipher, mac, comp } = self
+            .pending
+            .take()
+            .ok_or_else(|| SshError::Protocol("newkeys before key exchange".into()))?;
+        self.cipher = cipher;
+        self.mac = mac;
+        self.comp = comp;
+        if self.authenticated {
+            self.comp.activate();
+        }
+        self.transferred = 0;
+        if self.strict {
+            self.seq = Wrapping(0);
+        }
+        Ok(())
+    }
 }
 
 fn compute_hash(
@@ -80,6 +152,10 @@ fn compute_hash(
 pub(crate) struct State {
     session_id: Option<Bytes>,
 
+    /// Last key exchange, `None` before the first one.
+    #[get = "pub(crate)"]
+    keyed_at: Option<Instant>,
+
     #[get = "pub(crate)"]
     #[get_mut = "pub(crate)"]
     ctos: OneWayState,
@@ -93,6 +169,7 @@ impl State {
     pub(crate) fn new() -> Self {
         Self {
             session_id: None,
+            keyed_at: None,
             ctos: OneWayState::new(),
             stoc: OneWayState::new(),
         }
@@ -102,44 +179,73 @@ impl State {
         self.session_id.as_ref().unwrap()
     }
 
-    pub(crate) fn change_key(
+    /// Stage keys of both directions.
+    ///
+    /// Each direction switches right after its NEWKEYS, see [`OneWayState::switch_keys`].
+    pub(crate) fn stage_keys(
         &mut self,
         hash: &Bytes,
         secret: &Bytes,
         kex: &Kex,
         algorithm: &Algorithm,
     ) -> Result<(), SshError> {
-        let session_id = self.session_id.as_ref().unwrap_or(&hash);
+        let session_id = self.session_id.as_ref().unwrap_or(hash);
 
-        let iv_ctos_len = Cipher::block_size_by_name(algorithm.cipher_algorithm_c2s());
+        let iv_ctos_len = Cipher::iv_length_by_name(algorithm.cipher_algorithm_c2s());
         let iv_ctos = compute_hash(hash, secret, b'A', session_id, kex, iv_ctos_len);
-        let iv_stoc_len = Cipher::block_size_by_name(algorithm.cipher_algorithm_c2s());
+        let iv_stoc_len = Cipher::iv_length_by_name(algorithm.cipher_algorithm_s2c());
         let iv_stoc = compute_hash(hash, secret, b'B', session_id, kex, iv_stoc_len);
 
         let key_ctos_len = Cipher::key_length_by_name(algorithm.cipher_algorithm_c2s());
         let key_ctos = compute_hash(hash, secret, b'C', session_id, kex, key_ctos_len);
-        let key_stoc_len = Cipher::key_length_by_name(algorithm.cipher_algorithm_c2s());
+        let key_stoc_len = Cipher::key_length_by_name(algorithm.cipher_algorithm_s2c());
         let key_stoc = compute_hash(hash, secret, b'D', session_id, kex, key_stoc_len);
 
         let intk_ctos_len = Mac::len_by_name(algorithm.mac_algorithm_c2s());
         let intk_ctos = compute_hash(hash, secret, b'E', session_id, kex, intk_ctos_len);
-        let intk_stoc_len = Mac::len_by_name(algorithm.mac_algorithm_c2s());
+        let intk_stoc_len = Mac::len_by_name(algorithm.mac_algorithm_s2c());
         let intk_stoc = compute_hash(hash, secret, b'F', session_id, kex, intk_stoc_len);
 
-        self.ctos.cipher =
-            Cipher::new_for_decrypt(algorithm.cipher_algorithm_c2s(), &key_ctos, &iv_ctos)?;
-        self.stoc.cipher =
-            Cipher::new_for_encrypt(algorithm.cipher_algorithm_s2c(), &key_stoc, &iv_stoc)?;
-
-        self.ctos.mac = Mac::new(algorithm.mac_algorithm_c2s(), &intk_ctos);
-        self.stoc.mac = Mac::new(algorithm.mac_algorithm_s2c(), &intk_stoc);
-
-        self.ctos.comp = Compression::new(algorithm.compression_algorithm_c2s());
-        self.stoc.comp = Compression::new(algorithm.compression_algorithm_s2c());
+        self.ctos.stage(Keys::new(
+            Cipher::new_for_decrypt(algorithm.cipher_algorithm_c2s(), &key_ctos, &iv_ctos)?,
+            Mac::new(algorithm.mac_algorithm_c2s(), &intk_ctos),
+            Compression::new(algorithm.compression_algorithm_c2s()),
+        ));
+        self.stoc.stage(Keys::new(
+            Cipher::new_for_encrypt(algorithm.cipher_algorithm_s2c(), &key_stoc, &iv_stoc)?,
+            Mac::new(algorithm.mac_algorithm_s2c(), &intk_stoc),
+            Compression::new(algorithm.compression_algorithm_s2c()),
+        ));
 
         self.session_id = Some(session_id.clone());
+        self.keyed_at = Some(Instant::now());
         Ok(())
     }
+
+    /// Start `zlib@openssh.com` in both directions. Called after USERAUTH_SUCCESS.
+    pub(crate) fn enable_delayed_compression(&mut self) {
+        for state in [&mut self.ctos, &mut self.stoc] {
+            state.authenticated = true;
+            state.comp.activate();
+        }
+    }
+
+    /// Reset sequence numbers on every NEWKEYS from now on, in both directions.
+    pub(crate) fn enable_strict_kex(&mut self) {
+        self.ctos.strict = true;
+        self.stoc.strict = true;
+    }
+
+    /// Packet bytes of the busier direction under the current keys.
+    pub(crate) fn transferred(&self) -> u64 {
+        self.ctos.transferred.max(self.stoc.transferred)
+    }
+
+    /// Use the staged keys as client. Ciphers must be symmetric.
+    #[cfg(test)]
+    pub(crate) fn swap_directions(&mut self) {
+        std::mem::swap(&mut self.ctos.pending, &mut self.stoc.pending);
+    }
 }
 
 #[cfg(test)]
@@ -152,4 +258,60 @@ mod tests {
 
         assert::<State>();
     }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Known answers from OpenSSL's SSHKDF, e.g. `openssl kdf -keylen 32
+    /// -kdfopt digest:SHA1 -kdfopt hexkey:000000050080010203 -kdfopt
+    /// hexxcghash:<hash> -kdfopt hexsession_id:<session id> -kdfopt type:C SSHKDF`.
+    #[test]
+    fn test_compute_hash_extends() {
+        use crate::kex::Algorithm;
+
+        // High bit set, so the mpint gets a leading zero.
+        let secret = Bytes::from_static(&[0x80, 1, 2, 3]);
+        let hash = Bytes::from_static(b"exchange hash");
+        let session_id = Bytes::from_static(b"session id");
+
+        let cases = [
+            // aes256-ctr key from a 20 byte hash.
+            (
+                "diffie-hellman-group14-sha1",
+                b'C',
+                32,
+                "ca80c7885fab038fa6893ec0e937129535a858852e895e20298148f801e18255",
+            ),
+            // hmac-sha2-512 key from a 32 byte hash, extended once.
+            (
+                "curve25519-sha256",
+                b'E',
+                64,
+                "dc1b0fdd9948075db3d1e0231fabd18a8b903c572ece6966f4a8aae8d44bc398\
+                 b41fe98453c33453338822db6c0199dceccd2ec0284280a51f9d67655f06b6ac",
+            ),
+            // Extended twice over, the last block truncated.
+            (
+                "curve25519-sha256",
+                b'F',
+                80,
+                "da33fb06e020d2c0a49cb0df096ce3f82b2c8e78867dea48c25582f9069d8d89\
+                 25e4d38b43117b997eb924427b7e4b7d0ea7f2de48edbd8cd519ea3d56b43c85\
+                 f22fd83102527ef11e46928bdd364b2f",
+            ),
+            // Shorter than the hash output, truncated.
+            (
+                "diffie-hellman-group16-sha512",
+                b'A',
+                16,
+                "5f549b60fca0eb37f310fdb2941b2f7d",
+            ),
+        ];
+        for (name, kind, len, expect) in cases.iter() {
+            let kex = Kex::new(&name.parse::<Algorithm>().unwrap());
+            let key = compute_hash(&hash, &secret, *kind, &session_id, &kex, *len);
+            assert_eq!(hex(&key), *expect, "{} {}", name, len);
+        }
+    }
 }
@@ -0,0 +1,46 @@
+//! Options for reducing the fingerprintable surface before key exchange.
+use std::time::Duration;
+
+/// Scanner facing options, each off by default.
+///
+/// Set with [`ServerBuilder::stealth`](crate::ServerBuilder::stealth).
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ssssh::Stealth;
+///
+/// let stealth = Stealth::default()
+///     .minimal_banner(true)
+///     .randomize_kexinit_order(true)
+///     .await_client_banner_first(Duration::from_secs(1));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Stealth {
+    pub(crate) minimal_banner: bool,
+    pub(crate) randomize_kexinit_order: bool,
+    pub(crate) await_client_banner_first: Option<Duration>,
+}
+
+impl Stealth {
+    /// Send `SSH-2.0-unknown` instead of the software version.
+    pub fn minimal_banner(mut self, enable: bool) -> Self {
+        self.minimal_banner = enable;
+        self
+    }
+
+    /// Shuffle kexinit algorithm name-lists per connection.
+    ///
+    /// Only the order changes, so the negotiated algorithms stay the same.
+    pub fn randomize_kexinit_order(mut self, enable: bool) -> Self {
+        self.randomize_kexinit_order = enable;
+        self
+    }
+
+    /// Hold back the version banner until the client sends theirs, or `cap` expires.
+    pub fn await_client_banner_first(mut self, cap: Duration) -> Self {
+        self.await_client_banner_first = Some(cap);
+        self
+    }
+}
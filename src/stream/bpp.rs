@@ -10,16 +10,32 @@ use futures::ready;
 use futures::sink::Sink;
 use futures::stream::Stream;
 use ring::rand::{SecureRandom, SystemRandom};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
 
+use crate::connection::memory::{Charge, Memory};
+use crate::observer::Observer;
 use crate::state::{OneWayState, State};
 use crate::SshError;
 
 pub(crate) const MAXIMUM_PACKET_SIZE: usize = 35000;
 
-fn pad_len(len: usize, bs: usize) -> usize {
-    const MINIMUM_PAD_SIZE: usize = 4;
+/// Padding length byte, at least 4 bytes of padding and 7 more to fill a block.
+const MINIMUM_PACKET_LENGTH: usize = 12;
+
+const MINIMUM_PAD_SIZE: usize = 4;
+
+/// Receive buffer, refilled once less than a maximum packet is left.
+///
+/// Payloads are slices of it, so it is only reused once they are all dropped.
+const RX_BUFFER_SIZE: usize = MAXIMUM_PACKET_SIZE * 4;
+
+/// SSH_MSG_NEWKEYS. Keys of its direction switch right after it.
+const NEWKEYS: u8 = 21;
 
+/// Delayed compression starts right after this message.
+const USERAUTH_SUCCESS: u8 = 52;
+
+fn pad_len(len: usize, bs: usize) -> usize {
     let pad = (1 + len + MINIMUM_PAD_SIZE) % bs;
     if pad > (bs - MINIMUM_PAD_SIZE) {
         bs * 2 - pad
@@ -35,22 +51,26 @@ enum DecryptState {
 }
 
 #[derive(Debug)]
-pub(crate) struct BppStream<IO> {
+pub struct BppStream<IO> {
     state: State,
     io: IO,
     rxstate: DecryptState,
     rxbuf: BytesMut,
     txbuf: BytesMut,
+    observer: Observer,
+    charge: Charge,
 }
 
 impl<IO> BppStream<IO> {
-    pub(crate) fn new(io: IO) -> Self {
+    pub fn new(io: IO) -> Self {
         Self {
             state: State::new(),
             io,
             rxstate: DecryptState::FillFirst,
-            rxbuf: BytesMut::with_capacity(MAXIMUM_PACKET_SIZE),
+            rxbuf: BytesMut::with_capacity(RX_BUFFER_SIZE),
             txbuf: BytesMut::with_capacity(MAXIMUM_PACKET_SIZE),
+            observer: Observer::default(),
+            charge: Memory::default().charge(0),
         }
     }
 
@@ -61,6 +81,25 @@ impl<IO> BppStream<IO> {
     pub(crate) fn state_mut(&mut self) -> &mut State {
         &mut self.state
     }
+
+    /// Report every packet from now on.
+    pub(crate) fn set_observer(&mut self, observer: Observer) {
+        self.observer = observer;
+    }
+
+    /// Charge buffered bytes to `memory` from now on.
+    pub(crate) fn set_memory(&mut self, memory: &Memory) {
+        self.charge = memory.charge(self.rxbuf.len() + self.txbuf.len());
+    }
+
+    fn recharge(&mut self) {
+        self.charge.resize(self.rxbuf.len() + self.txbuf.len());
+    }
+
+    /// Encrypted bytes not yet written to the underlying io.
+    pub(crate) fn tx_pending(&self) -> usize {
+        self.txbuf.len()
+    }
 }
 
 fn poll_fill_buf<IO>(
@@ -71,6 +110,9 @@ fn poll_fill_buf<IO>(
 where
     IO: AsyncRead + Unpin,
 {
+    if buf.capacity() - buf.len() < MAXIMUM_PACKET_SIZE {
+        buf.reserve(RX_BUFFER_SIZE);
+    }
     let n = {
         let dst = buf.chunk_mut();
         let dst = unsafe { &mut *(dst as *mut _ as *mut [MaybeUninit<u8>]) };
@@ -86,17 +128,11 @@ where
     Poll::Ready(Ok(n))
 }
 
-fn consume(buf: &mut BytesMut, amt: usize) {
-    buf.advance(amt);
-    if buf.is_empty() {
-        buf.clear();
-    }
-}
-
 fn next_payload(
     buf: &mut BytesMut,
     state: &mut OneWayState,
     txstate: &mut DecryptState,
+    observer: &Observer,
 ) -> Poll<Result<Bytes, SshError>> {
     let mac_length = state.mac().len();
 
@@ -109,8 +145,13 @@ fn next_payload(
 
                 state.cipher_mut().update(&mut buf[..4])?;
                 let len = (&buf[..4]).get_u32() as usize;
-                if len + 4 + mac_length > MAXIMUM_PACKET_SIZE {
-                    return Poll::Ready(Err(SshError::TooLargePacket(len + 4 + mac_length)));
+                let total = len.saturating_add(4 + mac_length);
+                if total > MAXIMUM_PACKET_SIZE {
+                    return Poll::Ready(Err(SshError::TooLargePacket(total)));
+                }
+                if len < MINIMUM_PACKET_LENGTH {
+                    let msg = format!("too short packet length {}", len);
+                    return Poll::Ready(Err(SshError::Protocol(msg)));
                 }
                 *txstate = DecryptState::FillRemaining(len);
             }
@@ -124,14 +165,29 @@ fn next_payload(
                 let pkt = &pkt_and_mac[..(4 + *len)];
                 let mac = &pkt_and_mac[(*len + 4)..];
                 let seq = state.get_and_inc_seq();
-                state.mac().verify(seq, &pkt[..(*len + 4)], &mac)?;
+                state.mac().verify(seq, &pkt[..(*len + 4)], mac)?;
 
                 let pad = pkt[4] as usize;
-                let payload = &pkt[(1 + 4)..(*len + 4 - pad)];
-                let payload = state.comp().decompress(payload)?;
+                if pad < MINIMUM_PAD_SIZE || pad >= *len {
+                    let msg = format!("invalid padding length {} for packet length {}", pad, len);
+                    return Poll::Ready(Err(SshError::Protocol(msg)));
+                }
 
-                consume(buf, 4 + *len + mac_length);
+                // Payload without copy, unless it needs decompression.
+                let pkt = buf.split_to(4 + *len + mac_length).freeze();
+                let payload = pkt.slice((1 + 4)..(*len + 4 - pad));
+                let payload = if state.comp().is_passthrough() {
+                    payload
+                } else {
+                    state.comp_mut().decompress(&payload)?
+                };
+
+                state.count(4 + *len + mac_length);
+                observer.packet_received(4 + *len + mac_length);
                 *txstate = DecryptState::FillFirst;
+                if payload.first() == Some(&NEWKEYS) {
+                    state.switch_keys()?;
+                }
                 return Poll::Ready(Ok(payload));
             }
         }
@@ -145,22 +201,42 @@ where
     type Item = Result<Bytes, SshError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let result = this.poll_next_payload(cx);
+        this.recharge();
+        result
+    }
+}
+
+impl<IO> BppStream<IO>
+where
+    IO: AsyncRead + Unpin,
+{
+    fn poll_next_payload(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, SshError>>> {
         let Self {
             ref mut io,
             ref mut state,
             ref mut rxstate,
             ref mut rxbuf,
+            ref observer,
             ..
-        } = self.get_mut();
+        } = self;
         let state = state.ctos_mut();
 
         loop {
-            if let Poll::Ready(payload) = next_payload(rxbuf, state, rxstate)? {
+            if let Poll::Ready(payload) = next_payload(rxbuf, state, rxstate, observer)? {
                 return Poll::Ready(Some(Ok(payload)));
             }
             let n = ready!(poll_fill_buf(Pin::new(io), cx, rxbuf))?;
-            if n == 0 && rxbuf.is_empty() {
-                return Poll::Ready(None);
+            if n == 0 {
+                if rxbuf.is_empty() {
+                    return Poll::Ready(None);
+                }
+                // Closed within a packet. Reading on would spin forever.
+                rxbuf.clear();
+                *rxstate = DecryptState::FillFirst;
+                let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Poll::Ready(Some(Err(e.into())));
             }
         }
     }
@@ -181,28 +257,67 @@ where
     }
 
     fn start_send(self: Pin<&mut Self>, item: &[u8]) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let result = this.encrypt(item);
+        this.recharge();
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let result = this.poll_write_buf(cx);
+        this.recharge();
+        result
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut this = self.get_mut();
+        ready!(Pin::new(&mut this).poll_flush(cx))?;
+        ready!(Pin::new(&mut this.io).poll_shutdown(cx))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO> BppStream<IO>
+where
+    IO: AsyncWrite + Unpin,
+{
+    fn encrypt(&mut self, item: &[u8]) -> Result<(), SshError> {
         let Self {
             ref mut txbuf,
-            ref mut state,
+            state: ref mut both,
+            ref observer,
             ..
-        } = self.get_mut();
-        let state = state.stoc_mut();
-
-        let item = state.comp().compress(item)?;
+        } = self;
+        let state = both.stoc_mut();
+        let newkeys = item.first() == Some(&NEWKEYS);
+        let authenticated = item.first() == Some(&USERAUTH_SUCCESS);
+
+        let compressed;
+        let item = if state.comp().is_passthrough() {
+            item
+        } else {
+            compressed = state.comp_mut().compress(item)?;
+            &compressed[..]
+        };
         let len = item.len();
         let bs = state.cipher().block_size();
         let padding_length = pad_len(len, bs);
         let len = len + padding_length + 1;
 
-        let mut pad = vec![0; padding_length];
-        SystemRandom::new().fill(&mut pad).map_err(SshError::any)?;
-
-        let mut buf = txbuf.split();
+        // keep already encrypted, not yet flushed bytes out of this packet.
+        // Reserved up front, so the packet is contiguous and unsplit does not copy.
+        txbuf.reserve(4 + len + state.mac().len());
+        let mut buf = txbuf.split_off(txbuf.len());
 
         buf.put_u32(len as u32);
-        buf.put_u8(pad.len() as u8);
-        buf.put_slice(&item);
-        buf.put_slice(&pad);
+        buf.put_u8(padding_length as u8);
+        buf.put_slice(item);
+        let pad_start = buf.len();
+        buf.put_bytes(0, padding_length);
+        SystemRandom::new()
+            .fill(&mut buf[pad_start..])
+            .map_err(SshError::any)?;
 
         let seq = state.get_and_inc_seq();
         let sign = state.mac().sign(seq, &buf)?;
@@ -211,26 +326,26 @@ where
 
         buf.put_slice(&sign);
 
+        state.count(buf.len());
+        observer.packet_sent(buf.len());
         txbuf.unsplit(buf);
 
+        if newkeys {
+            state.switch_keys()?;
+        }
+        if authenticated {
+            both.enable_delayed_compression();
+        }
         Ok(())
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let this = self.get_mut();
-        while this.txbuf.has_remaining() {
-            let n = ready!(Pin::new(&mut this.io).poll_write(cx, &this.txbuf))?;
-            this.txbuf.advance(n);
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SshError>> {
+        while self.txbuf.has_remaining() {
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.txbuf))?;
+            self.txbuf.advance(n);
         }
-        this.txbuf.clear();
-        ready!(Pin::new(&mut this.io).poll_flush(cx))?;
-        Poll::Ready(Ok(()))
-    }
-
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let mut this = self.get_mut();
-        ready!(Pin::new(&mut this).poll_flush(cx))?;
-        ready!(Pin::new(&mut this.io).poll_shutdown(cx))?;
+        self.txbuf.clear();
+        ready!(Pin::new(&mut self.io).poll_flush(cx))?;
         Poll::Ready(Ok(()))
     }
 }
@@ -245,4 +360,230 @@ mod tests {
 
         assert::<BppStream<tokio::net::TcpStream>>();
     }
+
+    #[tokio::test]
+    async fn test_partial_packet_charged() {
+        use futures::StreamExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        let (ours, mut theirs) = tokio::io::duplex(MAXIMUM_PACKET_SIZE * 2);
+        let memory = Memory::default();
+        let mut stream = BppStream::new(ours);
+        stream.set_memory(&memory);
+
+        let mut buf = packet(30_000, 4, &[]);
+        buf.put_bytes(0, 20_000);
+        theirs.write_all(&buf).await.unwrap();
+        let next = futures::poll!(stream.next());
+        assert!(next.is_pending());
+        assert_eq!(memory.used(), buf.len());
+
+        drop(stream);
+        assert_eq!(memory.used(), 0);
+    }
+
+    fn packet(len: u32, pad: u8, body: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u32(len);
+        buf.put_u8(pad);
+        buf.put_slice(body);
+        buf
+    }
+
+    fn receive(mut buf: BytesMut) -> Poll<Result<Bytes, SshError>> {
+        let mut state = State::new();
+        next_payload(
+            &mut buf,
+            state.ctos_mut(),
+            &mut DecryptState::FillFirst,
+            &Observer::default(),
+        )
+    }
+
+    #[test]
+    fn test_receive_valid() {
+        let r = receive(packet(12, 10, &[0x05; 11]));
+        match r {
+            Poll::Ready(Ok(payload)) => assert_eq!(&payload[..], &[0x05]),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_receive_without_copy() {
+        let buf = packet(12, 10, &[0x05; 11]);
+        let start = buf.as_ptr();
+        match receive(buf) {
+            Poll::Ready(Ok(payload)) => assert_eq!(payload.as_ptr(), start.wrapping_add(5)),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_receive_pad_too_large() {
+        let r = receive(packet(12, 255, &[0; 11]));
+        assert!(matches!(r, Poll::Ready(Err(SshError::Protocol(..)))));
+
+        let r = receive(packet(12, 12, &[0; 11]));
+        assert!(matches!(r, Poll::Ready(Err(SshError::Protocol(..)))));
+    }
+
+    #[test]
+    fn test_receive_pad_too_small() {
+        let r = receive(packet(12, 0, &[0; 11]));
+        assert!(matches!(r, Poll::Ready(Err(SshError::Protocol(..)))));
+    }
+
+    #[test]
+    fn test_receive_too_short() {
+        let r = receive(packet(1, 0, &[]));
+        assert!(matches!(r, Poll::Ready(Err(SshError::Protocol(..)))));
+
+        let r = receive(packet(0, 0, &[]));
+        assert!(matches!(r, Poll::Ready(Err(SshError::Protocol(..)))));
+    }
+
+    #[test]
+    fn test_receive_mutated() {
+        let rng = SystemRandom::new();
+        for _ in 0..10000 {
+            let mut seed = [0; 3];
+            rng.fill(&mut seed).unwrap();
+            let len = 12 + (seed[0] as usize % 32);
+            let mut buf = packet(len as u32, seed[1], &vec![0; len - 1]);
+            buf[3] ^= seed[2] & 0x1f;
+            drop(receive(buf));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_truncated_at_eof() {
+        use futures::prelude::*;
+
+        let mut io = BppStream::new(&b"\0\0\0\x0c\x0a\x05"[..]);
+        match io.next().await {
+            Some(Err(SshError::IoError(e))) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(io.next().await.is_none());
+    }
+
+    fn keys(seed: u8, encrypt: bool) -> crate::state::Keys {
+        keys_with(seed, encrypt, crate::comp::Algorithm::None)
+    }
+
+    fn keys_with(seed: u8, encrypt: bool, comp: crate::comp::Algorithm) -> crate::state::Keys {
+        use crate::cipher::{Algorithm as CipherAlgorithm, Cipher};
+        use crate::comp::Compression;
+        use crate::mac::{Algorithm as MacAlgorithm, Mac};
+
+        let key = Bytes::from(vec![seed; 16]);
+        let iv = Bytes::from(vec![!seed; 16]);
+        let cipher = if encrypt {
+            Cipher::new_for_encrypt(&CipherAlgorithm::Aes128Ctr, &key, &iv)
+        } else {
+            Cipher::new_for_decrypt(&CipherAlgorithm::Aes128Ctr, &key, &iv)
+        };
+        let mac = Mac::new(&MacAlgorithm::HmacSha256, &[seed; 32]);
+        crate::state::Keys::new(cipher.unwrap(), mac, Compression::new(&comp))
+    }
+
+    #[tokio::test]
+    async fn test_newkeys_in_flight() {
+        use futures::prelude::*;
+
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut server = BppStream::new(a);
+        let mut client = BppStream::new(b);
+        server.state_mut().stoc_mut().stage(keys(1, true));
+        server.state_mut().ctos_mut().stage(keys(2, false));
+        client.state_mut().stoc_mut().stage(keys(2, true));
+        client.state_mut().ctos_mut().stage(keys(1, false));
+
+        // Both sides queue data around their NEWKEYS before reading anything.
+        for io in [&mut server, &mut client] {
+            io.feed(&b"\x5ebefore"[..]).await.unwrap();
+            io.feed(&[NEWKEYS][..]).await.unwrap();
+            io.feed(&b"\x5eafter"[..]).await.unwrap();
+            io.flush().await.unwrap();
+        }
+        for io in [&mut server, &mut client] {
+            assert_eq!(io.next().await.unwrap().unwrap(), &b"\x5ebefore"[..]);
+            assert_eq!(io.next().await.unwrap().unwrap(), &[NEWKEYS][..]);
+            assert_eq!(io.next().await.unwrap().unwrap(), &b"\x5eafter"[..]);
+        }
+
+        // Nothing staged for another NEWKEYS.
+        assert!(matches!(
+            server.send(&[NEWKEYS][..]).await,
+            Err(SshError::Protocol(..))
+        ));
+        client.state_mut().stoc_mut().stage(keys(3, true));
+        client.send(&[NEWKEYS][..]).await.unwrap();
+        assert!(matches!(
+            server.next().await,
+            Some(Err(SshError::Protocol(..)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transferred() {
+        use futures::prelude::*;
+
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut server = BppStream::new(a);
+        let mut client = BppStream::new(b);
+        server.send(&b"\x5ehello"[..]).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        let sent = *server.state().stoc().transferred();
+        assert!(sent > 0);
+        assert_eq!(client.state().ctos().transferred(), &sent);
+        assert_eq!(server.state().transferred(), sent);
+
+        server.state_mut().stoc_mut().stage(keys(1, true));
+        client.state_mut().ctos_mut().stage(keys(1, false));
+        server.send(&[NEWKEYS][..]).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        assert_eq!(server.state().stoc().transferred(), &0);
+        assert_eq!(client.state().ctos().transferred(), &0);
+    }
+
+    #[tokio::test]
+    async fn test_delayed_compression() {
+        use crate::comp::Algorithm as CompAlgorithm;
+        use futures::prelude::*;
+
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut server = BppStream::new(a);
+        let mut client = BppStream::new(b);
+        let zlib = || CompAlgorithm::ZlibOpenssh;
+        server
+            .state_mut()
+            .stoc_mut()
+            .stage(keys_with(1, true, zlib()));
+        client
+            .state_mut()
+            .ctos_mut()
+            .stage(keys_with(1, false, zlib()));
+        server.send(&[NEWKEYS][..]).await.unwrap();
+        client.next().await.unwrap().unwrap();
+
+        let payload = [&[0x5e][..], &[b'x'; 1024][..]].concat();
+        for authenticated in [false, true] {
+            let before = *server.state().stoc().transferred();
+            server.send(&payload[..]).await.unwrap();
+            assert_eq!(client.next().await.unwrap().unwrap(), &payload[..]);
+            let sent = (server.state().stoc().transferred() - before) as usize;
+            assert_eq!(sent < payload.len(), authenticated);
+
+            server.send(&[USERAUTH_SUCCESS][..]).await.unwrap();
+            assert_eq!(
+                client.next().await.unwrap().unwrap(),
+                &[USERAUTH_SUCCESS][..]
+            );
+            client.state_mut().enable_delayed_compression();
+        }
+    }
 }
@@ -1,19 +1,34 @@
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use bytes::BytesMut;
+use crate::trace::trace;
+use bytes::{Bytes, BytesMut};
+use futures::future;
 use futures::ready;
 use futures::sink::Sink;
-use futures::stream::Stream;
-use log::debug;
+use futures::stream::{Stream, StreamExt as _};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use super::bpp::BppStream;
-use crate::msg::{ContextualMsg, Msg};
+use crate::connection::memory::Memory;
+use crate::msg::{ContextualMsg, Msg, MsgName as _};
+use crate::observer::Observer;
 use crate::pack::{Pack, Unpack};
+#[cfg(feature = "replay")]
+use crate::replay::{Direction, ReplayRecorder};
 use crate::SshError;
 
+/// Progress made by [`MsgStream::next_or_flush`].
+#[derive(Debug)]
+pub(crate) enum Duplex {
+    /// Next inbound message (or end of stream).
+    Received(Option<Result<Msg, SshError>>),
+    /// All buffered outbound bytes were written.
+    Flushed(Result<(), SshError>),
+}
+
 #[derive(Debug)]
 pub(crate) struct MsgStream<IO>
 where
@@ -21,6 +36,67 @@ where
 {
     io: BppStream<IO>,
     txbuf: BytesMut,
+    defer: bool,
+    /// Deferred messages with their sequence numbers.
+    deferred: VecDeque<(u32, Msg)>,
+    /// Sequence number of the message yielded last.
+    last_seq: u32,
+    /// Sequence number and id of our recently sent messages.
+    sent: VecDeque<(u32, u8)>,
+    hold: bool,
+    /// Packed outbound messages held back during our key exchange.
+    held: VecDeque<Bytes>,
+    /// Disconnect sent. Nothing may follow it.
+    terminating: bool,
+    #[cfg(feature = "replay")]
+    recorder: Option<ReplayRecorder>,
+}
+
+#[cfg(feature = "replay")]
+fn record(recorder: &mut Option<ReplayRecorder>, direction: Direction, payload: &[u8]) {
+    if let Some(recorder) = recorder {
+        recorder.record(direction, payload);
+    }
+}
+
+/// Maximum number of connection messages held back during key exchange.
+const MAXIMUM_DEFERRED: usize = 1024;
+
+/// Sent messages remembered for resolving SSH_MSG_UNIMPLEMENTED.
+const MAXIMUM_SENT: usize = 64;
+
+/// Service request and accept.
+const SERVICE_MESSAGES: [u8; 2] = [5, 6];
+
+/// Messages not allowed during key exchange (RFC 4253 section 7.1).
+///
+/// Connection layer messages (channels, auth, global requests) start at 50.
+fn is_deferrable(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .is_some_and(|id| *id >= 50 || SERVICE_MESSAGES.contains(id))
+}
+
+fn hold_full(hold: bool, held: &VecDeque<Bytes>) -> bool {
+    hold && held.len() >= MAXIMUM_DEFERRED
+}
+
+/// Hold back connection layer message while deferring.
+fn defer(
+    deferred: &mut VecDeque<(u32, Msg)>,
+    seq: u32,
+    payload: &mut Bytes,
+) -> Result<(), SshError> {
+    if deferred.len() >= MAXIMUM_DEFERRED {
+        return Err(SshError::Protocol(
+            "too many messages during key exchange".into(),
+        ));
+    }
+    let len = payload.len();
+    let msg = Msg::unpack(payload)?;
+    trace!("< (deferred) {} ({} bytes)", msg.name(), len);
+    deferred.push_back((seq, msg));
+    Ok(())
 }
 
 impl<IO> MsgStream<IO>
@@ -31,6 +107,130 @@ where
         Self {
             io: BppStream::new(io),
             txbuf: BytesMut::new(),
+            defer: false,
+            deferred: VecDeque::new(),
+            last_seq: 0,
+            sent: VecDeque::new(),
+            hold: false,
+            held: VecDeque::new(),
+            terminating: false,
+            #[cfg(feature = "replay")]
+            recorder: None,
+        }
+    }
+
+    /// Record every message from now on.
+    #[cfg(feature = "replay")]
+    pub(crate) fn set_recorder(&mut self, recorder: ReplayRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Report every packet from now on.
+    pub(crate) fn set_observer(&mut self, observer: Observer) {
+        self.io.set_observer(observer);
+    }
+
+    /// Charge transport buffers to `memory` from now on.
+    pub(crate) fn set_memory(&mut self, memory: &Memory) {
+        self.io.set_memory(memory);
+    }
+
+    /// Hold back connection layer messages until key exchange completes.
+    ///
+    /// Received messages are yielded in order once deferring stops.
+    pub(crate) fn defer_non_kex(&mut self, defer: bool) {
+        self.defer = defer;
+    }
+
+    /// Hold back outbound connection layer messages during our key exchange.
+    ///
+    /// Held messages are sent in order once holding stops.
+    pub(crate) fn hold_non_kex(&mut self, hold: bool) -> Result<(), SshError> {
+        self.hold = hold;
+        if !hold {
+            if self.terminating {
+                self.held.clear();
+            }
+            while let Some(payload) = self.held.pop_front() {
+                #[cfg(feature = "replay")]
+                record(&mut self.recorder, Direction::Outbound, &payload);
+                Pin::new(&mut self.io).start_send(&payload)?;
+                self.remember_sent(payload[0]);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn holding_non_kex(&self) -> bool {
+        self.hold
+    }
+
+    /// Whether no more outbound messages can be held back.
+    ///
+    /// Received connection layer messages are deferred meanwhile, so their
+    /// answers wait for our key exchange instead of failing it.
+    pub(crate) fn hold_full(&self) -> bool {
+        hold_full(self.hold, &self.held)
+    }
+
+    /// Send packed `txbuf`, or hold it back.
+    ///
+    /// Callers stop producing output once [`Self::hold_full`], so running
+    /// out of room is a bug rather than a misbehaving peer.
+    fn send_packed(&mut self) -> Result<(), SshError> {
+        if self.hold && is_deferrable(&self.txbuf) {
+            if self.held.len() >= MAXIMUM_DEFERRED {
+                return Err(SshError::Protocol(
+                    "too many messages during key exchange".into(),
+                ));
+            }
+            self.held.push_back(self.txbuf.split().freeze());
+            return Ok(());
+        }
+        #[cfg(feature = "replay")]
+        record(&mut self.recorder, Direction::Outbound, &self.txbuf);
+        Pin::new(&mut self.io).start_send(&self.txbuf)?;
+        self.remember_sent(self.txbuf[0]);
+        Ok(())
+    }
+
+    fn remember_sent(&mut self, id: u8) {
+        if self.sent.len() >= MAXIMUM_SENT {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((self.io.state().stoc().last_seq(), id));
+    }
+
+    /// Id of our recent message sent with sequence number `seq`.
+    pub(crate) fn sent_id(&self, seq: u32) -> Option<u8> {
+        self.sent
+            .iter()
+            .rev()
+            .find(|(s, _)| *s == seq)
+            .map(|(_, id)| *id)
+    }
+
+    /// Sequence number of the message received last, for SSH_MSG_UNIMPLEMENTED.
+    pub(crate) fn last_seq(&self) -> u32 {
+        self.last_seq
+    }
+
+    /// Whether Disconnect was sent. Later messages are dropped.
+    #[cfg(test)]
+    pub(crate) fn terminating(&self) -> bool {
+        self.terminating
+    }
+
+    /// Discard the next packet unparsed, e.g. a wrongly guessed kex packet.
+    pub(crate) async fn skip_packet(&mut self) -> Result<(), SshError> {
+        match self.io.next().await.transpose()? {
+            Some(payload) => {
+                #[cfg(feature = "replay")]
+                record(&mut self.recorder, Direction::Inbound, &payload);
+                trace!("< (skipped) message {:?}", payload.first());
+                Ok(())
+            }
+            None => Err(SshError::NoPacketReceived),
         }
     }
 
@@ -42,6 +242,25 @@ where
         &mut self.io
     }
 
+    /// Receive next message while flushing buffered output.
+    ///
+    /// Reading is never held back by a peer that does not read our output.
+    /// Otherwise both sides may block writing to each other forever.
+    pub(crate) async fn next_or_flush(&mut self) -> Duplex {
+        future::poll_fn(|cx| {
+            if let Poll::Ready(msg) = Pin::new(&mut *self).poll_next(cx) {
+                return Poll::Ready(Duplex::Received(msg));
+            }
+            if self.io.tx_pending() > 0 {
+                if let Poll::Ready(result) = Pin::new(&mut self.io).poll_flush(cx) {
+                    return Poll::Ready(Duplex::Flushed(result));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
     pub(crate) fn context<M>(&mut self) -> ContextualMsgStream<'_, IO, M>
     where
         M: ContextualMsg + Unpin,
@@ -60,14 +279,35 @@ where
     type Item = Result<Msg, SshError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let io = &mut self.get_mut().io;
-        match ready!(Pin::new(io).poll_next(cx)?) {
-            Some(ref mut buf) => {
-                let msg = Unpack::unpack(buf)?;
-                debug!("< {:?}", msg);
-                Poll::Ready(Some(Ok(msg)))
+        let this = self.get_mut();
+        let deferring = this.defer || this.hold_full();
+        if !deferring {
+            if let Some((seq, msg)) = this.deferred.pop_front() {
+                this.last_seq = seq;
+                return Poll::Ready(Some(Ok(msg)));
+            }
+        }
+
+        loop {
+            let mut payload = ready!(Pin::new(&mut this.io).poll_next(cx)?);
+            #[cfg(feature = "replay")]
+            if let Some(buf) = &payload {
+                record(&mut this.recorder, Direction::Inbound, buf);
+            }
+            let seq = this.io.state().ctos().last_seq();
+            match payload {
+                Some(ref mut buf) if deferring && is_deferrable(buf) => {
+                    defer(&mut this.deferred, seq, buf)?;
+                }
+                Some(ref mut buf) => {
+                    let len = buf.len();
+                    let msg = Msg::unpack(buf)?;
+                    trace!("< {} ({} bytes)", msg.name(), len);
+                    this.last_seq = seq;
+                    return Poll::Ready(Some(Ok(msg)));
+                }
+                None => return Poll::Ready(None),
             }
-            None => Poll::Ready(None),
         }
     }
 }
@@ -85,12 +325,16 @@ where
     }
 
     fn start_send(self: Pin<&mut Self>, item: Msg) -> Result<(), Self::Error> {
-        debug!("> {:?}", item);
-        let Self { io, txbuf } = self.get_mut();
-        txbuf.clear();
-        item.pack(txbuf);
-        Pin::new(io).start_send(&txbuf)?;
-        Ok(())
+        let this = self.get_mut();
+        if this.terminating {
+            trace!("> (dropped after disconnect) {}", item.name());
+            return Ok(());
+        }
+        this.terminating = matches!(item, Msg::Disconnect(..));
+        this.txbuf.clear();
+        item.pack(&mut this.txbuf);
+        trace!("> {} ({} bytes)", item.name(), this.txbuf.len());
+        this.send_packed()
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -124,14 +368,39 @@ where
     type Item = Result<M, SshError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let io = &mut self.get_mut().inner.io;
-        match ready!(Pin::new(io).poll_next(cx)?) {
-            Some(ref mut buf) => {
-                let msg = Unpack::unpack(buf)?;
-                debug!("< {:?}", msg);
-                Poll::Ready(Some(Ok(msg)))
+        let MsgStream {
+            io,
+            defer: deferring_kex,
+            deferred,
+            last_seq,
+            hold,
+            held,
+            #[cfg(feature = "replay")]
+            recorder,
+            ..
+        } = &mut *self.get_mut().inner;
+        let deferring = *deferring_kex || hold_full(*hold, held);
+
+        loop {
+            let mut payload = ready!(Pin::new(&mut *io).poll_next(cx)?);
+            #[cfg(feature = "replay")]
+            if let Some(buf) = &payload {
+                record(recorder, Direction::Inbound, buf);
+            }
+            let seq = io.state().ctos().last_seq();
+            match payload {
+                Some(ref mut buf) if deferring && is_deferrable(buf) => {
+                    defer(deferred, seq, buf)?;
+                }
+                Some(ref mut buf) => {
+                    let len = buf.len();
+                    let msg = M::unpack(buf)?;
+                    trace!("< {} ({} bytes)", msg.name(), len);
+                    *last_seq = seq;
+                    return Poll::Ready(Some(Ok(msg)));
+                }
+                None => return Poll::Ready(None),
             }
-            None => Poll::Ready(None),
         }
     }
 }
@@ -150,12 +419,15 @@ where
     }
 
     fn start_send(self: Pin<&mut Self>, item: M) -> Result<(), Self::Error> {
-        debug!("> {:?}", item);
-        let MsgStream { io, txbuf } = self.get_mut().inner;
-        txbuf.clear();
-        item.pack(txbuf);
-        Pin::new(io).start_send(&txbuf)?;
-        Ok(())
+        let inner = &mut *self.get_mut().inner;
+        if inner.terminating {
+            trace!("> (dropped after disconnect) {}", item.name());
+            return Ok(());
+        }
+        inner.txbuf.clear();
+        item.pack(&mut inner.txbuf);
+        trace!("> {} ({} bytes)", item.name(), inner.txbuf.len());
+        inner.send_packed()
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -182,4 +454,201 @@ mod tests {
         assert::<MsgStream<tokio::net::TcpStream>>();
         assert::<ContextualMsgStream<tokio::net::TcpStream, crate::msg::GexMsg>>();
     }
+
+    #[tokio::test]
+    async fn test_defer_non_kex() {
+        use crate::msg::channel_data::ChannelData;
+        use futures::prelude::*;
+
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let mut ours = MsgStream::new(ours);
+        let mut theirs = MsgStream::new(theirs);
+
+        let kex_ecdh_init = Msg::unpack(&mut Bytes::from_static(&[30, 0, 0, 0, 1, 7])).unwrap();
+        theirs
+            .send(ChannelData::new(0, Bytes::from_static(b"late")).into())
+            .await
+            .unwrap();
+        theirs.send(kex_ecdh_init).await.unwrap();
+        theirs
+            .send(ChannelData::new(0, Bytes::from_static(b"next")).into())
+            .await
+            .unwrap();
+
+        ours.defer_non_kex(true);
+        assert!(matches!(
+            ours.next().await.unwrap().unwrap(),
+            Msg::KexEcdhInit(..)
+        ));
+        assert_eq!(ours.last_seq(), 1);
+        ours.defer_non_kex(false);
+
+        for (expect, seq) in [(&b"late"[..], 0), (&b"next"[..], 2)] {
+            match ours.next().await.unwrap().unwrap() {
+                Msg::ChannelData(msg) => assert_eq!(&msg.data()[..], expect),
+                x => panic!("{:?}", x),
+            }
+            assert_eq!(ours.last_seq(), seq);
+        }
+        drop(theirs);
+        assert!(ours.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sent_id() {
+        use crate::msg::channel_data::ChannelData;
+        use crate::msg::ignore::Ignore;
+        use futures::prelude::*;
+
+        let (ours, _theirs) = tokio::io::duplex(64 * 1024);
+        let mut ours = MsgStream::new(ours);
+
+        ours.send(Ignore::new(Bytes::new()).into()).await.unwrap();
+        for _ in 0..MAXIMUM_SENT {
+            let data = ChannelData::new(0, Bytes::from_static(b"x"));
+            ours.send(data.into()).await.unwrap();
+        }
+        // Forgotten after too many later messages.
+        assert_eq!(ours.sent_id(0), None);
+        assert_eq!(ours.sent_id(1), Some(94));
+        assert_eq!(ours.sent_id(MAXIMUM_SENT as u32 + 1), None);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_after_disconnect() {
+        use crate::msg::channel_data::ChannelData;
+        use crate::msg::disconnect::{Disconnect, ReasonCode};
+        use futures::prelude::*;
+
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let mut ours = MsgStream::new(ours);
+        let theirs = MsgStream::new(theirs);
+
+        let data = || ChannelData::new(0, Bytes::from_static(b"x")).into();
+        let disconnect = || Disconnect::new(ReasonCode::ByApplication, "".into(), "".into());
+        ours.feed(data()).await.unwrap();
+        ours.feed(disconnect().into()).await.unwrap();
+        assert!(ours.terminating());
+        ours.feed(data()).await.unwrap();
+        ours.feed(disconnect().into()).await.unwrap();
+        ours.close().await.unwrap();
+
+        let received = theirs.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(matches!(received[1], Msg::Disconnect(..)));
+    }
+
+    #[tokio::test]
+    async fn test_hold_non_kex() {
+        use crate::msg::channel_data::ChannelData;
+        use crate::msg::ignore::Ignore;
+        use futures::prelude::*;
+
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let mut ours = MsgStream::new(ours);
+        let theirs = MsgStream::new(theirs);
+
+        ours.hold_non_kex(true).unwrap();
+        ours.feed(ChannelData::new(0, Bytes::from_static(b"x")).into())
+            .await
+            .unwrap();
+        ours.feed(Ignore::new(Bytes::new()).into()).await.unwrap();
+        assert!(ours.holding_non_kex());
+        ours.hold_non_kex(false).unwrap();
+        ours.close().await.unwrap();
+
+        let received = theirs.try_collect::<Vec<_>>().await.unwrap();
+        assert!(matches!(received[0], Msg::Ignore(..)));
+        assert!(matches!(received[1], Msg::ChannelData(..)));
+        assert_eq!(received.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hold_full_defers() {
+        use crate::msg::channel_data::ChannelData;
+        use crate::msg::ignore::Ignore;
+        use futures::prelude::*;
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut ours = MsgStream::new(ours);
+        let mut theirs = MsgStream::new(theirs);
+
+        ours.hold_non_kex(true).unwrap();
+        for _ in 0..MAXIMUM_DEFERRED {
+            ours.feed(ChannelData::new(0, Bytes::from_static(b"x")).into())
+                .await
+                .unwrap();
+        }
+        assert!(ours.hold_full());
+
+        theirs
+            .feed(ChannelData::new(0, Bytes::from_static(b"y")).into())
+            .await
+            .unwrap();
+        theirs.feed(Ignore::new(Bytes::new()).into()).await.unwrap();
+        theirs.flush().await.unwrap();
+
+        // the answer to channel data would not fit, so it waits.
+        assert!(matches!(ours.next().await, Some(Ok(Msg::Ignore(..)))));
+        ours.hold_non_kex(false).unwrap();
+        assert!(!ours.hold_full());
+        assert!(matches!(ours.next().await, Some(Ok(Msg::ChannelData(..)))));
+    }
+
+    #[tokio::test]
+    async fn test_next_or_flush_both_blocked() {
+        use crate::msg::ignore::Ignore;
+        use bytes::Bytes;
+        use futures::prelude::*;
+
+        const N: usize = 64;
+        let (ours, theirs) = tokio::io::duplex(256);
+        let mut ours = MsgStream::new(ours);
+        let mut theirs = MsgStream::new(theirs);
+
+        // peer writes everything before reading anything.
+        let peer = async move {
+            for _ in 0..N {
+                theirs
+                    .send(Ignore::new(Bytes::from(vec![0; 100])).into())
+                    .await?;
+            }
+            let mut n = 0;
+            while n < N {
+                theirs.next().await.unwrap()?;
+                n += 1;
+            }
+            Ok::<_, SshError>(())
+        };
+
+        let local = async move {
+            for _ in 0..N {
+                ours.feed(Ignore::new(Bytes::from(vec![0; 100])).into())
+                    .await?;
+            }
+            let mut received = 0;
+            let mut flushed = false;
+            while received < N || !flushed {
+                match ours.next_or_flush().await {
+                    Duplex::Received(msg) => {
+                        msg.unwrap()?;
+                        received += 1;
+                    }
+                    Duplex::Flushed(result) => {
+                        result?;
+                        flushed = true;
+                    }
+                }
+            }
+            Ok::<_, SshError>(())
+        };
+
+        let (peer, local) = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            tokio::join!(peer, local)
+        })
+        .await
+        .unwrap();
+        peer.unwrap();
+        local.unwrap();
+    }
 }
@@ -0,0 +1,319 @@
+//! In-memory transport and scripted peer for tests.
+use bytes::{BufMut as _, Bytes, BytesMut};
+use futures::sink::SinkExt;
+use futures::stream::{StreamExt as _, TryStreamExt as _};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, DuplexStream};
+use tokio::time;
+
+use crate::msg::kexinit::Kexinit;
+use crate::msg::Msg;
+use crate::preference::Preference;
+use crate::stream::msg::MsgStream;
+
+/// Connected transport halves, one for the server and one for the peer.
+pub(crate) fn duplex_pair() -> (DuplexStream, DuplexStream) {
+    tokio::io::duplex(64 * 1024)
+}
+
+fn packed(msg: Msg) -> Bytes {
+    use crate::pack::Pack as _;
+
+    let mut buf = BytesMut::new();
+    msg.pack(&mut buf);
+    buf.freeze()
+}
+
+/// Key exchange as client of `preference`, pausing `delay` before NEWKEYS.
+///
+/// `versions` are the client and server identification strings.
+/// Returns exchange hash and shared secret.
+pub(crate) async fn client_handshake<IO>(
+    theirs: &mut MsgStream<IO>,
+    versions: (&str, &str),
+    c_kexinit: Kexinit,
+    preference: &Preference,
+    delay: time::Duration,
+) -> (Bytes, Bytes)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let s_kexinit = match theirs.next().await {
+        Some(Ok(Msg::Kexinit(kexinit))) => *kexinit,
+        msg => panic!("{:?}", msg),
+    };
+    theirs.send(c_kexinit.clone().into()).await.unwrap();
+    exchange(theirs, versions, c_kexinit, s_kexinit, preference, delay).await
+}
+
+/// Key exchange started by the client, sending `interleaved` right after its KEXINIT.
+///
+/// Returns exchange hash, shared secret and the connection messages the
+/// server sent before its KEXINIT.
+pub(crate) async fn client_rekey<IO>(
+    theirs: &mut MsgStream<IO>,
+    versions: (&str, &str),
+    c_kexinit: Kexinit,
+    preference: &Preference,
+    interleaved: Vec<Msg>,
+) -> (Bytes, Bytes, Vec<Msg>)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    theirs.feed(c_kexinit.clone().into()).await.unwrap();
+    for msg in interleaved {
+        theirs.feed(msg).await.unwrap();
+    }
+    theirs.flush().await.unwrap();
+    let mut received = vec![];
+    let s_kexinit = loop {
+        match theirs.next().await {
+            Some(Ok(Msg::Kexinit(kexinit))) => break *kexinit,
+            Some(Ok(msg)) => received.push(msg),
+            msg => panic!("{:?}", msg),
+        }
+    };
+    let zero = time::Duration::ZERO;
+    let (hash, secret) = exchange(theirs, versions, c_kexinit, s_kexinit, preference, zero).await;
+    (hash, secret, received)
+}
+
+/// Rest of the key exchange once both KEXINIT were sent.
+async fn exchange<IO>(
+    theirs: &mut MsgStream<IO>,
+    versions: (&str, &str),
+    c_kexinit: Kexinit,
+    s_kexinit: Kexinit,
+    preference: &Preference,
+    delay: time::Duration,
+) -> (Bytes, Bytes)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+    use ring::rand::SystemRandom;
+
+    use crate::hash::Hasher;
+    use crate::kex::{self, Kex};
+    use crate::msg::new_keys::NewKeys;
+    use crate::negotiate::negotiate;
+    use crate::pack::{Mpint, Pack as _, Unpack as _};
+
+    let strict = s_kexinit.kex_algorithms().contains(kex::STRICT_SERVER)
+        && c_kexinit.kex_algorithms().contains(kex::STRICT_CLIENT);
+    let s_kexinit = packed(s_kexinit.into());
+    let algorithm = negotiate(&c_kexinit, preference).unwrap();
+    if *c_kexinit.first_kex_packet_follows() && !algorithm.guessed_by(&c_kexinit) {
+        // Guessed packet for a kex the server won't run.
+        SinkExt::send(theirs.get_mut(), &b"\x1e\x00\x00\x00\x04junk"[..])
+            .await
+            .unwrap();
+    }
+
+    let rand = SystemRandom::new();
+    let private = EphemeralPrivateKey::generate(&X25519, &rand).unwrap();
+    let public = Bytes::copy_from_slice(private.compute_public_key().unwrap().as_ref());
+    let mut init = BytesMut::new();
+    init.put_u8(30);
+    public.pack(&mut init);
+    let init = Msg::unpack(&mut init.freeze()).unwrap();
+    theirs.send(init).await.unwrap();
+
+    let mut reply = match theirs.next().await {
+        Some(Ok(msg @ Msg::KexEcdhReply(..))) => packed(msg).split_off(1),
+        msg => panic!("{:?}", msg),
+    };
+    let hostkey = Bytes::unpack(&mut reply).unwrap();
+    let server_public = Bytes::unpack(&mut reply).unwrap();
+    let secret = agree_ephemeral(
+        private,
+        &UnparsedPublicKey::new(&X25519, &server_public),
+        (),
+        |k| Ok(Bytes::copy_from_slice(k)),
+    )
+    .unwrap();
+
+    let mut hasher = Hasher::sha256();
+    versions.0.pack(&mut hasher);
+    versions.1.pack(&mut hasher);
+    packed(c_kexinit.into()).pack(&mut hasher);
+    s_kexinit.pack(&mut hasher);
+    hostkey.pack(&mut hasher);
+    public.pack(&mut hasher);
+    server_public.pack(&mut hasher);
+    Mpint::new(secret.clone()).pack(&mut hasher);
+    let hash = hasher.finish();
+
+    let kex = Kex::new(algorithm.kex_algorithm());
+    let state = theirs.get_mut().state_mut();
+    state.stage_keys(&hash, &secret, &kex, &algorithm).unwrap();
+    state.swap_directions();
+    if strict {
+        state.enable_strict_kex();
+    }
+
+    time::sleep(delay).await;
+    theirs.send(NewKeys::new().into()).await.unwrap();
+    match theirs.next().await {
+        Some(Ok(Msg::NewKeys(..))) => {}
+        msg => panic!("{:?}", msg),
+    }
+    (hash, secret)
+}
+
+/// Client driven step by step by a test, panicking on anything unexpected.
+#[derive(Debug)]
+pub(crate) struct ScriptedClient<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    io: MsgStream<IO>,
+    c_version: String,
+    s_version: String,
+}
+
+impl<IO> ScriptedClient<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Exchange identification strings over `io`, announcing `version`.
+    pub(crate) async fn connect(mut io: IO, version: &str) -> Self {
+        io.write_all(format!("{}\r\n", version).as_bytes())
+            .await
+            .unwrap();
+        let mut line = vec![];
+        while !line.ends_with(b"\r\n") {
+            line.push(io.read_u8().await.unwrap());
+        }
+        line.truncate(line.len() - 2);
+        Self {
+            io: MsgStream::new(io),
+            c_version: version.into(),
+            s_version: String::from_utf8(line).unwrap(),
+        }
+    }
+
+    /// Identification string of the server.
+    pub(crate) fn server_version(&self) -> &str {
+        &self.s_version
+    }
+
+    /// Key exchange offering `c_kexinit`. Returns the exchange hash.
+    pub(crate) async fn handshake(&mut self, c_kexinit: Kexinit, preference: &Preference) -> Bytes {
+        let versions = (&*self.c_version, &*self.s_version);
+        let (hash, _) = client_handshake(
+            &mut self.io,
+            versions,
+            c_kexinit,
+            preference,
+            time::Duration::ZERO,
+        )
+        .await;
+        hash
+    }
+
+    pub(crate) async fn send(&mut self, msg: Msg) {
+        self.io.send(msg).await.unwrap();
+    }
+
+    /// Send a captured message payload as is.
+    pub(crate) async fn send_raw(&mut self, payload: &[u8]) {
+        SinkExt::send(self.io.get_mut(), payload).await.unwrap();
+    }
+
+    /// Next message, `None` once the server closed.
+    pub(crate) async fn recv(&mut self) -> Option<Msg> {
+        self.io.next().await.transpose().unwrap()
+    }
+
+    /// Next message payload, compared to `payload`.
+    pub(crate) async fn expect_raw(&mut self, payload: &[u8]) {
+        let actual = self.io.get_mut().try_next().await.unwrap();
+        assert_eq!(actual.as_deref(), Some(payload));
+    }
+
+    /// Skip messages until `f` picks one.
+    pub(crate) async fn expect<T, F>(&mut self, mut f: F) -> T
+    where
+        F: FnMut(Msg) -> Option<T>,
+    {
+        loop {
+            match self.recv().await {
+                Some(msg) => {
+                    if let Some(t) = f(msg) {
+                        return t;
+                    }
+                }
+                None => panic!("connection closed"),
+            }
+        }
+    }
+
+    /// Close our side, returning what the server still sent.
+    pub(crate) async fn close(mut self) -> Vec<Msg> {
+        self.io.close().await.unwrap();
+        let mut received = vec![];
+        while let Some(Ok(msg)) = self.io.next().await {
+            received.push(msg);
+        }
+        received
+    }
+}
+
+/// SSH_MSG_SERVICE_REQUEST for `name`.
+pub(crate) fn service_request(name: &str) -> Msg {
+    use crate::pack::Unpack as _;
+
+    let mut buf = BytesMut::new();
+    buf.put_u8(5);
+    buf.put_u32(name.len() as u32);
+    buf.put_slice(name.as_bytes());
+    Msg::unpack(&mut buf.freeze()).unwrap()
+}
+
+/// SSH_MSG_USERAUTH_REQUEST of `user_name`.
+///
+/// `method` is the method name followed by string fields, `password` adds
+/// the fields of a password attempt.
+pub(crate) fn userauth_request(user_name: &str, method: &[&str], password: Option<&str>) -> Msg {
+    use crate::pack::{Pack as _, Unpack as _};
+
+    let mut buf = BytesMut::new();
+    buf.put_u8(50);
+    user_name.pack(&mut buf);
+    "ssh-connection".pack(&mut buf);
+    for m in method {
+        m.pack(&mut buf);
+    }
+    if let Some(password) = password {
+        false.pack(&mut buf);
+        password.pack(&mut buf);
+    }
+    Msg::unpack(&mut buf.freeze()).unwrap()
+}
+
+/// Session open with the default window and maximum packet.
+pub(crate) fn session_open(channel: u32) -> Msg {
+    session_open_with(channel, 0x20_0000, 0x8000)
+}
+
+/// Session open advertising `window` and `maximum_packet` to us.
+pub(crate) fn session_open_with(channel: u32, window: u32, maximum_packet: u32) -> Msg {
+    use crate::pack::Unpack as _;
+
+    let mut buf = BytesMut::new();
+    buf.put_u8(90);
+    buf.put_u32(7);
+    buf.put_slice(b"session");
+    buf.put_u32(channel);
+    buf.put_u32(window);
+    buf.put_u32(maximum_packet);
+    Msg::unpack(&mut buf.freeze()).unwrap()
+}
+
+/// `tcpip-forward` request for `localhost:port`.
+pub(crate) fn tcpip_forward(port: u32, want_reply: bool) -> Msg {
+    use crate::msg::global_request::{GlobalRequest, TcpipForward, Type};
+
+    let item = TcpipForward::new("localhost".into(), port);
+    GlobalRequest::new(want_reply, Type::TcpipForward(item)).into()
+}
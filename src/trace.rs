@@ -0,0 +1,74 @@
+//! Diagnostics through `log`, or through `tracing` with the `tracing` feature.
+//!
+//! With `tracing`, every event is emitted in the span of its connection and,
+//! for handlers, of its channel. Without a subscriber the events still reach
+//! the `log` logger.
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error, info, trace, warn};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, trace, warn, Instrument, Span};
+
+/// Span of one connection. The user is recorded once authenticated.
+#[cfg(feature = "tracing")]
+pub(crate) fn connection_span(remote: Option<&str>) -> Span {
+    let span = tracing::info_span!(
+        "connection",
+        remote = tracing::field::Empty,
+        user = tracing::field::Empty,
+    );
+    if let Some(remote) = remote {
+        span.record("remote", remote);
+    }
+    span
+}
+
+/// Span of one channel, child of `connection`.
+#[cfg(feature = "tracing")]
+pub(crate) fn channel_span(connection: &Span, channel: u32) -> Span {
+    tracing::info_span!(parent: connection, "channel", id = channel)
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn record_user(connection: &Span, user: &str) {
+    connection.record("user", user);
+}
+
+/// Stand-in for `tracing::Span`, carrying nothing.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn none() -> Self {
+        Self
+    }
+
+    pub(crate) fn in_scope<F: FnOnce() -> T, T>(&self, f: F) -> T {
+        f()
+    }
+}
+
+/// Stand-in for `tracing::Instrument`, leaving the future as is.
+#[cfg(not(feature = "tracing"))]
+pub(crate) trait Instrument: Sized {
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<T: std::future::Future> Instrument for T {}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn connection_span(_remote: Option<&str>) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn channel_span(_connection: &Span, _channel: u32) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_user(_connection: &Span, _user: &str) {}
@@ -0,0 +1,39 @@
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use tokio::net::TcpStream;
+
+use ssssh::ServerBuilder;
+
+#[tokio::test]
+async fn test() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let events = Arc::new(Mutex::new(vec![]));
+
+    let hook_events = events.clone();
+    let mut server = ServerBuilder::default()
+        .after_bind(move || {
+            let bound = matches!(
+                std::net::TcpListener::bind("[::1]:2222"),
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse
+            );
+            let mut events = hook_events.lock().unwrap();
+            if bound {
+                events.push("bind");
+            }
+            events.push("hook");
+            Ok(())
+        })
+        .build("[::1]:2222")
+        .await
+        .unwrap();
+    assert_eq!(server.local_addr().unwrap().port(), 2222);
+
+    let client = TcpStream::connect("[::1]:2222");
+    let (connection, _client) = tokio::join!(server.try_next(), client);
+    connection.unwrap().unwrap();
+    events.lock().unwrap().push("accept");
+
+    assert_eq!(*events.lock().unwrap(), vec!["bind", "hook", "accept"]);
+}
@@ -8,9 +8,9 @@ use tokio::process::Command;
 
 use ssssh::{Handlers, ServerBuilder};
 
-const CIPHERS: &'static [&'static str] = &["aes128-ctr", "aes192-ctr", "aes256-ctr"];
+const CIPHERS: &[&str] = &["aes128-ctr", "aes192-ctr", "aes256-ctr"];
 
-const KEXS: &'static [&'static str] = &[
+const KEXS: &[&str] = &[
     "diffie-hellman-group1-sha1",
     "diffie-hellman-group14-sha1",
     "diffie-hellman-group14-sha256",
@@ -21,11 +21,11 @@ const KEXS: &'static [&'static str] = &[
     "curve25519-sha256",
 ];
 
-const KEYS: &'static [&'static str] = &["ssh-ed25519", "ssh-rsa"];
+const KEYS: &[&str] = &["ssh-ed25519", "rsa-sha2-256", "rsa-sha2-512"];
 
-const MACS: &'static [&'static str] = &["hmac-sha1", "hmac-sha2-256", "hmac-sha2-512"];
+const MACS: &[&str] = &["hmac-sha1", "hmac-sha2-256", "hmac-sha2-512"];
 
-const CKEYS: &'static [&'static str] = &["tests/ed25519", "tests/rsa"];
+const CKEYS: &[&str] = &["tests/ed25519", "tests/rsa"];
 
 fn algorithms() -> Vec<(
     &'static str,
@@ -23,7 +23,7 @@ async fn hostbased() {
                     return Ok(true);
                 }
             }
-            return Ok(false);
+            Ok(false)
         }
         .boxed()
     });
@@ -15,9 +15,7 @@ async fn shell() {
 
     let mut handlers = Handlers::<anyhow::Error>::new();
     handlers.on_auth_none(|_| ok(true).boxed());
-    handlers.on_channel_shell(|_| {
-        async move { Err(io::Error::new(io::ErrorKind::Other, "").into()) }.boxed()
-    });
+    handlers.on_channel_shell(|_| async move { Err(io::Error::other("").into()) }.boxed());
 
     let proc = Command::new("ssh")
         .env_clear()
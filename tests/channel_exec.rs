@@ -32,7 +32,7 @@ async fn exec() {
         async move {
             assert_eq!("cat /proc/cpuinfo", prog.to_str().unwrap());
             tokio::io::copy(&mut stdin, &mut stdout).await.unwrap();
-            stderr.write(b"hello, stderr!").await.unwrap();
+            stderr.write_all(b"hello, stderr!").await.unwrap();
             Ok(0)
         }
         .boxed()
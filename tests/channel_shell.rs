@@ -1,4 +1,5 @@
 use std::ffi::CString;
+use std::io;
 use std::os::unix::io::FromRawFd;
 use std::process::Stdio;
 
@@ -8,7 +9,7 @@ use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
-use ssssh::{Handlers, ServerBuilder};
+use ssssh::{Handlers, ServerBuilder, SshError};
 
 #[tokio::test]
 async fn shell() {
@@ -35,7 +36,7 @@ async fn shell() {
         }
         async move {
             tokio::io::copy(&mut stdin, &mut stdout).await.unwrap();
-            stderr.write(b"hello, stderr!").await.unwrap();
+            stderr.write_all(b"hello, stderr!").await.unwrap();
             Ok(0)
         }
         .boxed()
@@ -82,7 +83,7 @@ async fn pty_alloc_failed() {
         }
         async move {
             tokio::io::copy(&mut stdin, &mut stdout).await.unwrap();
-            stderr.write(b"hello, stderr!").await.unwrap();
+            stderr.write_all(b"hello, stderr!").await.unwrap();
             Ok(0)
         }
         .boxed()
@@ -106,7 +107,13 @@ async fn pty_alloc_failed() {
 
     let connection = server.try_next().await.unwrap().unwrap();
     let connection = connection.accept().await.unwrap();
-    connection.run(handlers).await.unwrap_err();
+    // The client hangs up with its channel still open: a reset if our output
+    // was still in flight, otherwise a plain EOF, which ends the run cleanly.
+    match connection.run(handlers).await {
+        Ok(()) => {}
+        Err(SshError::IoError(e)) if e.kind() == io::ErrorKind::ConnectionReset => {}
+        Err(e) => panic!("{:?}", e),
+    }
     let status = proc.wait().await.unwrap();
     assert!(!status.success())
 }
@@ -127,7 +134,7 @@ async fn pty_alloc_failed2() {
         }
         async move {
             tokio::io::copy(&mut stdin, &mut stdout).await.unwrap();
-            stderr.write(b"hello, stderr!").await.unwrap();
+            stderr.write_all(b"hello, stderr!").await.unwrap();
             Ok(0)
         }
         .boxed()
@@ -151,7 +158,13 @@ async fn pty_alloc_failed2() {
 
     let connection = server.try_next().await.unwrap().unwrap();
     let connection = connection.accept().await.unwrap();
-    connection.run(handlers).await.unwrap_err();
+    // The client hangs up with its channel still open: a reset if our output
+    // was still in flight, otherwise a plain EOF, which ends the run cleanly.
+    match connection.run(handlers).await {
+        Ok(()) => {}
+        Err(SshError::IoError(e)) if e.kind() == io::ErrorKind::ConnectionReset => {}
+        Err(e) => panic!("{:?}", e),
+    }
     let status = proc.wait().await.unwrap();
     assert!(!status.success())
 }
@@ -0,0 +1,58 @@
+use std::ffi::OsString;
+use std::process::Stdio;
+
+use futures::future::ok;
+use futures::{FutureExt, TryStreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use ssssh::{Handlers, ServerBuilder};
+
+const LEN: usize = 200 * 1024;
+
+#[tokio::test]
+async fn compression() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    for comp in ["zlib@openssh.com", "zlib"] {
+        let mut server = ServerBuilder::default()
+            .add_compression_algorithm(comp.parse().unwrap())
+            .build("[::1]:2222")
+            .await
+            .unwrap();
+
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_none(|_| ok(true).boxed());
+        handlers.on_channel_exec(|mut ctx: ssssh::SessionContext, _: OsString| {
+            let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                stdout.write_all(&[b'x'; LEN]).await.unwrap();
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let proc = Command::new("ssh")
+            .env_clear()
+            .arg("-oStrictHostKeyChecking=no")
+            .arg("-oUserKnownHostsFile=/dev/null")
+            .arg("-oCompression=yes")
+            .arg("-p2222")
+            .arg("-q")
+            .arg("::1")
+            .arg("yes")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let connection = server.try_next().await.unwrap().unwrap();
+        let connection = connection.accept().await.unwrap();
+        let (result, output) = tokio::join!(connection.run(handlers), proc.wait_with_output());
+        result.unwrap();
+
+        let output = output.unwrap();
+        assert!(output.status.success(), "{}", comp);
+        assert_eq!(output.stdout, vec![b'x'; LEN]);
+    }
+}
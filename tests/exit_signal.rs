@@ -0,0 +1,48 @@
+use std::io::Read as _;
+use std::net::TcpStream;
+
+use futures::prelude::*;
+use ssh2::Session;
+use ssssh::{Handlers, ServerBuilder, Signal};
+
+#[tokio::test]
+async fn exit_signal() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| future::ok(true).boxed());
+    handlers.on_channel_exec(|ctx: ssssh::SessionContext, _| {
+        async move {
+            ctx.send_exit_signal(Signal::Term, true, "terminated")
+                .await?;
+            assert!(ctx.send_exit_signal(Signal::Kill, false, "").await.is_err());
+            Ok(0)
+        }
+        .boxed()
+    });
+
+    let task = tokio::task::spawn_blocking(|| {
+        let connection = TcpStream::connect("[::1]:2222").unwrap();
+        let mut session = Session::new().unwrap();
+        session.set_tcp_stream(connection);
+        session.handshake().unwrap();
+        session.auth_methods("foo").unwrap();
+        assert!(session.authenticated());
+
+        let mut channel = session.channel_session().unwrap();
+        channel.exec("true").unwrap();
+        channel.read_to_end(&mut vec![]).unwrap();
+        channel.wait_close().unwrap();
+
+        let signal = channel.exit_signal().unwrap();
+        assert_eq!(signal.exit_signal.as_deref(), Some("TERM"));
+    });
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    connection.run(handlers).await.unwrap();
+
+    task.await.unwrap();
+}
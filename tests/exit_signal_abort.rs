@@ -0,0 +1,47 @@
+use std::io::Read as _;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use futures::prelude::*;
+use ssh2::Session;
+use ssssh::{Handlers, ServerBuilder, SshError};
+
+#[tokio::test]
+async fn exit_signal_abort() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default()
+        .timeout(Duration::from_secs(1))
+        .build("[::1]:2222")
+        .await
+        .unwrap();
+
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| future::ok(true).boxed());
+    handlers.on_channel_exec(|_, _| future::pending().boxed());
+
+    let task = tokio::task::spawn_blocking(|| {
+        let connection = TcpStream::connect("[::1]:2222").unwrap();
+        let mut session = Session::new().unwrap();
+        session.set_tcp_stream(connection);
+        session.handshake().unwrap();
+        session.auth_methods("foo").unwrap();
+        assert!(session.authenticated());
+
+        let mut channel = session.channel_session().unwrap();
+        channel.exec("sleep infinity").unwrap();
+        // disconnect follows immediately.
+        channel.read_to_end(&mut vec![]).ok();
+        channel.wait_close().ok();
+
+        let signal = channel.exit_signal().unwrap();
+        assert_eq!(signal.exit_signal.as_deref(), Some("KILL"));
+    });
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    let result = connection.run(handlers).await;
+    assert!(matches!(result, Err(SshError::Timeout)), "{:?}", result);
+
+    task.await.unwrap();
+}
@@ -0,0 +1,89 @@
+use std::io::{Read as _, Write as _};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use futures::prelude::*;
+use ssh2::Session;
+use ssssh::{ChannelMode, Handlers, ServerBuilder};
+
+#[tokio::test]
+async fn global_handle() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| future::ok(true).boxed());
+    handlers.on_channel_shell(|mut ctx: ssssh::SessionContext| {
+        let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+        async move {
+            tokio::io::copy(&mut stdin, &mut stdout).await?;
+            Ok(0)
+        }
+        .boxed()
+    });
+    handlers.on_channel_exec(|mut ctx: ssssh::SessionContext, _| {
+        let (mut stdin, _, _) = ctx.take_stdio().unwrap();
+        async move {
+            tokio::io::copy(&mut stdin, &mut tokio::io::sink()).await?;
+            Ok(0)
+        }
+        .boxed()
+    });
+
+    let task = tokio::task::spawn_blocking(|| {
+        let connection = TcpStream::connect("[::1]:2222").unwrap();
+        let mut session = Session::new().unwrap();
+        session.set_tcp_stream(connection);
+        session.handshake().unwrap();
+        session.auth_methods("foo").unwrap();
+        assert!(session.authenticated());
+
+        let mut shell = session.channel_session().unwrap();
+        shell.shell().unwrap();
+        let mut exec = session.channel_session().unwrap();
+        exec.exec("tail -f /var/log/syslog").unwrap();
+
+        exec.read_to_end(&mut vec![]).unwrap();
+        exec.wait_close().unwrap();
+
+        assert!(!shell.eof());
+        shell.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        shell.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        shell.send_eof().unwrap();
+        shell.read_to_end(&mut vec![]).unwrap();
+        shell.wait_close().unwrap();
+    });
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    let handle = connection.handle();
+    let running = tokio::spawn(connection.run(handlers));
+
+    let channels = loop {
+        let channels = handle.channels();
+        if channels.iter().filter(|c| c.mode().is_some()).count() == 2 {
+            break channels;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let modes = channels
+        .iter()
+        .map(|c| c.mode().clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        modes,
+        vec![
+            Some(ChannelMode::Shell),
+            Some(ChannelMode::Exec("tail -f /var/log/syslog".into()))
+        ]
+    );
+
+    handle.close_channel(*channels[1].id(), "admin").unwrap();
+
+    task.await.unwrap();
+    drop(handle);
+    running.await.unwrap().unwrap();
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+use std::process::Stdio;
+
+use futures::future::{err, ok};
+use futures::prelude::*;
+use tokio::process::Command;
+
+use ssssh::{Handlers, ServerBuilder};
+
+#[derive(Debug)]
+struct PanicError;
+
+impl fmt::Display for PanicError {
+    fn fmt(&self, _: &mut fmt::Formatter<'_>) -> fmt::Result {
+        panic!("Display panicked")
+    }
+}
+
+impl std::error::Error for PanicError {}
+
+#[tokio::test]
+async fn panic_display() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let mut handlers = Handlers::<PanicError>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    handlers.on_channel_shell(|_| err(PanicError).boxed());
+
+    let proc = Command::new("ssh")
+        .env_clear()
+        .arg("-oStrictHostKeyChecking=no")
+        .arg("-oUserKnownHostsFile=/dev/null")
+        .arg("-p2222")
+        .arg("-q")
+        .arg("::1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .unwrap();
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    let e = connection.run(handlers).await.unwrap_err();
+    assert!(e.to_string().contains("formatting panicked"), "{}", e);
+
+    let output = proc.wait_with_output().await.unwrap();
+    assert!(!output.status.success());
+}
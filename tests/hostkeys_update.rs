@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use futures::future::ok;
+use futures::prelude::*;
+use tokio::process::Command;
+
+use ssssh::{Handlers, ServerBuilder};
+
+#[tokio::test]
+async fn hostkeys_update() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default()
+        .hostkeys_from_path("tests/ed25519")
+        .hostkeys_from_path("tests/rsa")
+        .build("[::1]:2222")
+        .await
+        .unwrap();
+
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    handlers.on_channel_shell(|_| {
+        // Keep the session while the client proves the announced keys.
+        async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Ok(0)
+        }
+        .boxed()
+    });
+
+    // Only the ed25519 key is known, the RSA one is learned.
+    let known_hosts =
+        std::env::temp_dir().join(format!("ssssh-known-hosts-{}", std::process::id()));
+    let ed25519 = std::fs::read_to_string("tests/ed25519.pub").unwrap();
+    std::fs::write(&known_hosts, format!("[::1]:2222 {}", ed25519)).unwrap();
+
+    let proc = Command::new("ssh")
+        .env_clear()
+        .arg("-oStrictHostKeyChecking=yes")
+        .arg(format!("-oUserKnownHostsFile={}", known_hosts.display()))
+        .arg("-oUpdateHostKeys=yes")
+        .arg("-p2222")
+        .arg("::1")
+        .stdout(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .unwrap();
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    connection.run(handlers).await.unwrap();
+
+    let output = proc.wait_with_output().await.unwrap();
+    assert!(output.status.success());
+
+    let rsa = std::fs::read_to_string("tests/rsa.pub").unwrap();
+    let rsa = rsa.split_whitespace().nth(1).unwrap();
+    let learned = std::fs::read_to_string(&known_hosts).unwrap();
+    std::fs::remove_file(&known_hosts).ok();
+    assert!(learned.contains(rsa), "{}", learned);
+}
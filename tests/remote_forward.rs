@@ -0,0 +1,95 @@
+use std::process::Stdio;
+
+use futures::future::ok;
+use futures::prelude::*;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+
+use ssssh::{DisconnectReason, GlobalHandle, Handlers, ServerBuilder};
+
+#[tokio::test]
+async fn remote_forward() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    // Target of the forwarding on the client side, echoes once.
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = target.accept().await.unwrap();
+        let (mut r, mut w) = socket.split();
+        tokio::io::copy(&mut r, &mut w).await.unwrap();
+    });
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let (bound_tx, bound_rx) = futures::channel::oneshot::channel();
+    let bound_tx = std::sync::Mutex::new(Some(bound_tx));
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    handlers.on_tcpip_forward(move |address: String, port, handle: GlobalHandle| {
+        assert_eq!(port, 0);
+        let bound_tx = bound_tx.lock().unwrap().take();
+        async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let bound = listener.local_addr()?.port();
+            tokio::spawn(async move {
+                let (mut socket, peer) = listener.accept().await.unwrap();
+                let ip = peer.ip().to_string();
+                let (mut input, mut output) = handle
+                    .open_forwarded_tcpip(&address, bound, &ip, peer.port())
+                    .await
+                    .unwrap();
+                let (mut r, mut w) = socket.split();
+                let upstream = async move {
+                    tokio::io::copy(&mut r, &mut output).await.unwrap();
+                };
+                let downstream = async move {
+                    tokio::io::copy(&mut input, &mut w).await.unwrap();
+                    w.shutdown().await.unwrap();
+                };
+                tokio::join!(upstream, downstream);
+            });
+            bound_tx.unwrap().send(bound).unwrap();
+            Ok(Some(bound))
+        }
+        .boxed()
+    });
+
+    let mut proc = Command::new("ssh")
+        .env_clear()
+        .arg("-oExitOnForwardFailure=yes")
+        .arg("-oStrictHostKeyChecking=no")
+        .arg("-oUserKnownHostsFile=/dev/null")
+        .arg("-p2222")
+        .arg("-q")
+        .arg(format!("-R0:127.0.0.1:{}", target_port))
+        .arg("-N")
+        .arg("::1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .unwrap();
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    let handle = connection.handle();
+    let client = async move {
+        let bound = bound_rx.await.unwrap();
+        let mut socket = TcpStream::connect(("127.0.0.1", bound)).await.unwrap();
+        socket.write_all(b"hello, world!").await.unwrap();
+        socket.shutdown().await.unwrap();
+        let mut echoed = vec![];
+        socket.read_to_end(&mut echoed).await.unwrap();
+        handle
+            .disconnect(DisconnectReason::ByApplication, "done")
+            .unwrap();
+        echoed
+    };
+    let (result, echoed) = tokio::join!(connection.run(handlers), client);
+    result.unwrap();
+    assert_eq!(echoed, b"hello, world!");
+
+    proc.kill().await.ok();
+}
@@ -0,0 +1,60 @@
+use futures::future::ok;
+use futures::FutureExt;
+use tokio::io::AsyncWriteExt;
+
+use ssssh::{Handlers, ReplayDriver, ReplayError};
+
+fn handlers(farewell: &'static [u8]) -> Handlers<anyhow::Error> {
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    handlers.on_channel_exec(move |mut ctx: ssssh::SessionContext, _prog| {
+        let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+        async move {
+            tokio::io::copy(&mut stdin, &mut stdout).await?;
+            stdout.write_all(farewell).await?;
+            Ok(0)
+        }
+        .boxed()
+    });
+    handlers
+}
+
+#[tokio::test]
+async fn replay() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    // Recorded from OpenSSH running `echo` with stdin "hello\n".
+    let driver = ReplayDriver::parse(include_str!("vectors/exec.replay")).unwrap();
+    driver.run(handlers(b"bye\n")).await.unwrap();
+
+    let err = driver.run(handlers(b"farewell\n")).await.unwrap_err();
+    assert!(matches!(err, ReplayError::Mismatch { .. }), "{}", err);
+}
+
+#[tokio::test]
+async fn replay_authenticated() {
+    // `exec.replay` without the service request and auth exchange.
+    let recording = include_str!("vectors/exec_authenticated.replay");
+
+    let user = std::sync::Arc::new(std::sync::Mutex::new(None));
+    let mut recording_user = handlers(b"bye\n");
+    let seen = user.clone();
+    recording_user.on_channel_exec(move |mut ctx: ssssh::SessionContext, _prog| {
+        *seen.lock().unwrap() = Some(ctx.username());
+        let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+        async move {
+            tokio::io::copy(&mut stdin, &mut stdout).await?;
+            stdout.write_all(b"bye\n").await?;
+            Ok(0)
+        }
+        .boxed()
+    });
+    let driver = ReplayDriver::authenticated(recording, "alice").unwrap();
+    driver.run(recording_user).await.unwrap();
+    assert_eq!(user.lock().unwrap().as_deref(), Some("alice"));
+
+    // Channels are refused before auth.
+    let driver = ReplayDriver::parse(recording).unwrap();
+    let err = driver.run(handlers(b"bye\n")).await.unwrap_err();
+    assert!(matches!(err, ReplayError::Mismatch { .. }), "{}", err);
+}
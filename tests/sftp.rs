@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::future::{ok, ready, BoxFuture};
+use futures::{FutureExt, TryStreamExt};
+use tokio::process::Command;
+
+use ssssh::sftp::{self, FileAttributes, OpenFlags, SftpHandler, StatusCode};
+use ssssh::{Handlers, ServerBuilder};
+
+/// Files by path, handles are the paths.
+#[derive(Clone, Default)]
+struct Memory(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+impl SftpHandler for Memory {
+    fn open(
+        &mut self,
+        filename: String,
+        flags: OpenFlags,
+        _: FileAttributes,
+    ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        let mut files = self.0.lock().unwrap();
+        let result = if flags.create() {
+            files.insert(filename.clone(), vec![]);
+            Ok(filename.into())
+        } else if files.contains_key(&filename) {
+            Ok(filename.into())
+        } else {
+            Err(StatusCode::NoSuchFile)
+        };
+        ready(result).boxed()
+    }
+
+    fn close(&mut self, _: Bytes) -> BoxFuture<'_, Result<(), StatusCode>> {
+        ok(()).boxed()
+    }
+
+    fn read(
+        &mut self,
+        handle: Bytes,
+        offset: u64,
+        len: u32,
+    ) -> BoxFuture<'_, Result<Bytes, StatusCode>> {
+        let files = self.0.lock().unwrap();
+        let file = &files[&*String::from_utf8_lossy(&handle)];
+        let start = (offset as usize).min(file.len());
+        let end = (start + len as usize).min(file.len());
+        ok(Bytes::copy_from_slice(&file[start..end])).boxed()
+    }
+
+    fn write(
+        &mut self,
+        handle: Bytes,
+        offset: u64,
+        data: Bytes,
+    ) -> BoxFuture<'_, Result<(), StatusCode>> {
+        let mut files = self.0.lock().unwrap();
+        let file = files.get_mut(&*String::from_utf8_lossy(&handle)).unwrap();
+        let end = offset as usize + data.len();
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[offset as usize..end].copy_from_slice(&data);
+        ok(()).boxed()
+    }
+
+    fn stat(&mut self, path: String) -> BoxFuture<'_, Result<FileAttributes, StatusCode>> {
+        let files = self.0.lock().unwrap();
+        let attrs = |size, permissions| FileAttributes {
+            size: Some(size),
+            permissions: Some(permissions),
+            ..Default::default()
+        };
+        let result = match files.get(&path) {
+            Some(file) => Ok(attrs(file.len() as u64, 0o100644)),
+            None if path == "/" => Ok(attrs(0, 0o040755)),
+            None => Err(StatusCode::NoSuchFile),
+        };
+        ready(result).boxed()
+    }
+}
+
+#[tokio::test]
+async fn sftp() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let dir = std::env::temp_dir().join(format!("ssssh-sftp-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let content = (0..200 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+    std::fs::write(dir.join("up"), &content).unwrap();
+    let batch = "put up /file\nget /file down\nget /nothing\n";
+    std::fs::write(dir.join("batch"), batch).unwrap();
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let memory = Memory::default();
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    let session = memory.clone();
+    handlers.on_channel_subsystem(move |mut ctx: ssssh::SessionContext, name| {
+        assert_eq!(name, "sftp");
+        let (stdin, stdout, _) = ctx.take_stdio().unwrap();
+        sftp::serve(session.clone(), stdin, stdout)
+            .map(|r| r.map(|_| 0).map_err(Into::into))
+            .boxed()
+    });
+
+    // `-b` aborts at the failing get, after the others succeeded.
+    let proc = Command::new("sftp")
+        .env_clear()
+        .current_dir(&dir)
+        .arg("-oStrictHostKeyChecking=no")
+        .arg("-oUserKnownHostsFile=/dev/null")
+        .arg("-P2222")
+        .arg("-q")
+        .arg("-bbatch")
+        .arg("[::1]")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    let (result, output) = tokio::join!(connection.run(handlers), proc.wait_with_output());
+    result.unwrap();
+
+    let output = output.unwrap();
+    assert!(!output.status.success());
+    assert_eq!(memory.0.lock().unwrap()["/file"], content);
+    assert_eq!(std::fs::read(dir.join("down")).unwrap(), content);
+    std::fs::remove_dir_all(dir).ok();
+}
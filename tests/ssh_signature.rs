@@ -0,0 +1,73 @@
+//! Signature vectors generated by OpenSSH `ssh-keygen -Y sign -n file`.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use ring::digest;
+
+use ssssh::ssh_signature::{parse_signature_blob, verify};
+
+fn get_string(buf: &mut Bytes) -> Bytes {
+    let len = buf.get_u32() as usize;
+    buf.split_to(len)
+}
+
+fn put_string(buf: &mut BytesMut, data: &[u8]) {
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+}
+
+/// Returns (public key blob, signed data, signature blob) from armored SSHSIG.
+fn load(name: &str) -> (Bytes, Bytes, Bytes) {
+    let armored = std::fs::read_to_string(format!("tests/vectors/{}.sig", name)).unwrap();
+    let b64 = armored
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .collect::<String>();
+    let mut sshsig = Bytes::from(base64::decode(b64).unwrap());
+
+    assert_eq!(&sshsig.split_to(6)[..], b"SSHSIG");
+    assert_eq!(sshsig.get_u32(), 1);
+    let publickey = get_string(&mut sshsig);
+    let namespace = get_string(&mut sshsig);
+    let reserved = get_string(&mut sshsig);
+    let hash_algorithm = get_string(&mut sshsig);
+    let signature = get_string(&mut sshsig);
+    assert_eq!(&hash_algorithm[..], b"sha512");
+
+    let message = std::fs::read("tests/vectors/msg").unwrap();
+    let hash = digest::digest(&digest::SHA512, &message);
+
+    let mut signed = BytesMut::new();
+    signed.put_slice(b"SSHSIG");
+    put_string(&mut signed, &namespace);
+    put_string(&mut signed, &reserved);
+    put_string(&mut signed, &hash_algorithm);
+    put_string(&mut signed, hash.as_ref());
+
+    (publickey, signed.freeze(), signature)
+}
+
+fn tampered(data: &Bytes) -> Bytes {
+    let mut data = data.to_vec();
+    let last = data.len() - 1;
+    data[last] ^= 1;
+    data.into()
+}
+
+#[test]
+fn ed25519() {
+    let (publickey, signed, signature) = load("ed25519");
+    assert_eq!(parse_signature_blob(&signature).unwrap().0, "ssh-ed25519");
+
+    verify(&publickey, None, &signed, &signature).unwrap();
+    verify(&publickey, None, &tampered(&signed), &signature).unwrap_err();
+    verify(&publickey, None, &signed, &tampered(&signature)).unwrap_err();
+}
+
+#[test]
+fn rsa_sha2_512() {
+    let (publickey, signed, signature) = load("rsa");
+    assert_eq!(parse_signature_blob(&signature).unwrap().0, "rsa-sha2-512");
+
+    verify(&publickey, None, &signed, &signature).unwrap();
+    verify(&publickey, None, &tampered(&signed), &signature).unwrap_err();
+    verify(&publickey, None, &signed, &tampered(&signature)).unwrap_err();
+}
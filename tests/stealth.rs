@@ -0,0 +1,130 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use futures::future::ok;
+use futures::prelude::*;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+use ssssh::{Handlers, Random, ServerBuilder, SshError, Stealth};
+
+#[tokio::test]
+async fn test() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default()
+        .stealth(
+            Stealth::default()
+                .minimal_banner(true)
+                .randomize_kexinit_order(true)
+                .await_client_banner_first(std::time::Duration::from_secs(1)),
+        )
+        .build("[::1]:2222")
+        .await
+        .unwrap();
+
+    for _ in 0..2 {
+        let mut handlers = Handlers::<anyhow::Error>::new();
+        handlers.on_auth_none(|_| ok(true).boxed());
+        handlers.on_channel_shell(|_| ok(0).boxed());
+
+        let proc = Command::new("ssh")
+            .env_clear()
+            .arg("-oStrictHostKeyChecking=no")
+            .arg("-oUserKnownHostsFile=/dev/null")
+            .arg("-p2222")
+            .arg("::1")
+            .stdout(std::process::Stdio::inherit())
+            .stderr(std::process::Stdio::inherit())
+            .spawn()
+            .unwrap();
+
+        let connection = server.try_next().await.unwrap().unwrap();
+        let connection = connection.accept().await.unwrap();
+        assert!(connection.client_version().starts_with("SSH-2.0-"));
+        connection.run(handlers).await.unwrap();
+
+        let output = proc.wait_with_output().await.unwrap();
+        assert!(output.status.success());
+    }
+}
+
+#[derive(Debug)]
+struct Counter(AtomicU8);
+
+impl Random for Counter {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), SshError> {
+        for b in dest {
+            *b = self.0.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Name-lists of the kexinit the server sends first.
+async fn kexinit_names(addr: SocketAddr) -> Vec<Vec<String>> {
+    let mut io = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    io.get_mut().write_all(b"SSH-2.0-client\r\n").await.unwrap();
+    let mut banner = String::new();
+    io.read_line(&mut banner).await.unwrap();
+
+    let len = io.read_u32().await.unwrap() as usize;
+    let mut packet = vec![0; len];
+    io.read_exact(&mut packet).await.unwrap();
+    let payload = &packet[1..(len - packet[0] as usize)];
+    assert_eq!(payload[0], 20);
+
+    let mut rest = &payload[(1 + 16)..];
+    (0..10)
+        .map(|_| {
+            let (n, tail) = rest.split_at(4);
+            let n = u32::from_be_bytes([n[0], n[1], n[2], n[3]]) as usize;
+            let (names, tail) = tail.split_at(n);
+            rest = tail;
+            std::str::from_utf8(names)
+                .unwrap()
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_kexinit_order_per_connection() {
+    let mut server = ServerBuilder::default()
+        .random(Arc::new(Counter(AtomicU8::new(0))))
+        .stealth(Stealth::default().randomize_kexinit_order(true))
+        .build("[::1]:0")
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let mut orders = vec![];
+    for _ in 0..2 {
+        let client = kexinit_names(addr);
+        let run = async {
+            let connection = server.try_next().await.unwrap().unwrap();
+            let connection = connection.accept().await.unwrap();
+            connection.run(Handlers::<anyhow::Error>::new()).await
+        };
+        tokio::select! {
+            names = client => orders.push(names),
+            result = run => panic!("{:?}", result),
+        }
+    }
+
+    let sorted = |names: &[String]| {
+        let mut names = names.to_vec();
+        names.sort();
+        names
+    };
+    let (first, second) = (&orders[0], &orders[1]);
+    for (a, b) in first.iter().zip(second) {
+        assert_eq!(sorted(a), sorted(b));
+    }
+    assert_ne!(first[0], second[0]);
+}